@@ -0,0 +1,122 @@
+//! Benchmarks of one collection over a synthetic proc filesystem of 1k, 10k and 50k processes.
+//!
+//! The fixture copies the stat, status, statm and io files of the benchmark itself under a new pid
+//! for every process, so the parsing is the same as against /proc, without depending on what
+//! runs on the machine. The allocations of one collection are printed before each benchmark.
+//!
+//! Save a baseline before a change and compare against it after:
+//!
+//! ```bash
+//! cargo bench --bench collect -- --save-baseline before
+//! cargo bench --bench collect -- --baseline before
+//! ```
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use procshot_server::collect;
+
+/// Counts the allocations, to report them next to the timings.
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const SIZES: &[usize] = &[1_000, 10_000, 50_000];
+
+/// Creates a proc filesystem of `processes` processes, with pids from 1000.
+fn fake_proc(processes: usize) -> PathBuf {
+    let root = std::env::temp_dir().join(format!(
+        "procshot_bench_collect_{}_{}",
+        processes,
+        std::process::id()
+    ));
+    let _ = fs::remove_dir_all(&root);
+    let stat = fs::read_to_string("/proc/self/stat").unwrap();
+    let stat_fields = &stat[stat.rfind(')').unwrap() + 2..];
+    let status = fs::read_to_string("/proc/self/status").unwrap();
+    let statm = fs::read_to_string("/proc/self/statm").unwrap();
+    let io = fs::read_to_string("/proc/self/io").unwrap();
+    for i in 0..processes {
+        let pid = 1000 + i;
+        let name = format!("worker-{}", i % 100);
+        let dir = root.join(pid.to_string());
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("stat"),
+            format!("{} ({}) {}", pid, name, stat_fields),
+        )
+        .unwrap();
+        let status: String = status
+            .lines()
+            .map(|line| match line.split(':').next() {
+                Some("Name") => format!("Name:\t{}\n", name),
+                Some(key @ "Pid") | Some(key @ "Tgid") => format!("{}:\t{}\n", key, pid),
+                _ => format!("{}\n", line),
+            })
+            .collect();
+        fs::write(dir.join("status"), status).unwrap();
+        fs::write(dir.join("statm"), &statm).unwrap();
+        fs::write(dir.join("io"), &io).unwrap();
+        fs::write(
+            dir.join("cmdline"),
+            format!("/usr/bin/{}\0--id\0{}\0", name, pid),
+        )
+        .unwrap();
+    }
+    root
+}
+
+fn report_allocations(root: &Path, processes: usize) {
+    let (count, bytes) = (
+        ALLOCATIONS.load(Ordering::Relaxed),
+        ALLOCATED_BYTES.load(Ordering::Relaxed),
+    );
+    let collected = collect::collect_all(root).unwrap();
+    assert_eq!(collected.len(), processes);
+    let count = ALLOCATIONS.load(Ordering::Relaxed) - count;
+    let bytes = ALLOCATED_BYTES.load(Ordering::Relaxed) - bytes;
+    eprintln!(
+        "collect_all/{}: {} allocations ({:.1} per process), {} bytes allocated",
+        processes,
+        count,
+        count as f64 / processes as f64,
+        bytes
+    );
+}
+
+fn bench_collect(c: &mut Criterion) {
+    let mut group = c.benchmark_group("collect_all");
+    group.sample_size(10);
+    for &processes in SIZES {
+        let root = fake_proc(processes);
+        report_allocations(&root, processes);
+        group.throughput(Throughput::Elements(processes as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(processes), &root, |b, root| {
+            b.iter(|| collect::collect_all(root).unwrap())
+        });
+        fs::remove_dir_all(&root).unwrap();
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_collect);
+criterion_main!(benches);
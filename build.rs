@@ -0,0 +1,18 @@
+// Generates include/procshot.h for the C interface of the ffi module, with the ffi feature.
+
+fn main() {
+    #[cfg(feature = "ffi")]
+    {
+        let crate_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        println!("cargo:rerun-if-changed=src/ffi.rs");
+        println!("cargo:rerun-if-changed=cbindgen.toml");
+        cbindgen::Builder::new()
+            .with_crate(&crate_dir)
+            .with_config(
+                cbindgen::Config::from_file(format!("{}/cbindgen.toml", crate_dir)).unwrap(),
+            )
+            .generate()
+            .expect("Cannot generate the C header")
+            .write_to_file(format!("{}/include/procshot.h", crate_dir));
+    }
+}
@@ -0,0 +1,47 @@
+#ifndef PROCSHOT_H
+#define PROCSHOT_H
+
+/* Generated by cbindgen from src/ffi.rs, do not edit. */
+
+#include <stddef.h>
+#include <stdint.h>
+
+/**
+ * Writes a snapshot of every process as NUL terminated JSON to `buf`, of `len` bytes.
+ *
+ * Returns the length of the JSON, without the NUL, like snprintf. Nothing is written if that is
+ * `len` or more, call again with a larger buffer. Returns -1 if /proc can't be read.
+ *
+ * # Safety
+ *
+ * `buf` must be valid for writes of `len` bytes, or null with a `len` of 0.
+ */
+ptrdiff_t procshot_snapshot_once(char *buf, size_t len);
+
+/**
+ * Starts the server loop on a new thread, recording every `delay_ms` milliseconds to `datadir`.
+ *
+ * Returns 0 on success, -1 if `datadir` is not a valid datadir and can't be created as one (see
+ * `paths::create_datadir`), -2 if the loop is already running, and -3 if `delay_ms` is shorter
+ * than one clock tick of the host (see `header::clock_tick`).
+ *
+ * # Safety
+ *
+ * `datadir` must be a NUL terminated string. It is copied.
+ */
+int procshot_start(const char *datadir, uint64_t delay_ms);
+
+/**
+ * Stops the server loop started by `procshot_start`, waiting for the running iteration to
+ * finish. Returns 0 once stopped, -1 if it wasn't started, and -3 if it had stopped on its own
+ * on an error, logged to stderr, eg: the datadir is locked by another server.
+ */
+int procshot_stop(void);
+
+/**
+ * Returns 1 while the server loop started by `procshot_start` runs, 0 if it wasn't started or
+ * stopped on an error, which `procshot_stop` then returns.
+ */
+int procshot_running(void);
+
+#endif  /* PROCSHOT_H */
@@ -0,0 +1,192 @@
+//! Alert rules evaluated by the server on every snapshot.
+//!
+//! A rule is a metric of a process and a threshold, written as `<metric> > <threshold>`, eg:
+//! `rss_pct_of_limit > 90`. Any metric of `metrics::PROCESS_METRICS` can be used, by name or
+//! alias, and processes without a value for it, eg: without a memory limit, never match. An
+//! alert fires once when a process crosses the threshold, and may fire again only after the
+//! process went back below it, so a process sitting above the threshold doesn't produce an alert
+//! every iteration.
+//!
+//! Alerts are logged, and handed to the `AlertCommand`s, if any.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io;
+use std::process::Command;
+use std::str::FromStr;
+
+use crate::child::{split_command_line, ChildLimits, Supervisor};
+use crate::metrics::{self, Metric};
+use crate::{Pid, PidStatus};
+
+/// AlertRule fires when a metric of a process is above a threshold.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AlertRule {
+    pub metric: &'static Metric,
+    pub above: f64,
+}
+
+impl FromStr for AlertRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (metric, above) = s
+            .split_once('>')
+            .ok_or_else(|| format!("invalid alert rule {}, expected <metric> > <threshold>", s))?;
+        Ok(AlertRule {
+            metric: metrics::parse(metric.trim())?,
+            above: above
+                .trim()
+                .parse()
+                .map_err(|e| format!("invalid threshold in alert rule {}: {}", s, e))?,
+        })
+    }
+}
+
+impl fmt::Display for AlertRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} > {}", self.metric, self.above)
+    }
+}
+
+/// Alert is a rule that started matching a process.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Alert {
+    pub rule: String,
+    pub hostname: String,
+    pub time_epoch: u64,
+    pub pid: Pid,
+    pub name: String,
+    pub value: f64,
+}
+
+impl fmt::Display for Alert {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} ({}) matches {} with {:.1}",
+            self.hostname, self.name, self.pid, self.rule, self.value
+        )
+    }
+}
+
+/// AlertEngine evaluates the rules and remembers which processes are already alerted on.
+#[derive(Debug, Default)]
+pub struct AlertEngine {
+    rules: Vec<AlertRule>,
+    /// (rule index, pid) of the alerts currently firing.
+    firing: HashSet<(usize, Pid)>,
+}
+
+impl AlertEngine {
+    pub fn new(rules: Vec<AlertRule>) -> Self {
+        AlertEngine {
+            rules,
+            firing: HashSet::new(),
+        }
+    }
+
+    /// Returns the alerts that started firing with the processes of a snapshot.
+    pub fn evaluate(
+        &mut self,
+        hostname: &str,
+        time_epoch: u64,
+        processes: &HashMap<Pid, PidStatus>,
+    ) -> Vec<Alert> {
+        let mut alerts = Vec::new();
+        let mut firing = HashSet::new();
+        for (i, rule) in self.rules.iter().enumerate() {
+            for (pid, status) in processes {
+                let value = match rule.metric.of(status) {
+                    Some(v) if v > rule.above => v,
+                    _ => continue,
+                };
+                firing.insert((i, *pid));
+                if !self.firing.contains(&(i, *pid)) {
+                    alerts.push(Alert {
+                        rule: rule.to_string(),
+                        hostname: hostname.to_string(),
+                        time_epoch,
+                        pid: *pid,
+                        name: status.name.clone(),
+                        value,
+                    });
+                }
+            }
+        }
+        self.firing = firing;
+        alerts
+    }
+}
+
+/// AlertCommand runs an external command for every alert, with the JSON encoded `Alert` on its
+/// stdin. Like `hook::CommandHook`, it runs in the background under a `child::Supervisor`.
+#[derive(Debug)]
+pub struct AlertCommand {
+    command_line: String,
+    program: String,
+    args: Vec<String>,
+    supervisor: Supervisor,
+}
+
+impl AlertCommand {
+    /// `command_line` is split on whitespace into the program and its arguments, without a shell.
+    pub fn new(command_line: &str, limits: ChildLimits) -> io::Result<Self> {
+        let (program, args) = split_command_line(command_line)?;
+        Ok(AlertCommand {
+            command_line: command_line.to_string(),
+            program,
+            args,
+            supervisor: Supervisor::new(limits),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.command_line
+    }
+
+    /// Starts the command for `alert`.
+    pub fn notify(&self, alert: &Alert) -> io::Result<()> {
+        let json = serde_json::to_vec(alert)?;
+        let mut command = Command::new(&self.program);
+        command.args(&self.args);
+        self.supervisor
+            .spawn(&self.command_line, &mut command, json)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collect::{restricted_pid_status, PROC_ROOT};
+    use std::path::Path;
+
+    #[test]
+    fn test_evaluate() {
+        let rule: AlertRule = "rss_pct_of_limit > 90".parse().unwrap();
+        assert_eq!(rule.above, 90.0);
+        assert!("resident_memory > 90".parse::<AlertRule>().is_err());
+        let rss: AlertRule = "rss > 1e9".parse().unwrap();
+        assert_eq!(rss.to_string(), "rss_bytes > 1000000000");
+        let mut status = restricted_pid_status(Path::new(PROC_ROOT), Pid::current());
+        status.rss_pct_of_limit = Some(95.0);
+        let mut processes: HashMap<Pid, PidStatus> =
+            vec![(Pid::new(7), status)].into_iter().collect();
+        let mut engine = AlertEngine::new(vec![rule]);
+        assert_eq!(engine.evaluate("localghost", 1, &processes).len(), 1);
+        // Still above, already alerted on.
+        assert!(engine.evaluate("localghost", 2, &processes).is_empty());
+        let status = processes.get_mut(&Pid::new(7)).unwrap();
+        status.rss_pct_of_limit = Some(50.0);
+        assert!(engine.evaluate("localghost", 3, &processes).is_empty());
+        let status = processes.get_mut(&Pid::new(7)).unwrap();
+        status.rss_pct_of_limit = Some(91.0);
+        let alert = engine.evaluate("localghost", 4, &processes).remove(0);
+        assert_eq!(alert.value, 91.0);
+
+        let command =
+            AlertCommand::new("grep -q rss_pct_of_limit", ChildLimits::default()).unwrap();
+        command.notify(&alert).unwrap();
+        assert!(command.supervisor.wait_all()[0].is_success());
+    }
+}
@@ -0,0 +1,90 @@
+//! Annotations of the archive with operational events.
+//!
+//! A jump in rss or CPU is easier to explain next to what was done to the host at the time: a
+//! deploy, a config change, a failover. `procshot annotate --at <time> --text <text>` appends
+//! such a note to `<datadir>/annotations.jsonl`, one JSON encoded `Annotation` per line, and the
+//! reports and the charts of the web UI show the annotations of the period they cover.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+/// Name of the annotations file in the datadir.
+pub const ANNOTATIONS_FILE: &str = "annotations.jsonl";
+
+/// Annotation is a note about something that happened at a point of the archive.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Annotation {
+    /// When it happened, in seconds since the Unix epoch like `EncoDecode::time_epoch`.
+    pub time_epoch: u64,
+    pub text: String,
+}
+
+/// Returns the path of the annotations file of `datadir`.
+pub fn path(datadir: &Path) -> PathBuf {
+    datadir.join(ANNOTATIONS_FILE)
+}
+
+/// Appends `annotation` to the annotations of `datadir`, creating the file if needed. Each
+/// annotation is a single write, so concurrent writers don't interleave.
+pub fn add(datadir: &Path, annotation: &Annotation) -> io::Result<()> {
+    let mut line = serde_json::to_vec(annotation)?;
+    line.push(b'\n');
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path(datadir))?
+        .write_all(&line)
+}
+
+/// Returns the annotations of `datadir` within `[from, to]` (epoch seconds), oldest first. Lines
+/// that can't be decoded are skipped.
+pub fn read(datadir: &Path, from: u64, to: u64) -> io::Result<Vec<Annotation>> {
+    let file = match File::open(path(datadir)) {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut annotations = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let annotation: Annotation = match serde_json::from_str(&line?) {
+            Ok(a) => a,
+            Err(_) => continue,
+        };
+        if annotation.time_epoch >= from && annotation.time_epoch <= to {
+            annotations.push(annotation);
+        }
+    }
+    // Annotations may be added after the fact, out of order.
+    annotations.sort_by_key(|a| a.time_epoch);
+    Ok(annotations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_annotations() {
+        let dir = std::env::temp_dir().join(format!("procshot_annotations_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        assert_eq!(read(&dir, 0, u64::MAX).unwrap(), Vec::new());
+        let deploy = Annotation {
+            time_epoch: 1563617611,
+            text: "deployed v2.3".to_string(),
+        };
+        let failover = Annotation {
+            time_epoch: 1563617000,
+            text: "db \"failover\"\nto replica".to_string(),
+        };
+        add(&dir, &deploy).unwrap();
+        add(&dir, &failover).unwrap();
+        assert_eq!(
+            read(&dir, 0, u64::MAX).unwrap(),
+            vec![failover, deploy.clone()]
+        );
+        assert_eq!(read(&dir, 1563617600, u64::MAX).unwrap(), vec![deploy]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
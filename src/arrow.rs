@@ -0,0 +1,332 @@
+//! Snapshots as Arrow record batches, for dataframe libraries (`arrow` feature).
+//!
+//! `to_record_batch` lays out snapshots as one row per process and snapshot, in the columns of
+//! the `parquet` format of `convert`, and `read_range` does it for the snapshots of a datadir
+//! taken in a time range, in memory, without writing a file first. Polars, DataFusion and the
+//! other libraries built on arrow-rs take the batches as they are:
+//!
+//! ```text
+//! let store = SnapshotStore::open("/var/log/procshot/data")?;
+//! let batch = procshot_server::arrow::read_range(&store, from, to)?;
+//! ```
+//!
+//! Rows are ordered by snapshot, oldest first, then by pid. `PidStatus::io` is split in the
+//! nullable `io_*` columns, `PidStatus::fds` and `PidStatus::connections` are JSON arrays in the
+//! nullable `fds` and `connections` columns, and `PidStatus::extensions` is a JSON object in the
+//! `extensions` column.
+
+use std::io;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use arrow_array::{
+    ArrayRef, BooleanArray, Float64Array, Int32Array, Int64Array, RecordBatch, StringArray,
+    UInt32Array, UInt64Array,
+};
+use arrow_schema::{ArrowError, DataType, Field, Schema, SchemaRef};
+
+use crate::proc_io::ProcIo;
+use crate::query::SnapshotStore;
+use crate::{EncoDecode, Pid, PidStatus};
+
+/// Returns the schema of the batches.
+pub fn schema() -> SchemaRef {
+    let field = |name: &str, data_type: DataType| Field::new(name, data_type, false);
+    Arc::new(Schema::new(vec![
+        field("hostname", DataType::Utf8),
+        field("time_epoch", DataType::UInt64),
+        field("pid", DataType::Int32),
+        field("ppid", DataType::Int32),
+        field("euid", DataType::Int32),
+        field("name", DataType::Utf8),
+        field("cmd_long", DataType::Utf8),
+        field("state", DataType::Utf8),
+        field("fdsize", DataType::UInt32),
+        // PidStatus::fds, as a JSON array, null if it wasn't listed.
+        Field::new("fds", DataType::Utf8, true),
+        // PidStatus::connections, as a JSON array, null if they weren't listed.
+        Field::new("connections", DataType::Utf8, true),
+        Field::new("vmpeak", DataType::UInt64, true),
+        Field::new("vmsize", DataType::UInt64, true),
+        field("rss_bytes", DataType::Int64),
+        field("shared_pages", DataType::UInt64),
+        field("text_pages", DataType::UInt64),
+        field("data_pages", DataType::UInt64),
+        Field::new("rss_pct_of_limit", DataType::Float64, true),
+        field("utime", DataType::UInt64),
+        field("stime", DataType::UInt64),
+        Field::new("sched_policy", DataType::Utf8, true),
+        Field::new("rt_priority", DataType::UInt32, true),
+        // PidStatus::io, null if it wasn't readable.
+        Field::new("io_read_bytes", DataType::UInt64, true),
+        Field::new("io_write_bytes", DataType::UInt64, true),
+        Field::new("io_syscr", DataType::UInt64, true),
+        Field::new("io_syscw", DataType::UInt64, true),
+        Field::new("io_cancelled_write_bytes", DataType::UInt64, true),
+        Field::new("service_hint", DataType::Utf8, true),
+        Field::new("runtime", DataType::Utf8, true),
+        Field::new("cgroup", DataType::Utf8, true),
+        // PidStatus::cgroup_limits, null if not recorded or unlimited.
+        Field::new("cgroup_memory_max", DataType::UInt64, true),
+        Field::new("cgroup_cpus", DataType::Float64, true),
+        field("user_cpu_usage", DataType::Float64),
+        field("sys_cpu_usage", DataType::Float64),
+        field("restricted", DataType::Boolean),
+        field("vanished_during_scan", DataType::Boolean),
+        // PidStatus::extensions, as a JSON object.
+        field("extensions", DataType::Utf8),
+    ]))
+}
+
+/// Returns the processes of `snapshots` as a record batch of `schema`.
+pub fn to_record_batch(snapshots: &[EncoDecode]) -> Result<RecordBatch, ArrowError> {
+    let mut rows: Vec<(&EncoDecode, &Pid, &PidStatus)> = Vec::new();
+    for snapshot in snapshots {
+        let start = rows.len();
+        rows.extend(snapshot.pid_map_list.iter().map(|(p, s)| (snapshot, p, s)));
+        rows[start..].sort_by_key(|(_, pid, _)| **pid);
+    }
+    let io_column = |value: fn(&ProcIo) -> u64| -> ArrayRef {
+        Arc::new(UInt64Array::from(
+            rows.iter()
+                .map(|(_, _, s)| s.io.as_ref().map(value))
+                .collect::<Vec<_>>(),
+        ))
+    };
+    let fds = rows
+        .iter()
+        .map(|(_, _, s)| s.fds.as_ref().map(serde_json::to_string).transpose())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| ArrowError::ExternalError(Box::new(e)))?;
+    let connections = rows
+        .iter()
+        .map(|(_, _, s)| {
+            s.connections
+                .as_ref()
+                .map(serde_json::to_string)
+                .transpose()
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| ArrowError::ExternalError(Box::new(e)))?;
+    let extensions = rows
+        .iter()
+        .map(|(_, _, s)| serde_json::to_string(&s.extensions))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| ArrowError::ExternalError(Box::new(e)))?;
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(StringArray::from_iter_values(
+            rows.iter().map(|(e, _, _)| &e.hostname),
+        )),
+        Arc::new(UInt64Array::from_iter_values(
+            rows.iter().map(|(e, _, _)| e.time_epoch),
+        )),
+        Arc::new(Int32Array::from_iter_values(
+            rows.iter().map(|(_, p, _)| p.as_raw()),
+        )),
+        Arc::new(Int32Array::from_iter_values(
+            rows.iter().map(|(_, _, s)| s.ppid.as_raw()),
+        )),
+        Arc::new(Int32Array::from_iter_values(
+            rows.iter().map(|(_, _, s)| s.euid),
+        )),
+        Arc::new(StringArray::from_iter_values(
+            rows.iter().map(|(_, _, s)| &s.name),
+        )),
+        Arc::new(StringArray::from_iter_values(
+            rows.iter().map(|(_, _, s)| s.cmd_long.join(" ")),
+        )),
+        Arc::new(StringArray::from_iter_values(
+            rows.iter().map(|(_, _, s)| &s.state),
+        )),
+        Arc::new(UInt32Array::from_iter_values(
+            rows.iter().map(|(_, _, s)| s.fdsize),
+        )),
+        Arc::new(StringArray::from(fds)),
+        Arc::new(StringArray::from(connections)),
+        Arc::new(UInt64Array::from(
+            rows.iter().map(|(_, _, s)| s.vmpeak).collect::<Vec<_>>(),
+        )),
+        Arc::new(UInt64Array::from(
+            rows.iter().map(|(_, _, s)| s.vmsize).collect::<Vec<_>>(),
+        )),
+        Arc::new(Int64Array::from_iter_values(
+            rows.iter().map(|(_, _, s)| s.rss_bytes),
+        )),
+        Arc::new(UInt64Array::from_iter_values(
+            rows.iter().map(|(_, _, s)| s.shared_pages),
+        )),
+        Arc::new(UInt64Array::from_iter_values(
+            rows.iter().map(|(_, _, s)| s.text_pages),
+        )),
+        Arc::new(UInt64Array::from_iter_values(
+            rows.iter().map(|(_, _, s)| s.data_pages),
+        )),
+        Arc::new(Float64Array::from(
+            rows.iter()
+                .map(|(_, _, s)| s.rss_pct_of_limit)
+                .collect::<Vec<_>>(),
+        )),
+        Arc::new(UInt64Array::from_iter_values(
+            rows.iter().map(|(_, _, s)| s.utime),
+        )),
+        Arc::new(UInt64Array::from_iter_values(
+            rows.iter().map(|(_, _, s)| s.stime),
+        )),
+        Arc::new(StringArray::from(
+            rows.iter()
+                .map(|(_, _, s)| s.sched_policy.map(|p| p.to_string()))
+                .collect::<Vec<_>>(),
+        )),
+        Arc::new(UInt32Array::from(
+            rows.iter()
+                .map(|(_, _, s)| s.rt_priority)
+                .collect::<Vec<_>>(),
+        )),
+        io_column(|io| io.read_bytes),
+        io_column(|io| io.write_bytes),
+        io_column(|io| io.syscr),
+        io_column(|io| io.syscw),
+        io_column(|io| io.cancelled_write_bytes),
+        Arc::new(StringArray::from(
+            rows.iter()
+                .map(|(_, _, s)| s.service_hint.as_deref())
+                .collect::<Vec<_>>(),
+        )),
+        Arc::new(StringArray::from(
+            rows.iter()
+                .map(|(_, _, s)| s.runtime.as_deref())
+                .collect::<Vec<_>>(),
+        )),
+        Arc::new(StringArray::from(
+            rows.iter()
+                .map(|(_, _, s)| s.cgroup.as_deref())
+                .collect::<Vec<_>>(),
+        )),
+        Arc::new(UInt64Array::from(
+            rows.iter()
+                .map(|(_, _, s)| s.cgroup_limits.and_then(|l| l.memory_max))
+                .collect::<Vec<_>>(),
+        )),
+        Arc::new(Float64Array::from(
+            rows.iter()
+                .map(|(_, _, s)| s.cgroup_limits.and_then(|l| l.cpus))
+                .collect::<Vec<_>>(),
+        )),
+        Arc::new(Float64Array::from_iter_values(
+            rows.iter().map(|(_, _, s)| s.user_cpu_usage),
+        )),
+        Arc::new(Float64Array::from_iter_values(
+            rows.iter().map(|(_, _, s)| s.sys_cpu_usage),
+        )),
+        Arc::new(BooleanArray::from(
+            rows.iter()
+                .map(|(_, _, s)| s.restricted)
+                .collect::<Vec<_>>(),
+        )),
+        Arc::new(BooleanArray::from(
+            rows.iter()
+                .map(|(_, _, s)| s.vanished_during_scan)
+                .collect::<Vec<_>>(),
+        )),
+        Arc::new(StringArray::from(extensions)),
+    ];
+    RecordBatch::try_new(schema(), columns)
+}
+
+/// Reads the snapshots of `store` taken between `from` and `to` into a single record batch. Fails
+/// on the first snapshot that can't be read.
+pub fn read_range(
+    store: &SnapshotStore,
+    from: SystemTime,
+    to: SystemTime,
+) -> io::Result<RecordBatch> {
+    let snapshots = store
+        .query(from, to)
+        .collect::<Result<Vec<EncoDecode>, _>>()?;
+    to_record_batch(&snapshots).map_err(io::Error::other)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{DirBackend, StorageBackend};
+    use crate::priority::SchedPolicy;
+    use arrow_array::Array;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn status(name: &str, rss_bytes: i64) -> PidStatus {
+        PidStatus {
+            ppid: Pid::new(1),
+            cmd_long: vec![name.to_string(), "-f".to_string()],
+            name: name.to_string(),
+            cmd_short: name.to_string(),
+            fdsize: 64,
+            state: "S (sleeping)".to_string(),
+            vmpeak: Some(1024),
+            rss_pages: rss_bytes / 4096,
+            rss_bytes,
+            rsslim_bytes: u64::MAX,
+            processor_last_executed: Some(0),
+            sched_policy: Some(SchedPolicy::Other),
+            rt_priority: Some(0),
+            utime: 1,
+            stime: 1,
+            io: Some(ProcIo {
+                read_bytes: 4096,
+                ..Default::default()
+            }),
+            runtime: Some("native".to_string()),
+            user_cpu_usage: 1.5,
+            sys_cpu_usage: 0.5,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_read_range() {
+        let dir = std::env::temp_dir().join(format!("procshot_arrow_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let mut backend = DirBackend::new(&dir, false);
+        for epoch in [60, 120, 180] {
+            let mut pids = HashMap::new();
+            pids.insert(Pid::new(20), status("nginx", 8192));
+            pids.insert(Pid::new(10), status("sshd", 4096));
+            backend
+                .write_snapshot(&EncoDecode {
+                    hostname: "localghost".to_string(),
+                    pid_map_list: pids,
+                    time_epoch: epoch,
+                    delay: Duration::from_secs(60),
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+        let store = SnapshotStore::open(&dir).unwrap();
+        let at = |epoch| UNIX_EPOCH + Duration::from_secs(epoch);
+        let batch = read_range(&store, at(100), at(200)).unwrap();
+        assert_eq!(batch.schema(), schema());
+        assert_eq!(batch.num_rows(), 4);
+        let column = |name: &str| batch.column_by_name(name).unwrap().clone();
+        let epochs = column("time_epoch");
+        let epochs = epochs.as_any().downcast_ref::<UInt64Array>().unwrap();
+        assert_eq!(epochs.values().to_vec(), vec![120, 120, 180, 180]);
+        let pids = column("pid");
+        let pids = pids.as_any().downcast_ref::<Int32Array>().unwrap();
+        assert_eq!(pids.values().to_vec(), vec![10, 20, 10, 20]);
+        let cmd = column("cmd_long");
+        let cmd = cmd.as_any().downcast_ref::<StringArray>().unwrap();
+        assert_eq!(cmd.value(1), "nginx -f");
+        assert_eq!(column("vmsize").null_count(), 4);
+        assert_eq!(column("io_read_bytes").null_count(), 0);
+        assert_eq!(column("fds").null_count(), 4);
+        assert_eq!(column("connections").null_count(), 4);
+        let policy = column("sched_policy");
+        let policy = policy.as_any().downcast_ref::<StringArray>().unwrap();
+        assert_eq!(policy.value(0), "other");
+        assert_eq!(read_range(&store, at(200), at(300)).unwrap().num_rows(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
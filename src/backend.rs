@@ -0,0 +1,243 @@
+//! Storage backends the server writes its snapshots to, and queries read them from.
+//!
+//! A backend is anything implementing `StorageBackend`. The server writes to the datadir through
+//! `DirBackend` unless another backend is given in `ScanOptions::backend`, and `query::
+//! par_map_backend` reads from any backend, so downstream crates can store snapshots elsewhere
+//! (a column store, a remote service) by implementing the trait, without forking.
+//!
+//! Backends address snapshots by their `time_epoch`. With sub-second sampling, only one snapshot
+//! per second is reachable through `list_range` and `read_snapshot`.
+
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::{retention, store, EncoDecode};
+
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+/// StorageBackend is implemented by everything that can store and read back snapshots.
+pub trait StorageBackend: Send + Sync {
+    /// Short name of the backend, used in log messages.
+    fn name(&self) -> &str;
+
+    /// Stores a snapshot. Returns the file it was written to, for backends storing one file per
+    /// snapshot: post-write hooks only run for those.
+    fn write_snapshot(&mut self, snapshot: &EncoDecode) -> io::Result<Option<PathBuf>>;
+
+    /// Same as `write_snapshot`, for a snapshot taken at `time`, a duration since the epoch.
+    /// Backends keeping a finer resolution than the `time_epoch` of the snapshot override it.
+    fn write_snapshot_at(
+        &mut self,
+        snapshot: &EncoDecode,
+        _time: Duration,
+    ) -> io::Result<Option<PathBuf>> {
+        self.write_snapshot(snapshot)
+    }
+
+    /// Returns the epochs of the snapshots taken between `from` and `to` (inclusive), oldest
+    /// first.
+    fn list_range(&self, from: u64, to: u64) -> io::Result<Vec<u64>>;
+
+    /// Reads the snapshot taken at `epoch`. Fails with `NotFound` if there is none.
+    fn read_snapshot(&self, epoch: u64) -> io::Result<EncoDecode>;
+
+    /// Deletes the snapshots taken before `before` and returns how many were deleted.
+    fn prune(&mut self, before: u64) -> io::Result<usize>;
+
+    /// Compresses the snapshots written from now on at `level`, see `ScanOptions::
+    /// adaptive_compression`. Backends without a compression level ignore it.
+    fn set_compression_level(&mut self, _level: store::CompressionLevel) {}
+}
+
+impl fmt::Debug for dyn StorageBackend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "StorageBackend({})", self.name())
+    }
+}
+
+/// DirBackend stores one file per snapshot in a datadir, bincode encoded by default, the layout
+/// described in the `store` module.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DirBackend {
+    datadir: PathBuf,
+    name: String,
+    /// Name the files after the millisecond they were taken at, see `store::snapshot_file_name`.
+    sub_second: bool,
+    compression: store::CompressionMode,
+    level: store::CompressionLevel,
+    serialization: store::SerializationFormat,
+}
+
+impl DirBackend {
+    pub fn new<P: Into<PathBuf>>(datadir: P, sub_second: bool) -> Self {
+        let datadir = datadir.into();
+        DirBackend {
+            name: datadir.display().to_string(),
+            datadir,
+            sub_second,
+            compression: store::CompressionMode::None,
+            level: store::CompressionLevel::Default,
+            serialization: store::SerializationFormat::Bincode,
+        }
+    }
+
+    /// Compresses the files written from now on. Files already in the datadir stay readable
+    /// whatever their compression.
+    pub fn with_compression(mut self, compression: store::CompressionMode) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Encodes the files written from now on in `serialization`. Files already in the datadir
+    /// stay readable whatever their format.
+    pub fn with_serialization(mut self, serialization: store::SerializationFormat) -> Self {
+        self.serialization = serialization;
+        self
+    }
+
+    pub fn datadir(&self) -> &Path {
+        &self.datadir
+    }
+}
+
+impl StorageBackend for DirBackend {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn write_snapshot(&mut self, snapshot: &EncoDecode) -> io::Result<Option<PathBuf>> {
+        self.write_snapshot_at(snapshot, Duration::from_secs(snapshot.time_epoch))
+    }
+
+    fn write_snapshot_at(
+        &mut self,
+        snapshot: &EncoDecode,
+        time: Duration,
+    ) -> io::Result<Option<PathBuf>> {
+        let encoded = self.serialization.encode(snapshot)?;
+        let mut name = store::snapshot_file_name(time, self.sub_second);
+        if let Some(extension) = self.compression.extension() {
+            name = format!("{}.{}", name, extension);
+        }
+        // Written aside and renamed, so a server killed in the middle of a write, eg: by the
+        // watchdog, leaves no truncated snapshot behind.
+        let path = self.datadir.join(&name);
+        let tmp = self.datadir.join(format!(".{}.tmp", name));
+        let mut f = File::create(&tmp)?;
+        f.write_all(&self.compression.compress_at(encoded, self.level)?)?;
+        f.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        Ok(Some(path))
+    }
+
+    fn list_range(&self, from: u64, to: u64) -> io::Result<Vec<u64>> {
+        let mut epochs: Vec<u64> = store::snapshot_files(&self.datadir)?
+            .into_iter()
+            .map(|(epoch, _)| epoch)
+            .filter(|epoch| *epoch >= from && *epoch <= to)
+            .collect();
+        epochs.dedup();
+        Ok(epochs)
+    }
+
+    fn read_snapshot(&self, epoch: u64) -> io::Result<EncoDecode> {
+        let path = match store::find_snapshot(&self.datadir, epoch) {
+            Some(path) => path,
+            None => store::snapshot_files(&self.datadir)?
+                .into_iter()
+                .find(|(e, _)| *e == epoch)
+                .map(|(_, path)| path)
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("no snapshot at {} in {}", epoch, self.name),
+                    )
+                })?,
+        };
+        store::read_snapshot(&path)
+    }
+
+    /// Deletes the files of both tiers through the journal of the `retention` module. The files of
+    /// the cold tier are journaled in the cold directory.
+    fn prune(&mut self, before: u64) -> io::Result<usize> {
+        let cold = self.datadir.join(store::COLD_DIR);
+        let (mut warm_files, mut cold_files) = (Vec::new(), Vec::new());
+        for (epoch, path) in store::snapshot_files(&self.datadir)? {
+            if epoch >= before {
+                break;
+            }
+            let name = match path.file_name() {
+                Some(name) => PathBuf::from(name),
+                None => continue,
+            };
+            match path.parent() == Some(cold.as_path()) {
+                true => cold_files.push(name),
+                false => warm_files.push(name),
+            }
+        }
+        Ok(retention::prune_files(&self.datadir, &warm_files)?
+            + retention::prune_files(&cold, &cold_files)?)
+    }
+
+    fn set_compression_level(&mut self, level: store::CompressionLevel) {
+        self.level = level;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(epoch: u64) -> EncoDecode {
+        EncoDecode {
+            hostname: "localghost".to_string(),
+            time_epoch: epoch,
+            delay: Duration::from_secs(60),
+            total_cpu_time: epoch * 10,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_dir_backend() {
+        let dir = std::env::temp_dir().join(format!("procshot_backend_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut backend: Box<dyn StorageBackend> = Box::new(DirBackend::new(&dir, false));
+        for epoch in &[60, 120, 180] {
+            let path = backend.write_snapshot(&snapshot(*epoch)).unwrap();
+            assert_eq!(path, Some(dir.join(format!("{}.procshot", epoch))));
+        }
+        // No temporary file is left behind.
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 3);
+        assert_eq!(backend.list_range(100, 1000).unwrap(), vec![120, 180]);
+        assert_eq!(backend.read_snapshot(120).unwrap().total_cpu_time, 1200);
+        let epochs = backend.list_range(0, u64::MAX).unwrap();
+        let totals: Vec<u64> =
+            crate::query::par_map_backend(&*backend, &epochs, 2, |s| s.total_cpu_time)
+                .into_iter()
+                .map(|(_, t)| t.unwrap())
+                .collect();
+        assert_eq!(totals, vec![600, 1200, 1800]);
+        assert_eq!(
+            backend.read_snapshot(121).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        assert_eq!(backend.prune(150).unwrap(), 2);
+        assert_eq!(backend.list_range(0, u64::MAX).unwrap(), vec![180]);
+
+        let mut backend =
+            DirBackend::new(&dir, false).with_compression(store::CompressionMode::Gzip);
+        let path = backend.write_snapshot(&snapshot(240)).unwrap();
+        assert_eq!(path, Some(dir.join("240.procshot.gz")));
+        assert_eq!(backend.list_range(0, u64::MAX).unwrap(), vec![180, 240]);
+        assert_eq!(backend.read_snapshot(240).unwrap().total_cpu_time, 2400);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,304 @@
+//! SQLite backend (`sqlite` feature).
+//!
+//! Snapshots go to a `snapshots` table keyed by the time they were taken at in milliseconds,
+//! `time_ms`, so that sub-second snapshots don't collide, and a `processes` table keyed by
+//! `(time_ms, pid)`. The name and usage columns of `processes` are there for querying, the
+//! complete status is kept as JSON in its `status` column. As with the other backends,
+//! `list_range` and `read_snapshot` address snapshots by their `time_epoch`, and only the first
+//! snapshot of each second is reachable through them. This is also the `sqlite` format of
+//! `convert`.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use rusqlite::{params, Connection};
+
+use super::StorageBackend;
+use crate::EncoDecode;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS snapshots (
+        time_ms INTEGER PRIMARY KEY,
+        time_epoch INTEGER NOT NULL,
+        hostname TEXT NOT NULL,
+        delay_ms INTEGER NOT NULL,
+        total_cpu_time INTEGER NOT NULL,
+        cpu_times TEXT NOT NULL,
+        labels TEXT NOT NULL,
+        system_memory TEXT NOT NULL,
+        load_average TEXT NOT NULL,
+        uptime_ms INTEGER NOT NULL,
+        per_cpu TEXT NOT NULL,
+        disks TEXT NOT NULL,
+        interfaces TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS snapshots_time_epoch ON snapshots (time_epoch);
+    CREATE TABLE IF NOT EXISTS processes (
+        time_ms INTEGER NOT NULL REFERENCES snapshots (time_ms),
+        pid INTEGER NOT NULL,
+        name TEXT NOT NULL,
+        rss_bytes INTEGER NOT NULL,
+        user_cpu_usage REAL NOT NULL,
+        sys_cpu_usage REAL NOT NULL,
+        status TEXT NOT NULL,
+        PRIMARY KEY (time_ms, pid)
+    );";
+
+fn to_io(e: rusqlite::Error) -> io::Error {
+    match e {
+        rusqlite::Error::QueryReturnedNoRows => io::Error::new(io::ErrorKind::NotFound, e),
+        e => io::Error::other(e),
+    }
+}
+
+fn to_json<T: serde::Serialize>(value: &T) -> io::Result<String> {
+    serde_json::to_string(value).map_err(io::Error::from)
+}
+
+fn from_json<T: serde::de::DeserializeOwned>(value: &str) -> io::Result<T> {
+    serde_json::from_str(value).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Epochs are stored as SQLite integers, which are signed.
+fn to_sql_epoch(epoch: u64) -> i64 {
+    epoch.min(i64::MAX as u64) as i64
+}
+
+/// SqliteBackend stores snapshots in a SQLite database.
+#[derive(Debug)]
+pub struct SqliteBackend {
+    conn: Mutex<Connection>,
+    name: String,
+}
+
+impl SqliteBackend {
+    /// Opens the database at `path`, creating it and its tables if needed.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let conn = Connection::open(path).map_err(to_io)?;
+        conn.execute_batch(SCHEMA).map_err(to_io)?;
+        Ok(SqliteBackend {
+            conn: Mutex::new(conn),
+            name: path.display().to_string(),
+        })
+    }
+}
+
+impl StorageBackend for SqliteBackend {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn write_snapshot(&mut self, snapshot: &EncoDecode) -> io::Result<Option<PathBuf>> {
+        self.write_snapshot_at(snapshot, Duration::from_secs(snapshot.time_epoch))
+    }
+
+    fn write_snapshot_at(
+        &mut self,
+        snapshot: &EncoDecode,
+        time: Duration,
+    ) -> io::Result<Option<PathBuf>> {
+        let time_ms = to_sql_epoch(time.as_millis().min(u64::MAX as u128) as u64);
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(to_io)?;
+        tx.execute(
+            "INSERT INTO snapshots VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+            params![
+                time_ms,
+                to_sql_epoch(snapshot.time_epoch),
+                snapshot.hostname,
+                snapshot.delay.as_millis() as i64,
+                snapshot.total_cpu_time as i64,
+                to_json(&snapshot.cpu_times)?,
+                to_json(&snapshot.labels)?,
+                to_json(&snapshot.system_memory)?,
+                to_json(&snapshot.load_average)?,
+                snapshot.uptime.as_millis() as i64,
+                to_json(&snapshot.per_cpu)?,
+                to_json(&snapshot.disks)?,
+                to_json(&snapshot.interfaces)?,
+            ],
+        )
+        .map_err(to_io)?;
+        {
+            let mut stmt = tx
+                .prepare_cached("INSERT INTO processes VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)")
+                .map_err(to_io)?;
+            for (pid, status) in &snapshot.pid_map_list {
+                stmt.execute(params![
+                    time_ms,
+                    pid.as_raw(),
+                    status.name,
+                    status.rss_bytes,
+                    status.user_cpu_usage,
+                    status.sys_cpu_usage,
+                    to_json(status)?,
+                ])
+                .map_err(to_io)?;
+            }
+        }
+        tx.commit().map_err(to_io)?;
+        Ok(None)
+    }
+
+    fn list_range(&self, from: u64, to: u64) -> io::Result<Vec<u64>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare_cached(
+                "SELECT DISTINCT time_epoch FROM snapshots WHERE time_epoch BETWEEN ?1 AND ?2 ORDER BY time_epoch",
+            )
+            .map_err(to_io)?;
+        let epochs: Vec<i64> = stmt
+            .query_map(params![to_sql_epoch(from), to_sql_epoch(to)], |row| {
+                row.get(0)
+            })
+            .and_then(|rows| rows.collect())
+            .map_err(to_io)?;
+        Ok(epochs.into_iter().map(|e| e as u64).collect())
+    }
+
+    fn read_snapshot(&self, epoch: u64) -> io::Result<EncoDecode> {
+        let conn = self.conn.lock().unwrap();
+        let epoch = to_sql_epoch(epoch);
+        let (
+            time_ms,
+            hostname,
+            delay,
+            total_cpu_time,
+            cpu_times,
+            labels,
+            system_memory,
+            load_average,
+            uptime_ms,
+            per_cpu,
+            disks,
+            interfaces,
+        ) = conn
+            .query_row(
+                "SELECT time_ms, hostname, delay_ms, total_cpu_time, cpu_times, labels, system_memory, load_average, uptime_ms, per_cpu, disks, interfaces FROM snapshots WHERE time_epoch = ?1 ORDER BY time_ms LIMIT 1",
+                params![epoch],
+                |row| {
+                    Ok((
+                        row.get::<_, i64>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, i64>(2)?,
+                        row.get::<_, i64>(3)?,
+                        row.get::<_, String>(4)?,
+                        row.get::<_, String>(5)?,
+                        row.get::<_, String>(6)?,
+                        row.get::<_, String>(7)?,
+                        row.get::<_, i64>(8)?,
+                        row.get::<_, String>(9)?,
+                        row.get::<_, String>(10)?,
+                        row.get::<_, String>(11)?,
+                    ))
+                },
+            )
+            .map_err(to_io)?;
+        let mut stmt = conn
+            .prepare_cached("SELECT pid, status FROM processes WHERE time_ms = ?1")
+            .map_err(to_io)?;
+        let rows: Vec<(i32, String)> = stmt
+            .query_map(params![time_ms], |row| Ok((row.get(0)?, row.get(1)?)))
+            .and_then(|rows| rows.collect())
+            .map_err(to_io)?;
+        Ok(EncoDecode {
+            hostname,
+            pid_map_list: rows
+                .iter()
+                .map(|(pid, status)| Ok(((*pid).into(), from_json(status)?)))
+                .collect::<io::Result<_>>()?,
+            time_epoch: epoch as u64,
+            delay: std::time::Duration::from_millis(delay as u64),
+            total_cpu_time: total_cpu_time as u64,
+            cpu_times: from_json(&cpu_times)?,
+            per_cpu: from_json(&per_cpu)?,
+            system_memory: from_json(&system_memory)?,
+            load_average: from_json(&load_average)?,
+            uptime: std::time::Duration::from_millis(uptime_ms as u64),
+            disks: from_json(&disks)?,
+            interfaces: from_json(&interfaces)?,
+            labels: from_json(&labels)?,
+        })
+    }
+
+    fn prune(&mut self, before: u64) -> io::Result<usize> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(to_io)?;
+        let before = to_sql_epoch(before);
+        tx.execute(
+            "DELETE FROM processes WHERE time_ms < ?1",
+            params![before.saturating_mul(1000)],
+        )
+        .map_err(to_io)?;
+        let deleted = tx
+            .execute(
+                "DELETE FROM snapshots WHERE time_epoch < ?1",
+                params![before],
+            )
+            .map_err(to_io)?;
+        tx.commit().map_err(to_io)?;
+        Ok(deleted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sqlite_backend() {
+        let path = std::env::temp_dir().join(format!("procshot_sqlite_{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut backend = SqliteBackend::open(&path).unwrap();
+        let snapshot = EncoDecode {
+            hostname: "web-1".to_string(),
+            time_epoch: 120,
+            delay: Duration::from_secs(60),
+            total_cpu_time: 2000,
+            per_cpu: vec![(3, crate::cpu::CpuTimes::parse("cpu3 1 2 3 4").unwrap())]
+                .into_iter()
+                .collect(),
+            system_memory: crate::meminfo::MemInfo {
+                total_kb: 16314140,
+                ..Default::default()
+            },
+            load_average: crate::load::LoadAvg {
+                one: 0.52,
+                tasks: 612,
+                ..Default::default()
+            },
+            uptime: Duration::from_millis(350735470),
+            disks: crate::diskstats::parse(
+                "8 0 sda 4160 1207 345218 1722 7581 8853 380514 10238 0 8508 12652",
+            ),
+            interfaces: crate::netdev::parse(
+                "eth0: 1296000 2400 0 3 0 0 0 0 512000 1600 1 0 0 0 0 0",
+            ),
+            ..Default::default()
+        };
+        backend.write_snapshot(&snapshot).unwrap();
+        assert_eq!(backend.read_snapshot(120).unwrap(), snapshot);
+        assert_eq!(backend.list_range(0, u64::MAX).unwrap(), vec![120]);
+        // A second snapshot within the same second is kept, only the first one is read back.
+        let later = EncoDecode {
+            total_cpu_time: 2001,
+            ..snapshot.clone()
+        };
+        backend
+            .write_snapshot_at(&later, Duration::from_millis(120_500))
+            .unwrap();
+        assert_eq!(backend.list_range(0, u64::MAX).unwrap(), vec![120]);
+        assert_eq!(backend.read_snapshot(120).unwrap(), snapshot);
+        assert_eq!(
+            backend.read_snapshot(60).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        drop(backend);
+        // Opening an existing database leaves it as it is.
+        let mut backend = SqliteBackend::open(&path).unwrap();
+        assert_eq!(backend.prune(180).unwrap(), 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,242 @@
+//! Comparison of the storage formats on the snapshots of an archive.
+//!
+//! How well a format and a compression do depends on the processes a host runs: long command
+//! lines compress well, many short lived processes don't. `run` re-encodes a sample of the
+//! snapshots of a datadir in every combination this build supports and measures the size and
+//! the time it takes to encode and to decode them, so the settings of the server can be chosen
+//! on the actual workload:
+//!
+//! * `bincode` and `json`, the encodings of a single snapshot, with each `CompressionMode`. They
+//!   are encoded and decoded in memory, as the server and `store::read_snapshot` do.
+//! * `sqlite` and `parquet`, the archive formats of `convert`, when built with their features.
+//!   The sample is written to a temporary file, and read back for sqlite. Parquet archives can
+//!   only be written.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::convert::{self, Format};
+use crate::store::{self, CompressionMode};
+use crate::units::ByteFormat;
+use crate::EncoDecode;
+
+/// Number of snapshots re-encoded when `--sample` is not given.
+pub const DEFAULT_SAMPLE: usize = 20;
+
+/// BenchJob describes one run, as given to the `bench-format` subcommand.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchJob {
+    /// Datadir the snapshots are taken from, the `--datadir` of the server if None.
+    pub datadir: Option<PathBuf>,
+    /// Number of snapshots, spread evenly over the archive.
+    pub sample: usize,
+}
+
+/// BenchResult is the outcome of one combination on the whole sample.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchResult {
+    pub format: Format,
+    pub compression: CompressionMode,
+    /// Size of the encoded sample.
+    pub bytes: u64,
+    pub encode: Duration,
+    /// None for the formats that can only be written.
+    pub decode: Option<Duration>,
+}
+
+/// Reads `n` snapshots of `datadir`, spread evenly from the oldest to the newest. Snapshots that
+/// can't be read are skipped with a warning.
+pub fn sample(datadir: &Path, n: usize) -> io::Result<Vec<EncoDecode>> {
+    let files = store::snapshot_files(datadir)?;
+    let n = n.min(files.len());
+    let snapshots: Vec<EncoDecode> = (0..n)
+        .map(|i| &files[i * files.len() / n].1)
+        .filter_map(|path| match store::read_snapshot(path) {
+            Ok(s) => Some(s),
+            Err(e) => {
+                eprintln!("Skipping {}: {}", path.display(), e);
+                None
+            }
+        })
+        .collect();
+    match snapshots.is_empty() {
+        true => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no readable snapshot in {}", datadir.display()),
+        )),
+        false => Ok(snapshots),
+    }
+}
+
+/// Measures every combination supported by this build on `snapshots`.
+pub fn run(snapshots: &[EncoDecode]) -> io::Result<Vec<BenchResult>> {
+    let mut compressions = vec![CompressionMode::None, CompressionMode::Gzip];
+    if cfg!(feature = "zstd") {
+        compressions.push(CompressionMode::Zstd);
+    }
+    let mut results = Vec::new();
+    for format in [Format::Bincode, Format::Json] {
+        for compression in &compressions {
+            results.push(bench_encoding(format, *compression, snapshots)?);
+        }
+    }
+    if cfg!(feature = "sqlite") {
+        results.push(bench_archive(Format::Sqlite, snapshots)?);
+    }
+    if cfg!(feature = "parquet") {
+        results.push(bench_archive(Format::Parquet, snapshots)?);
+    }
+    Ok(results)
+}
+
+fn encode(format: Format, snapshot: &EncoDecode) -> io::Result<Vec<u8>> {
+    match format {
+        Format::Json => Ok(serde_json::to_vec(snapshot)?),
+        _ => bincode::serialize(snapshot).map_err(io::Error::other),
+    }
+}
+
+fn bench_encoding(
+    format: Format,
+    compression: CompressionMode,
+    snapshots: &[EncoDecode],
+) -> io::Result<BenchResult> {
+    let start = Instant::now();
+    let encoded = snapshots
+        .iter()
+        .map(|s| compression.compress(encode(format, s)?))
+        .collect::<io::Result<Vec<Vec<u8>>>>()?;
+    let encode = start.elapsed();
+    let bytes = encoded.iter().map(|e| e.len() as u64).sum();
+    let start = Instant::now();
+    for data in encoded {
+        store::decode_snapshot(&store::decompress(data)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    }
+    Ok(BenchResult {
+        format,
+        compression,
+        bytes,
+        encode,
+        decode: Some(start.elapsed()),
+    })
+}
+
+fn bench_archive(format: Format, snapshots: &[EncoDecode]) -> io::Result<BenchResult> {
+    let dst =
+        std::env::temp_dir().join(format!("procshot_bench_{}.{}", std::process::id(), format));
+    let _ = fs::remove_file(&dst);
+    let result = write_and_read(format, snapshots, &dst);
+    let _ = fs::remove_file(&dst);
+    result
+}
+
+fn write_and_read(format: Format, snapshots: &[EncoDecode], dst: &Path) -> io::Result<BenchResult> {
+    let start = Instant::now();
+    let mut writer = convert::create_writer(format, dst)?;
+    for snapshot in snapshots {
+        writer.write(snapshot)?;
+    }
+    writer.finish()?;
+    let encode = start.elapsed();
+    let decode = match format {
+        Format::Parquet => None,
+        _ => {
+            let start = Instant::now();
+            for snapshot in convert::open_source(format, dst, 0)?.1 {
+                snapshot?;
+            }
+            Some(start.elapsed())
+        }
+    };
+    Ok(BenchResult {
+        format,
+        compression: CompressionMode::None,
+        bytes: fs::metadata(dst)?.len(),
+        encode,
+        decode,
+    })
+}
+
+/// Formats the results as a table, with the size and times per snapshot and the size relative
+/// to uncompressed bincode, which the server writes by default.
+pub fn render(results: &[BenchResult], snapshots: usize, format: &ByteFormat) -> String {
+    let baseline = results
+        .iter()
+        .find(|r| r.format == Format::Bincode && r.compression == CompressionMode::None)
+        .map_or(0, |r| r.bytes);
+    let per_snapshot = |d: Duration| format!("{:.2}", d.as_secs_f64() * 1000.0 / snapshots as f64);
+    let mut out = format!(
+        "{:<8} {:<11} {:>12} {:>7} {:>10} {:>10}\n",
+        "FORMAT", "COMPRESSION", "SIZE", "RATIO", "ENCODE_MS", "DECODE_MS"
+    );
+    for r in results {
+        out.push_str(&format!(
+            "{:<8} {:<11} {:>12} {:>7} {:>10} {:>10}\n",
+            r.format.to_string(),
+            r.compression.to_string(),
+            format.bytes(r.bytes / snapshots as u64),
+            match baseline {
+                0 => "-".to_string(),
+                b => format!("{:.2}", r.bytes as f64 / b as f64),
+            },
+            per_snapshot(r.encode),
+            r.decode.map_or("-".to_string(), per_snapshot),
+        ));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{DirBackend, StorageBackend};
+
+    #[test]
+    fn test_run() {
+        let dir =
+            std::env::temp_dir().join(format!("procshot_bench_format_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let mut backend = DirBackend::new(&dir, false);
+        for epoch in 1..=10 {
+            let mut labels = std::collections::BTreeMap::new();
+            labels.insert("role".to_string(), "db".repeat(epoch as usize));
+            backend
+                .write_snapshot(&EncoDecode {
+                    hostname: "localghost".to_string(),
+                    time_epoch: 1563617600 + epoch,
+                    delay: Duration::from_secs(1),
+                    labels,
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+        let snapshots = sample(&dir, 4).unwrap();
+        let epochs: Vec<u64> = snapshots
+            .iter()
+            .map(|s| s.time_epoch - 1563617600)
+            .collect();
+        assert_eq!(epochs, vec![1, 3, 6, 8]);
+
+        let results = run(&snapshots).unwrap();
+        assert!(results.len() >= 4);
+        let bincode = &results[0];
+        assert_eq!(
+            (bincode.format, bincode.compression),
+            (Format::Bincode, CompressionMode::None)
+        );
+        assert!(results
+            .iter()
+            .filter(|r| r.format != Format::Parquet)
+            .all(|r| r.decode.is_some()));
+        let table = render(&results, snapshots.len(), &ByteFormat::default());
+        assert!(table.lines().nth(1).unwrap().contains(" 1.00 "));
+        assert_eq!(table.lines().count(), results.len() + 1);
+
+        assert!(sample(&dir.join("missing"), 4).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,384 @@
+//! Helpers to limit scanning to the processes of one cgroup subtree.
+//!
+//! Instead of walking all of `/proc`, the server can read `cgroup.procs` of a cgroup and of all of
+//! its descendants, and only look at those pids. This keeps the overhead low on busy shared hosts
+//! where only one service or pod is of interest.
+//!
+//! The cgroups of a process are read from /proc/<pid>/cgroup into a `Membership`, whose `path` is
+//! recorded in `PidStatus::cgroup`, so that the processes of a container or a service can be
+//! grouped. The memory and CPU limits the process is subject to are found with `Limits::read`,
+//! on both cgroup v2 and v1 hierarchies.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "server")]
+use crate::collect::PROC_ROOT;
+use crate::Pid;
+
+/// Mount point of the cgroup filesystem.
+pub const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// Resolves a user supplied cgroup to a directory in the cgroup filesystem. Paths below
+/// `CGROUP_ROOT` are used as is, anything else is taken relative to it, so both
+/// `/sys/fs/cgroup/system.slice/nginx.service` and `/system.slice/nginx.service` (as shown in
+/// `/proc/<pid>/cgroup`) point to the same directory.
+pub fn resolve(cgroup: &Path) -> PathBuf {
+    if cgroup.starts_with(CGROUP_ROOT) {
+        cgroup.to_path_buf()
+    } else {
+        let relative = cgroup.strip_prefix("/").unwrap_or(cgroup);
+        Path::new(CGROUP_ROOT).join(relative)
+    }
+}
+
+/// Returns the pids of every process in the cgroup at `dir` and in all of its descendants. `dir`
+/// must be a directory in the cgroup filesystem, see `resolve`.
+pub fn pids_in_subtree(dir: &Path) -> io::Result<Vec<Pid>> {
+    let mut pids = Vec::new();
+    collect_pids(dir, &mut pids)?;
+    pids.sort_unstable();
+    pids.dedup();
+    Ok(pids)
+}
+
+fn collect_pids(dir: &Path, pids: &mut Vec<Pid>) -> io::Result<()> {
+    let procs = fs::read_to_string(dir.join("cgroup.procs"))?;
+    pids.extend(procs.lines().filter_map(|l| l.trim().parse::<Pid>().ok()));
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            // A child cgroup can vanish between read_dir and the read, which is not an error.
+            match collect_pids(&entry.path(), pids) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                other => other?,
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns the processes of the cgroup subtree at `dir`. Processes that exit before they can be
+/// read are skipped, as `procfs::all_processes` does.
+#[cfg(feature = "server")]
+pub fn processes_in_subtree(dir: &Path) -> io::Result<Vec<procfs::Process>> {
+    Ok(pids_in_subtree(dir)?
+        .into_iter()
+        .filter_map(|pid| procfs::Process::new(pid.as_raw()).ok())
+        .collect())
+}
+
+/// Returns the cgroup v2 directory of a process, from the `0::<path>` line of /proc/<pid>/cgroup.
+#[cfg(feature = "server")]
+pub fn of_pid(pid: Pid) -> io::Result<PathBuf> {
+    of_pid_in(Path::new(PROC_ROOT), pid)
+}
+
+/// Same as `of_pid`, for the proc filesystem at `proc_root`.
+pub fn of_pid_in(proc_root: &Path, pid: Pid) -> io::Result<PathBuf> {
+    let content = fs::read_to_string(proc_root.join(pid.to_string()).join("cgroup"))?;
+    content
+        .lines()
+        .find_map(|l| l.strip_prefix("0::"))
+        .map(|path| resolve(Path::new(path)))
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("pid {} is not in a cgroup v2 hierarchy", pid),
+            )
+        })
+}
+
+/// Memory limits at or above this are how cgroup v1 writes that there is none.
+const V1_UNLIMITED: u64 = 1 << 62;
+
+/// Returns `dir` and its ancestors below `root`, `root` excluded.
+fn ancestors_below<'a>(dir: &'a Path, root: &'a Path) -> impl Iterator<Item = &'a Path> {
+    dir.ancestors()
+        .take_while(move |d| d.starts_with(root) && *d != root)
+}
+
+/// Returns the effective `memory.max` of the cgroup at `dir`: the lowest limit of `dir` and of its
+/// ancestors below `root`, or None if none of them is limited.
+pub fn memory_max(dir: &Path, root: &Path) -> Option<u64> {
+    ancestors_below(dir, root)
+        .filter_map(|d| fs::read_to_string(d.join("memory.max")).ok())
+        .filter_map(|max| max.trim().parse::<u64>().ok())
+        .min()
+}
+
+/// Parses a `cpu.max` file, eg: `150000 100000` for a quota of 150ms of CPU time every 100ms,
+/// and returns the quota in CPUs. None for `max`, which is no quota.
+pub fn parse_cpu_max(content: &str) -> Option<f64> {
+    let (quota, period) = content.trim().split_once(' ')?;
+    let (quota, period) = (quota.parse::<u64>().ok()?, period.parse::<u64>().ok()?);
+    Some(quota as f64 / period as f64).filter(|_| period > 0)
+}
+
+/// Returns the effective CPU quota of the cgroup at `dir`, in CPUs: the lowest `cpu.max` of `dir`
+/// and of its ancestors below `root`, or None if none of them has a quota.
+pub fn cpu_max(dir: &Path, root: &Path) -> Option<f64> {
+    ancestors_below(dir, root)
+        .filter_map(|d| fs::read_to_string(d.join("cpu.max")).ok())
+        .filter_map(|max| parse_cpu_max(&max))
+        .min_by(f64::total_cmp)
+}
+
+/// Same as `memory_max`, for the cgroup v1 memory hierarchy mounted at `root`.
+pub fn memory_max_v1(dir: &Path, root: &Path) -> Option<u64> {
+    ancestors_below(dir, root)
+        .filter_map(|d| fs::read_to_string(d.join("memory.limit_in_bytes")).ok())
+        .filter_map(|max| max.trim().parse::<u64>().ok())
+        .filter(|max| *max < V1_UNLIMITED)
+        .min()
+}
+
+/// Same as `cpu_max`, for the cgroup v1 cpu hierarchy mounted at `root`, from `cpu.cfs_quota_us`
+/// over `cpu.cfs_period_us`. A quota of -1 is no quota.
+pub fn cpu_max_v1(dir: &Path, root: &Path) -> Option<f64> {
+    let read = |d: &Path, file: &str| -> Option<i64> {
+        fs::read_to_string(d.join(file)).ok()?.trim().parse().ok()
+    };
+    ancestors_below(dir, root)
+        .filter_map(|d| Some((read(d, "cpu.cfs_quota_us")?, read(d, "cpu.cfs_period_us")?)))
+        .filter(|(quota, period)| *quota > 0 && *period > 0)
+        .map(|(quota, period)| quota as f64 / period as f64)
+        .min_by(f64::total_cmp)
+}
+
+/// Returns the directory of the cgroup at `path`, as shown in /proc/<pid>/cgroup, in the
+/// hierarchy mounted at `root`.
+fn dir_in(root: &Path, path: &str) -> PathBuf {
+    root.join(path.trim_start_matches('/'))
+}
+
+/// Membership holds the cgroups of a process, from /proc/<pid>/cgroup.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Membership {
+    /// Path in the cgroup v2 hierarchy, from the `0::<path>` line. None on hosts with only v1
+    /// hierarchies.
+    pub unified: Option<String>,
+    /// Path in each cgroup v1 hierarchy, by controller, eg: `memory` or `cpu`. The controllers
+    /// of a hierarchy mounted with several, eg: `cpu,cpuacct`, are each listed.
+    pub controllers: BTreeMap<String, String>,
+}
+
+impl Membership {
+    /// Parses the content of /proc/<pid>/cgroup, eg: `0::/system.slice/nginx.service` on cgroup
+    /// v2, or lines like `4:memory:/docker/3f2a` on v1. Malformed lines are skipped.
+    pub fn parse(content: &str) -> Membership {
+        let mut membership = Membership::default();
+        for line in content.lines() {
+            let mut fields = line.splitn(3, ':');
+            let (id, controllers, path) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(id), Some(controllers), Some(path)) => (id, controllers, path),
+                _ => continue,
+            };
+            if id == "0" && controllers.is_empty() {
+                membership.unified = Some(path.to_string());
+                continue;
+            }
+            for controller in controllers.split(',').filter(|c| !c.is_empty()) {
+                membership
+                    .controllers
+                    .insert(controller.to_string(), path.to_string());
+            }
+        }
+        membership
+    }
+
+    /// Reads the cgroups of a process of the proc filesystem at `proc_root`.
+    pub fn read_in(proc_root: &Path, pid: Pid) -> io::Result<Membership> {
+        let content = fs::read_to_string(proc_root.join(pid.to_string()).join("cgroup"))?;
+        Ok(Membership::parse(&content))
+    }
+
+    /// Returns the path the process is best known by: its cgroup v2 one, or on v1 hosts the one
+    /// of its memory, cpu or systemd hierarchy, in that order. None if it is in none of them.
+    pub fn path(&self) -> Option<&str> {
+        self.unified.as_deref().or_else(|| {
+            ["memory", "cpu", "name=systemd"]
+                .iter()
+                .find_map(|c| self.controllers.get(*c).map(String::as_str))
+        })
+    }
+}
+
+/// Limits holds the effective limits of the cgroup of a process: the lowest ones of the cgroup and
+/// of its ancestors.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Copy, Default)]
+pub struct Limits {
+    /// Memory limit in bytes, from `memory.max`, or `memory.limit_in_bytes` on cgroup v1.
+    pub memory_max: Option<u64>,
+    /// CPU quota in CPUs, eg: 1.5 for 150ms of CPU time every 100ms, from `cpu.max`, or
+    /// `cpu.cfs_quota_us` and `cpu.cfs_period_us` on cgroup v1.
+    pub cpus: Option<f64>,
+}
+
+impl Limits {
+    /// Reads the limits of the cgroups of `membership` in the cgroup filesystem mounted at `root`.
+    /// A limit missing from the v2 hierarchy, eg: on hybrid hosts where the controllers are still
+    /// on v1, is read from the v1 one.
+    pub fn read(membership: &Membership, root: &Path) -> Limits {
+        let unified = membership.unified.as_deref().map(|p| dir_in(root, p));
+        // The directory of the cgroup in the v1 hierarchy of a controller, and the hierarchy.
+        let v1 = |controller: &str| {
+            let hierarchy = root.join(controller);
+            let path = membership.controllers.get(controller)?;
+            Some((dir_in(&hierarchy, path), hierarchy))
+        };
+        Limits {
+            memory_max: unified
+                .as_ref()
+                .and_then(|dir| memory_max(dir, root))
+                .or_else(|| v1("memory").and_then(|(dir, root)| memory_max_v1(&dir, &root))),
+            cpus: unified
+                .as_ref()
+                .and_then(|dir| cpu_max(dir, root))
+                .or_else(|| v1("cpu").and_then(|(dir, root)| cpu_max_v1(&dir, &root))),
+        }
+    }
+}
+
+/// LimitsCache caches the limits of each cgroup, so processes sharing a cgroup only cost one walk
+/// of the hierarchy. Limits can change at any time, so a new cache should be used for each
+/// iteration.
+#[derive(Debug)]
+pub struct LimitsCache {
+    root: PathBuf,
+    limits: HashMap<Membership, Limits>,
+}
+
+impl Default for LimitsCache {
+    fn default() -> Self {
+        LimitsCache::new(Path::new(CGROUP_ROOT))
+    }
+}
+
+impl LimitsCache {
+    /// Reads the limits from the cgroup filesystem mounted at `root`.
+    pub fn new(root: &Path) -> Self {
+        LimitsCache {
+            root: root.to_path_buf(),
+            limits: HashMap::new(),
+        }
+    }
+
+    /// Returns the limits of the cgroups of `membership`.
+    pub fn of(&mut self, membership: &Membership) -> Limits {
+        if let Some(limits) = self.limits.get(membership) {
+            return *limits;
+        }
+        let limits = Limits::read(membership, &self.root);
+        self.limits.insert(membership.clone(), limits);
+        limits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        assert_eq!(
+            resolve(Path::new("/system.slice/nginx.service")),
+            PathBuf::from("/sys/fs/cgroup/system.slice/nginx.service")
+        );
+        assert_eq!(
+            resolve(Path::new("/sys/fs/cgroup/user.slice")),
+            PathBuf::from("/sys/fs/cgroup/user.slice")
+        );
+    }
+
+    #[test]
+    fn test_pids_in_subtree() {
+        let root = std::env::temp_dir().join(format!("procshot_cgroup_{}", std::process::id()));
+        let child = root.join("child");
+        fs::create_dir_all(&child).unwrap();
+        fs::write(root.join("cgroup.procs"), "10\n3\n").unwrap();
+        fs::write(child.join("cgroup.procs"), "7\n3\n").unwrap();
+        let pids = pids_in_subtree(&root).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(pids, vec![Pid::new(3), Pid::new(7), Pid::new(10)]);
+    }
+
+    #[test]
+    fn test_memory_max() {
+        let root = std::env::temp_dir().join(format!("procshot_memmax_{}", std::process::id()));
+        let child = root.join("parent/child");
+        fs::create_dir_all(&child).unwrap();
+        fs::write(root.join("memory.max"), "1\n").unwrap();
+        fs::write(root.join("parent/memory.max"), "4096\n").unwrap();
+        fs::write(child.join("memory.max"), "max\n").unwrap();
+        let max = memory_max(&child, &root);
+        let unlimited = memory_max(&root.join("parent/other"), &root.join("parent"));
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(max, Some(4096));
+        assert_eq!(unlimited, None);
+    }
+
+    #[test]
+    fn test_membership() {
+        let v2 = Membership::parse("0::/system.slice/nginx.service\n");
+        assert_eq!(v2.path(), Some("/system.slice/nginx.service"));
+        assert!(v2.controllers.is_empty());
+        let v1 = Membership::parse(
+            "12:pids:/docker/3f2a\n4:cpu,cpuacct:/docker/3f2a\n1:name=systemd:/docker/3f2a\nx\n",
+        );
+        assert_eq!(v1.unified, None);
+        assert_eq!(v1.controllers["cpuacct"], "/docker/3f2a");
+        assert_eq!(v1.path(), Some("/docker/3f2a"));
+        assert_eq!(Membership::parse("").path(), None);
+        assert_eq!(parse_cpu_max("150000 100000\n"), Some(1.5));
+        assert_eq!(parse_cpu_max("max 100000\n"), None);
+    }
+
+    #[test]
+    fn test_limits() {
+        let root = std::env::temp_dir().join(format!("procshot_limits_{}", std::process::id()));
+        let service = root.join("system.slice/nginx.service");
+        let v1 = root.join("memory/docker/3f2a");
+        let cpu = root.join("cpu/docker/3f2a");
+        for dir in [&service, &v1, &cpu] {
+            fs::create_dir_all(dir).unwrap();
+        }
+        fs::write(root.join("system.slice/cpu.max"), "200000 100000\n").unwrap();
+        fs::write(service.join("cpu.max"), "50000 100000\n").unwrap();
+        fs::write(service.join("memory.max"), "max\n").unwrap();
+        fs::write(v1.join("memory.limit_in_bytes"), "9223372036854771712\n").unwrap();
+        fs::write(
+            v1.parent().unwrap().join("memory.limit_in_bytes"),
+            "1073741824\n",
+        )
+        .unwrap();
+        fs::write(cpu.join("cpu.cfs_quota_us"), "-1\n").unwrap();
+        fs::write(cpu.join("cpu.cfs_period_us"), "100000\n").unwrap();
+
+        let v2 = Membership::parse("0::/system.slice/nginx.service\n");
+        // On hybrid hosts, the limits not in the v2 hierarchy come from the v1 ones.
+        let hybrid = Membership::parse("4:memory:/docker/3f2a\n3:cpu:/docker/3f2a\n0::/docker\n");
+        let mut cache = LimitsCache::new(&root);
+        let (v2, hybrid) = (cache.of(&v2), cache.of(&hybrid));
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(
+            v2,
+            Limits {
+                memory_max: None,
+                cpus: Some(0.5),
+            }
+        );
+        assert_eq!(
+            hybrid,
+            Limits {
+                memory_max: Some(1073741824),
+                cpus: None,
+            }
+        );
+        // Cached, the files are gone.
+        let v2 = Membership::parse("0::/system.slice/nginx.service\n");
+        assert_eq!(cache.of(&v2).cpus, Some(0.5));
+    }
+}
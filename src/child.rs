@@ -0,0 +1,350 @@
+//! Supervised execution of the external commands configured by users.
+//!
+//! Post-write hooks and alert commands are arbitrary programs, and the server runs for months. A
+//! hook that hangs must not stall the scan loop, one that forks must not leak its children, and
+//! one that exits must not stay a zombie. `Supervisor` runs the commands in the background, each
+//! in its own process group, and a reaper thread polls them with `try_wait` (no SIGCHLD handler
+//! is installed, which could interfere with other children of an embedding program):
+//!
+//! * a command still running after `ChildLimits::timeout` has its whole process group killed,
+//! * processes a command left behind in its group when it exited are killed too,
+//! * at most `ChildLimits::max_running` commands run at once, further ones are refused,
+//! * stdout and stderr are captured up to `ChildLimits::output_limit` bytes each, and logged with
+//!   the exit status when a command fails.
+
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Interval at which the reaper thread polls the running commands.
+const REAP_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Time given to the output readers to drain the pipes of a command that exited.
+const DRAIN_GRACE: Duration = Duration::from_millis(200);
+
+/// Number of finished commands kept for `Supervisor::take_finished`.
+const MAX_FINISHED: usize = 64;
+
+/// ChildLimits bounds what a supervised command may do.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChildLimits {
+    /// A command running longer than this is killed, with its process group.
+    pub timeout: Duration,
+    /// Maximum number of commands running at once.
+    pub max_running: usize,
+    /// Bytes of stdout and of stderr kept per command. The rest is read and discarded.
+    pub output_limit: usize,
+}
+
+impl Default for ChildLimits {
+    fn default() -> Self {
+        ChildLimits {
+            timeout: Duration::from_secs(30),
+            max_running: 4,
+            output_limit: 64 << 10,
+        }
+    }
+}
+
+/// Outcome is how a supervised command ended.
+#[derive(Debug)]
+pub struct Outcome {
+    pub name: String,
+    /// The exit status, or the error waiting for the command.
+    pub status: io::Result<ExitStatus>,
+    /// True if the command was killed for running longer than the timeout.
+    pub timed_out: bool,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+impl Outcome {
+    pub fn is_success(&self) -> bool {
+        !self.timed_out && self.status.as_ref().map(|s| s.success()).unwrap_or(false)
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.status, self.timed_out) {
+            (_, true) => write!(f, "{} timed out and was killed", self.name)?,
+            (Ok(status), false) => write!(f, "{} exited with {}", self.name, status)?,
+            (Err(e), false) => write!(f, "{} could not be waited for: {}", self.name, e)?,
+        }
+        let stderr = String::from_utf8_lossy(&self.stderr);
+        if !stderr.trim().is_empty() {
+            write!(f, ", stderr: {}", stderr.trim_end())?;
+        }
+        Ok(())
+    }
+}
+
+struct Running {
+    name: String,
+    child: Child,
+    deadline: Instant,
+    stdout: Capture,
+    stderr: Capture,
+}
+
+impl Running {
+    /// Kills the process group of the command. The group id is the pid of the command, see
+    /// `Supervisor::spawn`.
+    fn kill_group(&mut self) {
+        unsafe {
+            libc::kill(-(self.child.id() as i32), libc::SIGKILL);
+        }
+        let _ = self.child.kill();
+    }
+
+    fn finish(self, status: io::Result<ExitStatus>, timed_out: bool) -> Outcome {
+        let deadline = Instant::now() + DRAIN_GRACE;
+        Outcome {
+            name: self.name,
+            status,
+            timed_out,
+            stdout: self.stdout.take(deadline),
+            stderr: self.stderr.take(deadline),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    running: Vec<Running>,
+    finished: Vec<Outcome>,
+}
+
+impl Inner {
+    /// Collects the commands that exited and kills the ones past their deadline. Failures are
+    /// logged.
+    fn reap(&mut self) {
+        let now = Instant::now();
+        let mut i = 0;
+        while i < self.running.len() {
+            let r = &mut self.running[i];
+            let (status, timed_out) = match r.child.try_wait() {
+                Ok(Some(status)) => {
+                    // Background processes the command started must not outlive it.
+                    unsafe {
+                        libc::kill(-(r.child.id() as i32), libc::SIGKILL);
+                    }
+                    (Ok(status), false)
+                }
+                Ok(None) if now < r.deadline => {
+                    i += 1;
+                    continue;
+                }
+                Ok(None) => {
+                    r.kill_group();
+                    (r.child.wait(), true)
+                }
+                Err(e) => {
+                    r.kill_group();
+                    let _ = r.child.wait();
+                    (Err(e), false)
+                }
+            };
+            let outcome = self.running.swap_remove(i).finish(status, timed_out);
+            if !outcome.is_success() {
+                eprintln!("Command failed: {}", outcome);
+            }
+            if self.finished.len() == MAX_FINISHED {
+                self.finished.remove(0);
+            }
+            self.finished.push(outcome);
+        }
+    }
+}
+
+/// Supervisor runs commands in the background within `ChildLimits`.
+pub struct Supervisor {
+    limits: ChildLimits,
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl fmt::Debug for Supervisor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Supervisor")
+            .field("limits", &self.limits)
+            .field("running", &self.running())
+            .finish()
+    }
+}
+
+impl Supervisor {
+    /// Returns a supervisor and starts its reaper thread, which stops once the supervisor is
+    /// dropped.
+    pub fn new(limits: ChildLimits) -> Self {
+        let inner = Arc::new(Mutex::new(Inner::default()));
+        let weak: Weak<Mutex<Inner>> = Arc::downgrade(&inner);
+        thread::spawn(move || loop {
+            thread::sleep(REAP_INTERVAL);
+            match weak.upgrade() {
+                Some(inner) => inner.lock().unwrap().reap(),
+                None => break,
+            }
+        });
+        Supervisor { limits, inner }
+    }
+
+    /// Starts `command` under the name `name`, writing `stdin` to it. stdin, stdout and stderr
+    /// of `command` are replaced by pipes. Fails with `WouldBlock` if `max_running` commands are
+    /// already running.
+    pub fn spawn(&self, name: &str, command: &mut Command, stdin: Vec<u8>) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.reap();
+        if inner.running.len() >= self.limits.max_running {
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                format!(
+                    "{} commands are still running, not starting {}",
+                    inner.running.len(),
+                    name
+                ),
+            ));
+        }
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .process_group(0)
+            .spawn()?;
+        // Fed from a thread, a command that doesn't read its stdin can't block the server.
+        if let Some(mut pipe) = child.stdin.take() {
+            thread::spawn(move || {
+                // A command that doesn't care about its input may exit without reading it.
+                let _ = pipe.write_all(&stdin);
+            });
+        }
+        let limit = self.limits.output_limit;
+        let stdout = capture(child.stdout.take(), limit);
+        let stderr = capture(child.stderr.take(), limit);
+        inner.running.push(Running {
+            name: name.to_string(),
+            child,
+            deadline: Instant::now() + self.limits.timeout,
+            stdout,
+            stderr,
+        });
+        Ok(())
+    }
+
+    /// Number of commands still running.
+    pub fn running(&self) -> usize {
+        self.inner.lock().unwrap().running.len()
+    }
+
+    /// Returns the commands that finished since the last call, the oldest first.
+    pub fn take_finished(&self) -> Vec<Outcome> {
+        std::mem::take(&mut self.inner.lock().unwrap().finished)
+    }
+
+    /// Blocks until every running command exited or was killed, and returns the finished ones.
+    pub fn wait_all(&self) -> Vec<Outcome> {
+        while self.running() > 0 {
+            thread::sleep(REAP_INTERVAL);
+            self.inner.lock().unwrap().reap();
+        }
+        self.take_finished()
+    }
+}
+
+impl Drop for Supervisor {
+    /// Commands still running when the supervisor goes away are killed and reaped, so they
+    /// don't outlive the server.
+    fn drop(&mut self) {
+        let mut inner = self.inner.lock().unwrap();
+        for mut r in inner.running.drain(..) {
+            r.kill_group();
+            let _ = r.child.wait();
+        }
+    }
+}
+
+/// Splits `command_line` on whitespace into the program and its arguments. No shell is
+/// involved, `sh -c` has to be used explicitly if one is needed.
+pub fn split_command_line(command_line: &str) -> io::Result<(String, Vec<String>)> {
+    let mut words = command_line.split_whitespace().map(|w| w.to_string());
+    let program = words
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "empty command"))?;
+    Ok((program, words.collect()))
+}
+
+/// Capture is the output of a command, read from its pipe by a thread.
+struct Capture {
+    kept: Arc<Mutex<Vec<u8>>>,
+    reader: Option<JoinHandle<()>>,
+}
+
+impl Capture {
+    /// Returns the output once the pipe is closed, or what was read by `deadline`: a process that
+    /// escaped the process group may hold the pipe open forever.
+    fn take(self, deadline: Instant) -> Vec<u8> {
+        if let Some(reader) = &self.reader {
+            while !reader.is_finished() && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(5));
+            }
+        }
+        std::mem::take(&mut *self.kept.lock().unwrap())
+    }
+}
+
+/// Reads `pipe` to its end in a thread, keeping the first `limit` bytes.
+fn capture<R: Read + Send + 'static>(pipe: Option<R>, limit: usize) -> Capture {
+    let kept = Arc::new(Mutex::new(Vec::new()));
+    let buffer = Arc::clone(&kept);
+    let reader = pipe.map(|mut pipe| {
+        thread::spawn(move || {
+            let mut buf = [0u8; 8192];
+            while let Ok(n) = pipe.read(&mut buf) {
+                if n == 0 {
+                    break;
+                }
+                let mut kept = buffer.lock().unwrap();
+                let room = limit.saturating_sub(kept.len());
+                kept.extend_from_slice(&buf[..n.min(room)]);
+            }
+        })
+    });
+    Capture { kept, reader }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_supervisor() {
+        let supervisor = Supervisor::new(ChildLimits {
+            timeout: Duration::from_millis(300),
+            max_running: 2,
+            output_limit: 4,
+        });
+        supervisor
+            .spawn("cat", &mut Command::new("cat"), b"hello".to_vec())
+            .unwrap();
+        supervisor
+            .spawn("sleep", Command::new("sleep").arg("10"), Vec::new())
+            .unwrap();
+        // The limit is reached until cat exits, which may already be the case.
+        let third = supervisor.spawn("true", &mut Command::new("true"), Vec::new());
+        assert!(third.is_ok() || third.unwrap_err().kind() == io::ErrorKind::WouldBlock);
+        let started = Instant::now();
+        let outcomes = supervisor.wait_all();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        let cat = outcomes.iter().find(|o| o.name == "cat").unwrap();
+        assert!(cat.is_success());
+        assert_eq!(cat.stdout, b"hell");
+        let sleep = outcomes.iter().find(|o| o.name == "sleep").unwrap();
+        assert!(sleep.timed_out);
+        assert!(!sleep.is_success());
+    }
+}
@@ -0,0 +1,407 @@
+//! Reading and displaying the stored snapshots, when procshot runs without a subcommand.
+//!
+//! `procshot` alone prints the processes of the latest snapshot of the datadir as a table. `-t`
+//! picks another one: the first snapshot taken at or after a time, or every snapshot of a
+//! `<from>..<to>` range, one table each, see `TimeFrom`. `-o` sorts the processes by memory (the default) or by
+//! CPU usage.
+//!
+//! Every table after the first of a range is followed by the rates of the network interfaces
+//! since the one before, see `interface_rates`.
+//!
+//! `procshot top` ranks the processes instead, over every snapshot of the range, see `TopJob`.
+
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::query;
+use crate::report::{self, ReportFormat, TimeRange};
+use crate::store;
+use crate::tz::{TimeError, TimeZone};
+use crate::units::ByteFormat;
+use crate::{EncoDecode, Pid, PidStatus};
+
+/// SortBy is the order of the processes of a table, the heaviest first.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum SortBy {
+    /// By rss.
+    #[default]
+    Memory,
+    /// By user and system CPU usage.
+    Cpu,
+}
+
+impl SortBy {
+    fn key(self, status: &PidStatus) -> f64 {
+        match self {
+            SortBy::Memory => status.rss_bytes as f64,
+            SortBy::Cpu => status.user_cpu_usage + status.sys_cpu_usage,
+        }
+    }
+}
+
+impl fmt::Display for SortBy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SortBy::Memory => f.write_str("m"),
+            SortBy::Cpu => f.write_str("c"),
+        }
+    }
+}
+
+impl FromStr for SortBy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "m" | "mem" | "memory" | "rss" => Ok(SortBy::Memory),
+            "c" | "cpu" => Ok(SortBy::Cpu),
+            _ => Err(format!(
+                "unknown sort order {}, expected m (memory) or c (cpu)",
+                s
+            )),
+        }
+    }
+}
+
+/// TimeFrom is the selection of the `-t` option.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum TimeFrom {
+    /// The latest snapshot, without `-t`.
+    #[default]
+    Latest,
+    /// The first snapshot taken at or after the epoch.
+    At(u64),
+    /// Every snapshot of the range.
+    Range(TimeRange),
+}
+
+impl TimeFrom {
+    /// Parses the value of `-t`: empty, a time or a `<from>..<to>` range of times accepted by
+    /// `TimeZone::parse_at`, in the time zone `tz`, `now` being the current epoch.
+    pub fn parse(s: &str, tz: &TimeZone, now: u64) -> Result<Self, TimeError> {
+        match s.trim() {
+            "" => Ok(TimeFrom::Latest),
+            s if s.contains("..") => TimeRange::parse(s, tz).map(TimeFrom::Range),
+            s => tz.parse_at(s, now).map(TimeFrom::At),
+        }
+    }
+}
+
+/// TopJob describes one run of the `top` subcommand.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopJob {
+    /// The snapshots ranked, the latest one by default.
+    pub time_from: TimeFrom,
+    pub sort_by: SortBy,
+    /// Number of processes listed.
+    pub top: usize,
+    pub format: ReportFormat,
+}
+
+/// Prints the heaviest processes of the snapshots selected by the job, see `report::top_n`.
+pub fn run_top(
+    datadir: &Path,
+    job: &TopJob,
+    workers: usize,
+    format: &ByteFormat,
+    tz: &TimeZone,
+) -> io::Result<()> {
+    let range = select_range(datadir, &job.time_from)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let top = report::top_n(datadir, range, job.sort_by, job.top, workers)?;
+    match job.format {
+        ReportFormat::Table => report::print_top(&top, format, tz),
+        ReportFormat::Markdown => print!("{}", report::markdown_top(&top, format, tz)),
+    }
+    Ok(())
+}
+
+/// Returns the range of the snapshots of `datadir` selected by `time_from`, a single snapshot
+/// unless it is a range.
+pub fn select_range(datadir: &Path, time_from: &TimeFrom) -> Result<TimeRange, String> {
+    let from = match time_from {
+        TimeFrom::Range(range) => return Ok(*range),
+        TimeFrom::At(epoch) => Some(*epoch),
+        TimeFrom::Latest => None,
+    };
+    let files = store::snapshot_files(datadir)
+        .map_err(|e| format!("cannot list {}: {}", datadir.display(), e))?;
+    let epoch = match from {
+        None => files.last().map(|(epoch, _)| *epoch),
+        Some(from) => files.iter().map(|(epoch, _)| *epoch).find(|e| *e >= from),
+    };
+    match epoch {
+        Some(epoch) => Ok(TimeRange {
+            from: epoch,
+            to: epoch,
+        }),
+        None => Err(format!("no snapshot in {}", datadir.display())),
+    }
+}
+
+/// Reads the snapshots of `datadir` taken in `range` on `workers` threads, oldest first.
+/// Snapshots that can't be read are skipped with a warning.
+pub fn load_snapshots(
+    datadir: &Path,
+    range: &TimeRange,
+    workers: usize,
+) -> io::Result<Vec<EncoDecode>> {
+    let files = query::files_in_range(datadir, range.from, range.to)?;
+    Ok(query::par_map(&files, workers, |s| s.clone())
+        .into_iter()
+        .filter_map(|(epoch, snapshot)| match snapshot {
+            Ok(s) => Some(s),
+            Err(e) => {
+                eprintln!("Skipping the snapshot taken at {}: {}", epoch, e);
+                None
+            }
+        })
+        .collect())
+}
+
+/// Formats the processes of `snapshot` as a table sorted by `sort_by`, below a line with the
+/// time, the host and the totals of the snapshot.
+pub fn render_table(
+    snapshot: &EncoDecode,
+    sort_by: SortBy,
+    format: &ByteFormat,
+    tz: &TimeZone,
+) -> String {
+    let mut processes: Vec<(&Pid, &PidStatus)> = snapshot.pid_map_list.iter().collect();
+    processes.sort_by(|a, b| {
+        sort_by
+            .key(b.1)
+            .total_cmp(&sort_by.key(a.1))
+            .then(a.0.cmp(b.0))
+    });
+    let cpu: f64 = processes
+        .iter()
+        .map(|(_, s)| s.user_cpu_usage + s.sys_cpu_usage)
+        .sum();
+    let rss: i64 = processes.iter().map(|(_, s)| s.rss_bytes.max(0)).sum();
+    let mut out = format!(
+        "{} {}: {} processes, cpu {:.1}%, rss {}\n",
+        tz.format(snapshot.time_epoch),
+        snapshot.hostname,
+        processes.len(),
+        cpu,
+        format.bytes(rss as u64)
+    );
+    out.push_str(&format!(
+        "{:>8} {:>8} {:>6} {:>7} {:>10} {:>10} {:<5} COMMAND\n",
+        "PID", "PPID", "UID", "CPU%", "RSS", "VSZ", "STATE"
+    ));
+    for (pid, s) in processes {
+        let command = match s.cmd_long.is_empty() {
+            true => format!("[{}]", s.name),
+            false => s.cmd_long.join(" "),
+        };
+        out.push_str(&format!(
+            "{:>8} {:>8} {:>6} {:>7.1} {:>10} {:>10} {:<5} {}\n",
+            pid,
+            s.ppid,
+            s.euid,
+            s.user_cpu_usage + s.sys_cpu_usage,
+            format.bytes(s.rss_bytes.max(0) as u64),
+            s.vmsize.map_or("-".to_string(), |kib| format.kib(kib)),
+            s.state.split_whitespace().next().unwrap_or("-"),
+            command
+        ));
+    }
+    out
+}
+
+/// InterfaceRates are the rates of a network interface between two snapshots, per second, with
+/// the errors and drops in between.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InterfaceRates {
+    pub name: String,
+    pub rx_bytes_per_sec: f64,
+    pub rx_packets_per_sec: f64,
+    pub tx_bytes_per_sec: f64,
+    pub tx_packets_per_sec: f64,
+    /// Receive and transmit errors.
+    pub errors: u64,
+    /// Packets dropped, received or to transmit.
+    pub drops: u64,
+}
+
+/// Returns the rates of the network interfaces of `current` since `previous`, an earlier
+/// snapshot of the same host. Interfaces missing from `previous`, or whose counters went back
+/// because they were recreated, are left out, and so are all of them if no time passed between
+/// the two.
+pub fn interface_rates(previous: &EncoDecode, current: &EncoDecode) -> Vec<InterfaceRates> {
+    let elapsed = current.time_epoch.saturating_sub(previous.time_epoch);
+    if elapsed == 0 {
+        return Vec::new();
+    }
+    let delta = |now: u64, before: u64| now.checked_sub(before);
+    let per_sec = |now: u64, before: u64| Some(delta(now, before)? as f64 / elapsed as f64);
+    current
+        .interfaces
+        .iter()
+        .filter_map(|now| {
+            let before = previous.interfaces.iter().find(|i| i.name == now.name)?;
+            Some(InterfaceRates {
+                name: now.name.clone(),
+                rx_bytes_per_sec: per_sec(now.rx_bytes, before.rx_bytes)?,
+                rx_packets_per_sec: per_sec(now.rx_packets, before.rx_packets)?,
+                tx_bytes_per_sec: per_sec(now.tx_bytes, before.tx_bytes)?,
+                tx_packets_per_sec: per_sec(now.tx_packets, before.tx_packets)?,
+                errors: delta(
+                    now.rx_errors + now.tx_errors,
+                    before.rx_errors + before.tx_errors,
+                )?,
+                drops: delta(
+                    now.rx_drops + now.tx_drops,
+                    before.rx_drops + before.tx_drops,
+                )?,
+            })
+        })
+        .collect()
+}
+
+/// Formats the rates of the network interfaces as a table, or nothing if there are none.
+pub fn render_interfaces(rates: &[InterfaceRates], format: &ByteFormat) -> String {
+    if rates.is_empty() {
+        return String::new();
+    }
+    let mut out = format!(
+        "{:<12} {:>10} {:>9} {:>10} {:>9} {:>6} {:>6}\n",
+        "INTERFACE", "RX/s", "RXPKT/s", "TX/s", "TXPKT/s", "ERRORS", "DROPS"
+    );
+    for r in rates {
+        out.push_str(&format!(
+            "{:<12} {:>10} {:>9.0} {:>10} {:>9.0} {:>6} {:>6}\n",
+            r.name,
+            format.bytes(r.rx_bytes_per_sec.round() as u64),
+            r.rx_packets_per_sec,
+            format.bytes(r.tx_bytes_per_sec.round() as u64),
+            r.tx_packets_per_sec,
+            r.errors,
+            r.drops
+        ));
+    }
+    out
+}
+
+/// Prints the snapshots selected by `time_from`, sorted by `sort_by`, as the `-t` and `-o`
+/// options of the client.
+pub fn run(
+    datadir: &Path,
+    time_from: &TimeFrom,
+    sort_by: &str,
+    workers: usize,
+    format: &ByteFormat,
+    tz: &TimeZone,
+) -> io::Result<()> {
+    let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidInput, e);
+    let sort_by: SortBy = sort_by.parse().map_err(invalid)?;
+    let range = select_range(datadir, time_from).map_err(invalid)?;
+    let snapshots = load_snapshots(datadir, &range, workers)?;
+    for (i, snapshot) in snapshots.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        print!("{}", render_table(snapshot, sort_by, format, tz));
+        if i > 0 {
+            let rates = interface_rates(&snapshots[i - 1], snapshot);
+            print!("{}", render_interfaces(&rates, format));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{DirBackend, StorageBackend};
+    use std::fs;
+    use std::time::Duration;
+
+    fn status(name: &str, cpu: f64, rss_bytes: i64) -> PidStatus {
+        PidStatus {
+            ppid: Pid::new(1),
+            euid: 1000,
+            cmd_long: vec![format!("/usr/bin/{}", name), "--serve".to_string()],
+            name: name.to_string(),
+            cmd_short: name.to_string(),
+            fdsize: 64,
+            state: "S (sleeping)".to_string(),
+            vmpeak: Some(2048),
+            vmsize: Some(2048),
+            rss_pages: rss_bytes / 4096,
+            rss_bytes,
+            rsslim_bytes: u64::MAX,
+            processor_last_executed: Some(0),
+            user_cpu_usage: cpu,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_client() {
+        let dir = std::env::temp_dir().join(format!("procshot_client_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let mut backend = DirBackend::new(&dir, false);
+        for epoch in [60, 120, 180] {
+            let mut pids = std::collections::HashMap::new();
+            pids.insert(Pid::new(10), status("java", 5.0, 1 << 30));
+            pids.insert(Pid::new(20), status("nginx", 50.0, 1 << 20));
+            backend
+                .write_snapshot(&EncoDecode {
+                    hostname: "localghost".to_string(),
+                    pid_map_list: pids,
+                    time_epoch: epoch,
+                    delay: Duration::from_secs(60),
+                    interfaces: crate::netdev::parse(&format!(
+                        "eth0: {} {} 0 0 0 0 0 0 {} {} 0 0 0 0 0 0",
+                        epoch * 1000,
+                        epoch,
+                        epoch * 500,
+                        epoch / 2
+                    )),
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+        let tz = TimeZone::default();
+        let select = |t: &str| select_range(&dir, &TimeFrom::parse(t, &tz, 240).unwrap());
+        let latest = select("").unwrap();
+        assert_eq!((latest.from, latest.to), (180, 180));
+        let after = select("61").unwrap();
+        assert_eq!((after.from, after.to), (120, 120));
+        assert_eq!(select("2m ago").unwrap().from, 120);
+        assert!(select("1000").is_err());
+        assert!(TimeFrom::parse("tomorrow", &tz, 240).is_err());
+        let range = select("60..120").unwrap();
+        let snapshots = load_snapshots(&dir, &range, 2).unwrap();
+        assert_eq!(snapshots.len(), 2);
+        let rates = interface_rates(&snapshots[0], &snapshots[1]);
+        assert_eq!(rates.len(), 1);
+        assert_eq!(
+            (rates[0].rx_bytes_per_sec, rates[0].tx_packets_per_sec),
+            (1000.0, 0.5)
+        );
+        assert_eq!(
+            render_interfaces(&rates, &ByteFormat::default())
+                .lines()
+                .count(),
+            2
+        );
+        assert!(interface_rates(&snapshots[1], &snapshots[0]).is_empty());
+
+        let table = |sort_by| render_table(&snapshots[0], sort_by, &ByteFormat::default(), &tz);
+        let lines: Vec<String> = table(SortBy::Memory).lines().map(String::from).collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].contains("localghost: 2 processes, cpu 55.0%"));
+        assert!(lines[2].contains("/usr/bin/java --serve"));
+        assert!(table(SortBy::Cpu).lines().nth(2).unwrap().contains("nginx"));
+        assert_eq!("cpu".parse(), Ok(SortBy::Cpu));
+        assert!("pid".parse::<SortBy>().is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
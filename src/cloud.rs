@@ -0,0 +1,202 @@
+//! Labels from the cloud instance metadata.
+//!
+//! With `--cloud-metadata`, the server asks the instance metadata service of EC2, GCE and Azure
+//! at startup for the instance id, type and zone of the host, and stores them in the `labels` of
+//! every snapshot, so snapshots gathered from a fleet can be grouped by instance type without an
+//! inventory on the side. The lookup needs the `cloud` feature; the parsing of the replies is
+//! always available.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Label holding the provider, `ec2`, `gce` or `azure`.
+pub const LABEL_PROVIDER: &str = "cloud.provider";
+pub const LABEL_INSTANCE_ID: &str = "cloud.instance_id";
+pub const LABEL_INSTANCE_TYPE: &str = "cloud.instance_type";
+/// Label holding the availability zone, or the region on Azure hosts outside of a zone.
+pub const LABEL_ZONE: &str = "cloud.zone";
+
+/// Time given to each request to the metadata service. Hosts outside of a cloud don't answer at
+/// all, so this is how long they delay the startup.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Provider is a cloud whose metadata service is known.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Provider {
+    Ec2,
+    Gce,
+    Azure,
+}
+
+impl Provider {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Provider::Ec2 => "ec2",
+            Provider::Gce => "gce",
+            Provider::Azure => "azure",
+        }
+    }
+}
+
+/// InstanceMetadata is what the metadata service tells about the host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstanceMetadata {
+    pub provider: Provider,
+    pub instance_id: String,
+    pub instance_type: String,
+    pub zone: String,
+}
+
+impl InstanceMetadata {
+    /// Builds the metadata from the GCE replies, which give the machine type and zone as
+    /// `projects/<number>/machineTypes/<type>` and `projects/<number>/zones/<zone>`.
+    pub fn from_gce(instance_id: &str, machine_type: &str, zone: &str) -> Self {
+        let last = |s: &str| s.trim().rsplit('/').next().unwrap_or_default().to_string();
+        InstanceMetadata {
+            provider: Provider::Gce,
+            instance_id: instance_id.trim().to_string(),
+            instance_type: last(machine_type),
+            zone: last(zone),
+        }
+    }
+
+    /// Builds the metadata from the JSON reply of the Azure `metadata/instance/compute` endpoint.
+    /// Returns None if it isn't one.
+    pub fn from_azure(compute: &str) -> Option<Self> {
+        let compute: serde_json::Value = serde_json::from_str(compute).ok()?;
+        let field = |name: &str| {
+            compute
+                .get(name)
+                .and_then(|v| v.as_str())
+                .unwrap_or_default()
+        };
+        if field("vmId").is_empty() {
+            return None;
+        }
+        let zone = match field("zone") {
+            "" => field("location").to_string(),
+            zone => format!("{}-{}", field("location"), zone),
+        };
+        Some(InstanceMetadata {
+            provider: Provider::Azure,
+            instance_id: field("vmId").to_string(),
+            instance_type: field("vmSize").to_string(),
+            zone,
+        })
+    }
+
+    /// Returns the labels stored in the snapshots.
+    pub fn labels(&self) -> BTreeMap<String, String> {
+        vec![
+            (LABEL_PROVIDER, self.provider.as_str()),
+            (LABEL_INSTANCE_ID, &self.instance_id),
+            (LABEL_INSTANCE_TYPE, &self.instance_type),
+            (LABEL_ZONE, &self.zone),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+    }
+}
+
+#[cfg(feature = "cloud")]
+pub use self::imds::detect;
+
+#[cfg(feature = "cloud")]
+mod imds {
+    use std::time::Duration;
+
+    use super::{InstanceMetadata, Provider};
+
+    /// The link-local address all three providers serve their metadata on.
+    const ENDPOINT: &str = "http://169.254.169.254";
+
+    /// Asks the metadata services of EC2, GCE and Azure in turn, waiting at most `timeout` for
+    /// each request. Returns None if the host isn't on any of them.
+    pub fn detect(timeout: Duration) -> Option<InstanceMetadata> {
+        let agent = ureq::AgentBuilder::new()
+            .timeout(timeout)
+            .redirects(0)
+            .build();
+        ec2(&agent)
+            .or_else(|| gce(&agent))
+            .or_else(|| azure(&agent))
+    }
+
+    fn get(request: ureq::Request) -> Option<String> {
+        request.call().ok()?.into_string().ok()
+    }
+
+    /// Uses IMDSv2, which needs a session token, as IMDSv1 is disabled on many instances.
+    fn ec2(agent: &ureq::Agent) -> Option<InstanceMetadata> {
+        let token = get(agent
+            .put(&format!("{}/latest/api/token", ENDPOINT))
+            .set("X-aws-ec2-metadata-token-ttl-seconds", "60"))?;
+        let field = |path: &str| {
+            get(agent
+                .get(&format!("{}/latest/meta-data/{}", ENDPOINT, path))
+                .set("X-aws-ec2-metadata-token", &token))
+        };
+        Some(InstanceMetadata {
+            provider: Provider::Ec2,
+            instance_id: field("instance-id")?,
+            instance_type: field("instance-type")?,
+            zone: field("placement/availability-zone")?,
+        })
+    }
+
+    fn gce(agent: &ureq::Agent) -> Option<InstanceMetadata> {
+        let field = |path: &str| {
+            get(agent
+                .get(&format!(
+                    "{}/computeMetadata/v1/instance/{}",
+                    ENDPOINT, path
+                ))
+                .set("Metadata-Flavor", "Google"))
+        };
+        Some(InstanceMetadata::from_gce(
+            &field("id")?,
+            &field("machine-type")?,
+            &field("zone")?,
+        ))
+    }
+
+    fn azure(agent: &ureq::Agent) -> Option<InstanceMetadata> {
+        let compute = get(agent
+            .get(&format!(
+                "{}/metadata/instance/compute?api-version=2021-02-01",
+                ENDPOINT
+            ))
+            .set("Metadata", "true"))?;
+        InstanceMetadata::from_azure(&compute)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_metadata() {
+        let gce = InstanceMetadata::from_gce(
+            "4520031799277581759\n",
+            "projects/421616111/machineTypes/n2-standard-8",
+            "projects/421616111/zones/europe-west1-b",
+        );
+        assert_eq!(gce.instance_type, "n2-standard-8");
+        assert_eq!(gce.zone, "europe-west1-b");
+        let labels = gce.labels();
+        assert_eq!(labels[LABEL_PROVIDER], "gce");
+        assert_eq!(labels[LABEL_INSTANCE_ID], "4520031799277581759");
+
+        let azure = InstanceMetadata::from_azure(
+            r#"{"location": "westeurope", "vmId": "02aab8a4-74ef-476e-8182-f6d2ba4166a6",
+                "vmSize": "Standard_D4s_v3", "zone": "2"}"#,
+        )
+        .unwrap();
+        assert_eq!(azure.instance_type, "Standard_D4s_v3");
+        assert_eq!(azure.zone, "westeurope-2");
+        assert_eq!(InstanceMetadata::from_azure("<html>not found</html>"), None);
+        assert_eq!(InstanceMetadata::from_azure(r#"{"vmId": ""}"#), None);
+    }
+}
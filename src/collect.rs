@@ -0,0 +1,412 @@
+//! Reading the processes of a proc filesystem.
+//!
+//! The server reads the processes from `/proc` through `read_pid`, which takes the root of the
+//! proc filesystem so that benchmarks and tests can point it at a directory laid out the same
+//! way. `collect_all` reads every process of such a root, which is what one iteration of the
+//! server does before computing the CPU usage. Which processes are left out, eg: kernel threads,
+//! is decided by a `SkipPolicy`.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use crate::priority::SchedPolicy;
+use crate::procfile::{Stat, Status};
+use crate::{proc_io, statm, Pid, PidStatus};
+
+/// Where the proc filesystem is mounted.
+pub const PROC_ROOT: &str = "/proc";
+
+/// Process is what is read about a process in one iteration.
+#[derive(Debug)]
+pub struct Process {
+    pub status: PidStatus,
+    /// The parsed stat file, missing if it wasn't readable.
+    pub stat: Option<Stat>,
+}
+
+/// SkipPolicy is which processes `read_pid` leaves out. The default leaves out kernel threads,
+/// zombies and the processes without resident memory or VmPeak. Each kind can be recorded
+/// instead, eg: to keep the idle daemons whose memory was swapped out.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SkipPolicy {
+    /// Record the kernel threads, flagged with PF_KTHREAD in their stat.
+    pub include_kernel_threads: bool,
+    /// Record the processes without resident memory or VmPeak.
+    pub include_zero_rss: bool,
+    /// Record the processes that exited and weren't reaped yet.
+    pub include_zombies: bool,
+}
+
+impl SkipPolicy {
+    /// Records the kind of processes named as on the command line: kernel-threads, zero-rss or
+    /// zombies.
+    pub fn include(&mut self, kind: &str) -> Result<(), String> {
+        match kind {
+            "kernel-threads" => self.include_kernel_threads = true,
+            "zero-rss" => self.include_zero_rss = true,
+            "zombies" => self.include_zombies = true,
+            _ => {
+                return Err(format!(
+                    "invalid kind of process {}, expected kernel-threads, zero-rss or zombies",
+                    kind
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns true if the process of `stat` is left out. `has_vmpeak` is whether its status has
+    /// a VmPeak, and is only checked for processes that are neither kernel threads nor zombies.
+    fn skips(&self, stat: &Stat, has_vmpeak: bool) -> bool {
+        if stat.is_kernel_thread() {
+            !self.include_kernel_threads
+        } else if stat.state == 'Z' {
+            !self.include_zombies
+        } else {
+            (!has_vmpeak || stat.rss == 0) && !self.include_zero_rss
+        }
+    }
+}
+
+/// Lists the pids under `proc_root`, including the ones whose files we are not allowed to read.
+pub fn list_pids(proc_root: &Path) -> io::Result<Vec<Pid>> {
+    Ok(fs::read_dir(proc_root)?
+        .filter_map(|e| e.ok())
+        .filter_map(|e| e.file_name().to_str().and_then(|n| n.parse().ok()))
+        .collect())
+}
+
+/// Reads `pid` from `proc_root`. Returns None if the process exited before its stat was read, or
+/// if `skip` leaves it out. Processes whose status can't be read because of permissions are
+/// returned with whatever is readable, flagged as `restricted`. Processes exiting after their
+/// stat was read are returned with the fields read until then, flagged as
+/// `vanished_during_scan`. The statm columns are only read if `statm` is set.
+pub fn read_pid(proc_root: &Path, pid: Pid, statm: bool, skip: SkipPolicy) -> Option<Process> {
+    let dir = proc_root.join(pid.to_string());
+    let stat = match fs::read(dir.join("stat")) {
+        Ok(content) => Stat::parse(&content)?,
+        // hidepid=1 lets us see the pid, but not read its stat.
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            return Some(Process {
+                status: restricted_pid_status(proc_root, pid),
+                stat: None,
+            })
+        }
+        // The process exited after /proc was listed.
+        Err(_) => return None,
+    };
+    let mut vanished = false;
+    let owner = match fs::metadata(&dir) {
+        Ok(m) => m.uid(),
+        Err(e) if exited(&e) => {
+            vanished = true;
+            u32::MAX
+        }
+        Err(_) => return None,
+    };
+    let mut status = match fs::read(dir.join("status")) {
+        Ok(content) => {
+            let status = Status::parse(&content)?;
+            if skip.skips(&stat, status.vmpeak.is_some()) {
+                return None;
+            }
+            let cmd_long = match cmdline(&dir) {
+                Ok(c) => c,
+                Err(e) if exited(&e) => {
+                    vanished = true;
+                    Vec::new()
+                }
+                Err(_) => vec!["No cmd_long found".to_string()],
+            };
+            PidStatus {
+                ppid: status.ppid,
+                euid: status.euid,
+                cmd_long,
+                name: status.name,
+                cmd_short: stat.comm.clone(),
+                tracerpid: status.tracerpid,
+                fdsize: status.fdsize,
+                fds: None,
+                connections: None,
+                state: status.state,
+                vmpeak: status.vmpeak,
+                vmsize: status.vmsize,
+                rss_pages: stat.rss,
+                rss_bytes: stat.rss_bytes(),
+                rsslim_bytes: stat.rsslim,
+                shared_pages: 0,
+                text_pages: 0,
+                data_pages: 0,
+                rss_pct_of_limit: None,
+                processor_last_executed: stat.processor,
+                sched_policy: stat.policy.and_then(SchedPolicy::from_raw),
+                rt_priority: stat.rt_priority,
+                utime: stat.utime,
+                stime: stat.stime,
+                io: None,
+                service_hint: None,
+                runtime: None,
+                cgroup: None,
+                cgroup_limits: None,
+                user_cpu_usage: 0.0,
+                sys_cpu_usage: 0.0,
+                restricted: false,
+                vanished_during_scan: false,
+                extensions: Default::default(),
+            }
+        }
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            restricted_from_stat(&stat, owner, cmdline(&dir).unwrap_or_default())
+        }
+        // Whether it had a VmPeak is unknown, only its stat decides.
+        Err(e) if exited(&e) && !skip.skips(&stat, true) => {
+            vanished = true;
+            PidStatus {
+                restricted: false,
+                ..restricted_from_stat(&stat, owner, Vec::new())
+            }
+        }
+        Err(_) => return None,
+    };
+    if statm && !vanished {
+        match statm::read_in(proc_root, pid) {
+            Ok(m) => {
+                status.shared_pages = m.shared;
+                status.text_pages = m.text;
+                status.data_pages = m.data;
+            }
+            Err(e) => vanished = exited(&e),
+        }
+    }
+    if !status.restricted && !vanished {
+        match proc_io::read_in(proc_root, pid) {
+            Ok(io) => status.io = Some(io),
+            Err(e) => vanished = exited(&e),
+        }
+    }
+    status.vanished_during_scan = vanished;
+    Some(Process {
+        status,
+        stat: Some(stat),
+    })
+}
+
+/// Reads every process of `proc_root`, the way one iteration of the server does with the default
+/// `SkipPolicy`, without the CPU usage and memory limits.
+pub fn collect_all(proc_root: &Path) -> io::Result<HashMap<Pid, PidStatus>> {
+    let skip = SkipPolicy::default();
+    Ok(list_pids(proc_root)?
+        .into_iter()
+        .filter_map(|pid| Some((pid, read_pid(proc_root, pid, true, skip)?.status)))
+        .collect())
+}
+
+/// Returns true if `e` is what reading the files of an exited process fails with: ENOENT once it
+/// was reaped, ESRCH for some files of a zombie.
+fn exited(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::NotFound || e.raw_os_error() == Some(libc::ESRCH)
+}
+
+fn cmdline(dir: &Path) -> io::Result<Vec<String>> {
+    Ok(fs::read_to_string(dir.join("cmdline"))?
+        .split('\0')
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_string())
+        .collect())
+}
+
+/// Returns the record of a process whose stat file can't be read. Only the owner, from the
+/// metadata of /proc/<pid>, and the comm, if visible, are known.
+pub fn restricted_pid_status(proc_root: &Path, pid: Pid) -> PidStatus {
+    let root = proc_root.join(pid.to_string());
+    let name = fs::read_to_string(root.join("comm"))
+        .map(|c| c.trim_end().to_string())
+        .unwrap_or_default();
+    let euid = fs::metadata(&root).map(|m| m.uid() as i32).unwrap_or(-1);
+    PidStatus {
+        ppid: Pid::new(0),
+        euid,
+        cmd_long: Vec::new(),
+        name: name.clone(),
+        cmd_short: name,
+        tracerpid: Pid::new(0),
+        fdsize: 0,
+        fds: None,
+        connections: None,
+        state: String::new(),
+        vmpeak: None,
+        vmsize: None,
+        rss_pages: 0,
+        rss_bytes: 0,
+        rsslim_bytes: 0,
+        shared_pages: 0,
+        text_pages: 0,
+        data_pages: 0,
+        rss_pct_of_limit: None,
+        processor_last_executed: None,
+        sched_policy: None,
+        rt_priority: None,
+        utime: 0,
+        stime: 0,
+        io: None,
+        service_hint: None,
+        runtime: None,
+        cgroup: None,
+        cgroup_limits: None,
+        user_cpu_usage: 0.0,
+        sys_cpu_usage: 0.0,
+        restricted: true,
+        vanished_during_scan: false,
+        extensions: Default::default(),
+    }
+}
+
+/// Returns the record of a process whose stat is readable but whose status is not.
+fn restricted_from_stat(stat: &Stat, owner: u32, cmd_long: Vec<String>) -> PidStatus {
+    PidStatus {
+        ppid: stat.ppid,
+        euid: owner as i32,
+        cmd_long,
+        name: stat.comm.clone(),
+        cmd_short: stat.comm.clone(),
+        tracerpid: Pid::new(0),
+        fdsize: 0,
+        fds: None,
+        connections: None,
+        state: stat.state.to_string(),
+        vmpeak: None,
+        vmsize: Some(stat.vsize / 1024),
+        rss_pages: stat.rss,
+        rss_bytes: stat.rss_bytes(),
+        rsslim_bytes: stat.rsslim,
+        shared_pages: 0,
+        text_pages: 0,
+        data_pages: 0,
+        rss_pct_of_limit: None,
+        processor_last_executed: stat.processor,
+        sched_policy: stat.policy.and_then(SchedPolicy::from_raw),
+        rt_priority: stat.rt_priority,
+        utime: stat.utime,
+        stime: stat.stime,
+        io: None,
+        service_hint: None,
+        runtime: None,
+        cgroup: None,
+        cgroup_limits: None,
+        user_cpu_usage: 0.0,
+        sys_cpu_usage: 0.0,
+        restricted: true,
+        vanished_during_scan: false,
+        extensions: Default::default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_restricted_pid_status() {
+        let s = restricted_pid_status(Path::new(PROC_ROOT), Pid::current());
+        assert!(s.restricted);
+        assert!(!s.name.is_empty());
+        assert_eq!(s.name, s.cmd_short);
+    }
+
+    #[test]
+    fn test_read_pid() {
+        let s = read_pid(
+            Path::new(PROC_ROOT),
+            Pid::current(),
+            true,
+            SkipPolicy::default(),
+        )
+        .unwrap()
+        .status;
+        assert!(s.rss_bytes > 0);
+        assert!(s.data_pages > 0);
+        assert!(s.io.is_some());
+        assert!(!s.cmd_long.is_empty());
+        assert!(collect_all(Path::new(PROC_ROOT))
+            .unwrap()
+            .contains_key(&Pid::current()));
+        assert!(!s.vanished_during_scan);
+    }
+
+    #[test]
+    fn test_skip_policy() {
+        let stat = |state: &str, flags: &str, rss: &str| {
+            let mut fields = vec!["0"; 52 - 2];
+            fields[0] = state;
+            fields[9 - 3] = flags;
+            fields[24 - 3] = rss;
+            Stat::parse(format!("42 (worker) {}\n", fields.join(" ")).as_bytes()).unwrap()
+        };
+        let kernel_thread = stat("S", "2097216", "0");
+        let zombie = stat("Z", "4194380", "0");
+        let swapped_out = stat("S", "4194560", "0");
+        let running = stat("R", "4194560", "250");
+
+        let default = SkipPolicy::default();
+        assert!(default.skips(&kernel_thread, false));
+        assert!(default.skips(&zombie, false));
+        assert!(default.skips(&swapped_out, true));
+        assert!(default.skips(&running, false));
+        assert!(!default.skips(&running, true));
+
+        let mut all = SkipPolicy::default();
+        for kind in ["kernel-threads", "zero-rss", "zombies"] {
+            all.include(kind).unwrap();
+        }
+        assert!(!all.skips(&kernel_thread, false));
+        assert!(!all.skips(&zombie, false));
+        assert!(!all.skips(&swapped_out, false));
+
+        let mut zombies = SkipPolicy::default();
+        zombies.include("zombies").unwrap();
+        assert!(!zombies.skips(&zombie, false));
+        assert!(zombies.skips(&kernel_thread, false));
+        assert!(zombies.skips(&swapped_out, true));
+        assert!(zombies.include("threads").is_err());
+    }
+
+    #[test]
+    fn test_read_vanishing_pid() {
+        // A process whose files disappear one after the other, as they do when it exits.
+        let root = std::env::temp_dir().join(format!("procshot_vanish_{}", std::process::id()));
+        let pid = Pid::current();
+        let dir = root.join(pid.to_string());
+        fs::create_dir_all(&dir).unwrap();
+        let own = Path::new(PROC_ROOT).join(pid.to_string());
+        for file in &["stat", "status"] {
+            fs::copy(own.join(file), dir.join(file)).unwrap();
+        }
+
+        // Exited before its cmdline and statm were read.
+        let s = read_pid(&root, pid, true, SkipPolicy::default())
+            .unwrap()
+            .status;
+        assert!(s.vanished_during_scan);
+        assert!(!s.restricted);
+        assert!(s.vmpeak.is_some());
+        assert!(s.cmd_long.is_empty());
+
+        // Exited right after its stat was read.
+        fs::remove_file(dir.join("status")).unwrap();
+        let s = read_pid(&root, pid, true, SkipPolicy::default())
+            .unwrap()
+            .status;
+        assert!(s.vanished_during_scan);
+        assert!(!s.restricted);
+        assert!(s.rss_bytes > 0);
+        assert_eq!(s.vmpeak, None);
+
+        // Gone before anything was read.
+        fs::remove_file(dir.join("stat")).unwrap();
+        assert!(read_pid(&root, pid, true, SkipPolicy::default()).is_none());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,249 @@
+//! Collector storing the snapshots streamed by the servers of many hosts.
+//!
+//! Servers started with `--stream-to <host>:<port>` send every snapshot to a collector (see
+//! `sink::stream`), which stores the snapshots of each host in `<datadir>/<hostname>/`, a datadir
+//! of its own that every reader of the crate reads, and that `fleet::rollup` reads as a whole.
+//! Hosts sharing a hostname are told apart by the `identity` module, according to the
+//! `CollisionPolicy` of the collector.
+//!
+//! With `tls::TlsFiles`, the collector only accepts TLS connections from servers presenting a
+//! certificate signed by its CA, see the `tls` module.
+
+use std::io;
+use std::io::BufReader;
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::backend::{DirBackend, StorageBackend};
+use crate::identity::{CollisionPolicy, HostRegistry};
+use crate::sink::log::read_frames;
+use crate::store;
+use crate::tls::{Connection, TlsFiles, TlsServer};
+
+/// CollectorJob describes a collector, as given to the `collector` subcommand.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CollectorJob {
+    /// Address to listen on, eg: `0.0.0.0:7070`.
+    pub listen: String,
+    /// Directory the datadirs of the hosts are created in, the `--datadir` of the server if None.
+    pub datadir: Option<PathBuf>,
+    pub tls: Option<TlsFiles>,
+    pub collision: CollisionPolicy,
+}
+
+/// Collector receives the snapshots of the servers and stores them.
+pub struct Collector {
+    datadir: PathBuf,
+    hosts: Mutex<HostRegistry>,
+    tls: Option<TlsServer>,
+}
+
+impl Collector {
+    pub fn new(
+        datadir: &Path,
+        tls: Option<TlsFiles>,
+        collision: CollisionPolicy,
+    ) -> io::Result<Self> {
+        Ok(Collector {
+            datadir: datadir.to_path_buf(),
+            hosts: Mutex::new(HostRegistry::new(collision)),
+            tls: tls.map(TlsServer::new).transpose()?,
+        })
+    }
+
+    /// Accepts connections on `listener` until it fails, handling each one on a thread of its own.
+    pub fn serve(self: Arc<Self>, listener: TcpListener) -> io::Result<()> {
+        for tcp in listener.incoming() {
+            let tcp = tcp?;
+            let collector = self.clone();
+            thread::spawn(move || {
+                let peer = tcp
+                    .peer_addr()
+                    .map_or("unknown peer".to_string(), |a| a.to_string());
+                if let Err(e) = collector.handle(tcp) {
+                    eprintln!("Closing the connection of {}, err: {}", peer, e);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    fn handle(&self, tcp: TcpStream) -> io::Result<()> {
+        let conn: Box<dyn Connection> = match &self.tls {
+            Some(tls) => tls.accept(tcp)?,
+            None => Box::new(tcp),
+        };
+        read_frames(BufReader::new(conn), |body| {
+            // A snapshot that can't be stored is dropped, the next ones may be fine.
+            if let Err(e) = self.receive(body) {
+                eprintln!("Dropping a received snapshot, err: {}", e);
+            }
+            Ok(())
+        })
+        .map(|_| ())
+    }
+
+    /// Decodes a received snapshot and stores it in the datadir of its host. Returns the path of
+    /// the file written.
+    pub fn receive(&self, body: &[u8]) -> io::Result<PathBuf> {
+        let mut snapshot =
+            store::decode(body).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.hosts
+            .lock()
+            .unwrap()
+            .admit(&mut snapshot)
+            .map_err(|e| io::Error::new(io::ErrorKind::AlreadyExists, e))?;
+        let dir = self.host_dir(&snapshot.hostname)?;
+        std::fs::create_dir_all(&dir)?;
+        let path = DirBackend::new(dir, false).write_snapshot(&snapshot)?;
+        Ok(path.unwrap_or_default())
+    }
+
+    /// Returns the datadir of `hostname`. Hostnames that aren't a single path component are
+    /// refused, as they would escape the datadir of the collector.
+    pub fn host_dir(&self, hostname: &str) -> io::Result<PathBuf> {
+        match hostname {
+            "" | "." | ".." => None,
+            h if h.contains(['/', '\0']) => None,
+            h => Some(self.datadir.join(h)),
+        }
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid hostname {:?}", hostname),
+            )
+        })
+    }
+}
+
+/// Runs the collector of `job` until listening fails. `datadir` is used if the job has none.
+pub fn run(job: &CollectorJob, datadir: &Path) -> io::Result<()> {
+    let datadir = job.datadir.as_deref().unwrap_or(datadir);
+    let collector = Collector::new(datadir, job.tls.clone(), job.collision)?;
+    let listener = TcpListener::bind(&job.listen)?;
+    eprintln!(
+        "Collecting snapshots on {} into {}{}",
+        job.listen,
+        datadir.display(),
+        if job.tls.is_some() { " over TLS" } else { "" }
+    );
+    Arc::new(collector).serve(listener)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::stream::StreamSink;
+    use crate::sink::StorageSink;
+    #[cfg(feature = "tls")]
+    use crate::tls::TlsClient;
+    use crate::EncoDecode;
+    use std::fs;
+    use std::time::{Duration, Instant};
+
+    fn snapshot(hostname: &str, epoch: u64) -> EncoDecode {
+        EncoDecode {
+            hostname: hostname.to_string(),
+            time_epoch: epoch,
+            delay: Duration::from_secs(60),
+            ..Default::default()
+        }
+    }
+
+    /// Starts a collector on a free port of localhost and returns its address.
+    fn start(datadir: &Path, tls: Option<TlsFiles>) -> String {
+        let collector = Arc::new(Collector::new(datadir, tls, CollisionPolicy::Suffix).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || collector.serve(listener));
+        addr
+    }
+
+    fn wait_for(path: &Path) {
+        let start = Instant::now();
+        while !path.exists() {
+            assert!(
+                start.elapsed() < Duration::from_secs(10),
+                "{}",
+                path.display()
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_stream_to_collector() {
+        let dir = std::env::temp_dir().join(format!("procshot_collector_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let addr = start(&dir, None);
+        let mut sink = StreamSink::new(&addr, None).unwrap();
+        sink.write_snapshot(&snapshot("web-1", 1563617611)).unwrap();
+        sink.write_snapshot(&snapshot("web-1", 1563617671)).unwrap();
+        wait_for(&dir.join("web-1/1563617671.procshot"));
+        let read = store::read_snapshot(&dir.join("web-1/1563617611.procshot")).unwrap();
+        assert_eq!(read, snapshot("web-1", 1563617611));
+
+        let collector = Collector::new(&dir, None, CollisionPolicy::Reject).unwrap();
+        let body = store::SerializationFormat::Bincode
+            .encode(&snapshot("../etc", 1563617611))
+            .unwrap();
+        assert!(collector.receive(&body).is_err());
+        assert!(collector.receive(b"PSHT\x01").is_err());
+        assert!(StreamSink::new("localhost", None).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn test_stream_over_tls() {
+        let dir =
+            std::env::temp_dir().join(format!("procshot_collector_tls_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let fixture = |name: &str| {
+            Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("testdata/tls")
+                .join(name)
+        };
+        let files = |who: &str| TlsFiles {
+            cert: fixture(&format!("{}.pem", who)),
+            key: fixture(&format!("{}.key", who)),
+            ca: fixture("ca.pem"),
+        };
+        let addr = start(&dir, Some(files("collector")));
+        let port = addr.rsplit(':').next().unwrap();
+
+        let mut sink =
+            StreamSink::new(&format!("localhost:{}", port), Some(files("agent"))).unwrap();
+        sink.write_snapshot(&snapshot("db-1", 1563617611)).unwrap();
+        wait_for(&dir.join("db-1/1563617611.procshot"));
+
+        // Plain connections and certificates not meant for clients are refused.
+        let mut plain = StreamSink::new(&addr, None).unwrap();
+        let _ = plain.write_snapshot(&snapshot("db-2", 1563617611));
+        let mut server_cert = StreamSink::new(&addr, Some(files("collector"))).unwrap();
+        let _ = server_cert.write_snapshot(&snapshot("db-3", 1563617611));
+        thread::sleep(Duration::from_millis(200));
+        assert!(!dir.join("db-2").exists());
+        assert!(!dir.join("db-3").exists());
+
+        // Rotating the certificate reconnects.
+        let rotated = dir.join("rotated");
+        fs::create_dir_all(&rotated).unwrap();
+        let mut agent = files("agent");
+        for path in [&mut agent.cert, &mut agent.key] {
+            let copy = rotated.join(path.file_name().unwrap());
+            fs::copy(&path, &copy).unwrap();
+            *path = copy;
+        }
+        let mut client = TlsClient::new(agent.clone(), "localhost").unwrap();
+        assert!(!client.reload());
+        let file = fs::File::options().write(true).open(&agent.cert).unwrap();
+        file.set_modified(std::time::SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+        assert!(client.reload());
+        assert!(!client.reload());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
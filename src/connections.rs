@@ -0,0 +1,205 @@
+//! Network connections of processes, from the socket tables of /proc/net.
+//!
+//! After an incident the question is often who was talking to a host, or which process held the
+//! connections to a database, at a given time. The server can join the TCP and UDP sockets of
+//! /proc/net/{tcp,tcp6,udp,udp6} with the `socket:[inode]` links of the fds of every process, like
+//! the `services` module, and record the local and remote address and the state of each socket
+//! of a process in `PidStatus::connections`. Like the fd listing, it's opt-in since busy servers
+//! hold many connections, and the fds of processes of other users are only readable as root.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+
+#[cfg(feature = "server")]
+use crate::{tcpstats, Pid};
+
+/// Files of /proc/net listing the sockets, and their protocol.
+const SOCKET_TABLES: &[(&str, Protocol)] = &[
+    ("tcp", Protocol::Tcp),
+    ("tcp6", Protocol::Tcp),
+    ("udp", Protocol::Udp),
+    ("udp6", Protocol::Udp),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+/// SocketState is the state of a socket, as the TCP_* states of the kernel. UDP sockets are
+/// `established` once connected, and `close` otherwise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SocketState {
+    Established,
+    SynSent,
+    SynRecv,
+    FinWait1,
+    FinWait2,
+    TimeWait,
+    Close,
+    CloseWait,
+    LastAck,
+    Listen,
+    Closing,
+    NewSynRecv,
+}
+
+impl SocketState {
+    /// Decodes the `st` column of a socket table, eg: `0A` for `listen`.
+    pub fn from_hex(st: &str) -> Option<Self> {
+        let state = match u8::from_str_radix(st, 16).ok()? {
+            1 => SocketState::Established,
+            2 => SocketState::SynSent,
+            3 => SocketState::SynRecv,
+            4 => SocketState::FinWait1,
+            5 => SocketState::FinWait2,
+            6 => SocketState::TimeWait,
+            7 => SocketState::Close,
+            8 => SocketState::CloseWait,
+            9 => SocketState::LastAck,
+            10 => SocketState::Listen,
+            11 => SocketState::Closing,
+            12 => SocketState::NewSynRecv,
+            _ => return None,
+        };
+        Some(state)
+    }
+}
+
+/// Connection is a socket of a process. The remote address is unspecified, eg: `0.0.0.0:0`, for
+/// listening and unconnected sockets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Connection {
+    pub protocol: Protocol,
+    pub local: SocketAddr,
+    pub remote: SocketAddr,
+    pub state: SocketState,
+}
+
+impl Connection {
+    /// Returns true if the remote end of the connection is `ip`, and `port` if given.
+    pub fn is_to(&self, ip: IpAddr, port: Option<u16>) -> bool {
+        self.remote.ip() == ip && port.is_none_or(|p| self.remote.port() == p)
+    }
+}
+
+/// Decodes an `<address>:<port>` of a socket table. Addresses are printed as 32 bit words in the
+/// byte order of the host, and IPv4 addresses mapped in IPv6 are returned as IPv4.
+fn parse_address(s: &str) -> Option<SocketAddr> {
+    let (address, port) = s.split_once(':')?;
+    let port = u16::from_str_radix(port, 16).ok()?;
+    let mut bytes = Vec::with_capacity(16);
+    for i in (0..address.len()).step_by(8) {
+        let word = u32::from_str_radix(address.get(i..i + 8)?, 16).ok()?;
+        bytes.extend(word.to_ne_bytes());
+    }
+    let ip = match bytes.len() {
+        4 => IpAddr::V4(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3])),
+        16 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&bytes);
+            let ip = Ipv6Addr::from(octets);
+            ip.to_ipv4_mapped().map_or(IpAddr::V6(ip), IpAddr::V4)
+        }
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+/// Parses a socket table of /proc/net of `protocol` and returns its sockets by inode.
+pub fn parse_table(content: &str, protocol: Protocol) -> HashMap<u64, Connection> {
+    content
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            // sl local_address rem_address st tx_queue:rx_queue tr:tm->when retrnsmt uid timeout
+            // inode ...
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let inode: u64 = fields.get(9)?.parse().ok()?;
+            let connection = Connection {
+                protocol,
+                local: parse_address(fields.get(1)?)?,
+                remote: parse_address(fields.get(2)?)?,
+                state: SocketState::from_hex(fields.get(3)?)?,
+            };
+            // Sockets being torn down have no inode.
+            Some((inode, connection)).filter(|(inode, _)| *inode != 0)
+        })
+        .collect()
+}
+
+/// Returns the TCP and UDP sockets of the host, by inode. Tables missing from the kernel, eg:
+/// tcp6 without IPv6, are skipped.
+pub fn sockets(proc_root: &Path) -> io::Result<HashMap<u64, Connection>> {
+    let mut sockets = HashMap::new();
+    for (table, protocol) in SOCKET_TABLES {
+        match fs::read_to_string(proc_root.join("net").join(table)) {
+            Ok(content) => sockets.extend(parse_table(&content, *protocol)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(sockets)
+}
+
+/// Returns the connections of `pid` among the `sockets` of the host, sorted by protocol, local
+/// and remote address. Fails if its fds can't be read.
+#[cfg(feature = "server")]
+pub fn of_pid(
+    proc_root: &Path,
+    pid: Pid,
+    sockets: &HashMap<u64, Connection>,
+) -> io::Result<Vec<Connection>> {
+    let mut connections: Vec<Connection> = tcpstats::socket_inodes(proc_root, pid)?
+        .iter()
+        .filter_map(|inode| sockets.get(inode).copied())
+        .collect();
+    connections.sort_by_key(|c| (c.protocol as u8, c.local, c.remote));
+    Ok(connections)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_table() {
+        let tcp = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode\n   0: 00000000:1538 00000000:0000 0A 00000000:00000000 00:00000000 00000000   113        0 31337 1 0000000000000000 100 0 0 10 0\n   1: 0100007F:E2D4 0100007F:1538 01 00000000:00000000 00:00000000 00000000  1000        0 42424 1 0000000000000000 20 4 30 10 -1\n   2: 0100007F:E2D6 0100007F:1538 06 00000000:00000000 03:00001770 00000000     0        0 0 3 0000000000000000\n";
+        let sockets = parse_table(tcp, Protocol::Tcp);
+        assert_eq!(sockets.len(), 2);
+        let client = sockets[&42424];
+        assert_eq!(client.state, SocketState::Established);
+        assert_eq!(client.remote, "127.0.0.1:5432".parse().unwrap());
+        assert!(client.is_to("127.0.0.1".parse().unwrap(), Some(5432)));
+        assert!(!client.is_to("127.0.0.1".parse().unwrap(), Some(5433)));
+        assert_eq!(sockets[&31337].state, SocketState::Listen);
+        let mapped = "0000000000000000FFFF00000100007F:0050";
+        assert_eq!(parse_address(mapped), Some("127.0.0.1:80".parse().unwrap()));
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn test_of_pid() {
+        use std::net::{TcpListener, TcpStream};
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let proc_root = Path::new("/proc");
+        let connections = of_pid(proc_root, Pid::current(), &sockets(proc_root).unwrap()).unwrap();
+        assert!(connections.contains(&Connection {
+            protocol: Protocol::Tcp,
+            local: stream.local_addr().unwrap(),
+            remote: listener.local_addr().unwrap(),
+            state: SocketState::Established,
+        }));
+        assert!(connections
+            .iter()
+            .any(|c| c.local == listener.local_addr().unwrap() && c.state == SocketState::Listen));
+    }
+}
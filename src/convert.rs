@@ -0,0 +1,926 @@
+//! Conversion of archives between storage formats.
+//!
+//! `convert` re-encodes every snapshot of an archive into another format, so an archive isn't
+//! locked into the format it was started with. The supported formats are:
+//!
+//! * `bincode`: a datadir as written by the server, one `<epoch>.procshot` file per snapshot. Both
+//!   tiers are read, the output is always written uncompressed to the warm tier.
+//! * `json`: a single file with one JSON encoded snapshot per line, after a first line holding the
+//!   `header::Header` with the units of the fields.
+//! * `sqlite`: a database as written by `backend::sqlite::SqliteBackend` (`sqlite` feature).
+//! * `parquet`: a single table with one row per process and snapshot, in the columns of the
+//!   `arrow` module, for analytics tools. This format can only be written (`parquet` feature). The `header::Header` is stored as JSON in the
+//!   `procshot.header` key of the file metadata.
+//! * `csv`: the columns of the parquet table, with a header line. This format can only be
+//!   written.
+//!
+//! The format of the source is detected from its content unless given: a directory is a datadir,
+//! and files are recognized by their first bytes. Within a datadir, every file is decoded
+//! according to its own content, see `store`.
+//!
+//! After writing, the output is read back and every snapshot is checked to be there with the same
+//! number of processes. Snapshots that can't be read from the source are skipped with a warning.
+//!
+//! Snapshots are streamed one at a time from the source to the destination, and Parquet row
+//! groups are capped at `PARQUET_ROW_GROUP_ROWS`, so converting a month of snapshots takes no
+//! more memory than a day. With `ConvertJob::resume`, the conversion records how far it got in
+//! `<dst>.checkpoint` every `CHECKPOINT_EVERY` snapshots, and a conversion interrupted before it
+//! completed picks up from there when run again. Parquet files can't be appended to, so a
+//! resumable conversion to parquet writes a new part file at every checkpoint: `<dst>`, then
+//! `<dst stem>.1.parquet`, `<dst stem>.2.parquet` and so on, which analytics tools read as one
+//! table.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::backend::{DirBackend, StorageBackend};
+use crate::header::Header;
+use crate::{store, EncoDecode};
+
+/// Format is one of the storage formats of an archive.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Bincode,
+    Json,
+    Sqlite,
+    Parquet,
+    Csv,
+}
+
+/// Magic bytes starting an SQLite database.
+const SQLITE_MAGIC: &[u8] = b"SQLite format 3\0";
+/// Magic bytes starting a Parquet file.
+const PARQUET_MAGIC: &[u8] = b"PAR1";
+
+/// Columns of the `csv` format, which are those of the `parquet` one.
+const CSV_HEADER: &str = "hostname,time_epoch,pid,ppid,euid,name,cmd_long,state,fdsize,fds,connections,vmpeak,vmsize,rss_bytes,shared_pages,text_pages,data_pages,rss_pct_of_limit,utime,stime,sched_policy,rt_priority,io_read_bytes,io_write_bytes,io_syscr,io_syscw,io_cancelled_write_bytes,service_hint,runtime,cgroup,cgroup_memory_max,cgroup_cpus,user_cpu_usage,sys_cpu_usage,restricted,vanished_during_scan,extensions";
+
+/// A resumable conversion saves a checkpoint after this many snapshots.
+pub const CHECKPOINT_EVERY: usize = 1000;
+
+/// Parquet rows buffered in memory before they are written out as a row group.
+pub const PARQUET_ROW_GROUP_ROWS: usize = 64 * 1024;
+
+impl Format {
+    /// Detects the format of the archive at `path`: a directory is a `bincode` datadir, files are
+    /// recognized by their first bytes.
+    pub fn detect(path: &Path) -> io::Result<Format> {
+        if path.is_dir() {
+            return Ok(Format::Bincode);
+        }
+        let mut start = Vec::with_capacity(SQLITE_MAGIC.len());
+        File::open(path)?
+            .take(SQLITE_MAGIC.len() as u64)
+            .read_to_end(&mut start)?;
+        if start.starts_with(SQLITE_MAGIC) {
+            Ok(Format::Sqlite)
+        } else if start.starts_with(PARQUET_MAGIC) {
+            Ok(Format::Parquet)
+        } else if start.first() == Some(&b'{') {
+            Ok(Format::Json)
+        } else if start.starts_with(b"hostname,") {
+            Ok(Format::Csv)
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("cannot detect the format of {}", path.display()),
+            ))
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bincode" => Ok(Format::Bincode),
+            "json" => Ok(Format::Json),
+            "sqlite" => Ok(Format::Sqlite),
+            "parquet" => Ok(Format::Parquet),
+            "csv" => Ok(Format::Csv),
+            _ => Err(format!(
+                "unknown format {}, expected bincode, json, sqlite, parquet or csv",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Format::Bincode => "bincode",
+            Format::Json => "json",
+            Format::Sqlite => "sqlite",
+            Format::Parquet => "parquet",
+            Format::Csv => "csv",
+        };
+        f.write_str(name)
+    }
+}
+
+/// ConvertJob describes one conversion, as given to the `convert` subcommand.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConvertJob {
+    /// Format of the source, detected with `Format::detect` if None.
+    pub from: Option<Format>,
+    pub to: Format,
+    /// Source archive: a datadir for `bincode`, a file otherwise.
+    pub src: PathBuf,
+    /// Destination archive, which must not exist yet (or be an empty directory for `bincode`).
+    pub dst: PathBuf,
+    /// Read the destination back and compare it to what was written.
+    pub validate: bool,
+    /// Save checkpoints, and resume from the checkpoint of an interrupted conversion to the same
+    /// destination if there is one. The source must not change in between, other than new
+    /// snapshots added after the others.
+    pub resume: bool,
+}
+
+/// ConvertStats is the outcome of a conversion.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct ConvertStats {
+    pub snapshots: usize,
+    pub processes: usize,
+    /// Source snapshots that couldn't be read.
+    pub skipped: usize,
+}
+
+/// Converts `job.src` into `job.dst`, calling `progress` with the number of snapshots done and
+/// the total after each one.
+pub fn convert(
+    job: &ConvertJob,
+    progress: &mut dyn FnMut(usize, usize),
+) -> io::Result<ConvertStats> {
+    let checkpoint_path = Checkpoint::path(&job.dst);
+    let mut checkpoint = match job.resume {
+        true => Checkpoint::load(&checkpoint_path)?,
+        false => None,
+    };
+    match &checkpoint {
+        Some(c) => c.restore(job.to, &job.dst)?,
+        None => check_destination(job.to, &job.dst)?,
+    }
+    let from = match job.from {
+        Some(format) => format,
+        None => Format::detect(&job.src)?,
+    };
+    let resumed = checkpoint.as_ref().map_or(0, |c| c.done);
+    let (total, source) = open_source(from, &job.src, resumed)?;
+    let mut state = checkpoint.take().unwrap_or_default();
+    // Parquet parts are created when their first snapshot is written.
+    let mut writer = match (&job.to, resumed) {
+        (Format::Parquet, 1..) => None,
+        (_, 0) => Some(create_writer(job.to, &job.dst)?),
+        _ => Some(append_writer(job.to, &job.dst)?),
+    };
+    // Epoch to number of processes of every snapshot written, checked by the validation.
+    let mut written = HashMap::new();
+    for snapshot in source {
+        match snapshot {
+            Ok(snapshot) => {
+                let w = match writer.as_mut() {
+                    Some(w) => w,
+                    None => writer.insert(create_writer(
+                        job.to,
+                        &part_path(&job.dst, state.parts),
+                    )?),
+                };
+                w.write(&snapshot)?;
+                written.insert(snapshot.time_epoch, snapshot.pid_map_list.len());
+                state.stats.snapshots += 1;
+                state.stats.processes += snapshot.pid_map_list.len();
+            }
+            Err(e) => {
+                eprintln!("Skipping unreadable snapshot, err: {}", e);
+                state.stats.skipped += 1;
+            }
+        }
+        state.done += 1;
+        if job.resume && state.done % CHECKPOINT_EVERY == 0 {
+            if let Some(w) = writer.as_mut() {
+                match job.to {
+                    Format::Parquet => {
+                        writer.take().unwrap().finish()?;
+                        state.parts += 1;
+                    }
+                    _ => w.flush()?,
+                }
+            }
+            state.save(&checkpoint_path, job.to, &job.dst)?;
+        }
+        progress(state.done, total.max(state.done));
+    }
+    if let Some(w) = writer {
+        w.finish()?;
+    }
+    if job.validate {
+        validate(
+            job.to,
+            &job.dst,
+            &written,
+            &state.stats,
+            state.parts,
+            resumed > 0,
+        )?;
+    }
+    if job.resume {
+        match fs::remove_file(&checkpoint_path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => (),
+        }
+    }
+    Ok(state.stats)
+}
+
+/// Checkpoint is how far a resumable conversion got.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+struct Checkpoint {
+    /// Number of source snapshots done, including the skipped ones.
+    done: usize,
+    /// Number of parquet part files completed.
+    parts: usize,
+    /// Length of the destination file when it isn't a datadir or a database, so that what was
+    /// appended after the checkpoint can be cut off.
+    dst_len: Option<u64>,
+    stats: ConvertStats,
+}
+
+impl Checkpoint {
+    fn path(dst: &Path) -> PathBuf {
+        let mut name = dst.as_os_str().to_owned();
+        name.push(".checkpoint");
+        PathBuf::from(name)
+    }
+
+    fn load(path: &Path) -> io::Result<Option<Self>> {
+        match fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .map(Some)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn save(&mut self, path: &Path, format: Format, dst: &Path) -> io::Result<()> {
+        self.dst_len = match format {
+            Format::Json | Format::Csv => Some(fs::metadata(dst)?.len()),
+            _ => None,
+        };
+        let tmp = path.with_extension("checkpoint.tmp");
+        fs::write(&tmp, serde_json::to_vec(self)?)?;
+        File::open(&tmp)?.sync_all()?;
+        fs::rename(&tmp, path)
+    }
+
+    /// Brings the destination back to its state at the checkpoint.
+    fn restore(&self, format: Format, dst: &Path) -> io::Result<()> {
+        if let Some(len) = self.dst_len {
+            fs::OpenOptions::new().write(true).open(dst)?.set_len(len)?;
+        }
+        if format == Format::Parquet {
+            // The part being written when the conversion was interrupted has no footer.
+            match fs::remove_file(part_path(dst, self.parts)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the path of the `n`th parquet part file of `dst`, see the module documentation.
+fn part_path(dst: &Path, n: usize) -> PathBuf {
+    if n == 0 {
+        return dst.to_path_buf();
+    }
+    let stem = dst.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = dst.extension().map_or(String::new(), |e| format!(".{}", e.to_string_lossy()));
+    dst.with_file_name(format!("{}.{}{}", stem, n, extension))
+}
+
+/// Prints the progress of a conversion on a single terminal line.
+pub fn print_progress(done: usize, total: usize) {
+    eprint!("\rConverted {}/{} snapshots", done, total);
+    if done == total {
+        eprintln!();
+    }
+}
+
+fn check_destination(format: Format, dst: &Path) -> io::Result<()> {
+    let in_use = match format {
+        Format::Bincode => dst.is_dir() && fs::read_dir(dst)?.next().is_some(),
+        _ => dst.exists(),
+    };
+    match in_use {
+        true => Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already exists, refusing to overwrite it", dst.display()),
+        )),
+        false => Ok(()),
+    }
+}
+
+pub(crate) type Snapshots = Box<dyn Iterator<Item = io::Result<EncoDecode>>>;
+
+/// Opens an archive for reading and returns its number of snapshots and the snapshots, starting
+/// after the first `skip` ones. Skipped snapshots are not decoded.
+pub(crate) fn open_source(
+    format: Format,
+    src: &Path,
+    skip: usize,
+) -> io::Result<(usize, Snapshots)> {
+    match format {
+        Format::Bincode => {
+            let files = store::snapshot_files(src)?;
+            Ok((
+                files.len(),
+                Box::new(
+                    files
+                        .into_iter()
+                        .skip(skip)
+                        .map(|(_, path)| store::read_snapshot(&path)),
+                ),
+            ))
+        }
+        Format::Json => {
+            let mut lines = BufReader::new(File::open(src)?).lines().peekable();
+            // Archives written before the header was added start with a snapshot.
+            let has_header = match lines.peek() {
+                Some(Ok(first)) => serde_json::from_str::<Header>(first).is_ok(),
+                _ => false,
+            };
+            let total = BufReader::new(File::open(src)?).lines().count() - has_header as usize;
+            Ok((
+                total,
+                Box::new(lines.skip(has_header as usize + skip).map(|line| {
+                    serde_json::from_str(&line?)
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+                })),
+            ))
+        }
+        Format::Sqlite => sqlite::open(src, skip),
+        Format::Parquet | Format::Csv => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} archives can only be written", format),
+        )),
+    }
+}
+
+/// SnapshotWriter appends snapshots to an archive.
+pub(crate) trait SnapshotWriter {
+    fn write(&mut self, snapshot: &EncoDecode) -> io::Result<()>;
+    /// Writes out what is buffered, so that a checkpoint can be taken.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+    /// Flushes what is buffered. The archive is complete only after this returns.
+    fn finish(self: Box<Self>) -> io::Result<()>;
+}
+
+/// Creates an archive of `format` at `dst`, see `ConvertJob::dst`.
+pub(crate) fn create_writer(format: Format, dst: &Path) -> io::Result<Box<dyn SnapshotWriter>> {
+    match format {
+        Format::Bincode => {
+            fs::create_dir_all(dst)?;
+            Ok(Box::new(BackendWriter(DirBackend::new(dst, false))))
+        }
+        Format::Json => {
+            let mut file = BufWriter::new(File::create(dst)?);
+            serde_json::to_writer(&mut file, &Header::current())?;
+            file.write_all(b"\n")?;
+            Ok(Box::new(JsonWriter(file)))
+        }
+        Format::Sqlite => sqlite::create(dst),
+        Format::Parquet => parquet::create(dst),
+        Format::Csv => {
+            let mut file = BufWriter::new(File::create(dst)?);
+            writeln!(file, "{}", CSV_HEADER)?;
+            Ok(Box::new(CsvWriter(file)))
+        }
+    }
+}
+
+/// Opens the archive of `format` at `dst` to resume a conversion to it. Parquet files can't be
+/// appended to, see the module documentation.
+fn append_writer(format: Format, dst: &Path) -> io::Result<Box<dyn SnapshotWriter>> {
+    let append = || fs::OpenOptions::new().append(true).open(dst).map(BufWriter::new);
+    match format {
+        Format::Bincode => Ok(Box::new(ResumedWriter(DirBackend::new(dst, false)))),
+        Format::Json => Ok(Box::new(JsonWriter(append()?))),
+        Format::Sqlite => sqlite::append(dst),
+        Format::Parquet => parquet::create(dst),
+        Format::Csv => Ok(Box::new(CsvWriter(append()?))),
+    }
+}
+
+/// Formats that are storage backends are written through them.
+struct BackendWriter<B: StorageBackend>(B);
+
+impl<B: StorageBackend> SnapshotWriter for BackendWriter<B> {
+    fn write(&mut self, snapshot: &EncoDecode) -> io::Result<()> {
+        self.0.write_snapshot(snapshot).map(|_| ())
+    }
+
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Backends written to after a checkpoint may already hold the snapshots written between the
+/// checkpoint and the interruption, which are skipped.
+struct ResumedWriter<B: StorageBackend>(B);
+
+impl<B: StorageBackend> SnapshotWriter for ResumedWriter<B> {
+    fn write(&mut self, snapshot: &EncoDecode) -> io::Result<()> {
+        let epoch = snapshot.time_epoch;
+        match self.0.list_range(epoch, epoch)?.is_empty() {
+            true => self.0.write_snapshot(snapshot).map(|_| ()),
+            false => Ok(()),
+        }
+    }
+
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct JsonWriter(BufWriter<File>);
+
+impl SnapshotWriter for JsonWriter {
+    fn write(&mut self, snapshot: &EncoDecode) -> io::Result<()> {
+        serde_json::to_writer(&mut self.0, snapshot)?;
+        self.0.write_all(b"\n")
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()?;
+        self.0.get_ref().sync_all()
+    }
+
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+struct CsvWriter(BufWriter<File>);
+
+/// Quotes a CSV field if it holds a separator, a quote or a line break.
+fn csv_field(field: &str) -> String {
+    match field.contains(&[',', '"', '\n', '\r'][..]) {
+        true => format!("\"{}\"", field.replace('"', "\"\"")),
+        false => field.to_string(),
+    }
+}
+
+impl SnapshotWriter for CsvWriter {
+    fn write(&mut self, snapshot: &EncoDecode) -> io::Result<()> {
+        let optional = |value: Option<String>| value.unwrap_or_default();
+        let hostname = csv_field(&snapshot.hostname);
+        for (pid, s) in &snapshot.pid_map_list {
+            let io = |value: fn(&crate::proc_io::ProcIo) -> u64| {
+                optional(s.io.as_ref().map(|io| value(io).to_string()))
+            };
+            let fds = s.fds.as_ref().map(serde_json::to_string).transpose()?;
+            let connections = s
+                .connections
+                .as_ref()
+                .map(serde_json::to_string)
+                .transpose()?;
+            let limits = s.cgroup_limits.as_ref();
+            let fields = [
+                hostname.clone(),
+                snapshot.time_epoch.to_string(),
+                pid.to_string(),
+                s.ppid.to_string(),
+                s.euid.to_string(),
+                csv_field(&s.name),
+                csv_field(&s.cmd_long.join(" ")),
+                csv_field(&s.state),
+                s.fdsize.to_string(),
+                optional(fds.as_deref().map(csv_field)),
+                optional(connections.as_deref().map(csv_field)),
+                optional(s.vmpeak.map(|v| v.to_string())),
+                optional(s.vmsize.map(|v| v.to_string())),
+                s.rss_bytes.to_string(),
+                s.shared_pages.to_string(),
+                s.text_pages.to_string(),
+                s.data_pages.to_string(),
+                optional(s.rss_pct_of_limit.map(|v| v.to_string())),
+                s.utime.to_string(),
+                s.stime.to_string(),
+                optional(s.sched_policy.map(|p| p.to_string())),
+                optional(s.rt_priority.map(|p| p.to_string())),
+                io(|io| io.read_bytes),
+                io(|io| io.write_bytes),
+                io(|io| io.syscr),
+                io(|io| io.syscw),
+                io(|io| io.cancelled_write_bytes),
+                optional(s.service_hint.as_deref().map(csv_field)),
+                optional(s.runtime.as_deref().map(csv_field)),
+                optional(s.cgroup.as_deref().map(csv_field)),
+                optional(limits.and_then(|l| l.memory_max).map(|v| v.to_string())),
+                optional(limits.and_then(|l| l.cpus).map(|v| v.to_string())),
+                s.user_cpu_usage.to_string(),
+                s.sys_cpu_usage.to_string(),
+                s.restricted.to_string(),
+                s.vanished_during_scan.to_string(),
+                csv_field(&serde_json::to_string(&s.extensions)?),
+            ];
+            writeln!(self.0, "{}", fields.join(","))?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()?;
+        self.0.get_ref().sync_all()
+    }
+
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+/// Checks that the destination holds every snapshot written, with the same number of processes.
+/// Parquet and CSV files only record rows, so only the total number of processes is checked for
+/// them. After a resumed conversion, only the number of the snapshots written before the
+/// interruption is checked.
+fn validate(
+    format: Format,
+    dst: &Path,
+    written: &HashMap<u64, usize>,
+    stats: &ConvertStats,
+    parts: usize,
+    resumed: bool,
+) -> io::Result<()> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let rows = match format {
+        Format::Parquet => Some(
+            (0..=parts)
+                .map(|n| part_path(dst, n))
+                .filter(|part| part.exists())
+                .map(|part| parquet::count_rows(&part))
+                .sum::<io::Result<usize>>()?,
+        ),
+        Format::Csv => Some(BufReader::new(File::open(dst)?).lines().count() - 1),
+        _ => None,
+    };
+    if let Some(rows) = rows {
+        return match rows == stats.processes {
+            true => Ok(()),
+            false => Err(invalid(format!(
+                "validation failed: {} has {} rows, expected {}",
+                dst.display(),
+                rows,
+                stats.processes
+            ))),
+        };
+    }
+    let (total, snapshots) = open_source(format, dst, 0)?;
+    if total != stats.snapshots {
+        return Err(invalid(format!(
+            "validation failed: {} has {} snapshots, expected {}",
+            dst.display(),
+            total,
+            stats.snapshots
+        )));
+    }
+    for snapshot in snapshots {
+        let snapshot = snapshot?;
+        let matches = match written.get(&snapshot.time_epoch) {
+            Some(processes) => *processes == snapshot.pid_map_list.len(),
+            None => resumed,
+        };
+        if !matches {
+            return Err(invalid(format!(
+                "validation failed: snapshot {} of {} doesn't match the source",
+                snapshot.time_epoch,
+                dst.display()
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use std::io;
+    use std::path::Path;
+
+    use super::{BackendWriter, ResumedWriter, SnapshotWriter, Snapshots};
+    use crate::backend::sqlite::SqliteBackend;
+    use crate::backend::StorageBackend;
+
+    pub fn open(src: &Path, skip: usize) -> io::Result<(usize, Snapshots)> {
+        if !src.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} doesn't exist", src.display()),
+            ));
+        }
+        let backend = SqliteBackend::open(src)?;
+        let epochs = backend.list_range(0, u64::MAX)?;
+        Ok((
+            epochs.len(),
+            Box::new(
+                epochs
+                    .into_iter()
+                    .skip(skip)
+                    .map(move |epoch| backend.read_snapshot(epoch)),
+            ),
+        ))
+    }
+
+    pub fn create(dst: &Path) -> io::Result<Box<dyn SnapshotWriter>> {
+        Ok(Box::new(BackendWriter(SqliteBackend::open(dst)?)))
+    }
+
+    pub fn append(dst: &Path) -> io::Result<Box<dyn SnapshotWriter>> {
+        Ok(Box::new(ResumedWriter(SqliteBackend::open(dst)?)))
+    }
+}
+
+#[cfg(not(feature = "sqlite"))]
+mod sqlite {
+    use std::io;
+    use std::path::Path;
+
+    use super::{SnapshotWriter, Snapshots};
+
+    fn disabled() -> io::Error {
+        io::Error::other("sqlite archives need procshot_server built with the `sqlite` feature.")
+    }
+
+    pub fn open(_src: &Path, _skip: usize) -> io::Result<(usize, Snapshots)> {
+        Err(disabled())
+    }
+
+    pub fn create(_dst: &Path) -> io::Result<Box<dyn SnapshotWriter>> {
+        Err(disabled())
+    }
+
+    pub fn append(_dst: &Path) -> io::Result<Box<dyn SnapshotWriter>> {
+        Err(disabled())
+    }
+}
+
+#[cfg(feature = "parquet")]
+mod parquet {
+    use std::fs::File;
+    use std::io;
+    use std::path::Path;
+
+    use parquet::arrow::ArrowWriter;
+    use parquet::file::metadata::KeyValue;
+    use parquet::file::properties::WriterProperties;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+
+    use super::SnapshotWriter;
+    use crate::arrow::{schema, to_record_batch};
+    use crate::header::Header;
+    use crate::EncoDecode;
+
+    fn to_io(e: impl std::error::Error + Send + Sync + 'static) -> io::Error {
+        io::Error::other(e)
+    }
+
+    struct ParquetWriter(ArrowWriter<File>);
+
+    pub fn create(dst: &Path) -> io::Result<Box<dyn SnapshotWriter>> {
+        let header = KeyValue::new(
+            "procshot.header".to_string(),
+            serde_json::to_string(&Header::current())?,
+        );
+        let props = WriterProperties::builder()
+            .set_key_value_metadata(Some(vec![header]))
+            .set_max_row_group_size(super::PARQUET_ROW_GROUP_ROWS)
+            .build();
+        let writer =
+            ArrowWriter::try_new(File::create(dst)?, schema(), Some(props)).map_err(to_io)?;
+        Ok(Box::new(ParquetWriter(writer)))
+    }
+
+    impl SnapshotWriter for ParquetWriter {
+        fn write(&mut self, snapshot: &EncoDecode) -> io::Result<()> {
+            let batch = to_record_batch(std::slice::from_ref(snapshot)).map_err(to_io)?;
+            self.0.write(&batch).map_err(to_io)
+        }
+
+        fn finish(self: Box<Self>) -> io::Result<()> {
+            self.0.close().map(|_| ()).map_err(to_io)
+        }
+    }
+
+    pub fn count_rows(path: &Path) -> io::Result<usize> {
+        let reader = SerializedFileReader::new(File::open(path)?).map_err(to_io)?;
+        Ok(reader.metadata().file_metadata().num_rows() as usize)
+    }
+}
+
+#[cfg(not(feature = "parquet"))]
+mod parquet {
+    use std::io;
+    use std::path::Path;
+
+    use super::SnapshotWriter;
+
+    fn disabled() -> io::Error {
+        io::Error::other("parquet archives need procshot_server built with the `parquet` feature.")
+    }
+
+    pub fn create(_dst: &Path) -> io::Result<Box<dyn SnapshotWriter>> {
+        Err(disabled())
+    }
+
+    pub fn count_rows(_path: &Path) -> io::Result<usize> {
+        Err(disabled())
+    }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use super::*;
+    use crate::collect::{restricted_pid_status, PROC_ROOT};
+    use crate::Pid;
+
+    fn convert_test_data(to: Format, name: &str) -> (ConvertJob, ConvertStats) {
+        let dir =
+            std::env::temp_dir().join(format!("procshot_convert_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let src = dir.join("src");
+        let snapshot = EncoDecode {
+            hostname: "localghost".to_string(),
+            pid_map_list: vec![Pid::new(1), Pid::current()]
+                .into_iter()
+                .map(|pid| (pid, restricted_pid_status(Path::new(PROC_ROOT), pid)))
+                .collect(),
+            time_epoch: 1565151120,
+            delay: std::time::Duration::from_secs(60),
+            total_cpu_time: 1000,
+            ..Default::default()
+        };
+        fs::create_dir_all(&src).unwrap();
+        DirBackend::new(&src, false)
+            .write_snapshot(&snapshot)
+            .unwrap();
+        let job = ConvertJob {
+            from: None,
+            to,
+            src,
+            dst: dir.join("dst"),
+            validate: true,
+            resume: false,
+        };
+        let mut calls = 0;
+        let stats = convert(&job, &mut |done, total| {
+            calls += 1;
+            assert_eq!((done, total), (1, 1));
+        })
+        .unwrap();
+        assert_eq!(calls, 1);
+        assert_eq!(stats.snapshots, 1);
+        (job, stats)
+    }
+
+    #[test]
+    fn test_convert_json_and_back() {
+        let (job, stats) = convert_test_data(Format::Json, "json");
+        assert_eq!(Format::detect(&job.src).unwrap(), Format::Bincode);
+        assert_eq!(Format::detect(&job.dst).unwrap(), Format::Json);
+        let unknown = job.dst.with_file_name("unknown");
+        fs::write(&unknown, b"\x0a\x00").unwrap();
+        assert!(Format::detect(&unknown).is_err());
+        let content = fs::read_to_string(&job.dst).unwrap();
+        let header: Header = serde_json::from_str(content.lines().next().unwrap()).unwrap();
+        assert_eq!(header, Header::current());
+        let back = ConvertJob {
+            from: None,
+            to: Format::Bincode,
+            src: job.dst.clone(),
+            dst: job.dst.with_file_name("back"),
+            validate: true,
+            resume: false,
+        };
+        assert_eq!(convert(&back, &mut |_, _| ()).unwrap(), stats);
+        let original = store::read_snapshot(&job.src.join("1565151120.procshot")).unwrap();
+        let converted = store::read_snapshot(&back.dst.join("1565151120.procshot")).unwrap();
+        assert_eq!(original, converted);
+        assert_eq!(
+            convert(&back, &mut |_, _| ()).unwrap_err().kind(),
+            io::ErrorKind::AlreadyExists
+        );
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_convert_sqlite() {
+        let (job, _) = convert_test_data(Format::Sqlite, "sqlite");
+        assert_eq!(Format::detect(&job.dst).unwrap(), Format::Sqlite);
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn test_convert_parquet() {
+        let (job, _) = convert_test_data(Format::Parquet, "parquet");
+        assert_eq!(Format::detect(&job.dst).unwrap(), Format::Parquet);
+        let schema = crate::arrow::schema();
+        let columns: Vec<&str> = schema.fields().iter().map(|f| f.name().as_str()).collect();
+        assert_eq!(columns.join(","), CSV_HEADER);
+    }
+
+    #[test]
+    fn test_convert_csv() {
+        let (job, stats) = convert_test_data(Format::Csv, "csv");
+        assert_eq!(Format::detect(&job.dst).unwrap(), Format::Csv);
+        let content = fs::read_to_string(&job.dst).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines[0], CSV_HEADER);
+        assert_eq!(lines.len(), stats.processes + 1);
+        assert!(lines
+            .iter()
+            .any(|l| l.starts_with("localghost,1565151120,1,")));
+        assert_eq!(csv_field("a,\"b\""), "\"a,\"\"b\"\"\"");
+        assert_eq!(csv_field("{}"), "{}");
+
+        // The columns of the metrics are named by the registry. rss_pages and rsslim_bytes are
+        // left out, rss_bytes and rss_pct_of_limit tell the same.
+        let columns: Vec<&str> = CSV_HEADER.split(',').collect();
+        for metric in crate::metrics::PROCESS_METRICS.iter().filter(|m| m.field) {
+            let column = metric.column();
+            let exported = !["rss_pages", "rsslim_bytes"].contains(&metric.name);
+            assert_eq!(columns.contains(&column.as_str()), exported, "{}", metric);
+        }
+    }
+
+    #[test]
+    fn test_convert_resume() {
+        let dir =
+            std::env::temp_dir().join(format!("procshot_convert_resume_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let src = dir.join("src");
+        fs::create_dir_all(&src).unwrap();
+        let mut backend = DirBackend::new(&src, false);
+        let total = CHECKPOINT_EVERY + 5;
+        for epoch in 0..total as u64 {
+            backend
+                .write_snapshot(&EncoDecode {
+                    hostname: "localghost".to_string(),
+                    time_epoch: 1565151120 + epoch,
+                    delay: std::time::Duration::from_secs(1),
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+        let job = ConvertJob {
+            from: None,
+            to: Format::Json,
+            src,
+            dst: dir.join("dst.json"),
+            validate: true,
+            resume: true,
+        };
+        // Interrupted after the first checkpoint, with a snapshot written after it.
+        let mut done = 0;
+        let interrupted = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            convert(&job, &mut |d, _| {
+                done = d;
+                assert!(d <= CHECKPOINT_EVERY + 1);
+            })
+        }));
+        assert!(interrupted.is_err());
+        assert_eq!(done, CHECKPOINT_EVERY + 2);
+        let checkpoint = Checkpoint::load(&Checkpoint::path(&job.dst))
+            .unwrap()
+            .unwrap();
+        assert_eq!(checkpoint.done, CHECKPOINT_EVERY);
+
+        let mut first = None;
+        let stats = convert(&job, &mut |d, t| {
+            first.get_or_insert(d);
+            assert_eq!(t, total);
+        })
+        .unwrap();
+        assert_eq!(first, Some(CHECKPOINT_EVERY + 1));
+        assert_eq!(stats.snapshots, total);
+        assert!(!Checkpoint::path(&job.dst).exists());
+        let (written, snapshots) = open_source(Format::Json, &job.dst, 0).unwrap();
+        assert_eq!(written, total);
+        let epochs: Vec<u64> = snapshots.map(|s| s.unwrap().time_epoch).collect();
+        assert_eq!(epochs.windows(2).filter(|w| w[1] != w[0] + 1).count(), 0);
+
+        assert_eq!(
+            part_path(Path::new("/tmp/month.parquet"), 2),
+            Path::new("/tmp/month.2.parquet")
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,185 @@
+//! System wide CPU time accounting from /proc/stat.
+//!
+//! The first line of /proc/stat sums the times of every CPU, and hides a single core saturated by
+//! a single threaded process on a host that is otherwise idle. The snapshots keep that line in
+//! `EncoDecode::cpu_times`, and the line of each CPU in `EncoDecode::per_cpu`.
+
+use std::collections::BTreeMap;
+
+/// CpuTimes holds the columns of a `cpu` line of /proc/stat, in clock ticks since boot. Fields
+/// missing on older kernels are 0.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Copy, Default)]
+pub struct CpuTimes {
+    pub user: u64,
+    pub nice: u64,
+    pub system: u64,
+    pub idle: u64,
+    pub iowait: u64,
+    pub irq: u64,
+    pub softirq: u64,
+    /// Time stolen by the hypervisor to run other virtual machines (since Linux 2.6.11).
+    pub steal: u64,
+    /// Time spent running a virtual CPU for guests (since Linux 2.6.24). Already included in user.
+    pub guest: u64,
+    /// Time spent running a niced guest (since Linux 2.6.33). Already included in nice.
+    pub guest_nice: u64,
+}
+
+impl CpuTimes {
+    /// Parses a `cpu` or `cpuN` line of /proc/stat.
+    pub fn parse(line: &str) -> Option<CpuTimes> {
+        let mut fields = line.split_whitespace();
+        if !fields.next()?.starts_with("cpu") {
+            return None;
+        }
+        let values: Vec<u64> = fields.map(|f| f.parse().ok()).collect::<Option<_>>()?;
+        if values.len() < 4 {
+            return None;
+        }
+        let v = |i: usize| values.get(i).copied().unwrap_or(0);
+        Some(CpuTimes {
+            user: v(0),
+            nice: v(1),
+            system: v(2),
+            idle: v(3),
+            iowait: v(4),
+            irq: v(5),
+            softirq: v(6),
+            steal: v(7),
+            guest: v(8),
+            guest_nice: v(9),
+        })
+    }
+
+    /// Sum of every column, as recorded in `EncoDecode::total_cpu_time`.
+    pub fn total(&self) -> u64 {
+        self.user
+            + self.nice
+            + self.system
+            + self.idle
+            + self.iowait
+            + self.irq
+            + self.softirq
+            + self.steal
+            + self.guest
+            + self.guest_nice
+    }
+
+    /// Sum of the columns without double counting guest time, which the kernel also accounts in
+    /// user and nice.
+    pub fn elapsed(&self) -> u64 {
+        self.total() - self.guest - self.guest_nice
+    }
+
+    /// Percentage of the interval between `previous` and `self` that was stolen by the hypervisor.
+    /// Returns None if no time elapsed, eg: the counters were reset by a reboot.
+    pub fn steal_percent(&self, previous: &CpuTimes) -> Option<f64> {
+        self.percent_of_interval(previous, |t| t.steal)
+    }
+
+    /// Percentage of the interval between `previous` and `self` spent running guests.
+    pub fn guest_percent(&self, previous: &CpuTimes) -> Option<f64> {
+        self.percent_of_interval(previous, |t| t.guest + t.guest_nice)
+    }
+
+    /// Percentage of the interval between `previous` and `self` the CPUs were busy, that is
+    /// neither idle nor waiting for IO.
+    pub fn busy_percent(&self, previous: &CpuTimes) -> Option<f64> {
+        self.percent_of_interval(previous, |t| t.elapsed() - t.idle - t.iowait)
+    }
+
+    fn percent_of_interval<F: Fn(&CpuTimes) -> u64>(
+        &self,
+        previous: &CpuTimes,
+        field: F,
+    ) -> Option<f64> {
+        let elapsed = self.elapsed().checked_sub(previous.elapsed())?;
+        let value = field(self).checked_sub(field(previous))?;
+        match elapsed {
+            0 => None,
+            _ => Some(100.0 * value as f64 / elapsed as f64),
+        }
+    }
+}
+
+/// Parses the content of /proc/stat, and returns the times of its first line and those of each
+/// CPU, by index. Offline CPUs have no line. Returns None if the first line isn't a `cpu` line.
+pub fn parse_stat(content: &str) -> Option<(CpuTimes, BTreeMap<u32, CpuTimes>)> {
+    let mut lines = content.lines();
+    let total = lines.next().filter(|l| l.starts_with("cpu "))?;
+    let per_cpu = lines
+        .filter_map(|line| {
+            let index = line.split_whitespace().next()?.strip_prefix("cpu")?;
+            Some((index.parse().ok()?, CpuTimes::parse(line)?))
+        })
+        .collect();
+    Some((CpuTimes::parse(total)?, per_cpu))
+}
+
+/// Returns the busy percentage of each CPU found in both `current` and `previous`, see
+/// `CpuTimes::busy_percent`. CPUs without time elapsed in between are left out.
+pub fn busy_percent_per_cpu(
+    current: &BTreeMap<u32, CpuTimes>,
+    previous: &BTreeMap<u32, CpuTimes>,
+) -> BTreeMap<u32, f64> {
+    current
+        .iter()
+        .filter_map(|(cpu, times)| Some((*cpu, times.busy_percent(previous.get(cpu)?)?)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let t = CpuTimes::parse("cpu  4705 356 584 3699 23 23 0 12 34 5").unwrap();
+        assert_eq!(t.user, 4705);
+        assert_eq!(t.steal, 12);
+        assert_eq!(t.guest_nice, 5);
+        assert_eq!(t.total(), 4705 + 356 + 584 + 3699 + 23 + 23 + 12 + 34 + 5);
+        // Kernels before 2.6.11 have no steal column.
+        let old = CpuTimes::parse("cpu0 1 2 3 4 5 6 7").unwrap();
+        assert_eq!(old.steal, 0);
+        assert_eq!(CpuTimes::parse("intr 1 2 3 4"), None);
+    }
+
+    #[test]
+    fn test_parse_stat() {
+        let content = "cpu  300 0 100 600 0 0 0 0 0 0\ncpu0 250 0 50 100 0 0 0 0 0 0\ncpu2 50 0 50 500 0 0 0 0 0 0\nintr 1 2 3\nctxt 42\n";
+        let (total, per_cpu) = parse_stat(content).unwrap();
+        assert_eq!(total.user, 300);
+        assert_eq!(per_cpu.keys().copied().collect::<Vec<_>>(), vec![0, 2]);
+        assert_eq!(per_cpu[&2].idle, 500);
+        assert_eq!(parse_stat("intr 1 2 3\n"), None);
+
+        // cpu0 was saturated while the host as a whole was half idle.
+        let later = "cpu  500 0 100 700 0 0 0 0 0 0\ncpu0 450 0 50 100 0 0 0 0 0 0\ncpu2 50 0 50 600 0 0 0 0 0 0\n";
+        let (later_total, later_per_cpu) = parse_stat(later).unwrap();
+        assert_eq!(later_total.busy_percent(&total), Some(200.0 / 3.0));
+        let busy = busy_percent_per_cpu(&later_per_cpu, &per_cpu);
+        assert_eq!(busy.get(&0), Some(&100.0));
+        assert_eq!(busy.get(&2), Some(&0.0));
+    }
+
+    #[test]
+    fn test_steal_percent() {
+        let before = CpuTimes {
+            user: 100,
+            idle: 100,
+            steal: 0,
+            ..Default::default()
+        };
+        let after = CpuTimes {
+            user: 150,
+            idle: 130,
+            steal: 20,
+            ..Default::default()
+        };
+        assert_eq!(after.steal_percent(&before), Some(20.0));
+        assert_eq!(before.steal_percent(&after), None);
+        assert_eq!(before.steal_percent(&before), None);
+        assert_eq!(after.busy_percent(&before), Some(70.0));
+    }
+}
@@ -0,0 +1,308 @@
+//! Per-process delays from the kernel's delay accounting.
+//!
+//! Delay accounting measures the time tasks spend waiting: for a CPU while runnable, for
+//! synchronous block I/O to complete, and for pages to be swapped in. These are the most direct
+//! answer to why a process was slow, and nothing in /proc has them per process. They are read
+//! with the TASKSTATS generic netlink family, one request per process, which sums the delays of
+//! all its live threads.
+//!
+//! No privilege is needed to query a process, but since Linux 5.14 the kernel only accounts
+//! block I/O and swap-in delays once enabled with the `kernel.task_delayacct` sysctl or the
+//! `delayacct` boot parameter, they read as 0 otherwise. The CPU delay comes from the scheduler
+//! statistics and is always there.
+
+use std::fs;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+
+use crate::Pid;
+
+/// Key in `PidStatus::extensions` of the time spent waiting for a CPU, in nanoseconds.
+pub const EXT_CPU_DELAY_NS: &str = "cpu_delay_ns";
+/// Key in `PidStatus::extensions` of the time spent waiting for block I/O, in nanoseconds.
+pub const EXT_BLKIO_DELAY_NS: &str = "blkio_delay_ns";
+/// Key in `PidStatus::extensions` of the time spent waiting for swap-ins, in nanoseconds.
+pub const EXT_SWAPIN_DELAY_NS: &str = "swapin_delay_ns";
+
+/// Where the kernel tells whether delays are accounted.
+const SYSCTL: &str = "/proc/sys/kernel/task_delayacct";
+
+const GENL_ID_CTRL: u16 = 0x10;
+const CTRL_CMD_GETFAMILY: u8 = 3;
+const CTRL_ATTR_FAMILY_ID: u16 = 1;
+const CTRL_ATTR_FAMILY_NAME: u16 = 2;
+const TASKSTATS_GENL_NAME: &[u8] = b"TASKSTATS\0";
+const TASKSTATS_GENL_VERSION: u8 = 1;
+const TASKSTATS_CMD_GET: u8 = 1;
+const TASKSTATS_CMD_ATTR_TGID: u16 = 2;
+const TASKSTATS_TYPE_STATS: u16 = 3;
+const TASKSTATS_TYPE_AGGR_TGID: u16 = 5;
+
+/// Size of struct nlmsghdr.
+const NLMSG_HDRLEN: usize = 16;
+/// Size of struct genlmsghdr.
+const GENL_HDRLEN: usize = 4;
+/// Size of struct nlattr.
+const NLA_HDRLEN: usize = 4;
+/// Offset of cpu_count in struct taskstats, after the version, exit code, flag and nice.
+const CPU_COUNT: usize = 16;
+
+/// Delays are the delays of a process since it started, summed over its live threads.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Delays {
+    /// Number of times the process waited for a CPU.
+    pub cpu_count: u64,
+    pub cpu_delay_ns: u64,
+    /// Number of synchronous block I/O the process waited for.
+    pub blkio_count: u64,
+    pub blkio_delay_ns: u64,
+    /// Number of swap-ins the process waited for.
+    pub swapin_count: u64,
+    pub swapin_delay_ns: u64,
+}
+
+/// Taskstats is a generic netlink socket to the TASKSTATS family.
+#[derive(Debug)]
+pub struct Taskstats {
+    fd: OwnedFd,
+    family: u16,
+}
+
+impl Taskstats {
+    /// Opens the socket and resolves the family. Fails with `NotFound` if the kernel was built
+    /// without CONFIG_TASKSTATS.
+    pub fn connect() -> io::Result<Self> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+                libc::NETLINK_GENERIC,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut taskstats = Taskstats {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+            family: GENL_ID_CTRL,
+        };
+        let reply = taskstats
+            .request(
+                CTRL_CMD_GETFAMILY,
+                CTRL_ATTR_FAMILY_NAME,
+                TASKSTATS_GENL_NAME,
+            )
+            .map_err(|e| match e.raw_os_error() {
+                Some(libc::ENOENT) => {
+                    io::Error::new(io::ErrorKind::NotFound, "taskstats is not supported")
+                }
+                _ => e,
+            })?;
+        taskstats.family = attribute(&reply, CTRL_ATTR_FAMILY_ID)
+            .and_then(|id| u16_at(id, 0))
+            .ok_or_else(|| malformed("family"))?;
+        Ok(taskstats)
+    }
+
+    /// Returns the delays of `pid`. Fails with the `ESRCH` OS error if it exited.
+    pub fn delays(&self, pid: Pid) -> io::Result<Delays> {
+        let reply = self.request(
+            TASKSTATS_CMD_GET,
+            TASKSTATS_CMD_ATTR_TGID,
+            &(pid.as_raw() as u32).to_ne_bytes(),
+        )?;
+        let stats = attribute(&reply, TASKSTATS_TYPE_AGGR_TGID)
+            .and_then(|aggr| attribute(aggr, TASKSTATS_TYPE_STATS))
+            .ok_or_else(|| malformed("taskstats"))?;
+        parse_delays(stats).ok_or_else(|| malformed("taskstats"))
+    }
+
+    /// Sends a request of one attribute and returns the attributes of the reply.
+    fn request(&self, cmd: u8, attr: u16, value: &[u8]) -> io::Result<Vec<u8>> {
+        let msg = message(self.family, cmd, attr, value);
+        let sent = unsafe {
+            libc::send(
+                self.fd.as_raw_fd(),
+                msg.as_ptr() as *const libc::c_void,
+                msg.len(),
+                0,
+            )
+        };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut buf = vec![0u8; 4096];
+        let n = loop {
+            let n = unsafe {
+                libc::recv(
+                    self.fd.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                    0,
+                )
+            };
+            if n >= 0 {
+                break n as usize;
+            }
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        };
+        buf.truncate(n);
+        if u16_at(&buf, 4) == Some(libc::NLMSG_ERROR as u16) {
+            let errno = u32_at(&buf, NLMSG_HDRLEN).ok_or_else(|| malformed("error"))? as i32;
+            return Err(io::Error::from_raw_os_error(-errno));
+        }
+        let len = (u32_at(&buf, 0).ok_or_else(|| malformed("reply"))? as usize).min(buf.len());
+        Ok(buf
+            .get(NLMSG_HDRLEN + GENL_HDRLEN..len)
+            .ok_or_else(|| malformed("reply"))?
+            .to_vec())
+    }
+}
+
+/// Returns true unless the kernel says block I/O and swap-in delays aren't accounted. Kernels before 5.14 have no
+/// switch and always account them.
+pub fn enabled() -> bool {
+    fs::read_to_string(SYSCTL).map_or(true, |s| s.trim() != "0")
+}
+
+/// Returns a generic netlink request with one attribute.
+fn message(family: u16, cmd: u8, attr: u16, value: &[u8]) -> Vec<u8> {
+    let attr_len = NLA_HDRLEN + value.len();
+    let len = NLMSG_HDRLEN + GENL_HDRLEN + align(attr_len);
+    let mut msg = Vec::with_capacity(len);
+    // struct nlmsghdr
+    msg.extend_from_slice(&(len as u32).to_ne_bytes());
+    msg.extend_from_slice(&family.to_ne_bytes());
+    msg.extend_from_slice(&(libc::NLM_F_REQUEST as u16).to_ne_bytes());
+    msg.extend_from_slice(&0u32.to_ne_bytes());
+    msg.extend_from_slice(&std::process::id().to_ne_bytes());
+    // struct genlmsghdr
+    msg.extend_from_slice(&[cmd, TASKSTATS_GENL_VERSION, 0, 0]);
+    // struct nlattr
+    msg.extend_from_slice(&(attr_len as u16).to_ne_bytes());
+    msg.extend_from_slice(&attr.to_ne_bytes());
+    msg.extend_from_slice(value);
+    msg.resize(len, 0);
+    msg
+}
+
+/// Rounds `len` up to the 4 bytes alignment of netlink attributes.
+pub(crate) fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// Returns the payload of the attribute `kind` among `attrs`.
+pub(crate) fn attribute(attrs: &[u8], kind: u16) -> Option<&[u8]> {
+    let mut offset = 0;
+    while offset + NLA_HDRLEN <= attrs.len() {
+        let len = u16_at(attrs, offset)? as usize;
+        if len < NLA_HDRLEN {
+            return None;
+        }
+        // The high bits of the type are the nested and byte order flags.
+        if u16_at(attrs, offset + 2)? & 0x3fff == kind {
+            return attrs.get(offset + NLA_HDRLEN..offset + len);
+        }
+        offset += align(len);
+    }
+    None
+}
+
+/// Parses the delay fields of struct taskstats, which are in every version of it.
+fn parse_delays(stats: &[u8]) -> Option<Delays> {
+    let field = |n: usize| u64_at(stats, CPU_COUNT + 8 * n);
+    Some(Delays {
+        cpu_count: field(0)?,
+        cpu_delay_ns: field(1)?,
+        blkio_count: field(2)?,
+        blkio_delay_ns: field(3)?,
+        swapin_count: field(4)?,
+        swapin_delay_ns: field(5)?,
+    })
+}
+
+fn malformed(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("malformed {} reply from taskstats", what),
+    )
+}
+
+pub(crate) fn u16_at(buf: &[u8], offset: usize) -> Option<u16> {
+    let mut bytes = [0u8; 2];
+    bytes.copy_from_slice(buf.get(offset..offset + 2)?);
+    Some(u16::from_ne_bytes(bytes))
+}
+
+pub(crate) fn u32_at(buf: &[u8], offset: usize) -> Option<u32> {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(buf.get(offset..offset + 4)?);
+    Some(u32::from_ne_bytes(bytes))
+}
+
+pub(crate) fn u64_at(buf: &[u8], offset: usize) -> Option<u64> {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(buf.get(offset..offset + 8)?);
+    Some(u64::from_ne_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let mut stats = vec![0u8; 328];
+        for n in 0..6 {
+            let offset = CPU_COUNT + 8 * n;
+            stats[offset..offset + 8].copy_from_slice(&(n as u64 + 1).to_ne_bytes());
+        }
+        let pid = 42u32.to_ne_bytes();
+        // AGGR_TGID nesting PID and STATS, as the kernel sends them.
+        let mut aggr = Vec::new();
+        aggr.extend_from_slice(&8u16.to_ne_bytes());
+        aggr.extend_from_slice(&2u16.to_ne_bytes());
+        aggr.extend_from_slice(&pid);
+        aggr.extend_from_slice(&(4 + stats.len() as u16).to_ne_bytes());
+        aggr.extend_from_slice(&TASKSTATS_TYPE_STATS.to_ne_bytes());
+        aggr.extend_from_slice(&stats);
+        let mut reply = Vec::new();
+        reply.extend_from_slice(&(4 + aggr.len() as u16).to_ne_bytes());
+        reply.extend_from_slice(&(TASKSTATS_TYPE_AGGR_TGID | 0x8000).to_ne_bytes());
+        reply.extend_from_slice(&aggr);
+
+        let stats = attribute(&reply, TASKSTATS_TYPE_AGGR_TGID)
+            .and_then(|aggr| attribute(aggr, TASKSTATS_TYPE_STATS))
+            .unwrap();
+        let delays = parse_delays(stats).unwrap();
+        assert_eq!(delays.cpu_count, 1);
+        assert_eq!(delays.blkio_delay_ns, 4);
+        assert_eq!(delays.swapin_delay_ns, 6);
+        assert_eq!(attribute(&reply, TASKSTATS_TYPE_STATS), None);
+
+        let msg = message(0x1a, TASKSTATS_CMD_GET, TASKSTATS_CMD_ATTR_TGID, &pid);
+        assert_eq!(msg.len(), 28);
+        assert_eq!(
+            attribute(&msg[NLMSG_HDRLEN + GENL_HDRLEN..], 2),
+            Some(&pid[..])
+        );
+    }
+
+    #[test]
+    fn test_delays() {
+        // Kernels without CONFIG_TASKSTATS, or sandboxes without generic netlink.
+        let taskstats = match Taskstats::connect() {
+            Ok(t) => t,
+            Err(_) => return,
+        };
+        let delays = taskstats.delays(Pid::current()).unwrap();
+        if enabled() {
+            assert!(delays.cpu_count > 0);
+        }
+        let e = taskstats.delays(Pid::new(i32::MAX)).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::ESRCH));
+    }
+}
@@ -0,0 +1,126 @@
+//! I/O statistics of the block devices, from /proc/diskstats.
+//!
+//! A process stuck in uninterruptible sleep during a latency incident points to the disks, but
+//! only the counters of the devices tell if they were saturated. Every snapshot records them in
+//! `EncoDecode::disks`, and two snapshots give the reads, writes and busy time of each device
+//! in between.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// DiskStats holds the counters of a block device since boot, as a line of /proc/diskstats
+/// reports them. Sectors are of 512 bytes whatever the sector size of the device.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Default)]
+pub struct DiskStats {
+    /// Name of the device, eg: `sda`, `sda1` or `nvme0n1`.
+    pub name: String,
+    pub major: u32,
+    pub minor: u32,
+    /// Reads completed.
+    pub reads: u64,
+    /// Adjacent reads merged into one before being sent to the device.
+    pub reads_merged: u64,
+    pub sectors_read: u64,
+    /// Time spent by all the reads, in milliseconds.
+    pub read_time_ms: u64,
+    /// Writes completed.
+    pub writes: u64,
+    pub writes_merged: u64,
+    pub sectors_written: u64,
+    pub write_time_ms: u64,
+    /// I/Os in flight when it was read.
+    pub in_flight: u64,
+    /// Time the device had I/Os in flight, in milliseconds. It grows as fast as the wall clock
+    /// when the device is always busy.
+    pub io_time_ms: u64,
+    /// Time spent by all the I/Os, in milliseconds, weighted by the number in flight.
+    pub weighted_io_time_ms: u64,
+}
+
+impl DiskStats {
+    /// Parses a line of /proc/diskstats, eg:
+    /// `8 0 sda 4160 1207 345218 1722 7581 8853 380514 10238 0 8508 12652 0 0 0 0`. The discard
+    /// and flush counters of newer kernels are not kept.
+    pub fn parse(line: &str) -> Option<DiskStats> {
+        let mut fields = line.split_whitespace();
+        let (major, minor, name) = (fields.next()?, fields.next()?, fields.next()?);
+        let counters: Vec<u64> = fields
+            .take(11)
+            .map(|f| f.parse().ok())
+            .collect::<Option<_>>()?;
+        if counters.len() < 11 {
+            return None;
+        }
+        Some(DiskStats {
+            name: name.to_string(),
+            major: major.parse().ok()?,
+            minor: minor.parse().ok()?,
+            reads: counters[0],
+            reads_merged: counters[1],
+            sectors_read: counters[2],
+            read_time_ms: counters[3],
+            writes: counters[4],
+            writes_merged: counters[5],
+            sectors_written: counters[6],
+            write_time_ms: counters[7],
+            in_flight: counters[8],
+            io_time_ms: counters[9],
+            weighted_io_time_ms: counters[10],
+        })
+    }
+
+    /// Whether the device was never read from nor written to, like the unused loop and ram
+    /// devices.
+    pub fn is_idle(&self) -> bool {
+        self.reads == 0 && self.writes == 0
+    }
+}
+
+/// Parses the content of /proc/diskstats, without the idle devices. Malformed lines are skipped.
+pub fn parse(content: &str) -> Vec<DiskStats> {
+    content
+        .lines()
+        .filter_map(DiskStats::parse)
+        .filter(|disk| !disk.is_idle())
+        .collect()
+}
+
+/// Reads the diskstats file of the proc filesystem at `proc_root`.
+pub fn read_in(proc_root: &Path) -> io::Result<Vec<DiskStats>> {
+    Ok(parse(&fs::read_to_string(proc_root.join("diskstats"))?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let content = "   7       0 loop0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0\n   8       0 sda 4160 1207 345218 1722 7581 8853 380514 10238 0 8508 12652 0 0 0 0\n   8       1 sda1 x\n";
+        let disks = parse(content);
+        assert_eq!(
+            disks,
+            vec![DiskStats {
+                name: "sda".to_string(),
+                major: 8,
+                minor: 0,
+                reads: 4160,
+                reads_merged: 1207,
+                sectors_read: 345218,
+                read_time_ms: 1722,
+                writes: 7581,
+                writes_merged: 8853,
+                sectors_written: 380514,
+                write_time_ms: 10238,
+                in_flight: 0,
+                io_time_ms: 8508,
+                weighted_io_time_ms: 12652,
+            }]
+        );
+        // Kernels before 4.18 have no discard counters.
+        assert!(DiskStats::parse("8 0 sda 1 0 8 1 0 0 0 0 0 1 1").is_some());
+        assert_eq!(DiskStats::parse("8 0 sda 1 0 8 1 0 0 0 0 0 1"), None);
+        read_in(Path::new("/proc")).unwrap();
+    }
+}
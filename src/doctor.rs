@@ -0,0 +1,308 @@
+//! Environment diagnostics for the procshot server.
+//!
+//! `run_checks` probes the kernel features the collectors rely on, the `/proc` mount options, the
+//! datadir and the system clock, and returns one `Check` per probe. `print_capability_matrix`
+//! renders them as a table so setup issues can be spotted before any snapshot is written.
+
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+/// Outcome of a single diagnostic check.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CheckStatus {
+    /// The feature is available and usable.
+    Ok,
+    /// The feature is available, but in a degraded form.
+    Warn,
+    /// The feature is not available on this host.
+    Missing,
+    /// The check itself failed, usually a permission or IO problem.
+    Fail,
+}
+
+impl CheckStatus {
+    fn label(self) -> &'static str {
+        match self {
+            CheckStatus::Ok => "ok",
+            CheckStatus::Warn => "warn",
+            CheckStatus::Missing => "missing",
+            CheckStatus::Fail => "FAIL",
+        }
+    }
+}
+
+/// Check holds the result of one diagnostic probe.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Check {
+    /// Short name of the capability that was probed.
+    pub name: &'static str,
+    /// Result of the probe.
+    pub status: CheckStatus,
+    /// Human readable explanation of the result.
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: &'static str, status: CheckStatus, detail: String) -> Self {
+        Check {
+            name,
+            status,
+            detail,
+        }
+    }
+}
+
+/// Runs every diagnostic check against the running host and the given datadir.
+pub fn run_checks(datadir: &Path) -> Vec<Check> {
+    vec![
+        check_readable(
+            "psi",
+            "/proc/pressure/cpu",
+            "pressure stall information (CONFIG_PSI)",
+        ),
+        check_readable(
+            "smaps_rollup",
+            "/proc/self/smaps_rollup",
+            "per process memory rollup (Linux 4.14+)",
+        ),
+        check_readable(
+            "io_accounting",
+            "/proc/self/io",
+            "per process io accounting (CONFIG_TASK_IO_ACCOUNTING)",
+        ),
+        check_cgroup_v2(),
+        check_proc_mount(),
+        check_datadir(datadir),
+        check_clock(),
+    ]
+}
+
+/// Returns true if none of the checks failed outright. Missing optional features do not count as
+/// failures.
+pub fn all_passed(checks: &[Check]) -> bool {
+    checks.iter().all(|c| c.status != CheckStatus::Fail)
+}
+
+/// Prints the checks as a capability matrix on stdout.
+pub fn print_capability_matrix(checks: &[Check]) {
+    let width = checks.iter().map(|c| c.name.len()).max().unwrap_or(0);
+    println!("{:<width$}  {:<7}  detail", "check", "status", width = width);
+    for c in checks {
+        println!(
+            "{:<width$}  {:<7}  {}",
+            c.name,
+            c.status.label(),
+            c.detail,
+            width = width
+        );
+    }
+}
+
+fn check_readable(name: &'static str, path: &str, what: &str) -> Check {
+    match File::open(path) {
+        Ok(_) => Check::new(name, CheckStatus::Ok, format!("{} available", what)),
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Check::new(
+            name,
+            CheckStatus::Missing,
+            format!("{} not found at {}", what, path),
+        ),
+        Err(e) => Check::new(
+            name,
+            CheckStatus::Fail,
+            format!("cannot read {}: {}", path, e),
+        ),
+    }
+}
+
+fn check_cgroup_v2() -> Check {
+    match fs::read_to_string("/sys/fs/cgroup/cgroup.controllers") {
+        Ok(controllers) => Check::new(
+            "cgroup_v2",
+            CheckStatus::Ok,
+            format!("unified hierarchy, controllers: {}", controllers.trim()),
+        ),
+        Err(_) => Check::new(
+            "cgroup_v2",
+            CheckStatus::Missing,
+            "no unified cgroup hierarchy at /sys/fs/cgroup".to_string(),
+        ),
+    }
+}
+
+fn check_proc_mount() -> Check {
+    let f = match File::open("/proc/mounts") {
+        Ok(f) => f,
+        Err(e) => {
+            return Check::new(
+                "proc_mount",
+                CheckStatus::Fail,
+                format!("cannot read /proc/mounts: {}", e),
+            )
+        }
+    };
+    for line in BufReader::new(f).lines().map_while(Result::ok) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 4 || fields[1] != "/proc" {
+            continue;
+        }
+        return match hidepid_option(fields[3]) {
+            Some(v) if v != "0" && v != "off" => Check::new(
+                "proc_mount",
+                CheckStatus::Warn,
+                format!(
+                    "/proc mounted with hidepid={}, processes of other users may be invisible",
+                    v
+                ),
+            ),
+            _ => Check::new(
+                "proc_mount",
+                CheckStatus::Ok,
+                format!("/proc mounted with {}", fields[3]),
+            ),
+        };
+    }
+    Check::new(
+        "proc_mount",
+        CheckStatus::Fail,
+        "/proc not found in /proc/mounts".to_string(),
+    )
+}
+
+/// Extracts the value of the hidepid option from a comma separated mount option string.
+fn hidepid_option(opts: &str) -> Option<&str> {
+    opts.split(',').find_map(|o| o.strip_prefix("hidepid="))
+}
+
+fn check_datadir(datadir: &Path) -> Check {
+    let meta = match fs::metadata(datadir) {
+        Ok(m) => m,
+        Err(e) => {
+            return Check::new(
+                "datadir",
+                CheckStatus::Fail,
+                format!("cannot stat {}: {}", datadir.display(), e),
+            )
+        }
+    };
+    if !meta.is_dir() {
+        return Check::new(
+            "datadir",
+            CheckStatus::Fail,
+            format!("{} is not a directory", datadir.display()),
+        );
+    }
+    let probe = datadir.join(".procshot_doctor_probe");
+    let result = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe);
+    match result {
+        Ok(_) => {
+            let _ = fs::remove_file(&probe);
+            Check::new(
+                "datadir",
+                CheckStatus::Ok,
+                format!("{} is writable", datadir.display()),
+            )
+        }
+        Err(e) => Check::new(
+            "datadir",
+            CheckStatus::Fail,
+            format!("cannot write to {}: {}", datadir.display(), e),
+        ),
+    }
+}
+
+/// Snapshots are named after the epoch, so a clock that is far off or disagrees with the kernel's
+/// idea of boot time + uptime makes the timeline unusable.
+fn check_clock() -> Check {
+    let now = match std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH) {
+        Ok(d) => d.as_secs(),
+        Err(_) => {
+            return Check::new(
+                "clock",
+                CheckStatus::Fail,
+                "system clock is before the unix epoch".to_string(),
+            )
+        }
+    };
+    // 2019-01-01, older than any procshot release.
+    if now < 1_546_300_800 {
+        return Check::new(
+            "clock",
+            CheckStatus::Fail,
+            format!("system clock reads {}, which is in the past", now),
+        );
+    }
+    match (read_btime(), read_uptime()) {
+        (Some(btime), Some(uptime)) => {
+            let drift = clock_drift(now, btime, uptime);
+            if drift > 5 {
+                Check::new(
+                    "clock",
+                    CheckStatus::Warn,
+                    format!(
+                        "wall clock differs from boot time + uptime by {}s, the clock was probably stepped",
+                        drift
+                    ),
+                )
+            } else {
+                Check::new(
+                    "clock",
+                    CheckStatus::Ok,
+                    format!("wall clock consistent with uptime (drift {}s)", drift),
+                )
+            }
+        }
+        _ => Check::new(
+            "clock",
+            CheckStatus::Warn,
+            "cannot read btime or uptime to verify the clock".to_string(),
+        ),
+    }
+}
+
+fn clock_drift(now: u64, btime: u64, uptime: u64) -> u64 {
+    now.abs_diff(btime + uptime)
+}
+
+fn read_btime() -> Option<u64> {
+    let f = File::open("/proc/stat").ok()?;
+    BufReader::new(f)
+        .lines()
+        .map_while(Result::ok)
+        .find_map(|l| l.strip_prefix("btime ").and_then(|v| v.trim().parse().ok()))
+}
+
+fn read_uptime() -> Option<u64> {
+    let s = fs::read_to_string("/proc/uptime").ok()?;
+    let secs: f64 = s.split_whitespace().next()?.parse().ok()?;
+    Some(secs as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hidepid_option() {
+        assert_eq!(hidepid_option("rw,nosuid,nodev,noexec,relatime"), None);
+        assert_eq!(hidepid_option("rw,nosuid,hidepid=2"), Some("2"));
+        assert_eq!(hidepid_option("rw,hidepid=invisible,gid=4"), Some("invisible"));
+    }
+
+    #[test]
+    fn test_clock_drift() {
+        assert_eq!(clock_drift(1000, 900, 100), 0);
+        assert_eq!(clock_drift(1010, 900, 100), 10);
+        assert_eq!(clock_drift(990, 900, 100), 10);
+    }
+
+    #[test]
+    fn test_datadir_not_a_directory() {
+        let c = check_datadir(Path::new("/proc/self/status"));
+        assert_eq!(c.status, CheckStatus::Fail);
+    }
+}
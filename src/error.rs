@@ -0,0 +1,134 @@
+//! The error type of the library.
+//!
+//! The server loop logs what fails and carries on with the next iteration, but callers taking
+//! snapshots themselves, eg: through `snapshot`, need to know what went wrong. `ProcshotError`
+//! tells the failures apart. It converts into an `io::Error`, so functions returning
+//! `io::Result` can still use `?` on it.
+
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTimeError;
+
+/// ProcshotError is what the fallible functions of the library fail with.
+#[derive(Debug)]
+pub enum ProcshotError {
+    /// Reading or writing a file failed.
+    Io(io::Error),
+    /// A snapshot couldn't be encoded or decoded.
+    Serde(bincode::Error),
+    /// A file of the proc filesystem has a content that doesn't parse.
+    Procfs { path: PathBuf, reason: String },
+    /// The file can't be read by this user.
+    Permission(PathBuf),
+    /// The system clock is set before the Unix epoch.
+    Clock(SystemTimeError),
+    /// The snapshot file was written by a newer version of procshot, in format version `found`,
+    /// while this build reads up to `supported`, see `store::FORMAT_VERSION`.
+    VersionMismatch {
+        path: PathBuf,
+        found: u8,
+        supported: u8,
+    },
+    /// The snapshot file doesn't decode.
+    Corrupt { path: PathBuf, reason: String },
+}
+
+impl ProcshotError {
+    /// Wraps an error from reading `path`, telling a denied permission apart.
+    pub fn reading(path: &Path, e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::PermissionDenied => ProcshotError::Permission(path.to_path_buf()),
+            _ => ProcshotError::Io(io::Error::new(
+                e.kind(),
+                format!("cannot read {}: {}", path.display(), e),
+            )),
+        }
+    }
+
+    /// Returns a `ProcshotError::Procfs` for the content of `path`.
+    pub fn procfs(path: &Path, reason: impl Into<String>) -> Self {
+        ProcshotError::Procfs {
+            path: path.to_path_buf(),
+            reason: reason.into(),
+        }
+    }
+}
+
+impl fmt::Display for ProcshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProcshotError::Io(e) => write!(f, "{}", e),
+            ProcshotError::Serde(e) => write!(f, "cannot encode or decode snapshot: {}", e),
+            ProcshotError::Procfs { path, reason } => {
+                write!(f, "cannot parse {}: {}", path.display(), reason)
+            }
+            ProcshotError::Permission(path) => write!(
+                f,
+                "permission denied reading {}, run as root",
+                path.display()
+            ),
+            ProcshotError::Clock(e) => write!(f, "system clock is before the Unix epoch: {}", e),
+            ProcshotError::VersionMismatch {
+                path,
+                found,
+                supported,
+            } => write!(
+                f,
+                "{} was written in format version {}, newer than the version {} this build reads, upgrade procshot",
+                path.display(),
+                found,
+                supported
+            ),
+            ProcshotError::Corrupt { path, reason } => {
+                write!(f, "cannot decode {}: {}", path.display(), reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProcshotError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProcshotError::Io(e) => Some(e),
+            ProcshotError::Serde(e) => Some(e),
+            ProcshotError::Clock(e) => Some(e),
+            ProcshotError::Procfs { .. }
+            | ProcshotError::Permission(_)
+            | ProcshotError::VersionMismatch { .. }
+            | ProcshotError::Corrupt { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for ProcshotError {
+    fn from(e: io::Error) -> Self {
+        ProcshotError::Io(e)
+    }
+}
+
+impl From<bincode::Error> for ProcshotError {
+    fn from(e: bincode::Error) -> Self {
+        ProcshotError::Serde(e)
+    }
+}
+
+impl From<SystemTimeError> for ProcshotError {
+    fn from(e: SystemTimeError) -> Self {
+        ProcshotError::Clock(e)
+    }
+}
+
+impl From<ProcshotError> for io::Error {
+    fn from(e: ProcshotError) -> Self {
+        match e {
+            ProcshotError::Io(e) => e,
+            ProcshotError::Permission(_) => io::Error::new(io::ErrorKind::PermissionDenied, e),
+            ProcshotError::Procfs { .. }
+            | ProcshotError::Serde(_)
+            | ProcshotError::VersionMismatch { .. }
+            | ProcshotError::Corrupt { .. } => io::Error::new(io::ErrorKind::InvalidData, e),
+            ProcshotError::Clock(_) => io::Error::other(e),
+        }
+    }
+}
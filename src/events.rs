@@ -0,0 +1,152 @@
+//! Log of the process events seen between snapshots.
+//!
+//! Snapshots only catch the processes alive at the time they are taken, so a cron job or a build
+//! step living a few seconds is likely never recorded. Collectors that see process events as they
+//! happen (see the `proc_events` and `oom` modules), and the server itself for the changes it
+//! notices between two snapshots (see the `priority` module), append them to `<datadir>/events.jsonl`, one JSON
+//! encoded `Event` per line, with a millisecond timestamp.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use crate::priority::Priority;
+use crate::{Pid, PidStatus};
+
+/// Name of the events log in the datadir.
+pub const EVENTS_FILE: &str = "events.jsonl";
+
+/// Event is something that happened to a process.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Event {
+    /// When the event happened, in milliseconds since the Unix epoch.
+    pub time_epoch_ms: u64,
+    pub pid: Pid,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+/// EventKind is the type of an `Event`, with its details.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum EventKind {
+    /// The process called execve. The name and command line are read from /proc right after the
+    /// event, and are missing if the process was already gone.
+    Exec {
+        name: Option<String>,
+        cmd_long: Vec<String>,
+    },
+    /// The process exited, with its exit code or the signal that killed it.
+    Exit {
+        code: Option<i32>,
+        signal: Option<i32>,
+    },
+    /// The nice value, scheduling policy or I/O priority of the process changed since the
+    /// previous snapshot.
+    Priority {
+        name: String,
+        before: Priority,
+        after: Priority,
+    },
+    /// The OOM killer killed the process, because the host or the memory cgroup `memcg` ran out
+    /// of memory. The sizes are the ones logged by the kernel at the time of the kill, in kB.
+    /// `last_status` is the process in the snapshot taken at `last_snapshot`, the last one
+    /// before the kill, if it was recorded there.
+    OomKill {
+        name: String,
+        memcg: Option<String>,
+        total_vm_kb: Option<u64>,
+        anon_rss_kb: Option<u64>,
+        file_rss_kb: Option<u64>,
+        shmem_rss_kb: Option<u64>,
+        last_snapshot: Option<u64>,
+        last_status: Option<Box<PidStatus>>,
+    },
+}
+
+/// EventLog appends events to the events log of a datadir.
+#[derive(Debug)]
+pub struct EventLog {
+    file: File,
+}
+
+impl EventLog {
+    /// Opens the events log of `datadir` for appending, creating it if needed.
+    pub fn open(datadir: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path(datadir))?;
+        Ok(EventLog { file })
+    }
+
+    /// Appends `event` to the log. Each event is a single write, so lines of concurrent writers
+    /// don't interleave.
+    pub fn append(&mut self, event: &Event) -> io::Result<()> {
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+        self.file.write_all(&line)
+    }
+}
+
+/// Returns the path of the events log of `datadir`.
+pub fn path(datadir: &Path) -> PathBuf {
+    datadir.join(EVENTS_FILE)
+}
+
+/// Returns the events of `datadir` that happened within `[from_ms, to_ms]`, in log order. Lines
+/// that can't be decoded, such as a line cut short by a crash, are skipped.
+pub fn read(datadir: &Path, from_ms: u64, to_ms: u64) -> io::Result<Vec<Event>> {
+    let file = match File::open(path(datadir)) {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut events = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let event: Event = match serde_json::from_str(&line?) {
+            Ok(e) => e,
+            Err(_) => continue,
+        };
+        if event.time_epoch_ms >= from_ms && event.time_epoch_ms <= to_ms {
+            events.push(event);
+        }
+    }
+    Ok(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_log() {
+        let dir = std::env::temp_dir().join(format!("procshot_events_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let exec = Event {
+            time_epoch_ms: 1563617611250,
+            pid: Pid::new(42),
+            kind: EventKind::Exec {
+                name: Some("cc".to_string()),
+                cmd_long: vec!["cc".to_string(), "-c".to_string()],
+            },
+        };
+        let exit = Event {
+            time_epoch_ms: 1563617611900,
+            pid: Pid::new(42),
+            kind: EventKind::Exit {
+                code: Some(0),
+                signal: None,
+            },
+        };
+        let mut log = EventLog::open(&dir).unwrap();
+        log.append(&exec).unwrap();
+        log.append(&exit).unwrap();
+        let line = serde_json::to_string(&exit).unwrap();
+        assert!(line.contains("\"event\":\"exit\""));
+        assert_eq!(read(&dir, 0, u64::MAX).unwrap(), vec![exec, exit.clone()]);
+        assert_eq!(read(&dir, 1563617611500, u64::MAX).unwrap(), vec![exit]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
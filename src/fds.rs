@@ -0,0 +1,105 @@
+//! Open file descriptors from /proc/<pid>/fd.
+//!
+//! `PidStatus::fdsize` tells how many fd slots a process allocated, not what it holds: a process
+//! leaking sockets looks the same as one leaking files, and once it was restarted nobody can tell
+//! which files it kept open. The server can list the fds of every process in `PidStatus::fds`,
+//! with the target of each link, eg: the path of a file or the inode of a socket, so leaks can be
+//! diagnosed from the history. Processes with many fds make the snapshots larger, so the listing
+//! is opt-in. Like /proc/<pid>/io, the links are only readable by the owner of a process or root.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::Pid;
+
+/// FdKind is what an fd refers to, from the target of its link.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FdKind {
+    /// A file, directory or device, by path.
+    File,
+    Socket,
+    Pipe,
+    /// A kernel object without a file, eg: an eventfd, epoll or inotify instance.
+    AnonInode,
+    /// Anything else, eg: a namespace.
+    Other,
+}
+
+impl FdKind {
+    /// Returns the kind of the fd whose link points to `target`, eg: `socket:[123456]`.
+    pub fn of(target: &str) -> Self {
+        if target.starts_with('/') {
+            FdKind::File
+        } else if target.starts_with("socket:") {
+            FdKind::Socket
+        } else if target.starts_with("pipe:") {
+            FdKind::Pipe
+        } else if target.starts_with("anon_inode:") {
+            FdKind::AnonInode
+        } else {
+            FdKind::Other
+        }
+    }
+}
+
+/// FdInfo is one open fd of a process.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FdInfo {
+    pub fd: u32,
+    pub kind: FdKind,
+    /// Target of the link, eg: `/var/log/nginx/access.log`, `socket:[123456]`, `pipe:[7890]` or
+    /// `anon_inode:[eventfd]`. The path of a deleted file ends with ` (deleted)`.
+    pub target: String,
+}
+
+/// Reads the fds of `pid` under `proc_root`, sorted by fd. Fails if the process exited, and with
+/// `PermissionDenied` for the processes of other users when not running as root.
+pub fn read_in(proc_root: &Path, pid: Pid) -> io::Result<Vec<FdInfo>> {
+    let mut fds = Vec::new();
+    for entry in fs::read_dir(proc_root.join(pid.to_string()).join("fd"))? {
+        let entry = entry?;
+        let fd = match entry.file_name().to_str().and_then(|n| n.parse().ok()) {
+            Some(fd) => fd,
+            None => continue,
+        };
+        // The fd may be closed since the directory was listed.
+        let target = match fs::read_link(entry.path()) {
+            Ok(t) => t.to_string_lossy().into_owned(),
+            Err(_) => continue,
+        };
+        fds.push(FdInfo {
+            fd,
+            kind: FdKind::of(&target),
+            target,
+        });
+    }
+    fds.sort_by_key(|f| f.fd);
+    Ok(fds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::os::unix::io::AsRawFd;
+
+    #[test]
+    fn test_read_in() {
+        assert_eq!(FdKind::of("/var/log/syslog (deleted)"), FdKind::File);
+        assert_eq!(FdKind::of("anon_inode:[eventfd]"), FdKind::AnonInode);
+        assert_eq!(FdKind::of("net:[4026531840]"), FdKind::Other);
+
+        let path = std::env::temp_dir().join(format!("procshot_fds_{}", std::process::id()));
+        let file = fs::File::create(&path).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let fds = read_in(Path::new("/proc"), Pid::current()).unwrap();
+        let fd = |fd: i32| fds.iter().find(|f| f.fd == fd as u32).unwrap();
+        assert_eq!(fd(file.as_raw_fd()).kind, FdKind::File);
+        assert_eq!(fd(file.as_raw_fd()).target, path.to_str().unwrap());
+        assert_eq!(fd(listener.as_raw_fd()).kind, FdKind::Socket);
+        assert!(fds.windows(2).all(|w| w[0].fd < w[1].fd));
+        fs::remove_file(&path).unwrap();
+    }
+}
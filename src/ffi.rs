@@ -0,0 +1,173 @@
+//! C interface, for agents and supervisors not written in Rust.
+//!
+//! `procshot_snapshot_once` reads every process once and writes the snapshot as JSON, in the
+//! layout of `EncoDecode`, to a buffer of the caller. `procshot_start` runs the server loop on a
+//! thread of the calling process, writing to a datadir the way `procshot server` does, until
+//! `procshot_stop`. Built with the ffi feature, which also generates `include/procshot.h` with
+//! cbindgen. Link against the library built with
+//! `cargo rustc --release --features ffi --crate-type cdylib` (or `staticlib`).
+//!
+//! Unlike `procshot server`, the loop doesn't exit the process when it can't start, eg: the
+//! datadir is locked by another server, or when another server takes the datadir lock over: it
+//! stops, and `procshot_stop` tells it did with -3. `procshot_running` tells if it still runs.
+
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_int};
+use std::path::PathBuf;
+use std::ptr;
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::{header, paths, systemd, ScanOptions};
+
+/// The thread running the server loop, between `procshot_start` and `procshot_stop`.
+static RUNNING: Mutex<Option<JoinHandle<std::io::Result<()>>>> = Mutex::new(None);
+
+/// Writes a snapshot of every process as NUL terminated JSON to `buf`, of `len` bytes.
+///
+/// Returns the length of the JSON, without the NUL, like snprintf. Nothing is written if that is
+/// `len` or more, call again with a larger buffer. Returns -1 if /proc can't be read.
+///
+/// # Safety
+///
+/// `buf` must be valid for writes of `len` bytes, or null with a `len` of 0.
+#[no_mangle]
+pub unsafe extern "C" fn procshot_snapshot_once(buf: *mut c_char, len: usize) -> isize {
+    let hostname = hostname::get_hostname().unwrap_or_default();
+    let json = match crate::snapshot(&hostname, None).map(|s| serde_json::to_vec(&s)) {
+        Ok(Ok(j)) => j,
+        _ => return -1,
+    };
+    if json.len() < len && !buf.is_null() {
+        ptr::copy_nonoverlapping(json.as_ptr(), buf as *mut u8, json.len());
+        *buf.add(json.len()) = 0;
+    }
+    json.len() as isize
+}
+
+/// Starts the server loop on a new thread, recording every `delay_ms` milliseconds to `datadir`.
+///
+/// Returns 0 on success, -1 if `datadir` is not a valid datadir and can't be created as one (see
+/// `paths::create_datadir`), -2 if the loop is already running, and -3 if `delay_ms` is shorter
+/// than one clock tick of the host (see `header::clock_tick`).
+///
+/// # Safety
+///
+/// `datadir` must be a NUL terminated string. It is copied.
+#[no_mangle]
+pub unsafe extern "C" fn procshot_start(datadir: *const c_char, delay_ms: u64) -> c_int {
+    if datadir.is_null() {
+        return -1;
+    }
+    if Duration::from_millis(delay_ms) < header::clock_tick() {
+        return -3;
+    }
+    let datadir = match CStr::from_ptr(datadir).to_str() {
+        Ok(d) => PathBuf::from(d),
+        Err(_) => return -1,
+    };
+    if paths::create_datadir(&datadir).is_err() {
+        return -1;
+    }
+    let mut running = match RUNNING.lock() {
+        Ok(r) => r,
+        Err(_) => return -1,
+    };
+    if running.is_some() {
+        return -2;
+    }
+    systemd::clear_stop();
+    let spawned = thread::Builder::new()
+        .name("procshot".to_string())
+        .spawn(move || {
+            crate::try_scan_proc_with_options(
+                Duration::from_millis(delay_ms),
+                hostname::get_hostname().unwrap_or_default(),
+                datadir,
+                ScanOptions::default(),
+            )
+        });
+    match spawned {
+        Ok(handle) => {
+            *running = Some(handle);
+            0
+        }
+        Err(_) => -1,
+    }
+}
+
+/// Stops the server loop started by `procshot_start`, waiting for the running iteration to
+/// finish. Returns 0 once stopped, -1 if it wasn't started, and -3 if it had stopped on its own
+/// on an error, logged to stderr, eg: the datadir is locked by another server.
+#[no_mangle]
+pub extern "C" fn procshot_stop() -> c_int {
+    let handle = match RUNNING.lock().ok().and_then(|mut r| r.take()) {
+        Some(h) => h,
+        None => return -1,
+    };
+    systemd::request_stop();
+    match handle.join() {
+        Ok(Ok(())) => 0,
+        _ => -3,
+    }
+}
+
+/// Returns 1 while the server loop started by `procshot_start` runs, 0 if it wasn't started or
+/// stopped on an error, which `procshot_stop` then returns.
+#[no_mangle]
+pub extern "C" fn procshot_running() -> c_int {
+    match RUNNING.lock() {
+        Ok(r) if r.as_ref().is_some_and(|h| !h.is_finished()) => 1,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+    use std::fs;
+
+    #[test]
+    fn test_ffi() {
+        let needed = unsafe { procshot_snapshot_once(ptr::null_mut(), 0) };
+        assert!(needed > 0);
+        // Processes come and go between the two calls.
+        let mut buf = vec![0u8; needed as usize * 2];
+        let written = unsafe { procshot_snapshot_once(buf.as_mut_ptr() as *mut c_char, buf.len()) };
+        let json = CStr::from_bytes_until_nul(&buf).unwrap().to_str().unwrap();
+        assert_eq!(json.len(), written as usize);
+        let s: crate::EncoDecode = serde_json::from_str(json).unwrap();
+        assert!(s.pid_map_list.contains_key(&crate::Pid::current()));
+
+        let dir = std::env::temp_dir().join(format!("procshot_ffi_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let datadir = CString::new(dir.to_str().unwrap()).unwrap();
+        assert_eq!(procshot_stop(), -1);
+        assert_eq!(unsafe { procshot_start(datadir.as_ptr(), 0) }, -3);
+        assert_eq!(unsafe { procshot_start(datadir.as_ptr(), 100) }, 0);
+        assert_eq!(unsafe { procshot_start(datadir.as_ptr(), 100) }, -2);
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(procshot_running(), 1);
+        assert_eq!(procshot_stop(), 0);
+        assert!(!crate::store::snapshot_files(&dir).unwrap().is_empty());
+
+        // A datadir locked by another server stops the loop, not the process.
+        let other = crate::lock::LockOwner {
+            hostname: "otherhost".to_string(),
+            pid: crate::Pid::new(1),
+            heartbeat: u64::MAX,
+        };
+        fs::write(
+            dir.join(crate::lock::LOCK_FILE),
+            serde_json::to_vec(&other).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(unsafe { procshot_start(datadir.as_ptr(), 100) }, 0);
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(procshot_running(), 0);
+        assert_eq!(procshot_stop(), -3);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
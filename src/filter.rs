@@ -0,0 +1,208 @@
+//! Selection of the processes recorded by the server.
+//!
+//! On a host running many services, only a handful may be worth recording. A `ScanFilter` is
+//! checked against every pid before it is read, with the selectors given to `--include` and
+//! `--exclude`:
+//!
+//! - `name=<regex>`: the name of the process, as in /proc/<pid>/comm, in the syntax of the regex
+//!   crate. The regex isn't anchored, `name=^nginx$` matches nginx only;
+//! - `uid=<uid>`: the owner of the process;
+//! - `pid=<pid>`;
+//! - `cgroup=<path>`: the cgroup v2 of the process is the cgroup or one of its descendants, in
+//!   the forms accepted by `cgroup::resolve`.
+//!
+//! A process is recorded if it matches any of the include selectors, or if there is none, and
+//! none of the exclude selectors. Only the files the selectors need are read, one small file per
+//! selector kind at most, so the stat, status, cmdline and io of excluded processes aren't read
+//! at all. A process whose file can't be read, eg: because it exited, matches no selector of that
+//! kind.
+
+use std::fmt;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use regex::Regex;
+
+use crate::{cgroup, Pid};
+
+/// Selector matches processes on one attribute, see the module documentation.
+#[derive(Debug, Clone)]
+pub enum Selector {
+    Name(Regex),
+    Uid(u32),
+    Pid(Pid),
+    /// A directory of the cgroup filesystem, as returned by `cgroup::resolve`.
+    Cgroup(PathBuf),
+}
+
+impl FromStr for Selector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |e: &dyn fmt::Display| format!("invalid selector {}: {}", s, e);
+        match s.split_once('=') {
+            Some(("name", regex)) => Regex::new(regex)
+                .map(Selector::Name)
+                .map_err(|e| invalid(&e)),
+            Some(("uid", uid)) => uid.parse().map(Selector::Uid).map_err(|e| invalid(&e)),
+            Some(("pid", pid)) => pid.parse().map(Selector::Pid).map_err(|e| invalid(&e)),
+            Some(("cgroup", path)) if !path.is_empty() => {
+                Ok(Selector::Cgroup(cgroup::resolve(Path::new(path))))
+            }
+            _ => Err(format!(
+                "invalid selector {}, expected name=<regex>, uid=<uid>, pid=<pid> or cgroup=<path>",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Selector::Name(regex) => write!(f, "name={}", regex),
+            Selector::Uid(uid) => write!(f, "uid={}", uid),
+            Selector::Pid(pid) => write!(f, "pid={}", pid),
+            Selector::Cgroup(path) => write!(f, "cgroup={}", path.display()),
+        }
+    }
+}
+
+/// ScanFilter is the selection of the processes the server records. The default filter has no
+/// selector and records every process.
+#[derive(Debug, Clone, Default)]
+pub struct ScanFilter {
+    include: Vec<Selector>,
+    exclude: Vec<Selector>,
+}
+
+impl ScanFilter {
+    pub fn new(include: Vec<Selector>, exclude: Vec<Selector>) -> Self {
+        ScanFilter { include, exclude }
+    }
+
+    /// Returns true if the filter has no selector.
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    /// Returns true if the process `pid` of the proc filesystem at `proc_root` is to be recorded.
+    pub fn accepts(&self, proc_root: &Path, pid: Pid) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+        let mut process = Candidate::new(proc_root, pid);
+        (self.include.is_empty() || self.include.iter().any(|s| process.matches(s)))
+            && !self.exclude.iter().any(|s| process.matches(s))
+    }
+}
+
+/// Candidate is a process being checked, with the attributes read so far, so that each file is
+/// read once whatever the number of selectors.
+struct Candidate<'a> {
+    proc_root: &'a Path,
+    pid: Pid,
+    name: Option<Option<String>>,
+    uid: Option<Option<u32>>,
+    cgroup: Option<Option<PathBuf>>,
+}
+
+impl<'a> Candidate<'a> {
+    fn new(proc_root: &'a Path, pid: Pid) -> Self {
+        Candidate {
+            proc_root,
+            pid,
+            name: None,
+            uid: None,
+            cgroup: None,
+        }
+    }
+
+    fn dir(&self) -> PathBuf {
+        self.proc_root.join(self.pid.to_string())
+    }
+
+    fn matches(&mut self, selector: &Selector) -> bool {
+        match selector {
+            Selector::Pid(pid) => *pid == self.pid,
+            Selector::Name(regex) => {
+                let dir = self.dir();
+                let name = self.name.get_or_insert_with(|| {
+                    fs::read_to_string(dir.join("comm"))
+                        .ok()
+                        .map(|c| c.trim_end().to_string())
+                });
+                name.as_deref().is_some_and(|n| regex.is_match(n))
+            }
+            Selector::Uid(uid) => {
+                let dir = self.dir();
+                let owner = self
+                    .uid
+                    .get_or_insert_with(|| fs::metadata(dir).ok().map(|m| m.uid()));
+                *owner == Some(*uid)
+            }
+            Selector::Cgroup(path) => {
+                let (proc_root, pid) = (self.proc_root, self.pid);
+                let dir = self
+                    .cgroup
+                    .get_or_insert_with(|| cgroup::of_pid_in(proc_root, pid).ok());
+                dir.as_ref().is_some_and(|d| d.starts_with(path))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scan_filter() {
+        let root = std::env::temp_dir().join(format!("procshot_filter_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        for (pid, name, cgroup) in [
+            (10, "nginx", "/system.slice/nginx.service"),
+            (11, "nginx", "/system.slice/nginx.service/worker"),
+            (20, "postgres", "/system.slice/postgresql.service"),
+            (30, "bash", "/user.slice/user-1000.slice"),
+        ] {
+            let dir = root.join(pid.to_string());
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("comm"), format!("{}\n", name)).unwrap();
+            fs::write(dir.join("cgroup"), format!("0::{}\n", cgroup)).unwrap();
+        }
+        let selectors = |s: &[&str]| s.iter().map(|s| s.parse().unwrap()).collect();
+        let accepted = |filter: &ScanFilter| {
+            [10, 11, 20, 30, 40]
+                .iter()
+                .filter(|pid| filter.accepts(&root, Pid::new(**pid)))
+                .copied()
+                .collect::<Vec<i32>>()
+        };
+
+        assert_eq!(accepted(&ScanFilter::default()), vec![10, 11, 20, 30, 40]);
+        let services = ScanFilter::new(selectors(&["name=^(nginx|postgres)$", "pid=30"]), vec![]);
+        assert_eq!(accepted(&services), vec![10, 11, 20, 30]);
+        let no_workers = ScanFilter::new(
+            selectors(&["cgroup=/system.slice/nginx.service"]),
+            selectors(&["cgroup=/system.slice/nginx.service/worker"]),
+        );
+        assert_eq!(accepted(&no_workers), vec![10]);
+        let uid = fs::metadata(root.join("10")).unwrap().uid();
+        let mine = ScanFilter::new(
+            selectors(&[&format!("uid={}", uid)]),
+            selectors(&["name=gres"]),
+        );
+        assert_eq!(accepted(&mine), vec![10, 11, 30]);
+
+        assert!("name=(".parse::<Selector>().is_err());
+        assert!("uid=root".parse::<Selector>().is_err());
+        assert!("user=1000".parse::<Selector>().is_err());
+        assert!("cgroup=".parse::<Selector>().is_err());
+        let selector: Selector = "cgroup=/system.slice".parse().unwrap();
+        assert_eq!(selector.to_string(), "cgroup=/sys/fs/cgroup/system.slice");
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,380 @@
+//! Percentiles of a process metric across the hosts of a fleet.
+//!
+//! `rollup` reads the datadirs of many hosts, eg: synced from the object storage `upload` writes
+//! to, and computes for every time bucket the percentiles across hosts of one metric of the
+//! processes with a given name, eg: the p95 rss of `envoy` per hour over 500 hosts. The value of
+//! a host in a bucket is the metric summed over its processes of that name, averaged over its
+//! snapshots of the bucket the name is in, so the workers of a service count as a whole. Hosts
+//! are told apart by the hostname recorded in the snapshots, hosts without such a process in a
+//! bucket are left out of it. The result is exported as CSV, JSON or Markdown with `write`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::metrics::{self, Metric};
+use crate::query;
+use crate::report::{markdown_table, Align, TimeRange};
+
+/// Percentiles computed when none are given.
+pub const DEFAULT_PERCENTILES: &[f64] = &[50.0, 90.0, 95.0, 99.0];
+
+/// FleetMetric is the per-process value rolled up.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FleetMetric {
+    /// Resident memory, in bytes.
+    Rss,
+    /// user + sys CPU usage, in percent of the CPU time of the host.
+    Cpu,
+    /// File descriptor slots, see `PidStatus::fdsize`.
+    Fds,
+}
+
+impl FleetMetric {
+    /// Every metric that can be rolled up.
+    pub const ALL: [FleetMetric; 3] = [FleetMetric::Rss, FleetMetric::Cpu, FleetMetric::Fds];
+
+    /// Returns the definition of the metric, see the `metrics` module.
+    pub fn metric(self) -> &'static Metric {
+        match self {
+            FleetMetric::Rss => &metrics::RSS_BYTES,
+            FleetMetric::Cpu => &metrics::CPU_USAGE,
+            FleetMetric::Fds => &metrics::FDSIZE,
+        }
+    }
+}
+
+impl FromStr for FleetMetric {
+    type Err = String;
+
+    /// Parses the name of a metric, or its alias, eg: `rss_bytes` or `rss`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let metric = metrics::find(s);
+        FleetMetric::ALL
+            .iter()
+            .copied()
+            .find(|m| Some(m.metric()) == metric)
+            .ok_or_else(|| format!("unknown metric {}, expected rss, cpu or fds", s))
+    }
+}
+
+impl fmt::Display for FleetMetric {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.metric().name)
+    }
+}
+
+/// ExportFormat is the encoding of the output of `write`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    /// A header line, then one line per bucket.
+    Csv,
+    /// An array with one object per bucket.
+    Json,
+    /// A Markdown table with one row per bucket, in the columns of the CSV.
+    Markdown,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(ExportFormat::Csv),
+            "json" => Ok(ExportFormat::Json),
+            "md" | "markdown" => Ok(ExportFormat::Markdown),
+            _ => Err(format!("unknown format {}, expected csv, json or md", s)),
+        }
+    }
+}
+
+/// FleetQuery describes one roll-up, as given to `report fleet`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FleetQuery {
+    /// Datadirs to read, one or more per host.
+    pub datadirs: Vec<PathBuf>,
+    /// Name of the processes, compared to `PidStatus::name`.
+    pub name: String,
+    pub metric: FleetMetric,
+    pub range: TimeRange,
+    /// Length of the time buckets. Buckets are aligned on multiples of it since the epoch.
+    pub bucket: Duration,
+    /// Percentiles to compute, between 0 and 100.
+    pub percentiles: Vec<f64>,
+    pub format: ExportFormat,
+}
+
+/// Percentile is one percentile of a bucket.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Percentile {
+    pub percentile: f64,
+    pub value: f64,
+}
+
+/// FleetBucket holds the percentiles across hosts of one time bucket.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FleetBucket {
+    /// Epoch of the start of the bucket.
+    pub start: u64,
+    /// Number of hosts running the process in the bucket.
+    pub hosts: usize,
+    pub percentiles: Vec<Percentile>,
+}
+
+/// FleetReport is the result of `rollup`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FleetReport {
+    /// Number of snapshots read, over all the datadirs.
+    pub snapshots: usize,
+    /// Number of snapshot files that could not be decoded and were skipped.
+    pub skipped: usize,
+    /// Buckets with at least one host, oldest first.
+    pub buckets: Vec<FleetBucket>,
+}
+
+/// Computes the percentiles of `query`, decoding the snapshots of each datadir on `workers`
+/// threads.
+pub fn rollup(query: &FleetQuery, workers: usize) -> io::Result<FleetReport> {
+    let bucket = query.bucket.as_secs().max(1);
+    let mut report = FleetReport {
+        snapshots: 0,
+        skipped: 0,
+        buckets: Vec::new(),
+    };
+    // Sum of the values of the snapshots of each host in each bucket, and their number.
+    let mut by_host: BTreeMap<u64, HashMap<String, (f64, usize)>> = BTreeMap::new();
+    for datadir in &query.datadirs {
+        let samples = host_samples(datadir, query, workers)?;
+        for (epoch, sample) in samples {
+            let (hostname, value) = match sample {
+                Ok(s) => s,
+                Err(_) => {
+                    report.skipped += 1;
+                    continue;
+                }
+            };
+            report.snapshots += 1;
+            if let Some(value) = value {
+                let entry = by_host
+                    .entry(epoch - epoch % bucket)
+                    .or_default()
+                    .entry(hostname)
+                    .or_default();
+                entry.0 += value;
+                entry.1 += 1;
+            }
+        }
+    }
+    report.buckets = by_host
+        .into_iter()
+        .map(|(start, hosts)| {
+            let mut values: Vec<f64> = hosts
+                .values()
+                .map(|(sum, count)| sum / *count as f64)
+                .collect();
+            values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+            FleetBucket {
+                start,
+                hosts: values.len(),
+                percentiles: query
+                    .percentiles
+                    .iter()
+                    .map(|p| Percentile {
+                        percentile: *p,
+                        value: nearest_rank(&values, *p),
+                    })
+                    .collect(),
+            }
+        })
+        .collect();
+    Ok(report)
+}
+
+/// Hostname of a snapshot, and the value of its processes, None if there are none.
+type HostSample = (String, Option<f64>);
+
+/// Returns the hostname and the value of the processes named `query.name` of every snapshot of
+/// `datadir` in the range.
+fn host_samples(
+    datadir: &Path,
+    query: &FleetQuery,
+    workers: usize,
+) -> io::Result<Vec<(u64, io::Result<HostSample>)>> {
+    let files = query::files_in_range(datadir, query.range.from, query.range.to)?;
+    Ok(query::par_map_slim(&files, workers, |s| {
+        let value = s
+            .processes
+            .iter()
+            .filter(|p| p.name == query.name)
+            .map(|p| match query.metric {
+                FleetMetric::Rss => p.rss_bytes as f64,
+                FleetMetric::Cpu => p.user_cpu_usage + p.sys_cpu_usage,
+                FleetMetric::Fds => f64::from(p.fdsize),
+            })
+            .fold(None, |sum: Option<f64>, v| Some(sum.unwrap_or(0.0) + v));
+        (s.hostname.clone(), value)
+    }))
+}
+
+/// Parses a comma separated list of percentiles, eg: `50,95,99.9`.
+pub fn parse_percentiles(s: &str) -> Result<Vec<f64>, String> {
+    s.split(',')
+        .map(|p| match p.trim().parse::<f64>() {
+            Ok(p) if (0.0..=100.0).contains(&p) => Ok(p),
+            _ => Err(format!("Invalid percentile {}, expected 0 to 100", p)),
+        })
+        .collect()
+}
+
+/// Returns the `p`th percentile of the sorted `values` with the nearest rank method, so it is
+/// always the value of one of the hosts. 0 for no values.
+fn nearest_rank(values: &[f64], p: f64) -> f64 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    let rank = (p / 100.0 * values.len() as f64).ceil() as usize;
+    values[rank.clamp(1, values.len()) - 1]
+}
+
+/// Writes the buckets of `report` to `w` in `format`. CSV columns are the epoch of the bucket,
+/// the number of hosts and one column per percentile, eg: `start,hosts,p50,p95`.
+pub fn write(report: &FleetReport, format: ExportFormat, w: &mut dyn Write) -> io::Result<()> {
+    match format {
+        ExportFormat::Json => {
+            serde_json::to_writer_pretty(&mut *w, &report.buckets)?;
+            writeln!(w)
+        }
+        ExportFormat::Csv => {
+            write!(w, "start,hosts")?;
+            if let Some(first) = report.buckets.first() {
+                for p in &first.percentiles {
+                    write!(w, ",p{}", p.percentile)?;
+                }
+            }
+            writeln!(w)?;
+            for b in &report.buckets {
+                write!(w, "{},{}", b.start, b.hosts)?;
+                for p in &b.percentiles {
+                    write!(w, ",{}", p.value)?;
+                }
+                writeln!(w)?;
+            }
+            Ok(())
+        }
+        ExportFormat::Markdown => {
+            let header: Vec<String> = report.buckets.first().map_or(Vec::new(), |b| {
+                b.percentiles
+                    .iter()
+                    .map(|p| format!("p{}", p.percentile))
+                    .collect()
+            });
+            let mut columns = vec![("start", Align::Left), ("hosts", Align::Right)];
+            columns.extend(header.iter().map(|h| (h.as_str(), Align::Right)));
+            let rows: Vec<Vec<String>> = report
+                .buckets
+                .iter()
+                .map(|b| {
+                    let mut row = vec![b.start.to_string(), b.hosts.to_string()];
+                    row.extend(b.percentiles.iter().map(|p| p.value.to_string()));
+                    row
+                })
+                .collect();
+            w.write_all(markdown_table(&columns, &rows).as_bytes())
+        }
+    }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use super::*;
+    use crate::collect::{restricted_pid_status, PROC_ROOT};
+    use crate::{EncoDecode, Pid};
+    use std::fs;
+
+    fn write_snapshot(dir: &Path, host: &str, epoch: u64, envoy_rss: &[i64]) {
+        let mut processes = Vec::new();
+        for (i, rss) in envoy_rss.iter().enumerate() {
+            let mut status = restricted_pid_status(Path::new(PROC_ROOT), Pid::current());
+            status.name = "envoy".to_string();
+            status.rss_bytes = *rss;
+            processes.push((Pid::new(100 + i as i32), status));
+        }
+        let mut other = restricted_pid_status(Path::new(PROC_ROOT), Pid::current());
+        other.name = "cron".to_string();
+        other.rss_bytes = 1 << 30;
+        processes.push((Pid::new(1), other));
+        let s = EncoDecode {
+            hostname: host.to_string(),
+            pid_map_list: processes.into_iter().collect(),
+            time_epoch: epoch,
+            delay: Duration::from_secs(60),
+            ..Default::default()
+        };
+        fs::write(
+            dir.join(format!("{}.procshot", epoch)),
+            bincode::serialize(&s).unwrap(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_rollup() {
+        let root = std::env::temp_dir().join(format!("procshot_fleet_{}", std::process::id()));
+        let mut datadirs = Vec::new();
+        for host in 0..4 {
+            let dir = root.join(format!("web-{}", host));
+            fs::create_dir_all(&dir).unwrap();
+            // Two workers of 100 * (host + 1) bytes, then 300 * (host + 1) in a single one.
+            let rss = 100 * (host + 1);
+            write_snapshot(&dir, &format!("web-{}", host), 3600, &[rss, rss]);
+            write_snapshot(&dir, &format!("web-{}", host), 3660, &[3 * rss]);
+            datadirs.push(dir);
+        }
+        // Runs envoy in the second bucket only.
+        let dir = root.join("db-0");
+        fs::create_dir_all(&dir).unwrap();
+        write_snapshot(&dir, "db-0", 3600, &[]);
+        write_snapshot(&dir, "db-0", 7200, &[10_000]);
+        datadirs.push(dir);
+
+        let query = FleetQuery {
+            datadirs,
+            name: "envoy".to_string(),
+            metric: "rss".parse().unwrap(),
+            range: TimeRange { from: 0, to: 9999 },
+            bucket: Duration::from_secs(3600),
+            percentiles: vec![50.0, 95.0],
+            format: ExportFormat::Csv,
+        };
+        let report = rollup(&query, 2).unwrap();
+        assert_eq!((report.snapshots, report.skipped), (10, 0));
+        let mut csv = Vec::new();
+        write(&report, ExportFormat::Csv, &mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "start,hosts,p50,p95\n3600,4,500,1000\n7200,1,10000,10000\n"
+        );
+        let mut json = Vec::new();
+        write(&report, ExportFormat::Json, &mut json).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(json[0]["hosts"], 4);
+        assert_eq!(json[0]["percentiles"][1]["value"], 1000.0);
+        let mut md = Vec::new();
+        write(&report, ExportFormat::Markdown, &mut md).unwrap();
+        assert_eq!(
+            String::from_utf8(md).unwrap(),
+            "| start | hosts | p50 | p95 |\n| --- | ---: | ---: | ---: |\n\
+             | 3600 | 4 | 500 | 1000 |\n| 7200 | 1 | 10000 | 10000 |\n"
+        );
+        assert_eq!(parse_percentiles("50, 99.9"), Ok(vec![50.0, 99.9]));
+        assert!(parse_percentiles("95,101").is_err());
+        assert_eq!("cpu_usage".parse(), Ok(FleetMetric::Cpu));
+        assert_eq!(FleetMetric::Fds.to_string(), "fdsize");
+        assert!("vmsize".parse::<FleetMetric>().is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
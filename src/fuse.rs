@@ -0,0 +1,358 @@
+//! Read-only FUSE view of the datadir, enabled with the `fuse` feature.
+//!
+//! The archive is exposed as
+//!
+//! ```text
+//! /by-time/<epoch>/<pid>/status.json
+//! /by-pid/<pid>/<epoch>.json
+//! ```
+//!
+//! so grep, jq and friends work directly against the history. Every json file holds the
+//! `PidStatus` of one process in one snapshot. Snapshots are decoded on demand; the pid index
+//! behind `/by-pid` is built on first use and rebuilt when the set of snapshot files changes.
+
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsStr;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use fuser::{
+    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory,
+    ReplyEntry, Request,
+};
+
+use crate::{store, EncoDecode, Pid};
+
+const TTL: Duration = Duration::from_secs(1);
+const ROOT_INO: u64 = 1;
+
+/// Maps a pid to the epochs of the snapshots it appears in.
+type PidIndex = BTreeMap<Pid, Vec<u64>>;
+
+/// A path of the virtual tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Node {
+    Root,
+    ByTime,
+    ByPid,
+    Time(u64),
+    TimePid(u64, Pid),
+    TimePidStatus(u64, Pid),
+    Pid(Pid),
+    PidTime(Pid, u64),
+}
+
+/// ArchiveFs implements the FUSE filesystem over a datadir.
+pub struct ArchiveFs {
+    datadir: PathBuf,
+    /// Inode n is nodes[n - 1].
+    nodes: Vec<Node>,
+    inodes: HashMap<Node, u64>,
+    /// The last decoded snapshot, since a `cat` does lookup, getattr and read on the same one.
+    cache: Option<(u64, EncoDecode)>,
+    /// Epochs the pid index was built from, and pid to epochs.
+    pid_index: Option<(Vec<u64>, PidIndex)>,
+    uid: u32,
+    gid: u32,
+}
+
+impl ArchiveFs {
+    pub fn new(datadir: &Path) -> Self {
+        let mut fs = ArchiveFs {
+            datadir: datadir.to_path_buf(),
+            nodes: Vec::new(),
+            inodes: HashMap::new(),
+            cache: None,
+            pid_index: None,
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+        };
+        fs.ino(Node::Root);
+        fs
+    }
+
+    fn ino(&mut self, node: Node) -> u64 {
+        if let Some(ino) = self.inodes.get(&node) {
+            return *ino;
+        }
+        self.nodes.push(node);
+        let ino = self.nodes.len() as u64;
+        self.inodes.insert(node, ino);
+        ino
+    }
+
+    fn node(&self, ino: u64) -> Option<Node> {
+        self.nodes.get(ino.checked_sub(1)? as usize).copied()
+    }
+
+    fn epochs(&self) -> Vec<u64> {
+        store::snapshot_files(&self.datadir)
+            .map(|files| files.into_iter().map(|(epoch, _)| epoch).collect())
+            .unwrap_or_default()
+    }
+
+    fn snapshot(&mut self, epoch: u64) -> Option<&EncoDecode> {
+        let cached = matches!(&self.cache, Some((e, _)) if *e == epoch);
+        if !cached {
+            let path = store::find_snapshot(&self.datadir, epoch)?;
+            let snapshot = store::read_snapshot(&path).ok()?;
+            self.cache = Some((epoch, snapshot));
+        }
+        self.cache.as_ref().map(|(_, s)| s)
+    }
+
+    fn pid_index(&mut self) -> &PidIndex {
+        let epochs = self.epochs();
+        let stale = match &self.pid_index {
+            Some((built_from, _)) => *built_from != epochs,
+            None => true,
+        };
+        if stale {
+            let mut index = PidIndex::new();
+            for epoch in &epochs {
+                if let Some(snapshot) = self.snapshot(*epoch) {
+                    for pid in snapshot.pid_map_list.keys() {
+                        index.entry(*pid).or_default().push(*epoch);
+                    }
+                }
+            }
+            self.pid_index = Some((epochs, index));
+        }
+        &self.pid_index.as_ref().unwrap().1
+    }
+
+    /// Returns the json content of a file node.
+    fn content(&mut self, node: Node) -> Option<Vec<u8>> {
+        let (epoch, pid) = match node {
+            Node::TimePidStatus(epoch, pid) | Node::PidTime(pid, epoch) => (epoch, pid),
+            _ => return None,
+        };
+        let status = self.snapshot(epoch)?.pid_map_list.get(&pid)?;
+        let mut json = serde_json::to_vec_pretty(status).ok()?;
+        json.push(b'\n');
+        Some(json)
+    }
+
+    /// Returns the child `name` of `parent`, if it exists.
+    fn child(&mut self, parent: Node, name: &str) -> Option<Node> {
+        match parent {
+            Node::Root => match name {
+                "by-time" => Some(Node::ByTime),
+                "by-pid" => Some(Node::ByPid),
+                _ => None,
+            },
+            Node::ByTime => {
+                let epoch: u64 = name.parse().ok()?;
+                self.epochs().contains(&epoch).then_some(Node::Time(epoch))
+            }
+            Node::Time(epoch) => {
+                let pid: Pid = name.parse().ok()?;
+                self.snapshot(epoch)?
+                    .pid_map_list
+                    .contains_key(&pid)
+                    .then_some(Node::TimePid(epoch, pid))
+            }
+            Node::TimePid(epoch, pid) => {
+                (name == "status.json").then_some(Node::TimePidStatus(epoch, pid))
+            }
+            Node::ByPid => {
+                let pid: Pid = name.parse().ok()?;
+                self.pid_index().contains_key(&pid).then_some(Node::Pid(pid))
+            }
+            Node::Pid(pid) => {
+                let epoch: u64 = name.strip_suffix(".json")?.parse().ok()?;
+                self.pid_index()
+                    .get(&pid)?
+                    .contains(&epoch)
+                    .then_some(Node::PidTime(pid, epoch))
+            }
+            Node::TimePidStatus(..) | Node::PidTime(..) => None,
+        }
+    }
+
+    /// Returns the entries of a directory node.
+    fn children(&mut self, node: Node) -> Vec<(Node, String)> {
+        match node {
+            Node::Root => vec![
+                (Node::ByTime, "by-time".to_string()),
+                (Node::ByPid, "by-pid".to_string()),
+            ],
+            Node::ByTime => self
+                .epochs()
+                .into_iter()
+                .map(|e| (Node::Time(e), e.to_string()))
+                .collect(),
+            Node::Time(epoch) => {
+                let mut pids: Vec<Pid> = match self.snapshot(epoch) {
+                    Some(s) => s.pid_map_list.keys().copied().collect(),
+                    None => Vec::new(),
+                };
+                pids.sort_unstable();
+                pids.into_iter()
+                    .map(|p| (Node::TimePid(epoch, p), p.to_string()))
+                    .collect()
+            }
+            Node::TimePid(epoch, pid) => {
+                vec![(Node::TimePidStatus(epoch, pid), "status.json".to_string())]
+            }
+            Node::ByPid => self
+                .pid_index()
+                .keys()
+                .map(|p| (Node::Pid(*p), p.to_string()))
+                .collect(),
+            Node::Pid(pid) => self
+                .pid_index()
+                .get(&pid)
+                .cloned()
+                .unwrap_or_default()
+                .into_iter()
+                .map(|e| (Node::PidTime(pid, e), format!("{}.json", e)))
+                .collect(),
+            Node::TimePidStatus(..) | Node::PidTime(..) => Vec::new(),
+        }
+    }
+
+    fn attr(&mut self, node: Node) -> Option<FileAttr> {
+        let ino = self.ino(node);
+        let mtime = match node {
+            Node::Time(e) | Node::TimePid(e, _) | Node::TimePidStatus(e, _) | Node::PidTime(_, e) => {
+                UNIX_EPOCH + Duration::from_secs(e)
+            }
+            _ => SystemTime::now(),
+        };
+        let (kind, perm, size) = match node {
+            Node::TimePidStatus(..) | Node::PidTime(..) => {
+                (FileType::RegularFile, 0o444, self.content(node)?.len() as u64)
+            }
+            _ => (FileType::Directory, 0o555, 0),
+        };
+        Some(FileAttr {
+            ino,
+            size,
+            blocks: size.div_ceil(512),
+            atime: mtime,
+            mtime,
+            ctime: mtime,
+            crtime: mtime,
+            kind,
+            perm,
+            nlink: if kind == FileType::Directory { 2 } else { 1 },
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            blksize: 4096,
+            flags: 0,
+        })
+    }
+}
+
+impl Filesystem for ArchiveFs {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let child = match (self.node(parent), name.to_str()) {
+            (Some(p), Some(n)) => self.child(p, n),
+            _ => None,
+        };
+        match child.and_then(|c| self.attr(c)) {
+            Some(attr) => reply.entry(&TTL, &attr, 0),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        match self.node(ino).and_then(|n| self.attr(n)) {
+            Some(attr) => reply.attr(&TTL, &attr),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        match self.node(ino).and_then(|n| self.content(n)) {
+            Some(data) => {
+                let start = (offset.max(0) as usize).min(data.len());
+                let end = (start + size as usize).min(data.len());
+                reply.data(&data[start..end]);
+            }
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let node = match self.node(ino) {
+            Some(n) => n,
+            None => return reply.error(libc::ENOENT),
+        };
+        let mut entries = vec![
+            (ino, FileType::Directory, ".".to_string()),
+            (ROOT_INO, FileType::Directory, "..".to_string()),
+        ];
+        for (child, name) in self.children(node) {
+            let kind = match child {
+                Node::TimePidStatus(..) | Node::PidTime(..) => FileType::RegularFile,
+                _ => FileType::Directory,
+            };
+            entries.push((self.ino(child), kind, name));
+        }
+        for (i, (ino, kind, name)) in entries.into_iter().enumerate().skip(offset as usize) {
+            // The offset passed back to us is the index of the next entry.
+            if reply.add(ino, (i + 1) as i64, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+}
+
+/// Mounts the datadir read-only at `mountpoint` and serves it until it is unmounted.
+pub fn mount(datadir: &Path, mountpoint: &Path) -> io::Result<()> {
+    let options = [
+        MountOption::RO,
+        MountOption::FSName("procshot".to_string()),
+        MountOption::Subtype("procshot".to_string()),
+    ];
+    fuser::mount2(ArchiveFs::new(datadir), mountpoint, &options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inode_allocation() {
+        let mut fs = ArchiveFs::new(Path::new("/nonexistent"));
+        assert_eq!(fs.ino(Node::Root), ROOT_INO);
+        let a = fs.ino(Node::TimePid(1, Pid::new(2)));
+        assert_eq!(fs.ino(Node::TimePid(1, Pid::new(2))), a);
+        assert_eq!(fs.node(a), Some(Node::TimePid(1, Pid::new(2))));
+        assert_eq!(fs.node(0), None);
+    }
+
+    #[test]
+    fn test_child_names() {
+        let mut fs = ArchiveFs::new(Path::new("/nonexistent"));
+        assert_eq!(fs.child(Node::Root, "by-pid"), Some(Node::ByPid));
+        assert_eq!(fs.child(Node::Root, "etc"), None);
+        assert_eq!(
+            fs.child(Node::TimePid(5, Pid::new(7)), "status.json"),
+            Some(Node::TimePidStatus(5, Pid::new(7)))
+        );
+        assert_eq!(fs.child(Node::ByTime, "12"), None);
+    }
+}
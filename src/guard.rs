@@ -0,0 +1,104 @@
+//! Memory cap for the collector itself.
+//!
+//! On a pathological host (hundreds of thousands of processes, huge command lines) the server's
+//! own memory grows with what it records. `MemoryGuard` checks procshot's rss once per iteration
+//! against a configured limit. While it is above the limit the server sheds its optional work
+//! (the statm collector, the fd and connection lists and the percentile sketches) and returns freed
+//! memory to the system, so the monitoring agent doesn't become the OOM victim, or the cause.
+
+use std::io;
+
+use crate::{statm, Pid};
+
+/// Pressure is the result of a memory check.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pressure {
+    /// Below the limit.
+    Normal,
+    /// Above the limit, with the current rss in bytes.
+    Over(u64),
+}
+
+/// MemoryGuard compares the rss of the running process to a limit.
+#[derive(Debug)]
+pub struct MemoryGuard {
+    limit_bytes: u64,
+    page_size: u64,
+    shedding: bool,
+}
+
+impl MemoryGuard {
+    pub fn new(limit_bytes: u64) -> Self {
+        MemoryGuard {
+            limit_bytes,
+            page_size: procfs::page_size().map(|p| p as u64).unwrap_or(4096),
+            shedding: false,
+        }
+    }
+
+    /// True if the last check was above the limit.
+    pub fn is_shedding(&self) -> bool {
+        self.shedding
+    }
+
+    /// Returns the current rss of this process in bytes.
+    pub fn rss_bytes(&self) -> io::Result<u64> {
+        Ok(statm::read(Pid::current())?.resident * self.page_size)
+    }
+
+    /// Checks the rss against the limit, logging when the state changes. If the rss can't be read
+    /// the previous state is kept.
+    pub fn check(&mut self) -> Pressure {
+        let rss = match self.rss_bytes() {
+            Ok(rss) => rss,
+            Err(e) => {
+                eprintln!("Cannot read own memory usage, err: {}", e);
+                return self.pressure(0);
+            }
+        };
+        let over = rss > self.limit_bytes;
+        if over && !self.shedding {
+            eprintln!(
+                "Memory usage of {} bytes is above the limit of {} bytes, shedding optional collectors and caches",
+                rss, self.limit_bytes
+            );
+        } else if !over && self.shedding {
+            eprintln!(
+                "Memory usage of {} bytes is back below the limit of {} bytes, resuming optional collectors",
+                rss, self.limit_bytes
+            );
+        }
+        self.shedding = over;
+        self.pressure(rss)
+    }
+
+    fn pressure(&self, rss: u64) -> Pressure {
+        match self.shedding {
+            true => Pressure::Over(rss),
+            false => Pressure::Normal,
+        }
+    }
+}
+
+/// Hands the memory freed by dropped caches back to the kernel. glibc keeps it in its arenas
+/// otherwise, and the rss wouldn't go down.
+pub fn release_free_memory() {
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    unsafe {
+        libc::malloc_trim(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let mut guard = MemoryGuard::new(u64::MAX);
+        assert_eq!(guard.check(), Pressure::Normal);
+        let mut guard = MemoryGuard::new(1);
+        assert!(matches!(guard.check(), Pressure::Over(rss) if rss > 1));
+        assert!(guard.is_shedding());
+    }
+}
@@ -0,0 +1,275 @@
+//! Self-describing header of the exported formats.
+//!
+//! Generic consumers of an exported archive (a dashboard reading the JSON lines, a notebook) can't
+//! tell from the field names alone that `vmsize` is in kB, `rss_pages` in pages and `utime` in
+//! clock ticks. The `Header` written at the start of those formats carries a compact table of
+//! field name to `Unit`, along with the page size and the clock tick rate of the host, so values
+//! can be rendered correctly without hardcoding that knowledge.
+
+use std::collections::BTreeMap;
+
+use crate::metrics;
+
+/// Unit is the unit or meaning of a numeric field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Unit {
+    Bytes,
+    /// Kibibytes, as /proc reports them with a `kB` suffix.
+    Kibibytes,
+    /// Memory pages, of `Header::page_size` bytes.
+    Pages,
+    /// Sectors of 512 bytes, whatever the sector size of the device.
+    Sectors,
+    /// Clock ticks, of which there are `Header::clock_ticks_per_second` per second.
+    ClockTicks,
+    /// A percentage: of the CPU time of the host for the CPU usages, of the memory limit for
+    /// `rss_pct_of_limit`.
+    Percent,
+    /// Seconds since the Unix epoch.
+    EpochSeconds,
+    Seconds,
+    Milliseconds,
+    Nanoseconds,
+    Count,
+    /// A process id.
+    Pid,
+    /// A user id.
+    Uid,
+    /// The index of a CPU.
+    CpuIndex,
+    /// A number of CPUs, eg: 1.5 for a quota of 150ms of CPU time every 100ms.
+    Cpus,
+    /// A real-time scheduling priority, from 1 to 99, higher runs first.
+    RtPriority,
+    /// A load average: the average number of runnable and uninterruptible tasks.
+    LoadAverage,
+}
+
+/// Units of the numeric fields of a snapshot. Process fields are named as in `PidStatus`, the
+/// fields of the system wide CPU times, memory and load are prefixed with `cpu_times.`,
+/// `system_memory.` and `load_average.`, those of each CPU with `per_cpu.<N>.`, those of each
+/// block device with `disks.<N>.` and those of each network interface with `interfaces.<N>.`. In
+/// the names of the fields numbered at runtime, `<N>` stands for the number. The units of the
+/// process metrics, eg: `rss_bytes`, are those of `metrics::PROCESS_METRICS`.
+pub const FIELD_UNITS: &[(&str, Unit)] = &[
+    // EncoDecode
+    ("time_epoch", Unit::EpochSeconds),
+    ("delay.secs", Unit::Seconds),
+    ("delay.nanos", Unit::Nanoseconds),
+    ("total_cpu_time", Unit::ClockTicks),
+    ("cpu_times.user", Unit::ClockTicks),
+    ("cpu_times.nice", Unit::ClockTicks),
+    ("cpu_times.system", Unit::ClockTicks),
+    ("cpu_times.idle", Unit::ClockTicks),
+    ("cpu_times.iowait", Unit::ClockTicks),
+    ("cpu_times.irq", Unit::ClockTicks),
+    ("cpu_times.softirq", Unit::ClockTicks),
+    ("cpu_times.steal", Unit::ClockTicks),
+    ("cpu_times.guest", Unit::ClockTicks),
+    ("cpu_times.guest_nice", Unit::ClockTicks),
+    ("per_cpu.<N>.user", Unit::ClockTicks),
+    ("per_cpu.<N>.nice", Unit::ClockTicks),
+    ("per_cpu.<N>.system", Unit::ClockTicks),
+    ("per_cpu.<N>.idle", Unit::ClockTicks),
+    ("per_cpu.<N>.iowait", Unit::ClockTicks),
+    ("per_cpu.<N>.irq", Unit::ClockTicks),
+    ("per_cpu.<N>.softirq", Unit::ClockTicks),
+    ("per_cpu.<N>.steal", Unit::ClockTicks),
+    ("per_cpu.<N>.guest", Unit::ClockTicks),
+    ("per_cpu.<N>.guest_nice", Unit::ClockTicks),
+    ("system_memory.total_kb", Unit::Kibibytes),
+    ("system_memory.free_kb", Unit::Kibibytes),
+    ("system_memory.available_kb", Unit::Kibibytes),
+    ("system_memory.buffers_kb", Unit::Kibibytes),
+    ("system_memory.cached_kb", Unit::Kibibytes),
+    ("system_memory.swap_used_kb", Unit::Kibibytes),
+    ("load_average.one", Unit::LoadAverage),
+    ("load_average.five", Unit::LoadAverage),
+    ("load_average.fifteen", Unit::LoadAverage),
+    ("load_average.runnable", Unit::Count),
+    ("load_average.tasks", Unit::Count),
+    ("uptime.secs", Unit::Seconds),
+    ("uptime.nanos", Unit::Nanoseconds),
+    ("disks.<N>.major", Unit::Count),
+    ("disks.<N>.minor", Unit::Count),
+    ("disks.<N>.reads", Unit::Count),
+    ("disks.<N>.reads_merged", Unit::Count),
+    ("disks.<N>.sectors_read", Unit::Sectors),
+    ("disks.<N>.read_time_ms", Unit::Milliseconds),
+    ("disks.<N>.writes", Unit::Count),
+    ("disks.<N>.writes_merged", Unit::Count),
+    ("disks.<N>.sectors_written", Unit::Sectors),
+    ("disks.<N>.write_time_ms", Unit::Milliseconds),
+    ("disks.<N>.in_flight", Unit::Count),
+    ("disks.<N>.io_time_ms", Unit::Milliseconds),
+    ("disks.<N>.weighted_io_time_ms", Unit::Milliseconds),
+    ("interfaces.<N>.rx_bytes", Unit::Bytes),
+    ("interfaces.<N>.rx_packets", Unit::Count),
+    ("interfaces.<N>.rx_errors", Unit::Count),
+    ("interfaces.<N>.rx_drops", Unit::Count),
+    ("interfaces.<N>.tx_bytes", Unit::Bytes),
+    ("interfaces.<N>.tx_packets", Unit::Count),
+    ("interfaces.<N>.tx_errors", Unit::Count),
+    ("interfaces.<N>.tx_drops", Unit::Count),
+    // PidStatus, besides `metrics::PROCESS_METRICS`
+    ("ppid", Unit::Pid),
+    ("euid", Unit::Uid),
+    ("tracerpid", Unit::Pid),
+    ("processor_last_executed", Unit::CpuIndex),
+    ("rt_priority", Unit::RtPriority),
+    // PidStatus::extensions
+    ("extensions.offcpu_ns", Unit::Nanoseconds),
+    ("extensions.runq_latency_ns", Unit::Nanoseconds),
+    ("extensions.cpu_delay_ns", Unit::Nanoseconds),
+    ("extensions.blkio_delay_ns", Unit::Nanoseconds),
+    ("extensions.swapin_delay_ns", Unit::Nanoseconds),
+    ("extensions.tcp_sockets", Unit::Count),
+    ("extensions.tcp_segs_out", Unit::Count),
+    ("extensions.tcp_retrans_segs", Unit::Count),
+    ("extensions.tcp_drops", Unit::Count),
+    ("extensions.numa_node<N>_kb", Unit::Kibibytes),
+];
+
+/// Header describes the snapshots that follow it in an exported archive.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Header {
+    /// Always `procshot`, tells a header apart from a snapshot.
+    pub magic: String,
+    pub page_size: u64,
+    pub clock_ticks_per_second: u64,
+    pub units: BTreeMap<String, Unit>,
+}
+
+/// Page size of this host, 4096 if unknown or built without the server feature.
+fn page_size() -> u64 {
+    #[cfg(feature = "server")]
+    if let Ok(p) = procfs::page_size() {
+        return p as u64;
+    }
+    4096
+}
+
+/// Clock ticks per second of this host, 100 if unknown or built without the server feature.
+pub fn clock_ticks_per_second() -> u64 {
+    #[cfg(feature = "server")]
+    if let Ok(t) = procfs::ticks_per_second() {
+        return t as u64;
+    }
+    100
+}
+
+/// Duration of one clock tick of this host, the shortest delay of the server: the CPU usage is
+/// computed from the clock ticks elapsed between two scans.
+pub fn clock_tick() -> std::time::Duration {
+    std::time::Duration::from_secs(1) / clock_ticks_per_second() as u32
+}
+
+/// Value of `Header::magic`.
+pub const MAGIC: &str = "procshot";
+
+impl Header {
+    /// Returns the header of the snapshots recorded on this host.
+    pub fn current() -> Self {
+        Header {
+            magic: MAGIC.to_string(),
+            page_size: page_size(),
+            clock_ticks_per_second: clock_ticks_per_second(),
+            units: FIELD_UNITS
+                .iter()
+                .map(|(name, unit)| (name.to_string(), *unit))
+                .chain(
+                    metrics::PROCESS_METRICS
+                        .iter()
+                        .filter(|m| m.field)
+                        .map(|m| (m.name.to_string(), m.unit)),
+                )
+                .collect(),
+        }
+    }
+
+    /// Returns the unit of a field, if it is numeric. Numbers in `field` match the `<N>` of
+    /// the numbered fields, eg: `extensions.numa_node1_kb`.
+    pub fn unit(&self, field: &str) -> Option<Unit> {
+        if let Some(unit) = self.units.get(field) {
+            return Some(*unit);
+        }
+        let mut numbered = String::new();
+        for c in field.chars() {
+            match c.is_ascii_digit() {
+                true if !numbered.ends_with("<N>") => numbered.push_str("<N>"),
+                true => (),
+                false => numbered.push(c),
+            }
+        }
+        self.units.get(&numbered).copied()
+    }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use super::*;
+    use crate::collect::{restricted_pid_status, PROC_ROOT};
+    use crate::Pid;
+    use std::path::Path;
+
+    #[test]
+    fn test_every_numeric_field_has_a_unit() {
+        let header = Header::current();
+        let mut status = restricted_pid_status(Path::new(PROC_ROOT), Pid::current());
+        status.io = Some(Default::default());
+        status.service_hint = Some("postgresql".to_string());
+        status.runtime = Some("jvm".to_string());
+        status.cgroup = Some("/system.slice/nginx.service".to_string());
+        status.cgroup_limits = Some(crate::cgroup::Limits {
+            memory_max: Some(1 << 30),
+            cpus: Some(1.5),
+        });
+        status.fds = Some(Vec::new());
+        status.connections = Some(Vec::new());
+        status.sched_policy = Some(crate::priority::SchedPolicy::Other);
+        let status = serde_json::to_value(status).unwrap();
+        for (name, value) in status.as_object().unwrap() {
+            if value.is_number() || value.is_null() {
+                assert!(header.unit(name).is_some(), "no unit for {}", name);
+            }
+            if let Some(nested) = value.as_object() {
+                for (field, value) in nested {
+                    let name = format!("{}.{}", name, field);
+                    if value.is_number() {
+                        assert!(header.unit(&name).is_some(), "no unit for {}", name);
+                    }
+                }
+            }
+        }
+        let memory = serde_json::to_value(crate::meminfo::MemInfo::default()).unwrap();
+        let load = serde_json::to_value(crate::load::LoadAvg::default()).unwrap();
+        let cpu = serde_json::to_value(crate::cpu::CpuTimes::default()).unwrap();
+        let disk = serde_json::to_value(crate::diskstats::DiskStats::default()).unwrap();
+        let interface = serde_json::to_value(crate::netdev::InterfaceStats::default()).unwrap();
+        for (prefix, value) in [
+            ("system_memory", memory),
+            ("load_average", load),
+            ("per_cpu.15", cpu),
+            ("disks.0", disk),
+            ("interfaces.1", interface),
+        ] {
+            for (field, value) in value.as_object().unwrap() {
+                let name = format!("{}.{}", prefix, field);
+                if value.is_number() {
+                    assert!(header.unit(&name).is_some(), "no unit for {}", name);
+                }
+            }
+        }
+        assert_eq!(header.unit("vmsize"), Some(Unit::Kibibytes));
+        assert_eq!(
+            header.unit("extensions.numa_node12_kb"),
+            Some(Unit::Kibibytes)
+        );
+        assert_eq!(header.unit("extensions.numa_nodeX_kb"), None);
+        assert_eq!(
+            serde_json::to_string(&Unit::ClockTicks).unwrap(),
+            "\"clock_ticks\""
+        );
+    }
+}
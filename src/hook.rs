@@ -0,0 +1,137 @@
+//! Hooks run after every snapshot file is written to the datadir.
+//!
+//! A hook receives the path of the new file and its `SnapshotSummary`, so custom shipping or
+//! indexing can be chained to the server without patching the crate. Hooks are registered in
+//! `ScanOptions::hooks`: either a closure, or a `CommandHook` running an external command. Like
+//! sinks, an error in one hook is logged without affecting the others.
+
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::process::Command;
+
+use crate::child::{split_command_line, ChildLimits, Supervisor};
+use crate::summary::SnapshotSummary;
+
+/// PostWriteHook is implemented by everything that wants to be told about new snapshot files.
+pub trait PostWriteHook: Send {
+    /// Short name of the hook, used in log messages.
+    fn name(&self) -> &str;
+
+    /// Called once the snapshot file at `path` has been completely written.
+    fn after_write(&mut self, path: &Path, summary: &SnapshotSummary) -> io::Result<()>;
+}
+
+impl fmt::Debug for dyn PostWriteHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PostWriteHook({})", self.name())
+    }
+}
+
+/// Any `FnMut(&Path, &SnapshotSummary) -> io::Result<()>` closure can be registered as a hook.
+impl<F> PostWriteHook for F
+where
+    F: FnMut(&Path, &SnapshotSummary) -> io::Result<()> + Send,
+{
+    fn name(&self) -> &str {
+        "closure"
+    }
+
+    fn after_write(&mut self, path: &Path, summary: &SnapshotSummary) -> io::Result<()> {
+        self(path, summary)
+    }
+}
+
+/// CommandHook runs an external command for every snapshot. The path of the snapshot file is
+/// appended as the last argument and the JSON encoded summary is written to its stdin. The command
+/// runs in the background under a `child::Supervisor`: it is killed if it exceeds the timeout of
+/// its `ChildLimits`, and the server doesn't wait for it. Failures are logged once the command
+/// exits.
+#[derive(Debug)]
+pub struct CommandHook {
+    command_line: String,
+    program: String,
+    args: Vec<String>,
+    supervisor: Supervisor,
+}
+
+impl CommandHook {
+    /// `command_line` is split on whitespace into the program and its arguments; no shell is
+    /// involved, use `sh -c` explicitly if one is needed.
+    pub fn new(command_line: &str) -> io::Result<Self> {
+        Self::with_limits(command_line, ChildLimits::default())
+    }
+
+    /// Same as `new`, with the given limits on the running commands.
+    pub fn with_limits(command_line: &str, limits: ChildLimits) -> io::Result<Self> {
+        let (program, args) = split_command_line(command_line)?;
+        Ok(CommandHook {
+            command_line: command_line.to_string(),
+            program,
+            args,
+            supervisor: Supervisor::new(limits),
+        })
+    }
+}
+
+impl PostWriteHook for CommandHook {
+    fn name(&self) -> &str {
+        &self.command_line
+    }
+
+    /// Starts the command. Fails if it can't be started, or if too many of its previous runs
+    /// are still going.
+    fn after_write(&mut self, path: &Path, summary: &SnapshotSummary) -> io::Result<()> {
+        let json = serde_json::to_vec(summary).map_err(io::Error::other)?;
+        let mut command = Command::new(&self.program);
+        command.args(&self.args).arg(path);
+        self.supervisor
+            .spawn(&self.command_line, &mut command, json)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary() -> SnapshotSummary {
+        let snapshot = crate::EncoDecode {
+            hostname: "localghost".to_string(),
+            time_epoch: 1563617611,
+            delay: std::time::Duration::from_secs(60),
+            ..Default::default()
+        };
+        SnapshotSummary::new(&snapshot, None)
+    }
+
+    #[test]
+    fn test_command_hook() {
+        let path = Path::new("/tmp/1563617611.procshot");
+        let mut hook = CommandHook::new("true").unwrap();
+        assert!(hook.after_write(path, &summary()).is_ok());
+        assert!(hook.supervisor.wait_all()[0].is_success());
+        let mut hook = CommandHook::new("false").unwrap();
+        assert!(hook.after_write(path, &summary()).is_ok());
+        assert!(!hook.supervisor.wait_all()[0].is_success());
+        assert!(CommandHook::new("  ").is_err());
+        let hook = CommandHook::new("/usr/local/bin/ship --bucket logs").unwrap();
+        assert_eq!(hook.program, "/usr/local/bin/ship");
+        assert_eq!(hook.args, vec!["--bucket", "logs"]);
+    }
+
+    #[test]
+    fn test_closure_hook() {
+        let mut seen = Vec::new();
+        {
+            let mut hook = |path: &Path, s: &SnapshotSummary| {
+                seen.push((path.to_path_buf(), s.time_epoch));
+                Ok(())
+            };
+            let hooks: Vec<&mut dyn PostWriteHook> = vec![&mut hook];
+            for h in hooks {
+                h.after_write(Path::new("a.procshot"), &summary()).unwrap();
+            }
+        }
+        assert_eq!(seen, vec![(Path::new("a.procshot").to_path_buf(), 1563617611)]);
+    }
+}
@@ -0,0 +1,294 @@
+//! Hosts reporting under the same hostname.
+//!
+//! VMs cloned from one image often keep its hostname, and a collector keying the snapshots it
+//! receives by hostname then silently interleaves two machines under one name. The server labels
+//! every snapshot with the machine id and the boot id of its host (`host_labels`), and whatever
+//! receives snapshots from other hosts, eg: a consumer of the Kafka sink, passes each one to
+//! `HostRegistry::admit`, which tells the machines sharing a hostname apart:
+//!
+//! * a machine id other than the one first seen under the hostname is another machine;
+//! * a boot id seen again after the hostname moved on to a newer one is another machine too, a
+//!   clone that kept the machine id, since a host that rebooted never sends snapshots of a previous
+//!   boot.
+//!
+//! The `CollisionPolicy` decides whether the snapshots of the other machines are renamed or
+//! refused. Snapshots without the labels, eg: from older servers, are admitted as they are.
+//!
+//! The hostname itself is read once when the server starts, which attributes every snapshot to
+//! `localhost` when the server starts before DHCP or cloud-init named the host. `HostnameWatch`
+//! reads it again periodically, and at every iteration while it's a placeholder like
+//! `localhost`, so the server can switch to the new name and annotate the change.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::EncoDecode;
+
+/// Label holding the content of /etc/machine-id.
+pub const MACHINE_ID_LABEL: &str = "host.machine_id";
+/// Label holding the boot id of the kernel, which changes on every boot.
+pub const BOOT_ID_LABEL: &str = "host.boot_id";
+
+const MACHINE_ID: &str = "/etc/machine-id";
+const BOOT_ID: &str = "/proc/sys/kernel/random/boot_id";
+
+/// Returns the identity labels of this host. Ids that can't be read are left out.
+pub fn host_labels() -> BTreeMap<String, String> {
+    let mut labels = BTreeMap::new();
+    for (label, path) in &[(MACHINE_ID_LABEL, MACHINE_ID), (BOOT_ID_LABEL, BOOT_ID)] {
+        match fs::read_to_string(path) {
+            Ok(id) if !id.trim().is_empty() => {
+                labels.insert(label.to_string(), id.trim().to_string());
+            }
+            _ => (),
+        }
+    }
+    labels
+}
+
+/// Hostnames of hosts that weren't named yet.
+const PLACEHOLDER_HOSTNAMES: &[&str] = &["", "localhost", "localhost.localdomain"];
+
+/// HostnameWatch reads the hostname again every `interval`, to follow a host renamed after the
+/// server started.
+pub struct HostnameWatch {
+    interval: u64,
+    next_check: u64,
+    resolve: Box<dyn FnMut() -> Option<String> + Send>,
+}
+
+impl HostnameWatch {
+    /// Reads the hostname with `resolve`, which returns None if it can't be read.
+    pub fn new<F>(interval: Duration, resolve: F) -> Self
+    where
+        F: FnMut() -> Option<String> + Send + 'static,
+    {
+        HostnameWatch {
+            interval: interval.as_secs().max(1),
+            next_check: 0,
+            resolve: Box::new(resolve),
+        }
+    }
+
+    /// Returns the hostname if it's no longer `current`, at `time_epoch`. It's read at most once
+    /// per interval, unless `current` is a placeholder.
+    pub fn poll(&mut self, current: &str, time_epoch: u64) -> Option<String> {
+        if time_epoch < self.next_check && !PLACEHOLDER_HOSTNAMES.contains(&current) {
+            return None;
+        }
+        self.next_check = time_epoch + self.interval;
+        (self.resolve)().filter(|h| !h.is_empty() && h != current)
+    }
+}
+
+impl fmt::Debug for HostnameWatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("HostnameWatch")
+            .field("interval", &self.interval)
+            .field("next_check", &self.next_check)
+            .finish()
+    }
+}
+
+/// CollisionPolicy is what `HostRegistry::admit` does with the snapshots of a machine reporting
+/// the hostname of another one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CollisionPolicy {
+    /// Rename the host of the snapshot to `<hostname>~<id>`, with the first 8 characters of its
+    /// machine id, or of its boot id if the machine ids are the same.
+    Suffix,
+    /// Refuse the snapshot with a `HostCollision`.
+    Reject,
+}
+
+impl FromStr for CollisionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "suffix" => Ok(CollisionPolicy::Suffix),
+            "reject" => Ok(CollisionPolicy::Reject),
+            _ => Err(format!(
+                "unknown collision policy {}, expected suffix or reject",
+                s
+            )),
+        }
+    }
+}
+
+/// HostCollision is a snapshot refused because another machine reports the same hostname.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HostCollision {
+    pub hostname: String,
+    /// Label (`MACHINE_ID_LABEL` or `BOOT_ID_LABEL`) that tells the machines apart.
+    pub label: &'static str,
+    /// Its value for the machine first seen under the hostname.
+    pub known: String,
+    /// Its value in the refused snapshot.
+    pub received: String,
+}
+
+impl fmt::Display for HostCollision {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "two machines report the hostname {}: {} {} was seen first, refusing the snapshot with {} {}",
+            self.hostname, self.label, self.known, self.label, self.received
+        )
+    }
+}
+
+impl std::error::Error for HostCollision {}
+
+/// What is known of the machine first seen under a hostname.
+#[derive(Debug)]
+struct KnownHost {
+    machine_id: Option<String>,
+    /// Boot ids of the machine, oldest first. Only the last one may send snapshots.
+    boot_ids: Vec<String>,
+}
+
+/// HostRegistry remembers the machine behind every hostname it admitted snapshots from.
+#[derive(Debug)]
+pub struct HostRegistry {
+    policy: CollisionPolicy,
+    hosts: HashMap<String, KnownHost>,
+}
+
+impl HostRegistry {
+    pub fn new(policy: CollisionPolicy) -> Self {
+        HostRegistry {
+            policy,
+            hosts: HashMap::new(),
+        }
+    }
+
+    /// Admits `snapshot`, renaming its host under `CollisionPolicy::Suffix` if another machine
+    /// reported its hostname first. Fails under `CollisionPolicy::Reject` instead.
+    pub fn admit(&mut self, snapshot: &mut EncoDecode) -> Result<(), HostCollision> {
+        let machine_id = snapshot.labels.get(MACHINE_ID_LABEL).cloned();
+        let boot_id = snapshot.labels.get(BOOT_ID_LABEL).cloned();
+        let collision = match self.hosts.get_mut(&snapshot.hostname) {
+            None => {
+                self.hosts.insert(
+                    snapshot.hostname.clone(),
+                    KnownHost {
+                        machine_id,
+                        boot_ids: boot_id.into_iter().collect(),
+                    },
+                );
+                return Ok(());
+            }
+            Some(known) => match (&known.machine_id, &machine_id, boot_id) {
+                (Some(k), Some(m), _) if k != m => (MACHINE_ID_LABEL, k.clone(), m.clone()),
+                (_, _, Some(b)) => match known.boot_ids.iter().position(|k| *k == b) {
+                    // Rebooted.
+                    None => {
+                        known.boot_ids.push(b);
+                        return Ok(());
+                    }
+                    Some(i) if i + 1 == known.boot_ids.len() => return Ok(()),
+                    Some(_) => (BOOT_ID_LABEL, known.boot_ids.last().cloned().unwrap(), b),
+                },
+                _ => return Ok(()),
+            },
+        };
+        let (label, known, received) = collision;
+        match self.policy {
+            CollisionPolicy::Reject => Err(HostCollision {
+                hostname: snapshot.hostname.clone(),
+                label,
+                known,
+                received,
+            }),
+            CollisionPolicy::Suffix => {
+                let suffix: String = received.chars().take(8).collect();
+                snapshot.hostname = format!("{}~{}", snapshot.hostname, suffix);
+                // The renamed host may collide in turn.
+                self.admit(snapshot)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    fn snapshot(machine_id: &str, boot_id: &str) -> EncoDecode {
+        let mut labels = BTreeMap::new();
+        labels.insert(MACHINE_ID_LABEL.to_string(), machine_id.to_string());
+        labels.insert(BOOT_ID_LABEL.to_string(), boot_id.to_string());
+        EncoDecode {
+            hostname: "db-0".to_string(),
+            time_epoch: 1563617611,
+            delay: Duration::from_secs(60),
+            labels,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_admit() {
+        let mut registry = HostRegistry::new(CollisionPolicy::Suffix);
+        let mut s = snapshot("aaaaaaaaaaaa", "boot-1");
+        registry.admit(&mut s).unwrap();
+        // Rebooted.
+        registry
+            .admit(&mut snapshot("aaaaaaaaaaaa", "boot-2"))
+            .unwrap();
+        let mut s = snapshot("aaaaaaaaaaaa", "boot-2");
+        registry.admit(&mut s).unwrap();
+        assert_eq!(s.hostname, "db-0");
+        // A clone with another machine id, then one that kept the machine id.
+        let mut s = snapshot("bbbbbbbbbbbb", "boot-3");
+        registry.admit(&mut s).unwrap();
+        assert_eq!(s.hostname, "db-0~bbbbbbbb");
+        let mut s = snapshot("aaaaaaaaaaaa", "boot-1");
+        registry.admit(&mut s).unwrap();
+        assert_eq!(s.hostname, "db-0~boot-1");
+        // From a server without the labels.
+        let mut s = snapshot("", "");
+        s.labels.clear();
+        registry.admit(&mut s).unwrap();
+        assert_eq!(s.hostname, "db-0");
+
+        let mut registry = HostRegistry::new("reject".parse().unwrap());
+        registry
+            .admit(&mut snapshot("aaaaaaaaaaaa", "boot-1"))
+            .unwrap();
+        let e = registry
+            .admit(&mut snapshot("bbbbbbbbbbbb", "boot-1"))
+            .unwrap_err();
+        assert_eq!(e.label, MACHINE_ID_LABEL);
+        assert_eq!(e.received, "bbbbbbbbbbbb");
+        assert!(e.to_string().contains("hostname db-0"));
+
+        let labels = host_labels();
+        if std::path::Path::new(BOOT_ID).exists() {
+            assert_eq!(labels[BOOT_ID_LABEL].len(), 36);
+        }
+    }
+
+    #[test]
+    fn test_hostname_watch() {
+        let hostname = Arc::new(Mutex::new("localhost".to_string()));
+        let resolved = hostname.clone();
+        let mut watch = HostnameWatch::new(Duration::from_secs(300), move || {
+            Some(resolved.lock().unwrap().clone())
+        });
+        assert_eq!(watch.poll("localhost", 1000), None);
+        // Placeholders are read again at every iteration.
+        *hostname.lock().unwrap() = "web-1".to_string();
+        assert_eq!(watch.poll("localhost", 1060).as_deref(), Some("web-1"));
+        *hostname.lock().unwrap() = "web-2".to_string();
+        assert_eq!(watch.poll("web-1", 1120), None);
+        assert_eq!(watch.poll("web-1", 1360).as_deref(), Some("web-2"));
+        *hostname.lock().unwrap() = String::new();
+        assert_eq!(watch.poll("web-2", 2000), None);
+    }
+}
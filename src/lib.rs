@@ -6,41 +6,147 @@
 //! # Examples
 //!
 //! ```rust
-//! use std::fs::File;
-//! use std::io::Read;
 //! use procshot_server::EncoDecode;
 //! pub fn read_test_data() {
-//!         let mut file = File::open("./test_data.procshot").unwrap();
-//!         let mut data = Vec::new();
-//!         file.read_to_end(&mut data).unwrap();
-//!         let decoded: EncoDecode = bincode::deserialize(&data[..]).unwrap_or_else(|x| panic!("Error reading saved data. This was either created with an older version of procshot, or the file is corrupt. Error is {}", x));
+//!         let decoded = EncoDecode::from_file("./test_data.procshot").unwrap_or_else(|e| panic!("Error reading saved data: {}", e));
 //!         println!("Decoded test file data: {:#?}", decoded);
 //! }
 //! ```
 
+#[cfg(feature = "server")]
 extern crate procfs;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+#[cfg(feature = "server")]
 use std::thread;
 use std::time::Duration;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde;
-use std::fs::File;
-use std::io::Write;
-use std::io::{BufRead, BufReader};
 
 // Tmp imports
 
+#[cfg(feature = "server")]
 extern crate clap;
+#[cfg(feature = "server")]
 extern crate hostname;
+#[cfg(feature = "server")]
 use clap::{App, Arg, SubCommand};
 
+#[cfg(feature = "server")]
+pub mod alert;
+pub mod annotations;
+#[cfg(feature = "arrow")]
+pub mod arrow;
+pub mod backend;
+pub mod bench_format;
+pub mod cgroup;
+#[cfg(feature = "server")]
+pub mod child;
+pub mod client;
+#[cfg(feature = "server")]
+pub mod cloud;
+#[cfg(feature = "server")]
+pub mod collect;
+#[cfg(feature = "server")]
+pub mod collector;
+pub mod connections;
+pub mod convert;
+pub mod cpu;
+#[cfg(feature = "server")]
+pub mod delayacct;
+pub mod diskstats;
+#[cfg(feature = "server")]
+pub mod doctor;
+pub mod error;
+pub mod events;
+pub mod fds;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "server")]
+pub mod filter;
+pub mod fleet;
+#[cfg(feature = "fuse")]
+pub mod fuse;
+#[cfg(feature = "server")]
+pub mod guard;
+pub mod header;
+#[cfg(feature = "server")]
+pub mod hook;
+pub mod identity;
+pub mod load;
+#[cfg(feature = "server")]
+pub mod lock;
+pub mod meminfo;
+pub mod merge;
+pub mod metrics;
+pub mod netdev;
+#[cfg(feature = "server")]
+pub mod numa;
+#[cfg(all(feature = "server", feature = "ebpf"))]
+pub mod offcpu;
+#[cfg(feature = "server")]
+pub mod oom;
+pub mod paths;
+#[cfg(feature = "server")]
+pub mod placement;
+pub mod pid;
+pub mod prelude;
+pub mod priority;
+#[cfg(feature = "server")]
+pub mod proc_events;
+pub mod proc_io;
+#[cfg(feature = "server")]
+pub mod procfile;
+pub mod query;
+#[cfg(feature = "server")]
+pub mod realtime;
+#[cfg(feature = "server")]
+pub mod redact;
+pub mod report;
+pub mod retention;
+#[cfg(feature = "server")]
+pub mod runtime;
+#[cfg(feature = "server")]
+pub mod sampling;
+#[cfg(feature = "server")]
+pub mod services;
+pub mod shell;
+#[cfg(feature = "server")]
+pub mod sink;
+pub mod skew;
+pub mod sketch;
+pub mod slim;
+#[cfg(feature = "server")]
+pub mod statm;
+pub mod store;
+pub mod summary;
+#[cfg(feature = "server")]
+pub mod systemd;
+#[cfg(feature = "server")]
+pub mod tail;
+#[cfg(feature = "server")]
+pub mod tcpstats;
+pub mod tier;
+pub mod tls;
+pub mod tz;
+pub mod units;
+#[cfg(feature = "server")]
+pub mod upload;
+pub mod validate;
+pub mod versioned;
+#[cfg(feature = "server")]
+pub mod watchdog;
+#[cfg(feature = "server")]
+pub mod web;
+
+pub use pid::Pid;
+
 /// PidStatus is the struct that holds the data that we store for each process' status. In this crate, we create a
-/// ` Vec<HashMap<i32, PidStatus>>` which is a mapping of pid to its status.
-#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+/// ` Vec<HashMap<Pid, PidStatus>>` which is a mapping of pid to its status.
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Clone)]
 pub struct PidStatus {
     /// Parent pid
-    pub ppid: i32,
+    pub ppid: Pid,
     /// Effective uid
     pub euid: i32,
     /// The complete path to cmd_long if available.
@@ -52,9 +158,16 @@ pub struct PidStatus {
     /// This is visible whether or not the executable is swapped out.
     pub cmd_short: String,
     /// PID of process tracing this process (0 if not being traced).
-    pub tracerpid: i32,
+    pub tracerpid: Pid,
     /// Number of file descriptor slots currently allocated.
     pub fdsize: u32,
+    /// The open fds of the process, with what each refers to, see the `fds` module. None unless
+    /// fd listing is enabled, or if they couldn't be read.
+    pub fds: Option<Vec<fds::FdInfo>>,
+    /// The TCP and UDP sockets of the process, with their addresses and state, see the
+    /// `connections` module. None unless connection listing is enabled, or if they couldn't be
+    /// read.
+    pub connections: Option<Vec<connections::Connection>>,
     /// Current state of the process.
     pub state: String,
     /// Peak virtual memory size by kB.
@@ -71,10 +184,25 @@ pub struct PidStatus {
     /// Current soft limit in bytes on the rss of the process; see the description of RLIMIT_RSS in
     /// getrlimit(2).
     pub rsslim_bytes: u64,
+    /// Resident pages backed by files, which may be shared with other processes, from
+    /// /proc/<pid>/statm.
+    pub shared_pages: u64,
+    /// Pages of the executable's text (code), excluding shared libraries, from /proc/<pid>/statm.
+    pub text_pages: u64,
+    /// Pages of data and stack, resident or not, from /proc/<pid>/statm.
+    pub data_pages: u64,
+    /// rss as a percentage of the tightest memory limit of the process: its RLIMIT_RSS or the
+    /// `memory.max` of its cgroup. None if neither is set.
+    pub rss_pct_of_limit: Option<f64>,
     /// CPU number last executed on.
     ///
     /// (since Linux 2.2.8)
     pub processor_last_executed: Option<i32>,
+    /// Scheduling policy of the process, None if it couldn't be read. See the `priority` module.
+    pub sched_policy: Option<priority::SchedPolicy>,
+    /// Real-time priority, from 1 to 99 under the real-time policies and 0 under the others. None
+    /// if it couldn't be read.
+    pub rt_priority: Option<u32>,
     // Amount of time that this process has been scheduled in user mode, measured in clock ticks
     /// (divide by [`ticks_per_second()`].
     ///
@@ -85,137 +213,1193 @@ pub struct PidStatus {
     /// Amount of time that this process has been scheduled in kernel mode, measured in clock ticks
     /// (divide by [`ticks_per_second()`]).
     pub stime: u64,
+    /// I/O counters from /proc/<pid>/io, None if it wasn't readable, eg: for the processes of
+    /// other users when not running as root. See the `proc_io` module.
+    pub io: Option<proc_io::ProcIo>,
+    /// Names of the services usually behind the ports the process listens on, eg: `postgresql`
+    /// for 5432, separated by commas. None unless service hints are enabled, see the `services`
+    /// module.
+    pub service_hint: Option<String>,
+    /// Runtime of the process, eg: `jvm` or `python`, see `runtime::Runtime`. None unless runtime
+    /// classification is enabled, and for kernel threads.
+    pub runtime: Option<String>,
+    /// Path of the cgroup of the process, eg: `/system.slice/nginx.service`, see
+    /// `cgroup::Membership::path`. None if it couldn't be read.
+    pub cgroup: Option<String>,
+    /// Memory and CPU limits of the cgroup of the process, see `cgroup::Limits`. None unless
+    /// cgroup limits are recorded.
+    pub cgroup_limits: Option<cgroup::Limits>,
     /// Holds the user CPU usage by that process.
     pub user_cpu_usage: f64,
     /// Holds the sys CPU usage by that process.    
     pub sys_cpu_usage: f64,
+    /// True if /proc only exposed part of this process' data, eg: processes of other users on a
+    /// /proc mounted with hidepid. Fields that could not be read are left at zero or empty, and
+    /// `name` is empty if not even the comm was readable.
+    pub restricted: bool,
+    /// True if the process exited while it was being read. Only the fields read before it exited
+    /// are set, the others are left at zero or empty.
+    pub vanished_during_scan: bool,
+    /// Values of optional collectors, by name, eg: `offcpu_ns` (see the `offcpu` module) or
+    /// `blkio_delay_ns` (see the `delayacct` module). Empty unless such a collector is enabled.
+    pub extensions: BTreeMap<String, u64>,
 }
 
-/// EncodDecode is the struct that we use to hold additional metadata and write to disk as
-/// serialized data of the form `let enc encoded: Vec<u8> = bincode::serialize(&encodecode).unwrap();`.
-#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+/// EncodDecode is the struct that we use to hold additional metadata and write to disk, behind
+/// the header described in the `store` module, as `store::SerializationFormat::encode` does.
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Clone)]
 pub struct EncoDecode {
     pub hostname: String,
     /// Vector of hashmap of pid to the pidstats.
-    pub pid_map_list: HashMap<i32, PidStatus>,
+    pub pid_map_list: HashMap<Pid, PidStatus>,
     /// The epoch time at which the stats were recorded
     pub time_epoch: u64,
-    /// Can be used for sampling
-    pub delay: u64,
+    /// The delay of the server between two snapshots. Can be used for sampling
+    pub delay: Duration,
     /// The cumilative CPU time in jiffies.
     pub total_cpu_time: u64,
+    /// The system wide CPU times from the first line of /proc/stat, including steal and guest
+    /// time. `total_cpu_time` is the sum of these.
+    pub cpu_times: cpu::CpuTimes,
+    /// The CPU times of each online CPU, by index, from the `cpuN` lines of /proc/stat.
+    pub per_cpu: BTreeMap<u32, cpu::CpuTimes>,
+    /// The memory of the host from /proc/meminfo, to put the memory of the processes in context.
+    pub system_memory: meminfo::MemInfo,
+    /// The load average of the host from /proc/loadavg, to put the CPU usage of the processes in
+    /// context.
+    pub load_average: load::LoadAvg,
+    /// Time since boot from /proc/uptime, to compute rates since boot. Zero if it couldn't be
+    /// read.
+    pub uptime: Duration,
+    /// The I/O counters of the block devices that were ever used, from /proc/diskstats, to tell
+    /// if a latency incident was bound by the disks.
+    pub disks: Vec<diskstats::DiskStats>,
+    /// The counters of the network interfaces from /proc/net/dev, to tell if the host was
+    /// waiting on the network.
+    pub interfaces: Vec<netdev::InterfaceStats>,
+    /// Labels of the host, such as its cloud instance type (see the `cloud` module). Servers
+    /// started from a `Config` always record the machine and boot ids (see the `identity` module).
+    pub labels: BTreeMap<String, String>,
+}
+
+impl EncoDecode {
+    /// Reads a snapshot file, in any format and compression. Fails with
+    /// `ProcshotError::VersionMismatch` if it was written by a newer version of procshot.
+    pub fn from_file<P: AsRef<std::path::Path>>(path: P) -> Result<Self, error::ProcshotError> {
+        let path = path.as_ref();
+        let data = store::read_file(path).map_err(|e| error::ProcshotError::reading(path, e))?;
+        store::decode(&data).map_err(|e| e.at(path))
+    }
+
+    /// CPU time of the host in clock ticks, without the guest time the kernel also counts in user
+    /// and nice. The CPU usages of the processes are relative to it. Snapshots of the releases
+    /// without `cpu_times` only have `total_cpu_time`.
+    pub fn elapsed_cpu_time(&self) -> u64 {
+        match self.cpu_times == cpu::CpuTimes::default() {
+            true => self.total_cpu_time,
+            false => self.cpu_times.elapsed(),
+        }
+    }
+}
+
+/// ScanOptions holds the optional behaviour of the server loop. `ScanOptions::default()` gives the
+/// behaviour of the plain `scan_proc`.
+#[cfg(feature = "server")]
+#[derive(Debug, Default)]
+pub struct ScanOptions {
+    /// Persist the per-process percentile sketches (see the `sketch` module) to
+    /// `datadir/sketches.bin` every `sketch_every` iterations. 0 disables the sketches.
+    pub sketch_every: u64,
+    /// Only scan the processes of this cgroup and its descendants instead of all of /proc. See
+    /// `cgroup::resolve` for the accepted forms.
+    pub cgroup: Option<std::path::PathBuf>,
+    /// Only record the processes selected by this filter, see the `filter` module. The files of
+    /// the other processes are not read.
+    pub filter: filter::ScanFilter,
+    /// Which of the kernel threads, zombies and processes without resident memory are left out,
+    /// see `collect::SkipPolicy`.
+    pub skip: collect::SkipPolicy,
+    /// Where the snapshots are stored, the datadir by default. See the `backend` module.
+    pub backend: Option<Box<dyn backend::StorageBackend>>,
+    /// Every snapshot is also handed to these sinks, see the `sink` module.
+    pub sinks: Vec<Box<dyn sink::StorageSink>>,
+    /// Run after every snapshot file is written, see the `hook` module.
+    pub hooks: Vec<Box<dyn hook::PostWriteHook>>,
+    /// Move old snapshots to the compressed cold tier, see the `tier` module.
+    pub tiering: Option<tier::TieringPolicy>,
+    /// Delete the oldest snapshot files of the datadir beyond these limits after every
+    /// iteration, see `retention::RetentionPolicy`.
+    pub retention: Option<retention::RetentionPolicy>,
+    /// Compression of the snapshot files written to the datadir, see `store::CompressionMode`.
+    /// Ignored with another `backend`.
+    pub compression: store::CompressionMode,
+    /// Lower the compression level while the host CPU is saturated, and raise it while it is
+    /// idle, see `store::CompressionLevel::adapt`.
+    pub adaptive_compression: bool,
+    /// Encoding of the snapshot files written to the datadir, see `store::SerializationFormat`.
+    /// Ignored with another `backend`.
+    pub serialization: store::SerializationFormat,
+    /// Cap on the server's own rss in bytes. Above it, optional collectors and caches are shed
+    /// until the usage goes back down, see the `guard` module.
+    pub memory_limit: Option<u64>,
+    /// Record small idle processes less often than the others, see the `sampling` module.
+    pub idle_sampling: Option<sampling::IdleSampling>,
+    /// Only warn, instead of refusing to run, when another server holds the datadir lock (see the
+    /// `lock` module).
+    pub shared_datadir: bool,
+    /// Rules evaluated on every snapshot, see the `alert` module. Alerts are logged to stderr.
+    pub alerts: Vec<alert::AlertRule>,
+    /// Commands run for every alert.
+    pub alert_commands: Vec<alert::AlertCommand>,
+    /// Notify systemd of the server's state through `NOTIFY_SOCKET`, and shut down cleanly on
+    /// SIGTERM, see the `systemd` module.
+    pub sd_notify: bool,
+    /// Log the exec and exit of every process to the events log of the datadir, see the
+    /// `proc_events` module.
+    pub exec_events: bool,
+    /// Log the changes of nice value, scheduling policy and I/O priority of the processes to the
+    /// events log of the datadir, see the `priority` module.
+    pub priority_events: bool,
+    /// Labels stored in every snapshot, see `EncoDecode::labels`.
+    pub labels: BTreeMap<String, String>,
+    /// Read the hostname again at this interval and record the snapshots under the new one when
+    /// it changed, annotating the change, see `identity::HostnameWatch`. None keeps the hostname
+    /// the server was started with.
+    pub hostname_refresh: Option<Duration>,
+    /// Restart the server when an iteration runs longer than this many times the delay, see the
+    /// `watchdog` module.
+    pub watchdog: Option<u32>,
+    /// Record the off-CPU time and run queue latency of the processes with eBPF, see the `offcpu`
+    /// module. Needs the ebpf feature.
+    pub offcpu: bool,
+    /// Record the CPU, block I/O and swap-in delays of the processes, see the `delayacct` module.
+    pub delayacct: bool,
+    /// Record the TCP retransmit and drop counters of the processes, see the `tcpstats` module.
+    pub tcp_stats: bool,
+    /// Record the memory of the processes on each NUMA node, see the `numa` module.
+    pub numa_maps: bool,
+    /// List the open fds of the processes in `PidStatus::fds`, see the `fds` module.
+    pub fds: bool,
+    /// List the sockets of the processes in `PidStatus::connections`, see the `connections`
+    /// module.
+    pub connections: bool,
+    /// Record the services behind the ports the processes listen on in `PidStatus::service_hint`,
+    /// see the `services` module. None disables it.
+    pub service_ports: Option<services::PortRegistry>,
+    /// Classify the runtime of every process in `PidStatus::runtime`, see the `runtime` module.
+    pub runtimes: bool,
+    /// Record the memory and CPU limits of the cgroup of every process in
+    /// `PidStatus::cgroup_limits`, see the `cgroup` module.
+    pub cgroup_limits: bool,
+    /// Rewrite the command lines of the processes as they are read, before they are stored or
+    /// logged, see the `redact` module.
+    pub redact: redact::RedactPolicy,
+    /// Log the OOM kills to the events log of the datadir with the last status of the victim,
+    /// see the `oom` module.
+    pub oom_events: bool,
+    /// CPUs, nice value and I/O priority the server runs with, applied when it starts, see the
+    /// `placement` module.
+    pub placement: placement::Placement,
+    /// Start the iterations on a fixed grid of ticks and measure their jitter, without the
+    /// enrichment collectors, see the `realtime` module.
+    pub realtime: Option<realtime::RealtimeMode>,
+}
+
+#[cfg(feature = "server")]
+impl ScanOptions {
+    /// Builds the options selected on the command line.
+    pub fn from_config(config: &Config) -> std::io::Result<Self> {
+        let mut options = ScanOptions {
+            sketch_every: config.sketch_every,
+            cgroup: config.cgroup.clone(),
+            filter: filter::ScanFilter::new(config.include.clone(), config.exclude.clone()),
+            skip: config.skip,
+            tiering: config
+                .cold_after
+                .map(|secs| tier::TieringPolicy::new(Duration::from_secs(secs))),
+            retention: Some(config.retention.clone()).filter(|r| !r.is_unlimited()),
+            compression: config.compression,
+            adaptive_compression: config.adaptive_compression,
+            serialization: config.serialization,
+            memory_limit: config.memory_limit,
+            idle_sampling: config.idle_sampling.clone(),
+            shared_datadir: config.shared_datadir,
+            alerts: config.alerts.clone(),
+            sd_notify: config.sd_notify,
+            exec_events: config.exec_events,
+            priority_events: config.priority_events,
+            watchdog: config.watchdog,
+            hostname_refresh: config.hostname_refresh,
+            offcpu: config.offcpu,
+            delayacct: config.delayacct,
+            tcp_stats: config.tcp_stats,
+            numa_maps: config.numa_maps,
+            fds: config.fds,
+            connections: config.connections,
+            runtimes: config.runtimes,
+            cgroup_limits: config.cgroup_limits,
+            redact: redact::RedactPolicy::new(config.redact.clone()),
+            oom_events: config.oom_events,
+            placement: config.placement.clone(),
+            realtime: config
+                .realtime
+                .map(|max_jitter| realtime::RealtimeMode { max_jitter }),
+            ..Default::default()
+        };
+        if config.service_hints || !config.service_ports.is_empty() {
+            let mut registry = services::PortRegistry::default();
+            for p in &config.service_ports {
+                registry.insert(p.port, &p.name);
+            }
+            options.service_ports = Some(registry);
+        }
+        if config.offcpu && !cfg!(feature = "ebpf") {
+            return Err(std::io::Error::other(
+                "--offcpu needs procshot built with the ebpf feature",
+            ));
+        }
+        options.labels.extend(identity::host_labels());
+        if config.cloud_metadata {
+            options.labels.extend(cloud_labels()?);
+        }
+        if let Some(brokers) = &config.kafka_brokers {
+            options.sinks.push(kafka_sink(brokers, config)?);
+        }
+        if let Some(url) = &config.redis_url {
+            // Expire the key if the host misses a few iterations.
+            let ttl = config
+                .redis_ttl
+                .map(Duration::from_secs)
+                .unwrap_or(3 * config.delay);
+            options.sinks.push(Box::new(sink::redis::RedisSink::new(
+                url,
+                "procshot:host:",
+                ttl,
+            )?));
+        }
+        if let Some(path) = &config.live_socket {
+            options.sinks.push(Box::new(sink::live::LiveSink::bind(path)?));
+        }
+        if let Some(path) = &config.append_log {
+            options.sinks.push(Box::new(sink::log::AppendLogSink::open(path)?));
+        }
+        if let Some(path) = &config.jsonl {
+            options.sinks.push(Box::new(sink::jsonl::JsonLinesSink::open(path)?));
+        }
+        if let Some(addr) = &config.stream_to {
+            options.sinks.push(Box::new(sink::stream::StreamSink::new(
+                addr,
+                config.stream_tls.clone(),
+            )?));
+        }
+        for command_line in &config.post_write_hooks {
+            options
+                .hooks
+                .push(Box::new(hook::CommandHook::with_limits(
+                    command_line,
+                    config.child_limits,
+                )?));
+        }
+        for command_line in &config.alert_commands {
+            options
+                .alert_commands
+                .push(alert::AlertCommand::new(command_line, config.child_limits)?);
+        }
+        Ok(options)
+    }
+
+    /// Turns off the collectors reading more than the status of the processes, for the real-time
+    /// mode. Returns the names of those that were on.
+    pub fn skip_enrichment(&mut self) -> Vec<&'static str> {
+        let mut skipped = Vec::new();
+        let mut skip = |on: &mut bool, name| {
+            if std::mem::take(on) {
+                skipped.push(name);
+            }
+        };
+        skip(&mut self.offcpu, "offcpu");
+        skip(&mut self.delayacct, "delayacct");
+        skip(&mut self.tcp_stats, "tcp-stats");
+        skip(&mut self.numa_maps, "numa-maps");
+        skip(&mut self.fds, "fds");
+        skip(&mut self.connections, "connections");
+        skip(&mut self.runtimes, "runtimes");
+        if self.service_ports.take().is_some() {
+            skipped.push("service-hints");
+        }
+        skipped
+    }
+}
+
+#[cfg(all(feature = "server", feature = "kafka"))]
+fn kafka_sink(brokers: &str, config: &Config) -> std::io::Result<Box<dyn sink::StorageSink>> {
+    let mode = match config.kafka_per_process {
+        true => sink::kafka::RecordMode::PerProcess,
+        false => sink::kafka::RecordMode::Snapshot,
+    };
+    Ok(Box::new(sink::kafka::KafkaSink::new(
+        brokers,
+        &config.kafka_topic,
+        mode,
+        &[],
+    )?))
 }
 
+#[cfg(all(feature = "server", not(feature = "kafka")))]
+fn kafka_sink(_brokers: &str, _config: &Config) -> std::io::Result<Box<dyn sink::StorageSink>> {
+    Err(std::io::Error::other(
+        "Kafka brokers given, but procshot_server was built without the `kafka` feature.",
+    ))
+}
+
+#[cfg(all(feature = "server", feature = "cloud"))]
+fn cloud_labels() -> std::io::Result<BTreeMap<String, String>> {
+    match cloud::detect(cloud::DEFAULT_TIMEOUT) {
+        Some(metadata) => Ok(metadata.labels()),
+        None => {
+            eprintln!("No cloud instance metadata found, snapshots are not labelled");
+            Ok(BTreeMap::new())
+        }
+    }
+}
+
+#[cfg(all(feature = "server", not(feature = "cloud")))]
+fn cloud_labels() -> std::io::Result<BTreeMap<String, String>> {
+    Err(std::io::Error::other(
+        "--cloud-metadata given, but procshot_server was built without the `cloud` feature.",
+    ))
+}
+
+/// Windows of sketches older than this many seconds are dropped when the store is persisted.
+#[cfg(feature = "server")]
+const SKETCH_RETENTION_SECS: u64 = 90 * 24 * 60 * 60;
+
 /// scan_proc continuously scans /proc and records all the processes.
-/// scan_proc omits the pids if status.vmpeak == None || prc.stat.rss == 0 || status.pid < 0.
-/// One file is created for each iteration and sleeps for `delay` seconds after each iteration.
+/// scan_proc omits kernel threads, zombies and the pids if status.vmpeak.is_none() || prc.stat.rss == 0,
+/// unless `ScanOptions::skip` records them. Processes whose status can't be read because of
+/// permissions are recorded with whatever is readable, and flagged as `restricted`.
+/// One file is created for each iteration and sleeps for `delay` after each iteration. With a delay
+/// below one second, the files are named `<epoch>.<milliseconds>.procshot` (see
+/// `store::snapshot_file_name`) so that snapshots taken within the same second don't overwrite
+/// each other.
+/// The datadir is created if missing, see `paths::create_datadir`.
 /// The example in the description can be used as a reference to read the stored struct.
-pub fn scan_proc(delay: u64, host: String, datadir: &'static str) {
-    print!("Starting procshot server with delay set as {}", delay);
+#[cfg(feature = "server")]
+pub fn scan_proc<P: AsRef<std::path::Path>>(delay: Duration, host: String, datadir: P) {
+    scan_proc_with_options(delay, host, datadir, ScanOptions::default())
+}
+
+/// Takes a single snapshot of the processes of this host and returns it without writing anything,
+/// for programs embedding procshot that control the timing themselves. The CPU usage is computed
+/// since `previous`, a snapshot returned by an earlier call, and is 0 without one. Unlike the
+/// server loop, which logs what it can't read and carries on, it fails if /proc or /proc/stat
+/// can't be read. Processes are read as by `scan_proc`, without the optional collectors of
+/// `ScanOptions`, and the labels are left empty.
+#[cfg(feature = "server")]
+pub fn snapshot(
+    host: &str,
+    previous: Option<&EncoDecode>,
+) -> Result<EncoDecode, error::ProcshotError> {
+    snapshot_in(std::path::Path::new(collect::PROC_ROOT), host, previous)
+}
+
+/// Same as `snapshot`, for the proc filesystem at `proc_root`, eg: a directory laid out like
+/// /proc by a test.
+#[cfg(feature = "server")]
+pub fn snapshot_in(
+    proc_root: &std::path::Path,
+    host: &str,
+    previous: Option<&EncoDecode>,
+) -> Result<EncoDecode, error::ProcshotError> {
+    let time_epoch = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)?
+        .as_secs();
+    let (cpu_times, per_cpu) = read_proc_stat(proc_root)?;
+    let total_cpu_time = cpu_times.total();
+    let system_memory = meminfo::MemInfo::read_in(proc_root).unwrap_or_default();
+    let load_average = load::LoadAvg::read_in(proc_root).unwrap_or_default();
+    let uptime = load::read_uptime(proc_root).unwrap_or_default();
+    let disks = diskstats::read_in(proc_root).unwrap_or_default();
+    let interfaces = netdev::read_in(proc_root).unwrap_or_default();
+    let pids =
+        collect::list_pids(proc_root).map_err(|e| error::ProcshotError::reading(proc_root, e))?;
+    let previous_stats = previous.map(|p| p.pid_map_list.clone());
+    let previous_cpu_time = previous.map_or(0, EncoDecode::elapsed_cpu_time);
+    let mut limits = cgroup::LimitsCache::default();
+    let mut pid_map_list = HashMap::new();
+    for pid in pids {
+        let skip = collect::SkipPolicy::default();
+        let mut s = match collect::read_pid(proc_root, pid, true, skip) {
+            Some(collect::Process {
+                status,
+                stat: Some(_),
+            }) => status,
+            Some(collect::Process { status, stat: None }) => {
+                pid_map_list.insert(pid, status);
+                continue;
+            }
+            None => continue,
+        };
+        set_usage(
+            &mut s,
+            proc_root,
+            pid,
+            &previous_stats,
+            (previous_cpu_time, cpu_times.elapsed()),
+            &mut limits,
+            false,
+        );
+        pid_map_list.insert(pid, s);
+    }
+    Ok(EncoDecode {
+        hostname: host.to_string(),
+        pid_map_list,
+        time_epoch,
+        delay: previous.map_or(Duration::from_secs(0), |p| {
+            Duration::from_secs(time_epoch.saturating_sub(p.time_epoch))
+        }),
+        total_cpu_time,
+        cpu_times,
+        per_cpu,
+        system_memory,
+        load_average,
+        uptime,
+        disks,
+        interfaces,
+        labels: BTreeMap::new(),
+    })
+}
+
+/// Sets the CPU usage of the process `pid` of `proc_root` since `previous`, given the elapsed CPU
+/// time of the host then and now (see `EncoDecode::elapsed_cpu_time`), its cgroup, and its rss
+/// as a percentage of its limits. The limits of its cgroup are recorded if `cgroup_limits`.
+#[cfg(feature = "server")]
+fn set_usage(
+    s: &mut PidStatus,
+    proc_root: &std::path::Path,
+    pid: Pid,
+    previous: &Option<HashMap<Pid, PidStatus>>,
+    (previous_cpu_time, total_cpu_time): (u64, u64),
+    limits: &mut cgroup::LimitsCache,
+    cgroup_limits: bool,
+) {
+    let membership = cgroup::Membership::read_in(proc_root, pid).ok();
+    let cgroup = membership.as_ref().map(|m| limits.of(m));
+    if !s.restricted {
+        let memory_max = cgroup.and_then(|l| l.memory_max);
+        s.rss_pct_of_limit = rss_pct_of_limit(s.rss_bytes, s.rsslim_bytes, memory_max);
+    }
+    s.cgroup = membership.as_ref().and_then(|m| m.path()).map(str::to_string);
+    s.cgroup_limits = cgroup.filter(|_| cgroup_limits);
+    s.user_cpu_usage = get_cpu_usage(
+        "user".to_string(),
+        pid,
+        previous,
+        s.utime,
+        total_cpu_time,
+        previous_cpu_time,
+    );
+    s.sys_cpu_usage = get_cpu_usage(
+        "system".to_string(),
+        pid,
+        previous,
+        s.stime,
+        total_cpu_time,
+        previous_cpu_time,
+    );
+}
+
+/// Same as `scan_proc`, with the optional behaviour configured by `options`.
+#[cfg(feature = "server")]
+pub fn scan_proc_with_options<P: AsRef<std::path::Path>>(
+    delay: Duration,
+    host: String,
+    datadir: P,
+    options: ScanOptions,
+) {
+    if try_scan_proc_with_options(delay, host, datadir, options).is_err() {
+        std::process::exit(1);
+    }
+}
+
+/// Same as `scan_proc_with_options`, returning instead of exiting the process when the server
+/// can't start, eg: the datadir is locked by another server, or when another server takes the
+/// datadir lock over. The error is logged before it is returned. Returns Ok once stopped by
+/// `systemd::request_stop` or a shutdown signal.
+#[cfg(feature = "server")]
+pub fn try_scan_proc_with_options<P: AsRef<std::path::Path>>(
+    delay: Duration,
+    mut host: String,
+    datadir: P,
+    mut options: ScanOptions,
+) -> std::io::Result<()> {
+    print!("Starting procshot server with delay set as {:?}", delay);
+    let datadir_path = datadir.as_ref();
+    let proc_root = std::path::Path::new(collect::PROC_ROOT);
+    if let Err(e) = options.placement.apply(proc_root) {
+        eprintln!("Refusing to start: cannot set the CPUs and priority of the server: {}", e);
+        return Err(e);
+    }
+    if options.realtime.is_some() {
+        let skipped = options.skip_enrichment();
+        if !skipped.is_empty() {
+            eprintln!("Real-time mode, not recording: {}", skipped.join(", "));
+        }
+        if let Err(e) = realtime::lock_memory() {
+            eprintln!(
+                "Cannot lock the memory of the server (CAP_IPC_LOCK is needed), page faults may delay iterations, err: {}",
+                e
+            );
+        }
+    }
 
-    let mut previous_stats: Option<HashMap<i32, PidStatus>> = None;
+    // A lock missing three heartbeats in a row, with some slack for slow iterations, is stale.
+    let stale_after = 3 * delay.as_secs() + 60;
+    let sub_second = delay < Duration::from_secs(1);
+    if let Err(e) = paths::create_datadir(datadir_path) {
+        eprintln!("Refusing to start: {}", e);
+        return Err(e);
+    }
+    let datadir_lock = lock::DatadirLock::acquire(datadir_path, &host, stale_after);
+    let mut lock = match datadir_lock {
+        Ok(l) => Some(l),
+        Err(e) if options.shared_datadir => {
+            eprintln!(
+                "Writing to a shared datadir, snapshots of both servers will interleave: {}",
+                e
+            );
+            None
+        }
+        Err(e) => {
+            eprintln!("Refusing to start: {}", e);
+            return Err(e);
+        }
+    };
+
+    // Finish or discard a prune that was interrupted by a previous crash, in both tiers.
+    for dir in &[
+        datadir_path.to_path_buf(),
+        datadir_path.join(store::COLD_DIR),
+    ] {
+        match retention::recover(dir) {
+            Ok(retention::Recovery::Clean) => (),
+            Ok(r) => println!("Recovered interrupted prune: {:?}", r),
+            Err(e) => eprintln!("Cannot recover interrupted prune, err: {}", e),
+        }
+    }
+    let mut backend = options
+        .backend
+        .take()
+        .unwrap_or_else(|| {
+            Box::new(
+                backend::DirBackend::new(datadir_path, sub_second)
+                    .with_compression(options.compression)
+                    .with_serialization(options.serialization),
+            )
+        });
+
+    let sketch_path = datadir_path.join(sketch::SKETCH_FILE);
+    let mut sketches = if options.sketch_every > 0 {
+        // Keep accumulating into the sketches of a previous run, if any.
+        Some(resume_sketches(&sketch_path))
+    } else {
+        None
+    };
+    let notifier = match systemd::Notifier::from_env().filter(|_| options.sd_notify) {
+        Some(Ok(n)) => Some(n),
+        Some(Err(e)) => {
+            eprintln!("Cannot open the systemd notification socket, err: {}", e);
+            None
+        }
+        None => None,
+    };
+    if notifier.is_some() {
+        if let Err(e) = systemd::install_shutdown_handler() {
+            eprintln!("Cannot install the shutdown handler, err: {}", e);
+        }
+        if let Some(interval) = systemd::watchdog_interval().filter(|i| *i <= delay) {
+            eprintln!(
+                "The systemd watchdog interval ({:?}) is shorter than the delay, the server will be restarted",
+                interval
+            );
+        }
+    }
+    let mut ready = false;
+    if options.exec_events {
+        if let Err(e) = proc_events::spawn(datadir_path.to_path_buf(), options.redact.clone()) {
+            eprintln!("Cannot record process events (CAP_NET_ADMIN is needed), err: {}", e);
+        }
+    }
+    let mut priority_log = match options.priority_events {
+        true => match events::EventLog::open(datadir_path) {
+            Ok(log) => Some((log, priority::PriorityTracker::new())),
+            Err(e) => {
+                eprintln!("Cannot open the events log for priority changes, err: {}", e);
+                None
+            }
+        },
+        false => None,
+    };
+    let mut oom_log = match options.oom_events {
+        true => match (
+            oom::OomWatcher::open(),
+            events::EventLog::open(datadir_path),
+        ) {
+            (Ok(watcher), Ok(log)) => Some((watcher, log)),
+            (Err(e), _) => {
+                eprintln!(
+                    "Cannot read OOM kills from the kernel log (CAP_SYSLOG is needed), err: {}",
+                    e
+                );
+                None
+            }
+            (_, Err(e)) => {
+                eprintln!("Cannot open the events log for OOM kills, err: {}", e);
+                None
+            }
+        },
+        false => None,
+    };
+    let progress = std::sync::Arc::new(watchdog::Progress::new());
+    if let Some(multiple) = options.watchdog {
+        let timeout = watchdog::timeout(delay, multiple);
+        if let Err(e) = watchdog::Watchdog::new(progress.clone(), timeout).spawn() {
+            eprintln!("Cannot start the watchdog, err: {}", e);
+        }
+    }
+    #[cfg(feature = "ebpf")]
+    let mut offcpu = match options.offcpu {
+        true => match offcpu::OffCpuSampler::open() {
+            Ok(s) => Some(s),
+            Err(e) => {
+                eprintln!("Cannot record off-CPU time, err: {}", e);
+                None
+            }
+        },
+        false => None,
+    };
+    let taskstats = match options.delayacct {
+        true => match delayacct::Taskstats::connect() {
+            Ok(t) => {
+                if !delayacct::enabled() {
+                    eprintln!("Delay accounting is disabled, set the kernel.task_delayacct sysctl to 1 to record block I/O and swap-in delays");
+                }
+                Some(t)
+            }
+            Err(e) => {
+                eprintln!("Cannot record delays, err: {}", e);
+                None
+            }
+        },
+        false => None,
+    };
+    let sock_diag = match options.tcp_stats {
+        true => match tcpstats::SockDiag::connect() {
+            Ok(d) => Some(d),
+            Err(e) => {
+                eprintln!("Cannot record TCP counters, err: {}", e);
+                None
+            }
+        },
+        false => None,
+    };
+    if options.numa_maps && !numa::available(proc_root) {
+        eprintln!("Cannot record NUMA placement, the kernel has no numa_maps");
+        options.numa_maps = false;
+    }
+    let mut guard = options.memory_limit.map(guard::MemoryGuard::new);
+    let mut hostname_watch = options.hostname_refresh.map(|interval| {
+        identity::HostnameWatch::new(interval, || {
+            hostname::get_hostname().map(|h| h.to_string())
+        })
+    });
+    let mut alerts = alert::AlertEngine::new(options.alerts.clone());
+    let mut iteration: u64 = 0;
+    let mut scan_count: u64 = 0;
+    let mut previous_stats: Option<HashMap<Pid, PidStatus>> = None;
+    let mut previous_epoch: u64 = 0;
     let mut previous_cpu_time: u64 = 0;
+    let mut previous_cpu_times: Option<cpu::CpuTimes> = None;
+    let mut compression_level = store::CompressionLevel::Default;
+    let mut schedule = options.realtime.map(|mode| {
+        (
+            realtime::Ticker::new(delay, std::time::Instant::now()),
+            realtime::JitterStats::new(mode.max_jitter),
+        )
+    });
     // Starts the continuous iteration over /proc
     loop {
-        let mut pid_map_hash: HashMap<i32, PidStatus> = HashMap::new(); //Vec::new();
-        let time_epoch = std::time::SystemTime::now()
+        progress.start();
+        if let Some((ticker, stats)) = schedule.as_mut() {
+            let jitter = ticker.jitter(std::time::Instant::now());
+            if stats.record(jitter) {
+                eprintln!(
+                    "Iteration started {:?} after its tick, above the bound of {:?}",
+                    jitter,
+                    stats.max_jitter()
+                );
+            }
+            if let Some(report) = stats.report() {
+                println!("{}", report);
+            }
+        }
+        // Sized for as many processes as the last iteration, so it isn't grown while reading.
+        let mut pid_map_hash: HashMap<Pid, PidStatus> =
+            HashMap::with_capacity(previous_stats.as_ref().map_or(0, HashMap::len));
+        let now = std::time::SystemTime::now()
             .duration_since(std::time::SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        let total_cpu_time = match read_proc_stat() {
+            .unwrap();
+        let time_epoch = now.as_secs();
+        if let Some(renamed) = hostname_watch
+            .as_mut()
+            .and_then(|w| w.poll(&host, time_epoch))
+        {
+            println!("Hostname changed from {} to {}", host, renamed);
+            let annotation = annotations::Annotation {
+                time_epoch,
+                text: format!("hostname changed from {} to {}", host, renamed),
+            };
+            if let Err(e) = annotations::add(datadir_path, &annotation) {
+                eprintln!("Cannot annotate the hostname change!, err: {}", e);
+            }
+            host = renamed;
+        }
+        // Before reading /proc, so the victims are looked up in the snapshot preceding the kill.
+        if let Some((watcher, log)) = oom_log.as_mut() {
+            match watcher.take() {
+                Ok(kills) => {
+                    let last = previous_stats.as_ref().map(|p| (previous_epoch, p));
+                    for kill in kills {
+                        if let Err(e) = log.append(&kill.to_event(last)) {
+                            eprintln!("Cannot write to the events log!, err: {}", e);
+                        }
+                    }
+                }
+                Err(e) => eprintln!("Cannot read OOM kills, err: {}", e),
+            }
+        }
+        let shedding = match guard.as_mut() {
+            Some(g) => g.check() != guard::Pressure::Normal,
+            None => false,
+        };
+        if shedding && sketches.is_some() {
+            // Keep what was accumulated so far, it is reloaded once the pressure is gone.
+            if let Err(e) = sketches.take().unwrap().save(&sketch_path) {
+                eprintln!("Cannot persist sketches!, err: {}", e);
+            }
+            guard::release_free_memory();
+        } else if !shedding && sketches.is_none() && options.sketch_every > 0 {
+            sketches = Some(resume_sketches(&sketch_path));
+        }
+        let (cpu_times, per_cpu) = match read_proc_stat(proc_root) {
             Ok(t) => t,
             Err(e) => {
-                eprintln!("Cannot read from /proc/stat, error is:: {:?}", e);
+                eprintln!("Cannot read from /proc/stat, error is:: {}", e);
                 continue;
             }
         };
+        let total_cpu_time = cpu_times.total();
+        let system_memory = meminfo::MemInfo::read_in(proc_root).unwrap_or_else(|e| {
+            eprintln!("Cannot read from /proc/meminfo, error is:: {}", e);
+            meminfo::MemInfo::default()
+        });
+        let load_average = load::LoadAvg::read_in(proc_root).unwrap_or_else(|e| {
+            eprintln!("Cannot read from /proc/loadavg, error is:: {}", e);
+            load::LoadAvg::default()
+        });
+        let uptime = load::read_uptime(proc_root).unwrap_or_else(|e| {
+            eprintln!("Cannot read from /proc/uptime, error is:: {}", e);
+            Duration::from_secs(0)
+        });
+        let disks = diskstats::read_in(proc_root).unwrap_or_else(|e| {
+            eprintln!("Cannot read from /proc/diskstats, error is:: {}", e);
+            Vec::new()
+        });
+        let interfaces = netdev::read_in(proc_root).unwrap_or_else(|e| {
+            eprintln!("Cannot read from /proc/net/dev, error is:: {}", e);
+            Vec::new()
+        });
 
+        let pids = match &options.cgroup {
+            Some(cg) => cgroup::pids_in_subtree(&cgroup::resolve(cg)).unwrap_or_else(|e| {
+                eprintln!("Cannot read cgroup {}, error is:: {:?}", cg.display(), e);
+                Vec::new()
+            }),
+            None => collect::list_pids(proc_root).unwrap_or_else(|e| {
+                eprintln!("Cannot list /proc, error is:: {:?}", e);
+                Vec::new()
+            }),
+        };
+        let mut limits = cgroup::LimitsCache::default();
+        let mut priorities = HashMap::new();
         // Iterate over all processess
-        for prc in procfs::all_processes() {
-            let status = prc.status().unwrap_or_else(|_| dummy_pid_status());
-            if status.vmpeak == None || prc.stat.rss == 0 || status.pid < 0 {
+        for pid in pids {
+            if !options.filter.accepts(proc_root, pid) {
                 continue;
             }
-            let s = PidStatus {
-                ppid: status.ppid,
-                euid: status.euid,
-                cmd_long: prc
-                    .cmdline()
-                    .unwrap_or_else(|_| vec!["No cmd_long found".to_string()]),
-                name: status.name,
-                cmd_short: prc.stat.comm.clone(),
-                tracerpid: status.tracerpid,
-                fdsize: status.fdsize,
-                state: status.state,
-                vmpeak: status.vmpeak,
-                vmsize: status.vmsize,
-                rss_pages: prc.stat.rss,
-                rss_bytes: prc.stat.rss_bytes(),
-                rsslim_bytes: prc.stat.rsslim,
-                processor_last_executed: prc.stat.processor,
-                utime: prc.stat.utime,
-                stime: prc.stat.stime,
-                user_cpu_usage: get_cpu_usage(
-                    "user".to_string(),
-                    status.pid,
-                    &previous_stats,
-                    prc.stat.utime,
-                    total_cpu_time,
-                    previous_cpu_time,
-                ),
-                sys_cpu_usage: get_cpu_usage(
-                    "system".to_string(),
-                    status.pid,
-                    &previous_stats,
-                    prc.stat.stime,
-                    total_cpu_time,
-                    previous_cpu_time,
-                ),
+            progress.reading(Some(pid));
+            let (mut s, stat) = match collect::read_pid(proc_root, pid, !shedding, options.skip) {
+                Some(collect::Process {
+                    status,
+                    stat: Some(stat),
+                }) => (status, stat),
+                Some(collect::Process {
+                    mut status,
+                    stat: None,
+                }) => {
+                    options.redact.apply(&mut status);
+                    pid_map_hash.insert(pid, status);
+                    continue;
+                }
+                None => continue,
             };
-
-            // let mut pidmap: HashMap<i32, PidStatus> = HashMap::new();
-            pid_map_hash.insert(status.pid, s);
+            options.redact.apply(&mut s);
+            if priority_log.is_some() {
+                priorities.insert(
+                    pid,
+                    (
+                        stat.starttime,
+                        stat.comm.clone(),
+                        priority::Priority::of(&stat),
+                    ),
+                );
+            }
+            set_usage(
+                &mut s,
+                proc_root,
+                pid,
+                &previous_stats,
+                (previous_cpu_time, cpu_times.elapsed()),
+                &mut limits,
+                options.cgroup_limits,
+            );
+            pid_map_hash.insert(pid, s);
+        }
+        progress.reading(None);
+        #[cfg(feature = "ebpf")]
+        if let Some(sampler) = offcpu.as_mut() {
+            match sampler.take() {
+                Ok(by_pid) => {
+                    for (pid, o) in by_pid {
+                        if let Some(s) = pid_map_hash.get_mut(&pid) {
+                            s.extensions
+                                .insert(offcpu::EXT_OFFCPU_NS.to_string(), o.offcpu_ns);
+                            s.extensions
+                                .insert(offcpu::EXT_RUNQ_LATENCY_NS.to_string(), o.runq_latency_ns);
+                        }
+                    }
+                }
+                Err(e) => eprintln!("Cannot read off-CPU time, err: {}", e),
+            }
+        }
+        if let Some(taskstats) = taskstats.as_ref() {
+            for (pid, s) in pid_map_hash.iter_mut().filter(|(_, s)| !s.restricted) {
+                // The process may have exited since it was read.
+                if let Ok(d) = taskstats.delays(*pid) {
+                    s.extensions
+                        .insert(delayacct::EXT_CPU_DELAY_NS.to_string(), d.cpu_delay_ns);
+                    s.extensions
+                        .insert(delayacct::EXT_BLKIO_DELAY_NS.to_string(), d.blkio_delay_ns);
+                    s.extensions
+                        .insert(delayacct::EXT_SWAPIN_DELAY_NS.to_string(), d.swapin_delay_ns);
+                }
+            }
+        }
+        if let Some(sock_diag) = sock_diag.as_ref() {
+            match sock_diag.sockets() {
+                Ok(sockets) => {
+                    let pids = pid_map_hash
+                        .iter()
+                        .filter(|(_, s)| !s.restricted)
+                        .map(|(pid, _)| *pid)
+                        .collect::<Vec<Pid>>();
+                    for (pid, c) in tcpstats::by_pid(proc_root, pids, &sockets) {
+                        if let Some(s) = pid_map_hash.get_mut(&pid) {
+                            s.extensions
+                                .insert(tcpstats::EXT_TCP_SOCKETS.to_string(), c.sockets);
+                            s.extensions
+                                .insert(tcpstats::EXT_TCP_SEGS_OUT.to_string(), c.segs_out);
+                            s.extensions
+                                .insert(tcpstats::EXT_TCP_RETRANS_SEGS.to_string(), c.retrans_segs);
+                            s.extensions
+                                .insert(tcpstats::EXT_TCP_DROPS.to_string(), c.drops);
+                        }
+                    }
+                }
+                Err(e) => eprintln!("Cannot read TCP counters, err: {}", e),
+            }
+        }
+        if options.numa_maps {
+            for (pid, s) in pid_map_hash.iter_mut().filter(|(_, s)| !s.restricted) {
+                // The process may have exited since it was read.
+                if let Ok(kb_by_node) = numa::read_in(proc_root, *pid) {
+                    for (node, kb) in kb_by_node {
+                        s.extensions.insert(numa::extension_key(node), kb);
+                    }
+                }
+            }
+        }
+        if options.fds && !shedding {
+            for (pid, s) in pid_map_hash.iter_mut().filter(|(_, s)| !s.restricted) {
+                // The process may have exited since it was read.
+                s.fds = fds::read_in(proc_root, *pid).ok();
+            }
+        }
+        if options.connections && !shedding {
+            match connections::sockets(proc_root) {
+                Ok(sockets) => {
+                    for (pid, s) in pid_map_hash.iter_mut().filter(|(_, s)| !s.restricted) {
+                        s.connections = connections::of_pid(proc_root, *pid, &sockets).ok();
+                    }
+                }
+                Err(e) => eprintln!("Cannot read the sockets, err: {}", e),
+            }
+        }
+        if let Some(registry) = &options.service_ports {
+            match services::listening_ports(proc_root) {
+                Ok(listening) => {
+                    for (pid, s) in pid_map_hash.iter_mut().filter(|(_, s)| !s.restricted) {
+                        s.service_hint =
+                            registry.hint(services::ports_of(proc_root, *pid, &listening));
+                    }
+                }
+                Err(e) => eprintln!("Cannot read the listening sockets, err: {}", e),
+            }
+        }
+        if options.runtimes {
+            for (pid, s) in pid_map_hash.iter_mut() {
+                // The runtime of a process only changes on exec, which changes its command line.
+                let previous = previous_stats
+                    .as_ref()
+                    .and_then(|p| p.get(pid))
+                    .filter(|p| p.name == s.name && p.cmd_long == s.cmd_long);
+                s.runtime = match previous {
+                    Some(p) => p.runtime.clone(),
+                    None => runtime::detect(proc_root, *pid, &s.cmd_long).map(|r| r.to_string()),
+                };
+            }
         }
         previous_stats = Some(pid_map_hash.clone());
-        previous_cpu_time = total_cpu_time;
+        previous_epoch = time_epoch;
+        if let Some((log, tracker)) = priority_log.as_mut() {
+            for event in tracker.update(priorities, now.as_millis() as u64) {
+                if let Err(e) = log.append(&event) {
+                    eprintln!("Cannot write to the events log!, err: {}", e);
+                }
+            }
+        }
+        previous_cpu_time = cpu_times.elapsed();
+        for alert in alerts.evaluate(&host, time_epoch, &pid_map_hash) {
+            eprintln!("ALERT {}", alert);
+            for command in &options.alert_commands {
+                if let Err(e) = command.notify(&alert) {
+                    eprintln!("Alert command {} failed!, err: {}", command.name(), e);
+                }
+            }
+        }
+        // Idle processes are left out only after previous_stats is taken, so their CPU usage is
+        // still right in the iterations that record them.
+        if let Some(sampling) = &options.idle_sampling {
+            if !sampling.records_idle(scan_count) {
+                pid_map_hash.retain(|_, s| !sampling.is_idle(s));
+            }
+        }
+        scan_count += 1;
 
         let encodecode: EncoDecode = EncoDecode {
             hostname: host.clone(),
             pid_map_list: pid_map_hash,
-            delay: delay,
-            time_epoch: time_epoch,
-            total_cpu_time: total_cpu_time,
+            delay,
+            time_epoch,
+            total_cpu_time,
+            cpu_times,
+            per_cpu,
+            system_memory,
+            load_average,
+            uptime,
+            disks,
+            interfaces,
+            labels: options.labels.clone(),
+        };
+        if let Some(store) = sketches.as_mut() {
+            store.update(&encodecode);
+            iteration += 1;
+            if iteration.is_multiple_of(options.sketch_every) {
+                store.expire(time_epoch.saturating_sub(SKETCH_RETENTION_SECS));
+                if let Err(e) = store.save(&sketch_path) {
+                    eprintln!("Cannot persist sketches!, err: {}", e);
+                }
+            }
+        }
+        for sink in options.sinks.iter_mut() {
+            if let Err(e) = sink.write_snapshot(&encodecode) {
+                eprintln!("Cannot write to sink {}!, err: {}", sink.name(), e);
+            }
+        }
+        if options.adaptive_compression {
+            if let Some(busy) = previous_cpu_times
+                .as_ref()
+                .and_then(|p| encodecode.cpu_times.busy_percent(p))
+            {
+                let adapted = compression_level.adapt(busy);
+                if adapted != compression_level {
+                    compression_level = adapted;
+                    backend.set_compression_level(compression_level);
+                }
+            }
+        }
+        match backend.write_snapshot_at(&encodecode, now) {
+            Err(e) => eprintln!("Cannot write snapshot to {}!, err: {}", backend.name(), e),
+            Ok(path) => {
+                if !ready {
+                    if let Some(n) = &notifier {
+                        let status = format!("Recorded first snapshot at {}", time_epoch);
+                        if let Err(e) = n.ready(&status) {
+                            eprintln!("Cannot notify systemd!, err: {}", e);
+                        }
+                    }
+                    ready = true;
+                }
+                // Hooks are handed files, backends that don't write one per snapshot skip them.
+                if let Some(path) = path.filter(|_| !options.hooks.is_empty()) {
+                    let summary =
+                        summary::SnapshotSummary::new(&encodecode, previous_cpu_times.as_ref());
+                    for hook in options.hooks.iter_mut() {
+                        if let Err(e) = hook.after_write(&path, &summary) {
+                            eprintln!("Post-write hook {} failed!, err: {}", hook.name(), e);
+                        }
+                    }
+                }
+            }
+        }
+        previous_cpu_times = Some(cpu_times);
+        if let Some(l) = lock.as_mut() {
+            match l.heartbeat() {
+                Ok(None) => (),
+                Ok(Some(other)) if options.shared_datadir => eprintln!(
+                    "pid {} on {} took over the datadir lock, snapshots of both servers will interleave",
+                    other.pid, other.hostname
+                ),
+                Ok(Some(other)) => {
+                    let e = std::io::Error::new(
+                        std::io::ErrorKind::AddrInUse,
+                        format!(
+                            "pid {} on {} took over the datadir lock",
+                            other.pid, other.hostname
+                        ),
+                    );
+                    eprintln!("{}, stopping", e);
+                    return Err(e);
+                }
+                Err(e) => eprintln!("Cannot refresh the datadir lock, err: {}", e),
+            }
+        }
+        if let Some(policy) = &options.tiering {
+            if let Err(e) = tier::demote(datadir_path, time_epoch, policy) {
+                eprintln!("Cannot move snapshots to the cold tier!, err: {}", e);
+            }
+        }
+        if let Some(policy) = &options.retention {
+            if let Err(e) = retention::enforce(datadir_path, time_epoch, policy) {
+                eprintln!("Cannot delete old snapshots!, err: {}", e);
+            }
+        }
+        if let Some(n) = &notifier {
+            let status = format!(
+                "Recorded {} processes at {}",
+                encodecode.pid_map_list.len(),
+                time_epoch
+            );
+            if let Err(e) = n.watchdog(&status) {
+                eprintln!("Cannot notify systemd!, err: {}", e);
+            }
+        }
+        progress.finish();
+        let until = match schedule.as_mut() {
+            Some((ticker, stats)) => {
+                let (tick, skipped) = ticker.advance(std::time::Instant::now());
+                if skipped > 0 {
+                    eprintln!("Iteration ran past {} ticks, skipping them", skipped);
+                    stats.skip(skipped);
+                }
+                tick
+            }
+            None => std::time::Instant::now() + delay,
         };
-        let encoded: Vec<u8> = bincode::serialize(&encodecode).unwrap();
-        // println!("DECODED VALUES:: {:#?}", decoded);
-        //assert_eq!(pids, decoded);
-        let file = File::create(format! {"{}/{}.procshot", datadir, time_epoch});
-        match file {
-            Err(e) => eprintln!("Cannot create file!, err: {}", e),
-            Ok(mut f) => {
-                f.write_all(&encoded).unwrap();
+        if sleep_unless_shutdown(until) {
+            println!("Shutting down");
+            // Keep the iterations folded in since the last save.
+            if let Some(store) = &sketches {
+                if let Err(e) = store.save(&sketch_path) {
+                    eprintln!("Cannot persist sketches!, err: {}", e);
+                }
+            }
+            if let Some(n) = &notifier {
+                let _ = n.stopping();
             }
+            return Ok(());
         }
-        thread::sleep(Duration::from_secs(delay));
     }
 }
 
+/// Reads the sketches persisted at `path` by a previous run, see `sketch::SketchStore::resume`.
+#[cfg(feature = "server")]
+fn resume_sketches(path: &std::path::Path) -> sketch::SketchStore {
+    let (store, err) = sketch::SketchStore::resume(path);
+    if let Some(e) = err {
+        eprintln!("Cannot read sketches, starting over, err: {}", e);
+    }
+    store
+}
+
+/// Sleeps until `until`, or until a shutdown is requested (see
+/// `systemd::install_shutdown_handler`). Returns true in the latter case.
+#[cfg(feature = "server")]
+fn sleep_unless_shutdown(until: std::time::Instant) -> bool {
+    while !systemd::shutdown_requested() {
+        let left = until.saturating_duration_since(std::time::Instant::now());
+        if left.is_zero() {
+            return false;
+        }
+        thread::sleep(left.min(Duration::from_millis(100)));
+    }
+    true
+}
+
+/// rss_pct_of_limit returns rss as a percentage of the lowest of the RLIMIT_RSS of a process and
+/// the memory limit of its cgroup. An rsslim of 0 (unknown) or RLIM_INFINITY is not a limit.
+#[cfg(feature = "server")]
+fn rss_pct_of_limit(rss_bytes: i64, rsslim_bytes: u64, cgroup_max: Option<u64>) -> Option<f64> {
+    let rsslim = Some(rsslim_bytes).filter(|l| *l != 0 && *l != libc::RLIM_INFINITY);
+    let limit = match (rsslim, cgroup_max) {
+        (Some(a), Some(b)) => a.min(b),
+        (Some(l), None) | (None, Some(l)) => l,
+        (None, None) => return None,
+    };
+    Some(100.0 * rss_bytes as f64 / limit as f64)
+}
+
 /// get_cpu_usage calculates cpu usage for user/system.
 /// user_util = 100 * (utime_after - utime_before) / (time_total_after - time_total_before);
 /// sys_util = 100 * (stime_after - stime_before) / (time_total_after - time_total_before);
+/// Both are 0 when no clock tick elapsed between the two scans.
+#[cfg(feature = "server")]
 fn get_cpu_usage(
     type_of: String,
-    pid: i32,
-    previous: &Option<HashMap<i32, PidStatus>>,
+    pid: Pid,
+    previous: &Option<HashMap<Pid, PidStatus>>,
     current_type_time: u64,
     current_cpu_time: u64,
     previous_cpu_time: u64,
 ) -> f64 {
+    if current_cpu_time <= previous_cpu_time {
+        return 0.0;
+    }
     match type_of.as_ref() {
         "user" => match previous {
             Some(x) => match x.get(&pid) {
                 Some(p) => {
-                    100 as f64 * (current_type_time as f64 - p.utime as f64) / (current_cpu_time as f64 - previous_cpu_time as f64)
+                    100.0 * (current_type_time as f64 - p.utime as f64) / (current_cpu_time as f64 - previous_cpu_time as f64)
                 }
                 None => {
                     0.0
@@ -228,7 +1412,7 @@ fn get_cpu_usage(
         "system" => match previous {
             Some(x) => match x.get(&pid) {
                 Some(p) => {
-                    100 as f64 * (current_type_time as f64 - p.stime as f64)
+                    100.0 * (current_type_time as f64 - p.stime as f64)
                         / (current_cpu_time as f64 - previous_cpu_time as f64)
                 }
                 None => 0.0,
@@ -242,120 +1426,149 @@ fn get_cpu_usage(
     }
 }
 
-/// Reads and parses /proc/stat's first line for calculating cpu percentage
-fn read_proc_stat() -> Result<u64, std::io::Error> {
-    let f = match File::open("/proc/stat") {
-        Ok(somefile) => somefile,
-        Err(e) => return Err(e),
-    };
-
-    let mut reader_itr = BufReader::new(f).lines();
-    let first_line = match reader_itr.next() {
-        // next returns an Option<Result<>> type, and hence the nested some(ok())
-        Some(total_string) => match total_string {
-            Ok(s) => s,
-            Err(e) => return Err(e),
-        },
-        None => {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Cannot read the first line from /proc/stat.",
-            ))
-        }
-    };
-    let total_vector = first_line
-        .split("cpu") // Split at "cpu"
-        .collect::<Vec<&str>>()[1] // Skip 0th element
-        .split(" ") // Split at " "
-        .filter(|&x| x != "") // filter empty lines
-        .collect::<Vec<&str>>(); // collect
-    let mut total: u64 = 0;
-    for i in total_vector {
-        total += i.parse::<u64>().unwrap();
-    }
-    Ok(total)
+/// Reads and parses the CPU lines of /proc/stat for calculating cpu percentage
+#[cfg(feature = "server")]
+fn read_proc_stat(
+    proc_root: &std::path::Path,
+) -> Result<(cpu::CpuTimes, BTreeMap<u32, cpu::CpuTimes>), error::ProcshotError> {
+    let path = proc_root.join("stat");
+    let content =
+        std::fs::read_to_string(&path).map_err(|e| error::ProcshotError::reading(&path, e))?;
+    cpu::parse_stat(&content).ok_or_else(|| {
+        let first_line = content.lines().next().unwrap_or_default();
+        error::ProcshotError::procfs(&path, format!("unexpected first line {}", first_line))
+    })
 }
 
-///dummy_status is used to return a dummy procfs::Status struct
-fn dummy_pid_status() -> procfs::Status {
-    let ds = "Dummy because unwrap failed".to_string();
-    procfs::Status {
-        name: ds.clone(),
-        umask: Some(std::u32::MAX),
-        state: ds.clone(),
-        tgid: -1,
-        ngid: Some(-1),
-        pid: -1,
-        ppid: -1,
-        tracerpid: -1,
-        ruid: -1,
-        euid: -1,
-        suid: -1,
-        fuid: -1,
-        rgid: -1,
-        egid: -1,
-        sgid: -1,
-        fgid: -1,
-        fdsize: std::u32::MAX,
-        groups: vec![-1],
-        nstgid: Some(vec![-1]),
-        nspid: Some(vec![-1]),
-        nspgid: Some(vec![-1]),
-        nssid: Some(vec![-1]),
-        vmpeak: Some(std::u64::MAX),
-        vmsize: Some(std::u64::MAX),
-        vmlck: Some(std::u64::MAX),
-        vmpin: Some(std::u64::MAX),
-        vmhwm: Some(std::u64::MAX),
-        vmrss: Some(std::u64::MAX),
-        rssanon: Some(std::u64::MAX),
-        rssfile: Some(std::u64::MAX),
-        rssshmem: Some(std::u64::MAX),
-        vmdata: Some(std::u64::MAX),
-        vmstk: Some(std::u64::MAX),
-        vmexe: Some(std::u64::MAX),
-        vmlib: Some(std::u64::MAX),
-        vmpte: Some(std::u64::MAX),
-        vmswap: Some(std::u64::MAX),
-        hugetblpages: Some(std::u64::MAX),
-        threads: std::u64::MAX,
-        sigq: (std::u64::MAX, std::u64::MAX),
-        sigpnd: std::u64::MAX,
-        shdpnd: std::u64::MAX,
-        sigblk: std::u64::MAX,
-        sigign: std::u64::MAX,
-        sigcgt: std::u64::MAX,
-        capinh: std::u64::MAX,
-        capprm: std::u64::MAX,
-        capeff: std::u64::MAX,
-        capbnd: Some(std::u64::MAX),
-        capamb: Some(std::u64::MAX),
-        nonewprivs: Some(std::u64::MAX),
-        seccomp: Some(std::u32::MAX),
-        speculation_store_bypass: Some(ds.clone()),
-        cpus_allowed: Some(vec![std::u32::MAX]),
-        cpus_allowed_list: Some(vec![(std::u32::MAX, std::u32::MAX)]),
-        mems_allowed: Some(vec![std::u32::MAX]),
-        mems_allowed_list: Some(vec![(std::u32::MAX, std::u32::MAX)]),
-        voluntary_ctxt_switches: Some(std::u64::MAX),
-        nonvoluntary_ctxt_switches: Some(std::u64::MAX),
-    }
-}
+/// Datadir used when `--datadir` is not given.
+#[cfg(feature = "server")]
+pub const DEFAULT_DATADIR: &str = "/var/log/procshot/data";
 
 /// Config struct holds the user input when running the server. It is a bad design to hold the client's option as well in the same struct, but
 /// as of now, it is here.
+#[cfg(feature = "server")]
 #[derive(Debug)]
 pub struct Config {
     /// hostname of the server. This is derived by this crate from the [hostname](https://docs.rs/hostname/0.1.5/hostname/) crate.
     pub hostname: String,
-    /// Delay decides how many seconds to sleep after each iteration of scanning /proc
-    pub delay: u64,
+    /// Delay decides how long to sleep after each iteration of scanning /proc. Below one second,
+    /// the server samples at a sub-second interval, see `scan_proc`.
+    pub delay: Duration,
+    /// Where the server writes the snapshots, `DEFAULT_DATADIR` unless `--datadir` is given. It is
+    /// created if missing.
+    pub datadir: std::path::PathBuf,
     /// If true, runs as server. Defaults to false. Pass the subcommand `server` to set it to true.
     pub server: bool,
-    /// The time from which the client can fetch data to process.
-    pub client_time_from: String,
-    /// Sort the processed data by whatever the user wants.
+    /// The subcommand that was selected. `server` is kept in sync with `Command::Server`.
+    pub command: Command,
+    /// The snapshots the client prints, from the `-t` option parsed in the `tz` time zone.
+    pub client_time_from: client::TimeFrom,
+    /// How the client sorts the processes, see `client::SortBy`.
     pub client_sort_by: String,
+    /// Persist per-process percentile sketches every `sketch_every` iterations. 0 disables them.
+    pub sketch_every: u64,
+    /// Limit scanning to the processes of this cgroup subtree.
+    pub cgroup: Option<std::path::PathBuf>,
+    /// Selectors of the processes recorded, see `ScanOptions::filter`.
+    pub include: Vec<filter::Selector>,
+    /// Selectors of the processes not recorded, see `ScanOptions::filter`.
+    pub exclude: Vec<filter::Selector>,
+    /// Kinds of processes recorded even though they are skipped by default, see
+    /// `ScanOptions::skip`.
+    pub skip: collect::SkipPolicy,
+    /// How the client prints memory sizes.
+    pub byte_format: units::ByteFormat,
+    /// Comma separated Kafka brokers to publish snapshots to. Needs the `kafka` feature.
+    pub kafka_brokers: Option<String>,
+    /// Kafka topic the snapshots are published to.
+    pub kafka_topic: String,
+    /// Publish one Kafka message per process instead of one per snapshot.
+    pub kafka_per_process: bool,
+    /// Redis url (`redis://[:password@]host[:port][/db]`) to store the latest snapshot summary in.
+    pub redis_url: Option<String>,
+    /// TTL in seconds of the Redis key. Defaults to 3 times the delay.
+    pub redis_ttl: Option<u64>,
+    /// Number of threads the client uses to decode snapshot files, see `query::par_map`.
+    pub query_workers: usize,
+    /// Commands run after every snapshot file is written, see `hook::CommandHook`.
+    pub post_write_hooks: Vec<String>,
+    /// Snapshots older than this many seconds are compressed into `datadir/cold/`.
+    pub cold_after: Option<u64>,
+    /// Compression of the snapshot files, see `ScanOptions::compression`.
+    pub compression: store::CompressionMode,
+    /// Adapt the compression level to the CPU usage, see `ScanOptions::adaptive_compression`.
+    pub adaptive_compression: bool,
+    /// Encoding of the snapshot files, see `ScanOptions::serialization`.
+    pub serialization: store::SerializationFormat,
+    /// Limits of the snapshots kept, see `ScanOptions::retention`.
+    pub retention: retention::RetentionPolicy,
+    /// Time zone used to display times and to read the `-t` option. Snapshots are always stored
+    /// with UTC epochs.
+    pub tz: tz::TimeZone,
+    /// Unix socket the server streams live deltas on, see `sink::live`.
+    pub live_socket: Option<std::path::PathBuf>,
+    /// File every snapshot is also appended to, see `sink::log`.
+    pub append_log: Option<std::path::PathBuf>,
+    /// File every process of every snapshot is also written to as a JSON line, or stdout with
+    /// `-`, see `sink::jsonl`.
+    pub jsonl: Option<std::path::PathBuf>,
+    /// `<host>:<port>` of the collector every snapshot is also sent to, see `sink::stream`.
+    pub stream_to: Option<String>,
+    /// Certificates encrypting the stream to the collector, see the `tls` module.
+    pub stream_tls: Option<tls::TlsFiles>,
+    /// Cap on the server's own rss in bytes, see `ScanOptions::memory_limit`.
+    pub memory_limit: Option<u64>,
+    /// Record small idle processes less often, see `ScanOptions::idle_sampling`.
+    pub idle_sampling: Option<sampling::IdleSampling>,
+    /// Keep running when another server writes to the same datadir, see `ScanOptions::shared_datadir`.
+    pub shared_datadir: bool,
+    /// Alert rules, see `ScanOptions::alerts`.
+    pub alerts: Vec<alert::AlertRule>,
+    /// Commands run for every alert, see `alert::AlertCommand`.
+    pub alert_commands: Vec<String>,
+    /// Limits of the post-write hook and alert commands.
+    pub child_limits: child::ChildLimits,
+    /// Notify systemd of the server's state, see `ScanOptions::sd_notify`.
+    pub sd_notify: bool,
+    /// Log process exec and exit events, see `ScanOptions::exec_events`.
+    pub exec_events: bool,
+    /// Log priority changes, see `ScanOptions::priority_events`.
+    pub priority_events: bool,
+    /// Label the snapshots with the cloud instance metadata, see the `cloud` module.
+    pub cloud_metadata: bool,
+    /// Follow hostname changes, see `ScanOptions::hostname_refresh`.
+    pub hostname_refresh: Option<Duration>,
+    /// Restart wedged iterations, see `ScanOptions::watchdog`.
+    pub watchdog: Option<u32>,
+    /// Record off-CPU time with eBPF, see `ScanOptions::offcpu`.
+    pub offcpu: bool,
+    /// Record delay accounting, see `ScanOptions::delayacct`.
+    pub delayacct: bool,
+    /// Record TCP counters, see `ScanOptions::tcp_stats`.
+    pub tcp_stats: bool,
+    /// Record NUMA placement, see `ScanOptions::numa_maps`.
+    pub numa_maps: bool,
+    /// List the open fds, see `ScanOptions::fds`.
+    pub fds: bool,
+    /// List the sockets, see `ScanOptions::connections`.
+    pub connections: bool,
+    /// Record service hints with the well-known ports, see `ScanOptions::service_ports`.
+    pub service_hints: bool,
+    /// Ports added to the well-known ones, see `ScanOptions::service_ports`. Enables service
+    /// hints.
+    pub service_ports: Vec<services::ServicePort>,
+    /// Classify the runtimes of the processes, see `ScanOptions::runtimes`.
+    pub runtimes: bool,
+    /// Record the limits of the cgroups, see `ScanOptions::cgroup_limits`.
+    pub cgroup_limits: bool,
+    /// Redaction rules, see `ScanOptions::redact`.
+    pub redact: Vec<redact::RedactRule>,
+    /// Log OOM kills, see `ScanOptions::oom_events`.
+    pub oom_events: bool,
+    /// CPUs and priority of the server, see `ScanOptions::placement`.
+    pub placement: placement::Placement,
+    /// Bound of the jitter in real-time mode, see `ScanOptions::realtime`.
+    pub realtime: Option<Duration>,
 }
 
 /// Returns a new config object. This also gives the following command line argument options.
@@ -370,19 +1583,98 @@ pub struct Config {
 ///
 /// USAGE:
 ///     procshot [FLAGS] [OPTIONS] [SUBCOMMAND]
-
+///
 /// FLAGS:
 ///     -h, --help       Prints help information
-///     -o               Sort result by Memory or CPU. Accepted values are...
-///     -t               Read stats from a specific time. Accepted format: 2015-09-05 23:56:04
 ///     -V, --version    Prints version information
 ///
 /// OPTIONS:
-///     -d, --delay <delay>      Sets delay in seconds before it scans /proc every time. [default: 60]
+///     -o <order_by>            Sort result by Memory or CPU. Accepted values are m and c. [default: m]
+///     -t <time_from>           Read stats from a specific time, in the --tz time zone, or from every snapshot of a <from>..<to> range. Accepted formats: 2015-09-05 23:56:04 [utc|local|+05:30], an epoch, now, 2h ago
+///     -d, --delay <delay>      Sets delay before it scans /proc every time, eg: 60, 5s, 500ms. A plain number is seconds. [default: 60]
+///         --datadir <datadir>      Directory the snapshots are written to, created if missing. [default: /var/log/procshot/data]
+///         --sketch-every <sketch_every>    Persists per-process CPU and rss percentile sketches every N iterations. [default: 0]
+///         --cgroup <cgroup>    Only scans the processes of this cgroup and its descendants, eg: /system.slice/nginx.service
+///         --include <selector>...            Only records the processes matching a selector: name=<regex>, uid=<uid>, pid=<pid> or cgroup=<path>.
+///         --exclude <selector>...            Doesn't record the processes matching a selector, in the format of --include.
+///         --record <kind>...                 Also records kernel-threads, zero-rss processes or zombies, left out by default.
+///         --units <units>      Unit system for memory sizes: binary (KiB, MiB), decimal (KB, MB) or raw bytes. [default: binary]
+///         --decimal-separator <decimal_separator>    Decimal separator used when printing scaled memory sizes. [default: .]
+///         --kafka-brokers <kafka_brokers>    Comma separated Kafka brokers to publish snapshots to. Needs the kafka feature.
+///         --kafka-topic <kafka_topic>        Kafka topic the snapshots are published to. [default: procshot]
+///         --kafka-per-process                Publishes one Kafka message per process instead of one per snapshot.
+///         --redis <redis_url>                Stores the latest snapshot summary in Redis, eg: redis://:password@localhost:6379/0
+///         --redis-ttl <redis_ttl>            TTL in seconds of the Redis key. Defaults to 3 times the delay.
+///         --query-workers <query_workers>    Number of threads used to read snapshot files. Defaults to the number of CPUs.
+///         --tz <tz>                          Time zone used to show times and read -t: utc, local or an offset like +05:30. [default: utc]
+///         --live-socket <live_socket>        Streams every snapshot as a delta to local clients connected to this Unix socket.
+///         --append-log <append_log>          Also appends every snapshot to this single log file.
+///         --jsonl <jsonl>                    Also writes every process of every snapshot as a JSON line to this file, or to stdout with -.
+///         --stream-to <stream_to>            Also sends every snapshot to the collector listening on this <host>:<port>.
+///         --tls-cert <tls_cert>              Certificate presented to the collector. Encrypts the stream, with --tls-key and --tls-ca (tls feature).
+///         --tls-key <tls_key>                Private key of --tls-cert.
+///         --tls-ca <tls_ca>                  CA the certificate of the collector must be signed by.
+///         --memory-limit <memory_limit>      Sheds optional collectors and caches while the server's own rss is above this size, eg: 256M.
+///         --idle-every <idle_every>          Records processes below --idle-rss-below and --idle-cpu-below only every N iterations.
+///         --idle-rss-below <idle_rss_below>  rss below which a process may be idle. [default: 16M]
+///         --idle-cpu-below <idle_cpu_below>  CPU percentage below which a process may be idle. [default: 0.5]
+///         --allow-shared-datadir             Only warns, instead of refusing to start, when another server writes to the datadir.
+///         --alert <alert>...                 Logs an alert when a process crosses a threshold, eg: 'rss_pct_of_limit > 90'.
+///         --alert-command <alert_command>... Runs a command for every alert, with the alert as JSON on stdin.
+///         --command-timeout <command_timeout>    Kills hook and alert commands running longer than this. [default: 30s]
+///         --command-concurrency <command_concurrency>    Maximum number of runs of each hook and alert command at once. [default: 4]
+///         --cold-after <cold_after>          Compresses snapshots older than this many seconds into the cold/ subdirectory of the datadir.
+///         --compression <compression>        Compression of the snapshot files: none, gzip or zstd (zstd feature). [default: none]
+///         --adaptive-compression             Lowers the compression level while the host CPU is saturated and raises it while idle.
+///         --serialization <serialization>    Encoding of the snapshot files: bincode, json, msgpack (msgpack feature) or cbor (cbor feature). [default: bincode]
+///         --max-files <max_files>            Deletes the oldest snapshots beyond this number.
+///         --max-age <max_age>                Deletes the snapshots older than this, eg: 30d.
+///         --max-total-size <max_total_size>  Deletes the oldest snapshots while the datadir holds more than this, eg: 10G.
+///         --post-write-hook <post_write_hook>...    Runs a command after each snapshot is written, with the file path as last argument and a JSON summary on stdin.
+///         --exec-events                      Logs the exec and exit of every process between snapshots to events.jsonl in the datadir.
+///         --priority-events                  Logs the renice, ionice and scheduling policy changes of processes to events.jsonl in the datadir.
+///         --oom-events                       Logs the OOM kills from the kernel log to events.jsonl in the datadir, with the victim's last status.
+///         --cloud-metadata                   Labels the snapshots with the instance id, type and zone from the EC2, GCE or Azure metadata service.
+///         --hostname-refresh <hostname_refresh>    Reads the hostname again at this interval and records the snapshots under the new one, eg: 5m.
+///         --watchdog <watchdog>              Restarts the server when an iteration runs longer than this many times the delay (at least 30s).
+///         --offcpu                           Records the time processes spend blocked and waiting for a CPU, with eBPF.
+///         --delayacct                        Records the time processes wait for a CPU, block I/O and swap-ins, from taskstats.
+///         --tcp-stats                        Records the TCP segments sent, retransmitted and dropped by the sockets of each process.
+///         --numa-maps                        Records the memory of each process on every NUMA node, from numa_maps.
+///         --fds                              Records the open fds of each process with their targets: files, sockets, pipes or anon inodes.
+///         --connections                      Records the TCP and UDP connections of each process with their addresses and state.
+///         --service-hints                    Records the services behind the well-known ports each process listens on.
+///         --service-port <port=name>...      Adds a port to the well-known ones, eg: 8080=billing. Implies --service-hints.
+///         --runtimes                         Records the runtime of each process: jvm, python, node, go or native.
+///         --cgroup-limits                    Records the memory and CPU limits of the cgroup of each process.
+///         --redact <regex[=>replacement]>... Rewrites the matches of the regex in the command lines before they are stored.
+///         --sd-notify                        Notifies systemd through NOTIFY_SOCKET when ready, after every iteration and when stopping.
+///         --cpus <cpus>                      Pins the server's threads to these CPUs, eg: 0-1,8.
+///         --nice <nice>                      Nice value of the server, from -20 to 19.
+///         --ionice <ionice>                  I/O priority of the server: idle, best-effort[:<0-7>] or realtime[:<0-7>].
+///         --realtime <realtime>              Starts the iterations on a fixed grid, without the enrichment collectors, and logs those starting later than this, eg: 5ms.
 ///
 /// SUBCOMMANDS:
 ///     help      Prints this message or the help of the given subcommand(s)
 ///     server    Decides whether to run as server or client
+///     doctor    Checks kernel features, /proc mount options, datadir permissions and clock sanity
+///     upload    Uploads closed snapshot files to S3 compatible storage and deletes them locally
+///     mount     Mounts a read-only view of the archive
+///     report    Reports computed over the stored snapshots, eg: `report growth --window 24h` or
+///               `report compare --baseline <from>..<to> --current <from>..<to> --format md` or
+///               `report fleet --name envoy --metric rss --range <from>..<to> <datadir>...` or
+///               `report gaps --range <from>..<to> [<datadir>...]`
+///     serve-static    Serves a minimal web UI with tables and charts of the recent snapshots
+///     convert   Converts an archive to another storage format, eg: `convert --to sqlite <datadir> <db>`
+///     tail      Prints one line per new snapshot: time, total CPU, total rss, process count and top process
+///     top       Lists the heaviest processes of the latest snapshot or of a range, eg: `top -t "2h ago..1h ago" -o c --top 10`
+///     annotate  Stores an annotation shown by the reports and the web UI, eg: `annotate --at <time> --text "deployed v2.3"`
+///     shell     Explores the archive interactively: load, filter, top, diff, plot and export
+///     bench-format    Re-encodes a sample of the snapshots in every format and compression, and reports size and encode/decode time
+///     collector Stores the snapshots sent by servers started with --stream-to, eg: `collector --listen 0.0.0.0:7070`
+///     validate  Checks every snapshot file of the datadir and prints a JSON report of the problems found
+///     merge     Merges two archives of the same host into a new datadir, eg: `merge <restored> <datadir> <out>`
+#[cfg(feature = "server")]
 impl Config {
     pub fn new() -> Self {
         let matches = App::new("procshot")
@@ -393,16 +1685,616 @@ impl Config {
                             .short("d")
                             .long("delay")
                             .default_value("60")
-                            .help("Sets delay in seconds before it scans /proc every time."))
+                            .validator(|s| units::parse_duration(&s).and_then(|d| {
+                                let tick = header::clock_tick();
+                                match d < tick {
+                                    true => Err(format!("The delay must be at least one clock tick, {:?} on this host.", tick)),
+                                    false => Ok(()),
+                                }
+                            }))
+                            .help("Sets delay before it scans /proc every time, eg: 60, 5s, 500ms. A plain number is seconds."))
+                        .arg(Arg::with_name("datadir")
+                            .long("datadir")
+                            .takes_value(true)
+                            .default_value(DEFAULT_DATADIR)
+                            .help("Directory the snapshots are written to. It is created, readable by its owner and group only, if missing."))
+                        .arg(Arg::with_name("sketch_every")
+                            .long("sketch-every")
+                            .takes_value(true)
+                            .default_value("0")
+                            .help("Persists per-process CPU and rss percentile sketches every N iterations. 0 disables them."))
+                        .arg(Arg::with_name("cgroup")
+                            .long("cgroup")
+                            .takes_value(true)
+                            .help("Only scans the processes of this cgroup and its descendants, eg: /system.slice/nginx.service"))
+                        .arg(Arg::with_name("include")
+                            .long("include")
+                            .takes_value(true)
+                            .multiple(true)
+                            .number_of_values(1)
+                            .validator(|s| s.parse::<filter::Selector>().map(|_| ()))
+                            .help("Only records the processes matching one of the selectors: name=<regex> on the name of the process, uid=<uid>, pid=<pid> or cgroup=<path> for a cgroup and its descendants. Can be repeated."))
+                        .arg(Arg::with_name("exclude")
+                            .long("exclude")
+                            .takes_value(true)
+                            .multiple(true)
+                            .number_of_values(1)
+                            .validator(|s| s.parse::<filter::Selector>().map(|_| ()))
+                            .help("Doesn't record the processes matching one of the selectors, in the format of --include. Can be repeated."))
+                        .arg(Arg::with_name("record")
+                            .long("record")
+                            .takes_value(true)
+                            .multiple(true)
+                            .number_of_values(1)
+                            .possible_values(&["kernel-threads", "zero-rss", "zombies"])
+                            .help("Also records a kind of processes left out by default: kernel-threads, zero-rss for the processes without resident memory, eg: idle daemons that were swapped out, or zombies. Can be repeated."))
+                        .arg(Arg::with_name("units")
+                            .long("units")
+                            .takes_value(true)
+                            .default_value("binary")
+                            .possible_values(&["binary", "decimal", "raw"])
+                            .help("Unit system for memory sizes: binary (KiB, MiB), decimal (KB, MB) or raw bytes."))
+                        .arg(Arg::with_name("decimal_separator")
+                            .long("decimal-separator")
+                            .takes_value(true)
+                            .default_value(".")
+                            .help("Decimal separator used when printing scaled memory sizes."))
+                        .arg(Arg::with_name("kafka_brokers")
+                            .long("kafka-brokers")
+                            .takes_value(true)
+                            .help("Comma separated Kafka brokers to publish snapshots to. Needs the kafka feature."))
+                        .arg(Arg::with_name("kafka_topic")
+                            .long("kafka-topic")
+                            .takes_value(true)
+                            .default_value("procshot")
+                            .help("Kafka topic the snapshots are published to."))
+                        .arg(Arg::with_name("kafka_per_process")
+                            .long("kafka-per-process")
+                            .help("Publishes one Kafka message per process instead of one per snapshot."))
+                        .arg(Arg::with_name("redis_url")
+                            .long("redis")
+                            .takes_value(true)
+                            .help("Stores the latest snapshot summary in Redis, eg: redis://:password@localhost:6379/0"))
+                        .arg(Arg::with_name("redis_ttl")
+                            .long("redis-ttl")
+                            .takes_value(true)
+                            .help("TTL in seconds of the Redis key. Defaults to 3 times the delay."))
+                        .arg(Arg::with_name("query_workers")
+                            .long("query-workers")
+                            .takes_value(true)
+                            .help("Number of threads used to read snapshot files. Defaults to the number of CPUs."))
+                        .arg(Arg::with_name("tz")
+                            .long("tz")
+                            .takes_value(true)
+                            .default_value("utc")
+                            .validator(|s| s.parse::<tz::TimeZone>().map(|_| ()))
+                            .help("Time zone used to show times and read -t: utc, local or an offset like +05:30."))
+                        .arg(Arg::with_name("live_socket")
+                            .long("live-socket")
+                            .takes_value(true)
+                            .help("Streams every snapshot as a delta to local clients connected to this Unix socket."))
+                        .arg(Arg::with_name("append_log")
+                            .long("append-log")
+                            .takes_value(true)
+                            .help("Also appends every snapshot to this single log file."))
+                        .arg(Arg::with_name("jsonl")
+                            .long("jsonl")
+                            .takes_value(true)
+                            .help("Also writes every process of every snapshot as a JSON line to this file, or to stdout with -."))
+                        .arg(Arg::with_name("stream_to")
+                            .long("stream-to")
+                            .takes_value(true)
+                            .help("Also sends every snapshot to the collector listening on this <host>:<port>, see the collector subcommand."))
+                        .arg(Arg::with_name("tls_cert")
+                            .long("tls-cert")
+                            .takes_value(true)
+                            .requires_all(&["tls_key", "tls_ca"])
+                            .help("Certificate presented to the collector, in PEM. Encrypts the stream, with --tls-key and --tls-ca (needs the tls feature)."))
+                        .arg(Arg::with_name("tls_key")
+                            .long("tls-key")
+                            .takes_value(true)
+                            .requires("tls_cert")
+                            .help("Private key of --tls-cert, in PEM."))
+                        .arg(Arg::with_name("tls_ca")
+                            .long("tls-ca")
+                            .takes_value(true)
+                            .requires("tls_cert")
+                            .help("CA the certificate of the collector must be signed by, in PEM."))
+                        .arg(Arg::with_name("memory_limit")
+                            .long("memory-limit")
+                            .takes_value(true)
+                            .validator(|s| units::parse_size(&s).map(|_| ()))
+                            .help("Sheds optional collectors and caches while the server's own rss is above this size, eg: 256M."))
+                        .arg(Arg::with_name("idle_every")
+                            .long("idle-every")
+                            .takes_value(true)
+                            .help("Records processes below --idle-rss-below and --idle-cpu-below only every N iterations."))
+                        .arg(Arg::with_name("idle_rss_below")
+                            .long("idle-rss-below")
+                            .takes_value(true)
+                            .default_value("16M")
+                            .validator(|s| units::parse_size(&s).map(|_| ()))
+                            .help("rss below which a process may be idle."))
+                        .arg(Arg::with_name("idle_cpu_below")
+                            .long("idle-cpu-below")
+                            .takes_value(true)
+                            .default_value("0.5")
+                            .help("CPU percentage below which a process may be idle."))
+                        .arg(Arg::with_name("shared_datadir")
+                            .long("allow-shared-datadir")
+                            .help("Only warns, instead of refusing to start, when another server writes to the datadir."))
+                        .arg(Arg::with_name("alert")
+                            .long("alert")
+                            .takes_value(true)
+                            .multiple(true)
+                            .number_of_values(1)
+                            .validator(|s| s.parse::<alert::AlertRule>().map(|_| ()))
+                            .help("Logs an alert when a process crosses a threshold, eg: 'rss_pct_of_limit > 90'. Can be repeated."))
+                        .arg(Arg::with_name("alert_command")
+                            .long("alert-command")
+                            .takes_value(true)
+                            .multiple(true)
+                            .number_of_values(1)
+                            .help("Runs a command for every alert, with the alert as JSON on stdin. Can be repeated."))
+                        .arg(Arg::with_name("command_timeout")
+                            .long("command-timeout")
+                            .takes_value(true)
+                            .default_value("30s")
+                            .validator(|s| units::parse_duration(&s).map(|_| ()))
+                            .help("Kills hook and alert commands running longer than this, with the processes they started."))
+                        .arg(Arg::with_name("command_concurrency")
+                            .long("command-concurrency")
+                            .takes_value(true)
+                            .default_value("4")
+                            .help("Maximum number of runs of each hook and alert command at once. Further runs are skipped."))
+                        .arg(Arg::with_name("cold_after")
+                            .long("cold-after")
+                            .takes_value(true)
+                            .help("Compresses snapshots older than this many seconds into the cold/ subdirectory of the datadir."))
+                        .arg(Arg::with_name("compression")
+                            .long("compression")
+                            .takes_value(true)
+                            .default_value("none")
+                            .validator(|s| s.parse::<store::CompressionMode>().map(|_| ()))
+                            .help("Compression of the snapshot files written to the datadir: none, gzip or zstd (needs the zstd feature). Files of any compression are read."))
+                        .arg(Arg::with_name("adaptive_compression")
+                            .long("adaptive-compression")
+                            .help("Compresses faster while the host CPU is saturated, and smaller while it is idle, from the CPU usage of /proc/stat."))
+                        .arg(Arg::with_name("serialization")
+                            .long("serialization")
+                            .takes_value(true)
+                            .default_value("bincode")
+                            .validator(|s| s.parse::<store::SerializationFormat>().map(|_| ()))
+                            .help("Encoding of the snapshot files written to the datadir: bincode, json, msgpack or cbor (need the msgpack and cbor features). Files of any encoding are read."))
+                        .arg(Arg::with_name("max_files")
+                            .long("max-files")
+                            .takes_value(true)
+                            .help("Deletes the oldest snapshots after every iteration while there are more than this many, in both tiers."))
+                        .arg(Arg::with_name("max_age")
+                            .long("max-age")
+                            .takes_value(true)
+                            .validator(|s| units::parse_duration(&s).map(|_| ()))
+                            .help("Deletes the snapshots older than this after every iteration, eg: 30d."))
+                        .arg(Arg::with_name("max_total_size")
+                            .long("max-total-size")
+                            .takes_value(true)
+                            .validator(|s| units::parse_size(&s).map(|_| ()))
+                            .help("Deletes the oldest snapshots after every iteration while their files add up to more than this, eg: 10G. The newest snapshot is always kept."))
+                        .arg(Arg::with_name("post_write_hook")
+                            .long("post-write-hook")
+                            .takes_value(true)
+                            .multiple(true)
+                            .number_of_values(1)
+                            .help("Runs a command after each snapshot is written, with the file path as last argument and a JSON summary on stdin. Can be repeated."))
+                        .arg(Arg::with_name("exec_events")
+                            .long("exec-events")
+                            .help("Logs the exec and exit of every process between snapshots to events.jsonl in the datadir. Needs CAP_NET_ADMIN."))
+                        .arg(Arg::with_name("priority_events")
+                            .long("priority-events")
+                            .help("Logs the renice, ionice and scheduling policy changes of processes to events.jsonl in the datadir, with the values before and after."))
+                        .arg(Arg::with_name("cloud_metadata")
+                            .long("cloud-metadata")
+                            .help("Labels the snapshots with the instance id, type and zone from the EC2, GCE or Azure metadata service. Needs the cloud feature."))
+                        .arg(Arg::with_name("hostname_refresh")
+                            .long("hostname-refresh")
+                            .takes_value(true)
+                            .validator(|s| units::parse_duration(&s).map(|_| ()))
+                            .help("Reads the hostname again at this interval, and at every iteration while it is localhost, and records the snapshots under the new one after a change, with an annotation. By default the hostname is read once at startup."))
+                        .arg(Arg::with_name("watchdog")
+                            .long("watchdog")
+                            .takes_value(true)
+                            .validator(|s| match s.parse::<u32>() {
+                                Ok(n) if n > 0 => Ok(()),
+                                _ => Err(format!("{} is not a positive number", s)),
+                            })
+                            .help("Restarts the server when an iteration runs longer than this many times the delay (at least 30s), eg: hung on a /proc read. The stuck pid is logged."))
+                        .arg(Arg::with_name("offcpu")
+                            .long("offcpu")
+                            .help("Records the time the processes spend blocked and waiting for a CPU between snapshots, with eBPF. Needs the ebpf feature and root."))
+                        .arg(Arg::with_name("delayacct")
+                            .long("delayacct")
+                            .help("Records the time the processes waited for a CPU, for block I/O and for swap-ins, from the kernel's delay accounting. Block I/O and swap-in delays need the kernel.task_delayacct sysctl."))
+                        .arg(Arg::with_name("oom_events")
+                            .long("oom-events")
+                            .help("Logs the OOM kills from the kernel log to events.jsonl in the datadir, with the status of the victim in the last snapshot before the kill. Needs CAP_SYSLOG if dmesg is restricted."))
+                        .arg(Arg::with_name("tcp_stats")
+                            .long("tcp-stats")
+                            .help("Records the TCP segments sent, retransmitted and dropped by the sockets of each process, from sock_diag. Needs root to see the sockets of other users' processes."))
+                        .arg(Arg::with_name("numa_maps")
+                            .long("numa-maps")
+                            .help("Records the kB of memory of each process on every NUMA node, from /proc/<pid>/numa_maps. Slow for processes with a large rss. Needs root to read other users' processes."))
+                        .arg(Arg::with_name("fds")
+                            .long("fds")
+                            .help("Records the open fds of each process with what they refer to: the path of a file, a socket, a pipe or an anon inode, from /proc/<pid>/fd. Makes the snapshots of processes with many fds larger. Needs root to read other users' processes."))
+                        .arg(Arg::with_name("connections")
+                            .long("connections")
+                            .help("Records the TCP and UDP sockets of each process with their local and remote address and state, from /proc/net. Makes the snapshots of busy servers larger. Needs root to read other users' processes."))
+                        .arg(Arg::with_name("service_hints")
+                            .long("service-hints")
+                            .help("Records the services usually behind the ports each process listens on, eg: postgresql for 5432, in its service_hint. Needs root to see the sockets of other users' processes."))
+                        .arg(Arg::with_name("service_port")
+                            .long("service-port")
+                            .takes_value(true)
+                            .multiple(true)
+                            .number_of_values(1)
+                            .validator(|s| s.parse::<services::ServicePort>().map(|_| ()))
+                            .help("Adds a <port>=<name> mapping to the well-known ports, eg: 8080=billing. Can be repeated. Implies --service-hints."))
+                        .arg(Arg::with_name("runtimes")
+                            .long("runtimes")
+                            .help("Records the runtime of each process: jvm, python, node, go or native, from its executable, command line and mapped libraries. Needs root to read the executables and maps of other users' processes."))
+                        .arg(Arg::with_name("cgroup_limits")
+                            .long("cgroup-limits")
+                            .help("Records the memory and CPU limits of the cgroup of each process, the lowest of the cgroup and its ancestors, from cgroup v2 or v1."))
+                        .arg(Arg::with_name("redact")
+                            .long("redact")
+                            .takes_value(true)
+                            .multiple(true)
+                            .number_of_values(1)
+                            .validator(|s| s.parse::<redact::RedactRule>().map(|_| ()))
+                            .help("Replaces the matches of a regex in each argument of the command lines before they are stored or logged, with <redacted> or the replacement given after =>, eg: '(--password=).*=>${1}***'. Can be repeated."))
+                        .arg(Arg::with_name("sd_notify")
+                            .long("sd-notify")
+                            .help("Notifies systemd through NOTIFY_SOCKET when ready, after every iteration and when stopping. For Type=notify units."))
+                        .arg(Arg::with_name("cpus")
+                            .long("cpus")
+                            .takes_value(true)
+                            .validator(|s| s.parse::<placement::CpuList>().map(|_| ()))
+                            .help("Pins every thread of the server, and the commands it runs, to these CPUs when it starts, eg: 0-1,8, to keep its overhead on housekeeping cores."))
+                        .arg(Arg::with_name("nice")
+                            .long("nice")
+                            .takes_value(true)
+                            .allow_hyphen_values(true)
+                            .validator(|s| match s.parse::<i32>() {
+                                Ok(n) if (-20..=19).contains(&n) => Ok(()),
+                                _ => Err(format!("{} is not a nice value from -20 to 19", s)),
+                            })
+                            .help("Sets the nice value of the server when it starts, from -20 to 19. Values below the current one need CAP_SYS_NICE."))
+                        .arg(Arg::with_name("ionice")
+                            .long("ionice")
+                            .takes_value(true)
+                            .validator(|s| s.parse::<priority::IoPriority>().map(|_| ()))
+                            .help("Sets the I/O priority of the server when it starts, as ionice names it: idle, best-effort[:<0-7>] or realtime[:<0-7>]. The realtime class needs CAP_SYS_ADMIN."))
+                        .arg(Arg::with_name("realtime")
+                            .long("realtime")
+                            .takes_value(true)
+                            .validator(|s| units::parse_duration(&s).map(|_| ()))
+                            .help("Soft real-time mode: starts the iterations on a fixed grid of ticks a delay apart, skipping the ticks an iteration runs past, without the enrichment collectors (--fds, --connections, --runtimes...), and with the memory of the server locked. Iterations starting later than this after their tick are logged, and the jitter is summarised every 60 iterations, eg: 5ms."))
                         .subcommand(SubCommand::with_name("server")
                             .about("Runs as server and records stats."))
+                        .subcommand(SubCommand::with_name("doctor")
+                            .about("Checks kernel features, /proc mount options, datadir permissions and clock sanity."))
+                        .subcommand(SubCommand::with_name("upload")
+                            .about("Uploads closed snapshot files to S3 compatible storage and deletes them locally. Credentials are read from AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY.")
+                            .arg(Arg::with_name("endpoint")
+                                .long("endpoint")
+                                .takes_value(true)
+                                .default_value("https://s3.amazonaws.com")
+                                .help("S3 endpoint url."))
+                            .arg(Arg::with_name("bucket")
+                                .long("bucket")
+                                .takes_value(true)
+                                .required(true)
+                                .help("Bucket to upload to."))
+                            .arg(Arg::with_name("region")
+                                .long("region")
+                                .takes_value(true)
+                                .default_value("us-east-1")
+                                .help("Region used to sign the requests."))
+                            .arg(Arg::with_name("prefix")
+                                .long("prefix")
+                                .takes_value(true)
+                                .default_value("")
+                                .help("Prefix of the object keys. Objects are stored as <prefix><hostname>/<file>."))
+                            .arg(Arg::with_name("concurrency")
+                                .long("concurrency")
+                                .takes_value(true)
+                                .default_value("4")
+                                .help("Number of files uploaded in parallel.")))
+                        .subcommand(SubCommand::with_name("mount")
+                            .about("Mounts a read-only view of the archive as /by-time/<epoch>/<pid>/status.json and /by-pid/<pid>/<epoch>.json.")
+                            .arg(Arg::with_name("mountpoint")
+                                .required(true)
+                                .help("Directory to mount the archive at.")))
+                        .subcommand(SubCommand::with_name("report")
+                            .about("Reports computed over the stored snapshots.")
+                            .subcommand(SubCommand::with_name("growth")
+                                .about("Lists the processes with the largest rss and fd growth over a window.")
+                                .arg(Arg::with_name("window")
+                                    .long("window")
+                                    .takes_value(true)
+                                    .default_value("24h")
+                                    .validator(|s| units::parse_duration(&s).map(|_| ()))
+                                    .help("How far back to look, eg: 90m, 24h, 7d."))
+                                .arg(Arg::with_name("top")
+                                    .long("top")
+                                    .takes_value(true)
+                                    .default_value("10")
+                                    .help("Number of processes listed per table."))
+                                .arg(Arg::with_name("format")
+                                    .long("format")
+                                    .takes_value(true)
+                                    .default_value("table")
+                                    .validator(|s| s.parse::<report::ReportFormat>().map(|_| ()))
+                                    .help("Output format: table, or md for Markdown tables to paste into issues.")))
+                            .subcommand(SubCommand::with_name("compare")
+                                .about("Compares the average CPU and rss of every process name between two periods, eg: before and after a deploy.")
+                                .arg(Arg::with_name("baseline")
+                                    .long("baseline")
+                                    .takes_value(true)
+                                    .required(true)
+                                    .validator(|s| report::TimeRange::parse(&s, &tz::TimeZone::Utc).map(|_| ()).map_err(|e| e.to_string()))
+                                    .help("Period to compare against, as <from>..<to> epochs or -t times, eg: '2019-07-20 10:00:00..2019-07-20 12:00:00'."))
+                                .arg(Arg::with_name("current")
+                                    .long("current")
+                                    .takes_value(true)
+                                    .required(true)
+                                    .validator(|s| report::TimeRange::parse(&s, &tz::TimeZone::Utc).map(|_| ()).map_err(|e| e.to_string()))
+                                    .help("Period compared to the baseline, in the same format."))
+                                .arg(Arg::with_name("min_change")
+                                    .long("min-change")
+                                    .takes_value(true)
+                                    .default_value("10")
+                                    .help("Changes smaller than this percentage of the baseline are not shown."))
+                                .arg(Arg::with_name("min_cpu")
+                                    .long("min-cpu")
+                                    .takes_value(true)
+                                    .default_value("1")
+                                    .help("CPU changes smaller than this many percentage points are not shown."))
+                                .arg(Arg::with_name("min_rss")
+                                    .long("min-rss")
+                                    .takes_value(true)
+                                    .default_value("16M")
+                                    .validator(|s| units::parse_size(&s).map(|_| ()))
+                                    .help("rss changes smaller than this size are not shown."))
+                                .arg(Arg::with_name("top")
+                                    .long("top")
+                                    .takes_value(true)
+                                    .default_value("20")
+                                    .help("Number of process names listed."))
+                                .arg(Arg::with_name("format")
+                                    .long("format")
+                                    .takes_value(true)
+                                    .default_value("table")
+                                    .validator(|s| s.parse::<report::ReportFormat>().map(|_| ()))
+                                    .help("Output format: table, or md for Markdown tables to paste into issues.")))
+                            .subcommand(SubCommand::with_name("fleet")
+                                .about("Exports percentiles across hosts of a metric of the processes with a name, per time bucket, eg: the p95 rss of envoy per hour.")
+                                .arg(Arg::with_name("name")
+                                    .long("name")
+                                    .takes_value(true)
+                                    .required(true)
+                                    .help("Name of the processes."))
+                                .arg(Arg::with_name("metric")
+                                    .long("metric")
+                                    .takes_value(true)
+                                    .default_value("rss")
+                                    .validator(|s| s.parse::<fleet::FleetMetric>().map(|_| ()))
+                                    .help("Metric of the processes: rss_bytes (rss), cpu_usage (cpu) or fdsize (fds). The processes of a host sharing the name are summed."))
+                                .arg(Arg::with_name("range")
+                                    .long("range")
+                                    .takes_value(true)
+                                    .required(true)
+                                    .validator(|s| report::TimeRange::parse(&s, &tz::TimeZone::Utc).map(|_| ()).map_err(|e| e.to_string()))
+                                    .help("Period to read, as <from>..<to> epochs or -t times."))
+                                .arg(Arg::with_name("bucket")
+                                    .long("bucket")
+                                    .takes_value(true)
+                                    .default_value("1h")
+                                    .validator(|s| units::parse_duration(&s).map(|_| ()))
+                                    .help("Length of the time buckets, eg: 15m, 1h, 1d."))
+                                .arg(Arg::with_name("percentiles")
+                                    .long("percentiles")
+                                    .takes_value(true)
+                                    .default_value("50,90,95,99")
+                                    .validator(|s| fleet::parse_percentiles(&s).map(|_| ()))
+                                    .help("Comma separated percentiles to compute."))
+                                .arg(Arg::with_name("format")
+                                    .long("format")
+                                    .takes_value(true)
+                                    .default_value("csv")
+                                    .validator(|s| s.parse::<fleet::ExportFormat>().map(|_| ()))
+                                    .help("Output format: csv, json or md."))
+                                .arg(Arg::with_name("datadirs")
+                                    .multiple(true)
+                                    .required(true)
+                                    .help("Datadirs of the hosts, eg: synced from the upload bucket.")))
+                            .subcommand(SubCommand::with_name("gaps")
+                                .about("Lists per host the intervals without snapshots longer than expected from the delay, eg: while the server or the host was down, or the disk full.")
+                                .arg(Arg::with_name("range")
+                                    .long("range")
+                                    .takes_value(true)
+                                    .default_value("7d ago..now")
+                                    .validator(|s| report::TimeRange::parse(&s, &tz::TimeZone::Utc).map(|_| ()).map_err(|e| e.to_string()))
+                                    .help("Period to check, as <from>..<to> epochs or -t times."))
+                                .arg(Arg::with_name("tolerance")
+                                    .long("tolerance")
+                                    .takes_value(true)
+                                    .default_value("2")
+                                    .validator(|s| match s.parse::<f64>() {
+                                        Ok(t) if t >= 1.0 => Ok(()),
+                                        _ => Err(format!("Invalid tolerance {}, expected a number of at least 1", s)),
+                                    })
+                                    .help("Snapshots further apart than this many times the delay leave a gap."))
+                                .arg(Arg::with_name("format")
+                                    .long("format")
+                                    .takes_value(true)
+                                    .default_value("table")
+                                    .validator(|s| s.parse::<report::ReportFormat>().map(|_| ()))
+                                    .help("Output format: table, or md for Markdown tables to paste into issues."))
+                                .arg(Arg::with_name("datadirs")
+                                    .multiple(true)
+                                    .help("Datadirs to check, eg: of several hosts synced from the upload bucket. Defaults to --datadir."))))
+                        .subcommand(SubCommand::with_name("serve-static")
+                            .about("Serves a minimal web UI with tables and charts of the recent snapshots.")
+                            .arg(Arg::with_name("listen")
+                                .long("listen")
+                                .takes_value(true)
+                                .default_value(web::DEFAULT_LISTEN)
+                                .help("Address to listen on.")))
+                        .subcommand(SubCommand::with_name("convert")
+                            .about("Converts an archive to another storage format: bincode (a datadir), json (one snapshot per line), sqlite, parquet or csv.")
+                            .arg(Arg::with_name("from")
+                                .long("from")
+                                .takes_value(true)
+                                .default_value("auto")
+                                .validator(|s| match s.as_str() {
+                                    "auto" => Ok(()),
+                                    _ => s.parse::<convert::Format>().map(|_| ()),
+                                })
+                                .help("Format of the source archive, detected from its content with auto."))
+                            .arg(Arg::with_name("to")
+                                .long("to")
+                                .takes_value(true)
+                                .required(true)
+                                .validator(|s| s.parse::<convert::Format>().map(|_| ()))
+                                .help("Format of the destination archive."))
+                            .arg(Arg::with_name("no_validate")
+                                .long("no-validate")
+                                .help("Skips reading the destination back to check it."))
+                            .arg(Arg::with_name("resume")
+                                .long("resume")
+                                .help("Saves a checkpoint next to the destination as the conversion goes, and resumes from it if the conversion was interrupted."))
+                            .arg(Arg::with_name("src")
+                                .required(true)
+                                .help("Source datadir or file."))
+                            .arg(Arg::with_name("dst")
+                                .required(true)
+                                .help("Destination datadir or file, which must not exist yet.")))
+                        .subcommand(SubCommand::with_name("tail")
+                            .about("Prints one line per new snapshot: time, total CPU, total rss, process count and top process.")
+                            .arg(Arg::with_name("socket")
+                                .long("socket")
+                                .takes_value(true)
+                                .help("Follows the --live-socket of the server instead of the datadir.")))
+                        .subcommand(SubCommand::with_name("top")
+                            .about("Lists the processes that used the most CPU, or had the largest rss, over the latest snapshot or a range of snapshots.")
+                            .arg(Arg::with_name("time_from")
+                                .short("t")
+                                .takes_value(true)
+                                .validator(|s| client::TimeFrom::parse(&s, &tz::TimeZone::Utc, 0).map(|_| ()).map_err(|e| e.to_string()))
+                                .help("Snapshots ranked, in the format of the -t of the client, eg: '2h ago..1h ago'. Defaults to the latest snapshot."))
+                            .arg(Arg::with_name("order_by")
+                                .short("o")
+                                .takes_value(true)
+                                .default_value("c")
+                                .validator(|s| s.parse::<client::SortBy>().map(|_| ()))
+                                .help("Rank by c (average CPU usage) or m (peak rss)."))
+                            .arg(Arg::with_name("top")
+                                .long("top")
+                                .takes_value(true)
+                                .default_value("10")
+                                .help("Number of processes listed."))
+                            .arg(Arg::with_name("format")
+                                .long("format")
+                                .takes_value(true)
+                                .default_value("table")
+                                .validator(|s| s.parse::<report::ReportFormat>().map(|_| ()))
+                                .help("Output format: table, or md for a Markdown table to paste into issues.")))
+                        .subcommand(SubCommand::with_name("annotate")
+                            .about("Stores an annotation in the datadir, shown by the reports and the web UI next to the snapshots of the same time.")
+                            .arg(Arg::with_name("at")
+                                .long("at")
+                                .takes_value(true)
+                                .help("When it happened: an epoch, or a time in the --tz time zone in the format of -t. Defaults to now."))
+                            .arg(Arg::with_name("text")
+                                .long("text")
+                                .takes_value(true)
+                                .required(true)
+                                .validator(|s| match s.trim().is_empty() {
+                                    true => Err("The text must not be empty.".to_string()),
+                                    false => Ok(()),
+                                })
+                                .help("The annotation, eg: \"deployed v2.3\".")))
+                        .subcommand(SubCommand::with_name("shell")
+                            .about("Reads commands from stdin to load, filter, rank, diff, plot and export snapshots, keeping them loaded between commands."))
+                        .subcommand(SubCommand::with_name("bench-format")
+                            .about("Re-encodes a sample of the snapshots of a datadir in every supported format and compression, and reports the size and the encode and decode times of each.")
+                            .arg(Arg::with_name("sample")
+                                .long("sample")
+                                .takes_value(true)
+                                .validator(|s| match s.parse::<usize>() {
+                                    Ok(n) if n > 0 => Ok(()),
+                                    _ => Err("The sample must be a positive number of snapshots.".to_string()),
+                                })
+                                .help("Number of snapshots, spread evenly over the archive. Defaults to 20."))
+                            .arg(Arg::with_name("src")
+                                .help("Datadir to take the snapshots from. Defaults to --datadir.")))
+                        .subcommand(SubCommand::with_name("collector")
+                            .about("Stores the snapshots sent by servers started with --stream-to, in a datadir per host.")
+                            .arg(Arg::with_name("listen")
+                                .long("listen")
+                                .takes_value(true)
+                                .required(true)
+                                .help("Address to listen on, eg: 0.0.0.0:7070."))
+                            .arg(Arg::with_name("tls_cert")
+                                .long("tls-cert")
+                                .takes_value(true)
+                                .requires_all(&["tls_key", "tls_ca"])
+                                .help("Certificate presented to the servers, in PEM. Only accepts TLS connections, with --tls-key and --tls-ca (needs the tls feature)."))
+                            .arg(Arg::with_name("tls_key")
+                                .long("tls-key")
+                                .takes_value(true)
+                                .requires("tls_cert")
+                                .help("Private key of --tls-cert, in PEM."))
+                            .arg(Arg::with_name("tls_ca")
+                                .long("tls-ca")
+                                .takes_value(true)
+                                .requires("tls_cert")
+                                .help("CA the certificates of the servers must be signed by, in PEM."))
+                            .arg(Arg::with_name("collision")
+                                .long("collision")
+                                .takes_value(true)
+                                .default_value("suffix")
+                                .validator(|s| s.parse::<identity::CollisionPolicy>().map(|_| ()))
+                                .help("What to do with the snapshots of a host reporting the hostname of another one: suffix renames it, reject drops its snapshots."))
+                            .arg(Arg::with_name("dst")
+                                .help("Directory the datadirs of the hosts are created in. Defaults to --datadir.")))
+                        .subcommand(SubCommand::with_name("validate")
+                            .about("Checks the header, compression checksum, decoding, timestamps and counters of every snapshot file of a datadir, and prints a JSON report of the problems found.")
+                            .arg(Arg::with_name("src")
+                                .help("Datadir to check. Defaults to --datadir.")))
+                        .subcommand(SubCommand::with_name("merge")
+                            .about("Merges the snapshots of two datadirs of the same host into a new one, keeping one copy of the times found in both: the valid one if only one is, else the one of the first datadir. Writes what was taken from where to merge.json in the new datadir.")
+                            .arg(Arg::with_name("first")
+                                .required(true)
+                                .help("Datadir whose snapshots are kept when both copies of a time are valid, or both invalid."))
+                            .arg(Arg::with_name("second")
+                                .required(true)
+                                .help("Other datadir."))
+                            .arg(Arg::with_name("out")
+                                .required(true)
+                                .help("Datadir to create. It must not exist or be empty.")))
                         .arg(Arg::with_name("time_from")
                             .short("t")
-                            .help("Read stats from a specific time. Accepted format: 2015-09-05 23:56:04")
+                            .takes_value(true)
+                            .validator(|s| client::TimeFrom::parse(&s, &tz::TimeZone::Utc, 0).map(|_| ()).map_err(|e| e.to_string()))
+                            .help("Read stats from a specific time, in the --tz time zone, or from every snapshot of a <from>..<to> range. Accepted formats: 2015-09-05 23:56:04, optionally followed by utc, local or an offset like +05:30, an epoch, now, or a duration ago like 30m ago.")
                             )
                         .arg(Arg::with_name("order_by")
                             .short("o")
-                            .help("Sort result by Memory or CPU. Accepted values are...") // Todo here
+                            .takes_value(true)
+                            .validator(|s| s.parse::<client::SortBy>().map(|_| ()))
+                            .help("Sort result by Memory or CPU. Accepted values are m and c. [default: m]")
                             )
                         .get_matches();
 
@@ -410,18 +2302,510 @@ impl Config {
             hostname: hostname::get_hostname().unwrap().to_string(),
             delay: matches
                 .value_of("delay")
-                .unwrap_or("60")
-                .parse()
-                .unwrap_or(60),
-            server: match matches.subcommand_matches("server") {
-                Some(_) => true,
-                None => false,
+                .and_then(|s| units::parse_duration(s).ok())
+                .unwrap_or(Duration::from_secs(60)),
+            datadir: std::path::PathBuf::from(
+                matches.value_of("datadir").unwrap_or(DEFAULT_DATADIR),
+            ),
+            server: matches.subcommand_matches("server").is_some(),
+            command: match matches.subcommand_name() {
+                Some("server") => Command::Server,
+                Some("doctor") => Command::Doctor,
+                Some("upload") => {
+                    let m = matches.subcommand_matches("upload").unwrap();
+                    let mut s3 = upload::S3Config::new(
+                        m.value_of("endpoint").unwrap_or_default(),
+                        m.value_of("bucket").unwrap_or_default(),
+                        m.value_of("region").unwrap_or_default(),
+                        m.value_of("prefix").unwrap_or_default(),
+                    );
+                    s3.concurrency = m
+                        .value_of("concurrency")
+                        .and_then(|c| c.parse().ok())
+                        .unwrap_or(4);
+                    Command::Upload(s3)
+                }
+                Some("mount") => Command::Mount(
+                    matches
+                        .subcommand_matches("mount")
+                        .and_then(|m| m.value_of("mountpoint"))
+                        .unwrap_or_default()
+                        .into(),
+                ),
+                Some("report") => {
+                    let m = matches.subcommand_matches("report").unwrap();
+                    match m.subcommand() {
+                        ("growth", Some(g)) => Command::Report(report::ReportKind::Growth {
+                            window: units::parse_duration(g.value_of("window").unwrap_or("24h"))
+                                .unwrap_or(Duration::from_secs(24 * 60 * 60)),
+                            top: g
+                                .value_of("top")
+                                .and_then(|t| t.parse().ok())
+                                .unwrap_or(report::DEFAULT_TOP_N),
+                            format: g
+                                .value_of("format")
+                                .and_then(|s| s.parse().ok())
+                                .unwrap_or(report::ReportFormat::Table),
+                        }),
+                        ("compare", Some(c)) => {
+                            let tz: tz::TimeZone = matches
+                                .value_of("tz")
+                                .and_then(|s| s.parse().ok())
+                                .unwrap_or_default();
+                            let range = |name| {
+                                report::TimeRange::parse(c.value_of(name).unwrap_or_default(), &tz)
+                                    .unwrap_or_else(|e| {
+                                        eprintln!("{}", e);
+                                        std::process::exit(1);
+                                    })
+                            };
+                            let defaults = report::Thresholds::default();
+                            Command::Report(report::ReportKind::Compare {
+                                baseline: range("baseline"),
+                                current: range("current"),
+                                thresholds: report::Thresholds {
+                                    min_percent: c
+                                        .value_of("min_change")
+                                        .and_then(|s| s.parse().ok())
+                                        .unwrap_or(defaults.min_percent),
+                                    min_cpu: c
+                                        .value_of("min_cpu")
+                                        .and_then(|s| s.parse().ok())
+                                        .unwrap_or(defaults.min_cpu),
+                                    min_rss_bytes: c
+                                        .value_of("min_rss")
+                                        .and_then(|s| units::parse_size(s).ok())
+                                        .map(|b| b as f64)
+                                        .unwrap_or(defaults.min_rss_bytes),
+                                },
+                                top: c
+                                    .value_of("top")
+                                    .and_then(|t| t.parse().ok())
+                                    .unwrap_or(20),
+                                format: c
+                                    .value_of("format")
+                                    .and_then(|s| s.parse().ok())
+                                    .unwrap_or(report::ReportFormat::Table),
+                            })
+                        }
+                        ("fleet", Some(f)) => {
+                            let tz: tz::TimeZone = matches
+                                .value_of("tz")
+                                .and_then(|s| s.parse().ok())
+                                .unwrap_or_default();
+                            let range = report::TimeRange::parse(
+                                f.value_of("range").unwrap_or_default(),
+                                &tz,
+                            )
+                            .unwrap_or_else(|e| {
+                                eprintln!("{}", e);
+                                std::process::exit(1);
+                            });
+                            Command::Report(report::ReportKind::Fleet(fleet::FleetQuery {
+                                datadirs: f
+                                    .values_of("datadirs")
+                                    .map(|v| v.map(std::path::PathBuf::from).collect())
+                                    .unwrap_or_default(),
+                                name: f.value_of("name").unwrap_or_default().to_string(),
+                                metric: f
+                                    .value_of("metric")
+                                    .and_then(|s| s.parse().ok())
+                                    .unwrap_or(fleet::FleetMetric::Rss),
+                                range,
+                                bucket: f
+                                    .value_of("bucket")
+                                    .and_then(|s| units::parse_duration(s).ok())
+                                    .unwrap_or(Duration::from_secs(60 * 60)),
+                                percentiles: f
+                                    .value_of("percentiles")
+                                    .and_then(|s| fleet::parse_percentiles(s).ok())
+                                    .unwrap_or_else(|| fleet::DEFAULT_PERCENTILES.to_vec()),
+                                format: f
+                                    .value_of("format")
+                                    .and_then(|s| s.parse().ok())
+                                    .unwrap_or(fleet::ExportFormat::Csv),
+                            }))
+                        }
+                        ("gaps", Some(g)) => {
+                            let tz: tz::TimeZone = matches
+                                .value_of("tz")
+                                .and_then(|s| s.parse().ok())
+                                .unwrap_or_default();
+                            let range = report::TimeRange::parse(
+                                g.value_of("range").unwrap_or("7d ago..now"),
+                                &tz,
+                            )
+                            .unwrap_or_else(|e| {
+                                eprintln!("{}", e);
+                                std::process::exit(1);
+                            });
+                            Command::Report(report::ReportKind::Gaps {
+                                range,
+                                datadirs: g
+                                    .values_of("datadirs")
+                                    .map(|v| v.map(std::path::PathBuf::from).collect())
+                                    .unwrap_or_default(),
+                                tolerance: g
+                                    .value_of("tolerance")
+                                    .and_then(|s| s.parse().ok())
+                                    .unwrap_or(report::DEFAULT_GAP_TOLERANCE),
+                                format: g
+                                    .value_of("format")
+                                    .and_then(|s| s.parse().ok())
+                                    .unwrap_or(report::ReportFormat::Table),
+                            })
+                        }
+                        _ => {
+                            eprintln!("{}", m.usage());
+                            std::process::exit(1);
+                        }
+                    }
+                }
+                Some("serve-static") => Command::ServeStatic(
+                    matches
+                        .subcommand_matches("serve-static")
+                        .and_then(|m| m.value_of("listen"))
+                        .unwrap_or(web::DEFAULT_LISTEN)
+                        .to_string(),
+                ),
+                Some("convert") => {
+                    let m = matches.subcommand_matches("convert").unwrap();
+                    Command::Convert(convert::ConvertJob {
+                        from: m.value_of("from").and_then(|s| s.parse().ok()),
+                        to: m
+                            .value_of("to")
+                            .and_then(|s| s.parse().ok())
+                            .unwrap_or(convert::Format::Bincode),
+                        src: m.value_of("src").unwrap_or_default().into(),
+                        dst: m.value_of("dst").unwrap_or_default().into(),
+                        validate: !m.is_present("no_validate"),
+                        resume: m.is_present("resume"),
+                    })
+                }
+                Some("tail") => Command::Tail(
+                    match matches
+                        .subcommand_matches("tail")
+                        .and_then(|m| m.value_of("socket"))
+                    {
+                        Some(path) => tail::TailSource::Live(path.into()),
+                        None => tail::TailSource::Datadir,
+                    },
+                ),
+                Some("top") => {
+                    let t = matches.subcommand_matches("top").unwrap();
+                    let tz: tz::TimeZone = matches
+                        .value_of("tz")
+                        .and_then(|s| s.parse().ok())
+                        .unwrap_or_default();
+                    let now = std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .map_or(0, |d| d.as_secs());
+                    Command::Top(client::TopJob {
+                        time_from: client::TimeFrom::parse(
+                            t.value_of("time_from").unwrap_or_default(),
+                            &tz,
+                            now,
+                        )
+                        .unwrap_or_else(|e| {
+                            eprintln!("{}", e);
+                            std::process::exit(1);
+                        }),
+                        sort_by: t
+                            .value_of("order_by")
+                            .and_then(|s| s.parse().ok())
+                            .unwrap_or(client::SortBy::Cpu),
+                        top: t
+                            .value_of("top")
+                            .and_then(|s| s.parse().ok())
+                            .unwrap_or(report::DEFAULT_TOP_N),
+                        format: t
+                            .value_of("format")
+                            .and_then(|s| s.parse().ok())
+                            .unwrap_or(report::ReportFormat::Table),
+                    })
+                }
+                Some("annotate") => {
+                    let a = matches.subcommand_matches("annotate").unwrap();
+                    let tz: tz::TimeZone = matches
+                        .value_of("tz")
+                        .and_then(|s| s.parse().ok())
+                        .unwrap_or_default();
+                    let now = std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .map_or(0, |d| d.as_secs());
+                    let time_epoch = match a.value_of("at") {
+                        Some(at) => tz.parse_at(at, now),
+                        None => Ok(now),
+                    };
+                    Command::Annotate(annotations::Annotation {
+                        time_epoch: time_epoch.unwrap_or_else(|e| {
+                            eprintln!("{}", e);
+                            std::process::exit(1);
+                        }),
+                        text: a.value_of("text").unwrap_or_default().to_string(),
+                    })
+                }
+                Some("shell") => Command::Shell,
+                Some("bench-format") => {
+                    let m = matches.subcommand_matches("bench-format").unwrap();
+                    Command::BenchFormat(bench_format::BenchJob {
+                        datadir: m.value_of("src").map(std::path::PathBuf::from),
+                        sample: m
+                            .value_of("sample")
+                            .and_then(|s| s.parse().ok())
+                            .unwrap_or(bench_format::DEFAULT_SAMPLE),
+                    })
+                }
+                Some("collector") => {
+                    let m = matches.subcommand_matches("collector").unwrap();
+                    Command::Collector(collector::CollectorJob {
+                        listen: m.value_of("listen").unwrap_or_default().to_string(),
+                        datadir: m.value_of("dst").map(std::path::PathBuf::from),
+                        tls: tls_files(m),
+                        collision: m
+                            .value_of("collision")
+                            .and_then(|s| s.parse().ok())
+                            .unwrap_or(identity::CollisionPolicy::Suffix),
+                    })
+                }
+                Some("validate") => {
+                    let m = matches.subcommand_matches("validate").unwrap();
+                    Command::Validate(validate::ValidateJob {
+                        datadir: m.value_of("src").map(std::path::PathBuf::from),
+                    })
+                }
+                Some("merge") => {
+                    let m = matches.subcommand_matches("merge").unwrap();
+                    Command::Merge(merge::MergeJob {
+                        first: m.value_of("first").unwrap().into(),
+                        second: m.value_of("second").unwrap().into(),
+                        out: m.value_of("out").unwrap().into(),
+                    })
+                }
+                _ => Command::Client,
             },
-            client_time_from: matches.value_of("time_from").unwrap_or("").to_string(),
+            client_time_from: client::TimeFrom::parse(
+                matches.value_of("time_from").unwrap_or_default(),
+                &matches
+                    .value_of("tz")
+                    .and_then(|s| s.parse().ok())
+                    .unwrap_or_default(),
+                std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs()),
+            )
+            .unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }),
             client_sort_by: matches.value_of("order_by").unwrap_or("m").to_string(),
+            sketch_every: matches
+                .value_of("sketch_every")
+                .unwrap_or("0")
+                .parse()
+                .unwrap_or(0),
+            cgroup: matches.value_of("cgroup").map(std::path::PathBuf::from),
+            include: matches
+                .values_of("include")
+                .map(|v| v.filter_map(|s| s.parse().ok()).collect())
+                .unwrap_or_default(),
+            exclude: matches
+                .values_of("exclude")
+                .map(|v| v.filter_map(|s| s.parse().ok()).collect())
+                .unwrap_or_default(),
+            skip: matches.values_of("record").into_iter().flatten().fold(
+                collect::SkipPolicy::default(),
+                |mut skip, kind| {
+                    let _ = skip.include(kind);
+                    skip
+                },
+            ),
+            byte_format: units::ByteFormat {
+                units: matches
+                    .value_of("units")
+                    .unwrap_or("binary")
+                    .parse()
+                    .unwrap_or(units::UnitSystem::Binary),
+                decimal_separator: matches
+                    .value_of("decimal_separator")
+                    .and_then(|s| s.chars().next())
+                    .unwrap_or('.'),
+                ..Default::default()
+            },
+            kafka_brokers: matches.value_of("kafka_brokers").map(|s| s.to_string()),
+            kafka_topic: matches
+                .value_of("kafka_topic")
+                .unwrap_or("procshot")
+                .to_string(),
+            kafka_per_process: matches.is_present("kafka_per_process"),
+            redis_url: matches.value_of("redis_url").map(|s| s.to_string()),
+            redis_ttl: matches.value_of("redis_ttl").and_then(|s| s.parse().ok()),
+            query_workers: matches
+                .value_of("query_workers")
+                .and_then(|s| s.parse().ok())
+                .unwrap_or_else(query::default_workers),
+            post_write_hooks: matches
+                .values_of("post_write_hook")
+                .map(|v| v.map(|s| s.to_string()).collect())
+                .unwrap_or_default(),
+            cold_after: matches.value_of("cold_after").and_then(|s| s.parse().ok()),
+            compression: matches
+                .value_of("compression")
+                .and_then(|s| s.parse().ok())
+                .unwrap_or_default(),
+            adaptive_compression: matches.is_present("adaptive_compression"),
+            serialization: matches
+                .value_of("serialization")
+                .and_then(|s| s.parse().ok())
+                .unwrap_or_default(),
+            retention: retention::RetentionPolicy {
+                max_files: matches.value_of("max_files").and_then(|s| s.parse().ok()),
+                max_age: matches
+                    .value_of("max_age")
+                    .and_then(|s| units::parse_duration(s).ok()),
+                max_total_bytes: matches
+                    .value_of("max_total_size")
+                    .and_then(|s| units::parse_size(s).ok()),
+            },
+            tz: matches
+                .value_of("tz")
+                .and_then(|s| s.parse().ok())
+                .unwrap_or_default(),
+            live_socket: matches.value_of("live_socket").map(std::path::PathBuf::from),
+            append_log: matches.value_of("append_log").map(std::path::PathBuf::from),
+            jsonl: matches.value_of("jsonl").map(std::path::PathBuf::from),
+            stream_to: matches.value_of("stream_to").map(|s| s.to_string()),
+            stream_tls: tls_files(&matches),
+            memory_limit: matches
+                .value_of("memory_limit")
+                .and_then(|s| units::parse_size(s).ok()),
+            idle_sampling: matches
+                .value_of("idle_every")
+                .and_then(|s| s.parse().ok())
+                .map(|every| sampling::IdleSampling {
+                    rss_below: matches
+                        .value_of("idle_rss_below")
+                        .and_then(|s| units::parse_size(s).ok())
+                        .unwrap_or(16 << 20) as i64,
+                    cpu_below: matches
+                        .value_of("idle_cpu_below")
+                        .and_then(|s| s.parse().ok())
+                        .unwrap_or(0.5),
+                    every,
+                }),
+            shared_datadir: matches.is_present("shared_datadir"),
+            alerts: matches
+                .values_of("alert")
+                .map(|v| v.filter_map(|s| s.parse().ok()).collect())
+                .unwrap_or_default(),
+            alert_commands: matches
+                .values_of("alert_command")
+                .map(|v| v.map(|s| s.to_string()).collect())
+                .unwrap_or_default(),
+            child_limits: child::ChildLimits {
+                timeout: matches
+                    .value_of("command_timeout")
+                    .and_then(|s| units::parse_duration(s).ok())
+                    .unwrap_or(Duration::from_secs(30)),
+                max_running: matches
+                    .value_of("command_concurrency")
+                    .and_then(|s| s.parse().ok())
+                    .unwrap_or(4),
+                ..Default::default()
+            },
+            sd_notify: matches.is_present("sd_notify"),
+            exec_events: matches.is_present("exec_events"),
+            priority_events: matches.is_present("priority_events"),
+            cloud_metadata: matches.is_present("cloud_metadata"),
+            hostname_refresh: matches
+                .value_of("hostname_refresh")
+                .and_then(|s| units::parse_duration(s).ok()),
+            watchdog: matches.value_of("watchdog").and_then(|s| s.parse().ok()),
+            offcpu: matches.is_present("offcpu"),
+            delayacct: matches.is_present("delayacct"),
+            tcp_stats: matches.is_present("tcp_stats"),
+            numa_maps: matches.is_present("numa_maps"),
+            fds: matches.is_present("fds"),
+            connections: matches.is_present("connections"),
+            service_hints: matches.is_present("service_hints"),
+            service_ports: matches
+                .values_of("service_port")
+                .map(|v| v.filter_map(|s| s.parse().ok()).collect())
+                .unwrap_or_default(),
+            runtimes: matches.is_present("runtimes"),
+            cgroup_limits: matches.is_present("cgroup_limits"),
+            redact: matches
+                .values_of("redact")
+                .map(|v| v.filter_map(|s| s.parse().ok()).collect())
+                .unwrap_or_default(),
+            oom_events: matches.is_present("oom_events"),
+            placement: placement::Placement {
+                cpus: matches.value_of("cpus").and_then(|s| s.parse().ok()),
+                nice: matches.value_of("nice").and_then(|s| s.parse().ok()),
+                io_priority: matches.value_of("ionice").and_then(|s| s.parse().ok()),
+            },
+            realtime: matches
+                .value_of("realtime")
+                .and_then(|s| units::parse_duration(s).ok()),
         }
     }
 }
+/// Command is the subcommand selected on the command line.
+#[cfg(feature = "server")]
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    /// No subcommand, print the stored snapshots with `client::run`.
+    Client,
+    /// Run as server and record stats.
+    Server,
+    /// Run the environment diagnostics in the `doctor` module and print a capability matrix.
+    Doctor,
+    /// Upload closed snapshot files to object storage with `upload::Uploader` (`s3` feature).
+    Upload(upload::S3Config),
+    /// Mount a read-only view of the archive at the given mountpoint with `fuse::mount` (`fuse`
+    /// feature).
+    Mount(std::path::PathBuf),
+    /// Compute and print one of the reports of the `report` module.
+    Report(report::ReportKind),
+    /// Serve the web UI of the `web` module on the given address.
+    ServeStatic(String),
+    /// Convert an archive to another storage format with `convert::convert`.
+    Convert(convert::ConvertJob),
+    /// Print a line per new snapshot with `tail::run`.
+    Tail(tail::TailSource),
+    /// Print the heaviest processes of a range of snapshots with `client::run_top`.
+    Top(client::TopJob),
+    /// Store an annotation in the datadir with `annotations::add`.
+    Annotate(annotations::Annotation),
+    /// Explore the datadir interactively with `shell::run`.
+    Shell,
+    /// Compare the storage formats on a sample of the archive with `bench_format::run`.
+    BenchFormat(bench_format::BenchJob),
+    /// Store the snapshots streamed by other servers with `collector::run`.
+    Collector(collector::CollectorJob),
+    /// Check the snapshot files of a datadir with `validate::validate`.
+    Validate(validate::ValidateJob),
+    /// Merge two archives of the same host with `merge::merge`.
+    Merge(merge::MergeJob),
+}
+
+/// Returns the `--tls-cert`, `--tls-key` and `--tls-ca` files, if given.
+#[cfg(feature = "server")]
+fn tls_files(matches: &clap::ArgMatches) -> Option<tls::TlsFiles> {
+    Some(tls::TlsFiles {
+        cert: matches.value_of("tls_cert")?.into(),
+        key: matches.value_of("tls_key")?.into(),
+        ca: matches.value_of("tls_ca")?.into(),
+    })
+}
+
+#[cfg(feature = "server")]
+impl Default for Config {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// Checks if the program is run as sudo (root) user. This doesn't check if the user has the privilege to read over all of /proc or write to the datadir
 /// but just checks if the uid passed to this is 0, and returns a `Result`
 ///
@@ -442,6 +2826,7 @@ impl Config {
 ///     }
 /// }
 ///```
+#[cfg(feature = "server")]
 pub fn check_sudo(uid: u32) -> Result<(), &'static str> {
     match uid == 0 {
         true => Ok(()),
@@ -449,7 +2834,7 @@ pub fn check_sudo(uid: u32) -> Result<(), &'static str> {
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "server"))]
 mod tests {
     use super::*;
 
@@ -461,6 +2846,43 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_get_cpu_usage() {
+        let pid = Pid::new(1);
+        let mut status = collect::restricted_pid_status(std::path::Path::new("/nonexistent"), pid);
+        status.utime = 100;
+        let previous = Some(vec![(pid, status)].into_iter().collect());
+        let usage = |utime, cpu_time| {
+            get_cpu_usage("user".to_string(), pid, &previous, utime, cpu_time, 1000)
+        };
+        assert_eq!(usage(150, 1200), 25.0);
+        // No tick elapsed, eg: at a sub-second delay.
+        assert_eq!(usage(100, 1000), 0.0);
+    }
+
+    #[test]
+    fn test_rss_pct_of_limit() {
+        assert_eq!(rss_pct_of_limit(512, 1024, None), Some(50.0));
+        assert_eq!(rss_pct_of_limit(512, libc::RLIM_INFINITY, Some(2048)), Some(25.0));
+        assert_eq!(rss_pct_of_limit(512, 4096, Some(1024)), Some(50.0));
+        assert_eq!(rss_pct_of_limit(512, 0, None), None);
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let first = snapshot("localghost", None).unwrap();
+        assert!(first.pid_map_list.contains_key(&Pid::current()));
+        assert_eq!(first.total_cpu_time, first.cpu_times.total());
+        assert!(!first.per_cpu.is_empty());
+        assert!(first.elapsed_cpu_time() <= first.total_cpu_time);
+        let second = snapshot("localghost", Some(&first)).unwrap();
+        assert!(second.total_cpu_time >= first.total_cpu_time);
+        match read_proc_stat(std::path::Path::new("/nonexistent")) {
+            Err(error::ProcshotError::Io(e)) => assert!(e.to_string().contains("/nonexistent")),
+            other => panic!("Test failed, {:?}", other),
+        }
+    }
+
     #[test]
     #[should_panic]
     fn test_check_sudo_non_privileged() {
@@ -0,0 +1,113 @@
+//! Load average and uptime of the host, from /proc/loadavg and /proc/uptime.
+//!
+//! A process using 100% of a CPU is a problem on a host with a load of 30 and not on an idle one.
+//! Every snapshot records the load average in `EncoDecode::load_average`, to correlate the spikes
+//! of the processes with the load of the host, and the time since boot in `EncoDecode::uptime`,
+//! to turn the counters of the host and the processes into rates since boot.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+/// LoadAvg is a line of /proc/loadavg.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Copy, Default)]
+pub struct LoadAvg {
+    /// Average number of runnable and uninterruptible tasks over the last minute.
+    pub one: f64,
+    pub five: f64,
+    pub fifteen: f64,
+    /// Number of tasks runnable when it was read.
+    pub runnable: u32,
+    /// Number of tasks, counting every thread.
+    pub tasks: u32,
+}
+
+impl LoadAvg {
+    /// Parses the content of /proc/loadavg, eg: `0.52 0.58 0.59 2/612 12345`.
+    pub fn parse(content: &str) -> Option<LoadAvg> {
+        let mut fields = content.split_whitespace();
+        let mut average = || fields.next()?.parse().ok();
+        let (one, five, fifteen) = (average()?, average()?, average()?);
+        let (runnable, tasks) = fields.next()?.split_once('/')?;
+        Some(LoadAvg {
+            one,
+            five,
+            fifteen,
+            runnable: runnable.parse().ok()?,
+            tasks: tasks.parse().ok()?,
+        })
+    }
+
+    /// Reads the loadavg file of the proc filesystem at `proc_root`.
+    pub fn read_in(proc_root: &Path) -> io::Result<LoadAvg> {
+        let path = proc_root.join("loadavg");
+        let content = fs::read_to_string(&path)?;
+        LoadAvg::parse(&content).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "unexpected content of {}: {}",
+                    path.display(),
+                    content.trim()
+                ),
+            )
+        })
+    }
+}
+
+/// Parses the content of /proc/uptime, eg: `350735.47 234388.90`, and returns the time since
+/// boot. The idle time that follows is not kept, `EncoDecode::cpu_times` has it per state.
+pub fn parse_uptime(content: &str) -> Option<Duration> {
+    let uptime = content.split_whitespace().next()?;
+    let (secs, fraction) = uptime.split_once('.').unwrap_or((uptime, ""));
+    if fraction.len() > 9 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let nanos = format!("{:0<9}", fraction).parse().ok()?;
+    Some(Duration::new(secs.parse().ok()?, nanos))
+}
+
+/// Reads the uptime file of the proc filesystem at `proc_root`.
+pub fn read_uptime(proc_root: &Path) -> io::Result<Duration> {
+    let path = proc_root.join("uptime");
+    let content = fs::read_to_string(&path)?;
+    parse_uptime(&content).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "unexpected content of {}: {}",
+                path.display(),
+                content.trim()
+            ),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            LoadAvg::parse("0.52 0.58 0.59 2/612 12345\n"),
+            Some(LoadAvg {
+                one: 0.52,
+                five: 0.58,
+                fifteen: 0.59,
+                runnable: 2,
+                tasks: 612,
+            })
+        );
+        assert_eq!(LoadAvg::parse("0.52 0.58 0.59\n"), None);
+        assert_eq!(
+            parse_uptime("350735.47 234388.90\n"),
+            Some(Duration::from_millis(350735470))
+        );
+        assert_eq!(parse_uptime("-1 0"), None);
+        let proc_root = Path::new("/proc");
+        assert!(LoadAvg::read_in(proc_root).unwrap().tasks > 0);
+        assert!(read_uptime(proc_root).unwrap() > Duration::from_secs(0));
+    }
+}
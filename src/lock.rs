@@ -0,0 +1,256 @@
+//! Detection of concurrent servers writing to the same datadir.
+//!
+//! Two servers writing into one datadir (a second instance started by mistake, or two hosts
+//! sharing a network mount) interleave their snapshots into a single timeline, and every rate
+//! computed from consecutive snapshots is then wrong without any visible error. The server holds
+//! `<datadir>/procshot.lock`, which names its hostname and pid and carries a heartbeat refreshed
+//! every iteration. The server also holds an exclusive `flock` on the file for as long as it
+//! runs, so two servers of one host started at the same moment can't both take the lock, and the
+//! lock of a server that died is released by the kernel. The heartbeat is for servers of other
+//! hosts, which may not see the `flock` of a network mount: a lock whose owner is gone, or whose
+//! heartbeat is older than the staleness limit, is taken over; any other lock makes the second
+//! server refuse to start.
+
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::Pid;
+
+/// Name of the lock file in the datadir.
+pub const LOCK_FILE: &str = "procshot.lock";
+
+/// Descriptor of the lock file held by this process, -1 if none, for `release_flock`.
+static HELD: AtomicI32 = AtomicI32::new(-1);
+
+/// LockOwner is the content of the lock file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LockOwner {
+    pub hostname: String,
+    pub pid: Pid,
+    /// Epoch of the last heartbeat.
+    pub heartbeat: u64,
+}
+
+impl LockOwner {
+    /// True if this owner can't be writing anymore: its process is gone if it is on this host,
+    /// or its heartbeat is older than `stale_after` seconds.
+    fn is_stale(&self, hostname: &str, now: u64, stale_after: u64) -> bool {
+        if self.hostname == hostname && !Path::new(&format!("/proc/{}", self.pid)).exists() {
+            return true;
+        }
+        now.saturating_sub(self.heartbeat) > stale_after
+    }
+}
+
+/// DatadirLock is held by the server writing to a datadir. The lock file is removed on drop.
+#[derive(Debug)]
+pub struct DatadirLock {
+    path: PathBuf,
+    /// The lock file, `flock`ed for as long as the lock is held.
+    file: File,
+    owner: LockOwner,
+}
+
+impl DatadirLock {
+    /// Takes the lock of `datadir` for this process, failing with `AddrInUse` if another live
+    /// server holds it. A stale lock is taken over with a warning.
+    pub fn acquire(datadir: &Path, hostname: &str, stale_after: u64) -> io::Result<Self> {
+        let path = datadir.join(LOCK_FILE);
+        let now = now();
+        let file = lock_file(&path)?;
+        let in_use = |other: &LockOwner| {
+            io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!(
+                    "{} is in use by pid {} on {}, last heartbeat {} seconds ago",
+                    datadir.display(),
+                    other.pid,
+                    other.hostname,
+                    now.saturating_sub(other.heartbeat)
+                ),
+            )
+        };
+        let file = match file {
+            Some(f) => f,
+            None => {
+                let other = read_owner(&path)?.ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::AddrInUse,
+                        format!("{} is locked by another server", datadir.display()),
+                    )
+                })?;
+                return Err(in_use(&other));
+            }
+        };
+        if let Some(other) = read_owner(&path)? {
+            if !other.is_stale(hostname, now, stale_after) && other.pid != Pid::current() {
+                return Err(in_use(&other));
+            }
+            eprintln!(
+                "Taking over the stale lock of pid {} on {} in {}",
+                other.pid,
+                other.hostname,
+                datadir.display()
+            );
+        }
+        let lock = DatadirLock {
+            path,
+            file,
+            owner: LockOwner {
+                hostname: hostname.to_string(),
+                pid: Pid::current(),
+                heartbeat: now,
+            },
+        };
+        lock.write()?;
+        HELD.store(lock.file.as_raw_fd(), Ordering::Relaxed);
+        Ok(lock)
+    }
+
+    /// Refreshes the heartbeat. Returns the other owner and keeps the file untouched if another
+    /// server took the lock over in the meantime, eg: after this one was stopped for longer than
+    /// the staleness limit.
+    pub fn heartbeat(&mut self) -> io::Result<Option<LockOwner>> {
+        match read_owner(&self.path)? {
+            Some(ref other)
+                if other.pid != self.owner.pid || other.hostname != self.owner.hostname =>
+            {
+                return Ok(Some(other.clone()))
+            }
+            _ => (),
+        }
+        // With a sub-second delay, once per second is enough.
+        let now = now();
+        if now == self.owner.heartbeat {
+            return Ok(None);
+        }
+        self.owner.heartbeat = now;
+        self.write()?;
+        Ok(None)
+    }
+
+    fn write(&self) -> io::Result<()> {
+        // Written in place, renaming a new file would drop the `flock`. The content keeps its
+        // length from one heartbeat to the next, so a concurrent reader sees the old or the new
+        // one.
+        let content = serde_json::to_vec(&self.owner)?;
+        self.file.write_all_at(&content, 0)?;
+        self.file.set_len(content.len() as u64)
+    }
+}
+
+impl Drop for DatadirLock {
+    fn drop(&mut self) {
+        let _ = HELD.compare_exchange(
+            self.file.as_raw_fd(),
+            -1,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
+        if let Ok(Some(owner)) = read_owner(&self.path) {
+            if owner.pid == self.owner.pid && owner.hostname == self.owner.hostname {
+                let _ = fs::remove_file(&self.path);
+            }
+        }
+    }
+}
+
+/// Releases the `flock` of the datadir lock held by this process, if any, leaving the lock file
+/// as it is. For a process that exits while one of its threads is in uninterruptible sleep: its
+/// files are only closed once the thread wakes, and a new server can then take the lock over once
+/// its heartbeat is stale.
+pub fn release_flock() {
+    let fd = HELD.swap(-1, Ordering::Relaxed);
+    if fd >= 0 {
+        unsafe { libc::flock(fd, libc::LOCK_UN) };
+    }
+}
+
+/// Opens the lock file at `path`, creating it if needed, and takes an exclusive `flock` on it.
+/// Returns None if another process holds it.
+fn lock_file(path: &Path) -> io::Result<Option<File>> {
+    loop {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let e = io::Error::last_os_error();
+            return match e.raw_os_error() {
+                Some(libc::EWOULDBLOCK) => Ok(None),
+                _ => Err(e),
+            };
+        }
+        // The previous holder may have removed the file between the open and the flock, the
+        // lock is then on a file nobody else will open.
+        match fs::metadata(path) {
+            Ok(m) if m.ino() == file.metadata()?.ino() => return Ok(Some(file)),
+            Ok(_) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Reads the lock file, if there is one. An unparsable lock file is treated as stale.
+fn read_owner(path: &Path) -> io::Result<Option<LockOwner>> {
+    match fs::read(path) {
+        Ok(content) => Ok(serde_json::from_slice(&content).ok()),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_acquire() {
+        let dir = std::env::temp_dir().join(format!("procshot_lock_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(LOCK_FILE);
+        let mut other = LockOwner {
+            hostname: "otherhost".to_string(),
+            pid: Pid::new(1),
+            heartbeat: now(),
+        };
+        fs::write(&path, serde_json::to_vec(&other).unwrap()).unwrap();
+        let err = DatadirLock::acquire(&dir, "localghost", 180).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+
+        other.heartbeat -= 600;
+        fs::write(&path, serde_json::to_vec(&other).unwrap()).unwrap();
+        let mut lock = DatadirLock::acquire(&dir, "localghost", 180).unwrap();
+        assert_eq!(lock.heartbeat().unwrap(), None);
+        // The flock is held, even against a lock file naming this process.
+        let err = DatadirLock::acquire(&dir, "localghost", 180).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        // Until released, as the watchdog does before exiting.
+        release_flock();
+        drop(DatadirLock::acquire(&dir, "localghost", 180).unwrap());
+
+        other.heartbeat = now();
+        fs::write(&path, serde_json::to_vec(&other).unwrap()).unwrap();
+        assert_eq!(lock.heartbeat().unwrap(), Some(other));
+        drop(lock);
+        // The lock of the other server is left alone.
+        assert!(path.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
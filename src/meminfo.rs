@@ -0,0 +1,99 @@
+//! System wide memory statistics from /proc/meminfo.
+//!
+//! The RSS of a process says little without the memory pressure of the host: 2GiB is nothing on
+//! a host with 60GiB available, and the next OOM kill on one with 200MiB. Every snapshot records
+//! the few lines of /proc/meminfo that tell the pressure in `EncoDecode::system_memory`.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// MemInfo holds the memory of the host, in KiB as /proc/meminfo reports it. Fields missing on
+/// older kernels are 0.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Copy, Default)]
+pub struct MemInfo {
+    pub total_kb: u64,
+    pub free_kb: u64,
+    /// Estimate of the memory available to start new processes without swapping, counting the
+    /// reclaimable caches (since Linux 3.14).
+    pub available_kb: u64,
+    pub buffers_kb: u64,
+    /// Page cache, without the swap cache.
+    pub cached_kb: u64,
+    /// SwapTotal minus SwapFree.
+    pub swap_used_kb: u64,
+}
+
+impl MemInfo {
+    /// Parses the content of /proc/meminfo. Returns None if it has no MemTotal line.
+    pub fn parse(content: &str) -> Option<MemInfo> {
+        let mut info = MemInfo::default();
+        let (mut total, mut swap_total, mut swap_free) = (None, 0, 0);
+        for line in content.lines() {
+            // MemTotal:       16314140 kB
+            let mut fields = line.split_whitespace();
+            let (key, value) = match (fields.next(), fields.next().and_then(|v| v.parse().ok())) {
+                (Some(k), Some(v)) => (k, v),
+                _ => continue,
+            };
+            match key {
+                "MemTotal:" => total = Some(value),
+                "MemFree:" => info.free_kb = value,
+                "MemAvailable:" => info.available_kb = value,
+                "Buffers:" => info.buffers_kb = value,
+                "Cached:" => info.cached_kb = value,
+                "SwapTotal:" => swap_total = value,
+                "SwapFree:" => swap_free = value,
+                _ => (),
+            }
+        }
+        info.total_kb = total?;
+        info.swap_used_kb = swap_total.saturating_sub(swap_free);
+        Some(info)
+    }
+
+    /// Reads the meminfo file of the proc filesystem at `proc_root`.
+    pub fn read_in(proc_root: &Path) -> io::Result<MemInfo> {
+        let path = proc_root.join("meminfo");
+        MemInfo::parse(&fs::read_to_string(&path)?).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} has no MemTotal line", path.display()),
+            )
+        })
+    }
+
+    /// Percentage of the memory of the host that is not available, or None if the total is
+    /// unknown.
+    pub fn used_percent(&self) -> Option<f64> {
+        match self.total_kb {
+            0 => None,
+            total => Some(100.0 * total.saturating_sub(self.available_kb) as f64 / total as f64),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let content = "MemTotal:       16314140 kB\nMemFree:         1203380 kB\nMemAvailable:    8157070 kB\nBuffers:          402312 kB\nCached:          6512004 kB\nSwapCached:        10240 kB\nSwapTotal:       2097148 kB\nSwapFree:        1048574 kB\nHugePages_Total:       0\n";
+        let info = MemInfo::parse(content).unwrap();
+        assert_eq!(
+            info,
+            MemInfo {
+                total_kb: 16314140,
+                free_kb: 1203380,
+                available_kb: 8157070,
+                buffers_kb: 402312,
+                cached_kb: 6512004,
+                swap_used_kb: 1048574,
+            }
+        );
+        assert_eq!(info.used_percent(), Some(50.0));
+        assert_eq!(MemInfo::parse("MemFree: 1 kB\n"), None);
+        assert!(MemInfo::read_in(Path::new("/proc")).unwrap().total_kb > 0);
+    }
+}
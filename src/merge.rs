@@ -0,0 +1,319 @@
+//! Merging two archives of the same host.
+//!
+//! An archive restored from a backup and the one the server kept writing since often overlap, and
+//! reports over two datadirs count the overlap twice. `merge` combines the snapshot files of both
+//! tiers of two datadirs into a new one, taking each time once:
+//!
+//! * files of the same time with the same content are duplicates, one copy is kept;
+//! * files of the same time with different content are a conflict: the copy that passes
+//!   `validate::check_bytes`, which checks the checksum of compressed files, is kept, and the one
+//!   of the first datadir if both or none do. The CRC32 of both copies is recorded.
+//!
+//! Files are copied as they are, whatever their format and compression, into the top tier of the
+//! new datadir. The annotations and the events logs are merged too, without repeating the lines
+//! found in both. What was taken from where is written to `<out>/merge.json`, see `MergeManifest`.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::store;
+use crate::validate;
+use crate::{annotations, events};
+
+/// Name of the manifest written in the merged datadir.
+pub const MANIFEST_FILE: &str = "merge.json";
+
+/// MergeJob describes one run, as given to the `merge` subcommand.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergeJob {
+    /// Datadir whose files win the conflicts neither copy decides.
+    pub first: PathBuf,
+    pub second: PathBuf,
+    /// Datadir to create. It must not exist or be empty.
+    pub out: PathBuf,
+}
+
+/// Conflict is a time both datadirs have a different snapshot of.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Conflict {
+    pub kept: PathBuf,
+    pub kept_crc32: u32,
+    pub dropped: PathBuf,
+    pub dropped_crc32: u32,
+    /// False if neither copy or both copies are valid, and the first was kept by default.
+    pub decided_by_validity: bool,
+}
+
+/// MergeManifest tells how the merged datadir was made.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MergeManifest {
+    pub sources: Vec<PathBuf>,
+    pub hostname: Option<String>,
+    /// Number of snapshot files in the merged datadir.
+    pub snapshots: usize,
+    /// Number of files taken from each source, in the order of `sources`.
+    pub taken: Vec<usize>,
+    /// Number of times found in both sources with the same content.
+    pub duplicates: usize,
+    pub conflicts: Vec<Conflict>,
+    /// Times of the first and last snapshots, in epoch seconds.
+    pub first: Option<u64>,
+    pub last: Option<u64>,
+}
+
+/// Returns the CRC32 of `data`.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = flate2::Crc::new();
+    crc.update(data);
+    crc.sum()
+}
+
+/// Returns the hostname of the first snapshot of `files` that decodes.
+fn hostname(files: &[(u64, PathBuf)]) -> Option<String> {
+    files
+        .iter()
+        .find_map(|(_, path)| store::read_snapshot(path).ok())
+        .map(|s| s.hostname)
+}
+
+/// Returns `files`, as listed by `store::snapshot_files`, by time in milliseconds.
+fn files_by_millis(files: &[(u64, PathBuf)]) -> BTreeMap<u64, PathBuf> {
+    files
+        .iter()
+        .filter_map(|(_, path)| Some((store::snapshot_millis(path)?, path.clone())))
+        .collect()
+}
+
+/// Decides which of two files of the same time to keep. Returns the index of the kept one, and
+/// the conflict if they differ.
+fn resolve(paths: [&Path; 2]) -> io::Result<(usize, Option<Conflict>)> {
+    let data = [fs::read(paths[0])?, fs::read(paths[1])?];
+    if data[0] == data[1] {
+        return Ok((0, None));
+    }
+    let crcs = [crc32(&data[0]), crc32(&data[1])];
+    let valid = [
+        validate::check_bytes(&data[0]).is_ok(),
+        validate::check_bytes(&data[1]).is_ok(),
+    ];
+    let kept = match valid {
+        [false, true] => 1,
+        _ => 0,
+    };
+    let conflict = Conflict {
+        kept: paths[kept].to_path_buf(),
+        kept_crc32: crcs[kept],
+        dropped: paths[1 - kept].to_path_buf(),
+        dropped_crc32: crcs[1 - kept],
+        decided_by_validity: valid[0] != valid[1],
+    };
+    Ok((kept, Some(conflict)))
+}
+
+/// Writes the lines of the `name` logs of `sources` to `out`, skipping the lines already written.
+/// Nothing is written if no source has the log.
+fn merge_lines(sources: &[&Path], out: &Path, name: &str) -> io::Result<()> {
+    let mut seen = HashSet::new();
+    let mut merged = String::new();
+    for source in sources {
+        let content = match fs::read_to_string(source.join(name)) {
+            Ok(c) => c,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        for line in content.lines().filter(|l| !l.is_empty()) {
+            if seen.insert(line.to_string()) {
+                merged.push_str(line);
+                merged.push('\n');
+            }
+        }
+    }
+    match seen.is_empty() {
+        true => Ok(()),
+        false => fs::write(out.join(name), merged),
+    }
+}
+
+/// Merges the datadirs of `job` into `job.out`, see the module documentation. Fails if the
+/// datadirs hold snapshots of different hosts, or if `job.out` isn't empty.
+pub fn merge(job: &MergeJob) -> io::Result<MergeManifest> {
+    let sources = [job.first.as_path(), job.second.as_path()];
+    let files = [
+        store::snapshot_files(sources[0])?,
+        store::snapshot_files(sources[1])?,
+    ];
+    let hostnames = [hostname(&files[0]), hostname(&files[1])];
+    if let [Some(a), Some(b)] = &hostnames {
+        if a != b {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} holds snapshots of {} and {} of {}, only archives of the same host can be merged",
+                    sources[0].display(),
+                    a,
+                    sources[1].display(),
+                    b
+                ),
+            ));
+        }
+    }
+    match fs::read_dir(&job.out).map(|mut entries| entries.next().is_none()) {
+        Ok(true) => (),
+        Ok(false) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} isn't empty", job.out.display()),
+            ))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => fs::create_dir_all(&job.out)?,
+        Err(e) => return Err(e),
+    }
+
+    let mut manifest = MergeManifest {
+        sources: sources.iter().map(|s| s.to_path_buf()).collect(),
+        hostname: hostnames[0].clone().or_else(|| hostnames[1].clone()),
+        snapshots: 0,
+        taken: vec![0, 0],
+        duplicates: 0,
+        conflicts: Vec::new(),
+        first: None,
+        last: None,
+    };
+    let mut by_millis = files_by_millis(&files[0]);
+    let mut origin: BTreeMap<u64, usize> = by_millis.keys().map(|m| (*m, 0)).collect();
+    for (millis, path) in files_by_millis(&files[1]) {
+        let kept = match by_millis.get(&millis) {
+            None => 1,
+            Some(first) => {
+                let (kept, conflict) = resolve([first.as_path(), path.as_path()])?;
+                match conflict {
+                    Some(c) => manifest.conflicts.push(c),
+                    None => manifest.duplicates += 1,
+                }
+                kept
+            }
+        };
+        if kept == 1 {
+            by_millis.insert(millis, path);
+            origin.insert(millis, 1);
+        }
+    }
+    for (millis, path) in &by_millis {
+        let name = path.file_name().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "snapshot path without name")
+        })?;
+        fs::copy(path, job.out.join(name))?;
+        manifest.taken[origin[millis]] += 1;
+    }
+    manifest.snapshots = by_millis.len();
+    manifest.first = by_millis.keys().next().map(|m| m / 1000);
+    manifest.last = by_millis.keys().next_back().map(|m| m / 1000);
+
+    merge_lines(&sources, &job.out, annotations::ANNOTATIONS_FILE)?;
+    merge_lines(&sources, &job.out, events::EVENTS_FILE)?;
+    fs::write(
+        job.out.join(MANIFEST_FILE),
+        serde_json::to_vec_pretty(&manifest)?,
+    )?;
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{DirBackend, StorageBackend};
+    use crate::EncoDecode;
+    use std::time::Duration;
+
+    fn snapshot(hostname: &str, epoch: u64, total_cpu_time: u64) -> EncoDecode {
+        EncoDecode {
+            hostname: hostname.to_string(),
+            time_epoch: epoch,
+            delay: Duration::from_secs(60),
+            total_cpu_time,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_merge() {
+        let dir = std::env::temp_dir().join(format!("procshot_merge_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let (restored, live, other) = (dir.join("restored"), dir.join("live"), dir.join("other"));
+        for d in [&restored, &live, &other] {
+            fs::create_dir_all(d).unwrap();
+        }
+        let mut backend = DirBackend::new(&restored, false);
+        for epoch in [60, 120, 180, 240] {
+            backend
+                .write_snapshot(&snapshot("web-1", epoch, epoch))
+                .unwrap();
+        }
+        let mut backend = DirBackend::new(&live, false);
+        for epoch in [180, 240, 300] {
+            // 240 was written again with other values.
+            let cpu = if epoch == 240 { 1 } else { epoch };
+            backend
+                .write_snapshot(&snapshot("web-1", epoch, cpu))
+                .unwrap();
+        }
+        // The restored copy of 240 is corrupt, the live one wins.
+        fs::write(restored.join("240.procshot"), b"PSHT").unwrap();
+        let note = annotations::Annotation {
+            time_epoch: 200,
+            text: "restored from backup".to_string(),
+        };
+        annotations::add(&restored, &note).unwrap();
+        annotations::add(&live, &note).unwrap();
+
+        let out = dir.join("merged");
+        let job = MergeJob {
+            first: restored.clone(),
+            second: live.clone(),
+            out: out.clone(),
+        };
+        let manifest = merge(&job).unwrap();
+        assert_eq!(manifest.snapshots, 5);
+        assert_eq!(manifest.taken, vec![3, 2]);
+        assert_eq!(manifest.duplicates, 1);
+        assert_eq!(manifest.conflicts.len(), 1);
+        assert_eq!(manifest.conflicts[0].kept, live.join("240.procshot"));
+        assert!(manifest.conflicts[0].decided_by_validity);
+        assert_eq!((manifest.first, manifest.last), (Some(60), Some(300)));
+        assert_eq!(manifest.hostname.as_deref(), Some("web-1"));
+        let epochs: Vec<u64> = store::snapshot_files(&out)
+            .unwrap()
+            .iter()
+            .map(|(e, _)| *e)
+            .collect();
+        assert_eq!(epochs, vec![60, 120, 180, 240, 300]);
+        assert_eq!(
+            store::read_snapshot(&out.join("240.procshot"))
+                .unwrap()
+                .total_cpu_time,
+            1
+        );
+        assert_eq!(annotations::read(&out, 0, u64::MAX).unwrap(), vec![note]);
+        let written: MergeManifest =
+            serde_json::from_slice(&fs::read(out.join(MANIFEST_FILE)).unwrap()).unwrap();
+        assert_eq!(written, manifest);
+
+        // The output must be empty, and both archives of the same host.
+        assert_eq!(
+            merge(&job).unwrap_err().kind(),
+            io::ErrorKind::AlreadyExists
+        );
+        DirBackend::new(&other, false)
+            .write_snapshot(&snapshot("db-1", 60, 0))
+            .unwrap();
+        let job = MergeJob {
+            first: restored,
+            second: other,
+            out: dir.join("mixed"),
+        };
+        assert_eq!(merge(&job).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,344 @@
+//! Canonical names, units and descriptions of the per-process values procshot exports.
+//!
+//! The same quantity shows up in the header of the exported archives, the JSON lines and
+//! summaries, the CSV and parquet columns, the alert rules, the fleet, top and compare reports,
+//! the shell, the sketches and the validation of a datadir. `PROCESS_METRICS` defines in one place
+//! how each is called, in which `Unit`, and what it means, so that an output doesn't call
+//! `rss_bytes` `resident_memory`. The options and commands taking a metric look it up with `find`
+//! or `parse`, and the outputs naming one take the name from here, or are checked against it by
+//! their tests where the name is part of a serialized struct or a fixed list of columns. The name
+//! of a metric stored as is in `PidStatus` is the path of its field, eg: `io.read_bytes`, and
+//! outputs flattening the nested fields join the path with underscores, see `Metric::column`. The
+//! most used metrics also have a short alias accepted on the command line, eg: `rss`.
+
+use std::fmt;
+
+use crate::header::Unit;
+use crate::PidStatus;
+
+/// Metric is a numeric value of a process.
+#[derive(Clone, Copy)]
+pub struct Metric {
+    /// Canonical name, the path of the `PidStatus` field for the metrics stored as is.
+    pub name: &'static str,
+    /// Other names accepted on the command line.
+    pub aliases: &'static [&'static str],
+    pub unit: Unit,
+    /// One sentence describing the metric, eg: for the help of an exporter.
+    pub help: &'static str,
+    /// Whether the metric is a field of `PidStatus`. The others are computed from fields.
+    pub field: bool,
+    /// Name of the column of the metric in outputs without nested fields, when it was named
+    /// before the registry and isn't the name with underscores, see `Metric::column`.
+    pub legacy_column: Option<&'static str>,
+    read: fn(&PidStatus) -> Option<f64>,
+}
+
+impl Metric {
+    /// Returns the value of the metric for a process, None if it wasn't recorded.
+    pub fn of(&self, status: &PidStatus) -> Option<f64> {
+        (self.read)(status)
+    }
+
+    /// Returns the name of the metric in outputs without nested fields, eg: the CSV column
+    /// `io_read_bytes`.
+    pub fn column(&self) -> String {
+        match self.legacy_column {
+            Some(column) => column.to_string(),
+            None => self.name.replace('.', "_"),
+        }
+    }
+}
+
+impl PartialEq for Metric {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+impl fmt::Debug for Metric {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Metric({})", self.name)
+    }
+}
+
+impl fmt::Display for Metric {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name)
+    }
+}
+
+pub const RSS_BYTES: Metric = Metric {
+    name: "rss_bytes",
+    aliases: &["rss"],
+    unit: Unit::Bytes,
+    help: "Resident memory of the process.",
+    field: true,
+    legacy_column: None,
+    read: |s| Some(s.rss_bytes as f64),
+};
+
+pub const CPU_USAGE: Metric = Metric {
+    name: "cpu_usage",
+    aliases: &["cpu"],
+    unit: Unit::Percent,
+    help: "User and system CPU usage of the process since the previous snapshot, in percent of the CPU time of the host, all CPUs together.",
+    field: false,
+    legacy_column: None,
+    read: |s| Some(s.user_cpu_usage + s.sys_cpu_usage),
+};
+
+pub const FDSIZE: Metric = Metric {
+    name: "fdsize",
+    aliases: &["fds"],
+    unit: Unit::Count,
+    help: "File descriptor slots allocated by the process.",
+    field: true,
+    legacy_column: None,
+    read: |s| Some(f64::from(s.fdsize)),
+};
+
+pub const RSS_PCT_OF_LIMIT: Metric = Metric {
+    name: "rss_pct_of_limit",
+    aliases: &[],
+    unit: Unit::Percent,
+    help: "Resident memory as a percentage of the tightest of RLIMIT_RSS and the memory limit of the cgroup.",
+    field: true,
+    legacy_column: None,
+    read: |s| s.rss_pct_of_limit,
+};
+
+/// Every metric of a process.
+pub const PROCESS_METRICS: &[Metric] = &[
+    RSS_BYTES,
+    Metric {
+        name: "rss_pages",
+        aliases: &[],
+        unit: Unit::Pages,
+        help: "Resident memory of the process, in pages.",
+        field: true,
+        legacy_column: None,
+        read: |s| Some(s.rss_pages as f64),
+    },
+    Metric {
+        name: "rsslim_bytes",
+        aliases: &[],
+        unit: Unit::Bytes,
+        help: "Soft limit on the resident memory of the process, RLIMIT_RSS.",
+        field: true,
+        legacy_column: None,
+        read: |s| Some(s.rsslim_bytes as f64),
+    },
+    Metric {
+        name: "vmsize",
+        aliases: &[],
+        unit: Unit::Kibibytes,
+        help: "Virtual memory size of the process.",
+        field: true,
+        legacy_column: None,
+        read: |s| s.vmsize.map(|v| v as f64),
+    },
+    Metric {
+        name: "vmpeak",
+        aliases: &[],
+        unit: Unit::Kibibytes,
+        help: "Peak virtual memory size of the process.",
+        field: true,
+        legacy_column: None,
+        read: |s| s.vmpeak.map(|v| v as f64),
+    },
+    Metric {
+        name: "shared_pages",
+        aliases: &[],
+        unit: Unit::Pages,
+        help: "Resident pages of the process backed by files.",
+        field: true,
+        legacy_column: None,
+        read: |s| Some(s.shared_pages as f64),
+    },
+    Metric {
+        name: "text_pages",
+        aliases: &[],
+        unit: Unit::Pages,
+        help: "Pages of the code of the executable, without the shared libraries.",
+        field: true,
+        legacy_column: None,
+        read: |s| Some(s.text_pages as f64),
+    },
+    Metric {
+        name: "data_pages",
+        aliases: &[],
+        unit: Unit::Pages,
+        help: "Pages of data and stack of the process, resident or not.",
+        field: true,
+        legacy_column: None,
+        read: |s| Some(s.data_pages as f64),
+    },
+    RSS_PCT_OF_LIMIT,
+    CPU_USAGE,
+    Metric {
+        name: "user_cpu_usage",
+        aliases: &[],
+        unit: Unit::Percent,
+        help: "User CPU usage of the process since the previous snapshot, in percent of the CPU time of the host, all CPUs together.",
+        field: true,
+        legacy_column: None,
+        read: |s| Some(s.user_cpu_usage),
+    },
+    Metric {
+        name: "sys_cpu_usage",
+        aliases: &[],
+        unit: Unit::Percent,
+        help: "System CPU usage of the process since the previous snapshot, in percent of the CPU time of the host, all CPUs together.",
+        field: true,
+        legacy_column: None,
+        read: |s| Some(s.sys_cpu_usage),
+    },
+    Metric {
+        name: "utime",
+        aliases: &[],
+        unit: Unit::ClockTicks,
+        help: "CPU time the process spent in user mode since it started.",
+        field: true,
+        legacy_column: None,
+        read: |s| Some(s.utime as f64),
+    },
+    Metric {
+        name: "stime",
+        aliases: &[],
+        unit: Unit::ClockTicks,
+        help: "CPU time the process spent in kernel mode since it started.",
+        field: true,
+        legacy_column: None,
+        read: |s| Some(s.stime as f64),
+    },
+    FDSIZE,
+    Metric {
+        name: "io.read_bytes",
+        aliases: &[],
+        unit: Unit::Bytes,
+        help: "Bytes the process read from storage.",
+        field: true,
+        legacy_column: None,
+        read: |s| s.io.as_ref().map(|io| io.read_bytes as f64),
+    },
+    Metric {
+        name: "io.write_bytes",
+        aliases: &[],
+        unit: Unit::Bytes,
+        help: "Bytes the process caused to be written to storage.",
+        field: true,
+        legacy_column: None,
+        read: |s| s.io.as_ref().map(|io| io.write_bytes as f64),
+    },
+    Metric {
+        name: "io.syscr",
+        aliases: &[],
+        unit: Unit::Count,
+        help: "Read system calls of the process.",
+        field: true,
+        legacy_column: None,
+        read: |s| s.io.as_ref().map(|io| io.syscr as f64),
+    },
+    Metric {
+        name: "io.syscw",
+        aliases: &[],
+        unit: Unit::Count,
+        help: "Write system calls of the process.",
+        field: true,
+        legacy_column: None,
+        read: |s| s.io.as_ref().map(|io| io.syscw as f64),
+    },
+    Metric {
+        name: "io.cancelled_write_bytes",
+        aliases: &[],
+        unit: Unit::Bytes,
+        help: "Bytes the process caused to be written and then truncated before reaching storage.",
+        field: true,
+        legacy_column: None,
+        read: |s| s.io.as_ref().map(|io| io.cancelled_write_bytes as f64),
+    },
+    Metric {
+        name: "cgroup_limits.memory_max",
+        aliases: &[],
+        unit: Unit::Bytes,
+        help: "Memory limit of the cgroup of the process.",
+        field: true,
+        legacy_column: Some("cgroup_memory_max"),
+        read: |s| s.cgroup_limits?.memory_max.map(|m| m as f64),
+    },
+    Metric {
+        name: "cgroup_limits.cpus",
+        aliases: &[],
+        unit: Unit::Cpus,
+        help: "CPU quota of the cgroup of the process, in CPUs.",
+        field: true,
+        legacy_column: Some("cgroup_cpus"),
+        read: |s| s.cgroup_limits?.cpus,
+    },
+];
+
+/// Returns the metric named `name`, or with `name` as an alias.
+pub fn find(name: &str) -> Option<&'static Metric> {
+    PROCESS_METRICS
+        .iter()
+        .find(|m| m.name == name || m.aliases.contains(&name))
+}
+
+/// Same as `find`, with an error listing the metrics.
+pub fn parse(name: &str) -> Result<&'static Metric, String> {
+    find(name).ok_or_else(|| {
+        let names: Vec<&str> = PROCESS_METRICS.iter().map(|m| m.name).collect();
+        format!(
+            "unknown metric {}, accepted metrics are: {}",
+            name,
+            names.join(", ")
+        )
+    })
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use super::*;
+    use crate::collect::{restricted_pid_status, PROC_ROOT};
+    use crate::header::Header;
+    use crate::Pid;
+    use std::collections::HashSet;
+    use std::path::Path;
+
+    #[test]
+    fn test_process_metrics() {
+        let mut names = HashSet::new();
+        for metric in PROCESS_METRICS {
+            assert!(names.insert(metric.name), "{} is defined twice", metric);
+            for alias in metric.aliases {
+                assert!(names.insert(alias), "{} is defined twice", alias);
+            }
+        }
+        assert_eq!(find("rss"), Some(&RSS_BYTES));
+        assert_eq!(parse("cpu_usage"), Ok(&CPU_USAGE));
+        assert!(parse("resident_memory").is_err());
+        assert_eq!(find("io.read_bytes").unwrap().column(), "io_read_bytes");
+        let memory_max = find("cgroup_limits.memory_max").unwrap();
+        assert_eq!(memory_max.column(), "cgroup_memory_max");
+
+        // The fields are read where their name says, with the unit of the header.
+        let mut status = restricted_pid_status(Path::new(PROC_ROOT), Pid::current());
+        status.io = Some(Default::default());
+        status.cgroup_limits = Some(crate::cgroup::Limits {
+            memory_max: Some(1 << 30),
+            cpus: Some(1.5),
+        });
+        status.vmsize = Some(2048);
+        status.vmpeak = Some(4096);
+        status.rss_pct_of_limit = Some(12.5);
+        let json = serde_json::to_value(&status).unwrap();
+        let header = Header::current();
+        for metric in PROCESS_METRICS.iter().filter(|m| m.field) {
+            let pointer = format!("/{}", metric.name.replace('.', "/"));
+            let value = json.pointer(&pointer).and_then(|v| v.as_f64());
+            assert_eq!(value, metric.of(&status), "{}", metric);
+            assert_eq!(header.unit(metric.name), Some(metric.unit), "{}", metric);
+        }
+    }
+}
@@ -0,0 +1,95 @@
+//! Counters of the network interfaces, from /proc/net/dev.
+//!
+//! A process waiting on the network looks idle in its own statistics. Every snapshot records the
+//! bytes, packets, errors and drops each interface received and sent since boot in
+//! `EncoDecode::interfaces`, and the client turns two consecutive snapshots into rates, see
+//! `client::interface_rates`. The interfaces are those of the network namespace of the server.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// InterfaceStats holds the counters of a network interface since it was created.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Default)]
+pub struct InterfaceStats {
+    /// Name of the interface, eg: `lo` or `eth0`.
+    pub name: String,
+    pub rx_bytes: u64,
+    pub rx_packets: u64,
+    pub rx_errors: u64,
+    /// Packets received but dropped, eg: because the receive queue was full.
+    pub rx_drops: u64,
+    pub tx_bytes: u64,
+    pub tx_packets: u64,
+    pub tx_errors: u64,
+    pub tx_drops: u64,
+}
+
+impl InterfaceStats {
+    /// Parses a line of /proc/net/dev, eg:
+    /// `  eth0: 1296000 2400 0 3 0 0 0 0 512000 1600 0 0 0 0 0 0`. The fifo, frame, compressed,
+    /// multicast, collision and carrier counters are not kept.
+    pub fn parse(line: &str) -> Option<InterfaceStats> {
+        let (name, counters) = line.split_once(':')?;
+        let counters: Vec<u64> = counters
+            .split_whitespace()
+            .map(|f| f.parse().ok())
+            .collect::<Option<_>>()?;
+        if counters.len() < 16 {
+            return None;
+        }
+        Some(InterfaceStats {
+            name: name.trim().to_string(),
+            rx_bytes: counters[0],
+            rx_packets: counters[1],
+            rx_errors: counters[2],
+            rx_drops: counters[3],
+            tx_bytes: counters[8],
+            tx_packets: counters[9],
+            tx_errors: counters[10],
+            tx_drops: counters[11],
+        })
+    }
+}
+
+/// Parses the content of /proc/net/dev. The two header lines and malformed lines are skipped.
+pub fn parse(content: &str) -> Vec<InterfaceStats> {
+    content.lines().filter_map(InterfaceStats::parse).collect()
+}
+
+/// Reads the net/dev file of the proc filesystem at `proc_root`.
+pub fn read_in(proc_root: &Path) -> io::Result<Vec<InterfaceStats>> {
+    let path = proc_root.join("net").join("dev");
+    Ok(parse(&fs::read_to_string(path)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let content = "Inter-|   Receive                                                |  Transmit\n face |bytes    packets errs drop fifo frame compressed multicast|bytes    packets errs drop fifo colls carrier compressed\n    lo:    4000      40    0    0    0     0          0         0     4000      40    0    0    0     0       0          0\n  eth0: 1296000 2400 0 3 0 0 0 0 512000 1600 1 0 0 0 0 0\n  eth1: x\n";
+        let interfaces = parse(content);
+        assert_eq!(interfaces.len(), 2);
+        assert_eq!(interfaces[0].name, "lo");
+        assert_eq!(
+            interfaces[1],
+            InterfaceStats {
+                name: "eth0".to_string(),
+                rx_bytes: 1296000,
+                rx_packets: 2400,
+                rx_errors: 0,
+                rx_drops: 3,
+                tx_bytes: 512000,
+                tx_packets: 1600,
+                tx_errors: 1,
+                tx_drops: 0,
+            }
+        );
+        assert!(read_in(Path::new("/proc"))
+            .unwrap()
+            .iter()
+            .any(|i| i.name == "lo"));
+    }
+}
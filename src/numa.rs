@@ -0,0 +1,86 @@
+//! Per-node memory placement from /proc/<pid>/numa_maps.
+//!
+//! On hosts with several NUMA nodes, a process whose memory ended up on a remote node pays for
+//! every access, and it shows as a regression nothing else explains. numa_maps lists, for every
+//! mapping of a process, the number of its pages on each node. The server sums them per node and
+//! records them in the extensions of the process as `numa_node<N>_kb`, so a placement that drifted
+//! can be found in the history instead of only with live tools like `numastat`.
+//!
+//! Reading numa_maps walks the page tables of the process, which takes a while for processes
+//! with a large rss, so the collector is opt-in. Like /proc/<pid>/io, only the owner of a process
+//! or root may read it.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::Pid;
+
+/// Returns the key in `PidStatus::extensions` of the kB of a process on NUMA node `node`.
+pub fn extension_key(node: u32) -> String {
+    format!("numa_node{}_kb", node)
+}
+
+/// Returns true if the kernel was built with NUMA support, and has numa_maps.
+pub fn available(proc_root: &Path) -> bool {
+    proc_root.join("self").join("numa_maps").is_file()
+}
+
+/// Sums the pages of every mapping of a numa_maps file per node, in kB. Each line is a mapping,
+/// eg: `7f0c... default file=/usr/lib/libc.so.6 mapped=40 N0=25 N1=15 kernelpagesize_kB=4`.
+pub fn parse(content: &str) -> BTreeMap<u32, u64> {
+    let mut kb_by_node = BTreeMap::new();
+    for line in content.lines() {
+        let mut pages_by_node: Vec<(u32, u64)> = Vec::new();
+        // Huge page mappings have larger pages.
+        let mut page_kb: u64 = 4;
+        for field in line.split_whitespace() {
+            if let Some(kb) = field.strip_prefix("kernelpagesize_kB=") {
+                page_kb = kb.parse().unwrap_or(page_kb);
+                continue;
+            }
+            let node_pages = field
+                .strip_prefix('N')
+                .and_then(|f| f.split_once('='))
+                .and_then(|(node, pages)| Some((node.parse().ok()?, pages.parse().ok()?)));
+            if let Some((node, pages)) = node_pages {
+                pages_by_node.push((node, pages));
+            }
+        }
+        for (node, pages) in pages_by_node {
+            *kb_by_node.entry(node).or_insert(0) += pages * page_kb;
+        }
+    }
+    kb_by_node
+}
+
+/// Reads <pid>/numa_maps under `proc_root` and returns the kB of the process on each node it has
+/// pages on.
+pub fn read_in(proc_root: &Path, pid: Pid) -> io::Result<BTreeMap<u32, u64>> {
+    let content = fs::read_to_string(proc_root.join(pid.to_string()).join("numa_maps"))?;
+    Ok(parse(&content))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let kb = parse(
+            "55d0c8a00000 default file=/usr/bin/postgres mapped=200 N0=150 N1=50 kernelpagesize_kB=4\n\
+             7f0c00000000 default anon=512 dirty=512 N1=512 kernelpagesize_kB=4\n\
+             7f0d00000000 bind:1 huge anon=4 dirty=4 N1=4 kernelpagesize_kB=2048\n\
+             7ffd5a1f0000 default stack anon=3 dirty=3 N0=3 kernelpagesize_kB=4\n\
+             7ffd5a3fe000 default\n",
+        );
+        assert_eq!(kb.get(&0), Some(&(153 * 4)));
+        assert_eq!(kb.get(&1), Some(&(562 * 4 + 4 * 2048)));
+        assert_eq!(kb.len(), 2);
+        assert_eq!(extension_key(1), "numa_node1_kb");
+        if available(Path::new("/proc")) {
+            assert!(read_in(Path::new("/proc"), Pid::current()).is_ok());
+        }
+    }
+}
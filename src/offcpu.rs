@@ -0,0 +1,729 @@
+//! Off-CPU time and run queue latency of the processes, from eBPF (`ebpf` feature).
+//!
+//! The CPU times of /proc tell how long a process ran, not how long it waited: a latency incident
+//! caused by a saturated run queue looks like idle processes in the snapshots. `OffCpuSampler`
+//! loads two small eBPF programs on the `sched_switch` and `sched_wakeup` tracepoints, which add
+//! up per thread the time spent blocked (off-CPU) and the time spent runnable but waiting for a
+//! CPU (run queue latency). `take` returns the sums per process since the previous call, which
+//! the server stores as the `offcpu_ns` and `runq_latency_ns` extensions of `PidStatus`.
+//!
+//! The programs are assembled here and loaded with the bpf syscall, no compiler or BTF is needed
+//! at runtime, only tracefs and CAP_BPF with CAP_PERFMON (or root). Time spent blocked is counted
+//! when the thread is woken up, so a thread blocked for the whole interval only shows it once it
+//! wakes up.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+
+use crate::Pid;
+
+/// Extension of `PidStatus` holding the nanoseconds the threads of the process spent blocked.
+pub const EXT_OFFCPU_NS: &str = "offcpu_ns";
+/// Extension of `PidStatus` holding the nanoseconds the threads of the process spent runnable,
+/// waiting for a CPU.
+pub const EXT_RUNQ_LATENCY_NS: &str = "runq_latency_ns";
+
+/// Maximum number of threads tracked at once.
+const MAX_THREADS: u32 = 1 << 18;
+/// Where tracefs is usually mounted.
+const TRACEFS_MOUNTS: &[&str] = &["/sys/kernel/tracing", "/sys/kernel/debug/tracing"];
+
+const BPF_MAP_CREATE: libc::c_long = 0;
+const BPF_MAP_LOOKUP_ELEM: libc::c_long = 1;
+const BPF_MAP_DELETE_ELEM: libc::c_long = 3;
+const BPF_MAP_GET_NEXT_KEY: libc::c_long = 4;
+const BPF_PROG_LOAD: libc::c_long = 5;
+const BPF_MAP_TYPE_HASH: u32 = 1;
+const BPF_F_NO_PREALLOC: u32 = 1;
+const BPF_PROG_TYPE_TRACEPOINT: u32 = 5;
+const PERF_TYPE_TRACEPOINT: u32 = 2;
+const PERF_FLAG_FD_CLOEXEC: libc::c_ulong = 8;
+const PERF_EVENT_IOC_ENABLE: libc::c_ulong = 0x2400;
+const PERF_EVENT_IOC_SET_BPF: libc::c_ulong = 0x4004_2408;
+
+/// Bits of `prev_state` in `sched_switch` that mean the thread is blocked. None of them is set
+/// when it was preempted, and is still runnable.
+const TASK_REPORT_MASK: i32 = 0xff;
+
+/// OffCpu is the time the threads of a process didn't run.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct OffCpu {
+    /// Nanoseconds spent blocked, eg: sleeping or waiting for I/O.
+    pub offcpu_ns: u64,
+    /// Nanoseconds spent runnable, waiting for a CPU.
+    pub runq_latency_ns: u64,
+}
+
+/// OffCpuSampler holds the maps and the attached programs.
+#[derive(Debug)]
+pub struct OffCpuSampler {
+    /// Thread id to the time it left the CPU or was woken up, shifted left by one, with the
+    /// lowest bit set if it is runnable.
+    last: OwnedFd,
+    /// Thread id to the sums of the off-CPU time and of the run queue latency.
+    offcpu: OwnedFd,
+    runq: OwnedFd,
+    /// The programs, and one perf event per CPU and tracepoint they are attached to.
+    _programs: Vec<OwnedFd>,
+    _events: Vec<OwnedFd>,
+}
+
+impl OffCpuSampler {
+    /// Loads and attaches the programs on every online CPU.
+    pub fn open() -> io::Result<Self> {
+        let tracefs = TRACEFS_MOUNTS
+            .iter()
+            .map(PathBuf::from)
+            .find(|p| p.join("events/sched").is_dir())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "tracefs is not mounted"))?;
+        let switch = Tracepoint::read(&tracefs, "sched_switch")?;
+        let wakeup = Tracepoint::read(&tracefs, "sched_wakeup")?;
+        let last = create_map()?;
+        let offcpu = create_map()?;
+        let runq = create_map()?;
+        let maps = Maps {
+            last: last.as_raw_fd(),
+            offcpu: offcpu.as_raw_fd(),
+            runq: runq.as_raw_fd(),
+        };
+        let switch_program = load_program(&switch_program(&switch, &maps)?)?;
+        let wakeup_program = load_program(&wakeup_program(&wakeup, &maps)?)?;
+        let mut events = Vec::new();
+        for cpu in online_cpus()? {
+            events.push(attach(switch.id, cpu, &switch_program)?);
+            events.push(attach(wakeup.id, cpu, &wakeup_program)?);
+        }
+        Ok(OffCpuSampler {
+            last,
+            offcpu,
+            runq,
+            _programs: vec![switch_program, wakeup_program],
+            _events: events,
+        })
+    }
+
+    /// Returns the time off-CPU of every process since the previous call, summed over its
+    /// threads, and forgets the threads that exited.
+    pub fn take(&mut self) -> io::Result<HashMap<Pid, OffCpu>> {
+        let mut by_pid: HashMap<Pid, OffCpu> = HashMap::new();
+        let mut tgids: HashMap<u32, Option<Pid>> = HashMap::new();
+        for (map, runq) in &[(&self.offcpu, false), (&self.runq, true)] {
+            for tid in keys(map)? {
+                let ns = match lookup(map, tid)? {
+                    Some(ns) => ns,
+                    None => continue,
+                };
+                delete(map, tid)?;
+                let tgid = *tgids.entry(tid).or_insert_with(|| tgid_of(tid));
+                if let Some(pid) = tgid {
+                    let entry = by_pid.entry(pid).or_default();
+                    match runq {
+                        true => entry.runq_latency_ns += ns,
+                        false => entry.offcpu_ns += ns,
+                    }
+                }
+            }
+        }
+        for tid in keys(&self.last)? {
+            if !Path::new(&format!("/proc/{}", tid)).exists() {
+                delete(&self.last, tid)?;
+            }
+        }
+        Ok(by_pid)
+    }
+}
+
+/// Returns the process of the thread `tid`, None if it exited.
+fn tgid_of(tid: u32) -> Option<Pid> {
+    let status = fs::read_to_string(format!("/proc/{}/status", tid)).ok()?;
+    crate::procfile::status_field(&status, "Tgid")?.parse().ok()
+}
+
+/// Tracepoint is the id of a tracepoint and the offsets of the fields of its context.
+#[derive(Debug)]
+struct Tracepoint {
+    id: u64,
+    fields: HashMap<String, i16>,
+}
+
+impl Tracepoint {
+    fn read(tracefs: &Path, name: &str) -> io::Result<Self> {
+        let dir = tracefs.join("events/sched").join(name);
+        let id = fs::read_to_string(dir.join("id"))?
+            .trim()
+            .parse()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(Tracepoint {
+            id,
+            fields: parse_format(&fs::read_to_string(dir.join("format"))?),
+        })
+    }
+
+    fn offset(&self, field: &str) -> io::Result<i16> {
+        self.fields.get(field).copied().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("tracepoint {} has no field {}", self.id, field),
+            )
+        })
+    }
+}
+
+/// Returns the offsets of the fields of a tracepoint format file, eg:
+/// `field:pid_t prev_pid; offset:24; size:4; signed:1;`, with tabs between the attributes.
+fn parse_format(format: &str) -> HashMap<String, i16> {
+    format
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.trim().split(';');
+            let declaration = parts.next()?.strip_prefix("field:")?;
+            let name = declaration.rsplit(' ').next()?;
+            let name = name.split('[').next()?;
+            let offset = parts
+                .find_map(|p| p.trim().strip_prefix("offset:"))?
+                .parse()
+                .ok()?;
+            Some((name.to_string(), offset))
+        })
+        .collect()
+}
+
+/// Parses a list of CPUs like /sys/devices/system/cpu/online, eg: `0-3,6`.
+fn parse_cpu_list(list: &str) -> Option<Vec<i32>> {
+    let mut cpus = Vec::new();
+    for range in list.trim().split(',').filter(|r| !r.is_empty()) {
+        match range.split_once('-') {
+            Some((from, to)) => cpus.extend(from.parse::<i32>().ok()?..=to.parse().ok()?),
+            None => cpus.push(range.parse().ok()?),
+        }
+    }
+    Some(cpus)
+}
+
+fn online_cpus() -> io::Result<Vec<i32>> {
+    let list = fs::read_to_string("/sys/devices/system/cpu/online")?;
+    parse_cpu_list(&list).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("cannot parse the online CPUs {:?}", list),
+        )
+    })
+}
+
+/// Returns the file descriptor returned by the bpf syscall.
+fn bpf<T>(cmd: libc::c_long, attr: &T) -> io::Result<libc::c_long> {
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            cmd,
+            attr as *const T,
+            mem::size_of::<T>() as libc::c_uint,
+        )
+    };
+    match ret {
+        r if r < 0 => Err(io::Error::last_os_error()),
+        r => Ok(r),
+    }
+}
+
+#[repr(C)]
+struct MapCreateAttr {
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+    map_flags: u32,
+}
+
+#[repr(C)]
+struct MapElemAttr {
+    map_fd: u32,
+    _pad: u32,
+    key: u64,
+    value: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+struct ProgLoadAttr {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+    kern_version: u32,
+    prog_flags: u32,
+}
+
+/// The first fields of struct perf_event_attr, `PERF_ATTR_SIZE_VER0`.
+#[repr(C)]
+struct PerfEventAttr {
+    event_type: u32,
+    size: u32,
+    config: u64,
+    sample_period: u64,
+    sample_type: u64,
+    read_format: u64,
+    flags: u64,
+    wakeup_events: u32,
+    bp_type: u32,
+    config1: u64,
+}
+
+/// Creates a hash map of thread id to u64.
+fn create_map() -> io::Result<OwnedFd> {
+    let fd = bpf(
+        BPF_MAP_CREATE,
+        &MapCreateAttr {
+            map_type: BPF_MAP_TYPE_HASH,
+            key_size: 4,
+            value_size: 8,
+            max_entries: MAX_THREADS,
+            map_flags: BPF_F_NO_PREALLOC,
+        },
+    )?;
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as i32) })
+}
+
+fn elem_attr(map: &OwnedFd, key: &u32, value: u64) -> MapElemAttr {
+    MapElemAttr {
+        map_fd: map.as_raw_fd() as u32,
+        _pad: 0,
+        key: key as *const u32 as u64,
+        value,
+        flags: 0,
+    }
+}
+
+fn lookup(map: &OwnedFd, key: u32) -> io::Result<Option<u64>> {
+    let mut value = 0u64;
+    match bpf(
+        BPF_MAP_LOOKUP_ELEM,
+        &elem_attr(map, &key, &mut value as *mut u64 as u64),
+    ) {
+        Ok(_) => Ok(Some(value)),
+        Err(ref e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn delete(map: &OwnedFd, key: u32) -> io::Result<()> {
+    match bpf(BPF_MAP_DELETE_ELEM, &elem_attr(map, &key, 0)) {
+        Err(ref e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(()),
+        r => r.map(|_| ()),
+    }
+}
+
+fn keys(map: &OwnedFd) -> io::Result<Vec<u32>> {
+    let mut keys = Vec::new();
+    // An unknown key returns the first key of the map.
+    let mut key = u32::MAX;
+    loop {
+        let mut next = 0u32;
+        match bpf(
+            BPF_MAP_GET_NEXT_KEY,
+            &elem_attr(map, &key, &mut next as *mut u32 as u64),
+        ) {
+            Ok(_) => keys.push(next),
+            Err(ref e) if e.raw_os_error() == Some(libc::ENOENT) => return Ok(keys),
+            Err(e) => return Err(e),
+        }
+        key = next;
+    }
+}
+
+fn load_program(insns: &[u64]) -> io::Result<OwnedFd> {
+    let license = b"GPL\0";
+    let mut log = vec![0u8; 64 * 1024];
+    let attr = ProgLoadAttr {
+        prog_type: BPF_PROG_TYPE_TRACEPOINT,
+        insn_cnt: insns.len() as u32,
+        insns: insns.as_ptr() as u64,
+        license: license.as_ptr() as u64,
+        log_level: 1,
+        log_size: log.len() as u32,
+        log_buf: log.as_mut_ptr() as u64,
+        kern_version: 0,
+        prog_flags: 0,
+    };
+    match bpf(BPF_PROG_LOAD, &attr) {
+        Ok(fd) => Ok(unsafe { OwnedFd::from_raw_fd(fd as i32) }),
+        Err(e) => {
+            let end = log.iter().position(|b| *b == 0).unwrap_or(log.len());
+            let verifier = String::from_utf8_lossy(&log[..end]);
+            Err(io::Error::new(
+                e.kind(),
+                format!("{}, verifier log: {}", e, verifier.trim()),
+            ))
+        }
+    }
+}
+
+/// Opens a perf event on the tracepoint `id` of `cpu` and attaches `program` to it.
+fn attach(id: u64, cpu: i32, program: &OwnedFd) -> io::Result<OwnedFd> {
+    let attr = PerfEventAttr {
+        event_type: PERF_TYPE_TRACEPOINT,
+        size: mem::size_of::<PerfEventAttr>() as u32,
+        config: id,
+        sample_period: 1,
+        sample_type: 0,
+        read_format: 0,
+        flags: 0,
+        wakeup_events: 1,
+        bp_type: 0,
+        config1: 0,
+    };
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_perf_event_open,
+            &attr as *const PerfEventAttr,
+            -1,
+            cpu,
+            -1,
+            PERF_FLAG_FD_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let event = unsafe { OwnedFd::from_raw_fd(fd as i32) };
+    for (request, arg) in &[
+        (PERF_EVENT_IOC_SET_BPF, program.as_raw_fd()),
+        (PERF_EVENT_IOC_ENABLE, 0),
+    ] {
+        if unsafe { libc::ioctl(event.as_raw_fd(), *request, *arg) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(event)
+}
+
+/// The file descriptors of the maps, as loaded into the programs.
+struct Maps {
+    last: i32,
+    offcpu: i32,
+    runq: i32,
+}
+
+/// Registers of the eBPF virtual machine.
+const R0: u8 = 0;
+const R1: u8 = 1;
+const R2: u8 = 2;
+const R3: u8 = 3;
+const R4: u8 = 4;
+const R6: u8 = 6;
+const R7: u8 = 7;
+const R8: u8 = 8;
+const R9: u8 = 9;
+const R10: u8 = 10;
+
+/// Helper functions callable from programs.
+const MAP_LOOKUP_ELEM: i32 = 1;
+const MAP_UPDATE_ELEM: i32 = 2;
+const MAP_DELETE_ELEM: i32 = 3;
+const KTIME_GET_NS: i32 = 5;
+
+/// Stack slots of the programs, relative to R10: the thread id and a value.
+const KEY: i16 = -4;
+const VALUE: i16 = -16;
+
+/// Asm assembles an eBPF program, with forward jumps to labels.
+#[derive(Default)]
+struct Asm {
+    insns: Vec<u64>,
+    /// Index of the jumps to each label.
+    jumps: HashMap<&'static str, Vec<usize>>,
+}
+
+impl Asm {
+    fn insn(&mut self, code: u8, dst: u8, src: u8, off: i16, imm: i32) -> &mut Self {
+        self.insns.push(
+            u64::from(code)
+                | u64::from(dst | (src << 4)) << 8
+                | u64::from(off as u16) << 16
+                | u64::from(imm as u32) << 32,
+        );
+        self
+    }
+
+    fn mov(&mut self, dst: u8, src: u8) -> &mut Self {
+        self.insn(0xbf, dst, src, 0, 0)
+    }
+
+    fn mov_imm(&mut self, dst: u8, imm: i32) -> &mut Self {
+        self.insn(0xb7, dst, 0, 0, imm)
+    }
+
+    fn add_imm(&mut self, dst: u8, imm: i32) -> &mut Self {
+        self.insn(0x07, dst, 0, 0, imm)
+    }
+
+    fn sub(&mut self, dst: u8, src: u8) -> &mut Self {
+        self.insn(0x1f, dst, src, 0, 0)
+    }
+
+    fn and_imm(&mut self, dst: u8, imm: i32) -> &mut Self {
+        self.insn(0x57, dst, 0, 0, imm)
+    }
+
+    fn or_imm(&mut self, dst: u8, imm: i32) -> &mut Self {
+        self.insn(0x47, dst, 0, 0, imm)
+    }
+
+    fn lsh_imm(&mut self, dst: u8, imm: i32) -> &mut Self {
+        self.insn(0x67, dst, 0, 0, imm)
+    }
+
+    fn rsh_imm(&mut self, dst: u8, imm: i32) -> &mut Self {
+        self.insn(0x77, dst, 0, 0, imm)
+    }
+
+    /// dst = *(u32 *)(src + off)
+    fn load32(&mut self, dst: u8, src: u8, off: i16) -> &mut Self {
+        self.insn(0x61, dst, src, off, 0)
+    }
+
+    /// dst = *(u64 *)(src + off)
+    fn load64(&mut self, dst: u8, src: u8, off: i16) -> &mut Self {
+        self.insn(0x79, dst, src, off, 0)
+    }
+
+    /// *(u32 *)(dst + off) = src
+    fn store32(&mut self, dst: u8, off: i16, src: u8) -> &mut Self {
+        self.insn(0x63, dst, src, off, 0)
+    }
+
+    /// *(u64 *)(dst + off) = src
+    fn store64(&mut self, dst: u8, off: i16, src: u8) -> &mut Self {
+        self.insn(0x7b, dst, src, off, 0)
+    }
+
+    /// Atomically adds src to *(u64 *)(dst + off).
+    fn atomic_add64(&mut self, dst: u8, off: i16, src: u8) -> &mut Self {
+        self.insn(0xdb, dst, src, off, 0)
+    }
+
+    /// Loads the file descriptor of a map, which the kernel replaces with its address.
+    fn load_map(&mut self, dst: u8, fd: i32) -> &mut Self {
+        // BPF_LD | BPF_DW | BPF_IMM with BPF_PSEUDO_MAP_FD, over two instructions.
+        self.insn(0x18, dst, 1, 0, fd).insn(0, 0, 0, 0, 0)
+    }
+
+    /// Sets R2 to the address of the key on the stack.
+    fn key_arg(&mut self) -> &mut Self {
+        self.mov(R2, R10).add_imm(R2, i32::from(KEY))
+    }
+
+    fn call(&mut self, helper: i32) -> &mut Self {
+        self.insn(0x85, 0, 0, 0, helper)
+    }
+
+    /// Jumps to `label` if dst == imm.
+    fn jeq_imm(&mut self, dst: u8, imm: i32, label: &'static str) -> &mut Self {
+        self.jump(0x15, dst, imm, label)
+    }
+
+    /// Jumps to `label` if dst != imm.
+    fn jne_imm(&mut self, dst: u8, imm: i32, label: &'static str) -> &mut Self {
+        self.jump(0x55, dst, imm, label)
+    }
+
+    fn ja(&mut self, label: &'static str) -> &mut Self {
+        self.jump(0x05, 0, 0, label)
+    }
+
+    fn jump(&mut self, code: u8, dst: u8, imm: i32, label: &'static str) -> &mut Self {
+        self.jumps.entry(label).or_default().push(self.insns.len());
+        self.insn(code, dst, 0, 0, imm)
+    }
+
+    /// Places `label` at the next instruction, resolving the jumps to it.
+    fn label(&mut self, label: &'static str) -> &mut Self {
+        let target = self.insns.len();
+        for at in self.jumps.remove(label).unwrap_or_default() {
+            let off = (target - at - 1) as u16;
+            self.insns[at] = (self.insns[at] & !(0xffff << 16)) | u64::from(off) << 16;
+        }
+        self
+    }
+
+    /// Adds R7 to the value of the key in the map in R8, creating it if needed. Uses the value
+    /// slot of the stack.
+    fn add_to_map(&mut self, done: &'static str) -> &mut Self {
+        self.mov(R1, R8)
+            .key_arg()
+            .call(MAP_LOOKUP_ELEM)
+            .jeq_imm(R0, 0, "create")
+            .atomic_add64(R0, 0, R7)
+            .ja(done)
+            .label("create")
+            .store64(R10, VALUE, R7)
+            .mov(R1, R8)
+            .key_arg()
+            .mov(R3, R10)
+            .add_imm(R3, i32::from(VALUE))
+            .mov_imm(R4, 0)
+            .call(MAP_UPDATE_ELEM)
+    }
+
+    fn finish(&mut self) -> io::Result<Vec<u64>> {
+        self.mov_imm(R0, 0).insn(0x95, 0, 0, 0, 0);
+        match self.jumps.keys().next() {
+            Some(label) => Err(io::Error::other(format!("unresolved label {}", label))),
+            None => Ok(std::mem::take(&mut self.insns)),
+        }
+    }
+}
+
+/// On a switch, records when the previous thread left the CPU and whether it is still runnable,
+/// and adds the time the next thread waited for the CPU to its run queue latency, or, if its
+/// wakeup wasn't seen, to its off-CPU time.
+fn switch_program(tp: &Tracepoint, maps: &Maps) -> io::Result<Vec<u64>> {
+    let mut asm = Asm::default();
+    asm.mov(R6, R1)
+        .call(KTIME_GET_NS)
+        .mov(R7, R0)
+        // The previous thread, unless it is the idle task.
+        .load32(R1, R6, tp.offset("prev_pid")?)
+        .jeq_imm(R1, 0, "next")
+        .store32(R10, KEY, R1)
+        .load64(R1, R6, tp.offset("prev_state")?)
+        .and_imm(R1, TASK_REPORT_MASK)
+        .mov(R2, R7)
+        .lsh_imm(R2, 1)
+        .jne_imm(R1, 0, "blocked")
+        .or_imm(R2, 1)
+        .label("blocked")
+        .store64(R10, VALUE, R2)
+        .load_map(R1, maps.last)
+        .key_arg()
+        .mov(R3, R10)
+        .add_imm(R3, i32::from(VALUE))
+        .mov_imm(R4, 0)
+        .call(MAP_UPDATE_ELEM)
+        // The next thread.
+        .label("next")
+        .load32(R1, R6, tp.offset("next_pid")?)
+        .jeq_imm(R1, 0, "exit")
+        .store32(R10, KEY, R1)
+        .load_map(R1, maps.last)
+        .key_arg()
+        .call(MAP_LOOKUP_ELEM)
+        .jeq_imm(R0, 0, "exit")
+        .load64(R1, R0, 0)
+        .mov(R9, R1)
+        .and_imm(R9, 1)
+        .rsh_imm(R1, 1)
+        .sub(R7, R1)
+        .load_map(R1, maps.last)
+        .key_arg()
+        .call(MAP_DELETE_ELEM)
+        .load_map(R8, maps.offcpu)
+        .jeq_imm(R9, 0, "add")
+        .load_map(R8, maps.runq)
+        .label("add")
+        .add_to_map("exit")
+        .label("exit");
+    asm.finish()
+}
+
+/// On a wakeup of a blocked thread, adds the time it was blocked to its off-CPU time and marks it
+/// runnable from now on.
+fn wakeup_program(tp: &Tracepoint, maps: &Maps) -> io::Result<Vec<u64>> {
+    let mut asm = Asm::default();
+    asm.mov(R6, R1)
+        .call(KTIME_GET_NS)
+        .mov(R9, R0)
+        .load32(R1, R6, tp.offset("pid")?)
+        .jeq_imm(R1, 0, "exit")
+        .store32(R10, KEY, R1)
+        .load_map(R1, maps.last)
+        .key_arg()
+        .call(MAP_LOOKUP_ELEM)
+        .jeq_imm(R0, 0, "exit")
+        .load64(R1, R0, 0)
+        .mov(R2, R1)
+        .and_imm(R2, 1)
+        .jne_imm(R2, 0, "exit")
+        .rsh_imm(R1, 1)
+        .mov(R7, R9)
+        .sub(R7, R1)
+        .lsh_imm(R9, 1)
+        .or_imm(R9, 1)
+        .store64(R0, 0, R9)
+        .load_map(R8, maps.offcpu)
+        .add_to_map("exit")
+        .label("exit");
+    asm.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    const SWITCH_FORMAT: &str = "name: sched_switch
+ID: 372
+format:
+	field:unsigned short common_type;	offset:0;	size:2;	signed:0;
+	field:int common_pid;	offset:4;	size:4;	signed:1;
+
+	field:char prev_comm[16];	offset:8;	size:16;	signed:0;
+	field:pid_t prev_pid;	offset:24;	size:4;	signed:1;
+	field:long prev_state;	offset:32;	size:8;	signed:1;
+	field:pid_t next_pid;	offset:56;	size:4;	signed:1;
+
+print fmt: \"prev_comm=%s prev_pid=%d\", REC->prev_comm, REC->prev_pid";
+
+    #[test]
+    fn test_assemble() {
+        let fields = parse_format(SWITCH_FORMAT);
+        assert_eq!(fields["prev_comm"], 8);
+        assert_eq!(fields["prev_state"], 32);
+        assert_eq!(fields["next_pid"], 56);
+        assert_eq!(parse_cpu_list("0-3,6\n"), Some(vec![0, 1, 2, 3, 6]));
+        assert_eq!(parse_cpu_list("0"), Some(vec![0]));
+        assert_eq!(parse_cpu_list("a-b"), None);
+
+        let tp = Tracepoint { id: 372, fields };
+        let maps = Maps {
+            last: 3,
+            offcpu: 4,
+            runq: 5,
+        };
+        let insns = switch_program(&tp, &maps).unwrap();
+        // r6 = r1, then exit.
+        assert_eq!(insns[0], 0x16bf);
+        assert_eq!(*insns.last().unwrap(), 0x95);
+        // The jump over the marking of preempted threads as runnable.
+        let jne = insns.iter().position(|i| i & 0xff == 0x55).unwrap();
+        assert_eq!((insns[jne] >> 16) & 0xffff, 1);
+        assert!(wakeup_program(&tp, &maps).is_err());
+    }
+
+    /// Needs root and tracefs, skipped otherwise.
+    #[test]
+    fn test_sampler() {
+        let mut sampler = match OffCpuSampler::open() {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("Skipping, cannot load the eBPF programs: {}", e);
+                return;
+            }
+        };
+        sampler.take().unwrap();
+        for _ in 0..5 {
+            thread::sleep(Duration::from_millis(20));
+        }
+        let by_pid = sampler.take().unwrap();
+        let own = by_pid.get(&Pid::current()).copied().unwrap_or_default();
+        assert!(own.offcpu_ns >= 80_000_000, "{:?}", own);
+    }
+}
@@ -0,0 +1,246 @@
+//! OOM kills from the kernel log.
+//!
+//! When the kernel, or the limit of a memory cgroup, runs out of memory, the OOM killer picks a
+//! victim and logs it to the kernel ring buffer, and nowhere else. `OomWatcher` follows
+//! /dev/kmsg for these messages, and the server logs every kill to the events log of the
+//! datadir (see the `events` module) along with the last recorded status of the victim and the
+//! snapshot it comes from, so what preceded a kill is one lookup away instead of a grep through
+//! the kernel logs.
+//!
+//! Reading /dev/kmsg needs CAP_SYSLOG when the `kernel.dmesg_restrict` sysctl is set. Only the
+//! kills logged after the watcher is opened are reported.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::fs::OpenOptionsExt;
+
+use crate::events::{Event, EventKind};
+use crate::proc_events::epoch_ms;
+use crate::{Pid, PidStatus};
+
+/// Where the kernel log is read from.
+const KMSG: &str = "/dev/kmsg";
+
+/// Records of /dev/kmsg are at most this long, reads with a smaller buffer fail.
+const RECORD_MAX: usize = 8192;
+
+/// OomKill is a process killed by the OOM killer, as logged by the kernel.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OomKill {
+    pub pid: Pid,
+    pub name: String,
+    /// When the kill was logged, in microseconds of CLOCK_MONOTONIC.
+    pub timestamp_us: u64,
+    /// The memory cgroup whose limit was hit, None if the whole host ran out of memory.
+    pub memcg: Option<String>,
+    pub total_vm_kb: Option<u64>,
+    pub anon_rss_kb: Option<u64>,
+    pub file_rss_kb: Option<u64>,
+    pub shmem_rss_kb: Option<u64>,
+}
+
+impl OomKill {
+    /// Returns the event to log. `last` is the epoch and the processes of the last snapshot
+    /// taken before the kill. The victim's status is only taken from it if its name matches, so
+    /// that a reused pid isn't mistaken for the victim.
+    pub fn to_event(self, last: Option<(u64, &HashMap<Pid, PidStatus>)>) -> Event {
+        let (last_snapshot, last_status) = match last {
+            Some((epoch, processes)) => (
+                Some(epoch),
+                processes
+                    .get(&self.pid)
+                    .filter(|s| s.name == self.name)
+                    .map(|s| Box::new(s.clone())),
+            ),
+            None => (None, None),
+        };
+        Event {
+            time_epoch_ms: epoch_ms(self.timestamp_us * 1000),
+            pid: self.pid,
+            kind: EventKind::OomKill {
+                name: self.name,
+                memcg: self.memcg,
+                total_vm_kb: self.total_vm_kb,
+                anon_rss_kb: self.anon_rss_kb,
+                file_rss_kb: self.file_rss_kb,
+                shmem_rss_kb: self.shmem_rss_kb,
+                last_snapshot,
+                last_status,
+            },
+        }
+    }
+}
+
+/// OomWatcher follows the kernel log for OOM kills.
+#[derive(Debug)]
+pub struct OomWatcher {
+    kmsg: File,
+    /// The oom_memcg of the `oom-kill:` summary lines, by pid, until the matching `Killed
+    /// process` line is read.
+    memcgs: HashMap<Pid, String>,
+}
+
+impl OomWatcher {
+    /// Opens /dev/kmsg after its last record. Fails with `PermissionDenied` without CAP_SYSLOG
+    /// on hosts restricting dmesg.
+    pub fn open() -> io::Result<Self> {
+        let mut kmsg = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(KMSG)?;
+        kmsg.seek(SeekFrom::End(0))?;
+        Ok(OomWatcher {
+            kmsg,
+            memcgs: HashMap::new(),
+        })
+    }
+
+    /// Returns the kills logged since the previous call, without waiting for new ones.
+    pub fn take(&mut self) -> io::Result<Vec<OomKill>> {
+        let mut kills = Vec::new();
+        let mut buf = vec![0u8; RECORD_MAX];
+        loop {
+            // Every read returns one record.
+            let n = match self.kmsg.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                // Records were overwritten before they were read, the next read resumes after
+                // them.
+                Err(e) if e.raw_os_error() == Some(libc::EPIPE) => continue,
+                Err(e) => return Err(e),
+            };
+            if let Some(kill) = self.parse_record(&String::from_utf8_lossy(&buf[..n])) {
+                kills.push(kill);
+            }
+        }
+        Ok(kills)
+    }
+
+    /// Parses one record, `<priority>,<sequence>,<timestamp>,<flags>;<message>` followed by
+    /// indented key=value lines.
+    fn parse_record(&mut self, record: &str) -> Option<OomKill> {
+        let (prefix, message) = record.split_once(';')?;
+        let timestamp_us = prefix.split(',').nth(2)?.parse().ok()?;
+        let message = message.lines().next()?;
+        if let Some(summary) = message.strip_prefix("oom-kill:") {
+            let fields: HashMap<&str, &str> = summary
+                .split(',')
+                .filter_map(|kv| kv.split_once('='))
+                .collect();
+            let memcg = match fields.get("constraint") {
+                Some(&"CONSTRAINT_MEMCG") => fields.get("oom_memcg"),
+                _ => None,
+            };
+            if let (Some(pid), Some(memcg)) = (fields.get("pid"), memcg) {
+                self.memcgs.insert(pid.parse().ok()?, memcg.to_string());
+            }
+            return None;
+        }
+        let mut kill = parse_killed(message, timestamp_us)?;
+        kill.memcg = self.memcgs.remove(&kill.pid);
+        Some(kill)
+    }
+}
+
+/// Parses a `Killed process <pid> (<name>) total-vm:<n>kB, anon-rss:<n>kB, ...` message, as
+/// logged after either a host or a memory cgroup ran out of memory.
+fn parse_killed(message: &str, timestamp_us: u64) -> Option<OomKill> {
+    let rest = &message[message.find("Killed process ")? + "Killed process ".len()..];
+    let (pid, rest) = rest.split_once(" (")?;
+    let (name, rest) = rest
+        .rsplit_once(") ")
+        .unwrap_or((rest.trim_end_matches(')'), ""));
+    let kb = |key: &str| {
+        rest.split([',', ' '])
+            .find_map(|field| field.strip_prefix(key))
+            .and_then(|v| v.trim_end_matches("kB").parse().ok())
+    };
+    Some(OomKill {
+        pid: pid.parse().ok()?,
+        name: name.to_string(),
+        timestamp_us,
+        memcg: None,
+        total_vm_kb: kb("total-vm:"),
+        anon_rss_kb: kb("anon-rss:"),
+        file_rss_kb: kb("file-rss:"),
+        shmem_rss_kb: kb("shmem-rss:"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_record() {
+        let mut watcher = OomWatcher {
+            kmsg: File::open("/dev/null").unwrap(),
+            memcgs: HashMap::new(),
+        };
+        assert_eq!(
+            watcher.parse_record("6,1201,5120304,-;oom-kill:constraint=CONSTRAINT_MEMCG,nodemask=(null),cpuset=/,mems_allowed=0,oom_memcg=/system.slice/app.service,task_memcg=/system.slice/app.service,task=java,pid=4242,uid=1000\n"),
+            None
+        );
+        let kill = watcher
+            .parse_record("3,1202,5120311,-;Memory cgroup out of memory: Killed process 4242 (java) total-vm:4194304kB, anon-rss:1048576kB, file-rss:2048kB, shmem-rss:0kB, UID:1000 pgtables:2500kB oom_score_adj:0\n SUBSYSTEM=memory\n")
+            .unwrap();
+        assert_eq!(
+            kill,
+            OomKill {
+                pid: Pid::new(4242),
+                name: "java".to_string(),
+                timestamp_us: 5120311,
+                memcg: Some("/system.slice/app.service".to_string()),
+                total_vm_kb: Some(4194304),
+                anon_rss_kb: Some(1048576),
+                file_rss_kb: Some(2048),
+                shmem_rss_kb: Some(0),
+            }
+        );
+        assert!(watcher.memcgs.is_empty());
+
+        // Host wide, as logged by kernels before 5.0.
+        let kill = watcher
+            .parse_record("3,1300,9000000,-;Killed process 77 (my (weird) name) total-vm:1000kB, anon-rss:500kB, file-rss:0kB, shmem-rss:0kB")
+            .unwrap();
+        assert_eq!(kill.name, "my (weird) name");
+        assert_eq!(kill.memcg, None);
+        assert_eq!(kill.anon_rss_kb, Some(500));
+        assert_eq!(watcher.parse_record("6,1301,9000001,-;eth0: link up"), None);
+
+        let mut status = crate::collect::restricted_pid_status(
+            std::path::Path::new(crate::collect::PROC_ROOT),
+            Pid::current(),
+        );
+        status.name = "my (weird) name".to_string();
+        let mut processes = HashMap::new();
+        processes.insert(Pid::new(77), status.clone());
+        let event = kill.clone().to_event(Some((1563617611, &processes)));
+        assert_eq!(event.pid, Pid::new(77));
+        match event.kind {
+            EventKind::OomKill {
+                last_snapshot,
+                last_status,
+                ..
+            } => {
+                assert_eq!(last_snapshot, Some(1563617611));
+                assert_eq!(last_status, Some(Box::new(status)));
+            }
+            k => panic!("unexpected {:?}", k),
+        }
+        // pid 77 was reused since the snapshot.
+        processes.get_mut(&Pid::new(77)).unwrap().name = "cron".to_string();
+        let line = serde_json::to_string(&kill.to_event(Some((1563617611, &processes)))).unwrap();
+        assert!(line.contains("\"event\":\"oom_kill\""));
+        assert!(line.contains("\"last_status\":null"));
+
+        // Hosts restricting dmesg to CAP_SYSLOG.
+        if let Ok(mut watcher) = OomWatcher::open() {
+            assert!(watcher.take().is_ok());
+        }
+    }
+}
@@ -0,0 +1,203 @@
+//! Checks on the paths built from user input and from recorded values.
+//!
+//! The datadir comes from the command line, and names such as the hostname or the labels of a
+//! snapshot end up in file names and object keys. `validate_datadir` refuses datadirs the server
+//! must never write to, `create_datadir` creates missing ones with restrictive permissions, `component` turns any value into a single harmless path component, and
+//! `join_within` refuses relative paths escaping their root, so that a hostname like `../../etc`
+//! can't direct a write outside of where it belongs.
+
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+/// System directories that are refused as datadir, along with everything below them.
+const FORBIDDEN_DATADIRS: &[&str] = &["/proc", "/sys", "/dev", "/boot", "/etc"];
+
+/// Mode of the directories created by `create_datadir`. Snapshots hold the command line of every
+/// process, so others may not read them.
+pub const DATADIR_MODE: u32 = 0o750;
+
+/// Canonicalizes `datadir` and checks that it is an existing directory the server may fill with
+/// snapshots: not the root directory and not below a system directory such as /proc or /etc.
+pub fn validate_datadir(datadir: &Path) -> io::Result<PathBuf> {
+    let canonical = fs::canonicalize(datadir).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Cannot resolve datadir {}: {}", datadir.display(), e),
+        )
+    })?;
+    if !canonical.is_dir() {
+        return Err(invalid(format!(
+            "datadir {} is not a directory",
+            canonical.display()
+        )));
+    }
+    if canonical == Path::new("/") || below_forbidden(&canonical) {
+        return Err(invalid(format!(
+            "refusing to use {} as datadir",
+            canonical.display()
+        )));
+    }
+    Ok(canonical)
+}
+
+/// Creates `datadir` and its missing parents with `DATADIR_MODE` if it doesn't exist, then
+/// validates it with `validate_datadir`. Missing directories are not created below the system
+/// directories `validate_datadir` refuses, nor through `..` components.
+pub fn create_datadir(datadir: &Path) -> io::Result<PathBuf> {
+    if !datadir.exists() {
+        if datadir.components().any(|c| c == Component::ParentDir) {
+            return Err(invalid(format!(
+                "refusing to create datadir {}, it has .. components",
+                datadir.display()
+            )));
+        }
+        let existing = datadir
+            .ancestors()
+            .skip(1)
+            .find(|a| a.exists())
+            .unwrap_or_else(|| Path::new("."));
+        if below_forbidden(&fs::canonicalize(existing)?) {
+            return Err(invalid(format!(
+                "refusing to use {} as datadir",
+                datadir.display()
+            )));
+        }
+        let mut builder = fs::DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, DATADIR_MODE);
+        builder.create(datadir).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Cannot create datadir {}: {}", datadir.display(), e),
+            )
+        })?;
+    }
+    validate_datadir(datadir)
+}
+
+/// Returns true if `canonical` is, or is below, one of the `FORBIDDEN_DATADIRS`.
+fn below_forbidden(canonical: &Path) -> bool {
+    FORBIDDEN_DATADIRS
+        .iter()
+        .any(|dir| canonical.starts_with(dir))
+}
+
+/// Returns `value` as a single path component: path separators, NUL and other control
+/// characters are replaced with `_`, and so are leading dots, so the result is never `.`, `..`
+/// or a hidden file. An empty value gives `_`.
+pub fn component(value: &str) -> String {
+    let mut leading = true;
+    let sanitized: String = value
+        .chars()
+        .map(|c| {
+            let replace = c == '/' || c == '\\' || c.is_control() || (leading && c == '.');
+            leading = leading && c == '.';
+            if replace {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+    match sanitized.is_empty() {
+        true => "_".to_string(),
+        false => sanitized,
+    }
+}
+
+/// Joins `relative` to `root`, refusing absolute paths and `..` components, so the result is
+/// always below `root`. Symbolic links inside `root` are not resolved.
+pub fn join_within(root: &Path, relative: &Path) -> io::Result<PathBuf> {
+    for c in relative.components() {
+        match c {
+            Component::Normal(_) | Component::CurDir => (),
+            _ => {
+                return Err(invalid(format!(
+                    "refusing {}, it is not below {}",
+                    relative.display(),
+                    root.display()
+                )))
+            }
+        }
+    }
+    Ok(root.join(relative))
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hostile_values() {
+        let hostile = [
+            ("../../etc", "___.._etc"),
+            ("..", "__"),
+            (".", "_"),
+            ("", "_"),
+            ("web-01/../../root", "web-01_.._.._root"),
+            ("/etc/cron.d/x", "_etc_cron.d_x"),
+            ("host\0name\n", "host_name_"),
+            ("..\\..\\windows", "___.._windows"),
+            ("m5.large", "m5.large"),
+            ("eu-west-1a", "eu-west-1a"),
+        ];
+        let root = Path::new("/var/lib/procshot");
+        for (value, expected) in &hostile {
+            let c = component(value);
+            assert_eq!(c, *expected, "{:?}", value);
+            let path = join_within(root, Path::new(&c)).unwrap();
+            assert_eq!(path.parent(), Some(root), "{:?}", value);
+        }
+
+        assert!(join_within(root, Path::new("../../etc/passwd")).is_err());
+        assert!(join_within(root, Path::new("/etc/passwd")).is_err());
+        assert!(join_within(root, Path::new("a/../../b")).is_err());
+        assert_eq!(
+            join_within(root, Path::new("cold/1.procshot.gz")).unwrap(),
+            root.join("cold/1.procshot.gz")
+        );
+    }
+
+    #[test]
+    fn test_validate_datadir() {
+        let dir = std::env::temp_dir().join(format!("procshot_paths_{}", std::process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        assert_eq!(
+            validate_datadir(&dir.join("sub/..")).unwrap(),
+            fs::canonicalize(&dir).unwrap()
+        );
+        fs::write(dir.join("file"), "").unwrap();
+        assert!(validate_datadir(&dir.join("file")).is_err());
+        assert!(validate_datadir(&dir.join("missing")).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(validate_datadir(Path::new("/")).is_err());
+        assert!(validate_datadir(Path::new("/proc/self")).is_err());
+        assert!(validate_datadir(Path::new("/etc/../etc")).is_err());
+    }
+
+    #[test]
+    fn test_create_datadir() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("procshot_create_{}", std::process::id()));
+        let datadir = dir.join("var/data");
+        assert_eq!(
+            create_datadir(&datadir).unwrap(),
+            fs::canonicalize(&datadir).unwrap()
+        );
+        let mode = fs::metadata(&datadir).unwrap().permissions().mode();
+        assert_eq!(mode & 0o027, 0);
+        // Existing ones are left alone.
+        assert!(create_datadir(&datadir).is_ok());
+        assert!(create_datadir(&dir.join("new/../../escape")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(create_datadir(Path::new("/etc/procshot_create_test")).is_err());
+        assert!(!Path::new("/etc/procshot_create_test").exists());
+    }
+}
@@ -0,0 +1,119 @@
+//! The process id type used throughout the crate.
+//!
+//! Linux caps pid_max at 2^22 (PID_MAX_LIMIT), and thread ids share the same space, so a pid
+//! always fits the kernel's 32 bit `pid_t`. `Pid` keeps that representation on disk: it is
+//! serialized exactly like the bare `i32` that older versions stored, so existing snapshot files
+//! keep decoding. The conversions from wider integers are checked, so a pid read from an
+//! untrusted source (a cgroup file, a container runtime, a query string) can't silently wrap.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::num::TryFromIntError;
+use std::str::FromStr;
+
+/// Pid is the id of a process or thread.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct Pid(i32);
+
+impl Pid {
+    pub const fn new(pid: i32) -> Self {
+        Pid(pid)
+    }
+
+    /// Returns the pid as the kernel's `pid_t`, eg: to pass to `procfs::Process::new`.
+    pub const fn as_raw(self) -> i32 {
+        self.0
+    }
+
+    /// Returns the pid of the running process.
+    pub fn current() -> Self {
+        Pid(std::process::id() as i32)
+    }
+}
+
+impl From<i32> for Pid {
+    fn from(pid: i32) -> Self {
+        Pid(pid)
+    }
+}
+
+impl From<Pid> for i32 {
+    fn from(pid: Pid) -> Self {
+        pid.0
+    }
+}
+
+impl From<Pid> for i64 {
+    fn from(pid: Pid) -> Self {
+        i64::from(pid.0)
+    }
+}
+
+impl TryFrom<u32> for Pid {
+    type Error = TryFromIntError;
+
+    fn try_from(pid: u32) -> Result<Self, Self::Error> {
+        i32::try_from(pid).map(Pid)
+    }
+}
+
+impl TryFrom<i64> for Pid {
+    type Error = TryFromIntError;
+
+    fn try_from(pid: i64) -> Result<Self, Self::Error> {
+        i32::try_from(pid).map(Pid)
+    }
+}
+
+impl TryFrom<u64> for Pid {
+    type Error = TryFromIntError;
+
+    fn try_from(pid: u64) -> Result<Self, Self::Error> {
+        i32::try_from(pid).map(Pid)
+    }
+}
+
+impl fmt::Display for Pid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl FromStr for Pid {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(Pid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_same_encoding_as_i32() {
+        let pid = Pid::new(4_194_304);
+        assert_eq!(
+            bincode::serialize(&pid).unwrap(),
+            bincode::serialize(&4_194_304i32).unwrap()
+        );
+        assert_eq!(serde_json::to_string(&pid).unwrap(), "4194304");
+        let decoded: Pid = bincode::deserialize(&bincode::serialize(&42i32).unwrap()).unwrap();
+        assert_eq!(decoded, Pid::new(42));
+    }
+
+    #[test]
+    fn test_conversions() {
+        assert_eq!("1234".parse::<Pid>().unwrap(), Pid::new(1234));
+        assert!("abc".parse::<Pid>().is_err());
+        assert_eq!(Pid::try_from(7u64).unwrap().as_raw(), 7);
+        assert!(Pid::try_from(u64::MAX).is_err());
+        assert!(Pid::try_from(1i64 << 40).is_err());
+        assert_eq!(i64::from(Pid::new(-1)), -1);
+        assert_eq!(Pid::new(99).to_string(), "99");
+    }
+}
@@ -0,0 +1,183 @@
+//! CPU affinity and priority of the server itself.
+//!
+//! On latency sensitive hosts, the time the server spends reading /proc and encoding snapshots
+//! is best kept off the cores of the application. A `Placement` pins every thread of the server
+//! to a set of housekeeping CPUs, and sets its nice value and I/O priority, when the server
+//! starts. Threads started later, like those of the watchdog and the process events, and the
+//! hook and alert commands inherit them from the main thread.
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::priority::{IoPriority, IOPRIO_WHO_PROCESS};
+
+/// CpuList is a set of CPUs, written like the lists of taskset(1) and /sys, eg: `0-3,8`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CpuList(pub BTreeSet<usize>);
+
+impl CpuList {
+    /// Returns the CPUs the calling thread may run on.
+    pub fn current() -> io::Result<Self> {
+        let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+        let size = std::mem::size_of::<libc::cpu_set_t>();
+        if unsafe { libc::sched_getaffinity(0, size, &mut set) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let cpus = (0..libc::CPU_SETSIZE as usize)
+            .filter(|cpu| unsafe { libc::CPU_ISSET(*cpu, &set) })
+            .collect();
+        Ok(CpuList(cpus))
+    }
+
+    fn to_cpu_set(&self) -> libc::cpu_set_t {
+        let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+        for cpu in &self.0 {
+            unsafe { libc::CPU_SET(*cpu, &mut set) };
+        }
+        set
+    }
+}
+
+impl FromStr for CpuList {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "invalid CPU list {}, expected CPUs and ranges, eg: 0-3,8",
+                s
+            )
+        };
+        let mut cpus = BTreeSet::new();
+        for part in s.split(',') {
+            let (first, last) = match part.trim().split_once('-') {
+                Some((first, last)) => (first, last),
+                None => (part.trim(), part.trim()),
+            };
+            let first: usize = first.parse().map_err(|_| invalid())?;
+            let last: usize = last.parse().map_err(|_| invalid())?;
+            if first > last || last >= libc::CPU_SETSIZE as usize {
+                return Err(invalid());
+            }
+            cpus.extend(first..=last);
+        }
+        Ok(CpuList(cpus))
+    }
+}
+
+impl fmt::Display for CpuList {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut ranges: Vec<(usize, usize)> = Vec::new();
+        for cpu in &self.0 {
+            match ranges.last_mut() {
+                Some((_, last)) if *last + 1 == *cpu => *last = *cpu,
+                _ => ranges.push((*cpu, *cpu)),
+            }
+        }
+        let ranges: Vec<String> = ranges
+            .iter()
+            .map(|(first, last)| match first == last {
+                true => first.to_string(),
+                false => format!("{}-{}", first, last),
+            })
+            .collect();
+        f.write_str(&ranges.join(","))
+    }
+}
+
+/// Placement holds where and at which priority the server runs. None leaves the value the
+/// server was started with.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Placement {
+    pub cpus: Option<CpuList>,
+    /// Nice value, from -20 to 19. Values below the current one need CAP_SYS_NICE.
+    pub nice: Option<i32>,
+    /// The realtime class needs CAP_SYS_ADMIN.
+    pub io_priority: Option<IoPriority>,
+}
+
+impl Placement {
+    pub fn is_empty(&self) -> bool {
+        *self == Placement::default()
+    }
+
+    /// Applies the placement to every thread of the process, listed in the proc filesystem at
+    /// `proc_root`. Fails on the first thread it can't be applied to.
+    pub fn apply(&self, proc_root: &Path) -> io::Result<()> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        for entry in fs::read_dir(proc_root.join("self").join("task"))? {
+            let tid = match entry?.file_name().to_str().and_then(|s| s.parse().ok()) {
+                Some(tid) => tid,
+                None => continue,
+            };
+            match self.apply_to_thread(tid) {
+                Ok(()) => (),
+                // The thread exited since the directory was listed.
+                Err(e) if e.raw_os_error() == Some(libc::ESRCH) => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn apply_to_thread(&self, tid: libc::pid_t) -> io::Result<()> {
+        let check = |ret: libc::c_long| match ret {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        };
+        if let Some(cpus) = &self.cpus {
+            let set = cpus.to_cpu_set();
+            let size = std::mem::size_of::<libc::cpu_set_t>();
+            check(unsafe { libc::sched_setaffinity(tid, size, &set) }.into())?;
+        }
+        if let Some(nice) = self.nice {
+            check(
+                unsafe { libc::setpriority(libc::PRIO_PROCESS, tid as libc::id_t, nice) }.into(),
+            )?;
+        }
+        if let Some(io_priority) = self.io_priority {
+            check(unsafe {
+                libc::syscall(
+                    libc::SYS_ioprio_set,
+                    IOPRIO_WHO_PROCESS,
+                    tid,
+                    io_priority.to_raw() as libc::c_int,
+                )
+            })?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_placement() {
+        let cpus: CpuList = "0-3, 8,10-11".parse().unwrap();
+        assert_eq!(
+            cpus.0.iter().copied().collect::<Vec<_>>(),
+            vec![0, 1, 2, 3, 8, 10, 11]
+        );
+        assert_eq!(cpus.to_string(), "0-3,8,10-11");
+        assert!("3-1".parse::<CpuList>().is_err());
+        assert!("0,x".parse::<CpuList>().is_err());
+        assert!("4096".parse::<CpuList>().is_err());
+
+        // Pinning the tests to the CPUs they already run on changes nothing.
+        let placement = Placement {
+            cpus: Some(CpuList::current().unwrap()),
+            ..Default::default()
+        };
+        placement.apply(Path::new("/proc")).unwrap();
+        assert_eq!(placement.cpus, Some(CpuList::current().unwrap()));
+        assert!(Placement::default().is_empty());
+    }
+}
@@ -0,0 +1,30 @@
+//! Stable re-exports of the commonly used types and functions.
+//!
+//! ```rust
+//! use procshot_server::prelude::*;
+//! ```
+//!
+//! Modules are still being rearranged, but everything exported here keeps its name and path
+//! within a semver compatible release: an item is only removed or renamed in the next breaking
+//! release, after being deprecated here. Downstream code importing from the prelude instead of
+//! the individual modules is not affected by internal moves.
+
+pub use crate::backend::{DirBackend, StorageBackend};
+pub use crate::cpu::CpuTimes;
+pub use crate::error::ProcshotError;
+#[cfg(feature = "server")]
+pub use crate::hook::PostWriteHook;
+pub use crate::query::{
+    files_in_range, par_map, par_map_backend, par_map_slim, ParallelReader, SnapshotStore,
+};
+#[cfg(feature = "server")]
+pub use crate::sink::StorageSink;
+pub use crate::store::{find_snapshot, read_snapshot, snapshot_files};
+pub use crate::summary::SnapshotSummary;
+pub use crate::tz::TimeZone;
+pub use crate::units::ByteFormat;
+pub use crate::{EncoDecode, Pid, PidStatus};
+#[cfg(feature = "server")]
+pub use crate::{
+    scan_proc, scan_proc_with_options, snapshot, snapshot_in, Command, Config, ScanOptions,
+};
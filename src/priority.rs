@@ -0,0 +1,311 @@
+//! Changes of the scheduling and I/O priority of processes.
+//!
+//! A process reniced or ioniced by some automation looks the same as any other in the snapshots
+//! taken afterwards. `PriorityTracker` compares the priorities of every process with those of
+//! the previous iteration and gives a `priority` event for each change, with the values before
+//! and after, which the server appends to the events log (see the `events` module). Changes made
+//! and undone between two snapshots are not seen.
+//!
+//! The scheduling policy and real-time priority are also recorded in every snapshot, in
+//! `PidStatus::sched_policy` and `PidStatus::rt_priority`, so a process that was started with a
+//! real-time policy is found as well.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::events::{Event, EventKind};
+#[cfg(feature = "server")]
+use crate::procfile::Stat;
+use crate::Pid;
+
+#[cfg(feature = "server")]
+pub(crate) const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+const IOPRIO_CLASS_SHIFT: u32 = 13;
+
+/// SchedPolicy is the CPU scheduling policy of a process, see sched(7).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SchedPolicy {
+    /// SCHED_OTHER, the default time-sharing policy.
+    Other,
+    Fifo,
+    RoundRobin,
+    Batch,
+    Idle,
+    Deadline,
+}
+
+impl SchedPolicy {
+    /// Decodes a SCHED_* constant, as in /proc/<pid>/stat. Returns None for an unknown policy.
+    pub fn from_raw(policy: u32) -> Option<Self> {
+        match policy {
+            0 => Some(SchedPolicy::Other),
+            1 => Some(SchedPolicy::Fifo),
+            2 => Some(SchedPolicy::RoundRobin),
+            3 => Some(SchedPolicy::Batch),
+            5 => Some(SchedPolicy::Idle),
+            6 => Some(SchedPolicy::Deadline),
+            _ => None,
+        }
+    }
+
+    /// Returns true for the real-time policies, whose processes preempt all the others.
+    pub fn is_realtime(self) -> bool {
+        matches!(
+            self,
+            SchedPolicy::Fifo | SchedPolicy::RoundRobin | SchedPolicy::Deadline
+        )
+    }
+}
+
+impl fmt::Display for SchedPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            SchedPolicy::Other => "other",
+            SchedPolicy::Fifo => "fifo",
+            SchedPolicy::RoundRobin => "round_robin",
+            SchedPolicy::Batch => "batch",
+            SchedPolicy::Idle => "idle",
+            SchedPolicy::Deadline => "deadline",
+        };
+        f.write_str(name)
+    }
+}
+
+/// IoClass is the I/O scheduling class set with ionice.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IoClass {
+    /// No class was set, the process gets the best effort class with a level derived from its
+    /// nice value.
+    None,
+    Realtime,
+    BestEffort,
+    Idle,
+}
+
+/// IoPriority is the I/O scheduling class of a process and its level within the class, 0 being
+/// the highest priority.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IoPriority {
+    pub class: IoClass,
+    pub level: u8,
+}
+
+impl IoPriority {
+    /// Decodes a value returned by ioprio_get(2). Returns None for an unknown class.
+    pub fn from_raw(ioprio: u32) -> Option<Self> {
+        let class = match ioprio >> IOPRIO_CLASS_SHIFT {
+            0 => IoClass::None,
+            1 => IoClass::Realtime,
+            2 => IoClass::BestEffort,
+            3 => IoClass::Idle,
+            _ => return None,
+        };
+        Some(IoPriority {
+            class,
+            level: (ioprio & ((1 << IOPRIO_CLASS_SHIFT) - 1)) as u8,
+        })
+    }
+
+    /// Encodes the priority for ioprio_set(2).
+    pub fn to_raw(self) -> u32 {
+        let class = match self.class {
+            IoClass::None => 0,
+            IoClass::Realtime => 1,
+            IoClass::BestEffort => 2,
+            IoClass::Idle => 3,
+        };
+        class << IOPRIO_CLASS_SHIFT | u32::from(self.level)
+    }
+
+    /// Returns the I/O priority of `pid`, or None if it can't be read.
+    #[cfg(feature = "server")]
+    pub fn of_pid(pid: Pid) -> Option<Self> {
+        let ioprio = unsafe {
+            libc::syscall(
+                libc::SYS_ioprio_get,
+                IOPRIO_WHO_PROCESS,
+                pid.as_raw() as libc::c_int,
+            )
+        };
+        if ioprio < 0 {
+            return None;
+        }
+        Self::from_raw(ioprio as u32)
+    }
+}
+
+/// Parses a class as ionice(1) names them, `idle`, `best-effort` or `realtime`, followed by
+/// `:<level>` for the last two, eg: `best-effort:7`. The level defaults to 4, like ionice's.
+impl FromStr for IoPriority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "invalid I/O priority {}, expected idle, best-effort[:<0-7>] or realtime[:<0-7>]",
+                s
+            )
+        };
+        let (class, level) = match s.split_once(':') {
+            Some((class, level)) => (class, Some(level.parse::<u8>().map_err(|_| invalid())?)),
+            None => (s, None),
+        };
+        let class = match class {
+            "idle" if level.is_none() => IoClass::Idle,
+            "best-effort" => IoClass::BestEffort,
+            "realtime" => IoClass::Realtime,
+            _ => return Err(invalid()),
+        };
+        match level.unwrap_or(if class == IoClass::Idle { 0 } else { 4 }) {
+            level @ 0..=7 => Ok(IoPriority { class, level }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Priority is the scheduling and I/O priority of a process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Priority {
+    /// Nice value, from -20 to 19.
+    pub nice: i64,
+    /// Scheduling policy, as the SCHED_* constants of sched(7).
+    pub policy: u32,
+    /// Real-time priority, 0 unless the policy is a real-time one.
+    pub rt_priority: u32,
+    /// Missing if it couldn't be read.
+    pub io: Option<IoPriority>,
+}
+
+impl Priority {
+    /// Returns the priority of the process of `stat`, as of when its stat was read.
+    #[cfg(feature = "server")]
+    pub fn of(stat: &Stat) -> Self {
+        Priority {
+            nice: stat.nice,
+            policy: stat.policy.unwrap_or_default(),
+            rt_priority: stat.rt_priority.unwrap_or_default(),
+            io: IoPriority::of_pid(stat.pid),
+        }
+    }
+}
+
+/// PriorityTracker remembers the priority of every process between iterations.
+#[derive(Debug, Default)]
+pub struct PriorityTracker {
+    /// The start time of every process, to tell a pid that was reused, and its priority.
+    last: HashMap<Pid, (u64, Priority)>,
+}
+
+impl PriorityTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Takes the processes of the current iteration, keyed by pid with their start time, name and
+    /// priority, and returns a `priority` event at `time_epoch_ms` for each process whose priority
+    /// changed since the previous call. Processes missing from `current` are forgotten.
+    pub fn update(
+        &mut self,
+        current: HashMap<Pid, (u64, String, Priority)>,
+        time_epoch_ms: u64,
+    ) -> Vec<Event> {
+        let mut events = Vec::new();
+        let mut last = HashMap::with_capacity(current.len());
+        for (pid, (start_time, name, after)) in current {
+            if let Some((previous_start, before)) = self.last.get(&pid) {
+                if *previous_start == start_time && *before != after {
+                    events.push(Event {
+                        time_epoch_ms,
+                        pid,
+                        kind: EventKind::Priority {
+                            name,
+                            before: *before,
+                            after,
+                        },
+                    });
+                }
+            }
+            last.insert(pid, (start_time, after));
+        }
+        self.last = last;
+        events.sort_by_key(|e| e.pid);
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn priority(nice: i64, ioprio: u32) -> Priority {
+        Priority {
+            nice,
+            policy: 0,
+            rt_priority: 0,
+            io: IoPriority::from_raw(ioprio),
+        }
+    }
+
+    fn iteration(processes: &[(i32, u64, Priority)]) -> HashMap<Pid, (u64, String, Priority)> {
+        processes
+            .iter()
+            .map(|(pid, start, p)| (Pid::new(*pid), (*start, "backup".to_string(), *p)))
+            .collect()
+    }
+
+    #[test]
+    fn test_priority_changes() {
+        assert_eq!(
+            IoPriority::from_raw(3 << 13),
+            Some(IoPriority {
+                class: IoClass::Idle,
+                level: 0
+            })
+        );
+        assert_eq!(IoPriority::from_raw(7 << 13), None);
+        let background: IoPriority = "best-effort:7".parse().unwrap();
+        assert_eq!(IoPriority::from_raw(background.to_raw()), Some(background));
+        assert_eq!("idle".parse::<IoPriority>().unwrap().to_raw(), 3 << 13);
+        assert_eq!("realtime".parse::<IoPriority>().unwrap().level, 4);
+        assert!("idle:3".parse::<IoPriority>().is_err());
+        assert!("best-effort:8".parse::<IoPriority>().is_err());
+        assert_eq!(SchedPolicy::from_raw(2), Some(SchedPolicy::RoundRobin));
+        assert!(SchedPolicy::from_raw(6).unwrap().is_realtime());
+        assert!(!SchedPolicy::from_raw(5).unwrap().is_realtime());
+        assert_eq!(SchedPolicy::from_raw(4), None);
+        assert_eq!(
+            serde_json::to_string(&SchedPolicy::RoundRobin).unwrap(),
+            format!("\"{}\"", SchedPolicy::RoundRobin)
+        );
+        #[cfg(feature = "server")]
+        assert!(IoPriority::of_pid(Pid::current()).is_some());
+
+        let mut tracker = PriorityTracker::new();
+        let normal = priority(0, 0);
+        assert!(tracker
+            .update(iteration(&[(10, 100, normal), (11, 100, normal)]), 1000)
+            .is_empty());
+        // 10 is reniced and ioniced to idle, 11 exits and its pid is reused.
+        let reniced = priority(19, 3 << 13);
+        let events = tracker.update(iteration(&[(10, 100, reniced), (11, 200, reniced)]), 2000);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].pid, Pid::new(10));
+        assert_eq!(
+            events[0].kind,
+            EventKind::Priority {
+                name: "backup".to_string(),
+                before: normal,
+                after: reniced
+            }
+        );
+        assert!(serde_json::to_string(&events[0])
+            .unwrap()
+            .contains("\"event\":\"priority\""));
+        assert!(tracker
+            .update(iteration(&[(10, 100, reniced)]), 3000)
+            .is_empty());
+    }
+}
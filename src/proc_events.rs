@@ -0,0 +1,304 @@
+//! Process exec and exit events from the kernel's proc connector.
+//!
+//! The proc connector is a netlink multicast group on which the kernel announces every fork,
+//! exec and exit, with a nanosecond timestamp. Listening to it records the short-lived processes
+//! that start and end between two snapshots, without taking snapshots more often: `spawn` starts
+//! a thread appending the exec and exit events of processes (not of individual threads) to the
+//! events log of the datadir, see the `events` module.
+//!
+//! The command lines of the execs go through the `RedactPolicy` of the server before they are
+//! logged. Subscribing needs CAP_NET_ADMIN. If the socket buffer overflows during a burst of events, the
+//! events in it are lost, which is logged.
+
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::PathBuf;
+use std::thread;
+use std::thread::JoinHandle;
+
+use crate::events::{Event, EventKind, EventLog};
+use crate::redact::RedactPolicy;
+use crate::Pid;
+
+const CN_IDX_PROC: u32 = 1;
+const CN_VAL_PROC: u32 = 1;
+const PROC_CN_MCAST_LISTEN: u32 = 1;
+const PROC_EVENT_EXEC: u32 = 0x0000_0002;
+const PROC_EVENT_EXIT: u32 = 0x8000_0000;
+
+/// Size of struct nlmsghdr.
+const NLMSG_HDRLEN: usize = 16;
+/// Size of struct cn_msg, without its payload.
+const CN_MSG_LEN: usize = 20;
+/// Size of the what, cpu and timestamp_ns fields that start struct proc_event.
+const PROC_EVENT_HDRLEN: usize = 16;
+
+/// ProcEvent is an event of the proc connector, with its timestamp in nanoseconds of
+/// CLOCK_MONOTONIC.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProcEvent {
+    Exec {
+        pid: Pid,
+        timestamp_ns: u64,
+    },
+    /// `status` is the exit status as returned by wait(2).
+    Exit {
+        pid: Pid,
+        timestamp_ns: u64,
+        status: i32,
+    },
+}
+
+/// ProcConnector is a netlink socket subscribed to the proc connector.
+#[derive(Debug)]
+pub struct ProcConnector {
+    fd: OwnedFd,
+}
+
+impl ProcConnector {
+    /// Opens the socket and subscribes to the events. Fails with `PermissionDenied` without
+    /// CAP_NET_ADMIN.
+    pub fn connect() -> io::Result<Self> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+                libc::NETLINK_CONNECTOR,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups = CN_IDX_PROC;
+        let bound = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if bound < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let connector = ProcConnector { fd };
+        connector.send(&subscribe_message())?;
+        Ok(connector)
+    }
+
+    fn send(&self, message: &[u8]) -> io::Result<()> {
+        let sent = unsafe {
+            libc::send(
+                self.fd.as_raw_fd(),
+                message.as_ptr() as *const libc::c_void,
+                message.len(),
+                0,
+            )
+        };
+        if sent < 0 {
+            // The kernel refuses the subscription of unprivileged processes.
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Blocks until the next exec or exit event of a process. Fails with the `ENOBUFS` OS error
+    /// if events were lost.
+    pub fn next_event(&self) -> io::Result<ProcEvent> {
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = unsafe {
+                libc::recv(
+                    self.fd.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                    0,
+                )
+            };
+            if n < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+            if let Some(event) = parse(&buf[..n as usize]) {
+                return Ok(event);
+            }
+        }
+    }
+}
+
+/// Returns the netlink message subscribing to the proc connector.
+fn subscribe_message() -> Vec<u8> {
+    let len = NLMSG_HDRLEN + CN_MSG_LEN + 4;
+    let mut msg = Vec::with_capacity(len);
+    // struct nlmsghdr
+    msg.extend_from_slice(&(len as u32).to_ne_bytes());
+    msg.extend_from_slice(&(libc::NLMSG_DONE as u16).to_ne_bytes());
+    msg.extend_from_slice(&0u16.to_ne_bytes());
+    msg.extend_from_slice(&0u32.to_ne_bytes());
+    msg.extend_from_slice(&std::process::id().to_ne_bytes());
+    // struct cn_msg
+    msg.extend_from_slice(&CN_IDX_PROC.to_ne_bytes());
+    msg.extend_from_slice(&CN_VAL_PROC.to_ne_bytes());
+    msg.extend_from_slice(&0u32.to_ne_bytes());
+    msg.extend_from_slice(&0u32.to_ne_bytes());
+    msg.extend_from_slice(&4u16.to_ne_bytes());
+    msg.extend_from_slice(&0u16.to_ne_bytes());
+    msg.extend_from_slice(&PROC_CN_MCAST_LISTEN.to_ne_bytes());
+    msg
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> Option<u32> {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(buf.get(offset..offset + 4)?);
+    Some(u32::from_ne_bytes(bytes))
+}
+
+fn u64_at(buf: &[u8], offset: usize) -> Option<u64> {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(buf.get(offset..offset + 8)?);
+    Some(u64::from_ne_bytes(bytes))
+}
+
+/// Parses a netlink message of the proc connector. Returns None for the other events, for the
+/// events of threads that aren't the thread group leader, and for anything malformed.
+fn parse(buf: &[u8]) -> Option<ProcEvent> {
+    if u32_at(buf, NLMSG_HDRLEN)? != CN_IDX_PROC || u32_at(buf, NLMSG_HDRLEN + 4)? != CN_VAL_PROC {
+        return None;
+    }
+    let event = NLMSG_HDRLEN + CN_MSG_LEN;
+    let what = u32_at(buf, event)?;
+    let timestamp_ns = u64_at(buf, event + 8)?;
+    let data = event + PROC_EVENT_HDRLEN;
+    let pid = u32_at(buf, data)? as i32;
+    let tgid = u32_at(buf, data + 4)? as i32;
+    if pid != tgid {
+        return None;
+    }
+    let pid = Pid::new(pid);
+    match what {
+        PROC_EVENT_EXEC => Some(ProcEvent::Exec { pid, timestamp_ns }),
+        PROC_EVENT_EXIT => Some(ProcEvent::Exit {
+            pid,
+            timestamp_ns,
+            status: u32_at(buf, data + 8)? as i32,
+        }),
+        _ => None,
+    }
+}
+
+fn clock_ns(clock: libc::clockid_t) -> u64 {
+    let mut ts: libc::timespec = unsafe { mem::zeroed() };
+    unsafe {
+        libc::clock_gettime(clock, &mut ts);
+    }
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+/// Converts a CLOCK_MONOTONIC timestamp to milliseconds since the Unix epoch.
+pub(crate) fn epoch_ms(timestamp_ns: u64) -> u64 {
+    let offset = clock_ns(libc::CLOCK_REALTIME).saturating_sub(clock_ns(libc::CLOCK_MONOTONIC));
+    (offset + timestamp_ns) / 1_000_000
+}
+
+impl ProcEvent {
+    /// Returns the event to log. The name and command line of an exec are read from /proc, as
+    /// the connector only gives the pid.
+    pub fn to_event(self) -> Event {
+        match self {
+            ProcEvent::Exec { pid, timestamp_ns } => {
+                let prc = procfs::Process::new(pid.as_raw()).ok();
+                Event {
+                    time_epoch_ms: epoch_ms(timestamp_ns),
+                    pid,
+                    kind: EventKind::Exec {
+                        name: prc.as_ref().map(|p| p.stat.comm.clone()),
+                        cmd_long: prc.and_then(|p| p.cmdline().ok()).unwrap_or_default(),
+                    },
+                }
+            }
+            ProcEvent::Exit {
+                pid,
+                timestamp_ns,
+                status,
+            } => Event {
+                time_epoch_ms: epoch_ms(timestamp_ns),
+                pid,
+                kind: EventKind::Exit {
+                    code: Some(libc::WEXITSTATUS(status)).filter(|_| libc::WIFEXITED(status)),
+                    signal: Some(libc::WTERMSIG(status)).filter(|_| libc::WIFSIGNALED(status)),
+                },
+            },
+        }
+    }
+}
+
+/// Subscribes to the proc connector and starts a thread appending the events to the events log
+/// of `datadir`, with the command lines redacted by `redact`. Fails if the subscription or the log
+/// can't be opened.
+pub fn spawn(datadir: PathBuf, redact: RedactPolicy) -> io::Result<JoinHandle<()>> {
+    let connector = ProcConnector::connect()?;
+    let mut log = EventLog::open(&datadir)?;
+    Ok(thread::spawn(move || loop {
+        match connector.next_event() {
+            Ok(event) => {
+                let mut event = event.to_event();
+                if let EventKind::Exec { cmd_long, .. } = &mut event.kind {
+                    redact.redact_args(cmd_long);
+                }
+                if let Err(e) = log.append(&event) {
+                    eprintln!("Cannot write to the events log!, err: {}", e);
+                }
+            }
+            Err(e) if e.raw_os_error() == Some(libc::ENOBUFS) => {
+                eprintln!("Process events were lost, the events log is incomplete")
+            }
+            Err(e) => {
+                eprintln!("Cannot read process events, stopping, err: {}", e);
+                return;
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(what: u32, pid: u32, tgid: u32, status: u32) -> Vec<u8> {
+        let mut msg = subscribe_message();
+        msg.truncate(NLMSG_HDRLEN + CN_MSG_LEN);
+        msg.extend_from_slice(&what.to_ne_bytes());
+        msg.extend_from_slice(&0u32.to_ne_bytes());
+        msg.extend_from_slice(&1_500_000_000u64.to_ne_bytes());
+        for field in &[pid, tgid, status, 0] {
+            msg.extend_from_slice(&field.to_ne_bytes());
+        }
+        msg
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse(&message(PROC_EVENT_EXEC, 42, 42, 0)),
+            Some(ProcEvent::Exec {
+                pid: Pid::new(42),
+                timestamp_ns: 1_500_000_000
+            })
+        );
+        let exit = parse(&message(PROC_EVENT_EXIT, 42, 42, 9)).unwrap();
+        match exit.to_event().kind {
+            EventKind::Exit { code, signal } => assert_eq!((code, signal), (None, Some(9))),
+            k => panic!("unexpected event {:?}", k),
+        }
+        // A thread exiting, and a fork.
+        assert_eq!(parse(&message(PROC_EVENT_EXIT, 43, 42, 0)), None);
+        assert_eq!(parse(&message(0x1, 42, 42, 0)), None);
+        assert_eq!(parse(&[0u8; 8]), None);
+    }
+}
@@ -0,0 +1,84 @@
+//! I/O counters from /proc/<pid>/io.
+//!
+//! CPU and memory don't show which process keeps the disks busy. The io file counts the bytes
+//! each process made the storage layer read and write, and its read and write system calls.
+//! Only the owner of a process, or root, may read it (ptrace access mode), so the counters of
+//! other users' processes are missing unless the server runs as root.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::Pid;
+
+/// ProcIo holds the counters of /proc/<pid>/io, since the process started.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct ProcIo {
+    /// Bytes the process caused to be fetched from the storage layer.
+    pub read_bytes: u64,
+    /// Bytes the process caused to be sent to the storage layer, when dirtied.
+    pub write_bytes: u64,
+    /// Number of read system calls, eg: read(2) and pread(2).
+    pub syscr: u64,
+    /// Number of write system calls, eg: write(2) and pwrite(2).
+    pub syscw: u64,
+    /// Bytes counted in `write_bytes` that were never written, eg: of a file truncated while
+    /// dirty in the page cache.
+    pub cancelled_write_bytes: u64,
+}
+
+impl ProcIo {
+    /// Parses the `key: value` lines of an io file.
+    pub fn parse(content: &str) -> Option<ProcIo> {
+        let field = |key: &str| {
+            content.lines().find_map(|line| {
+                let (k, v) = line.split_once(':')?;
+                match k == key {
+                    true => v.trim().parse().ok(),
+                    false => None,
+                }
+            })
+        };
+        Some(ProcIo {
+            read_bytes: field("read_bytes")?,
+            write_bytes: field("write_bytes")?,
+            syscr: field("syscr")?,
+            syscw: field("syscw")?,
+            cancelled_write_bytes: field("cancelled_write_bytes")?,
+        })
+    }
+}
+
+/// Reads <pid>/io under `proc_root`. Fails with `PermissionDenied` for the processes of other
+/// users when not running as root.
+pub fn read_in(proc_root: &Path, pid: Pid) -> io::Result<ProcIo> {
+    let path = proc_root.join(pid.to_string()).join("io");
+    let content = fs::read_to_string(&path)?;
+    ProcIo::parse(&content).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Cannot parse {}: {}", path.display(), content.trim_end()),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let io = ProcIo::parse(
+            "rchar: 323934931\nwchar: 323929600\nsyscr: 632687\nsyscw: 632675\n\
+             read_bytes: 4096\nwrite_bytes: 323932160\ncancelled_write_bytes: 8192\n",
+        )
+        .unwrap();
+        assert_eq!(io.read_bytes, 4096);
+        assert_eq!(io.write_bytes, 323932160);
+        assert_eq!(io.syscr, 632687);
+        assert_eq!(io.syscw, 632675);
+        assert_eq!(io.cancelled_write_bytes, 8192);
+        assert_eq!(ProcIo::parse("rchar: 1\n"), None);
+        assert!(read_in(Path::new("/proc"), Pid::current()).unwrap().syscr > 0);
+    }
+}
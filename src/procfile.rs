@@ -0,0 +1,287 @@
+//! Parsing of the per-process files of /proc, on top of the procfs crate.
+//!
+//! `Stat` and `Status` hold the fields of /proc/<pid>/stat and /proc/<pid>/status the server
+//! uses. Both are parsed with procfs first. When procfs can't parse a file, eg: a kernel added,
+//! removed or changed a field it expects, the file is parsed again with the text parsers of this
+//! module, which only look at the fields they need. procfs panics on some values it doesn't
+//! understand, those panics are caught. Fields procfs doesn't know about at all can be read with
+//! `status_field` and `stat_field`, so newer kernel data can be captured without waiting for an
+//! upstream release.
+
+use std::panic;
+use std::str;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use crate::Pid;
+
+/// Flag of the kernel threads, from include/linux/sched.h.
+const PF_KTHREAD: u32 = 0x0020_0000;
+
+/// Stat is what the server reads from /proc/<pid>/stat.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stat {
+    pub pid: Pid,
+    /// The filename of the executable, without the parentheses.
+    pub comm: String,
+    pub state: char,
+    pub ppid: Pid,
+    /// The PF_* flags of the kernel, see `is_kernel_thread`.
+    pub flags: u32,
+    /// Time scheduled in user mode, in clock ticks.
+    pub utime: u64,
+    /// Time scheduled in kernel mode, in clock ticks.
+    pub stime: u64,
+    /// Nice value, from -20 to 19.
+    pub nice: i64,
+    /// Time the process started after boot, in clock ticks.
+    pub starttime: u64,
+    /// Virtual memory size, in bytes.
+    pub vsize: u64,
+    /// Resident set size, in pages.
+    pub rss: i64,
+    /// Soft limit of the rss, in bytes.
+    pub rsslim: u64,
+    /// CPU number last executed on.
+    pub processor: Option<i32>,
+    pub rt_priority: Option<u32>,
+    /// Scheduling policy, as the SCHED_* constants of sched(7).
+    pub policy: Option<u32>,
+}
+
+/// Status is what the server reads from /proc/<pid>/status.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Status {
+    pub name: String,
+    /// The state and its description, eg: `S (sleeping)`.
+    pub state: String,
+    pub ppid: Pid,
+    pub tracerpid: Pid,
+    pub euid: i32,
+    /// Number of file descriptor slots allocated.
+    pub fdsize: u32,
+    /// Peak virtual memory size, in kB. Missing for kernel threads.
+    pub vmpeak: Option<u64>,
+    /// Virtual memory size, in kB.
+    pub vmsize: Option<u64>,
+}
+
+/// Returns the page size of the system, in bytes.
+fn page_size() -> i64 {
+    static PAGE_SIZE: OnceLock<i64> = OnceLock::new();
+    *PAGE_SIZE.get_or_init(|| procfs::page_size().unwrap_or(4096))
+}
+
+/// Runs a procfs parser, returning None if it panics.
+fn catch<T>(parse: impl FnOnce() -> Option<T>) -> Option<T> {
+    panic::catch_unwind(panic::AssertUnwindSafe(parse))
+        .ok()
+        .flatten()
+}
+
+impl Stat {
+    /// Parses the content of /proc/<pid>/stat.
+    pub fn parse(content: &[u8]) -> Option<Self> {
+        catch(|| procfs::Stat::from_reader(content))
+            .map(Stat::from)
+            .or_else(|| Stat::parse_text(str::from_utf8(content).ok()?))
+    }
+
+    /// Returns true if the process is a kernel thread, flagged with PF_KTHREAD.
+    pub fn is_kernel_thread(&self) -> bool {
+        self.flags & PF_KTHREAD != 0
+    }
+
+    /// Resident set size, in bytes.
+    pub fn rss_bytes(&self) -> i64 {
+        self.rss * page_size()
+    }
+
+    /// Parses the fields by their position, see proc(5). The fields added after Linux 2.6 are
+    /// optional.
+    fn parse_text(content: &str) -> Option<Self> {
+        let content = content.trim_end();
+        let open = content.find('(')?;
+        let close = content.rfind(')')?;
+        // Fields from the state on, numbered from 3 as in proc(5).
+        let fields: Vec<&str> = content.get(close + 2..)?.split(' ').collect();
+        let field = |n: usize| fields.get(n - 3).copied();
+        Some(Stat {
+            pid: number(Some(content[..open].trim()))?,
+            comm: content[open + 1..close].to_string(),
+            state: field(3)?.chars().next()?,
+            ppid: number(field(4))?,
+            // Flags procfs can't parse don't flag a kernel thread.
+            flags: number(field(9)).unwrap_or(0),
+            utime: number(field(14))?,
+            stime: number(field(15))?,
+            nice: number(field(19))?,
+            starttime: number(field(22))?,
+            vsize: number(field(23))?,
+            rss: number(field(24))?,
+            rsslim: number(field(25))?,
+            processor: number(field(39)),
+            rt_priority: number(field(40)),
+            policy: number(field(41)),
+        })
+    }
+}
+
+impl From<procfs::Stat> for Stat {
+    fn from(s: procfs::Stat) -> Self {
+        Stat {
+            pid: Pid::new(s.pid),
+            comm: s.comm,
+            state: s.state,
+            ppid: Pid::new(s.ppid),
+            flags: s.flags,
+            utime: s.utime,
+            stime: s.stime,
+            nice: s.nice,
+            starttime: s.starttime as u64,
+            vsize: s.vsize,
+            rss: s.rss,
+            rsslim: s.rsslim,
+            processor: s.processor,
+            rt_priority: s.rt_priority,
+            policy: s.policy,
+        }
+    }
+}
+
+impl Status {
+    /// Parses the content of /proc/<pid>/status.
+    pub fn parse(content: &[u8]) -> Option<Self> {
+        catch(|| procfs::Status::from_reader(content))
+            .map(Status::from)
+            .or_else(|| Status::parse_text(str::from_utf8(content).ok()?))
+    }
+
+    fn parse_text(content: &str) -> Option<Self> {
+        let field = |key| status_field(content, key);
+        let kb = |key| number(field(key).map(|v| v.trim_end_matches("kB").trim()));
+        Some(Status {
+            name: field("Name")?.to_string(),
+            state: field("State")?.to_string(),
+            ppid: number(field("PPid"))?,
+            tracerpid: number(field("TracerPid"))?,
+            // Real, effective, saved set and filesystem uids.
+            euid: number(field("Uid")?.split_whitespace().nth(1))?,
+            fdsize: number(field("FDSize"))?,
+            vmpeak: kb("VmPeak"),
+            vmsize: kb("VmSize"),
+        })
+    }
+}
+
+impl From<procfs::Status> for Status {
+    fn from(s: procfs::Status) -> Self {
+        Status {
+            name: s.name,
+            state: s.state,
+            ppid: Pid::new(s.ppid),
+            tracerpid: Pid::new(s.tracerpid),
+            euid: s.euid,
+            fdsize: s.fdsize,
+            vmpeak: s.vmpeak,
+            vmsize: s.vmsize,
+        }
+    }
+}
+
+/// Parses a field, None if it's missing or invalid.
+fn number<T: FromStr>(s: Option<&str>) -> Option<T> {
+    s?.parse().ok()
+}
+
+/// Returns the value of `key` in a `Key:<tab>value` file like /proc/<pid>/status, eg:
+/// `status_field(content, "Kthread")`.
+pub fn status_field<'a>(content: &'a str, key: &str) -> Option<&'a str> {
+    content.lines().find_map(|line| {
+        let (k, value) = line.split_once(':')?;
+        match k == key {
+            true => Some(value.trim()),
+            false => None,
+        }
+    })
+}
+
+/// Returns field `n` of /proc/<pid>/stat, numbered from 1 as in proc(5), eg: 52 for the exit
+/// code. The comm, which may contain spaces, counts as the single field 2.
+pub fn stat_field(content: &str, n: usize) -> Option<&str> {
+    let open = content.find('(')?;
+    let close = content.rfind(')')?;
+    match n {
+        0 => None,
+        1 => Some(content[..open].trim()),
+        2 => content.get(open + 1..close),
+        n => content.get(close + 2..)?.trim_end().split(' ').nth(n - 3),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_parse_text_like_procfs() {
+        let stat = fs::read("/proc/self/stat").unwrap();
+        let text = Stat::parse_text(str::from_utf8(&stat).unwrap()).unwrap();
+        let procfs = Stat::from(procfs::Stat::from_reader(&stat[..]).unwrap());
+        assert_eq!(text, procfs);
+        assert_eq!(text.pid, Pid::current());
+
+        let status = fs::read("/proc/self/status").unwrap();
+        let text = Status::parse_text(str::from_utf8(&status).unwrap()).unwrap();
+        let procfs = Status::from(procfs::Status::from_reader(&status[..]).unwrap());
+        assert_eq!(text, procfs);
+        assert!(text.vmpeak.is_some());
+    }
+
+    #[test]
+    fn test_fallback() {
+        // A comm with spaces and parentheses, flags procfs can't parse and fields of a kernel
+        // from the future.
+        let mut fields = vec!["0"; 54 - 2];
+        let values = [
+            (3, "S"),
+            (4, "1"),
+            (9, "x"),
+            (14, "12"),
+            (15, "34"),
+            (19, "5"),
+            (22, "987"),
+            (24, "250"),
+            (39, "3"),
+            (52, "7"),
+            (54, "98"),
+        ];
+        for (n, value) in &values {
+            fields[n - 3] = value;
+        }
+        let stat = format!("42 (a (b) c) {}\n", fields.join(" "));
+        let stat = stat.as_str();
+        let s = Stat::parse(stat.as_bytes()).unwrap();
+        assert_eq!(s.comm, "a (b) c");
+        assert_eq!((s.utime, s.stime, s.nice), (12, 34, 5));
+        assert_eq!((s.starttime, s.rss), (987, 250));
+        assert_eq!(s.processor, Some(3));
+        assert_eq!(stat_field(stat, 2), Some("a (b) c"));
+        assert_eq!(stat_field(stat, 52), Some("7"));
+        assert_eq!(stat_field(stat, 54), Some("98"));
+        assert_eq!(stat_field(stat, 55), None);
+
+        // Missing fields procfs requires, a value it can't parse and one it doesn't know.
+        let status = "Name:\tworker\nUmask:\tzz\nState:\tS (sleeping)\nTgid:\t42\nPid:\t42\n\
+                      PPid:\t1\nTracerPid:\t0\nUid:\t1000\t1001\t1000\t1000\n\
+                      Gid:\t1000\t1000\t1000\t1000\nFDSize:\t64\nVmPeak:\t  2048 kB\n\
+                      VmSize:\t  1024 kB\nKthread:\t0\n";
+        let s = Status::parse(status.as_bytes()).unwrap();
+        assert_eq!(s.name, "worker");
+        assert_eq!((s.euid, s.fdsize), (1001, 64));
+        assert_eq!((s.vmpeak, s.vmsize), (Some(2048), Some(1024)));
+        assert_eq!(status_field(status, "Kthread"), Some("0"));
+        assert_eq!(Status::parse(b"Name:\tworker\n"), None);
+    }
+}
@@ -0,0 +1,287 @@
+//! Parallel execution of queries over a range of snapshot files.
+//!
+//! Decoding is what dominates a scan over a week of one minute snapshots, and every file decodes
+//! independently, so the files are spread over a bounded pool of worker threads. Results are
+//! always handed back in file (time) order, whatever order the workers finish in.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::backend::StorageBackend;
+use crate::error::ProcshotError;
+use crate::slim::SlimSnapshot;
+use crate::{store, EncoDecode};
+
+/// Returns the number of workers to use when none is configured: one per available CPU.
+pub fn default_workers() -> usize {
+    thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+}
+
+/// Returns the snapshot files of `datadir` taken between `from` and `to` (epoch seconds,
+/// inclusive), oldest first. Only file names are looked at, nothing is decoded.
+pub fn files_in_range(datadir: &Path, from: u64, to: u64) -> io::Result<Vec<(u64, PathBuf)>> {
+    Ok(store::snapshot_files(datadir)?
+        .into_iter()
+        .filter(|(epoch, _)| *epoch >= from && *epoch <= to)
+        .collect())
+}
+
+/// Decodes every file on `workers` threads and applies `f` to each snapshot. The results are
+/// returned in the order of `files`, paired with the file's epoch. Only the output of `f` is kept
+/// in memory, so `f` should reduce the snapshot to what the query needs.
+pub fn par_map<T, F>(files: &[(u64, PathBuf)], workers: usize, f: F) -> Vec<(u64, io::Result<T>)>
+where
+    T: Send,
+    F: Fn(&EncoDecode) -> T + Sync,
+{
+    par_map_with(files, workers, |path| store::read_snapshot(path), f)
+}
+
+/// Same as `par_map`, decoding only the fields of a `SlimSnapshot`, which is much cheaper for
+/// queries that don't need the command lines.
+pub fn par_map_slim<T, F>(
+    files: &[(u64, PathBuf)],
+    workers: usize,
+    f: F,
+) -> Vec<(u64, io::Result<T>)>
+where
+    T: Send,
+    F: Fn(&SlimSnapshot) -> T + Sync,
+{
+    par_map_with(files, workers, |path| store::read_slim_snapshot(path), f)
+}
+
+/// Same as `par_map`, for the snapshots of `backend` taken at `epochs`, eg: as returned by
+/// `StorageBackend::list_range`.
+pub fn par_map_backend<T, F>(
+    backend: &dyn StorageBackend,
+    epochs: &[u64],
+    workers: usize,
+    f: F,
+) -> Vec<(u64, io::Result<T>)>
+where
+    T: Send,
+    F: Fn(&EncoDecode) -> T + Sync,
+{
+    let items: Vec<(u64, u64)> = epochs.iter().map(|e| (*e, *e)).collect();
+    par_map_with(&items, workers, |epoch| backend.read_snapshot(*epoch), f)
+}
+
+/// Reads every item with `read` on `workers` threads and applies `f` to each snapshot, keeping
+/// the order of `items`. A single worker reads on the calling thread.
+fn par_map_with<K, S, T, R, F>(
+    items: &[(u64, K)],
+    workers: usize,
+    read: R,
+    f: F,
+) -> Vec<(u64, io::Result<T>)>
+where
+    K: Sync,
+    T: Send,
+    R: Fn(&K) -> io::Result<S> + Sync,
+    F: Fn(&S) -> T + Sync,
+{
+    // Without threads to spare, or to spawn at all, eg: on wasm32.
+    if workers <= 1 {
+        return items
+            .iter()
+            .map(|(epoch, item)| (*epoch, read(item).map(|s| f(&s))))
+            .collect();
+    }
+    let next = AtomicUsize::new(0);
+    let slots: Vec<Mutex<Option<io::Result<T>>>> = items.iter().map(|_| Mutex::new(None)).collect();
+    thread::scope(|scope| {
+        for _ in 0..workers.max(1).min(items.len().max(1)) {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                if i >= items.len() {
+                    break;
+                }
+                let result = read(&items[i].1).map(|s| f(&s));
+                *slots[i].lock().unwrap() = Some(result);
+            });
+        }
+    });
+    items
+        .iter()
+        .zip(slots)
+        .map(|((epoch, _), slot)| (*epoch, slot.into_inner().unwrap().unwrap()))
+        .collect()
+}
+
+/// ParallelReader yields the decoded snapshots of a list of files in order, decoding up to
+/// `2 * workers` files ahead in parallel. Unlike `par_map`, memory use is bounded by the batch
+/// size rather than the number of files.
+pub struct ParallelReader {
+    files: Vec<(u64, PathBuf)>,
+    position: usize,
+    workers: usize,
+    batch: std::vec::IntoIter<(u64, io::Result<EncoDecode>)>,
+}
+
+impl ParallelReader {
+    pub fn new(files: Vec<(u64, PathBuf)>, workers: usize) -> Self {
+        ParallelReader {
+            files,
+            position: 0,
+            workers: workers.max(1),
+            batch: Vec::new().into_iter(),
+        }
+    }
+}
+
+impl Iterator for ParallelReader {
+    type Item = (u64, io::Result<EncoDecode>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(item) = self.batch.next() {
+            return Some(item);
+        }
+        if self.position >= self.files.len() {
+            return None;
+        }
+        let end = (self.position + 2 * self.workers).min(self.files.len());
+        let batch = par_map(&self.files[self.position..end], self.workers, |s| s.clone());
+        self.position = end;
+        self.batch = batch.into_iter();
+        self.batch.next()
+    }
+}
+
+/// SnapshotStore reads the snapshots of a datadir by time range. The range is matched against
+/// the times in the file names, so only the files in it are opened, whatever the size of the
+/// archive.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnapshotStore {
+    datadir: PathBuf,
+}
+
+impl SnapshotStore {
+    /// Opens `datadir`. Fails if it isn't a readable directory.
+    pub fn open<P: AsRef<Path>>(datadir: P) -> io::Result<Self> {
+        let datadir = datadir.as_ref();
+        fs::read_dir(datadir)?;
+        Ok(SnapshotStore {
+            datadir: datadir.to_path_buf(),
+        })
+    }
+
+    pub fn datadir(&self) -> &Path {
+        &self.datadir
+    }
+
+    /// Returns the snapshot files of both tiers taken between `from` and `to` (inclusive, to the
+    /// millisecond for the sub-second snapshots), oldest first, with their epoch.
+    pub fn files(&self, from: SystemTime, to: SystemTime) -> io::Result<Vec<(u64, PathBuf)>> {
+        let millis = |t: SystemTime| {
+            t.duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis() as u64)
+        };
+        let (from, to) = (millis(from), millis(to));
+        Ok(files_in_range(&self.datadir, from / 1000, to / 1000)?
+            .into_iter()
+            .filter(|(_, path)| {
+                store::snapshot_millis(path).is_some_and(|ms| ms >= from && ms <= to)
+            })
+            .collect())
+    }
+
+    /// Returns the snapshots taken between `from` and `to`, oldest first. Files are read and
+    /// decoded one at a time as the iterator advances, and a file that fails to read yields its
+    /// error without ending the iteration. Failing to list the datadir yields one error.
+    pub fn query(
+        &self,
+        from: SystemTime,
+        to: SystemTime,
+    ) -> impl Iterator<Item = Result<EncoDecode, ProcshotError>> {
+        let (files, error) = match self.files(from, to) {
+            Ok(files) => (files, None),
+            Err(e) => (Vec::new(), Some(ProcshotError::reading(&self.datadir, e))),
+        };
+        error.into_iter().map(Err).chain(
+            files
+                .into_iter()
+                .map(|(_, path)| EncoDecode::from_file(path)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn write_snapshots(dir: &Path, epochs: &[u64]) {
+        fs::create_dir_all(dir).unwrap();
+        for epoch in epochs {
+            let s = EncoDecode {
+                hostname: "localghost".to_string(),
+                time_epoch: *epoch,
+                delay: std::time::Duration::from_secs(60),
+                total_cpu_time: *epoch * 10,
+                ..Default::default()
+            };
+            fs::write(
+                dir.join(format!("{}.procshot", epoch)),
+                bincode::serialize(&s).unwrap(),
+            )
+            .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_par_map_keeps_order() {
+        let dir = std::env::temp_dir().join(format!("procshot_query_{}", std::process::id()));
+        let epochs: Vec<u64> = (1..=20).map(|i| 1000 + i * 60).collect();
+        write_snapshots(&dir, &epochs);
+        fs::write(dir.join("sketches.bin"), "not a snapshot").unwrap();
+
+        let files = files_in_range(&dir, 1120, 1600).unwrap();
+        assert_eq!(files.len(), 9);
+        let results = par_map(&files, 4, |s| s.total_cpu_time);
+        let got: Vec<u64> = results.into_iter().map(|(_, r)| r.unwrap()).collect();
+        let want: Vec<u64> = files.iter().map(|(e, _)| e * 10).collect();
+        assert_eq!(got, want);
+        let inline = par_map(&files, 1, |s| s.total_cpu_time);
+        assert_eq!(inline.len(), files.len());
+
+        let all = files_in_range(&dir, 0, u64::MAX).unwrap();
+        let read: Vec<u64> = ParallelReader::new(all, 3)
+            .map(|(_, s)| s.unwrap().time_epoch)
+            .collect();
+        assert_eq!(read, epochs);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_store() {
+        use std::time::Duration;
+
+        let dir = std::env::temp_dir().join(format!("procshot_query_store_{}", std::process::id()));
+        let epochs: Vec<u64> = (0..10).map(|i| 1000 + i * 60).collect();
+        write_snapshots(&dir, &epochs);
+        fs::write(dir.join("1030.500.procshot"), "not a snapshot").unwrap();
+
+        let store = SnapshotStore::open(&dir).unwrap();
+        let at = |millis: u64| UNIX_EPOCH + Duration::from_millis(millis);
+        let read: Vec<u64> = store
+            .query(at(1_060_000), at(1_180_000))
+            .map(|s| s.unwrap().time_epoch)
+            .collect();
+        assert_eq!(read, vec![1060, 1120, 1180]);
+        assert_eq!(store.files(at(1_030_000), at(1_030_499)).unwrap().len(), 0);
+        let results: Vec<_> = store.query(at(1_000_000), at(1_030_500)).collect();
+        assert_eq!(results.len(), 2);
+        assert!(results[1].is_err());
+        assert_eq!(store.query(at(2_000_000), at(3_000_000)).count(), 0);
+        assert!(SnapshotStore::open(dir.join("missing")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,211 @@
+//! Soft real-time mode, for consumers that need evenly spaced snapshots above all else.
+//!
+//! The plain server loop sleeps for the delay after each iteration, so the spacing of the
+//! snapshots is the delay plus the time the iteration took, and varies with it. Control loops
+//! fed with the snapshots need that spacing to be regular more than they need the snapshots to
+//! be complete. In real-time mode the iterations start on a fixed grid of ticks, see `Ticker`,
+//! and an iteration running past a tick skips it instead of making the next one start late. The
+//! enrichment collectors, which read more files per process, are turned off (see
+//! `ScanOptions::skip_enrichment`), and the memory of the server is locked so that page faults
+//! don't delay it. How late each iteration starts after its tick, its jitter, is measured by
+//! `JitterStats`, in buffers allocated once: iterations later than the bound are logged, and a
+//! summary is printed every `REPORT_EVERY` iterations.
+
+use std::fmt;
+use std::io;
+use std::time::{Duration, Instant};
+
+/// Number of iterations summarised by a `JitterReport`.
+pub const REPORT_EVERY: usize = 60;
+
+/// RealtimeMode holds the settings of the real-time mode.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RealtimeMode {
+    /// Iterations starting later than this after their tick are logged.
+    pub max_jitter: Duration,
+}
+
+/// Ticker places the iterations on a grid of ticks `delay` apart, from the first one.
+#[derive(Debug)]
+pub struct Ticker {
+    origin: Instant,
+    delay: Duration,
+    /// Tick of the running iteration.
+    tick: u32,
+}
+
+impl Ticker {
+    /// Starts the grid at `origin`, the tick of the first iteration.
+    pub fn new(delay: Duration, origin: Instant) -> Self {
+        Ticker {
+            origin,
+            // Ticks must be apart for the skipped ones to be counted.
+            delay: delay.max(Duration::from_nanos(1)),
+            tick: 0,
+        }
+    }
+
+    fn at(&self, tick: u32) -> Instant {
+        self.origin + self.delay * tick
+    }
+
+    /// Returns how late an iteration starting at `now` is after its tick.
+    pub fn jitter(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.at(self.tick))
+    }
+
+    /// Moves to the tick of the next iteration, after the running one ended at `now`. Returns
+    /// the tick, and the number of ticks skipped because the iteration ran past them.
+    pub fn advance(&mut self, now: Instant) -> (Instant, u32) {
+        let next = self.tick.saturating_add(1);
+        let late = now.saturating_duration_since(self.at(next)).as_nanos();
+        let delay = self.delay.as_nanos();
+        let skipped = late.div_ceil(delay).min(u32::MAX as u128) as u32;
+        self.tick = next.saturating_add(skipped);
+        (self.at(self.tick), skipped)
+    }
+}
+
+/// JitterReport summarises the jitter of the iterations since the previous report.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct JitterReport {
+    pub iterations: usize,
+    pub p50: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+    /// Iterations that started later than `max_jitter`.
+    pub late: u64,
+    pub max_jitter: Duration,
+    /// Ticks skipped by iterations running past them.
+    pub skipped: u64,
+}
+
+impl fmt::Display for JitterReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Jitter of the last {} iterations: p50 {:?}, p99 {:?}, max {:?}, {} above {:?}, {} ticks skipped",
+            self.iterations, self.p50, self.p99, self.max, self.late, self.max_jitter, self.skipped
+        )
+    }
+}
+
+/// JitterStats accumulates the jitter of the iterations until the next report.
+#[derive(Debug)]
+pub struct JitterStats {
+    max_jitter: Duration,
+    window: Vec<Duration>,
+    /// Where `window` is sorted for the percentiles, allocated along with it.
+    sorted: Vec<Duration>,
+    late: u64,
+    skipped: u64,
+}
+
+impl JitterStats {
+    pub fn new(max_jitter: Duration) -> Self {
+        JitterStats {
+            max_jitter,
+            window: Vec::with_capacity(REPORT_EVERY),
+            sorted: Vec::with_capacity(REPORT_EVERY),
+            late: 0,
+            skipped: 0,
+        }
+    }
+
+    pub fn max_jitter(&self) -> Duration {
+        self.max_jitter
+    }
+
+    /// Records the jitter of an iteration. Returns true if it is above `max_jitter`.
+    pub fn record(&mut self, jitter: Duration) -> bool {
+        self.window.push(jitter);
+        let late = jitter > self.max_jitter;
+        self.late += late as u64;
+        late
+    }
+
+    /// Records ticks skipped by an iteration.
+    pub fn skip(&mut self, ticks: u32) {
+        self.skipped += u64::from(ticks);
+    }
+
+    /// Returns the report of the last `REPORT_EVERY` iterations once they were recorded, and
+    /// starts over.
+    pub fn report(&mut self) -> Option<JitterReport> {
+        if self.window.len() < REPORT_EVERY {
+            return None;
+        }
+        self.sorted.clear();
+        self.sorted.extend_from_slice(&self.window);
+        self.sorted.sort_unstable();
+        let percentile = |p: usize| self.sorted[(self.sorted.len() - 1) * p / 100];
+        let report = JitterReport {
+            iterations: self.sorted.len(),
+            p50: percentile(50),
+            p99: percentile(99),
+            max: percentile(100),
+            late: self.late,
+            max_jitter: self.max_jitter,
+            skipped: self.skipped,
+        };
+        self.window.clear();
+        self.late = 0;
+        self.skipped = 0;
+        Some(report)
+    }
+}
+
+/// Locks the current and future memory of the server in RAM. Needs CAP_IPC_LOCK, or an
+/// RLIMIT_MEMLOCK above the memory of the server.
+pub fn lock_memory() -> io::Result<()> {
+    match unsafe { libc::mlockall(libc::MCL_CURRENT | libc::MCL_FUTURE) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ticker() {
+        let origin = Instant::now();
+        let second = Duration::from_secs(1);
+        let mut ticker = Ticker::new(second, origin);
+        assert_eq!(
+            ticker.jitter(origin + Duration::from_millis(3)),
+            Duration::from_millis(3)
+        );
+        // A short iteration waits for the next tick.
+        assert_eq!(
+            ticker.advance(origin + Duration::from_millis(300)),
+            (origin + second, 0)
+        );
+        assert_eq!(ticker.jitter(origin), Duration::from_secs(0));
+        // One running past two ticks skips them.
+        let (tick, skipped) = ticker.advance(origin + Duration::from_millis(3500));
+        assert_eq!((tick, skipped), (origin + 4 * second, 2));
+        let (tick, skipped) = ticker.advance(origin + 5 * second);
+        assert_eq!((tick, skipped), (origin + 5 * second, 0));
+    }
+
+    #[test]
+    fn test_jitter_stats() {
+        let mut stats = JitterStats::new(Duration::from_millis(5));
+        for i in 0..REPORT_EVERY as u64 - 1 {
+            assert!(!stats.record(Duration::from_micros(i * 10)));
+            assert_eq!(stats.report(), None);
+        }
+        assert!(stats.record(Duration::from_millis(8)));
+        stats.skip(1);
+        let report = stats.report().unwrap();
+        assert_eq!(report.iterations, REPORT_EVERY);
+        assert_eq!(report.p50, Duration::from_micros(290));
+        assert_eq!(report.p99, Duration::from_micros(580));
+        assert_eq!(report.max, Duration::from_millis(8));
+        assert_eq!((report.late, report.skipped), (1, 1));
+        assert_eq!(stats.window.capacity(), REPORT_EVERY);
+        assert_eq!(stats.report(), None);
+    }
+}
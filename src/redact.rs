@@ -0,0 +1,136 @@
+//! Redaction of the command lines at collection time.
+//!
+//! Command lines carry secrets more often than they should: `--password=...`, tokens in URLs,
+//! keys passed to a one-off script. Rewriting the archive afterwards still leaves them on disk in
+//! the meantime, and in backups of the datadir. The server applies a `RedactPolicy` to every
+//! command line it reads, before the process is stored, logged to the events log or handed to
+//! an alert, so the sensitive arguments never reach the disk.
+//!
+//! A rule is a regular expression, in the syntax of the regex crate, and the replacement of its
+//! matches, given as `<regex>=><replacement>`, eg: `(--password=).*=>${1}***`. The replacement
+//! may refer to the groups of the match with `$1` or `${name}`, and defaults to `<redacted>` when
+//! omitted. Rules are applied in order to each argument on its own, so `--password secret`, in two
+//! arguments, needs a rule on the value rather than on the flag. The environment of the processes
+//! is never read, so it needs no rule.
+
+use std::borrow::Cow;
+use std::fmt;
+use std::str::FromStr;
+
+use regex::Regex;
+
+use crate::PidStatus;
+
+/// Separator of the regex and the replacement of a rule.
+const SEPARATOR: &str = "=>";
+
+/// Replacement of the rules that don't give one.
+pub const REDACTED: &str = "<redacted>";
+
+/// RedactRule replaces the matches of a regex in the arguments of a command line.
+#[derive(Debug, Clone)]
+pub struct RedactRule {
+    pub pattern: Regex,
+    pub replacement: String,
+}
+
+impl FromStr for RedactRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pattern, replacement) = match s.rsplit_once(SEPARATOR) {
+            Some((pattern, replacement)) => (pattern, replacement),
+            None => (s, REDACTED),
+        };
+        if pattern.is_empty() {
+            return Err(format!(
+                "invalid redaction rule {}, expected <regex>[=><replacement>]",
+                s
+            ));
+        }
+        Ok(RedactRule {
+            pattern: Regex::new(pattern)
+                .map_err(|e| format!("invalid regex in redaction rule {}: {}", s, e))?,
+            replacement: replacement.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for RedactRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}{}", self.pattern, SEPARATOR, self.replacement)
+    }
+}
+
+/// RedactPolicy is the rules applied to every command line read. The default policy has no rule
+/// and leaves the command lines untouched.
+#[derive(Debug, Clone, Default)]
+pub struct RedactPolicy {
+    rules: Vec<RedactRule>,
+}
+
+impl RedactPolicy {
+    pub fn new(rules: Vec<RedactRule>) -> Self {
+        RedactPolicy { rules }
+    }
+
+    /// Returns true if the policy has no rule.
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Applies the rules to each argument of `args`, in place.
+    pub fn redact_args(&self, args: &mut [String]) {
+        for arg in args.iter_mut() {
+            for rule in &self.rules {
+                if let Cow::Owned(redacted) =
+                    rule.pattern.replace_all(arg, rule.replacement.as_str())
+                {
+                    *arg = redacted;
+                }
+            }
+        }
+    }
+
+    /// Redacts the command line of a process.
+    pub fn apply(&self, status: &mut PidStatus) {
+        self.redact_args(&mut status.cmd_long);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact() {
+        let policy = RedactPolicy::new(vec![
+            "(--password=).*=>${1}***".parse().unwrap(),
+            "[A-Za-z0-9]{20,}".parse().unwrap(),
+        ]);
+        let mut args: Vec<String> = [
+            "/usr/bin/mysql",
+            "--user=app",
+            "--password=hunter2",
+            "https://api.example.com/?token=AKIAABCDEFGHIJKLMNOPQRST",
+        ]
+        .iter()
+        .map(|a| a.to_string())
+        .collect();
+        policy.redact_args(&mut args);
+        assert_eq!(
+            args,
+            vec![
+                "/usr/bin/mysql",
+                "--user=app",
+                "--password=***",
+                "https://api.example.com/?token=<redacted>",
+            ]
+        );
+        assert!(RedactPolicy::default().is_empty());
+        assert!("=>x".parse::<RedactRule>().is_err());
+        assert!("(unclosed".parse::<RedactRule>().is_err());
+        let rule: RedactRule = "secret".parse().unwrap();
+        assert_eq!(rule.to_string(), "secret=><redacted>");
+    }
+}
@@ -0,0 +1,1432 @@
+//! Reports computed over a range of stored snapshots.
+//!
+//! Every report is a library function returning typed results, plus a `print_*` function rendering
+//! them as the table shown by the `report` subcommand, and a `markdown_*` function rendering them
+//! as Markdown tables for `--format md`. Reports list the annotations of the period they cover,
+//! see the `annotations` module.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::annotations::{self, Annotation};
+use crate::client::SortBy;
+use crate::metrics;
+use crate::tz::{TimeError, TimeZone};
+use crate::units::{self, ByteFormat};
+use crate::{query, EncoDecode, Pid};
+
+/// Number of rows printed per table when none is given.
+pub const DEFAULT_TOP_N: usize = 10;
+
+/// Snapshots of a host further apart than this many times its delay leave a gap, unless another
+/// tolerance is given to `gaps`.
+pub const DEFAULT_GAP_TOLERANCE: f64 = 2.0;
+
+/// ReportKind is the report selected with the `report` subcommand.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReportKind {
+    /// Processes with the largest rss and fd growth over the last `window`.
+    Growth {
+        window: Duration,
+        top: usize,
+        format: ReportFormat,
+    },
+    /// Per process name changes in average CPU and rss between two periods.
+    Compare {
+        baseline: TimeRange,
+        current: TimeRange,
+        thresholds: Thresholds,
+        top: usize,
+        format: ReportFormat,
+    },
+    /// Percentiles of a process metric across the hosts of several datadirs, see the `fleet`
+    /// module.
+    Fleet(crate::fleet::FleetQuery),
+    /// Intervals without snapshots per host, see `gaps`.
+    Gaps {
+        range: TimeRange,
+        /// Datadirs to read, the datadir of the command line if empty.
+        datadirs: Vec<PathBuf>,
+        tolerance: f64,
+        format: ReportFormat,
+    },
+}
+
+/// TopProcess is the usage of one process in a snapshot, or over the snapshots of a range it was
+/// seen in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopProcess {
+    pub pid: Pid,
+    pub name: String,
+    /// user + sys CPU usage, in percent of the CPU time of the host. Over a range, the average over
+    /// every snapshot of the range, the ones the process isn't in counting as 0, so it ranks the
+    /// processes by the CPU time they used.
+    pub cpu: f64,
+    /// rss, the peak over a range.
+    pub rss_bytes: i64,
+    /// Number of snapshots the process was seen in.
+    pub snapshots: usize,
+}
+
+/// TopReport is the result of `top_n`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopReport {
+    pub range: TimeRange,
+    pub sort_by: SortBy,
+    /// Number of snapshots read.
+    pub snapshots: usize,
+    /// Number of snapshot files that could not be decoded and were skipped.
+    pub skipped: usize,
+    /// The heaviest processes, the heaviest first.
+    pub processes: Vec<TopProcess>,
+    /// The annotations of the range, oldest first.
+    pub annotations: Vec<Annotation>,
+}
+
+/// Sorts `processes` by `sort_by`, the heaviest first, and keeps the first `n`.
+fn sort_top(mut processes: Vec<TopProcess>, sort_by: SortBy, n: usize) -> Vec<TopProcess> {
+    let key = |p: &TopProcess| match sort_by {
+        SortBy::Memory => p.rss_bytes as f64,
+        SortBy::Cpu => p.cpu,
+    };
+    processes.sort_by(|a, b| key(b).total_cmp(&key(a)).then(a.pid.cmp(&b.pid)));
+    processes.truncate(n);
+    processes
+}
+
+fn top_of_snapshot(snapshot: &EncoDecode, sort_by: SortBy, n: usize) -> Vec<TopProcess> {
+    let processes = snapshot
+        .pid_map_list
+        .iter()
+        .map(|(pid, s)| TopProcess {
+            pid: *pid,
+            name: s.name.clone(),
+            cpu: s.user_cpu_usage + s.sys_cpu_usage,
+            rss_bytes: s.rss_bytes,
+            snapshots: 1,
+        })
+        .collect();
+    sort_top(processes, sort_by, n)
+}
+
+/// The `n` processes of `snapshot` using the most CPU.
+pub fn top_n_by_cpu(snapshot: &EncoDecode, n: usize) -> Vec<TopProcess> {
+    top_of_snapshot(snapshot, SortBy::Cpu, n)
+}
+
+/// The `n` processes of `snapshot` with the largest rss.
+pub fn top_n_by_rss(snapshot: &EncoDecode, n: usize) -> Vec<TopProcess> {
+    top_of_snapshot(snapshot, SortBy::Memory, n)
+}
+
+/// Returns the `n` processes that used the most CPU, or had the largest peak rss, over the
+/// snapshots of `range`, decoding them on `workers` threads. Like `growth`, a pid that shows up
+/// with a different name is taken as another process.
+pub fn top_n(
+    datadir: &Path,
+    range: TimeRange,
+    sort_by: SortBy,
+    n: usize,
+    workers: usize,
+) -> io::Result<TopReport> {
+    let files = query::files_in_range(datadir, range.from, range.to)?;
+    let samples = query::par_map_slim(&files, workers, |s| {
+        s.processes
+            .iter()
+            .map(|p| {
+                (
+                    (p.pid, p.name.clone()),
+                    (p.user_cpu_usage + p.sys_cpu_usage, p.rss_bytes),
+                )
+            })
+            .collect::<Vec<_>>()
+    });
+    let (mut snapshots, mut skipped) = (0, 0);
+    let mut seen: HashMap<(Pid, String), TopProcess> = HashMap::new();
+    for (_, sample) in samples {
+        let sample = match sample {
+            Ok(s) => s,
+            Err(_) => {
+                skipped += 1;
+                continue;
+            }
+        };
+        snapshots += 1;
+        for ((pid, name), (cpu, rss)) in sample {
+            let entry = seen
+                .entry((pid, name.clone()))
+                .or_insert_with(|| TopProcess {
+                    pid,
+                    name,
+                    cpu: 0.0,
+                    rss_bytes: rss,
+                    snapshots: 0,
+                });
+            entry.cpu += cpu;
+            entry.rss_bytes = entry.rss_bytes.max(rss);
+            entry.snapshots += 1;
+        }
+    }
+    let processes = seen
+        .into_values()
+        .map(|mut p| {
+            p.cpu /= snapshots as f64;
+            p
+        })
+        .collect();
+    Ok(TopReport {
+        range,
+        sort_by,
+        snapshots,
+        skipped,
+        processes: sort_top(processes, sort_by, n),
+        annotations: annotations::read(datadir, range.from, range.to)?,
+    })
+}
+
+/// TimeRange is a period of time, in epoch seconds, both ends inclusive.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimeRange {
+    pub from: u64,
+    pub to: u64,
+}
+
+impl TimeRange {
+    /// Parses `<from>..<to>`, where both ends are times accepted by `TimeZone::parse_at`, in the
+    /// time zone `tz`. eg: `2019-07-20 10:00:00..2019-07-20 12:00:00` or `2h ago..now`.
+    pub fn parse(s: &str, tz: &TimeZone) -> Result<Self, TimeError> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let (from, to) = s
+            .split_once("..")
+            .ok_or_else(|| TimeError::NotARange(s.to_string()))?;
+        let range = TimeRange {
+            from: tz.parse_at(from, now)?,
+            to: tz.parse_at(to, now)?,
+        };
+        if range.from > range.to {
+            return Err(TimeError::Reversed(s.to_string()));
+        }
+        Ok(range)
+    }
+}
+
+/// Delta holds the value of a counter at the start and at the end of a period.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Delta {
+    pub start: i64,
+    pub end: i64,
+}
+
+impl Delta {
+    pub fn absolute(&self) -> i64 {
+        self.end - self.start
+    }
+
+    /// Growth relative to the start value, in percent. None if the start value is 0.
+    pub fn percent(&self) -> Option<f64> {
+        match self.start {
+            0 => None,
+            start => Some(100.0 * self.absolute() as f64 / start as f64),
+        }
+    }
+}
+
+/// ProcessGrowth is the growth of one process between the first and the last snapshot of the
+/// window it appears in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProcessGrowth {
+    pub pid: Pid,
+    pub name: String,
+    /// Epoch of the first snapshot of the window the process was seen in.
+    pub first_seen: u64,
+    /// Epoch of the last snapshot of the window the process was seen in.
+    pub last_seen: u64,
+    pub rss_bytes: Delta,
+    /// Growth of `fdsize`, the number of file descriptor slots, which the kernel grows as the
+    /// process opens more files.
+    pub fds: Delta,
+}
+
+/// GrowthReport is the result of `growth`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GrowthReport {
+    pub from: u64,
+    pub to: u64,
+    /// Number of snapshots read.
+    pub snapshots: usize,
+    /// Number of snapshot files that could not be decoded and were skipped.
+    pub skipped: usize,
+    /// Every process seen in at least two snapshots of the window, in no particular order.
+    pub processes: Vec<ProcessGrowth>,
+    /// The annotations of the window, oldest first.
+    pub annotations: Vec<Annotation>,
+}
+
+impl GrowthReport {
+    /// The `n` processes whose rss grew the most, in bytes.
+    pub fn top_rss(&self, n: usize) -> Vec<&ProcessGrowth> {
+        self.top_by(n, |p| p.rss_bytes.absolute())
+    }
+
+    /// The `n` processes whose file descriptor table grew the most.
+    pub fn top_fds(&self, n: usize) -> Vec<&ProcessGrowth> {
+        self.top_by(n, |p| p.fds.absolute())
+    }
+
+    fn top_by<F: Fn(&ProcessGrowth) -> i64>(&self, n: usize, key: F) -> Vec<&ProcessGrowth> {
+        let mut sorted: Vec<&ProcessGrowth> =
+            self.processes.iter().filter(|p| key(p) > 0).collect();
+        sorted.sort_by(|a, b| key(b).cmp(&key(a)).then(a.pid.cmp(&b.pid)));
+        sorted.truncate(n);
+        sorted
+    }
+}
+
+/// Computes the rss and fd growth of every process between `from` and `to` (epoch seconds,
+/// inclusive), decoding the snapshots on `workers` threads. A pid that shows up with a different
+/// name is taken as a new process, so pid reuse doesn't count as growth.
+pub fn growth(datadir: &Path, from: u64, to: u64, workers: usize) -> io::Result<GrowthReport> {
+    let files = query::files_in_range(datadir, from, to)?;
+    let samples = query::par_map_slim(&files, workers, |s| {
+        s.processes
+            .iter()
+            .map(|p| ((p.pid, p.name.clone()), (p.rss_bytes, i64::from(p.fdsize))))
+            .collect::<HashMap<_, _>>()
+    });
+
+    let mut report = GrowthReport {
+        from,
+        to,
+        snapshots: 0,
+        skipped: 0,
+        processes: Vec::new(),
+        annotations: annotations::read(datadir, from, to)?,
+    };
+    let mut seen: HashMap<(Pid, String), ProcessGrowth> = HashMap::new();
+    for (epoch, sample) in samples {
+        let sample = match sample {
+            Ok(s) => s,
+            Err(_) => {
+                report.skipped += 1;
+                continue;
+            }
+        };
+        report.snapshots += 1;
+        for ((pid, name), (rss, fds)) in sample {
+            let entry = seen
+                .entry((pid, name.clone()))
+                .or_insert_with(|| ProcessGrowth {
+                    pid,
+                    name,
+                    first_seen: epoch,
+                    last_seen: epoch,
+                    rss_bytes: Delta {
+                        start: rss,
+                        end: rss,
+                    },
+                    fds: Delta {
+                        start: fds,
+                        end: fds,
+                    },
+                });
+            entry.last_seen = epoch;
+            entry.rss_bytes.end = rss;
+            entry.fds.end = fds;
+        }
+    }
+    report.processes = seen
+        .into_values()
+        .filter(|p| p.last_seen > p.first_seen)
+        .collect();
+    Ok(report)
+}
+
+/// Thresholds below which a change between two periods is not significant. A change must pass
+/// both its absolute and the relative threshold.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Thresholds {
+    /// Minimum change, in percent of the baseline value.
+    pub min_percent: f64,
+    /// Minimum change of the CPU usage, in percentage points of the CPU time of the host.
+    pub min_cpu: f64,
+    /// Minimum change of the rss, in bytes.
+    pub min_rss_bytes: f64,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Thresholds {
+            min_percent: 10.0,
+            min_cpu: 1.0,
+            min_rss_bytes: (16 << 20) as f64,
+        }
+    }
+}
+
+impl Thresholds {
+    fn significant(&self, before: f64, after: f64, min_absolute: f64) -> bool {
+        let change = (after - before).abs();
+        change >= min_absolute && (before == 0.0 || 100.0 * change / before >= self.min_percent)
+    }
+}
+
+/// Usage is the average usage of the processes sharing a name over a period. The values of the
+/// processes of a name are summed per snapshot, then averaged over the snapshots the name is in.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Usage {
+    /// user + sys CPU usage, in percent of the CPU time of the host.
+    pub cpu: f64,
+    pub rss_bytes: f64,
+    /// Number of snapshots of the period the name is in.
+    pub snapshots: usize,
+}
+
+/// NameComparison is the usage of a process name in the baseline and in the current period. A
+/// name missing from a period has a usage of 0 and 0 snapshots there.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NameComparison {
+    pub name: String,
+    pub baseline: Usage,
+    pub current: Usage,
+    /// True if the change of the CPU usage passes the thresholds.
+    pub cpu_significant: bool,
+    /// True if the change of the rss passes the thresholds.
+    pub rss_significant: bool,
+}
+
+impl NameComparison {
+    pub fn cpu_change(&self) -> f64 {
+        self.current.cpu - self.baseline.cpu
+    }
+
+    pub fn rss_change(&self) -> f64 {
+        self.current.rss_bytes - self.baseline.rss_bytes
+    }
+
+    pub fn is_significant(&self) -> bool {
+        self.cpu_significant || self.rss_significant
+    }
+}
+
+/// CompareReport is the result of `compare`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompareReport {
+    pub baseline: TimeRange,
+    pub current: TimeRange,
+    /// Number of snapshots read in the baseline and in the current period.
+    pub baseline_snapshots: usize,
+    pub current_snapshots: usize,
+    /// Number of snapshot files that could not be decoded and were skipped.
+    pub skipped: usize,
+    /// Every name seen in either period, sorted by name.
+    pub names: Vec<NameComparison>,
+    /// The annotations from the start of the first period to the end of the last one, oldest
+    /// first, so that the deploy between the two shows up.
+    pub annotations: Vec<Annotation>,
+}
+
+impl CompareReport {
+    /// The `n` significant changes with the largest relative change of either metric, the
+    /// names that appeared or disappeared first.
+    pub fn top_changes(&self, n: usize) -> Vec<&NameComparison> {
+        let relative = |c: &NameComparison| {
+            let pct = |before: f64, after: f64, significant: bool| {
+                if !significant {
+                    0.0
+                } else if before == 0.0 {
+                    f64::INFINITY
+                } else {
+                    ((after - before) / before).abs()
+                }
+            };
+            pct(c.baseline.cpu, c.current.cpu, c.cpu_significant).max(pct(
+                c.baseline.rss_bytes,
+                c.current.rss_bytes,
+                c.rss_significant,
+            ))
+        };
+        let mut sorted: Vec<&NameComparison> =
+            self.names.iter().filter(|c| c.is_significant()).collect();
+        sorted.sort_by(|a, b| {
+            relative(b)
+                .partial_cmp(&relative(a))
+                .unwrap_or(std::cmp::Ordering::Equal)
+                .then(a.name.cmp(&b.name))
+        });
+        sorted.truncate(n);
+        sorted
+    }
+}
+
+/// Returns the average usage per process name of the snapshots between `range`, and the number
+/// of snapshots read and skipped.
+fn usage_by_name(
+    datadir: &Path,
+    range: TimeRange,
+    workers: usize,
+) -> io::Result<(HashMap<String, Usage>, usize, usize)> {
+    let files = query::files_in_range(datadir, range.from, range.to)?;
+    let samples = query::par_map_slim(&files, workers, |s| {
+        let mut by_name: HashMap<String, (f64, f64)> = HashMap::new();
+        for p in &s.processes {
+            let entry = by_name.entry(p.name.clone()).or_default();
+            entry.0 += p.user_cpu_usage + p.sys_cpu_usage;
+            entry.1 += p.rss_bytes as f64;
+        }
+        by_name
+    });
+    let (mut read, mut skipped) = (0, 0);
+    let mut usage: HashMap<String, Usage> = HashMap::new();
+    for (_, sample) in samples {
+        let sample = match sample {
+            Ok(s) => s,
+            Err(_) => {
+                skipped += 1;
+                continue;
+            }
+        };
+        read += 1;
+        for (name, (cpu, rss)) in sample {
+            let u = usage.entry(name).or_default();
+            u.cpu += cpu;
+            u.rss_bytes += rss;
+            u.snapshots += 1;
+        }
+    }
+    for u in usage.values_mut() {
+        u.cpu /= u.snapshots as f64;
+        u.rss_bytes /= u.snapshots as f64;
+    }
+    Ok((usage, read, skipped))
+}
+
+/// Compares the average CPU and rss of every process name between the `baseline` and the
+/// `current` periods, eg: the day before and the day after a deploy. Processes are grouped by
+/// name, so the workers of a service are compared as a whole even if their pids changed.
+pub fn compare(
+    datadir: &Path,
+    baseline: TimeRange,
+    current: TimeRange,
+    thresholds: &Thresholds,
+    workers: usize,
+) -> io::Result<CompareReport> {
+    let (before, baseline_snapshots, skipped_before) = usage_by_name(datadir, baseline, workers)?;
+    let (after, current_snapshots, skipped_after) = usage_by_name(datadir, current, workers)?;
+    let mut names: Vec<&String> = before.keys().chain(after.keys()).collect();
+    names.sort();
+    names.dedup();
+    let names = names
+        .into_iter()
+        .map(|name| {
+            let b = before.get(name).copied().unwrap_or_default();
+            let a = after.get(name).copied().unwrap_or_default();
+            NameComparison {
+                name: name.clone(),
+                baseline: b,
+                current: a,
+                cpu_significant: thresholds.significant(b.cpu, a.cpu, thresholds.min_cpu),
+                rss_significant: thresholds.significant(
+                    b.rss_bytes,
+                    a.rss_bytes,
+                    thresholds.min_rss_bytes,
+                ),
+            }
+        })
+        .collect();
+    Ok(CompareReport {
+        baseline,
+        current,
+        baseline_snapshots,
+        current_snapshots,
+        skipped: skipped_before + skipped_after,
+        names,
+        annotations: annotations::read(
+            datadir,
+            baseline.from.min(current.from),
+            baseline.to.max(current.to),
+        )?,
+    })
+}
+
+/// Gap is an interval without snapshots of a host, between two of its snapshots.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Gap {
+    /// Epoch of the last snapshot before the gap.
+    pub after: u64,
+    /// Epoch of the first snapshot after the gap.
+    pub before: u64,
+    /// Number of snapshots the delay would have taken in between.
+    pub missing: u64,
+}
+
+impl Gap {
+    /// Length of the gap, in seconds.
+    pub fn length(&self) -> u64 {
+        self.before - self.after
+    }
+}
+
+/// HostGaps is what `gaps` found for one host.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HostGaps {
+    pub hostname: String,
+    /// Number of snapshots of the host in the range.
+    pub snapshots: usize,
+    /// The delay recorded in the latest snapshot of the host.
+    pub delay: Duration,
+    /// Epoch of the latest snapshot of the host in the range. A host that went down, or whose
+    /// disk filled up, has no snapshot after it.
+    pub last_seen: u64,
+    /// The gaps, oldest first.
+    pub gaps: Vec<Gap>,
+}
+
+/// GapReport is the result of `gaps`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GapReport {
+    pub range: TimeRange,
+    pub tolerance: f64,
+    /// Number of snapshots read, over all the datadirs.
+    pub snapshots: usize,
+    /// Number of snapshot files that could not be decoded and were skipped.
+    pub skipped: usize,
+    /// One entry per hostname, sorted by hostname.
+    pub hosts: Vec<HostGaps>,
+    /// The annotations of the range in all the datadirs, oldest first.
+    pub annotations: Vec<Annotation>,
+}
+
+/// Finds the gaps in the snapshots of every host of `datadirs` taken in `range`, decoding them on
+/// `workers` threads: two consecutive snapshots of a host further apart than `tolerance` times
+/// the delay, eg: because the server or the host was down, or the disk full. The delay is the
+/// longer of the ones recorded in both snapshots, so a restart with another delay isn't a gap.
+/// Hosts are told apart by the hostname recorded in the snapshots, so the datadirs of many hosts
+/// can be checked at once.
+pub fn gaps(
+    datadirs: &[PathBuf],
+    range: TimeRange,
+    tolerance: f64,
+    workers: usize,
+) -> io::Result<GapReport> {
+    let mut report = GapReport {
+        range,
+        tolerance,
+        snapshots: 0,
+        skipped: 0,
+        hosts: Vec::new(),
+        annotations: Vec::new(),
+    };
+    // Epoch and delay of every snapshot of each host.
+    let mut by_host: BTreeMap<String, Vec<(u64, Duration)>> = BTreeMap::new();
+    for datadir in datadirs {
+        let files = query::files_in_range(datadir, range.from, range.to)?;
+        for (_, sample) in query::par_map_slim(&files, workers, |s| {
+            (s.hostname.clone(), s.time_epoch, s.delay)
+        }) {
+            match sample {
+                Ok((hostname, epoch, delay)) => {
+                    report.snapshots += 1;
+                    by_host.entry(hostname).or_default().push((epoch, delay));
+                }
+                Err(_) => report.skipped += 1,
+            }
+        }
+        report
+            .annotations
+            .extend(annotations::read(datadir, range.from, range.to)?);
+    }
+    report.annotations.sort_by_key(|a| a.time_epoch);
+    report.hosts = by_host
+        .into_iter()
+        .map(|(hostname, mut snapshots)| {
+            snapshots.sort_by_key(|(epoch, _)| *epoch);
+            let gaps = snapshots
+                .windows(2)
+                .filter_map(|pair| {
+                    let ((after, d1), (before, d2)) = (pair[0], pair[1]);
+                    let delay = d1.max(d2).as_secs_f64();
+                    let length = (before - after) as f64;
+                    (delay > 0.0 && length > (delay * tolerance).ceil()).then(|| Gap {
+                        after,
+                        before,
+                        missing: ((length / delay).round() as u64).saturating_sub(1),
+                    })
+                })
+                .collect();
+            let (last_seen, delay) = snapshots.last().copied().unwrap_or_default();
+            HostGaps {
+                hostname,
+                snapshots: snapshots.len(),
+                delay,
+                last_seen,
+                gaps,
+            }
+        })
+        .collect();
+    Ok(report)
+}
+
+/// ReportFormat is how the `report` subcommand renders its tables.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReportFormat {
+    /// Aligned columns, for a terminal.
+    Table,
+    /// GitHub flavored Markdown tables, to paste into issues and incident documents.
+    Markdown,
+}
+
+impl FromStr for ReportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(ReportFormat::Table),
+            "md" | "markdown" => Ok(ReportFormat::Markdown),
+            _ => Err(format!("unknown format {}, expected table or md", s)),
+        }
+    }
+}
+
+/// Align is the alignment of a column of a Markdown table.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Align {
+    Left,
+    Right,
+}
+
+/// Renders a Markdown table. Pipes in cells are escaped and line breaks replaced, so that a
+/// process name can't break the table.
+pub(crate) fn markdown_table(columns: &[(&str, Align)], rows: &[Vec<String>]) -> String {
+    let cell = |c: &str| c.replace('|', "\\|").replace(['\r', '\n'], " ");
+    let mut out = String::new();
+    let line = |out: &mut String, cells: Vec<String>| {
+        out.push_str("| ");
+        out.push_str(&cells.join(" | "));
+        out.push_str(" |\n");
+    };
+    line(
+        &mut out,
+        columns.iter().map(|(name, _)| cell(name)).collect(),
+    );
+    line(
+        &mut out,
+        columns
+            .iter()
+            .map(|(_, align)| match align {
+                Align::Left => "---".to_string(),
+                Align::Right => "---:".to_string(),
+            })
+            .collect(),
+    );
+    for row in rows {
+        line(&mut out, row.iter().map(|c| cell(c)).collect());
+    }
+    out
+}
+
+/// The baseline of each metric is followed by its current value and the change.
+const COMPARE_COLUMNS: [(&str, Align); 8] = [
+    ("name", Align::Left),
+    (metrics::CPU_USAGE.name, Align::Right),
+    ("now", Align::Right),
+    ("change", Align::Right),
+    (metrics::RSS_BYTES.name, Align::Right),
+    ("now", Align::Right),
+    ("change", Align::Right),
+    ("%", Align::Right),
+];
+
+/// Returns the cells of the top `n` significant changes of a comparison.
+fn compare_rows(report: &CompareReport, n: usize, format: &ByteFormat) -> Vec<Vec<String>> {
+    report
+        .top_changes(n)
+        .into_iter()
+        .map(|c| {
+            let rss_change = match c.rss_change() {
+                d if d < 0.0 => format!("-{}", format.bytes(-d as u64)),
+                d => format!("+{}", format.bytes(d as u64)),
+            };
+            vec![
+                c.name.clone(),
+                format!("{:.1}", c.baseline.cpu),
+                format!("{:.1}", c.current.cpu),
+                format!("{:+.1}", c.cpu_change()),
+                format.bytes(c.baseline.rss_bytes as u64),
+                format.bytes(c.current.rss_bytes as u64),
+                rss_change,
+                if c.baseline.snapshots == 0 {
+                    "new".to_string()
+                } else if c.current.snapshots == 0 {
+                    "gone".to_string()
+                } else {
+                    percent_change(c.baseline.rss_bytes, c.current.rss_bytes)
+                },
+            ]
+        })
+        .collect()
+}
+
+fn compare_title(report: &CompareReport, tz: &TimeZone) -> String {
+    format!(
+        "Baseline {} to {} ({} snapshots), current {} to {} ({} snapshots), {} unreadable",
+        tz.format(report.baseline.from),
+        tz.format(report.baseline.to),
+        report.baseline_snapshots,
+        tz.format(report.current.from),
+        tz.format(report.current.to),
+        report.current_snapshots,
+        report.skipped
+    )
+}
+
+/// Prints the top `n` significant changes of a comparison, with times shown in `tz`.
+pub fn print_compare(report: &CompareReport, n: usize, format: &ByteFormat, tz: &TimeZone) {
+    println!("{}", compare_title(report, tz));
+    println!();
+    let names: Vec<&str> = COMPARE_COLUMNS.iter().map(|(name, _)| *name).collect();
+    println!(
+        "{:<16}  {:>9}  {:>9}  {:>9}  {:>12}  {:>12}  {:>12}  {:>8}",
+        names[0], names[1], names[2], names[3], names[4], names[5], names[6], names[7]
+    );
+    for r in compare_rows(report, n, format) {
+        println!(
+            "{:<16}  {:>9}  {:>9}  {:>9}  {:>12}  {:>12}  {:>12}  {:>8}",
+            r[0], r[1], r[2], r[3], r[4], r[5], r[6], r[7]
+        );
+    }
+    print_annotations(&report.annotations, tz);
+}
+
+/// Renders the top `n` significant changes of a comparison as Markdown, with times shown in `tz`.
+pub fn markdown_compare(
+    report: &CompareReport,
+    n: usize,
+    format: &ByteFormat,
+    tz: &TimeZone,
+) -> String {
+    format!(
+        "{}\n\n{}{}",
+        compare_title(report, tz),
+        markdown_table(&COMPARE_COLUMNS, &compare_rows(report, n, format)),
+        markdown_annotations(&report.annotations, tz)
+    )
+}
+
+fn growth_title(report: &GrowthReport, tz: &TimeZone) -> String {
+    format!(
+        "Growth between {} and {} ({} snapshots, {} unreadable)",
+        tz.format(report.from),
+        tz.format(report.to),
+        report.snapshots,
+        report.skipped
+    )
+}
+
+/// Returns the columns and the cells of the top `n` rss growers, then of the top `n` fd growers.
+fn growth_tables(
+    report: &GrowthReport,
+    n: usize,
+    format: &ByteFormat,
+) -> [([&'static str; 6], Vec<Vec<String>>); 2] {
+    let rss = report
+        .top_rss(n)
+        .into_iter()
+        .map(|p| {
+            vec![
+                p.pid.to_string(),
+                p.name.clone(),
+                format.bytes(p.rss_bytes.start.max(0) as u64),
+                format.bytes(p.rss_bytes.end.max(0) as u64),
+                format.bytes(p.rss_bytes.absolute() as u64),
+                percent(p.rss_bytes),
+            ]
+        })
+        .collect();
+    let fds = report
+        .top_fds(n)
+        .into_iter()
+        .map(|p| {
+            vec![
+                p.pid.to_string(),
+                p.name.clone(),
+                p.fds.start.to_string(),
+                p.fds.end.to_string(),
+                p.fds.absolute().to_string(),
+                percent(p.fds),
+            ]
+        })
+        .collect();
+    [
+        (["pid", "name", "rss start", "rss end", "growth", "%"], rss),
+        (["pid", "name", "fds start", "fds end", "growth", "%"], fds),
+    ]
+}
+
+/// Prints the top `n` rss and fd growers of a report, with times shown in `tz`.
+pub fn print_growth(report: &GrowthReport, n: usize, format: &ByteFormat, tz: &TimeZone) {
+    println!("{}", growth_title(report, tz));
+    for (h, rows) in growth_tables(report, n, format).iter() {
+        println!();
+        println!(
+            "{:>8}  {:<16}  {:>12}  {:>12}  {:>12}  {:>8}",
+            h[0], h[1], h[2], h[3], h[4], h[5]
+        );
+        for r in rows {
+            println!(
+                "{:>8}  {:<16}  {:>12}  {:>12}  {:>12}  {:>8}",
+                r[0], r[1], r[2], r[3], r[4], r[5]
+            );
+        }
+    }
+    print_annotations(&report.annotations, tz);
+}
+
+/// Renders the top `n` rss and fd growers of a report as Markdown, with times shown in `tz`.
+pub fn markdown_growth(
+    report: &GrowthReport,
+    n: usize,
+    format: &ByteFormat,
+    tz: &TimeZone,
+) -> String {
+    let mut out = growth_title(report, tz);
+    out.push('\n');
+    for (h, rows) in growth_tables(report, n, format).iter() {
+        let mut columns: Vec<(&str, Align)> = h.iter().map(|c| (*c, Align::Right)).collect();
+        columns[1].1 = Align::Left;
+        out.push('\n');
+        out.push_str(&markdown_table(&columns, rows));
+    }
+    out.push_str(&markdown_annotations(&report.annotations, tz));
+    out
+}
+
+fn top_title(report: &TopReport, tz: &TimeZone) -> String {
+    let by = match report.sort_by {
+        SortBy::Memory => "peak rss",
+        SortBy::Cpu => "CPU usage",
+    };
+    format!(
+        "Top processes by {} between {} and {} ({} snapshots, {} unreadable)",
+        by,
+        tz.format(report.range.from),
+        tz.format(report.range.to),
+        report.snapshots,
+        report.skipped
+    )
+}
+
+const TOP_COLUMNS: [(&str, Align); 5] = [
+    ("pid", Align::Right),
+    ("name", Align::Left),
+    (metrics::CPU_USAGE.name, Align::Right),
+    (metrics::RSS_BYTES.name, Align::Right),
+    ("snapshots", Align::Right),
+];
+
+fn top_rows(report: &TopReport, format: &ByteFormat) -> Vec<Vec<String>> {
+    report
+        .processes
+        .iter()
+        .map(|p| {
+            vec![
+                p.pid.to_string(),
+                p.name.clone(),
+                format!("{:.1}", p.cpu),
+                format.bytes(p.rss_bytes.max(0) as u64),
+                p.snapshots.to_string(),
+            ]
+        })
+        .collect()
+}
+
+/// Prints the processes of a top report, with times shown in `tz`.
+pub fn print_top(report: &TopReport, format: &ByteFormat, tz: &TimeZone) {
+    println!("{}", top_title(report, tz));
+    println!();
+    let names: Vec<&str> = TOP_COLUMNS.iter().map(|(name, _)| *name).collect();
+    println!(
+        "{:>8}  {:<16}  {:>9}  {:>12}  {:>9}",
+        names[0], names[1], names[2], names[3], names[4]
+    );
+    for r in top_rows(report, format) {
+        println!(
+            "{:>8}  {:<16}  {:>9}  {:>12}  {:>9}",
+            r[0], r[1], r[2], r[3], r[4]
+        );
+    }
+    print_annotations(&report.annotations, tz);
+}
+
+/// Renders the processes of a top report as Markdown, with times shown in `tz`.
+pub fn markdown_top(report: &TopReport, format: &ByteFormat, tz: &TimeZone) -> String {
+    format!(
+        "{}\n\n{}{}",
+        top_title(report, tz),
+        markdown_table(&TOP_COLUMNS, &top_rows(report, format)),
+        markdown_annotations(&report.annotations, tz)
+    )
+}
+
+fn gaps_title(report: &GapReport, tz: &TimeZone) -> String {
+    format!(
+        "Gaps longer than {} times the delay between {} and {} ({} snapshots, {} unreadable)",
+        report.tolerance,
+        tz.format(report.range.from),
+        tz.format(report.range.to),
+        report.snapshots,
+        report.skipped
+    )
+}
+
+const GAP_HOST_COLUMNS: [(&str, Align); 6] = [
+    ("host", Align::Left),
+    ("delay", Align::Right),
+    ("snapshots", Align::Right),
+    ("last seen", Align::Left),
+    ("gaps", Align::Right),
+    ("missing", Align::Right),
+];
+
+const GAP_COLUMNS: [(&str, Align); 5] = [
+    ("host", Align::Left),
+    ("from", Align::Left),
+    ("to", Align::Left),
+    ("length", Align::Right),
+    ("missing", Align::Right),
+];
+
+/// Returns the cells of the summary of every host, then of every gap.
+fn gap_rows(report: &GapReport, tz: &TimeZone) -> (Vec<Vec<String>>, Vec<Vec<String>>) {
+    let hosts = report
+        .hosts
+        .iter()
+        .map(|h| {
+            vec![
+                h.hostname.clone(),
+                units::format_duration(h.delay),
+                h.snapshots.to_string(),
+                tz.format(h.last_seen),
+                h.gaps.len().to_string(),
+                h.gaps.iter().map(|g| g.missing).sum::<u64>().to_string(),
+            ]
+        })
+        .collect();
+    let gaps = report
+        .hosts
+        .iter()
+        .flat_map(|h| {
+            h.gaps.iter().map(move |g| {
+                vec![
+                    h.hostname.clone(),
+                    tz.format(g.after),
+                    tz.format(g.before),
+                    units::format_duration(Duration::from_secs(g.length())),
+                    g.missing.to_string(),
+                ]
+            })
+        })
+        .collect();
+    (hosts, gaps)
+}
+
+/// Prints the hosts and the gaps of a report, with times shown in `tz`.
+pub fn print_gaps(report: &GapReport, tz: &TimeZone) {
+    println!("{}", gaps_title(report, tz));
+    let (hosts, gaps) = gap_rows(report, tz);
+    println!();
+    let h: Vec<&str> = GAP_HOST_COLUMNS.iter().map(|(name, _)| *name).collect();
+    println!(
+        "{:<24}  {:>8}  {:>9}  {:<26}  {:>5}  {:>8}",
+        h[0], h[1], h[2], h[3], h[4], h[5]
+    );
+    for r in hosts {
+        println!(
+            "{:<24}  {:>8}  {:>9}  {:<26}  {:>5}  {:>8}",
+            r[0], r[1], r[2], r[3], r[4], r[5]
+        );
+    }
+    if !gaps.is_empty() {
+        println!();
+        let h: Vec<&str> = GAP_COLUMNS.iter().map(|(name, _)| *name).collect();
+        println!(
+            "{:<24}  {:<26}  {:<26}  {:>8}  {:>8}",
+            h[0], h[1], h[2], h[3], h[4]
+        );
+        for r in gaps {
+            println!(
+                "{:<24}  {:<26}  {:<26}  {:>8}  {:>8}",
+                r[0], r[1], r[2], r[3], r[4]
+            );
+        }
+    }
+    print_annotations(&report.annotations, tz);
+}
+
+/// Renders the hosts and the gaps of a report as Markdown, with times shown in `tz`.
+pub fn markdown_gaps(report: &GapReport, tz: &TimeZone) -> String {
+    let (hosts, gaps) = gap_rows(report, tz);
+    format!(
+        "{}\n\n{}\n{}{}",
+        gaps_title(report, tz),
+        markdown_table(&GAP_HOST_COLUMNS, &hosts),
+        markdown_table(&GAP_COLUMNS, &gaps),
+        markdown_annotations(&report.annotations, tz)
+    )
+}
+
+/// Prints `annotations` below a report, if any.
+fn print_annotations(annotations: &[Annotation], tz: &TimeZone) {
+    if annotations.is_empty() {
+        return;
+    }
+    println!();
+    println!("Annotations");
+    for a in annotations {
+        println!("{}  {}", tz.format(a.time_epoch), a.text.replace('\n', " "));
+    }
+}
+
+/// Renders `annotations` as a Markdown list to append to a report, empty if there are none.
+fn markdown_annotations(annotations: &[Annotation], tz: &TimeZone) -> String {
+    let mut out = String::new();
+    if annotations.is_empty() {
+        return out;
+    }
+    out.push_str("\nAnnotations:\n\n");
+    for a in annotations {
+        out.push_str(&format!(
+            "- {}: {}\n",
+            tz.format(a.time_epoch),
+            a.text.replace('\n', " ")
+        ));
+    }
+    out
+}
+
+fn percent_change(before: f64, after: f64) -> String {
+    match before {
+        b if b > 0.0 => format!("{:+.1}", 100.0 * (after - b) / b),
+        _ => "-".to_string(),
+    }
+}
+
+fn percent(delta: Delta) -> String {
+    match delta.percent() {
+        Some(p) => format!("{:.1}", p),
+        None => "-".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PidStatus;
+    use std::fs;
+
+    fn status(name: &str, rss_bytes: i64, fdsize: u32) -> PidStatus {
+        PidStatus {
+            ppid: Pid::new(1),
+            name: name.to_string(),
+            cmd_short: name.to_string(),
+            fdsize,
+            state: "S (sleeping)".to_string(),
+            vmpeak: Some(0),
+            vmsize: Some(0),
+            rss_pages: rss_bytes / 4096,
+            rss_bytes,
+            ..Default::default()
+        }
+    }
+
+    fn write(dir: &Path, epoch: u64, processes: &[(i32, PidStatus)]) {
+        let s = EncoDecode {
+            hostname: "localghost".to_string(),
+            pid_map_list: processes
+                .iter()
+                .map(|(pid, s)| (Pid::new(*pid), s.clone()))
+                .collect(),
+            time_epoch: epoch,
+            delay: Duration::from_secs(60),
+            ..Default::default()
+        };
+        fs::write(
+            dir.join(format!("{}.procshot", epoch)),
+            bincode::serialize(&s).unwrap(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_growth() {
+        let dir = std::env::temp_dir().join(format!("procshot_report_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        write(
+            &dir,
+            60,
+            &[
+                (10, status("java", 1000, 64)),
+                (20, status("leaky", 100, 64)),
+            ],
+        );
+        write(
+            &dir,
+            120,
+            &[
+                (10, status("java", 900, 64)),
+                (20, status("leaky", 300, 256)),
+            ],
+        );
+        // pid 20 was reused by another process.
+        write(
+            &dir,
+            180,
+            &[
+                (10, status("java", 1500, 64)),
+                (20, status("cron", 5000, 64)),
+            ],
+        );
+
+        let report = growth(&dir, 0, 1000, 2).unwrap();
+        assert_eq!(report.snapshots, 3);
+        let rss: Vec<(&str, i64)> = report
+            .top_rss(10)
+            .iter()
+            .map(|p| (p.name.as_str(), p.rss_bytes.absolute()))
+            .collect();
+        assert_eq!(rss, vec![("java", 500), ("leaky", 200)]);
+        assert_eq!(report.top_rss(10)[1].rss_bytes.percent(), Some(200.0));
+        let fds: Vec<&str> = report.top_fds(10).iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(fds, vec!["leaky"]);
+
+        let md = markdown_growth(&report, 10, &ByteFormat::default(), &TimeZone::Utc);
+        let lines: Vec<&str> = md.lines().collect();
+        assert_eq!(lines[1], "");
+        assert_eq!(
+            lines[2],
+            "| pid | name | rss start | rss end | growth | % |"
+        );
+        assert_eq!(lines[3], "| ---: | --- | ---: | ---: | ---: | ---: |");
+        assert_eq!(lines[4], "| 10 | java | 1000 B | 1.5 KiB | 500 B | 50.0 |");
+        assert_eq!(
+            lines[7],
+            "| pid | name | fds start | fds end | growth | % |"
+        );
+        assert_eq!(lines[9], "| 20 | leaky | 64 | 256 | 192 | 300.0 |");
+        assert_eq!(lines.len(), 10);
+
+        let deploy = Annotation {
+            time_epoch: 90,
+            text: "deployed v2.3".to_string(),
+        };
+        annotations::add(&dir, &deploy).unwrap();
+        let report = growth(&dir, 0, 1000, 2).unwrap();
+        assert_eq!(report.annotations, vec![deploy]);
+        let md = markdown_growth(&report, 10, &ByteFormat::default(), &TimeZone::Utc);
+        assert!(md.ends_with("\nAnnotations:\n\n- 1970-01-01 00:01:30 +00:00: deployed v2.3\n"));
+        assert!(growth(&dir, 100, 1000, 2).unwrap().annotations.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_compare() {
+        let dir = std::env::temp_dir().join(format!("procshot_compare_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mb = 1 << 20;
+        let mut busy = status("nginx", 100 * mb, 64);
+        busy.user_cpu_usage = 4.0;
+        write(
+            &dir,
+            60,
+            &[(10, busy.clone()), (11, status("cron", mb, 64))],
+        );
+        write(
+            &dir,
+            120,
+            &[(10, busy.clone()), (11, status("cron", mb, 64))],
+        );
+        // After the deploy, nginx runs two workers using as much as one did.
+        write(
+            &dir,
+            600,
+            &[
+                (20, busy.clone()),
+                (21, busy),
+                (22, status("cron", mb + 1, 64)),
+            ],
+        );
+        write(&dir, 660, &[(23, status("sidecar", 64 * mb, 64))]);
+
+        let tz = TimeZone::Utc;
+        let baseline = TimeRange::parse("0..300", &tz).unwrap();
+        let current = TimeRange::parse("1970-01-01 00:05:00..1970-01-01 00:15:00", &tz).unwrap();
+        assert_eq!(current, TimeRange { from: 300, to: 900 });
+        assert!(TimeRange::parse("900..300", &tz).is_err());
+        let report = compare(&dir, baseline, current, &Thresholds::default(), 2).unwrap();
+        assert_eq!(
+            (report.baseline_snapshots, report.current_snapshots),
+            (2, 2)
+        );
+        let nginx = report.names.iter().find(|c| c.name == "nginx").unwrap();
+        assert_eq!(nginx.baseline.cpu, 4.0);
+        assert_eq!(nginx.current.cpu, 8.0);
+        assert_eq!(nginx.rss_change(), (100 * mb) as f64);
+        let changed: Vec<&str> = report
+            .top_changes(10)
+            .iter()
+            .map(|c| c.name.as_str())
+            .collect();
+        assert_eq!(changed, vec!["sidecar", "nginx"]);
+
+        let md = markdown_compare(&report, 10, &ByteFormat::default(), &tz);
+        assert!(md.contains(
+            "\n| name | cpu_usage | now | change | rss_bytes | now | change | % |\n\
+             | --- | ---: | ---: | ---: | ---: | ---: | ---: | ---: |\n\
+             | sidecar | 0.0 | 0.0 | +0.0 | 0 B | 64.0 MiB | +64.0 MiB | new |\n"
+        ));
+        assert_eq!("md".parse(), Ok(ReportFormat::Markdown));
+        let rows = [vec!["a|b".to_string(), "1\n2".to_string()]];
+        assert_eq!(
+            markdown_table(&[("x", Align::Left), ("y", Align::Right)], &rows),
+            "| x | y |\n| --- | ---: |\n| a\\|b | 1 2 |\n"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_top_n() {
+        let dir = std::env::temp_dir().join(format!("procshot_top_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let with_cpu = |name: &str, cpu: f64, rss_bytes: i64| {
+            let mut s = status(name, rss_bytes, 64);
+            s.user_cpu_usage = cpu;
+            s
+        };
+        write(
+            &dir,
+            60,
+            &[
+                (10, with_cpu("java", 10.0, 4000)),
+                (20, with_cpu("backup", 90.0, 1000)),
+            ],
+        );
+        // The backup only ran for the first minute.
+        write(&dir, 120, &[(10, with_cpu("java", 30.0, 8000))]);
+        write(
+            &dir,
+            180,
+            &[
+                (10, with_cpu("java", 50.0, 6000)),
+                (30, with_cpu("cron", 1.0, 100)),
+            ],
+        );
+
+        let snapshot = store_snapshot(&dir, 60);
+        let names = |top: &[TopProcess]| top.iter().map(|p| p.name.clone()).collect::<Vec<_>>();
+        assert_eq!(names(&top_n_by_cpu(&snapshot, 10)), vec!["backup", "java"]);
+        assert_eq!(names(&top_n_by_rss(&snapshot, 1)), vec!["java"]);
+
+        let range = TimeRange { from: 0, to: 1000 };
+        let report = top_n(&dir, range, SortBy::Cpu, 2, 2).unwrap();
+        assert_eq!(report.snapshots, 3);
+        assert_eq!(names(&report.processes), vec!["java", "backup"]);
+        assert_eq!(report.processes[0].cpu, 30.0);
+        assert_eq!(report.processes[1].cpu, 30.0);
+        assert_eq!(report.processes[1].snapshots, 1);
+        let report = top_n(&dir, range, SortBy::Memory, 10, 2).unwrap();
+        assert_eq!(names(&report.processes), vec!["java", "backup", "cron"]);
+        assert_eq!(report.processes[0].rss_bytes, 8000);
+        let range = TimeRange {
+            from: 100,
+            to: 1000,
+        };
+        let report = top_n(&dir, range, SortBy::Cpu, 10, 2).unwrap();
+        assert_eq!(names(&report.processes), vec!["java", "cron"]);
+
+        let md = markdown_top(&report, &ByteFormat::default(), &TimeZone::Utc);
+        let lines: Vec<&str> = md.lines().collect();
+        assert_eq!(
+            lines[2],
+            "| pid | name | cpu_usage | rss_bytes | snapshots |"
+        );
+        assert_eq!(lines[4], "| 10 | java | 40.0 | 7.8 KiB | 2 |");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_gaps() {
+        let root = std::env::temp_dir().join(format!("procshot_gaps_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let snapshot = |dir: &Path, host: &str, epoch: u64, delay: u64| {
+            fs::create_dir_all(dir).unwrap();
+            let s = EncoDecode {
+                hostname: host.to_string(),
+                time_epoch: epoch,
+                delay: Duration::from_secs(delay),
+                ..Default::default()
+            };
+            fs::write(
+                dir.join(format!("{}.procshot", epoch)),
+                bincode::serialize(&s).unwrap(),
+            )
+            .unwrap();
+        };
+        // web-0 was down between 1920 and 2400, and runs with a 10 minute delay from 3000.
+        let web = root.join("web-0");
+        for epoch in [1800, 1861, 1920, 2400, 3000, 3600] {
+            snapshot(&web, "web-0", epoch, if epoch < 3000 { 60 } else { 600 });
+        }
+        // db-0 stopped writing after 1900.
+        let db = root.join("db-0");
+        for epoch in [1800, 1900] {
+            snapshot(&db, "db-0", epoch, 100);
+        }
+        fs::write(db.join("9999.procshot"), b"garbage").unwrap();
+
+        let range = TimeRange { from: 0, to: 10000 };
+        let report = gaps(&[web.clone(), db], range, DEFAULT_GAP_TOLERANCE, 2).unwrap();
+        assert_eq!((report.snapshots, report.skipped), (8, 1));
+        let hosts: Vec<&str> = report.hosts.iter().map(|h| h.hostname.as_str()).collect();
+        assert_eq!(hosts, vec!["db-0", "web-0"]);
+        assert_eq!(report.hosts[0].gaps, vec![]);
+        assert_eq!(report.hosts[0].last_seen, 1900);
+        let web_0 = &report.hosts[1];
+        assert_eq!(web_0.delay, Duration::from_secs(600));
+        assert_eq!(
+            web_0.gaps,
+            vec![Gap {
+                after: 1920,
+                before: 2400,
+                missing: 7,
+            }]
+        );
+        let strict = gaps(&[web], range, 1.0, 1).unwrap();
+        assert_eq!(strict.hosts[0].gaps.len(), 2);
+        assert_eq!(strict.hosts[0].gaps[0].missing, 0);
+
+        let md = markdown_gaps(&report, &TimeZone::Utc);
+        let lines: Vec<&str> = md.lines().collect();
+        assert_eq!(
+            lines[2],
+            "| host | delay | snapshots | last seen | gaps | missing |"
+        );
+        assert_eq!(
+            lines[5],
+            "| web-0 | 10m | 6 | 1970-01-01 01:00:00 +00:00 | 1 | 7 |"
+        );
+        assert_eq!(
+            lines[9],
+            "| web-0 | 1970-01-01 00:32:00 +00:00 | 1970-01-01 00:40:00 +00:00 | 8m | 7 |"
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    fn store_snapshot(dir: &Path, epoch: u64) -> EncoDecode {
+        crate::store::read_snapshot(&dir.join(format!("{}.procshot", epoch))).unwrap()
+    }
+}
@@ -0,0 +1,335 @@
+//! Removal of old snapshot files from the datadir.
+//!
+//! Pruning deletes several files, and an interruption halfway (crash, kill -9, power loss) must not
+//! leave the datadir in an inconsistent state. Every prune therefore goes through a small intent
+//! log, `.prune.journal`:
+//!
+//! 1. the list of files to delete is written to the journal, followed by a `COMMIT` line, and the
+//!    journal is synced to disk;
+//! 2. the files are deleted;
+//! 3. the journal is removed.
+//!
+//! On startup `recover` looks for a leftover journal. A committed journal is rolled forward by
+//! deleting whatever is still listed, an uncommitted one is rolled back by discarding it, since no
+//! file was touched before the commit.
+//!
+//! A `RetentionPolicy` bounds the number, age and total size of the snapshots kept, so a server can
+//! run unattended for months. `enforce` prunes the oldest snapshots of both tiers beyond it, and
+//! the server calls it after every iteration.
+
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::backend::{DirBackend, StorageBackend};
+use crate::store;
+
+/// Name of the intent log inside the datadir.
+pub const JOURNAL_FILE: &str = ".prune.journal";
+
+const JOURNAL_HEADER: &str = "procshot-prune-journal v1";
+const JOURNAL_COMMIT: &str = "COMMIT";
+
+/// What `recover` found and did.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Recovery {
+    /// There was no leftover journal.
+    Clean,
+    /// A committed journal was found and the listed files were deleted.
+    RolledForward(usize),
+    /// An incomplete journal was found and discarded.
+    RolledBack,
+}
+
+/// RetentionPolicy bounds the snapshots kept in a datadir. The oldest snapshots are deleted until
+/// every limit holds, except the newest snapshot which is always kept.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct RetentionPolicy {
+    /// Number of snapshots kept.
+    pub max_files: Option<usize>,
+    /// Snapshots taken longer ago than this are deleted.
+    pub max_age: Option<Duration>,
+    /// Total size of the snapshot files kept, in bytes, compressed size for compressed files.
+    pub max_total_bytes: Option<u64>,
+}
+
+impl RetentionPolicy {
+    /// Returns true if no limit is set.
+    pub fn is_unlimited(&self) -> bool {
+        self.max_files.is_none() && self.max_age.is_none() && self.max_total_bytes.is_none()
+    }
+
+    /// Returns the epoch of the oldest snapshot to keep, given the epochs and sizes of the
+    /// snapshots oldest first, or None if there is nothing to delete.
+    pub fn cutoff(&self, snapshots: &[(u64, u64)], now: u64) -> Option<u64> {
+        let (newest, _) = *snapshots.last()?;
+        let mut cutoff = 0;
+        if let Some(max_age) = self.max_age {
+            cutoff = cutoff.max(now.saturating_sub(max_age.as_secs()));
+        }
+        if let Some(max_files) = self.max_files {
+            let keep = max_files.max(1);
+            if snapshots.len() > keep {
+                cutoff = cutoff.max(snapshots[snapshots.len() - keep].0);
+            }
+        }
+        if let Some(max_total_bytes) = self.max_total_bytes {
+            let mut total = 0;
+            for (epoch, size) in snapshots.iter().rev() {
+                total += size;
+                if total > max_total_bytes {
+                    // This one doesn't fit, the one after it is the oldest kept.
+                    cutoff = cutoff.max(epoch + 1);
+                    break;
+                }
+            }
+        }
+        match snapshots[0].0 < cutoff {
+            true => Some(cutoff.min(newest)),
+            false => None,
+        }
+    }
+}
+
+/// Deletes the snapshots of both tiers of `datadir` beyond `policy`, through the journal, and
+/// returns how many were deleted. `now` is in epoch seconds. Snapshots are deleted by whole
+/// seconds, so with sub-second sampling `max_files` and `max_total_bytes` are approximate.
+pub fn enforce(datadir: &Path, now: u64, policy: &RetentionPolicy) -> io::Result<usize> {
+    if policy.is_unlimited() {
+        return Ok(0);
+    }
+    let mut snapshots = Vec::new();
+    for (epoch, path) in store::snapshot_files(datadir)? {
+        match fs::metadata(&path) {
+            Ok(m) => snapshots.push((epoch, m.len())),
+            // Deleted in the meantime, eg: by `tier::demote`.
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+    }
+    match policy.cutoff(&snapshots, now) {
+        Some(cutoff) => DirBackend::new(datadir, false).prune(cutoff),
+        None => Ok(0),
+    }
+}
+
+/// Deletes `files` (names relative to `datadir`) through the journal and returns the number of
+/// files that were actually removed. Files that are already gone are not an error.
+pub fn prune_files(datadir: &Path, files: &[PathBuf]) -> io::Result<usize> {
+    if files.is_empty() {
+        return Ok(0);
+    }
+    for f in files {
+        check_relative(f)?;
+    }
+    let journal = datadir.join(JOURNAL_FILE);
+    write_journal(&journal, files)?;
+    let removed = apply(datadir, files)?;
+    fs::remove_file(&journal)?;
+    sync_dir(datadir);
+    Ok(removed)
+}
+
+/// Completes or discards a prune that was interrupted. Must be called before the datadir is used,
+/// the server does it on startup.
+pub fn recover(datadir: &Path) -> io::Result<Recovery> {
+    let journal = datadir.join(JOURNAL_FILE);
+    let content = match fs::read_to_string(&journal) {
+        Ok(c) => c,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Recovery::Clean),
+        Err(e) => return Err(e),
+    };
+    let result = match parse_journal(&content) {
+        Some(files) => {
+            for f in &files {
+                check_relative(f)?;
+            }
+            Recovery::RolledForward(apply(datadir, &files)?)
+        }
+        None => Recovery::RolledBack,
+    };
+    fs::remove_file(&journal)?;
+    sync_dir(datadir);
+    Ok(result)
+}
+
+/// Only plain file names are accepted, so a tampered journal can't delete outside the datadir.
+fn check_relative(f: &Path) -> io::Result<()> {
+    match f.components().count() == 1 && f.file_name().is_some() {
+        true => Ok(()),
+        false => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("refusing to prune {}, not a file name", f.display()),
+        )),
+    }
+}
+
+fn write_journal(journal: &Path, files: &[PathBuf]) -> io::Result<()> {
+    let mut content = String::from(JOURNAL_HEADER);
+    content.push('\n');
+    for f in files {
+        content.push_str(&f.to_string_lossy());
+        content.push('\n');
+    }
+    content.push_str(JOURNAL_COMMIT);
+    content.push('\n');
+    let mut f = File::create(journal)?;
+    f.write_all(content.as_bytes())?;
+    f.sync_all()?;
+    if let Some(dir) = journal.parent() {
+        sync_dir(dir);
+    }
+    Ok(())
+}
+
+/// Returns the listed files if the journal is complete, None if it was torn.
+fn parse_journal(content: &str) -> Option<Vec<PathBuf>> {
+    let mut lines = content.lines();
+    if lines.next()? != JOURNAL_HEADER {
+        return None;
+    }
+    let mut files = Vec::new();
+    for line in lines {
+        if line == JOURNAL_COMMIT {
+            return Some(files);
+        }
+        files.push(PathBuf::from(line));
+    }
+    None
+}
+
+fn apply(datadir: &Path, files: &[PathBuf]) -> io::Result<usize> {
+    let mut removed = 0;
+    for f in files {
+        match fs::remove_file(datadir.join(f)) {
+            Ok(()) => removed += 1,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(removed)
+}
+
+/// Persists directory entries (creates, renames and unlinks). Failure is not fatal, some
+/// filesystems don't support syncing a directory.
+fn sync_dir(dir: &Path) {
+    if let Ok(d) = File::open(dir) {
+        let _ = d.sync_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("procshot_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_prune_files() {
+        let dir = scratch_dir("prune");
+        fs::write(dir.join("1.procshot"), "a").unwrap();
+        fs::write(dir.join("2.procshot"), "b").unwrap();
+        let files = vec![PathBuf::from("1.procshot"), PathBuf::from("3.procshot")];
+        assert_eq!(prune_files(&dir, &files).unwrap(), 1);
+        assert!(!dir.join("1.procshot").exists());
+        assert!(dir.join("2.procshot").exists());
+        assert!(!dir.join(JOURNAL_FILE).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_recover_committed_and_torn() {
+        let dir = scratch_dir("recover");
+        fs::write(dir.join("1.procshot"), "a").unwrap();
+        write_journal(&dir.join(JOURNAL_FILE), &[PathBuf::from("1.procshot")]).unwrap();
+        assert_eq!(recover(&dir).unwrap(), Recovery::RolledForward(1));
+        assert!(!dir.join("1.procshot").exists());
+
+        fs::write(dir.join("2.procshot"), "b").unwrap();
+        fs::write(
+            dir.join(JOURNAL_FILE),
+            format!("{}\n2.procshot\n", JOURNAL_HEADER),
+        )
+        .unwrap();
+        assert_eq!(recover(&dir).unwrap(), Recovery::RolledBack);
+        assert!(dir.join("2.procshot").exists());
+        assert_eq!(recover(&dir).unwrap(), Recovery::Clean);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_retention_policy() {
+        let snapshots: Vec<(u64, u64)> = (1..=10).map(|i| (i * 60, 100)).collect();
+        let policy = |max_files, max_age: Option<u64>, max_total_bytes| RetentionPolicy {
+            max_files,
+            max_age: max_age.map(Duration::from_secs),
+            max_total_bytes,
+        };
+        assert_eq!(RetentionPolicy::default().cutoff(&snapshots, 600), None);
+        assert_eq!(
+            policy(Some(3), None, None).cutoff(&snapshots, 600),
+            Some(480)
+        );
+        assert_eq!(policy(Some(20), None, None).cutoff(&snapshots, 600), None);
+        assert_eq!(
+            policy(None, Some(150), None).cutoff(&snapshots, 600),
+            Some(450)
+        );
+        assert_eq!(
+            policy(None, None, Some(250)).cutoff(&snapshots, 600),
+            Some(481)
+        );
+        // The strictest limit wins, and the newest snapshot is kept whatever the limits.
+        assert_eq!(
+            policy(Some(5), Some(150), None).cutoff(&snapshots, 600),
+            Some(450)
+        );
+        assert_eq!(
+            policy(Some(0), None, None).cutoff(&snapshots, 600),
+            Some(600)
+        );
+        assert_eq!(
+            policy(None, Some(0), None).cutoff(&snapshots, 9999),
+            Some(600)
+        );
+        assert_eq!(
+            policy(None, None, Some(10)).cutoff(&snapshots, 600),
+            Some(600)
+        );
+        assert_eq!(policy(Some(3), None, None).cutoff(&[], 600), None);
+
+        let dir = scratch_dir("retention");
+        fs::create_dir_all(dir.join(store::COLD_DIR)).unwrap();
+        for epoch in &[60, 120, 180] {
+            fs::write(dir.join(format!("cold/{}.procshot.gz", epoch)), "a").unwrap();
+        }
+        for epoch in &[240, 300] {
+            fs::write(dir.join(format!("{}.procshot", epoch)), "a").unwrap();
+        }
+        assert_eq!(enforce(&dir, 300, &policy(Some(2), None, None)).unwrap(), 3);
+        let epochs: Vec<u64> = store::snapshot_files(&dir)
+            .unwrap()
+            .into_iter()
+            .map(|(epoch, _)| epoch)
+            .collect();
+        assert_eq!(epochs, vec![240, 300]);
+        assert_eq!(enforce(&dir, 300, &policy(Some(2), None, None)).unwrap(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_refuses_paths_outside_datadir() {
+        let dir = scratch_dir("refuse");
+        let files = vec![PathBuf::from("../etc/passwd")];
+        assert!(prune_files(&dir, &files).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
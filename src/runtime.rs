@@ -0,0 +1,153 @@
+//! Runtime classification of the processes.
+//!
+//! Questions asked of a fleet are often about runtimes rather than binaries: how much memory do
+//! all the JVMs use, how many Python interpreters run on a host. The names of the processes don't
+//! answer them without a regex per query, as a JVM may be named `java`, after the main class of an
+//! application, or after the launcher of a vendor. The server classifies every process in
+//! `PidStatus::runtime` as one of the `Runtime`s, from cheap to costly evidence:
+//!
+//! * the executable (`/proc/<pid>/exe`) and the first argument of the command line, eg: `java`,
+//!   `python3.11` or `node`;
+//! * the libraries mapped by the process (`/proc/<pid>/maps`): `libjvm.so`, `libpython` or
+//!   `libnode.so` for the runtimes embedded in a launcher, and the heap arenas the Go runtime
+//!   reserves at address `0xc000000000` for Go binaries, which map no library of their own.
+//!
+//! Anything else with an executable is `native`. Kernel threads have no runtime. The executable
+//! and the maps of the processes of other users are only readable when running as root, the
+//! command line alone is used otherwise.
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::Pid;
+
+/// Start of the first mapping of the heap of Go programs on 64 bit platforms.
+const GO_ARENA: &str = "c000000000-";
+
+/// Runtime is what runs the code of a process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Runtime {
+    Jvm,
+    Python,
+    Node,
+    Go,
+    /// Compiled code without a runtime recognized by procshot, eg: C, C++ or Rust.
+    Native,
+}
+
+impl fmt::Display for Runtime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Runtime::Jvm => "jvm",
+            Runtime::Python => "python",
+            Runtime::Node => "node",
+            Runtime::Go => "go",
+            Runtime::Native => "native",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Returns the runtime telling from the name of an executable, if any.
+fn from_name(path: &str) -> Option<Runtime> {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    match name {
+        "java" => Some(Runtime::Jvm),
+        "node" | "nodejs" => Some(Runtime::Node),
+        n if n.starts_with("python") || n.starts_with("pypy") => Some(Runtime::Python),
+        _ => None,
+    }
+}
+
+/// Returns the runtime telling from the mappings of a process, in the format of
+/// /proc/<pid>/maps, if any.
+fn from_maps(maps: &str) -> Option<Runtime> {
+    for line in maps.lines() {
+        if line.starts_with(GO_ARENA) {
+            return Some(Runtime::Go);
+        }
+        let path = match line.split_whitespace().nth(5) {
+            Some(path) => path.rsplit('/').next().unwrap_or(path),
+            None => continue,
+        };
+        if path.starts_with("libjvm.so") {
+            return Some(Runtime::Jvm);
+        }
+        if path.starts_with("libpython") {
+            return Some(Runtime::Python);
+        }
+        if path.starts_with("libnode.so") {
+            return Some(Runtime::Node);
+        }
+    }
+    None
+}
+
+/// Classifies a process from its executable, its command line and its maps, any of which may be
+/// empty if unreadable. `maps` is only called if the executable and the command line don't tell.
+/// None if there is no executable at all, as for kernel threads.
+pub fn classify<F>(exe: &str, cmd_long: &[String], maps: F) -> Option<Runtime>
+where
+    F: FnOnce() -> String,
+{
+    let argv0 = cmd_long.first().map_or("", |a| a.as_str());
+    if exe.is_empty() && argv0.is_empty() {
+        return None;
+    }
+    let runtime = from_name(exe)
+        .or_else(|| from_name(argv0))
+        .or_else(|| from_maps(&maps()))
+        .unwrap_or(Runtime::Native);
+    Some(runtime)
+}
+
+/// Classifies `pid` from its files under `proc_root`.
+pub fn detect(proc_root: &Path, pid: Pid, cmd_long: &[String]) -> Option<Runtime> {
+    let dir = proc_root.join(pid.to_string());
+    let exe = fs::read_link(dir.join("exe"))
+        .map(|p| p.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    classify(&exe, cmd_long, || {
+        fs::read_to_string(dir.join("maps")).unwrap_or_default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        let cmd = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<String>>();
+        let no_maps = || String::new();
+        assert_eq!(
+            classify("/usr/lib/jvm/java-17/bin/java", &cmd(&["java"]), no_maps),
+            Some(Runtime::Jvm)
+        );
+        assert_eq!(
+            classify("", &cmd(&["/usr/bin/python3.11", "app.py"]), no_maps),
+            Some(Runtime::Python)
+        );
+        assert_eq!(classify("/usr/bin/node", &[], no_maps), Some(Runtime::Node));
+        let launcher = "55d0c0a00000-55d0c0a01000 r-xp 00000000 fd:01 1234 /opt/kafka/bin/launcher\n7f3a00000000-7f3a01000000 r-xp 00000000 fd:01 5678 /usr/lib/jvm/java-17/lib/server/libjvm.so\n";
+        assert_eq!(
+            classify("/opt/kafka/bin/launcher", &[], || launcher.to_string()),
+            Some(Runtime::Jvm)
+        );
+        let go = "00400000-00800000 r-xp 00000000 fd:01 42 /usr/local/bin/etcd\nc000000000-c000400000 rw-p 00000000 00:00 0 \n";
+        assert_eq!(
+            classify("/usr/local/bin/etcd", &[], || go.to_string()),
+            Some(Runtime::Go)
+        );
+        assert_eq!(
+            classify("/usr/sbin/nginx", &cmd(&["nginx: master process"]), no_maps),
+            Some(Runtime::Native)
+        );
+        assert_eq!(classify("", &[], || panic!("kernel thread")), None);
+        assert_eq!(Runtime::Jvm.to_string(), "jvm");
+
+        let me = detect(Path::new("/proc"), Pid::current(), &[]);
+        assert_eq!(me, Some(Runtime::Native));
+    }
+}
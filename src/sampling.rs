@@ -0,0 +1,68 @@
+//! Weighted sampling of low-interest processes.
+//!
+//! Hosts with thousands of small idle processes spend most of each snapshot on processes nobody
+//! looks at. With `IdleSampling`, processes below both
+//! the rss and the CPU threshold are only recorded every `every` iterations, while everything
+//! else is recorded every time. Every `every`-th snapshot is therefore complete, and the ones in
+//! between only hold the big consumers.
+
+use crate::PidStatus;
+
+/// IdleSampling decides which processes are recorded in a given iteration.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IdleSampling {
+    /// Processes with an rss below this many bytes may be idle.
+    pub rss_below: i64,
+    /// Processes with a user + sys CPU usage below this percentage may be idle.
+    pub cpu_below: f64,
+    /// Idle processes are recorded once every `every` iterations.
+    pub every: u64,
+}
+
+impl IdleSampling {
+    /// True if the process is below both thresholds.
+    pub fn is_idle(&self, status: &PidStatus) -> bool {
+        status.rss_bytes < self.rss_below
+            && status.user_cpu_usage + status.sys_cpu_usage < self.cpu_below
+    }
+
+    /// True if idle processes are recorded in the `iteration`-th iteration, counting from 0. The
+    /// first snapshot is always complete.
+    pub fn records_idle(&self, iteration: u64) -> bool {
+        self.every <= 1 || iteration.is_multiple_of(self.every)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Pid;
+
+    #[test]
+    fn test_is_idle() {
+        let sampling = IdleSampling {
+            rss_below: 10 << 20,
+            cpu_below: 0.5,
+            every: 4,
+        };
+        let mut s = PidStatus {
+            ppid: Pid::new(1),
+            name: "getty".to_string(),
+            cmd_short: "getty".to_string(),
+            fdsize: 64,
+            state: "S (sleeping)".to_string(),
+            vmpeak: Some(1),
+            vmsize: Some(1),
+            rss_pages: 256,
+            rss_bytes: 1 << 20,
+            user_cpu_usage: 0.1,
+            ..Default::default()
+        };
+        assert!(sampling.is_idle(&s));
+        s.sys_cpu_usage = 2.0;
+        assert!(!sampling.is_idle(&s));
+        assert!(sampling.records_idle(0));
+        assert!(!sampling.records_idle(3));
+        assert!(sampling.records_idle(8));
+    }
+}
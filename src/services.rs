@@ -0,0 +1,211 @@
+//! Service hints from the ports processes listen on.
+//!
+//! Operators reading a report often know their services by what they serve, not by the name of
+//! their binary: `postmaster`, `beam.smp` or `java` say little, while listening on 5432, 5672 or
+//! 9092 says postgresql, rabbitmq or kafka. The server reads the listening TCP sockets and the
+//! bound UDP sockets from /proc/net, attributes them to processes by the `socket:[inode]` links
+//! of their fds like the `tcpstats` module, and records the names of the known ports a process
+//! listens on in `PidStatus::service_hint`.
+//!
+//! `PortRegistry::default()` knows the well-known ports of common services, and more can be given
+//! with `--service-port <port>=<name>`, eg: for in-house services. As with the TCP counters, the
+//! fds of processes of other users are only readable when running as root.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::{tcpstats, Pid};
+
+/// Well-known ports and the service usually behind them.
+const WELL_KNOWN: &[(u16, &str)] = &[
+    (21, "ftp"),
+    (22, "ssh"),
+    (25, "smtp"),
+    (53, "dns"),
+    (80, "http"),
+    (110, "pop3"),
+    (123, "ntp"),
+    (143, "imap"),
+    (389, "ldap"),
+    (443, "https"),
+    (636, "ldaps"),
+    (1433, "mssql"),
+    (1521, "oracle"),
+    (2181, "zookeeper"),
+    (2379, "etcd"),
+    (3000, "grafana"),
+    (3306, "mysql"),
+    (4222, "nats"),
+    (5432, "postgresql"),
+    (5672, "rabbitmq"),
+    (6379, "redis"),
+    (6443, "kube-apiserver"),
+    (8086, "influxdb"),
+    (8500, "consul"),
+    (9090, "prometheus"),
+    (9092, "kafka"),
+    (9200, "elasticsearch"),
+    (11211, "memcached"),
+    (27017, "mongodb"),
+];
+
+/// Files of /proc/net listing the sockets, and the state of the ones a service listens on: TCP
+/// sockets in LISTEN and unconnected UDP sockets.
+const SOCKET_TABLES: &[(&str, &str)] =
+    &[("tcp", "0A"), ("tcp6", "0A"), ("udp", "07"), ("udp6", "07")];
+
+/// PortRegistry maps ports to the names of the services behind them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PortRegistry {
+    ports: BTreeMap<u16, String>,
+}
+
+impl Default for PortRegistry {
+    /// The well-known ports of common services.
+    fn default() -> Self {
+        PortRegistry {
+            ports: WELL_KNOWN
+                .iter()
+                .map(|(port, name)| (*port, name.to_string()))
+                .collect(),
+        }
+    }
+}
+
+impl PortRegistry {
+    /// Maps `port` to `name`, replacing the well-known name of the port if any.
+    pub fn insert(&mut self, port: u16, name: &str) {
+        self.ports.insert(port, name.to_string());
+    }
+
+    /// Returns the service usually listening on `port`.
+    pub fn service(&self, port: u16) -> Option<&str> {
+        self.ports.get(&port).map(|s| s.as_str())
+    }
+
+    /// Returns the hint of a process listening on `ports`: the names of the known ones, by port and
+    /// separated by commas. None if no port is known.
+    pub fn hint<I: IntoIterator<Item = u16>>(&self, ports: I) -> Option<String> {
+        let names: BTreeSet<(u16, &str)> = ports
+            .into_iter()
+            .filter_map(|p| Some((p, self.service(p)?)))
+            .collect();
+        let mut seen = BTreeSet::new();
+        let hint: Vec<&str> = names
+            .into_iter()
+            .map(|(_, name)| name)
+            .filter(|name| seen.insert(*name))
+            .collect();
+        match hint.is_empty() {
+            true => None,
+            false => Some(hint.join(",")),
+        }
+    }
+}
+
+/// ServicePort is a `<port>=<name>` mapping, as given to `--service-port`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServicePort {
+    pub port: u16,
+    pub name: String,
+}
+
+impl FromStr for ServicePort {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid service port {}, expected <port>=<name>", s);
+        let (port, name) = s.split_once('=').ok_or_else(invalid)?;
+        let name = name.trim();
+        if name.is_empty() || name.contains(',') {
+            return Err(invalid());
+        }
+        Ok(ServicePort {
+            port: port.trim().parse().map_err(|_| invalid())?,
+            name: name.to_string(),
+        })
+    }
+}
+
+/// Parses a socket table of /proc/net and returns the local port of every socket in `state`, by
+/// inode.
+pub fn parse_sockets(content: &str, state: &str) -> HashMap<u64, u16> {
+    content
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            // sl local_address rem_address st tx_queue:rx_queue tr:tm->when retrnsmt uid timeout
+            // inode ...
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.get(3) != Some(&state) {
+                return None;
+            }
+            let port = fields.get(1)?.rsplit(':').next()?;
+            let port = u16::from_str_radix(port, 16).ok()?;
+            let inode: u64 = fields.get(9)?.parse().ok()?;
+            // Sockets being torn down have no inode.
+            Some((inode, port)).filter(|(inode, _)| *inode != 0)
+        })
+        .collect()
+}
+
+/// Returns the local port of every listening socket of the host, by inode. Tables missing from
+/// the kernel, eg: tcp6 without IPv6, are skipped.
+pub fn listening_ports(proc_root: &Path) -> io::Result<HashMap<u64, u16>> {
+    let mut ports = HashMap::new();
+    for (table, state) in SOCKET_TABLES {
+        match fs::read_to_string(proc_root.join("net").join(table)) {
+            Ok(content) => ports.extend(parse_sockets(&content, state)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(ports)
+}
+
+/// Returns the ports `pid` listens on, given the `listening_ports` of the host.
+pub fn ports_of(proc_root: &Path, pid: Pid, listening: &HashMap<u64, u16>) -> Vec<u16> {
+    tcpstats::socket_inodes(proc_root, pid)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|inode| listening.get(inode).copied())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_sockets() {
+        let tcp = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode\n   0: 00000000:1538 00000000:0000 0A 00000000:00000000 00:00000000 00000000   113        0 31337 1 0000000000000000 100 0 0 10 0\n   1: 0100007F:E2D4 0100007F:1538 01 00000000:00000000 00:00000000 00000000  1000        0 42424 1 0000000000000000 20 4 30 10 -1\n";
+        let ports = parse_sockets(tcp, "0A");
+        assert_eq!(ports.len(), 1);
+        assert_eq!(ports.get(&31337), Some(&5432));
+
+        let mut registry = PortRegistry::default();
+        assert_eq!(
+            registry.hint(vec![5432, 5432, 40000]).as_deref(),
+            Some("postgresql")
+        );
+        assert_eq!(registry.hint(vec![40000]), None);
+        let custom: ServicePort = "9100=node-exporter".parse().unwrap();
+        registry.insert(custom.port, &custom.name);
+        registry.insert(80, "nginx");
+        assert_eq!(
+            registry.hint(vec![9100, 80]).as_deref(),
+            Some("nginx,node-exporter")
+        );
+        assert!("9100".parse::<ServicePort>().is_err());
+        assert!("http=nginx".parse::<ServicePort>().is_err());
+
+        // Other tests of this process may be listening.
+        let listening = listening_ports(Path::new("/proc")).unwrap();
+        assert!(ports_of(Path::new("/proc"), Pid::current(), &listening)
+            .iter()
+            .all(|port| listening.values().any(|p| p == port)));
+    }
+}
@@ -0,0 +1,587 @@
+//! Interactive exploration of the archive.
+//!
+//! `procshot shell` reads commands from stdin and keeps the snapshots it loaded, and the filter it
+//! was given, between them, so an investigation doesn't decode the same week of files for every
+//! question asked. The commands are:
+//!
+//! * `load <from>..<to>` or `load <duration>`: loads the snapshots of a range (epochs or times in
+//!   the format of `-t`), or of the last duration, eg: `load 2h`.
+//! * `filter name|cmd <text>`, `filter pid <pid>`, `filter runtime <runtime>`,
+//!   `filter cgroup <path>`, `filter off`: only looks at the processes whose name or command line
+//!   contains the text, with the pid, classified with the runtime, eg: `jvm`, or in the cgroup or
+//!   its descendants. Filters add up until cleared.
+//! * `top [metric] [n]`: the heaviest processes of the last loaded snapshot, by CPU usage or any
+//!   metric of `metrics::PROCESS_METRICS`, eg: `rss` or `io.write_bytes`.
+//! * `cgroups [cpu|rss] [n]`: the heaviest cgroups of the last loaded snapshot, with the CPU and
+//!   rss of their processes next to their limits, when recorded with `--cgroup-limits`.
+//! * `diff`: processes started and exited between the first and the last loaded snapshot, and the
+//!   largest rss changes.
+//! * `plot [metric]`: a one line chart of the total of a metric over the loaded range.
+//! * `export <format> <path>`: writes the loaded snapshots, filtered, in a format of `convert`.
+//! * `status`, `help` and `quit`.
+
+use std::collections::HashMap;
+use std::io;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::cgroup::Limits;
+use crate::convert::{self, Format};
+use crate::header::Unit;
+use crate::metrics::{self, Metric};
+use crate::report::TimeRange;
+use crate::tz::TimeZone;
+use crate::units::{self, ByteFormat};
+use crate::{query, EncoDecode, Pid, PidStatus};
+
+/// Number of processes listed by `top` and `diff` when none is given.
+const DEFAULT_TOP: usize = 10;
+
+/// Width of the charts drawn by `plot`, in characters.
+const PLOT_WIDTH: usize = 60;
+
+const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+const HELP: &str = "\
+load <from>..<to> | load <duration>   load the snapshots of a range, or of the last duration
+filter name|cmd <text> | filter pid <pid> | filter runtime <runtime> | filter cgroup <path> | filter off
+top [metric] [n]                      heaviest processes of the last loaded snapshot, eg: top rss
+cgroups [cpu|rss] [n]                 heaviest cgroups of the last loaded snapshot, with their limits
+diff                                  changes between the first and the last loaded snapshot
+plot [metric]                         total over the loaded range
+export <format> <path>                write the loaded snapshots: bincode, json, sqlite or parquet
+status | help | quit";
+
+/// Filter selects the processes the commands look at.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    /// The name contains the text.
+    Name(String),
+    /// The command line contains the text.
+    Cmd(String),
+    Pid(Pid),
+    /// The runtime is the one given, see `PidStatus::runtime`.
+    Runtime(String),
+    /// The cgroup is the one given or one of its descendants, see `PidStatus::cgroup`.
+    Cgroup(PathBuf),
+}
+
+impl Filter {
+    pub fn matches(&self, pid: Pid, status: &PidStatus) -> bool {
+        match self {
+            Filter::Name(text) => status.name.contains(text.as_str()),
+            Filter::Cmd(text) => status.cmd_long.join(" ").contains(text.as_str()),
+            Filter::Pid(p) => pid == *p,
+            Filter::Runtime(runtime) => status.runtime.as_ref() == Some(runtime),
+            Filter::Cgroup(path) => status
+                .cgroup
+                .as_ref()
+                .is_some_and(|c| Path::new(c).starts_with(path)),
+        }
+    }
+}
+
+/// Returns the metric `top`, `cgroups` and `plot` rank or draw, `cpu_usage` if none is given.
+fn parse_metric(s: Option<&str>) -> Result<&'static Metric, String> {
+    s.map_or(Ok(&metrics::CPU_USAGE), metrics::parse)
+}
+
+/// Returns the CPU usage of a process, which is always recorded.
+fn cpu(status: &PidStatus) -> f64 {
+    metrics::CPU_USAGE.of(status).unwrap_or(0.0)
+}
+
+/// Shell holds the state kept between the commands.
+pub struct Shell {
+    datadir: PathBuf,
+    workers: usize,
+    format: ByteFormat,
+    tz: TimeZone,
+    /// The loaded snapshots, oldest first.
+    snapshots: Vec<EncoDecode>,
+    filters: Vec<Filter>,
+}
+
+impl Shell {
+    pub fn new(datadir: &Path, workers: usize, format: ByteFormat, tz: TimeZone) -> Self {
+        Shell {
+            datadir: datadir.to_path_buf(),
+            workers,
+            format,
+            tz,
+            snapshots: Vec::new(),
+            filters: Vec::new(),
+        }
+    }
+
+    /// Runs one command line and returns what to print, or None once the shell should exit.
+    pub fn execute(&mut self, line: &str) -> Result<Option<String>, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (command, args) = match words.split_first() {
+            Some((command, args)) => (*command, args),
+            None => return Ok(Some(String::new())),
+        };
+        let output = match command {
+            "load" => self.load(line.trim()["load".len()..].trim())?,
+            "filter" => self.filter(args)?,
+            "top" => self.top(args)?,
+            "cgroups" => self.cgroups(args)?,
+            "diff" => self.diff()?,
+            "plot" => self.plot(args)?,
+            "export" => self.export(args)?,
+            "status" => self.status(),
+            "help" => HELP.to_string(),
+            "quit" | "exit" => return Ok(None),
+            _ => return Err(format!("unknown command {}, see help", command)),
+        };
+        Ok(Some(output))
+    }
+
+    fn load(&mut self, arg: &str) -> Result<String, String> {
+        let range = match arg.contains("..") {
+            true => TimeRange::parse(arg, &self.tz).map_err(|e| e.to_string())?,
+            false => {
+                let last = units::parse_duration(arg)?;
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs());
+                TimeRange {
+                    from: now.saturating_sub(last.as_secs()),
+                    to: now,
+                }
+            }
+        };
+        let files = query::files_in_range(&self.datadir, range.from, range.to)
+            .map_err(|e| format!("cannot list {}: {}", self.datadir.display(), e))?;
+        let mut skipped = 0;
+        self.snapshots = query::par_map(&files, self.workers, |s| s.clone())
+            .into_iter()
+            .filter_map(|(_, snapshot)| {
+                skipped += snapshot.is_err() as usize;
+                snapshot.ok()
+            })
+            .collect();
+        let mut output = format!("loaded {} snapshots", self.snapshots.len());
+        if let (Some(first), Some(last)) = (self.snapshots.first(), self.snapshots.last()) {
+            output += &format!(
+                " from {} to {}",
+                self.tz.format(first.time_epoch),
+                self.tz.format(last.time_epoch)
+            );
+        }
+        if skipped > 0 {
+            output += &format!(", skipped {} unreadable", skipped);
+        }
+        Ok(output)
+    }
+
+    fn filter(&mut self, args: &[&str]) -> Result<String, String> {
+        let filter = match args {
+            [] => return Ok(self.describe_filters()),
+            ["off"] => {
+                self.filters.clear();
+                return Ok(self.describe_filters());
+            }
+            ["pid", pid] => Filter::Pid(
+                pid.parse()
+                    .map_err(|_| format!("invalid pid {}", pid))
+                    .map(Pid::new)?,
+            ),
+            ["name", text @ ..] if !text.is_empty() => Filter::Name(text.join(" ")),
+            ["cmd", text @ ..] if !text.is_empty() => Filter::Cmd(text.join(" ")),
+            ["runtime", runtime] => Filter::Runtime(runtime.to_lowercase()),
+            ["cgroup", path] => Filter::Cgroup(PathBuf::from(path)),
+            _ => {
+                return Err(
+                    "usage: filter name|cmd <text> | filter pid <pid> | filter runtime <runtime> | filter cgroup <path> | filter off"
+                        .into(),
+                )
+            }
+        };
+        self.filters.push(filter);
+        Ok(self.describe_filters())
+    }
+
+    fn describe_filters(&self) -> String {
+        match self.filters.is_empty() {
+            true => "no filter".to_string(),
+            false => format!("filters: {:?}", self.filters),
+        }
+    }
+
+    /// Returns the processes of `snapshot` matching every filter.
+    fn matching<'a>(&self, snapshot: &'a EncoDecode) -> Vec<(Pid, &'a PidStatus)> {
+        snapshot
+            .pid_map_list
+            .iter()
+            .filter(|(pid, status)| self.filters.iter().all(|f| f.matches(**pid, status)))
+            .map(|(pid, status)| (*pid, status))
+            .collect()
+    }
+
+    fn loaded(&self) -> Result<(&EncoDecode, &EncoDecode), String> {
+        match (self.snapshots.first(), self.snapshots.last()) {
+            (Some(first), Some(last)) => Ok((first, last)),
+            _ => Err("no snapshot loaded, see load".to_string()),
+        }
+    }
+
+    /// Formats a value of `metric` in its unit.
+    fn value(&self, metric: &Metric, value: f64) -> String {
+        match metric.unit {
+            Unit::Bytes => self.format.bytes(value.max(0.0) as u64),
+            Unit::Kibibytes => self.format.bytes(value.max(0.0) as u64 * 1024),
+            Unit::Percent => format!("{:.1}%", value),
+            _ => format!("{}", value),
+        }
+    }
+
+    fn top(&self, args: &[&str]) -> Result<String, String> {
+        let metric = parse_metric(args.first().copied())?;
+        let n = match args.get(1) {
+            Some(n) => n.parse().map_err(|_| format!("invalid count {}", n))?,
+            None => DEFAULT_TOP,
+        };
+        let (_, last) = self.loaded()?;
+        let mut processes = self.matching(last);
+        // Processes without the metric, eg: io of another user's process, rank last.
+        let value = |s: &PidStatus| metric.of(s).unwrap_or(f64::NEG_INFINITY);
+        processes.sort_by(|a, b| value(b.1).total_cmp(&value(a.1)).then(a.0.cmp(&b.0)));
+        // The metric ranked by gets its own column, unless it is one of those always shown.
+        let extra = Some(metric).filter(|m| ![metrics::CPU_USAGE, metrics::RSS_BYTES].contains(m));
+        let mut output = format!("{:>8}  {:<20} {:>7} {:>10}", "pid", "name", "cpu %", "rss");
+        if let Some(m) = extra {
+            output += &format!(" {:>12}", m.name);
+        }
+        output += &format!("  at {}", self.tz.format(last.time_epoch));
+        for (pid, status) in processes.into_iter().take(n) {
+            // The service is easier to recognize than the binary, eg: postmaster.
+            let name = match &status.service_hint {
+                Some(hint) => format!("{} ({})", status.name, hint),
+                None => status.name.clone(),
+            };
+            output += &format!(
+                "\n{:>8}  {:<20} {:>7.1} {:>10}",
+                pid,
+                name,
+                cpu(status),
+                self.format.bytes(status.rss_bytes.max(0) as u64)
+            );
+            if let Some(m) = extra {
+                let v = m.of(status).map_or("-".to_string(), |v| self.value(m, v));
+                output += &format!(" {:>12}", v);
+            }
+        }
+        Ok(output)
+    }
+
+    fn cgroups(&self, args: &[&str]) -> Result<String, String> {
+        let by_rss = match parse_metric(args.first().copied())? {
+            m if *m == metrics::CPU_USAGE => false,
+            m if *m == metrics::RSS_BYTES => true,
+            m => {
+                return Err(format!(
+                    "cgroups are ranked by cpu_usage or rss_bytes, not {}",
+                    m
+                ))
+            }
+        };
+        let n = match args.get(1) {
+            Some(n) => n.parse().map_err(|_| format!("invalid count {}", n))?,
+            None => DEFAULT_TOP,
+        };
+        let (_, last) = self.loaded()?;
+        // The processes, CPU, rss and limits of every cgroup. Processes without a recorded cgroup,
+        // eg: from snapshots of older releases, are grouped under `-`.
+        let mut groups: HashMap<&str, (usize, f64, i64, Option<Limits>)> = HashMap::new();
+        for (_, status) in self.matching(last) {
+            let cgroup = status.cgroup.as_deref().unwrap_or("-");
+            let group = groups.entry(cgroup).or_default();
+            group.0 += 1;
+            group.1 += cpu(status);
+            group.2 += status.rss_bytes;
+            group.3 = group.3.or(status.cgroup_limits);
+        }
+        let mut groups: Vec<_> = groups.into_iter().collect();
+        let key = |group: &(usize, f64, i64, Option<Limits>)| match by_rss {
+            false => group.1,
+            true => group.2 as f64,
+        };
+        groups.sort_by(|a, b| key(&b.1).total_cmp(&key(&a.1)).then(a.0.cmp(b.0)));
+        let mut output = format!(
+            "{:<40} {:>6} {:>7} {:>7} {:>10} {:>10}  at {}",
+            "cgroup",
+            "procs",
+            "cpu %",
+            "quota %",
+            "rss",
+            "limit",
+            self.tz.format(last.time_epoch)
+        );
+        for (cgroup, (processes, cpu, rss, limits)) in groups.into_iter().take(n) {
+            let limits = limits.unwrap_or_default();
+            let quota = limits
+                .cpus
+                .map_or("-".to_string(), |c| format!("{:.0}", c * 100.0));
+            let limit = limits
+                .memory_max
+                .map_or("-".to_string(), |m| self.format.bytes(m));
+            output += &format!(
+                "\n{:<40} {:>6} {:>7.1} {:>7} {:>10} {:>10}",
+                cgroup,
+                processes,
+                cpu,
+                quota,
+                self.format.bytes(rss.max(0) as u64),
+                limit
+            );
+        }
+        Ok(output)
+    }
+
+    fn diff(&self) -> Result<String, String> {
+        let (first, last) = self.loaded()?;
+        let before: HashMap<Pid, &PidStatus> = self.matching(first).into_iter().collect();
+        let after: HashMap<Pid, &PidStatus> = self.matching(last).into_iter().collect();
+        let list = |title: &str, from: &HashMap<Pid, &PidStatus>, to: &HashMap<Pid, &PidStatus>| {
+            let mut pids: Vec<&Pid> = from.keys().filter(|pid| !to.contains_key(pid)).collect();
+            pids.sort();
+            let mut output = format!("{}: {}", title, pids.len());
+            for pid in pids {
+                output += &format!("\n  {:>8}  {}", pid, from[pid].name);
+            }
+            output
+        };
+        let mut changes: Vec<(Pid, i64)> = after
+            .iter()
+            .filter_map(|(pid, s)| before.get(pid).map(|b| (*pid, s.rss_bytes - b.rss_bytes)))
+            .filter(|(_, change)| *change != 0)
+            .collect();
+        changes.sort_by(|a, b| b.1.abs().cmp(&a.1.abs()).then(a.0.cmp(&b.0)));
+        let mut output = format!(
+            "{} -> {}\n{}\n{}\nrss changes:",
+            self.tz.format(first.time_epoch),
+            self.tz.format(last.time_epoch),
+            list("started", &after, &before),
+            list("exited", &before, &after)
+        );
+        for (pid, change) in changes.into_iter().take(DEFAULT_TOP) {
+            let sign = if change < 0 { "-" } else { "+" };
+            output += &format!(
+                "\n  {:>8}  {:<20} {}{}",
+                pid,
+                after[&pid].name,
+                sign,
+                self.format.bytes(change.unsigned_abs())
+            );
+        }
+        Ok(output)
+    }
+
+    fn plot(&self, args: &[&str]) -> Result<String, String> {
+        let metric = parse_metric(args.first().copied())?;
+        let (first, last) = self.loaded()?;
+        let totals: Vec<f64> = self
+            .snapshots
+            .iter()
+            .map(|s| {
+                self.matching(s)
+                    .iter()
+                    .filter_map(|(_, p)| metric.of(p))
+                    .sum()
+            })
+            .collect();
+        // Several snapshots per column are averaged.
+        let columns: Vec<f64> = totals
+            .chunks(totals.len().div_ceil(PLOT_WIDTH))
+            .map(|c| c.iter().sum::<f64>() / c.len() as f64)
+            .collect();
+        let max = columns.iter().copied().fold(0.0, f64::max);
+        let chart: String = columns
+            .iter()
+            .map(|v| match max > 0.0 {
+                true => BARS[((v / max) * (BARS.len() - 1) as f64).round() as usize],
+                false => BARS[0],
+            })
+            .collect();
+        Ok(format!(
+            "{}  max {}\n{} .. {}",
+            chart,
+            self.value(metric, max),
+            self.tz.format(first.time_epoch),
+            self.tz.format(last.time_epoch)
+        ))
+    }
+
+    fn export(&self, args: &[&str]) -> Result<String, String> {
+        let (format, path) = match args {
+            [format, path] => (format.parse::<Format>()?, Path::new(path)),
+            _ => return Err("usage: export <format> <path>".to_string()),
+        };
+        self.loaded()?;
+        if path.exists() && format != Format::Bincode {
+            return Err(format!("{} already exists", path.display()));
+        }
+        let error = |e: io::Error| format!("cannot export to {}: {}", path.display(), e);
+        let mut writer = convert::create_writer(format, path).map_err(error)?;
+        for snapshot in &self.snapshots {
+            let mut snapshot = snapshot.clone();
+            snapshot.pid_map_list = self
+                .matching(&snapshot)
+                .into_iter()
+                .map(|(pid, status)| (pid, status.clone()))
+                .collect();
+            writer.write(&snapshot).map_err(error)?;
+        }
+        writer.finish().map_err(error)?;
+        Ok(format!(
+            "exported {} snapshots to {}",
+            self.snapshots.len(),
+            path.display()
+        ))
+    }
+
+    fn status(&self) -> String {
+        let loaded = match self.loaded() {
+            Ok((first, last)) => format!(
+                "{} snapshots from {} to {}",
+                self.snapshots.len(),
+                self.tz.format(first.time_epoch),
+                self.tz.format(last.time_epoch)
+            ),
+            Err(_) => "no snapshot loaded".to_string(),
+        };
+        format!(
+            "datadir {}\n{}\n{}",
+            self.datadir.display(),
+            loaded,
+            self.describe_filters()
+        )
+    }
+}
+
+/// Runs the shell on stdin until `quit` or the end of the input.
+pub fn run(datadir: &Path, workers: usize, format: ByteFormat, tz: TimeZone) -> io::Result<()> {
+    let mut shell = Shell::new(datadir, workers, format, tz);
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("procshot> ");
+        io::stdout().flush()?;
+        let line = match lines.next() {
+            Some(line) => line?,
+            None => return Ok(()),
+        };
+        match shell.execute(&line) {
+            Ok(Some(output)) if output.is_empty() => (),
+            Ok(Some(output)) => println!("{}", output),
+            Ok(None) => return Ok(()),
+            Err(e) => eprintln!("error: {}", e),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use super::*;
+    use crate::backend::{DirBackend, StorageBackend};
+    use crate::collect::{restricted_pid_status, PROC_ROOT};
+
+    #[test]
+    fn test_shell() {
+        let dir = std::env::temp_dir().join(format!("procshot_shell_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let status = restricted_pid_status(Path::new(PROC_ROOT), Pid::current());
+        let mut backend = DirBackend::new(&dir, false);
+        for (epoch, pids) in [
+            (1000, vec![(1, "init"), (2, "bash")]),
+            (1060, vec![(1, "init"), (3, "java")]),
+        ] {
+            let mut snapshot = EncoDecode {
+                hostname: "localghost".to_string(),
+                time_epoch: epoch,
+                delay: std::time::Duration::from_secs(60),
+                ..Default::default()
+            };
+            for (pid, name) in pids {
+                let mut status = status.clone();
+                status.name = name.to_string();
+                status.rss_bytes = pid as i64 * 4096;
+                status.runtime = Some(if name == "java" { "jvm" } else { "native" }.to_string());
+                if name == "java" {
+                    status.cgroup = Some("/system.slice/billing.service".to_string());
+                    status.cgroup_limits = Some(Limits {
+                        memory_max: Some(1 << 30),
+                        cpus: Some(1.5),
+                    });
+                }
+                snapshot.pid_map_list.insert(Pid::new(pid), status);
+            }
+            backend.write_snapshot(&snapshot).unwrap();
+        }
+
+        let mut shell = Shell::new(&dir, 1, ByteFormat::default(), TimeZone::Utc);
+        assert!(shell.execute("top").is_err());
+        assert!(shell.execute("frobnicate").is_err());
+        assert_eq!(shell.execute("").unwrap(), Some(String::new()));
+        let loaded = shell.execute("load 0..2000").unwrap().unwrap();
+        assert!(loaded.starts_with("loaded 2 snapshots"), "{}", loaded);
+        let top = shell.execute("top rss 1").unwrap().unwrap();
+        assert_eq!(top.lines().count(), 2);
+        assert!(top.contains("java"));
+        let diff = shell.execute("diff").unwrap().unwrap();
+        assert!(diff.contains("started: 1\n         3  java"), "{}", diff);
+        assert!(diff.contains("exited: 1\n         2  bash"), "{}", diff);
+        assert!(shell
+            .execute("plot rss")
+            .unwrap()
+            .unwrap()
+            .starts_with("▆█"));
+
+        shell.execute("filter name ini").unwrap();
+        let top = shell.execute("top").unwrap().unwrap();
+        assert_eq!(top.lines().count(), 2);
+        assert!(top.contains("init"));
+        let out = dir.join("export.jsonl");
+        shell
+            .execute(&format!("export json {}", out.display()))
+            .unwrap();
+        assert!(shell
+            .execute(&format!("export json {}", out.display()))
+            .is_err());
+        let exported = std::fs::read_to_string(&out).unwrap();
+        assert_eq!(exported.lines().count(), 3);
+        assert!(!exported.contains("java"));
+        assert_eq!(
+            shell.execute("filter off").unwrap(),
+            Some("no filter".to_string())
+        );
+        shell.execute("filter runtime JVM").unwrap();
+        let top = shell.execute("top").unwrap().unwrap();
+        assert_eq!(top.lines().count(), 2);
+        assert!(top.contains("java"));
+        shell.execute("filter off").unwrap();
+        let top = shell.execute("top vmsize 1").unwrap().unwrap();
+        assert!(top.lines().next().unwrap().contains("vmsize"), "{}", top);
+        assert!(shell.execute("top resident_memory").is_err());
+        assert!(shell.execute("cgroups vmsize").is_err());
+        let cgroups = shell.execute("cgroups rss").unwrap().unwrap();
+        let lines: Vec<&str> = cgroups.lines().collect();
+        assert_eq!(lines.len(), 3, "{}", cgroups);
+        assert!(lines[1].starts_with("/system.slice/billing.service"));
+        assert!(
+            lines[1].contains(" 150 ") && lines[1].ends_with("1.0 GiB"),
+            "{}",
+            cgroups
+        );
+        assert!(lines[2].starts_with("- "));
+        shell.execute("filter cgroup /system.slice").unwrap();
+        let top = shell.execute("top").unwrap().unwrap();
+        assert_eq!(top.lines().count(), 2);
+        assert!(top.contains("java"));
+        assert_eq!(shell.execute("quit").unwrap(), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
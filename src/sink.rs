@@ -0,0 +1,114 @@
+//! Sinks receive every snapshot the server takes, in addition to the file written to the datadir.
+//!
+//! A sink is anything implementing `StorageSink`. Sinks are registered in `ScanOptions::sinks`, and
+//! an error in one sink is logged without affecting the others or the datadir. Besides the network
+//! sinks, `file::FileSink` writes a second copy of the archive to a directory, `log::AppendLogSink`
+//! appends the snapshots to a single file, `jsonl::JsonLinesSink` writes their processes as JSON
+//! lines and `ring::RingBufferSink` keeps the last ones in memory.
+//! `stream::StreamSink` sends them to a `collector`, over TLS with the `tls` feature.
+//!
+//! Sinks streaming processes one after another send them in `priority_order`, heaviest first, so
+//! a consumer that only reads so many bytes of a frame, or for so long, still gets the processes
+//! that matter most.
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+
+use crate::{EncoDecode, Pid, PidStatus};
+
+pub mod file;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+pub mod jsonl;
+pub mod live;
+pub mod log;
+pub mod redis;
+pub mod ring;
+pub mod stream;
+
+/// StorageSink is implemented by everything that can receive snapshots.
+pub trait StorageSink: Send {
+    /// Short name of the sink, used in log messages.
+    fn name(&self) -> &str;
+
+    /// Called once per iteration with the snapshot that was just taken.
+    fn write_snapshot(&mut self, snapshot: &EncoDecode) -> io::Result<()>;
+}
+
+impl fmt::Debug for dyn StorageSink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "StorageSink({})", self.name())
+    }
+}
+
+/// ProcessRecord is the unit published by sinks that send one record per process instead of one
+/// per snapshot. It carries the snapshot metadata so each record stands on its own.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct ProcessRecord {
+    pub hostname: String,
+    pub time_epoch: u64,
+    pub pid: Pid,
+    pub status: PidStatus,
+}
+
+/// Compares two processes by how much of the host they use: CPU usage first, then rss. The
+/// heaviest sorts first, ties are broken by pid so the order is stable.
+pub fn by_priority(a: (&Pid, &PidStatus), b: (&Pid, &PidStatus)) -> Ordering {
+    let cpu = |s: &PidStatus| s.user_cpu_usage + s.sys_cpu_usage;
+    cpu(b.1)
+        .total_cmp(&cpu(a.1))
+        .then(b.1.rss_bytes.cmp(&a.1.rss_bytes))
+        .then(a.0.cmp(b.0))
+}
+
+/// Returns the processes in the order streaming sinks send them, see `by_priority`.
+pub fn priority_order(processes: &HashMap<Pid, PidStatus>) -> Vec<(&Pid, &PidStatus)> {
+    let mut ordered: Vec<_> = processes.iter().collect();
+    ordered.sort_by(|a, b| by_priority(*a, *b));
+    ordered
+}
+
+/// Splits a snapshot into one `ProcessRecord` per process, heaviest first.
+pub fn process_records(snapshot: &EncoDecode) -> Vec<ProcessRecord> {
+    priority_order(&snapshot.pid_map_list)
+        .into_iter()
+        .map(|(pid, status)| ProcessRecord {
+            hostname: snapshot.hostname.clone(),
+            time_epoch: snapshot.time_epoch,
+            pid: *pid,
+            status: status.clone(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_process_records() {
+        let snapshot = EncoDecode {
+            hostname: "localghost".to_string(),
+            time_epoch: 1563617611,
+            delay: std::time::Duration::from_secs(5),
+            ..Default::default()
+        };
+        assert!(process_records(&snapshot).is_empty());
+
+        let mut snapshot = snapshot;
+        let status = crate::collect::restricted_pid_status(
+            std::path::Path::new(crate::collect::PROC_ROOT),
+            Pid::current(),
+        );
+        for (pid, cpu, rss) in [(1, 0.0, 4096), (2, 12.5, 0), (3, 0.0, 8192), (4, 0.0, 4096)] {
+            let mut status = status.clone();
+            status.user_cpu_usage = cpu;
+            status.rss_bytes = rss;
+            snapshot.pid_map_list.insert(Pid::new(pid), status);
+        }
+        let pids: Vec<Pid> = process_records(&snapshot).iter().map(|r| r.pid).collect();
+        assert_eq!(pids, vec![Pid::new(2), Pid::new(3), Pid::new(1), Pid::new(4)]);
+    }
+}
@@ -0,0 +1,44 @@
+//! Flat file sink.
+//!
+//! Writes every snapshot to a file of its own in a directory, laid out like the datadir (see the
+//! `store` module), through a `DirBackend`. Used to keep a second copy of the archive, eg: on a
+//! network filesystem, that the queries and tools read like any datadir.
+
+use std::io;
+use std::path::Path;
+
+use super::StorageSink;
+use crate::backend::{DirBackend, StorageBackend};
+use crate::EncoDecode;
+
+/// FileSink writes one file per snapshot to a directory.
+pub struct FileSink {
+    name: String,
+    backend: DirBackend,
+}
+
+impl FileSink {
+    /// Writes to the datadir of `backend`, which is created if missing. The files are compressed
+    /// as configured on `backend`, eg: with `DirBackend::with_compression`.
+    pub fn new(backend: DirBackend) -> io::Result<Self> {
+        std::fs::create_dir_all(backend.datadir())?;
+        Ok(FileSink {
+            name: format!("files {}", backend.datadir().display()),
+            backend,
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        self.backend.datadir()
+    }
+}
+
+impl StorageSink for FileSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn write_snapshot(&mut self, snapshot: &EncoDecode) -> io::Result<()> {
+        self.backend.write_snapshot(snapshot).map(|_| ())
+    }
+}
@@ -0,0 +1,158 @@
+//! JSON lines sink, one line per process.
+//!
+//! Log shippers like Vector and Fluent Bit tail files of newline delimited JSON natively, and
+//! ship each line as an event. `JsonLinesSink` writes every process of every snapshot as one
+//! `ProcessRecord` per line, heaviest first, with the hostname and time of its snapshot, to a
+//! file or to stdout. A file moved away by logrotate is reopened at the next snapshot, so no
+//! `copytruncate` is needed.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufWriter, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use super::{process_records, StorageSink};
+use crate::EncoDecode;
+
+/// Path given to write to stdout instead of a file.
+pub const STDOUT: &str = "-";
+
+enum Output {
+    Stdout,
+    File {
+        path: PathBuf,
+        file: BufWriter<File>,
+        /// Device and inode of the open file, to notice it was rotated.
+        id: (u64, u64),
+    },
+}
+
+/// JsonLinesSink writes one JSON line per process of every snapshot.
+pub struct JsonLinesSink {
+    name: String,
+    output: Output,
+}
+
+fn open_append(path: &Path) -> io::Result<(BufWriter<File>, (u64, u64))> {
+    let file = OpenOptions::new().append(true).create(true).open(path)?;
+    let metadata = file.metadata()?;
+    Ok((BufWriter::new(file), (metadata.dev(), metadata.ino())))
+}
+
+impl JsonLinesSink {
+    /// Appends to the file at `path`, creating it if missing, or writes to stdout if `path` is
+    /// `STDOUT`.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        if path == Path::new(STDOUT) {
+            return Ok(JsonLinesSink {
+                name: "jsonl stdout".to_string(),
+                output: Output::Stdout,
+            });
+        }
+        let (file, id) = open_append(path)?;
+        Ok(JsonLinesSink {
+            name: format!("jsonl {}", path.display()),
+            output: Output::File {
+                path: path.to_path_buf(),
+                file,
+                id,
+            },
+        })
+    }
+
+    /// Reopens the file if it isn't at its path anymore.
+    fn reopen_if_rotated(&mut self) -> io::Result<()> {
+        if let Output::File { path, file, id } = &mut self.output {
+            let current = match path.metadata() {
+                Ok(m) => Some((m.dev(), m.ino())),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e),
+            };
+            if current != Some(*id) {
+                file.flush()?;
+                let (reopened, reopened_id) = open_append(path)?;
+                *file = reopened;
+                *id = reopened_id;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes the records of `snapshot` to `out`, one per line.
+fn write_records(out: &mut impl Write, snapshot: &EncoDecode) -> io::Result<()> {
+    for record in process_records(snapshot) {
+        serde_json::to_writer(&mut *out, &record)?;
+        out.write_all(b"\n")?;
+    }
+    out.flush()
+}
+
+impl StorageSink for JsonLinesSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn write_snapshot(&mut self, snapshot: &EncoDecode) -> io::Result<()> {
+        self.reopen_if_rotated()?;
+        match &mut self.output {
+            Output::Stdout => write_records(&mut io::stdout().lock(), snapshot),
+            Output::File { file, .. } => write_records(file, snapshot),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::ProcessRecord;
+    use crate::{Pid, PidStatus};
+    use std::fs;
+    use std::time::Duration;
+
+    fn snapshot(epoch: u64) -> EncoDecode {
+        let status: PidStatus =
+            crate::collect::restricted_pid_status(Path::new("/nonexistent"), Pid::new(1));
+        EncoDecode {
+            hostname: "localghost".to_string(),
+            pid_map_list: vec![(Pid::new(1), status.clone()), (Pid::new(2), status)]
+                .into_iter()
+                .collect(),
+            time_epoch: epoch,
+            delay: Duration::from_secs(1),
+            ..Default::default()
+        }
+    }
+
+    fn read_records(path: &Path) -> Vec<ProcessRecord> {
+        fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_json_lines() {
+        let path = std::env::temp_dir().join(format!("procshot_{}.jsonl", std::process::id()));
+        let rotated = path.with_extension("jsonl.1");
+        let _ = fs::remove_file(&path);
+        let mut sink = JsonLinesSink::open(&path).unwrap();
+        sink.write_snapshot(&snapshot(1)).unwrap();
+        let records = read_records(&path);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].hostname, "localghost");
+        assert_eq!((records[0].time_epoch, records[0].pid), (1, Pid::new(1)));
+
+        // logrotate moves the file away, the next snapshot goes to a new one.
+        fs::rename(&path, &rotated).unwrap();
+        sink.write_snapshot(&snapshot(2)).unwrap();
+        assert_eq!(read_records(&rotated).len(), 2);
+        let records = read_records(&path);
+        assert_eq!(records.len(), 2);
+        assert!(records.iter().all(|r| r.time_epoch == 2));
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&rotated).unwrap();
+    }
+}
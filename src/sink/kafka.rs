@@ -0,0 +1,98 @@
+//! Kafka producer sink, enabled with the `kafka` feature.
+//!
+//! Snapshots (or per-process records, see `RecordMode`) are bincode serialized and published to a
+//! topic with the hostname as key, so all records of a host land in the same partition and keep
+//! their order.
+
+use std::io;
+use std::time::Duration;
+
+use rdkafka::config::ClientConfig;
+use rdkafka::error::KafkaError;
+use rdkafka::producer::{BaseProducer, BaseRecord, Producer};
+use rdkafka::types::RDKafkaErrorCode;
+
+use super::{process_records, StorageSink};
+use crate::EncoDecode;
+
+/// How long `write_snapshot` waits for the queued records to be delivered.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// RecordMode selects what one Kafka message holds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecordMode {
+    /// One message per snapshot, holding the serialized `EncoDecode`.
+    Snapshot,
+    /// One message per process, holding a serialized `ProcessRecord`.
+    PerProcess,
+}
+
+/// KafkaSink publishes snapshots to a Kafka topic.
+pub struct KafkaSink {
+    producer: BaseProducer,
+    topic: String,
+    mode: RecordMode,
+}
+
+impl KafkaSink {
+    /// Creates a producer for the comma separated list of `brokers`. Extra librdkafka settings
+    /// (eg: `security.protocol`) can be passed in `settings`.
+    pub fn new(
+        brokers: &str,
+        topic: &str,
+        mode: RecordMode,
+        settings: &[(String, String)],
+    ) -> io::Result<Self> {
+        let mut config = ClientConfig::new();
+        config.set("bootstrap.servers", brokers);
+        for (k, v) in settings {
+            config.set(k, v);
+        }
+        let producer: BaseProducer = config.create().map_err(io::Error::other)?;
+        Ok(KafkaSink {
+            producer,
+            topic: topic.to_string(),
+            mode,
+        })
+    }
+
+    fn send(&self, key: &str, payload: &[u8]) -> io::Result<()> {
+        let mut record = BaseRecord::to(&self.topic).key(key).payload(payload);
+        loop {
+            match self.producer.send(record) {
+                Ok(()) => return Ok(()),
+                Err((
+                    KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull),
+                    r,
+                )) => {
+                    // Wait for deliveries to make room in the local queue.
+                    self.producer.poll(Duration::from_millis(100));
+                    record = r;
+                }
+                Err((e, _)) => return Err(io::Error::other(e)),
+            }
+        }
+    }
+}
+
+impl StorageSink for KafkaSink {
+    fn name(&self) -> &str {
+        "kafka"
+    }
+
+    fn write_snapshot(&mut self, snapshot: &EncoDecode) -> io::Result<()> {
+        match self.mode {
+            RecordMode::Snapshot => {
+                let payload = bincode::serialize(snapshot).map_err(io::Error::other)?;
+                self.send(&snapshot.hostname, &payload)?;
+            }
+            RecordMode::PerProcess => {
+                for record in process_records(snapshot) {
+                    let payload = bincode::serialize(&record).map_err(io::Error::other)?;
+                    self.send(&snapshot.hostname, &payload)?;
+                }
+            }
+        }
+        self.producer.flush(FLUSH_TIMEOUT).map_err(io::Error::other)
+    }
+}
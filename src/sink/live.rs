@@ -0,0 +1,320 @@
+//! Live delta streaming to local clients over a Unix socket.
+//!
+//! The server pushes one `LiveDelta` per iteration to every connected client, so a viewer attached
+//! to a running server updates as soon as a snapshot is taken instead of polling the datadir. A
+//! new client first receives the whole current snapshot, and from then on only the processes that
+//! started, changed or exited. `LiveClient` applies the deltas and hands back full snapshots.
+//!
+//! Frames are a little endian u32 length followed by the bincode encoded `LiveDelta`. The
+//! processes of a delta are encoded heaviest first (see `sink::priority_order`), after everything
+//! else, so a client that stops decoding a frame early only misses the lightest processes.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::io::{BufReader, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use super::{priority_order, StorageSink};
+use crate::cpu::CpuTimes;
+use crate::diskstats::DiskStats;
+use crate::load::LoadAvg;
+use crate::meminfo::MemInfo;
+use crate::netdev::InterfaceStats;
+use crate::{EncoDecode, Pid, PidStatus};
+
+/// A client that doesn't read its deltas within this time is disconnected.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Frames larger than this are refused by the client, as a guard against a corrupt stream.
+const MAX_FRAME_LEN: u32 = 256 << 20;
+
+/// LiveDelta is the change between two consecutive snapshots.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct LiveDelta {
+    pub hostname: String,
+    pub time_epoch: u64,
+    pub delay: Duration,
+    pub total_cpu_time: u64,
+    pub cpu_times: CpuTimes,
+    pub per_cpu: BTreeMap<u32, CpuTimes>,
+    pub system_memory: MemInfo,
+    pub load_average: LoadAvg,
+    pub uptime: Duration,
+    pub disks: Vec<DiskStats>,
+    pub interfaces: Vec<InterfaceStats>,
+    pub labels: BTreeMap<String, String>,
+    /// True if `upserted` holds every process, and the previous state must be discarded.
+    pub full: bool,
+    /// Processes that exited.
+    pub removed: Vec<Pid>,
+    /// Processes that are new or whose status changed, heaviest first. Last, so that truncating
+    /// a frame only loses processes from its tail.
+    pub upserted: Vec<(Pid, PidStatus)>,
+}
+
+impl LiveDelta {
+    /// Returns the delta from `previous` to `current`, or the full snapshot if there is no
+    /// previous one.
+    pub fn between(previous: Option<&EncoDecode>, current: &EncoDecode) -> Self {
+        let ordered = priority_order(&current.pid_map_list).into_iter();
+        let (upserted, removed) = match previous {
+            None => (
+                ordered.map(|(pid, status)| (*pid, status.clone())).collect(),
+                Vec::new(),
+            ),
+            Some(previous) => (
+                ordered
+                    .filter(|(pid, status)| previous.pid_map_list.get(pid) != Some(status))
+                    .map(|(pid, status)| (*pid, status.clone()))
+                    .collect(),
+                previous
+                    .pid_map_list
+                    .keys()
+                    .filter(|pid| !current.pid_map_list.contains_key(pid))
+                    .copied()
+                    .collect(),
+            ),
+        };
+        LiveDelta {
+            hostname: current.hostname.clone(),
+            time_epoch: current.time_epoch,
+            delay: current.delay,
+            total_cpu_time: current.total_cpu_time,
+            cpu_times: current.cpu_times,
+            per_cpu: current.per_cpu.clone(),
+            system_memory: current.system_memory,
+            load_average: current.load_average,
+            uptime: current.uptime,
+            disks: current.disks.clone(),
+            interfaces: current.interfaces.clone(),
+            labels: current.labels.clone(),
+            full: previous.is_none(),
+            removed,
+            upserted,
+        }
+    }
+
+    /// Applies the delta to `state`, which becomes the snapshot the delta was computed for.
+    pub fn apply(self, state: &mut EncoDecode) {
+        if self.full {
+            state.pid_map_list.clear();
+        }
+        for pid in &self.removed {
+            state.pid_map_list.remove(pid);
+        }
+        state.pid_map_list.extend(self.upserted);
+        state.hostname = self.hostname;
+        state.time_epoch = self.time_epoch;
+        state.delay = self.delay;
+        state.total_cpu_time = self.total_cpu_time;
+        state.cpu_times = self.cpu_times;
+        state.per_cpu = self.per_cpu;
+        state.system_memory = self.system_memory;
+        state.load_average = self.load_average;
+        state.uptime = self.uptime;
+        state.disks = self.disks;
+        state.interfaces = self.interfaces;
+        state.labels = self.labels;
+    }
+}
+
+/// LiveSink accepts clients on a Unix socket and streams the deltas to them.
+pub struct LiveSink {
+    path: PathBuf,
+    /// Clients that connected since the last snapshot and still need a full one.
+    pending: Arc<Mutex<Vec<UnixStream>>>,
+    clients: Vec<UnixStream>,
+    previous: Option<EncoDecode>,
+}
+
+impl LiveSink {
+    /// Listens on `path`, replacing a stale socket left by a previous run. The socket is only
+    /// accessible to the owner, like the datadir.
+    pub fn bind(path: &Path) -> io::Result<Self> {
+        if UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} is in use by another server", path.display()),
+            ));
+        }
+        match fs::remove_file(path) {
+            Err(ref e) if e.kind() != io::ErrorKind::NotFound => {
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!("cannot remove stale {}: {}", path.display(), e),
+                ))
+            }
+            _ => (),
+        }
+        let listener = UnixListener::bind(path)?;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        let pending = Arc::new(Mutex::new(Vec::new()));
+        let accepted = Arc::clone(&pending);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream.and_then(|s| s.set_write_timeout(Some(WRITE_TIMEOUT)).map(|()| s)) {
+                    Ok(s) => accepted.lock().unwrap().push(s),
+                    Err(e) => eprintln!("Cannot accept live client, err: {}", e),
+                }
+            }
+        });
+        Ok(LiveSink {
+            path: path.to_path_buf(),
+            pending,
+            clients: Vec::new(),
+            previous: None,
+        })
+    }
+}
+
+impl StorageSink for LiveSink {
+    fn name(&self) -> &str {
+        "live"
+    }
+
+    fn write_snapshot(&mut self, snapshot: &EncoDecode) -> io::Result<()> {
+        let new_clients: Vec<UnixStream> = self.pending.lock().unwrap().drain(..).collect();
+        if !self.clients.is_empty() {
+            let frame = encode_frame(&LiveDelta::between(self.previous.as_ref(), snapshot))?;
+            // A client that can't keep up, or went away, is dropped.
+            self.clients.retain_mut(|c| c.write_all(&frame).is_ok());
+        }
+        if !new_clients.is_empty() {
+            let frame = encode_frame(&LiveDelta::between(None, snapshot))?;
+            for mut client in new_clients {
+                if client.write_all(&frame).is_ok() {
+                    self.clients.push(client);
+                }
+            }
+        }
+        self.previous = Some(snapshot.clone());
+        Ok(())
+    }
+}
+
+impl Drop for LiveSink {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn encode_frame(delta: &LiveDelta) -> io::Result<Vec<u8>> {
+    let body = bincode::serialize(delta).map_err(io::Error::other)?;
+    let mut frame = (body.len() as u32).to_le_bytes().to_vec();
+    frame.extend_from_slice(&body);
+    Ok(frame)
+}
+
+/// LiveClient connects to the socket of a running server and rebuilds its snapshots.
+pub struct LiveClient {
+    stream: BufReader<UnixStream>,
+    state: EncoDecode,
+}
+
+impl LiveClient {
+    pub fn connect(path: &Path) -> io::Result<Self> {
+        Ok(LiveClient {
+            stream: BufReader::new(UnixStream::connect(path)?),
+            state: EncoDecode::default(),
+        })
+    }
+
+    /// Blocks until the server takes its next snapshot and returns it.
+    pub fn next_snapshot(&mut self) -> io::Result<&EncoDecode> {
+        let delta = self.next_delta()?;
+        delta.apply(&mut self.state);
+        Ok(&self.state)
+    }
+
+    /// Blocks until the next delta is received and returns it without applying it.
+    pub fn next_delta(&mut self) -> io::Result<LiveDelta> {
+        let mut len = [0u8; 4];
+        self.stream.read_exact(&mut len)?;
+        let len = u32::from_le_bytes(len);
+        if len > MAX_FRAME_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("live frame of {} bytes is too large", len),
+            ));
+        }
+        let mut body = vec![0u8; len as usize];
+        self.stream.read_exact(&mut body)?;
+        bincode::deserialize(&body).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(epoch: u64, pids: &[(i32, &str)]) -> EncoDecode {
+        let status = |name: &str| PidStatus {
+            ppid: Pid::new(1),
+            name: name.to_string(),
+            cmd_short: name.to_string(),
+            fdsize: 64,
+            state: "S (sleeping)".to_string(),
+            vmpeak: Some(1),
+            vmsize: Some(1),
+            rss_pages: 1,
+            rss_bytes: 4096,
+            ..Default::default()
+        };
+        EncoDecode {
+            hostname: "localghost".to_string(),
+            pid_map_list: pids
+                .iter()
+                .map(|(p, n)| (Pid::new(*p), status(n)))
+                .collect(),
+            time_epoch: epoch,
+            delay: Duration::from_secs(1),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_delta_round_trip() {
+        let a = snapshot(1, &[(1, "init"), (2, "bash")]);
+        let b = snapshot(2, &[(1, "init"), (3, "vim")]);
+        let delta = LiveDelta::between(Some(&a), &b);
+        assert!(!delta.full);
+        assert_eq!(delta.removed, vec![Pid::new(2)]);
+        assert_eq!(delta.upserted.len(), 1);
+        let mut state = a.clone();
+        delta.apply(&mut state);
+        assert_eq!(state, b);
+
+        let mut c = snapshot(3, &[(1, "init"), (2, "bash"), (3, "vim")]);
+        c.pid_map_list.get_mut(&Pid::new(3)).unwrap().user_cpu_usage = 50.0;
+        c.pid_map_list.get_mut(&Pid::new(2)).unwrap().rss_bytes = 1 << 20;
+        let delta = LiveDelta::between(None, &c);
+        let pids: Vec<Pid> = delta.upserted.iter().map(|(pid, _)| *pid).collect();
+        assert_eq!(pids, vec![Pid::new(3), Pid::new(2), Pid::new(1)]);
+    }
+
+    #[test]
+    fn test_socket() {
+        let path = std::env::temp_dir().join(format!("procshot_live_{}.sock", std::process::id()));
+        let mut sink = LiveSink::bind(&path).unwrap();
+        let mut client = LiveClient::connect(&path).unwrap();
+        // Wait for the accept thread to pick the client up.
+        while sink.pending.lock().unwrap().is_empty() {
+            thread::sleep(Duration::from_millis(5));
+        }
+        let a = snapshot(1, &[(1, "init"), (2, "bash")]);
+        let b = snapshot(2, &[(1, "init")]);
+        sink.write_snapshot(&a).unwrap();
+        sink.write_snapshot(&b).unwrap();
+        assert_eq!(client.next_snapshot().unwrap(), &a);
+        assert_eq!(client.next_delta().unwrap().removed, vec![Pid::new(2)]);
+        assert!(LiveSink::bind(&path).is_err());
+        drop(sink);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,155 @@
+//! Single append-only log file sink.
+//!
+//! Every snapshot is appended to one file as a little endian u32 length followed by the bincode
+//! encoded `EncoDecode`, the framing of the live socket. A single file is cheaper than one file
+//! per snapshot on filesystems with many small files, and can be shipped with tools that follow
+//! a growing file. `read_log` reads it back.
+//!
+//! A server killed while appending leaves a truncated frame at the end of the file. Readers stop
+//! before it, and `AppendLogSink::open` cuts it off before appending again.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use super::StorageSink;
+use crate::EncoDecode;
+
+/// Frames larger than this are taken for a corrupt length, and end the log.
+const MAX_FRAME_LEN: u32 = 256 << 20;
+
+/// AppendLogSink appends every snapshot to a log file.
+pub struct AppendLogSink {
+    path: PathBuf,
+    name: String,
+    file: BufWriter<File>,
+}
+
+impl AppendLogSink {
+    /// Opens the log at `path`, creating it if missing and cutting off a truncated frame left at
+    /// its end.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        let valid = read_frames(BufReader::new(&file), |_| Ok(()))?;
+        if valid < file.metadata()?.len() {
+            eprintln!(
+                "Cutting off a truncated snapshot at the end of {}",
+                path.display()
+            );
+            file.set_len(valid)?;
+        }
+        Ok(AppendLogSink {
+            path: path.to_path_buf(),
+            name: format!("log {}", path.display()),
+            file: BufWriter::new(file),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl StorageSink for AppendLogSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn write_snapshot(&mut self, snapshot: &EncoDecode) -> io::Result<()> {
+        let body = bincode::serialize(snapshot).map_err(io::Error::other)?;
+        self.file.write_all(&(body.len() as u32).to_le_bytes())?;
+        self.file.write_all(&body)?;
+        // A frame is either wholly in the file, or truncated at its end.
+        self.file.flush()
+    }
+}
+
+/// Reads every snapshot of the log at `path`, oldest first.
+pub fn read_log(path: &Path) -> io::Result<Vec<EncoDecode>> {
+    let mut snapshots = Vec::new();
+    read_frames(BufReader::new(File::open(path)?), |body| {
+        snapshots.push(
+            bincode::deserialize(body)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+        );
+        Ok(())
+    })?;
+    Ok(snapshots)
+}
+
+/// Calls `each` with the body of every complete frame of `reader`, and returns the length of the
+/// frames read.
+pub(crate) fn read_frames(
+    mut reader: impl Read,
+    mut each: impl FnMut(&[u8]) -> io::Result<()>,
+) -> io::Result<u64> {
+    let mut valid = 0;
+    let mut body = Vec::new();
+    loop {
+        let mut len = [0u8; 4];
+        match reader.read_exact(&mut len) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(valid),
+            r => r?,
+        }
+        let len = u32::from_le_bytes(len);
+        if len > MAX_FRAME_LEN {
+            return Ok(valid);
+        }
+        body.resize(len as usize, 0);
+        match reader.read_exact(&mut body) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(valid),
+            r => r?,
+        }
+        each(&body)?;
+        valid += 4 + len as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::time::Duration;
+
+    fn snapshot(epoch: u64) -> EncoDecode {
+        EncoDecode {
+            hostname: "localghost".to_string(),
+            time_epoch: epoch,
+            delay: Duration::from_secs(1),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_append_log() {
+        let path = std::env::temp_dir().join(format!("procshot_log_{}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut sink = AppendLogSink::open(&path).unwrap();
+        sink.write_snapshot(&snapshot(1)).unwrap();
+        sink.write_snapshot(&snapshot(2)).unwrap();
+        drop(sink);
+        // A frame cut short by a crash.
+        let len = fs::metadata(&path).unwrap().len();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&100u32.to_le_bytes()).unwrap();
+        file.write_all(b"abc").unwrap();
+        drop(file);
+        assert_eq!(read_log(&path).unwrap().len(), 2);
+
+        let mut sink = AppendLogSink::open(&path).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+        sink.write_snapshot(&snapshot(3)).unwrap();
+        let epochs: Vec<u64> = read_log(&path)
+            .unwrap()
+            .iter()
+            .map(|s| s.time_epoch)
+            .collect();
+        assert_eq!(epochs, vec![1, 2, 3]);
+        fs::remove_file(&path).unwrap();
+    }
+}
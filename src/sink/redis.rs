@@ -0,0 +1,192 @@
+//! Redis (or any RESP compatible server, eg: keydb) live cache sink.
+//!
+//! After every snapshot the JSON encoded `SnapshotSummary` is stored under
+//! `<prefix><hostname>` with a TTL, so dashboards can read the current state of every host
+//! cheaply, and a host that stops reporting disappears once its key expires.
+
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use super::StorageSink;
+use crate::cpu::CpuTimes;
+use crate::summary::SnapshotSummary;
+use crate::EncoDecode;
+
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Connection settings parsed from a `redis://[:password@]host[:port][/db]` url.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RedisAddr {
+    pub host: String,
+    pub port: u16,
+    pub password: Option<String>,
+    pub db: u32,
+}
+
+impl RedisAddr {
+    pub fn parse(url: &str) -> io::Result<Self> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid redis url {}", url),
+            )
+        };
+        let rest = url.strip_prefix("redis://").unwrap_or(url);
+        let (auth, rest) = match rest.rfind('@') {
+            Some(i) => (Some(&rest[..i]), &rest[i + 1..]),
+            None => (None, rest),
+        };
+        let (hostport, db) = match rest.find('/') {
+            Some(i) if i + 1 < rest.len() => {
+                (&rest[..i], rest[i + 1..].parse().map_err(|_| invalid())?)
+            }
+            Some(i) => (&rest[..i], 0),
+            None => (rest, 0),
+        };
+        let (host, port) = match hostport.rfind(':') {
+            Some(i) => (&hostport[..i], hostport[i + 1..].parse().map_err(|_| invalid())?),
+            None => (hostport, 6379),
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        // Only the password part of user:password is used, as with AUTH <password>.
+        let password = auth.map(|a| a.rsplit(':').next().unwrap_or(a).to_string());
+        Ok(RedisAddr {
+            host: host.to_string(),
+            port,
+            password,
+            db,
+        })
+    }
+}
+
+/// RedisSink stores the latest summary of each snapshot in Redis.
+pub struct RedisSink {
+    addr: RedisAddr,
+    key_prefix: String,
+    ttl: Duration,
+    conn: Option<BufReader<TcpStream>>,
+    /// CPU times of the previous snapshot, for the steal percentage.
+    previous: Option<CpuTimes>,
+}
+
+impl RedisSink {
+    /// `ttl` should be a few times the scan delay, so a single slow iteration doesn't expire the
+    /// key. The connection is opened lazily and reopened after errors.
+    pub fn new(url: &str, key_prefix: &str, ttl: Duration) -> io::Result<Self> {
+        Ok(RedisSink {
+            addr: RedisAddr::parse(url)?,
+            key_prefix: key_prefix.to_string(),
+            ttl,
+            conn: None,
+            previous: None,
+        })
+    }
+
+    fn connect(&self) -> io::Result<BufReader<TcpStream>> {
+        let stream = TcpStream::connect((self.addr.host.as_str(), self.addr.port))?;
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+        let mut conn = BufReader::new(stream);
+        if let Some(password) = &self.addr.password {
+            command(&mut conn, &[b"AUTH", password.as_bytes()])?;
+        }
+        if self.addr.db != 0 {
+            command(&mut conn, &[b"SELECT", self.addr.db.to_string().as_bytes()])?;
+        }
+        Ok(conn)
+    }
+
+    fn set(&mut self, key: &str, value: &[u8]) -> io::Result<()> {
+        if self.conn.is_none() {
+            self.conn = Some(self.connect()?);
+        }
+        let ttl = self.ttl.as_secs().max(1).to_string();
+        let conn = self.conn.as_mut().unwrap();
+        let result = command(conn, &[b"SET", key.as_bytes(), value, b"EX", ttl.as_bytes()]);
+        if result.is_err() {
+            self.conn = None;
+        }
+        result
+    }
+}
+
+impl StorageSink for RedisSink {
+    fn name(&self) -> &str {
+        "redis"
+    }
+
+    fn write_snapshot(&mut self, snapshot: &EncoDecode) -> io::Result<()> {
+        let summary = SnapshotSummary::new(snapshot, self.previous.as_ref());
+        self.previous = Some(snapshot.cpu_times);
+        let value = serde_json::to_vec(&summary).map_err(io::Error::other)?;
+        let key = format!("{}{}", self.key_prefix, snapshot.hostname);
+        self.set(&key, &value)
+    }
+}
+
+/// Encodes a command as a RESP array of bulk strings.
+fn encode_command(args: &[&[u8]]) -> Vec<u8> {
+    let mut buf = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        buf.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        buf.extend_from_slice(arg);
+        buf.extend_from_slice(b"\r\n");
+    }
+    buf
+}
+
+/// Sends a command and reads a simple status reply.
+fn command(conn: &mut BufReader<TcpStream>, args: &[&[u8]]) -> io::Result<()> {
+    conn.get_mut().write_all(&encode_command(args))?;
+    let mut reply = String::new();
+    if conn.read_line(&mut reply)? == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "redis closed the connection",
+        ));
+    }
+    match reply.as_bytes().first() {
+        Some(b'+') => Ok(()),
+        _ => Err(io::Error::other(format!("redis replied {}", reply.trim_end()))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_url() {
+        assert_eq!(
+            RedisAddr::parse("redis://localhost").unwrap(),
+            RedisAddr {
+                host: "localhost".to_string(),
+                port: 6379,
+                password: None,
+                db: 0
+            }
+        );
+        assert_eq!(
+            RedisAddr::parse("redis://:s3cret@10.0.0.1:6380/2").unwrap(),
+            RedisAddr {
+                host: "10.0.0.1".to_string(),
+                port: 6380,
+                password: Some("s3cret".to_string()),
+                db: 2
+            }
+        );
+        assert!(RedisAddr::parse("redis://:6379").is_err());
+    }
+
+    #[test]
+    fn test_encode_command() {
+        assert_eq!(
+            encode_command(&[b"SET", b"k", b"v"]),
+            b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n".to_vec()
+        );
+    }
+}
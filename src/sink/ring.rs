@@ -0,0 +1,102 @@
+//! In-memory ring buffer sink.
+//!
+//! Keeps the last snapshots in memory, for programs embedding the server that want the recent
+//! history without reading it back from the datadir. The sink is moved into `ScanOptions::sinks`
+//! and runs on the server thread, `RingBufferSink::handle` returns a `RingBuffer` reading the same
+//! buffer from other threads.
+
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use super::StorageSink;
+use crate::EncoDecode;
+
+/// RingBufferSink keeps the last `capacity` snapshots.
+pub struct RingBufferSink {
+    buffer: RingBuffer,
+}
+
+/// RingBuffer reads the snapshots kept by a `RingBufferSink`.
+#[derive(Clone)]
+pub struct RingBuffer {
+    capacity: usize,
+    snapshots: Arc<Mutex<VecDeque<EncoDecode>>>,
+}
+
+impl RingBufferSink {
+    /// Keeps the last `capacity` snapshots, at least one.
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        RingBufferSink {
+            buffer: RingBuffer {
+                capacity,
+                snapshots: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            },
+        }
+    }
+
+    pub fn handle(&self) -> RingBuffer {
+        self.buffer.clone()
+    }
+}
+
+impl RingBuffer {
+    /// Returns the snapshots kept, oldest first.
+    pub fn snapshots(&self) -> Vec<EncoDecode> {
+        self.snapshots.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Returns the newest snapshot, if any.
+    pub fn latest(&self) -> Option<EncoDecode> {
+        self.snapshots.lock().unwrap().back().cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.snapshots.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl StorageSink for RingBufferSink {
+    fn name(&self) -> &str {
+        "ring buffer"
+    }
+
+    fn write_snapshot(&mut self, snapshot: &EncoDecode) -> io::Result<()> {
+        let mut snapshots = self.buffer.snapshots.lock().unwrap();
+        if snapshots.len() == self.buffer.capacity {
+            snapshots.pop_front();
+        }
+        snapshots.push_back(snapshot.clone());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_ring_buffer() {
+        let mut sink = RingBufferSink::new(2);
+        let buffer = sink.handle();
+        assert!(buffer.is_empty());
+        for epoch in 1..=3 {
+            sink.write_snapshot(&EncoDecode {
+                hostname: "localghost".to_string(),
+                time_epoch: epoch,
+                delay: Duration::from_secs(1),
+                ..Default::default()
+            })
+            .unwrap();
+        }
+        let epochs: Vec<u64> = buffer.snapshots().iter().map(|s| s.time_epoch).collect();
+        assert_eq!(epochs, vec![2, 3]);
+        assert_eq!(buffer.latest().unwrap().time_epoch, 3);
+    }
+}
@@ -0,0 +1,90 @@
+//! Streaming sink sending every snapshot to a collector over TCP.
+//!
+//! Hosts without a local disk worth keeping, or whose history should be kept in one place, send
+//! their snapshots to a `collector`, which stores them in a datadir per host. Frames are a little
+//! endian u32 length followed by the snapshot as written to a `.procshot` file, header included
+//! (see `store::SerializationFormat`), so the collector stores them as they are received.
+//!
+//! With `tls::TlsFiles`, the stream is encrypted and both ends authenticate each other, see the
+//! `tls` module. The connection is opened lazily and reopened after errors, and after the
+//! certificate was rotated. A snapshot whose frame couldn't be sent is lost for the collector, as
+//! it is still in the local datadir.
+
+use std::io;
+use std::io::Write;
+use std::net::TcpStream;
+use std::time::Duration;
+
+use super::StorageSink;
+use crate::store::SerializationFormat;
+use crate::tls::{Connection, TlsClient, TlsFiles};
+use crate::EncoDecode;
+
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// StreamSink sends the snapshots to a collector.
+pub struct StreamSink {
+    addr: String,
+    name: String,
+    tls: Option<TlsClient>,
+    conn: Option<Box<dyn Connection>>,
+}
+
+impl StreamSink {
+    /// Streams to the collector listening on `addr`, a `<host>:<port>`, encrypted if `tls` is
+    /// given. The certificate of the collector must then be valid for `<host>`.
+    pub fn new(addr: &str, tls: Option<TlsFiles>) -> io::Result<Self> {
+        let host = match addr.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => host,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid collector address {}, expected <host>:<port>", addr),
+                ))
+            }
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        Ok(StreamSink {
+            addr: addr.to_string(),
+            name: format!("stream {}", addr),
+            tls: tls.map(|files| TlsClient::new(files, host)).transpose()?,
+            conn: None,
+        })
+    }
+
+    fn connect(&self) -> io::Result<Box<dyn Connection>> {
+        let tcp = TcpStream::connect(&self.addr)?;
+        tcp.set_read_timeout(Some(IO_TIMEOUT))?;
+        tcp.set_write_timeout(Some(IO_TIMEOUT))?;
+        tcp.set_nodelay(true)?;
+        match &self.tls {
+            Some(tls) => tls.connect(tcp),
+            None => Ok(Box::new(tcp)),
+        }
+    }
+}
+
+impl StorageSink for StreamSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn write_snapshot(&mut self, snapshot: &EncoDecode) -> io::Result<()> {
+        if self.tls.as_mut().is_some_and(|tls| tls.reload()) {
+            self.conn = None;
+        }
+        let body = SerializationFormat::Bincode.encode(snapshot)?;
+        if self.conn.is_none() {
+            self.conn = Some(self.connect()?);
+        }
+        let conn = self.conn.as_mut().unwrap();
+        let result = conn
+            .write_all(&(body.len() as u32).to_le_bytes())
+            .and_then(|_| conn.write_all(&body))
+            .and_then(|_| conn.flush());
+        if result.is_err() {
+            self.conn = None;
+        }
+        result
+    }
+}
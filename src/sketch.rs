@@ -0,0 +1,435 @@
+//! Per-process percentile sketches of CPU% and rss.
+//!
+//! The server updates a `SketchStore` after every iteration and persists it to the datadir every
+//! few iterations and when it stops. The sketches are kept by day, see `WINDOW_SECS`, and since a
+//! sketch only keeps logarithmic buckets, percentile queries over long ranges can be answered from
+//! the store alone, even after the raw snapshots have been pruned.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use crate::metrics::{self, Metric};
+use crate::report::TimeRange;
+use crate::{EncoDecode, Pid};
+
+/// Name of the file the sketches are persisted to inside the datadir.
+pub const SKETCH_FILE: &str = "sketches.bin";
+
+/// Relative accuracy of the returned quantiles. A value of 0.01 means that any quantile is within
+/// 1% of the true value.
+const RELATIVE_ACCURACY: f64 = 0.01;
+
+/// LogHistogram is a mergeable quantile sketch with bounded relative error. Values are mapped to
+/// logarithmically sized buckets, so the memory used grows with the log of the value range rather
+/// than with the number of samples.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct LogHistogram {
+    /// Count of samples per bucket index.
+    buckets: BTreeMap<i32, u64>,
+    /// Samples that were zero or negative, which cannot be mapped to a log bucket.
+    zero_count: u64,
+    /// Total number of samples.
+    count: u64,
+    /// Smallest sample seen.
+    min: f64,
+    /// Largest sample seen.
+    max: f64,
+}
+
+impl Default for LogHistogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LogHistogram {
+    pub fn new() -> Self {
+        LogHistogram {
+            buckets: BTreeMap::new(),
+            zero_count: 0,
+            count: 0,
+            min: f64::MAX,
+            max: f64::MIN,
+        }
+    }
+
+    fn gamma() -> f64 {
+        (1.0 + RELATIVE_ACCURACY) / (1.0 - RELATIVE_ACCURACY)
+    }
+
+    /// Records one sample.
+    pub fn add(&mut self, value: f64) {
+        if value.is_nan() {
+            return;
+        }
+        self.count += 1;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        if value <= 0.0 {
+            self.zero_count += 1;
+            return;
+        }
+        let index = (value.ln() / Self::gamma().ln()).ceil() as i32;
+        *self.buckets.entry(index).or_insert(0) += 1;
+    }
+
+    /// Number of samples recorded.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Merges another sketch into this one.
+    pub fn merge(&mut self, other: &LogHistogram) {
+        for (index, count) in &other.buckets {
+            *self.buckets.entry(*index).or_insert(0) += count;
+        }
+        self.zero_count += other.zero_count;
+        self.count += other.count;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+
+    /// Returns the estimated value at quantile `q` (0.0 to 1.0), or None if the sketch is empty.
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        if self.count == 0 || !(0.0..=1.0).contains(&q) {
+            return None;
+        }
+        let rank = (q * (self.count - 1) as f64) as u64;
+        if rank < self.zero_count {
+            return Some(self.min.min(0.0));
+        }
+        let gamma = Self::gamma();
+        let mut seen = self.zero_count;
+        for (index, count) in &self.buckets {
+            seen += count;
+            if seen > rank {
+                // Midpoint of the bucket (gamma^(i-1), gamma^i] in relative terms.
+                let value = 2.0 * gamma.powi(*index) / (gamma + 1.0);
+                return Some(value.max(self.min).min(self.max));
+            }
+        }
+        Some(self.max)
+    }
+}
+
+/// SketchKey identifies a process across iterations. The name is part of the key so a reused pid
+/// doesn't mix the samples of two unrelated processes.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
+pub struct SketchKey {
+    pub pid: Pid,
+    pub name: String,
+}
+
+/// Sketches for one process.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Default)]
+pub struct ProcessSketches {
+    /// Sketch of `metrics::CPU_USAGE`.
+    pub cpu: LogHistogram,
+    /// Sketch of `metrics::RSS_BYTES`.
+    pub rss: LogHistogram,
+    /// The epoch time of the last snapshot that contained this process.
+    pub last_seen: u64,
+}
+
+impl ProcessSketches {
+    /// Returns the sketch of `metric`, None for the metrics without one.
+    fn of(&self, metric: &Metric) -> Option<&LogHistogram> {
+        match metric {
+            m if *m == metrics::CPU_USAGE => Some(&self.cpu),
+            m if *m == metrics::RSS_BYTES => Some(&self.rss),
+            _ => None,
+        }
+    }
+}
+
+/// Length of the windows the sketches are kept by, in seconds. Ranges are answered with whole
+/// windows.
+pub const WINDOW_SECS: u64 = 24 * 60 * 60;
+
+/// Returns the start of the window `epoch` falls in.
+pub fn window_of(epoch: u64) -> u64 {
+    epoch - epoch % WINDOW_SECS
+}
+
+/// SketchStore holds the sketches of every process seen since `started`, by window of
+/// `WINDOW_SECS`.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Default)]
+pub struct SketchStore {
+    /// The epoch time of the first snapshot folded into the store.
+    pub started: u64,
+    /// The epoch time of the last snapshot folded into the store.
+    pub updated: u64,
+    /// Sketches of the processes seen in each window, by start of the window.
+    pub windows: BTreeMap<u64, HashMap<SketchKey, ProcessSketches>>,
+}
+
+impl SketchStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Folds a snapshot into the store.
+    pub fn update(&mut self, snapshot: &EncoDecode) {
+        if self.started == 0 {
+            self.started = snapshot.time_epoch;
+        }
+        self.updated = snapshot.time_epoch;
+        let window = self
+            .windows
+            .entry(window_of(snapshot.time_epoch))
+            .or_default();
+        for (pid, status) in &snapshot.pid_map_list {
+            let key = SketchKey {
+                pid: *pid,
+                name: status.name.clone(),
+            };
+            let entry = window.entry(key).or_default();
+            entry.cpu.add(metrics::CPU_USAGE.of(status).unwrap_or(0.0));
+            entry.rss.add(metrics::RSS_BYTES.of(status).unwrap_or(0.0));
+            entry.last_seen = snapshot.time_epoch;
+        }
+    }
+
+    /// Drops the windows that ended before `before` (epoch seconds), so the store doesn't grow
+    /// forever.
+    pub fn expire(&mut self, before: u64) {
+        self.windows
+            .retain(|start, _| start.saturating_add(WINDOW_SECS) > before);
+    }
+
+    /// Returns the sketches of the windows overlapping `range`, none if `range` is reversed.
+    fn in_range(
+        &self,
+        range: TimeRange,
+    ) -> impl Iterator<Item = &HashMap<SketchKey, ProcessSketches>> {
+        // `BTreeMap::range` panics on a reversed range, which `window_of` can't make one of.
+        let start = window_of(range.from);
+        let reversed = range.to < range.from;
+        self.windows
+            .range(start..=range.to.max(start))
+            .filter(move |_| !reversed)
+            .map(|(_, sketches)| sketches)
+    }
+
+    /// Returns the estimated quantile of `metric` for the process `key`, over the windows
+    /// overlapping `range`. Only `cpu_usage` and `rss_bytes` are sketched, the other metrics
+    /// have no quantiles.
+    pub fn quantile(
+        &self,
+        key: &SketchKey,
+        metric: &Metric,
+        q: f64,
+        range: TimeRange,
+    ) -> Option<f64> {
+        let mut merged = LogHistogram::new();
+        for s in self.in_range(range).filter_map(|w| w.get(key)) {
+            merged.merge(s.of(metric)?);
+        }
+        merged.quantile(q)
+    }
+
+    /// Returns the estimated quantile of `metric` over every process with the given name,
+    /// regardless of pid, over the windows overlapping `range`.
+    pub fn quantile_by_name(
+        &self,
+        name: &str,
+        metric: &Metric,
+        q: f64,
+        range: TimeRange,
+    ) -> Option<f64> {
+        let mut merged = LogHistogram::new();
+        for window in self.in_range(range) {
+            for (key, s) in window {
+                if key.name == name {
+                    merged.merge(s.of(metric)?);
+                }
+            }
+        }
+        merged.quantile(q)
+    }
+
+    /// Writes the store to `path`, replacing it atomically.
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        let encoded = bincode::serialize(self).map_err(std::io::Error::other)?;
+        let tmp = path.with_extension("tmp");
+        let mut f = File::create(&tmp)?;
+        f.write_all(&encoded)?;
+        f.sync_all()?;
+        std::fs::rename(&tmp, path)
+    }
+
+    /// Reads a store previously written with `save`.
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        let mut data = Vec::new();
+        File::open(path)?.read_to_end(&mut data)?;
+        bincode::deserialize(&data[..]).map_err(std::io::Error::other)
+    }
+
+    /// Reads the store a previous run wrote to `path`, to keep accumulating into it. Without
+    /// one, returns an empty store. A store that can't be read is moved aside to
+    /// `<path>.corrupt` along with the error, so the next `save` doesn't overwrite it.
+    pub fn resume(path: &Path) -> (Self, Option<std::io::Error>) {
+        match Self::load(path) {
+            Ok(store) => (store, None),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (Self::new(), None),
+            Err(e) => {
+                let aside = path.with_extension("bin.corrupt");
+                let e = match std::fs::rename(path, &aside) {
+                    Ok(()) => std::io::Error::new(
+                        e.kind(),
+                        format!("{}, moved it to {}", e, aside.display()),
+                    ),
+                    Err(rename) => std::io::Error::new(
+                        e.kind(),
+                        format!("{}, and cannot move it aside: {}", e, rename),
+                    ),
+                };
+                (Self::new(), Some(e))
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quantile_relative_error() {
+        let mut h = LogHistogram::new();
+        for i in 1..=1000 {
+            h.add(i as f64);
+        }
+        for &(q, expected) in &[(0.5, 500.0), (0.95, 950.0), (0.99, 990.0)] {
+            let got = h.quantile(q).unwrap();
+            assert!(
+                (got - expected).abs() / expected <= 2.0 * RELATIVE_ACCURACY,
+                "q{} got {} expected {}",
+                q,
+                got,
+                expected
+            );
+        }
+        assert_eq!(h.quantile(1.0), Some(1000.0));
+    }
+
+    #[test]
+    fn test_zero_and_empty() {
+        let mut h = LogHistogram::new();
+        assert_eq!(h.quantile(0.5), None);
+        h.add(0.0);
+        h.add(0.0);
+        h.add(10.0);
+        assert_eq!(h.quantile(0.0), Some(0.0));
+        assert_eq!(h.count(), 3);
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut a = LogHistogram::new();
+        let mut b = LogHistogram::new();
+        for i in 1..=50 {
+            a.add(i as f64);
+            b.add((i + 50) as f64);
+        }
+        a.merge(&b);
+        assert_eq!(a.count(), 100);
+        let median = a.quantile(0.5).unwrap();
+        assert!((median - 50.0).abs() <= 2.0);
+    }
+
+    fn snapshot(epoch: u64, rss_bytes: i64) -> EncoDecode {
+        let pid = Pid::new(1);
+        let mut status = crate::collect::restricted_pid_status(Path::new("/nonexistent"), pid);
+        status.name = "nginx".to_string();
+        status.rss_bytes = rss_bytes;
+        EncoDecode {
+            hostname: "localghost".to_string(),
+            pid_map_list: vec![(pid, status)].into_iter().collect(),
+            time_epoch: epoch,
+            delay: std::time::Duration::from_secs(60),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_windows() {
+        let mut store = SketchStore::new();
+        // 100 bytes on the first day, 10000 on the second.
+        for i in 0..10 {
+            store.update(&snapshot(i * 60, 100));
+            store.update(&snapshot(WINDOW_SECS + i * 60, 10000));
+        }
+        assert_eq!(store.windows.len(), 2);
+        let all = TimeRange {
+            from: 0,
+            to: 2 * WINDOW_SECS,
+        };
+        assert_eq!(
+            store.quantile_by_name("nginx", &metrics::CPU_USAGE, 0.5, all),
+            Some(0.0)
+        );
+        assert_eq!(
+            store.quantile_by_name("nginx", metrics::find("vmsize").unwrap(), 0.5, all),
+            None
+        );
+        let day = |from, to| TimeRange { from, to };
+        let median = |range| {
+            store
+                .quantile_by_name("nginx", &metrics::RSS_BYTES, 0.5, range)
+                .unwrap()
+        };
+        assert!((median(day(0, WINDOW_SECS - 1)) - 100.0).abs() <= 2.0);
+        assert!((median(day(WINDOW_SECS + 3600, WINDOW_SECS + 7200)) - 10000.0).abs() <= 200.0);
+        let key = SketchKey {
+            pid: Pid::new(1),
+            name: "nginx".to_string(),
+        };
+        let max = store.quantile(&key, &metrics::RSS_BYTES, 1.0, day(0, 2 * WINDOW_SECS));
+        assert!((max.unwrap() - 10000.0).abs() <= 200.0);
+        assert_eq!(
+            store.quantile_by_name(
+                "nginx",
+                &metrics::RSS_BYTES,
+                0.5,
+                day(2 * WINDOW_SECS, 3 * WINDOW_SECS)
+            ),
+            None
+        );
+        // A reversed range, within one window or across two, has no sketches.
+        for reversed in [day(WINDOW_SECS + 60, 0), day(WINDOW_SECS + 60, WINDOW_SECS)] {
+            let median = store.quantile_by_name("nginx", &metrics::RSS_BYTES, 0.5, reversed);
+            assert_eq!(median, None);
+        }
+
+        store.expire(WINDOW_SECS + 60);
+        assert_eq!(store.windows.keys().collect::<Vec<_>>(), vec![&WINDOW_SECS]);
+    }
+
+    #[test]
+    fn test_resume() {
+        let dir = std::env::temp_dir().join(format!("procshot_sketch_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(SKETCH_FILE);
+        let (store, err) = SketchStore::resume(&path);
+        assert!(store.windows.is_empty() && err.is_none());
+
+        let mut store = SketchStore::new();
+        store.update(&snapshot(60, 100));
+        store.save(&path).unwrap();
+        assert_eq!(SketchStore::resume(&path).0, store);
+
+        // A corrupt store is moved aside instead of being overwritten by the next save.
+        std::fs::write(&path, b"not sketches").unwrap();
+        let (resumed, err) = SketchStore::resume(&path);
+        assert!(resumed.windows.is_empty() && err.is_some());
+        assert!(!path.exists());
+        assert_eq!(
+            std::fs::read(dir.join("sketches.bin.corrupt")).unwrap(),
+            b"not sketches"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
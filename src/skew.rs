@@ -0,0 +1,160 @@
+//! Clock skew of the hosts snapshots are received from.
+//!
+//! Snapshots are stamped with the clock of the host that took them, so joining the snapshots of
+//! several hosts on `time_epoch` silently misaligns them if a clock drifted. Whatever receives
+//! snapshots from other hosts, eg: a consumer of the Kafka sink, passes each one to
+//! `SkewTracker::observe` with the time it was received. The difference between the two is the
+//! skew of the host's clock plus the transport delay, which is never negative, so the smallest
+//! difference seen for a host is the best estimate of its skew. Hosts whose estimate is beyond
+//! the threshold are flagged, and `observe` records the skew of every snapshot in its labels.
+
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::EncoDecode;
+
+/// Label set by `SkewTracker::observe` on received snapshots, with the skew in milliseconds.
+pub const SKEW_LABEL: &str = "clock_skew_ms";
+
+/// Skew below which a host is not flagged when no threshold is given.
+pub const DEFAULT_THRESHOLD: Duration = Duration::from_secs(2);
+
+/// HostSkew is what is known about the clock of one host.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HostSkew {
+    pub hostname: String,
+    /// Number of snapshots received from the host.
+    pub snapshots: usize,
+    /// Estimated skew in milliseconds, positive if the host's clock is behind the receiver's.
+    pub skew_ms: i64,
+    /// Difference between the receipt time and `time_epoch` of the last snapshot, in
+    /// milliseconds.
+    pub last_ms: i64,
+    /// True if the estimated skew is beyond the threshold.
+    pub drifting: bool,
+}
+
+/// SkewTracker estimates the clock skew of every host it receives snapshots from.
+#[derive(Debug)]
+pub struct SkewTracker {
+    threshold: Duration,
+    hosts: BTreeMap<String, HostSkew>,
+}
+
+impl Default for SkewTracker {
+    fn default() -> Self {
+        SkewTracker::new(DEFAULT_THRESHOLD)
+    }
+}
+
+impl SkewTracker {
+    /// Flags hosts whose clock is off by more than `threshold`, either way.
+    pub fn new(threshold: Duration) -> Self {
+        SkewTracker {
+            threshold,
+            hosts: BTreeMap::new(),
+        }
+    }
+
+    /// Records `snapshot`, received at `received`, sets its `SKEW_LABEL` label and returns the
+    /// updated skew of its host.
+    pub fn observe(&mut self, snapshot: &mut EncoDecode, received: SystemTime) -> &HostSkew {
+        let received_ms = match received.duration_since(UNIX_EPOCH) {
+            Ok(d) => d.as_millis() as i64,
+            Err(e) => -(e.duration().as_millis() as i64),
+        };
+        let diff = received_ms - snapshot.time_epoch as i64 * 1000;
+        snapshot
+            .labels
+            .insert(SKEW_LABEL.to_string(), diff.to_string());
+        let threshold = self.threshold.as_millis() as i64;
+        let host = self
+            .hosts
+            .entry(snapshot.hostname.clone())
+            .or_insert_with(|| HostSkew {
+                hostname: snapshot.hostname.clone(),
+                snapshots: 0,
+                skew_ms: diff,
+                last_ms: diff,
+                drifting: false,
+            });
+        host.snapshots += 1;
+        host.skew_ms = host.skew_ms.min(diff);
+        host.last_ms = diff;
+        host.drifting = host.skew_ms.abs() > threshold;
+        host
+    }
+
+    /// Returns every host seen, by hostname.
+    pub fn report(&self) -> Vec<&HostSkew> {
+        self.hosts.values().collect()
+    }
+
+    /// Returns the hosts whose clock is off by more than the threshold.
+    pub fn drifting(&self) -> Vec<&HostSkew> {
+        self.hosts.values().filter(|h| h.drifting).collect()
+    }
+}
+
+/// Prints one line per host: hostname, snapshots, estimated and last skew, and whether it drifts.
+pub fn print_report(hosts: &[&HostSkew]) {
+    println!(
+        "{:<24}  {:>9}  {:>10}  {:>10}  drifting",
+        "host", "snapshots", "skew ms", "last ms"
+    );
+    for h in hosts {
+        println!(
+            "{:<24}  {:>9}  {:>10}  {:>10}  {}",
+            h.hostname,
+            h.snapshots,
+            h.skew_ms,
+            h.last_ms,
+            if h.drifting { "yes" } else { "no" }
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(hostname: &str, time_epoch: u64) -> EncoDecode {
+        EncoDecode {
+            hostname: hostname.to_string(),
+            time_epoch,
+            delay: Duration::from_secs(60),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_observe() {
+        let at = |ms: u64| UNIX_EPOCH + Duration::from_millis(ms);
+        let mut tracker = SkewTracker::default();
+        // In sync, received after 300ms then 150ms of transport.
+        let mut s = snapshot("web-0", 1000);
+        tracker.observe(&mut s, at(1_000_300));
+        assert_eq!(s.labels[SKEW_LABEL], "300");
+        tracker.observe(&mut snapshot("web-0", 1060), at(1_060_150));
+        // 10s ahead of the receiver.
+        tracker.observe(&mut snapshot("web-1", 1010), at(1_000_200));
+        // 5s behind.
+        tracker.observe(&mut snapshot("web-2", 1000), at(1_005_100));
+
+        let skews: Vec<(&str, i64, i64, bool)> = tracker
+            .report()
+            .iter()
+            .map(|h| (h.hostname.as_str(), h.skew_ms, h.last_ms, h.drifting))
+            .collect();
+        assert_eq!(
+            skews,
+            vec![
+                ("web-0", 150, 150, false),
+                ("web-1", -9800, -9800, true),
+                ("web-2", 5100, 5100, true),
+            ]
+        );
+        assert_eq!(tracker.report()[0].snapshots, 2);
+        assert_eq!(tracker.drifting().len(), 2);
+    }
+}
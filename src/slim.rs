@@ -0,0 +1,297 @@
+//! Reduced decoding of snapshots for queries that only need a few fields.
+//!
+//! Most of the bytes of a snapshot, and most of the allocations made to decode it, are in the
+//! command lines and the other strings of every process. Queries looking only at the pid, name,
+//! memory and CPU of the processes decode a `SlimSnapshot` instead of an `EncoDecode`, see
+//! `store::read_slim_snapshot` and `query::par_map_slim`. bincode isn't self-describing, so
+//! every field is still walked, but the ones left out are borrowed from the file buffer and
+//! dropped rather than copied.
+//!
+//! `WireStatus` mirrors the layout of `PidStatus` and must be kept in sync with it.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::time::Duration;
+
+use serde::de::{Deserialize, Deserializer, SeqAccess, Visitor};
+
+use crate::cgroup::Limits;
+use crate::connections::Connection;
+use crate::cpu::CpuTimes;
+use crate::diskstats::DiskStats;
+use crate::fds::FdKind;
+use crate::load::LoadAvg;
+use crate::meminfo::MemInfo;
+use crate::netdev::InterfaceStats;
+use crate::priority::SchedPolicy;
+use crate::proc_io::ProcIo;
+use crate::{EncoDecode, Pid};
+
+/// SlimProcess is the subset of `PidStatus` kept by a `SlimSnapshot`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SlimProcess {
+    pub pid: Pid,
+    pub ppid: Pid,
+    pub euid: i32,
+    pub name: String,
+    pub fdsize: u32,
+    pub rss_bytes: i64,
+    pub user_cpu_usage: f64,
+    pub sys_cpu_usage: f64,
+    pub restricted: bool,
+}
+
+/// SlimSnapshot is an `EncoDecode` without the command lines and other strings of its
+/// processes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SlimSnapshot {
+    pub hostname: String,
+    pub time_epoch: u64,
+    pub delay: Duration,
+    pub total_cpu_time: u64,
+    pub cpu_times: CpuTimes,
+    pub per_cpu: BTreeMap<u32, CpuTimes>,
+    pub system_memory: MemInfo,
+    pub load_average: LoadAvg,
+    pub uptime: Duration,
+    pub disks: Vec<DiskStats>,
+    pub interfaces: Vec<InterfaceStats>,
+    /// The processes of the snapshot, in no particular order.
+    pub processes: Vec<SlimProcess>,
+}
+
+impl SlimSnapshot {
+    /// Decodes a bincode encoded `EncoDecode`.
+    pub fn decode(data: &[u8]) -> bincode::Result<Self> {
+        let wire: WireSnapshot = bincode::deserialize(data)?;
+        Ok(SlimSnapshot {
+            hostname: wire.hostname,
+            time_epoch: wire.time_epoch,
+            delay: wire.delay,
+            total_cpu_time: wire.total_cpu_time,
+            cpu_times: wire.cpu_times,
+            per_cpu: wire.per_cpu,
+            system_memory: wire.system_memory,
+            load_average: wire.load_average,
+            uptime: wire.uptime,
+            disks: wire.disks,
+            interfaces: wire.interfaces,
+            processes: wire
+                .pid_map_list
+                .into_iter()
+                .map(|(pid, s)| SlimProcess {
+                    pid,
+                    ppid: s.ppid,
+                    euid: s.euid,
+                    name: s.name,
+                    fdsize: s.fdsize,
+                    rss_bytes: s.rss_bytes,
+                    user_cpu_usage: s.user_cpu_usage,
+                    sys_cpu_usage: s.sys_cpu_usage,
+                    restricted: s.restricted,
+                })
+                .collect(),
+        })
+    }
+}
+
+impl From<&EncoDecode> for SlimSnapshot {
+    fn from(snapshot: &EncoDecode) -> Self {
+        SlimSnapshot {
+            hostname: snapshot.hostname.clone(),
+            time_epoch: snapshot.time_epoch,
+            delay: snapshot.delay,
+            total_cpu_time: snapshot.total_cpu_time,
+            cpu_times: snapshot.cpu_times,
+            per_cpu: snapshot.per_cpu.clone(),
+            system_memory: snapshot.system_memory,
+            load_average: snapshot.load_average,
+            uptime: snapshot.uptime,
+            disks: snapshot.disks.clone(),
+            interfaces: snapshot.interfaces.clone(),
+            processes: snapshot
+                .pid_map_list
+                .iter()
+                .map(|(pid, s)| SlimProcess {
+                    pid: *pid,
+                    ppid: s.ppid,
+                    euid: s.euid,
+                    name: s.name.clone(),
+                    fdsize: s.fdsize,
+                    rss_bytes: s.rss_bytes,
+                    user_cpu_usage: s.user_cpu_usage,
+                    sys_cpu_usage: s.sys_cpu_usage,
+                    restricted: s.restricted,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// The fields of `EncoDecode` up to the last one `SlimSnapshot` needs. Trailing fields are left
+/// undecoded.
+#[derive(Deserialize)]
+struct WireSnapshot<'a> {
+    hostname: String,
+    #[serde(borrow)]
+    pid_map_list: HashMap<Pid, WireStatus<'a>>,
+    time_epoch: u64,
+    delay: Duration,
+    total_cpu_time: u64,
+    cpu_times: CpuTimes,
+    per_cpu: BTreeMap<u32, CpuTimes>,
+    system_memory: MemInfo,
+    load_average: LoadAvg,
+    uptime: Duration,
+    disks: Vec<DiskStats>,
+    interfaces: Vec<InterfaceStats>,
+}
+
+/// Every field of `PidStatus`, in order.
+#[derive(Deserialize)]
+#[allow(dead_code)]
+struct WireStatus<'a> {
+    ppid: Pid,
+    euid: i32,
+    cmd_long: Skipped,
+    name: String,
+    cmd_short: &'a str,
+    tracerpid: Pid,
+    fdsize: u32,
+    #[serde(borrow)]
+    fds: Option<Vec<WireFdInfo<'a>>>,
+    connections: Option<Vec<Connection>>,
+    state: &'a str,
+    vmpeak: Option<u64>,
+    vmsize: Option<u64>,
+    rss_pages: i64,
+    rss_bytes: i64,
+    rsslim_bytes: u64,
+    shared_pages: u64,
+    text_pages: u64,
+    data_pages: u64,
+    rss_pct_of_limit: Option<f64>,
+    processor_last_executed: Option<i32>,
+    sched_policy: Option<SchedPolicy>,
+    rt_priority: Option<u32>,
+    utime: u64,
+    stime: u64,
+    io: Option<ProcIo>,
+    service_hint: Option<&'a str>,
+    runtime: Option<&'a str>,
+    cgroup: Option<&'a str>,
+    cgroup_limits: Option<Limits>,
+    user_cpu_usage: f64,
+    sys_cpu_usage: f64,
+    restricted: bool,
+    vanished_during_scan: bool,
+    extensions: BTreeMap<&'a str, u64>,
+}
+
+/// Every field of `FdInfo`, in order.
+#[derive(Deserialize)]
+#[allow(dead_code)]
+struct WireFdInfo<'a> {
+    fd: u32,
+    kind: FdKind,
+    target: &'a str,
+}
+
+/// A sequence of strings that is walked without being kept.
+struct Skipped;
+
+impl<'de> Deserialize<'de> for Skipped {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct SkipVisitor;
+
+        impl<'de> Visitor<'de> for SkipVisitor {
+            type Value = Skipped;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a sequence of strings")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Skipped, A::Error> {
+                while seq.next_element::<&'de str>()?.is_some() {}
+                Ok(Skipped)
+            }
+        }
+
+        deserializer.deserialize_seq(SkipVisitor)
+    }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use super::*;
+    use crate::collect::{restricted_pid_status, PROC_ROOT};
+    use std::path::Path;
+
+    #[test]
+    fn test_decode() {
+        let mut status = restricted_pid_status(Path::new(PROC_ROOT), Pid::current());
+        status.cmd_long = vec!["java".to_string(), "-Xmx4g".repeat(1000)];
+        status.name = "java".to_string();
+        status.state = "S (sleeping)".to_string();
+        status.rss_bytes = 4096;
+        status.rss_pct_of_limit = Some(12.5);
+        status.sys_cpu_usage = 1.5;
+        status.restricted = false;
+        status.sched_policy = Some(SchedPolicy::Fifo);
+        status.rt_priority = Some(50);
+        status.fds = Some(vec![crate::fds::FdInfo {
+            fd: 3,
+            kind: FdKind::File,
+            target: "/var/log/java/gc.log".to_string(),
+        }]);
+        let snapshot = EncoDecode {
+            hostname: "localghost".to_string(),
+            pid_map_list: vec![
+                (Pid::new(7), status),
+                (
+                    Pid::new(8),
+                    restricted_pid_status(Path::new(PROC_ROOT), Pid::new(8)),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+            time_epoch: 1563617611,
+            delay: Duration::from_secs(60),
+            total_cpu_time: 1000,
+            system_memory: MemInfo {
+                total_kb: 16314140,
+                ..Default::default()
+            },
+            uptime: Duration::from_secs(350735),
+            disks: vec![crate::diskstats::DiskStats::parse(
+                "8 0 sda 4160 1207 345218 1722 7581 8853 380514 10238 0 8508 12652",
+            )
+            .unwrap()],
+            labels: vec![("cloud.zone".to_string(), "eu-west-1a".to_string())]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+        let slim = SlimSnapshot::decode(&bincode::serialize(&snapshot).unwrap()).unwrap();
+        assert_eq!(slim.time_epoch, 1563617611);
+        assert_eq!(slim.total_cpu_time, 1000);
+        assert_eq!(slim.system_memory.total_kb, 16314140);
+        assert_eq!(slim.uptime, Duration::from_secs(350735));
+        assert_eq!(slim.disks[0].sectors_written, 380514);
+        assert_eq!(slim.processes.len(), 2);
+        let p = slim
+            .processes
+            .iter()
+            .find(|p| p.pid == Pid::new(7))
+            .unwrap();
+        assert_eq!(p.name, "java");
+        assert_eq!((p.rss_bytes, p.sys_cpu_usage), (4096, 1.5));
+        assert!(!p.restricted);
+        let p = slim
+            .processes
+            .iter()
+            .find(|p| p.pid == Pid::new(8))
+            .unwrap();
+        assert!(p.restricted);
+    }
+}
@@ -0,0 +1,79 @@
+//! Memory breakdown from /proc/<pid>/statm.
+//!
+//! rss alone doesn't tell a process heavy on code (many shared libraries, mapped binaries) from
+//! one heavy on data (heap, anonymous mappings). statm splits it into shared, text and data pages.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::Pid;
+
+/// StatM holds the columns of /proc/<pid>/statm, in pages.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct StatM {
+    /// Total program size, the same as VmSize.
+    pub size: u64,
+    /// Resident set size, the same as VmRSS.
+    pub resident: u64,
+    /// Resident file backed pages, which may be shared with other processes (RssFile + RssShmem).
+    pub shared: u64,
+    /// Text (code) of the executable, excluding the shared libraries.
+    pub text: u64,
+    /// Data + stack, resident or not.
+    pub data: u64,
+}
+
+impl StatM {
+    /// Parses the content of a statm file. The `lib` and `dt` columns are always 0 since Linux 2.6
+    /// and are ignored.
+    pub fn parse(content: &str) -> Option<StatM> {
+        let values: Vec<u64> = content
+            .split_whitespace()
+            .map(|v| v.parse().ok())
+            .collect::<Option<_>>()?;
+        if values.len() < 6 {
+            return None;
+        }
+        Some(StatM {
+            size: values[0],
+            resident: values[1],
+            shared: values[2],
+            text: values[3],
+            data: values[5],
+        })
+    }
+}
+
+/// Reads /proc/<pid>/statm.
+pub fn read(pid: Pid) -> io::Result<StatM> {
+    read_in(Path::new(crate::collect::PROC_ROOT), pid)
+}
+
+/// Reads <pid>/statm under `proc_root`.
+pub fn read_in(proc_root: &Path, pid: Pid) -> io::Result<StatM> {
+    let path = proc_root.join(pid.to_string()).join("statm");
+    let content = fs::read_to_string(&path)?;
+    StatM::parse(&content).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Cannot parse {}: {}", path.display(), content.trim_end()),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let m = StatM::parse("2931 1089 878 12 0 201 0\n").unwrap();
+        assert_eq!(m.resident, 1089);
+        assert_eq!(m.shared, 878);
+        assert_eq!(m.text, 12);
+        assert_eq!(m.data, 201);
+        assert_eq!(StatM::parse("1 2 3"), None);
+        assert!(read(Pid::current()).unwrap().resident > 0);
+    }
+}
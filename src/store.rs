@@ -0,0 +1,811 @@
+//! Helpers to find and read the snapshots stored in a datadir.
+//!
+//! Snapshots live either directly in the datadir (the warm tier), or gzip compressed in its
+//! `cold/` subdirectory once `tier::demote` moved them there. Both tiers are listed and read
+//! transparently.
+//!
+//! The server may also compress the snapshots it writes to the warm tier, see `CompressionMode`,
+//! and encode them in another format than bincode, see `SerializationFormat`.
+//!
+//! Files are listed by name, but read according to their content: gzip and zstd compression are
+//! detected from the magic bytes starting their streams, the serialization format from the
+//! `SNAPSHOT_MAGIC` header and the snapshots written before the header was added, bincode or
+//! JSON, from their first byte, so a datadir holding files of several formats, eg: in the middle
+//! of a migration or after the compression was changed, stays readable.
+//!
+//! The header also holds the `FORMAT_VERSION` the snapshot was written in. A file written by a
+//! newer version of procshot fails with `DecodeError::Version`, which tells to upgrade, instead of
+//! a decoding error that can't be told apart from a corrupt file. Snapshots of older versions are
+//! decoded in their own layout and upgraded to the current one, see the `versioned` module.
+
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::de::DeserializeOwned;
+
+use crate::error::ProcshotError;
+use crate::slim::SlimSnapshot;
+use crate::versioned::{EncoDecodeV1, EncoDecodeV2, VersionedSnapshot};
+use crate::EncoDecode;
+
+/// Extension of the snapshot files written by the server.
+pub const SNAPSHOT_EXTENSION: &str = "procshot";
+
+/// Subdirectory of the datadir holding the cold tier.
+pub const COLD_DIR: &str = "cold";
+
+/// Extension appended to the name of compressed snapshot files.
+pub const GZIP_EXTENSION: &str = "gz";
+
+/// Extension appended to the name of zstd compressed snapshot files.
+pub const ZSTD_EXTENSION: &str = "zst";
+
+/// CompressionMode is how the server compresses the snapshot files it writes. Compressed files
+/// are named `<epoch>.procshot.gz` or `<epoch>.procshot.zst`, but decoded according to their
+/// content, so files written before the mode was changed still decode.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum CompressionMode {
+    #[default]
+    None,
+    Gzip,
+    /// Smaller and faster than gzip, needs the zstd feature to write and to read.
+    Zstd,
+}
+
+impl CompressionMode {
+    /// Returns the extension appended to the names of the files, if any.
+    pub fn extension(self) -> Option<&'static str> {
+        match self {
+            CompressionMode::None => None,
+            CompressionMode::Gzip => Some(GZIP_EXTENSION),
+            CompressionMode::Zstd => Some(ZSTD_EXTENSION),
+        }
+    }
+
+    /// Compresses an encoded snapshot at the default level.
+    pub fn compress(self, data: Vec<u8>) -> io::Result<Vec<u8>> {
+        self.compress_at(data, CompressionLevel::Default)
+    }
+
+    /// Compresses an encoded snapshot at `level`.
+    pub fn compress_at(self, data: Vec<u8>, level: CompressionLevel) -> io::Result<Vec<u8>> {
+        match self {
+            CompressionMode::None => Ok(data),
+            CompressionMode::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::new(level.gzip()));
+                encoder.write_all(&data)?;
+                encoder.finish()
+            }
+            #[cfg(feature = "zstd")]
+            CompressionMode::Zstd => zstd::encode_all(&data[..], level.zstd()),
+            #[cfg(not(feature = "zstd"))]
+            CompressionMode::Zstd => Err(no_zstd()),
+        }
+    }
+}
+
+impl fmt::Display for CompressionMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            CompressionMode::None => "none",
+            CompressionMode::Gzip => "gzip",
+            CompressionMode::Zstd => "zstd",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for CompressionMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(CompressionMode::None),
+            "gzip" => Ok(CompressionMode::Gzip),
+            #[cfg(feature = "zstd")]
+            "zstd" => Ok(CompressionMode::Zstd),
+            #[cfg(not(feature = "zstd"))]
+            "zstd" => Err(no_zstd().to_string()),
+            _ => Err(format!(
+                "unknown compression {}, expected none, gzip or zstd",
+                s
+            )),
+        }
+    }
+}
+
+/// CompressionLevel trades the CPU time spent compressing a snapshot for its size. The level of
+/// each `CompressionMode` is mapped to the comparable level of its codec.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum CompressionLevel {
+    Fast,
+    #[default]
+    Default,
+    Best,
+}
+
+impl CompressionLevel {
+    /// Busy share of the host CPU, in percent, above which `adapt` lowers the level.
+    pub const BUSY_PERCENT: f64 = 85.0;
+
+    /// Busy share of the host CPU, in percent, below which `adapt` raises the level.
+    pub const IDLE_PERCENT: f64 = 30.0;
+
+    /// Level of gzip, from 0 to 9.
+    pub fn gzip(self) -> u32 {
+        match self {
+            CompressionLevel::Fast => 1,
+            CompressionLevel::Default => 6,
+            CompressionLevel::Best => 9,
+        }
+    }
+
+    /// Level of zstd, from 1 to 22.
+    pub fn zstd(self) -> i32 {
+        match self {
+            CompressionLevel::Fast => 1,
+            CompressionLevel::Default => 3,
+            CompressionLevel::Best => 9,
+        }
+    }
+
+    /// Returns the level to compress at when the host CPU was busy `busy_percent` of the last
+    /// interval: one step faster on a saturated host, one step better on an idle one, and the
+    /// same in between, so the level doesn't flap on a load hovering around a threshold.
+    pub fn adapt(self, busy_percent: f64) -> Self {
+        match self {
+            CompressionLevel::Best if busy_percent > Self::BUSY_PERCENT => {
+                CompressionLevel::Default
+            }
+            CompressionLevel::Default if busy_percent > Self::BUSY_PERCENT => {
+                CompressionLevel::Fast
+            }
+            CompressionLevel::Fast if busy_percent < Self::IDLE_PERCENT => {
+                CompressionLevel::Default
+            }
+            CompressionLevel::Default if busy_percent < Self::IDLE_PERCENT => {
+                CompressionLevel::Best
+            }
+            level => level,
+        }
+    }
+}
+
+/// Magic bytes starting the snapshot files, followed by one byte with the `FORMAT_VERSION` and
+/// one byte telling the `SerializationFormat`: 0 for bincode, 1 for JSON, 2 for MessagePack and 3
+/// for CBOR. The header is under the compression, if any.
+pub const SNAPSHOT_MAGIC: &[u8] = b"PSHT";
+
+/// Version of the layout of the snapshots written by this build. It is raised whenever a change
+/// of `EncoDecode` makes the snapshots unreadable by older builds. Snapshots of older versions,
+/// and those without header, stay readable. Versions 1 and 2 are the layouts of the releases
+/// that wrote no header, see the `versioned` module.
+pub const FORMAT_VERSION: u8 = 3;
+
+/// Length of the header of the snapshot files.
+pub const HEADER_LEN: usize = SNAPSHOT_MAGIC.len() + 2;
+
+/// DecodeError is why a snapshot doesn't decode.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodeError {
+    /// The snapshot was written in this `FORMAT_VERSION`, newer than the one of this build.
+    Version(u8),
+    /// The content is corrupt, or its serialization format isn't enabled in this build.
+    Invalid(String),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::Version(found) => write!(
+                f,
+                "format version {} is newer than the version {} this build reads, upgrade procshot",
+                found, FORMAT_VERSION
+            ),
+            DecodeError::Invalid(reason) => f.write_str(reason),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl DecodeError {
+    /// Returns the `ProcshotError` of the file at `path` failing to decode.
+    pub fn at(self, path: &Path) -> ProcshotError {
+        match self {
+            DecodeError::Version(found) => ProcshotError::VersionMismatch {
+                path: path.to_path_buf(),
+                found,
+                supported: FORMAT_VERSION,
+            },
+            DecodeError::Invalid(reason) => ProcshotError::Corrupt {
+                path: path.to_path_buf(),
+                reason,
+            },
+        }
+    }
+}
+
+/// SerializationFormat is how the server encodes the snapshot files it writes. Bincode is the
+/// most compact and the fastest, but only Rust reads it; the other formats are for reading the
+/// snapshots from other languages, eg: with the `json`, `msgpack` or `cbor2` Python modules after
+/// skipping the `HEADER_LEN` bytes of the header.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum SerializationFormat {
+    #[default]
+    Bincode,
+    Json,
+    /// Structs are encoded as maps with the names of their fields. Needs the msgpack feature.
+    MessagePack,
+    /// Needs the cbor feature.
+    Cbor,
+}
+
+impl SerializationFormat {
+    /// Returns the byte following `SNAPSHOT_MAGIC` in the snapshots of this format.
+    fn codec(self) -> u8 {
+        match self {
+            SerializationFormat::Bincode => 0,
+            SerializationFormat::Json => 1,
+            SerializationFormat::MessagePack => 2,
+            SerializationFormat::Cbor => 3,
+        }
+    }
+
+    fn from_codec(codec: u8) -> Option<Self> {
+        [
+            SerializationFormat::Bincode,
+            SerializationFormat::Json,
+            SerializationFormat::MessagePack,
+            SerializationFormat::Cbor,
+        ]
+        .iter()
+        .copied()
+        .find(|f| f.codec() == codec)
+    }
+
+    /// Encodes a snapshot, behind the `SNAPSHOT_MAGIC` header.
+    pub fn encode(self, snapshot: &EncoDecode) -> io::Result<Vec<u8>> {
+        let mut data = SNAPSHOT_MAGIC.to_vec();
+        data.push(FORMAT_VERSION);
+        data.push(self.codec());
+        match self {
+            SerializationFormat::Bincode => {
+                bincode::serialize_into(&mut data, snapshot).map_err(io::Error::other)?
+            }
+            SerializationFormat::Json => serde_json::to_writer(&mut data, snapshot)?,
+            #[cfg(feature = "msgpack")]
+            SerializationFormat::MessagePack => {
+                rmp_serde::encode::write_named(&mut data, snapshot).map_err(io::Error::other)?
+            }
+            #[cfg(feature = "cbor")]
+            SerializationFormat::Cbor => {
+                ciborium::into_writer(snapshot, &mut data).map_err(io::Error::other)?
+            }
+            #[allow(unreachable_patterns)]
+            _ => return Err(disabled_format(self)),
+        }
+        Ok(data)
+    }
+
+    /// Decodes a snapshot of this format, without its header.
+    fn decode<T: DeserializeOwned>(self, data: &[u8]) -> Result<T, String> {
+        match self {
+            SerializationFormat::Bincode => bincode::deserialize(data).map_err(|e| e.to_string()),
+            SerializationFormat::Json => serde_json::from_slice(data).map_err(|e| e.to_string()),
+            #[cfg(feature = "msgpack")]
+            SerializationFormat::MessagePack => {
+                rmp_serde::from_slice(data).map_err(|e| e.to_string())
+            }
+            #[cfg(feature = "cbor")]
+            SerializationFormat::Cbor => ciborium::from_reader(data).map_err(|e| e.to_string()),
+            #[allow(unreachable_patterns)]
+            _ => Err(disabled_format(self).to_string()),
+        }
+    }
+}
+
+impl fmt::Display for SerializationFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            SerializationFormat::Bincode => "bincode",
+            SerializationFormat::Json => "json",
+            SerializationFormat::MessagePack => "msgpack",
+            SerializationFormat::Cbor => "cbor",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for SerializationFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let format = match s {
+            "bincode" => SerializationFormat::Bincode,
+            "json" => SerializationFormat::Json,
+            "msgpack" => SerializationFormat::MessagePack,
+            "cbor" => SerializationFormat::Cbor,
+            _ => {
+                return Err(format!(
+                    "unknown serialization format {}, expected bincode, json, msgpack or cbor",
+                    s
+                ))
+            }
+        };
+        match format {
+            #[cfg(not(feature = "msgpack"))]
+            SerializationFormat::MessagePack => Err(disabled_format(format).to_string()),
+            #[cfg(not(feature = "cbor"))]
+            SerializationFormat::Cbor => Err(disabled_format(format).to_string()),
+            _ => Ok(format),
+        }
+    }
+}
+
+#[cfg_attr(all(feature = "msgpack", feature = "cbor"), allow(dead_code))]
+fn disabled_format(format: SerializationFormat) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "{} snapshots need procshot built with the {} feature",
+            format, format
+        ),
+    )
+}
+
+#[cfg(not(feature = "zstd"))]
+fn no_zstd() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "zstd compression needs procshot built with the zstd feature",
+    )
+}
+
+/// Returns the name of the snapshot file taken at `time`, a duration since the epoch. Servers
+/// sampling more than once per second use `<epoch>.<milliseconds>.procshot` names, which sort
+/// and list along with the plain `<epoch>.procshot` ones.
+pub fn snapshot_file_name(time: Duration, sub_second: bool) -> String {
+    match sub_second {
+        true => format!(
+            "{}.{:03}.{}",
+            time.as_secs(),
+            time.subsec_millis(),
+            SNAPSHOT_EXTENSION
+        ),
+        false => format!("{}.{}", time.as_secs(), SNAPSHOT_EXTENSION),
+    }
+}
+
+/// Returns the epoch a snapshot file was taken at, from its `<epoch>.procshot`,
+/// `<epoch>.<milliseconds>.procshot` or compressed `.procshot.gz` and `.procshot.zst` name.
+pub fn snapshot_epoch(path: &Path) -> Option<u64> {
+    snapshot_millis(path).map(|ms| ms / 1000)
+}
+
+/// Same as `snapshot_epoch`, in milliseconds.
+pub fn snapshot_millis(path: &Path) -> Option<u64> {
+    let name = path.file_name()?.to_str()?;
+    let name = name
+        .strip_suffix(GZIP_EXTENSION)
+        .or_else(|| name.strip_suffix(ZSTD_EXTENSION))
+        .and_then(|n| n.strip_suffix('.'))
+        .unwrap_or(name);
+    let stem = name.strip_suffix(SNAPSHOT_EXTENSION)?.strip_suffix('.')?;
+    let (secs, millis) = match stem.split_once('.') {
+        Some((secs, millis)) if millis.len() == 3 => (secs, millis.parse::<u64>().ok()?),
+        Some(_) => return None,
+        None => (stem, 0),
+    };
+    Some(secs.parse::<u64>().ok()?.checked_mul(1000)? + millis)
+}
+
+/// Magic bytes starting a gzip stream.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Magic bytes starting a zstd frame.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Returns true if the file at `path` is compressed, judging by its name.
+pub fn is_compressed(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|e| e == GZIP_EXTENSION || e == ZSTD_EXTENSION)
+}
+
+/// Lists the snapshot files of both tiers of the datadir with their epoch, oldest first. Other
+/// files (sketches, journals, ...) are ignored. If a snapshot is in both tiers, eg: after a crash
+/// in the middle of a demotion, only the warm copy is listed.
+pub fn snapshot_files(datadir: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut files = tier_files(datadir)?;
+    match tier_files(&datadir.join(COLD_DIR)) {
+        Ok(cold) => files.extend(cold),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e),
+    }
+    // Warm paths sort before the cold ones of the same time, as datadir/cold/x > datadir/x.
+    files.sort();
+    files.dedup_by_key(|(millis, _)| *millis);
+    Ok(files
+        .into_iter()
+        .map(|(millis, path)| (millis / 1000, path))
+        .collect())
+}
+
+/// Lists the snapshot files directly in `dir` with their time in milliseconds, unsorted.
+fn tier_files(dir: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    Ok(fs::read_dir(dir)?
+        .filter_map(|e| e.ok())
+        .filter_map(|e| {
+            let path = e.path();
+            snapshot_millis(&path).map(|millis| (millis, path))
+        })
+        .collect())
+}
+
+/// Returns the path of the snapshot taken at `epoch`, in whichever tier it is. Only the whole
+/// second snapshot names are looked up, the sub-second ones are found with `snapshot_files`.
+pub fn find_snapshot(datadir: &Path, epoch: u64) -> Option<PathBuf> {
+    let name = format!("{}.{}", epoch, SNAPSHOT_EXTENSION);
+    let warm = datadir.join(&name);
+    let gzip = datadir.join(format!("{}.{}", name, GZIP_EXTENSION));
+    let zstd = datadir.join(format!("{}.{}", name, ZSTD_EXTENSION));
+    let cold = datadir
+        .join(COLD_DIR)
+        .join(format!("{}.{}", name, GZIP_EXTENSION));
+    vec![warm, gzip, zstd, cold]
+        .into_iter()
+        .find(|p| p.is_file())
+}
+
+/// Reads and decodes one snapshot file, decompressing it first if needed. See
+/// `EncoDecode::from_file` for the typed errors.
+pub fn read_snapshot(path: &Path) -> io::Result<EncoDecode> {
+    Ok(EncoDecode::from_file(path)?)
+}
+
+/// Same as `read_snapshot`, decoding only the fields of a `SlimSnapshot`. Snapshots encoded in
+/// other formats than bincode are decoded whole.
+pub fn read_slim_snapshot(path: &Path) -> io::Result<SlimSnapshot> {
+    let data = read_file(path)?;
+    let slim = match split_header(&data) {
+        Ok(Some((FORMAT_VERSION, SerializationFormat::Bincode, payload))) => {
+            SlimSnapshot::decode(payload).map_err(|e| DecodeError::Invalid(e.to_string()))
+        }
+        // Snapshots of older releases don't decode as the current layout.
+        Ok(None) if !is_json(&data) => {
+            SlimSnapshot::decode(&data).or_else(|_| decode(&data).map(|s| SlimSnapshot::from(&s)))
+        }
+        Ok(_) => decode(&data).map(|s| SlimSnapshot::from(&s)),
+        Err(e) => Err(e),
+    };
+    Ok(slim.map_err(|e| e.at(path))?)
+}
+
+/// Decodes an uncompressed snapshot, encoded in any `SerializationFormat`, or without header
+/// like the ones written by older versions and the lines of a `json` archive of `convert`.
+pub fn decode_snapshot(data: &[u8]) -> Result<EncoDecode, String> {
+    decode(data).map_err(|e| e.to_string())
+}
+
+/// Same as `decode_snapshot`, with a typed error.
+pub fn decode(data: &[u8]) -> Result<EncoDecode, DecodeError> {
+    decode_versioned(data).map(VersionedSnapshot::upgrade)
+}
+
+/// Same as `decode`, returning the snapshot in the layout of the version it was written in.
+pub fn decode_versioned(data: &[u8]) -> Result<VersionedSnapshot, DecodeError> {
+    if let Some((version, format, payload)) = split_header(data)? {
+        let snapshot = match version {
+            1 => format.decode(payload).map(VersionedSnapshot::V1),
+            2 => format.decode(payload).map(VersionedSnapshot::V2),
+            _ => format
+                .decode(payload)
+                .map(Box::new)
+                .map(VersionedSnapshot::V3),
+        };
+        return snapshot.map_err(DecodeError::Invalid);
+    }
+    if is_json(data) {
+        // A bincode snapshot whose hostname is 123 bytes long also starts with `{`.
+        if let Ok(snapshot) = serde_json::from_slice(data) {
+            return Ok(VersionedSnapshot::V3(Box::new(snapshot)));
+        }
+    }
+    // Snapshots without header are in the current layout if written before the header was
+    // added, or in the layout of an older release. Trailing bytes are refused so that a layout
+    // isn't mistaken for another.
+    let e = match exact_bincode(data) {
+        Ok(snapshot) => return Ok(VersionedSnapshot::V3(Box::new(snapshot))),
+        Err(e) => e,
+    };
+    exact_bincode::<EncoDecodeV2>(data)
+        .map(VersionedSnapshot::V2)
+        .or_else(|_| exact_bincode::<EncoDecodeV1>(data).map(VersionedSnapshot::V1))
+        .map_err(|_| DecodeError::Invalid(legacy_error(e)))
+}
+
+/// Decodes bincode like `bincode::deserialize`, but fails if bytes are left over.
+fn exact_bincode<T: DeserializeOwned>(data: &[u8]) -> bincode::Result<T> {
+    use bincode::Options;
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .reject_trailing_bytes()
+        .deserialize(data)
+}
+
+/// Format version, serialization format and encoded snapshot of a snapshot file.
+pub(crate) type Header<'a> = (u8, SerializationFormat, &'a [u8]);
+
+/// Splits the `SNAPSHOT_MAGIC` header off an uncompressed snapshot and returns its format version,
+/// its serialization format and the encoded snapshot. None for the snapshots without header. A
+/// bincode snapshot without header starts with the length of its hostname, which can't be `PSHT`.
+pub(crate) fn split_header(data: &[u8]) -> Result<Option<Header<'_>>, DecodeError> {
+    let header = match data.strip_prefix(SNAPSHOT_MAGIC) {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+    match header {
+        [version, ..] if *version > FORMAT_VERSION => Err(DecodeError::Version(*version)),
+        [0, ..] => Err(DecodeError::Invalid("format version 0".to_string())),
+        [version, codec, payload @ ..] => match SerializationFormat::from_codec(*codec) {
+            Some(format) => Ok(Some((*version, format, payload))),
+            None => Err(DecodeError::Invalid(format!(
+                "unknown serialization format {}",
+                codec
+            ))),
+        },
+        _ => Err(DecodeError::Invalid("truncated header".to_string())),
+    }
+}
+
+/// Describes the failure to decode a snapshot without header.
+fn legacy_error(e: impl fmt::Display) -> String {
+    format!(
+        "{}. The file is corrupt, or was written by a version of procshot in an unknown layout",
+        e
+    )
+}
+
+fn is_json(data: &[u8]) -> bool {
+    data.first() == Some(&b'{')
+}
+
+/// Reads a file, decompressing it if it starts with the gzip or zstd magic bytes, whatever its
+/// name.
+pub(crate) fn read_file(path: &Path) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+    decompress(data).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
+
+/// Decompresses the content of a snapshot file if it starts with the gzip or zstd magic bytes,
+/// returns it as is otherwise.
+pub fn decompress(data: Vec<u8>) -> io::Result<Vec<u8>> {
+    if data.starts_with(&GZIP_MAGIC) {
+        let mut decompressed = Vec::new();
+        GzDecoder::new(&data[..]).read_to_end(&mut decompressed)?;
+        return Ok(decompressed);
+    }
+    if data.starts_with(&ZSTD_MAGIC) {
+        #[cfg(feature = "zstd")]
+        return zstd::decode_all(&data[..]);
+        #[cfg(not(feature = "zstd"))]
+        return Err(no_zstd());
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_epoch() {
+        assert_eq!(
+            snapshot_epoch(Path::new("/var/log/procshot/data/1563617611.procshot")),
+            Some(1563617611)
+        );
+        assert_eq!(snapshot_epoch(Path::new("sketches.bin")), None);
+        assert_eq!(snapshot_epoch(Path::new("test_data.procshot")), None);
+        assert_eq!(
+            snapshot_epoch(Path::new("cold/1563617611.procshot.gz")),
+            Some(1563617611)
+        );
+        assert_eq!(
+            snapshot_epoch(Path::new("1563617611.procshot.zst")),
+            Some(1563617611)
+        );
+        assert_eq!(snapshot_epoch(Path::new("1563617611.gz")), None);
+        assert_eq!(
+            snapshot_millis(Path::new("1563617611.250.procshot")),
+            Some(1563617611250)
+        );
+        assert_eq!(snapshot_epoch(Path::new("1563617611.25.procshot")), None);
+        assert_eq!(
+            snapshot_file_name(Duration::from_millis(1563617611050), true),
+            "1563617611.050.procshot"
+        );
+    }
+
+    #[test]
+    fn test_adapt_compression_level() {
+        use CompressionLevel::*;
+        assert_eq!(Default.adapt(95.0), Fast);
+        assert_eq!(Fast.adapt(95.0), Fast);
+        assert_eq!(Best.adapt(95.0), Default);
+        assert_eq!(Fast.adapt(50.0), Fast);
+        assert_eq!(Best.adapt(50.0), Best);
+        assert_eq!(Fast.adapt(10.0), Default);
+        assert_eq!(Default.adapt(10.0), Best);
+        let data = vec![b'a'; 4096];
+        for level in [Fast, Default, Best] {
+            let compressed = CompressionMode::Gzip
+                .compress_at(data.clone(), level)
+                .unwrap();
+            assert_eq!(decompress(compressed).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_read_mixed_formats() {
+        use flate2::write::GzEncoder;
+        use std::io::Write;
+
+        let dir =
+            std::env::temp_dir().join(format!("procshot_mixed_formats_{}", std::process::id()));
+        fs::create_dir_all(dir.join(COLD_DIR)).unwrap();
+        let snapshot = |epoch| EncoDecode {
+            hostname: "localghost".to_string(),
+            time_epoch: epoch,
+            delay: Duration::from_secs(60),
+            total_cpu_time: epoch * 10,
+            ..Default::default()
+        };
+        let bincode = |epoch| bincode::serialize(&snapshot(epoch)).unwrap();
+        let gzip = |data: &[u8]| {
+            let mut e = GzEncoder::new(Vec::new(), flate2::Compression::fast());
+            e.write_all(data).unwrap();
+            e.finish().unwrap()
+        };
+        fs::write(dir.join("60.procshot"), bincode(60)).unwrap();
+        // Compressed without the extension, and in the cold tier without compression.
+        fs::write(dir.join("120.procshot"), gzip(&bincode(120))).unwrap();
+        fs::write(dir.join("cold/180.procshot.gz"), bincode(180)).unwrap();
+        let json = |epoch| serde_json::to_vec(&snapshot(epoch)).unwrap();
+        fs::write(dir.join("240.procshot"), json(240)).unwrap();
+        fs::write(dir.join("300.procshot.gz"), gzip(&json(300))).unwrap();
+        for (epoch, path) in snapshot_files(&dir).unwrap() {
+            let s = read_snapshot(&path).unwrap();
+            assert_eq!(s.total_cpu_time, epoch * 10, "{}", path.display());
+            assert_eq!(read_slim_snapshot(&path).unwrap().time_epoch, epoch);
+        }
+        let compressed = |mode: CompressionMode, epoch| mode.compress(bincode(epoch)).unwrap();
+        fs::write(
+            dir.join("420.procshot.gz"),
+            compressed(CompressionMode::Gzip, 420),
+        )
+        .unwrap();
+        #[cfg(feature = "zstd")]
+        fs::write(
+            dir.join("480.procshot.zst"),
+            compressed(CompressionMode::Zstd, 480),
+        )
+        .unwrap();
+        assert_eq!(find_snapshot(&dir, 420), Some(dir.join("420.procshot.gz")));
+        for (epoch, path) in snapshot_files(&dir).unwrap() {
+            let s = read_snapshot(&path).unwrap();
+            assert_eq!(s.total_cpu_time, epoch * 10, "{}", path.display());
+        }
+        #[allow(unused_mut)]
+        let mut formats = vec![SerializationFormat::Bincode, SerializationFormat::Json];
+        #[cfg(feature = "msgpack")]
+        formats.push(SerializationFormat::MessagePack);
+        #[cfg(feature = "cbor")]
+        formats.push(SerializationFormat::Cbor);
+        for format in formats {
+            let encoded = format.encode(&snapshot(540)).unwrap();
+            assert!(encoded.starts_with(SNAPSHOT_MAGIC));
+            assert_eq!(format.to_string().parse(), Ok(format));
+            fs::write(dir.join("540.procshot"), encoded).unwrap();
+            let path = dir.join("540.procshot");
+            assert_eq!(read_snapshot(&path).unwrap(), snapshot(540), "{}", format);
+            assert_eq!(read_slim_snapshot(&path).unwrap().total_cpu_time, 5400);
+        }
+        fs::write(dir.join("360.procshot"), b"{ not a snapshot").unwrap();
+        let e = read_snapshot(&dir.join("360.procshot")).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        let mut newer = SerializationFormat::Bincode.encode(&snapshot(600)).unwrap();
+        newer[SNAPSHOT_MAGIC.len()] = FORMAT_VERSION + 1;
+        fs::write(dir.join("600.procshot"), newer).unwrap();
+        match EncoDecode::from_file(dir.join("600.procshot")).unwrap_err() {
+            ProcshotError::VersionMismatch {
+                found, supported, ..
+            } => assert_eq!((found, supported), (FORMAT_VERSION + 1, FORMAT_VERSION)),
+            e => panic!("unexpected error {}", e),
+        }
+        assert!(read_slim_snapshot(&dir.join("600.procshot"))
+            .unwrap_err()
+            .to_string()
+            .contains("upgrade procshot"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_older_versions() {
+        use crate::versioned::PidStatusV2;
+        use crate::Pid;
+
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("test_data.procshot");
+        let v1 = decode_versioned(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(v1.version(), 1);
+        let s = read_snapshot(&path).unwrap();
+        assert_eq!(
+            (s.hostname.as_str(), s.time_epoch),
+            ("localghost", 1563617611)
+        );
+        assert_eq!(
+            (s.pid_map_list.len(), s.delay),
+            (119, Duration::from_secs(5))
+        );
+        let init = &s.pid_map_list[&Pid::new(1)];
+        assert_eq!((init.name.as_str(), init.rss_bytes), ("systemd", 10506240));
+        assert_eq!(read_slim_snapshot(&path).unwrap().processes.len(), 119);
+
+        let status = PidStatusV2 {
+            ppid: Pid::new(1),
+            euid: 1000,
+            cmd_long: vec!["nginx".to_string()],
+            name: "nginx".to_string(),
+            cmd_short: "nginx".to_string(),
+            tracerpid: Pid::new(0),
+            fdsize: 64,
+            state: "S (sleeping)".to_string(),
+            vmpeak: Some(1024),
+            vmsize: Some(1000),
+            rss_pages: 100,
+            rss_bytes: 409600,
+            rsslim_bytes: u64::MAX,
+            processor_last_executed: Some(2),
+            utime: 30,
+            stime: 10,
+            user_cpu_usage: 1.5,
+            sys_cpu_usage: 0.5,
+        };
+        let v2 = EncoDecodeV2 {
+            hostname: "web-1".to_string(),
+            pid_map_list: vec![(Pid::new(42), status)].into_iter().collect(),
+            time_epoch: 1563617611,
+            delay: 60,
+            total_cpu_time: 1000,
+        };
+        let headerless = bincode::serialize(&v2).unwrap();
+        let mut json = SNAPSHOT_MAGIC.to_vec();
+        json.extend([2, SerializationFormat::Json.codec()]);
+        json.extend(serde_json::to_vec(&v2).unwrap());
+        for data in [headerless, json] {
+            let decoded = decode_versioned(&data).unwrap();
+            assert_eq!(decoded, VersionedSnapshot::V2(v2.clone()));
+            let s = decoded.upgrade();
+            assert_eq!(s.delay, Duration::from_secs(60));
+            let nginx = &s.pid_map_list[&Pid::new(42)];
+            assert_eq!((nginx.user_cpu_usage, nginx.io), (1.5, None));
+        }
+
+        // The fields added after version 2 are left to their defaults.
+        let s = decode(&bincode::serialize(&v2).unwrap()).unwrap();
+        let nginx = &s.pid_map_list[&Pid::new(42)];
+        assert!(nginx.runtime.is_none() && nginx.cgroup.is_none());
+        assert_eq!(s.uptime, Duration::from_secs(0));
+        assert_eq!(s.system_memory, Default::default());
+        assert!(s.disks.is_empty() && s.interfaces.is_empty() && s.per_cpu.is_empty());
+    }
+}
@@ -0,0 +1,132 @@
+//! Compact per-snapshot summaries: totals plus the top processes by CPU and rss.
+//!
+//! A summary is small enough to be pushed to live caches or printed as a one line heartbeat, while
+//! the full snapshot stays on disk. The values are named after their `metrics`, and the totals
+//! `total_<metric>`.
+
+use crate::cpu::CpuTimes;
+use crate::metrics;
+use crate::{EncoDecode, Pid};
+
+/// Number of processes listed in each of the top lists.
+pub const TOP_N: usize = 5;
+
+/// One entry of a top list.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct TopProcess {
+    pub pid: Pid,
+    pub name: String,
+    /// `metrics::CPU_USAGE` of the process.
+    pub cpu_usage: f64,
+    pub rss_bytes: i64,
+}
+
+/// SnapshotSummary holds the totals of one snapshot.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct SnapshotSummary {
+    pub hostname: String,
+    pub time_epoch: u64,
+    pub delay: std::time::Duration,
+    /// Number of processes recorded in the snapshot.
+    pub process_count: usize,
+    /// Sum of the rss of all recorded processes.
+    pub total_rss_bytes: i64,
+    /// Sum of the user + sys CPU usage of all recorded processes, in percent.
+    pub total_cpu_usage: f64,
+    /// Percentage of CPU time stolen by the hypervisor since the previous snapshot, if known.
+    pub steal_percent: Option<f64>,
+    /// The processes using the most CPU, highest first.
+    pub top_cpu: Vec<TopProcess>,
+    /// The processes using the most memory, highest first.
+    pub top_rss: Vec<TopProcess>,
+}
+
+impl SnapshotSummary {
+    /// Summarizes `snapshot`. `previous` are the CPU times of the snapshot before it, needed for
+    /// the interval based values like `steal_percent`.
+    pub fn new(snapshot: &EncoDecode, previous: Option<&CpuTimes>) -> Self {
+        let mut all: Vec<TopProcess> = snapshot
+            .pid_map_list
+            .iter()
+            .map(|(pid, s)| TopProcess {
+                pid: *pid,
+                name: s.name.clone(),
+                cpu_usage: metrics::CPU_USAGE.of(s).unwrap_or(0.0),
+                rss_bytes: s.rss_bytes,
+            })
+            .collect();
+        let total_rss_bytes = all.iter().map(|p| p.rss_bytes).sum();
+        let total_cpu_usage = all.iter().map(|p| p.cpu_usage).sum();
+
+        all.sort_by(|a, b| b.cpu_usage.total_cmp(&a.cpu_usage).then(a.pid.cmp(&b.pid)));
+        let top_cpu = all.iter().take(TOP_N).cloned().collect();
+        all.sort_by(|a, b| b.rss_bytes.cmp(&a.rss_bytes).then(a.pid.cmp(&b.pid)));
+        let top_rss = all.iter().take(TOP_N).cloned().collect();
+
+        SnapshotSummary {
+            hostname: snapshot.hostname.clone(),
+            time_epoch: snapshot.time_epoch,
+            delay: snapshot.delay,
+            process_count: snapshot.pid_map_list.len(),
+            total_rss_bytes,
+            total_cpu_usage,
+            steal_percent: previous.and_then(|p| snapshot.cpu_times.steal_percent(p)),
+            top_cpu,
+            top_rss,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PidStatus;
+    use std::collections::HashMap;
+
+    fn status(name: &str, cpu: f64, rss: i64) -> PidStatus {
+        PidStatus {
+            ppid: Pid::new(1),
+            cmd_long: vec![],
+            name: name.to_string(),
+            cmd_short: name.to_string(),
+            fdsize: 64,
+            state: "S (sleeping)".to_string(),
+            vmpeak: Some(1),
+            vmsize: Some(1),
+            rss_pages: rss / 4096,
+            rss_bytes: rss,
+            rsslim_bytes: u64::MAX,
+            processor_last_executed: Some(0),
+            user_cpu_usage: cpu,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_summary() {
+        let mut pids = HashMap::new();
+        pids.insert(Pid::new(1), status("init", 0.5, 4096));
+        pids.insert(Pid::new(2), status("java", 50.0, 1 << 30));
+        pids.insert(Pid::new(3), status("chrome", 75.0, 1 << 20));
+        let snapshot = EncoDecode {
+            hostname: "localghost".to_string(),
+            pid_map_list: pids,
+            time_epoch: 1563617611,
+            delay: std::time::Duration::from_secs(5),
+            ..Default::default()
+        };
+        let s = SnapshotSummary::new(&snapshot, None);
+        assert_eq!(s.process_count, 3);
+        assert_eq!(s.total_rss_bytes, 4096 + (1 << 30) + (1 << 20));
+        assert_eq!(s.top_cpu[0].name, "chrome");
+        assert_eq!(s.top_rss[0].name, "java");
+        assert_eq!(s.steal_percent, None);
+
+        let json = serde_json::to_value(&s).unwrap();
+        for metric in [&metrics::CPU_USAGE, &metrics::RSS_BYTES].iter() {
+            let total = format!("total_{}", metric.name);
+            assert!(json["top_cpu"][0].get(metric.name).is_some(), "{}", metric);
+            assert!(json.get(&total).is_some(), "{}", total);
+        }
+    }
+}
@@ -0,0 +1,156 @@
+//! systemd service integration through the sd_notify protocol.
+//!
+//! Run under a `Type=notify` unit (see `systemd/procshot.service`), the server tells systemd:
+//!
+//! * `READY=1` once the first snapshot is written, so units ordered after procshot start only
+//!   when it actually records,
+//! * `WATCHDOG=1` after every iteration, so a unit with `WatchdogSec=` is restarted if the scan
+//!   loop wedges,
+//! * `STOPPING=1` when it shuts down on SIGTERM or SIGINT.
+//!
+//! The protocol is a datagram per message on the Unix socket named by `NOTIFY_SOCKET`, which is
+//! simple enough not to need libsystemd. Without `NOTIFY_SOCKET`, there is nobody to notify and
+//! `Notifier::from_env` returns `None`.
+
+use std::env;
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// Notifier sends state changes to the service manager.
+#[derive(Debug)]
+pub struct Notifier {
+    socket: UnixDatagram,
+    /// Path of the notification socket. A leading `@` is a socket in the abstract namespace.
+    path: PathBuf,
+}
+
+impl Notifier {
+    /// Returns a notifier for the socket in `NOTIFY_SOCKET`, if the variable is set.
+    pub fn from_env() -> Option<io::Result<Self>> {
+        env::var_os("NOTIFY_SOCKET").map(|path| Self::new(PathBuf::from(path)))
+    }
+
+    /// Returns a notifier sending to the socket at `path`.
+    pub fn new(path: PathBuf) -> io::Result<Self> {
+        Ok(Notifier {
+            socket: UnixDatagram::unbound()?,
+            path,
+        })
+    }
+
+    /// Sends `state`, newline separated `VARIABLE=value` assignments, eg: `READY=1`.
+    pub fn notify(&self, state: &str) -> io::Result<()> {
+        let path = self.path.to_string_lossy();
+        match path.strip_prefix('@') {
+            Some(name) => {
+                use std::os::linux::net::SocketAddrExt;
+                let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+                self.socket.send_to_addr(state.as_bytes(), &addr)?;
+            }
+            None => {
+                self.socket.send_to(state.as_bytes(), &self.path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Tells the service manager the server is up, with a status line for `systemctl status`.
+    pub fn ready(&self, status: &str) -> io::Result<()> {
+        self.notify(&format!("READY=1\nSTATUS={}", status))
+    }
+
+    /// Pings the watchdog, with a status line for `systemctl status`.
+    pub fn watchdog(&self, status: &str) -> io::Result<()> {
+        self.notify(&format!("WATCHDOG=1\nSTATUS={}", status))
+    }
+
+    /// Tells the service manager the server is shutting down.
+    pub fn stopping(&self) -> io::Result<()> {
+        self.notify("STOPPING=1")
+    }
+}
+
+/// Returns the watchdog interval systemd expects pings within, from `WATCHDOG_USEC`. None if
+/// the watchdog is disabled, or meant for another process (`WATCHDOG_PID`).
+pub fn watchdog_interval() -> Option<Duration> {
+    parse_watchdog(
+        env::var("WATCHDOG_USEC").ok().as_deref(),
+        env::var("WATCHDOG_PID").ok().as_deref(),
+        std::process::id(),
+    )
+}
+
+fn parse_watchdog(usec: Option<&str>, pid: Option<&str>, own_pid: u32) -> Option<Duration> {
+    if let Some(pid) = pid {
+        if pid.parse::<u32>().ok()? != own_pid {
+            return None;
+        }
+    }
+    match usec?.parse().ok()? {
+        0 => None,
+        usec => Some(Duration::from_micros(usec)),
+    }
+}
+
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_shutdown(_signal: libc::c_int) {
+    SHUTDOWN.store(true, Ordering::SeqCst);
+}
+
+/// Makes SIGTERM and SIGINT request a shutdown instead of killing the process, so the server can
+/// notify `STOPPING=1` and release the datadir lock. See `shutdown_requested`.
+pub fn install_shutdown_handler() -> io::Result<()> {
+    for signal in &[libc::SIGTERM, libc::SIGINT] {
+        let handler = request_shutdown as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        if unsafe { libc::signal(*signal, handler) } == libc::SIG_ERR {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Requests a shutdown the way SIGTERM does, eg: from a program embedding the server. The server
+/// stops at the end of the running iteration.
+pub fn request_stop() {
+    SHUTDOWN.store(true, Ordering::SeqCst);
+}
+
+/// Withdraws a shutdown request, so the server can be started again in the same process.
+pub fn clear_stop() {
+    SHUTDOWN.store(false, Ordering::SeqCst);
+}
+
+/// True once SIGTERM or SIGINT was received, after `install_shutdown_handler`.
+pub fn shutdown_requested() -> bool {
+    SHUTDOWN.load(Ordering::SeqCst)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_notify() {
+        let path = env::temp_dir().join(format!("procshot_notify_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let receiver = UnixDatagram::bind(&path).unwrap();
+        let notifier = Notifier::new(path.clone()).unwrap();
+        notifier.ready("1 snapshot").unwrap();
+        let mut buf = [0u8; 64];
+        let n = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1\nSTATUS=1 snapshot");
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            parse_watchdog(Some("30000000"), None, 7),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(parse_watchdog(Some("30000000"), Some("8"), 7), None);
+        assert_eq!(parse_watchdog(Some("0"), Some("7"), 7), None);
+        assert_eq!(parse_watchdog(None, None, 7), None);
+    }
+}
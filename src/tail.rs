@@ -0,0 +1,179 @@
+//! One line per new snapshot, for a heartbeat view of a running server.
+//!
+//! `procshot tail` follows the datadir, or the live socket of the server (see `sink::live`), and
+//! prints the time, total CPU usage, total rss, process count and top CPU process of every new
+//! snapshot as it is taken, like `tail -f` on a log.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use crate::cpu::CpuTimes;
+use crate::sink::live::LiveClient;
+use crate::summary::SnapshotSummary;
+use crate::tz::TimeZone;
+use crate::units::ByteFormat;
+use crate::{store, EncoDecode};
+
+/// How often the datadir is checked for new snapshot files.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// TailSource is where `tail` gets the snapshots from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TailSource {
+    /// Check the datadir for new snapshot files.
+    Datadir,
+    /// Connect to the live socket of the server at this path, which gets every snapshot as soon
+    /// as it is taken.
+    Live(PathBuf),
+}
+
+/// DatadirFollower returns the snapshots written to a datadir since it last looked.
+#[derive(Debug)]
+pub struct DatadirFollower {
+    datadir: PathBuf,
+    /// Time of the last snapshot returned, in milliseconds.
+    last_millis: Option<u64>,
+}
+
+impl DatadirFollower {
+    pub fn new(datadir: &Path) -> Self {
+        DatadirFollower {
+            datadir: datadir.to_path_buf(),
+            last_millis: None,
+        }
+    }
+
+    /// Returns the snapshots written since the previous call, oldest first. The first call only
+    /// returns the newest snapshot. A file that can't be decoded yet, as the server may still be
+    /// writing it, is returned by a later call.
+    pub fn poll(&mut self) -> io::Result<Vec<EncoDecode>> {
+        let mut files: Vec<(u64, PathBuf)> = store::snapshot_files(&self.datadir)?
+            .into_iter()
+            .filter_map(|(_, path)| store::snapshot_millis(&path).map(|millis| (millis, path)))
+            .filter(|(millis, _)| self.last_millis.is_none_or(|last| *millis > last))
+            .collect();
+        if self.last_millis.is_none() {
+            files = files.split_off(files.len().saturating_sub(1));
+        }
+        let mut snapshots = Vec::new();
+        for (millis, path) in files {
+            match store::read_snapshot(&path) {
+                Ok(snapshot) => snapshots.push(snapshot),
+                Err(ref e) if e.kind() == io::ErrorKind::InvalidData => break,
+                // Deleted by the retention in the meantime.
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e),
+            }
+            self.last_millis = Some(millis);
+        }
+        Ok(snapshots)
+    }
+}
+
+/// Returns the line printed for a snapshot, eg:
+/// `2019-07-20 10:13:31 +00:00  cpu  34.5%  rss  12.3 GiB  procs  412  top java[1234] 20.1%`.
+pub fn format_line(summary: &SnapshotSummary, format: &ByteFormat, tz: &TimeZone) -> String {
+    let top = match summary.top_cpu.first() {
+        Some(p) => format!("{}[{}] {:.1}%", p.name, p.pid, p.cpu_usage),
+        None => "-".to_string(),
+    };
+    format!(
+        "{}  cpu {:>5.1}%  rss {:>10}  procs {:>5}  top {}",
+        tz.format(summary.time_epoch),
+        summary.total_cpu_usage,
+        format.bytes(summary.total_rss_bytes.max(0) as u64),
+        summary.process_count,
+        top
+    )
+}
+
+/// Prints a line for every new snapshot of `source`, until the source fails. `datadir` is only
+/// used with `TailSource::Datadir`.
+pub fn run(
+    source: &TailSource,
+    datadir: &Path,
+    format: &ByteFormat,
+    tz: &TimeZone,
+) -> io::Result<()> {
+    let mut previous: Option<CpuTimes> = None;
+    let mut print = |snapshot: &EncoDecode| {
+        let summary = SnapshotSummary::new(snapshot, previous.as_ref());
+        println!("{}", format_line(&summary, format, tz));
+        previous = Some(snapshot.cpu_times);
+    };
+    match source {
+        TailSource::Datadir => {
+            let mut follower = DatadirFollower::new(datadir);
+            loop {
+                for snapshot in follower.poll()? {
+                    print(&snapshot);
+                }
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+        TailSource::Live(path) => {
+            let mut client = LiveClient::connect(path)?;
+            loop {
+                print(client.next_snapshot()?);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{DirBackend, StorageBackend};
+    use crate::collect::{restricted_pid_status, PROC_ROOT};
+    use crate::units::UnitSystem;
+    use crate::Pid;
+
+    fn snapshot(epoch: u64) -> EncoDecode {
+        let mut status = restricted_pid_status(Path::new(PROC_ROOT), Pid::current());
+        status.name = "java".to_string();
+        status.rss_bytes = 3 << 30;
+        status.user_cpu_usage = 20.0;
+        status.sys_cpu_usage = 0.5;
+        EncoDecode {
+            hostname: "localghost".to_string(),
+            pid_map_list: vec![(Pid::new(1234), status)].into_iter().collect(),
+            time_epoch: epoch,
+            delay: Duration::from_secs(60),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_tail_datadir() {
+        let dir = std::env::temp_dir().join(format!("procshot_tail_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut backend = DirBackend::new(&dir, false);
+        let mut follower = DatadirFollower::new(&dir);
+        assert!(follower.poll().unwrap().is_empty());
+        for epoch in &[60, 120] {
+            backend.write_snapshot(&snapshot(*epoch)).unwrap();
+        }
+        let epochs = |s: Vec<EncoDecode>| s.iter().map(|s| s.time_epoch).collect::<Vec<_>>();
+        assert_eq!(epochs(follower.poll().unwrap()), vec![120]);
+        // A file still being written is picked up once complete.
+        std::fs::write(dir.join("240.procshot"), b"\x0a").unwrap();
+        backend.write_snapshot(&snapshot(180)).unwrap();
+        assert_eq!(epochs(follower.poll().unwrap()), vec![180]);
+        backend.write_snapshot(&snapshot(240)).unwrap();
+        assert_eq!(epochs(follower.poll().unwrap()), vec![240]);
+        assert!(follower.poll().unwrap().is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let summary = SnapshotSummary::new(&snapshot(1563617611), None);
+        assert_eq!(
+            format_line(
+                &summary,
+                &ByteFormat::new(UnitSystem::Binary),
+                &TimeZone::Utc
+            ),
+            "2019-07-20 10:13:31 +00:00  cpu  20.5%  rss    3.0 GiB  procs     1  top java[1234] 20.5%"
+        );
+    }
+}
@@ -0,0 +1,328 @@
+//! Per-process TCP retransmit and drop counters.
+//!
+//! Resource usage alone doesn't tell whether a service's network degraded. The kernel keeps
+//! retransmit and drop counters for every TCP socket, and the sock_diag netlink interface that
+//! `ss` uses dumps them for all sockets at once, along with the socket's inode. The sockets are
+//! attributed to processes by the `socket:[inode]` links in /proc/<pid>/fd, which needs the
+//! same privilege as reading the process' fds, so processes of other users are only covered
+//! when running as root.
+//!
+//! The counters are summed over the live sockets of a process: they grow as its connections
+//! retransmit and drop, and fall back when a connection closes. A socket shared by several
+//! processes, eg: a listener inherited by forked workers, is counted for each of them.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::Path;
+
+use crate::delayacct::{align, attribute, u16_at, u32_at};
+use crate::Pid;
+
+/// Key in `PidStatus::extensions` of the number of TCP sockets of the process.
+pub const EXT_TCP_SOCKETS: &str = "tcp_sockets";
+/// Key in `PidStatus::extensions` of the segments sent by the TCP sockets of the process.
+pub const EXT_TCP_SEGS_OUT: &str = "tcp_segs_out";
+/// Key in `PidStatus::extensions` of the segments retransmitted by the TCP sockets of the
+/// process.
+pub const EXT_TCP_RETRANS_SEGS: &str = "tcp_retrans_segs";
+/// Key in `PidStatus::extensions` of the packets dropped on receipt by the TCP sockets of the
+/// process.
+pub const EXT_TCP_DROPS: &str = "tcp_drops";
+
+const SOCK_DIAG_BY_FAMILY: u16 = 20;
+const INET_DIAG_INFO: u16 = 2;
+const INET_DIAG_SKMEMINFO: u16 = 7;
+/// Every TCP state, from TCP_ESTABLISHED (1) to TCP_NEW_SYN_RECV (12).
+const ALL_STATES: u32 = 0x1ffe;
+
+/// Size of struct nlmsghdr.
+const NLMSG_HDRLEN: usize = 16;
+/// Size of struct inet_diag_req_v2.
+const REQ_LEN: usize = 56;
+/// Size of struct inet_diag_msg.
+const DIAG_MSG_LEN: usize = 72;
+/// Offset of idiag_inode in struct inet_diag_msg.
+const DIAG_INODE: usize = 68;
+/// Offset of tcpi_total_retrans in struct tcp_info.
+const TCPI_TOTAL_RETRANS: usize = 100;
+/// Offset of tcpi_segs_out in struct tcp_info, added in Linux 4.2.
+const TCPI_SEGS_OUT: usize = 136;
+/// Index of SK_MEMINFO_DROPS in the INET_DIAG_SKMEMINFO array of u32.
+const SK_MEMINFO_DROPS: usize = 8;
+
+/// TcpCounters are the counters of one socket, or summed over the sockets of a process.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct TcpCounters {
+    pub sockets: u64,
+    /// Segments sent, 0 before Linux 4.2.
+    pub segs_out: u64,
+    pub retrans_segs: u64,
+    /// Packets dropped on receipt, eg: for a full receive buffer or a failed checksum.
+    pub drops: u64,
+}
+
+impl TcpCounters {
+    fn add(&mut self, other: &TcpCounters) {
+        self.sockets += other.sockets;
+        self.segs_out += other.segs_out;
+        self.retrans_segs += other.retrans_segs;
+        self.drops += other.drops;
+    }
+}
+
+/// SockDiag is a sock_diag netlink socket.
+#[derive(Debug)]
+pub struct SockDiag {
+    fd: OwnedFd,
+}
+
+impl SockDiag {
+    /// Opens the socket. Fails if the kernel was built without CONFIG_INET_DIAG.
+    pub fn connect() -> io::Result<Self> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+                libc::NETLINK_SOCK_DIAG,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(SockDiag {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        })
+    }
+
+    /// Returns the counters of every IPv4 and IPv6 TCP socket, by inode.
+    pub fn sockets(&self) -> io::Result<HashMap<u64, TcpCounters>> {
+        let mut sockets = HashMap::new();
+        for family in &[libc::AF_INET, libc::AF_INET6] {
+            self.dump(*family as u8, &mut sockets)?;
+        }
+        Ok(sockets)
+    }
+
+    /// Dumps the TCP sockets of `family` into `sockets`.
+    fn dump(&self, family: u8, sockets: &mut HashMap<u64, TcpCounters>) -> io::Result<()> {
+        let msg = request(family);
+        let sent = unsafe {
+            libc::send(
+                self.fd.as_raw_fd(),
+                msg.as_ptr() as *const libc::c_void,
+                msg.len(),
+                0,
+            )
+        };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut buf = vec![0u8; 32 * 1024];
+        loop {
+            let n = unsafe {
+                libc::recv(
+                    self.fd.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                    0,
+                )
+            };
+            if n < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+            if parse_messages(&buf[..n as usize], sockets)? {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Returns the dump request of the TCP sockets of `family`, with their tcp_info and memory
+/// counters.
+fn request(family: u8) -> Vec<u8> {
+    let len = NLMSG_HDRLEN + REQ_LEN;
+    let mut msg = Vec::with_capacity(len);
+    // struct nlmsghdr
+    msg.extend_from_slice(&(len as u32).to_ne_bytes());
+    msg.extend_from_slice(&SOCK_DIAG_BY_FAMILY.to_ne_bytes());
+    msg.extend_from_slice(&((libc::NLM_F_REQUEST | libc::NLM_F_DUMP) as u16).to_ne_bytes());
+    msg.extend_from_slice(&0u32.to_ne_bytes());
+    msg.extend_from_slice(&0u32.to_ne_bytes());
+    // struct inet_diag_req_v2, the extensions are a bitmask of attribute types - 1.
+    let ext = (1 << (INET_DIAG_INFO - 1)) | (1 << (INET_DIAG_SKMEMINFO - 1));
+    msg.extend_from_slice(&[family, libc::IPPROTO_TCP as u8, ext, 0]);
+    msg.extend_from_slice(&ALL_STATES.to_ne_bytes());
+    // An empty struct inet_diag_sockid matches every socket.
+    msg.resize(len, 0);
+    msg
+}
+
+/// Parses the messages of one datagram of a dump into `sockets`. Returns true once the dump is
+/// done.
+fn parse_messages(buf: &[u8], sockets: &mut HashMap<u64, TcpCounters>) -> io::Result<bool> {
+    let mut offset = 0;
+    while offset + NLMSG_HDRLEN <= buf.len() {
+        let len = u32_at(buf, offset).ok_or_else(|| malformed("message"))? as usize;
+        let kind = u16_at(buf, offset + 4).ok_or_else(|| malformed("message"))?;
+        if len < NLMSG_HDRLEN || offset + len > buf.len() {
+            return Err(malformed("message"));
+        }
+        let payload = &buf[offset + NLMSG_HDRLEN..offset + len];
+        match kind as i32 {
+            libc::NLMSG_DONE => return Ok(true),
+            libc::NLMSG_ERROR => {
+                let errno = u32_at(payload, 0).ok_or_else(|| malformed("error"))? as i32;
+                return Err(io::Error::from_raw_os_error(-errno));
+            }
+            _ => {
+                if let Some((inode, counters)) = parse_socket(payload) {
+                    sockets.insert(inode, counters);
+                }
+            }
+        }
+        offset += align(len);
+    }
+    Ok(false)
+}
+
+/// Parses the inode and counters of one struct inet_diag_msg and its attributes.
+fn parse_socket(payload: &[u8]) -> Option<(u64, TcpCounters)> {
+    let inode = u32_at(payload, DIAG_INODE)? as u64;
+    // Sockets being set up or torn down have no inode, and no process to attribute them to.
+    if inode == 0 {
+        return None;
+    }
+    let attrs = payload.get(DIAG_MSG_LEN..)?;
+    let info = attribute(attrs, INET_DIAG_INFO).unwrap_or_default();
+    let meminfo = attribute(attrs, INET_DIAG_SKMEMINFO).unwrap_or_default();
+    Some((
+        inode,
+        TcpCounters {
+            sockets: 1,
+            segs_out: u32_at(info, TCPI_SEGS_OUT).unwrap_or(0) as u64,
+            retrans_segs: u32_at(info, TCPI_TOTAL_RETRANS).unwrap_or(0) as u64,
+            drops: u32_at(meminfo, 4 * SK_MEMINFO_DROPS).unwrap_or(0) as u64,
+        },
+    ))
+}
+
+/// Returns the inodes of the sockets `pid` has open. Fails if it exited, or its fds can't be
+/// read.
+pub fn socket_inodes(proc_root: &Path, pid: Pid) -> io::Result<Vec<u64>> {
+    let mut inodes = Vec::new();
+    for entry in fs::read_dir(proc_root.join(pid.to_string()).join("fd"))? {
+        // The fd may be closed since the directory was listed.
+        let target = match fs::read_link(entry?.path()) {
+            Ok(t) => t,
+            Err(_) => continue,
+        };
+        let inode = target
+            .to_str()
+            .and_then(|t| t.strip_prefix("socket:["))
+            .and_then(|t| t.strip_suffix(']'))
+            .and_then(|t| t.parse().ok());
+        if let Some(inode) = inode {
+            inodes.push(inode);
+        }
+    }
+    Ok(inodes)
+}
+
+/// Sums the counters of `sockets` over the sockets of each of `pids`. Processes without TCP
+/// sockets, or whose fds can't be read, are left out.
+pub fn by_pid<I: IntoIterator<Item = Pid>>(
+    proc_root: &Path,
+    pids: I,
+    sockets: &HashMap<u64, TcpCounters>,
+) -> HashMap<Pid, TcpCounters> {
+    let mut counters = HashMap::new();
+    for pid in pids {
+        let inodes = match socket_inodes(proc_root, pid) {
+            Ok(i) => i,
+            Err(_) => continue,
+        };
+        let mut sum = TcpCounters::default();
+        for c in inodes.iter().filter_map(|i| sockets.get(i)) {
+            sum.add(c);
+        }
+        if sum.sockets > 0 {
+            counters.insert(pid, sum);
+        }
+    }
+    counters
+}
+
+fn malformed(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("malformed {} from sock_diag", what),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collect::PROC_ROOT;
+    use std::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn test_parse() {
+        let mut info = vec![0u8; 232];
+        info[TCPI_TOTAL_RETRANS..TCPI_TOTAL_RETRANS + 4].copy_from_slice(&7u32.to_ne_bytes());
+        info[TCPI_SEGS_OUT..TCPI_SEGS_OUT + 4].copy_from_slice(&900u32.to_ne_bytes());
+        let mut meminfo = vec![0u8; 4 * 9];
+        meminfo[32..36].copy_from_slice(&3u32.to_ne_bytes());
+        let mut diag = vec![0u8; DIAG_MSG_LEN];
+        diag[DIAG_INODE..DIAG_INODE + 4].copy_from_slice(&4242u32.to_ne_bytes());
+        for (kind, value) in &[(INET_DIAG_SKMEMINFO, &meminfo), (INET_DIAG_INFO, &info)] {
+            diag.extend_from_slice(&(4 + value.len() as u16).to_ne_bytes());
+            diag.extend_from_slice(&kind.to_ne_bytes());
+            diag.extend_from_slice(value);
+        }
+        let mut buf = Vec::new();
+        for (kind, payload) in &[
+            (SOCK_DIAG_BY_FAMILY, diag),
+            (libc::NLMSG_DONE as u16, vec![0; 4]),
+        ] {
+            buf.extend_from_slice(&((NLMSG_HDRLEN + payload.len()) as u32).to_ne_bytes());
+            buf.extend_from_slice(&kind.to_ne_bytes());
+            buf.extend_from_slice(&[0; 10]);
+            buf.extend_from_slice(payload);
+        }
+
+        let mut sockets = HashMap::new();
+        assert!(parse_messages(&buf, &mut sockets).unwrap());
+        assert_eq!(
+            sockets[&4242],
+            TcpCounters {
+                sockets: 1,
+                segs_out: 900,
+                retrans_segs: 7,
+                drops: 3,
+            }
+        );
+        assert_eq!(request(libc::AF_INET as u8).len(), 72);
+    }
+
+    #[test]
+    fn test_by_pid() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let _server = listener.accept().unwrap();
+        let proc_root = Path::new(PROC_ROOT);
+        assert!(socket_inodes(proc_root, Pid::current()).unwrap().len() >= 3);
+        // Kernels without CONFIG_INET_DIAG, or sandboxes without netlink.
+        let sockets = match SockDiag::connect().and_then(|d| d.sockets()) {
+            Ok(s) => s,
+            Err(_) => return,
+        };
+        let counters = by_pid(proc_root, vec![Pid::current()], &sockets);
+        assert!(counters[&Pid::current()].sockets >= 3);
+    }
+}
@@ -0,0 +1,136 @@
+//! Warm/cold tiering of the datadir.
+//!
+//! Recent snapshots are the ones read most, so they stay uncompressed in the datadir. Once a
+//! snapshot is older than `TieringPolicy::warm_for` it is gzip compressed at a high level and moved
+//! to `datadir/cold/`. Files the server wrote compressed already (see `store::CompressionMode`) are
+//! moved as they are. The readers in `store` list and decode both tiers, so callers don't need to
+//! know where a snapshot lives.
+//!
+//! A demotion writes and syncs the cold copy under a temporary name, renames it in place and only
+//! then removes the warm file. A crash at any point leaves at least one complete copy, and
+//! `store::snapshot_files` ignores the cold one while both exist; the next demotion overwrites it.
+
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use crate::store;
+
+/// TieringPolicy decides when snapshots move to the cold tier.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TieringPolicy {
+    /// Snapshots older than this are moved to the cold tier.
+    pub warm_for: Duration,
+    /// gzip level (0-9) of the cold tier.
+    pub level: u32,
+}
+
+impl TieringPolicy {
+    pub fn new(warm_for: Duration) -> Self {
+        TieringPolicy { warm_for, level: 9 }
+    }
+}
+
+/// Moves every warm snapshot taken before `now - policy.warm_for` (epoch seconds) to the cold
+/// tier. Returns the number of snapshots moved.
+pub fn demote(datadir: &Path, now: u64, policy: &TieringPolicy) -> io::Result<usize> {
+    let cutoff = now.saturating_sub(policy.warm_for.as_secs());
+    let cold_dir = datadir.join(store::COLD_DIR);
+    let mut moved = 0;
+    for (epoch, path) in store::snapshot_files(datadir)? {
+        if epoch >= cutoff {
+            break;
+        }
+        if path.parent() == Some(cold_dir.as_path()) {
+            continue;
+        }
+        if moved == 0 {
+            fs::create_dir_all(&cold_dir)?;
+        }
+        match store::is_compressed(&path) {
+            true => move_file(&path, &cold_dir)?,
+            false => demote_file(&path, &cold_dir, policy.level)?,
+        }
+        moved += 1;
+    }
+    Ok(moved)
+}
+
+fn demote_file(path: &Path, cold_dir: &Path, level: u32) -> io::Result<()> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a snapshot file"))?
+        .to_string_lossy();
+    let target = cold_dir.join(format!("{}.{}", name, store::GZIP_EXTENSION));
+    let tmp = cold_dir.join(format!(".{}.{}.tmp", name, store::GZIP_EXTENSION));
+
+    let mut encoder = GzEncoder::new(File::create(&tmp)?, Compression::new(level.min(9)));
+    io::copy(&mut File::open(path)?, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+    fs::rename(&tmp, &target)?;
+    fs::remove_file(path)
+}
+
+/// Moves a file that is compressed already, within the filesystem of the datadir.
+fn move_file(path: &Path, cold_dir: &Path) -> io::Result<()> {
+    match path.file_name() {
+        Some(name) => fs::rename(path, cold_dir.join(name)),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "not a snapshot file",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::EncoDecode;
+
+    #[test]
+    fn test_demote_and_read_back() {
+        let dir = std::env::temp_dir().join(format!("procshot_tier_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for epoch in &[100u64, 200, 300] {
+            let s = EncoDecode {
+                hostname: "localghost".to_string(),
+                time_epoch: *epoch,
+                delay: Duration::from_secs(100),
+                ..Default::default()
+            };
+            fs::write(
+                dir.join(format!("{}.procshot", epoch)),
+                bincode::serialize(&s).unwrap(),
+            )
+            .unwrap();
+        }
+
+        let policy = TieringPolicy::new(Duration::from_secs(150));
+        assert_eq!(demote(&dir, 400, &policy).unwrap(), 2);
+        assert_eq!(demote(&dir, 400, &policy).unwrap(), 0);
+        assert!(dir.join("cold/100.procshot.gz").is_file());
+        assert!(!dir.join("100.procshot").exists());
+
+        // Written compressed by the server.
+        fs::rename(dir.join("300.procshot"), dir.join("300.procshot.gz")).unwrap();
+        assert_eq!(demote(&dir, 500, &policy).unwrap(), 1);
+        assert!(dir.join("cold/300.procshot.gz").is_file());
+
+        let files = store::snapshot_files(&dir).unwrap();
+        let epochs: Vec<u64> = files.iter().map(|(e, _)| *e).collect();
+        assert_eq!(epochs, vec![100, 200, 300]);
+        for (epoch, path) in &files {
+            assert_eq!(store::read_snapshot(path).unwrap().time_epoch, *epoch);
+        }
+        assert_eq!(
+            store::find_snapshot(&dir, 200),
+            Some(dir.join("cold/200.procshot.gz"))
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,271 @@
+//! TLS with client certificates for the snapshots streamed from the servers to a collector.
+//!
+//! Process names and command lines often say more about a host than its owners would like, so
+//! the stream from a server (`sink::stream::StreamSink`) to the collector (`collector` module)
+//! is encrypted with rustls when given certificates, and both ends authenticate each other: the
+//! collector only accepts servers presenting a certificate signed by the CA it was given, and
+//! servers only send to a collector presenting one.
+//!
+//! Certificates are short lived in most setups, and renewed by an agent writing new files in
+//! place. Both ends watch the modification times of their files: the collector uses the new
+//! certificate from the next connection on, and a server reconnects with its new certificate.
+//! Files that fail to load, eg: a key written before its certificate, are retried at the next
+//! check while the previous ones stay in use.
+//!
+//! Needs the `tls` feature.
+
+#[cfg(feature = "tls")]
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+#[cfg(feature = "tls")]
+use std::time::SystemTime;
+
+/// Connection is a stream to the other end, encrypted or not.
+pub trait Connection: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Connection for T {}
+
+/// TlsFiles are the PEM files of one end.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsFiles {
+    /// Certificate chain of this end, its own certificate first.
+    pub cert: PathBuf,
+    /// Private key of the certificate, in PKCS#8, PKCS#1 or SEC1.
+    pub key: PathBuf,
+    /// CA the certificate of the other end must be signed by.
+    pub ca: PathBuf,
+}
+
+#[cfg(feature = "tls")]
+impl TlsFiles {
+    /// Returns the modification times of the files, which change when they are rotated.
+    fn modified(&self) -> io::Result<Vec<SystemTime>> {
+        [&self.cert, &self.key, &self.ca]
+            .iter()
+            .map(|path| fs::metadata(path)?.modified())
+            .collect()
+    }
+}
+
+/// Reloading holds a configuration built from `TlsFiles`, rebuilt when they change.
+#[cfg(feature = "tls")]
+struct Reloading<T> {
+    files: TlsFiles,
+    modified: Vec<SystemTime>,
+    config: T,
+    build: fn(&TlsFiles) -> io::Result<T>,
+}
+
+#[cfg(feature = "tls")]
+impl<T: Clone> Reloading<T> {
+    fn new(files: TlsFiles, build: fn(&TlsFiles) -> io::Result<T>) -> io::Result<Self> {
+        Ok(Reloading {
+            modified: files.modified()?,
+            config: build(&files)?,
+            files,
+            build,
+        })
+    }
+
+    /// Rebuilds the configuration if the files changed since it was built. Returns true if it
+    /// was rebuilt.
+    fn reload(&mut self) -> bool {
+        let modified = match self.files.modified() {
+            Ok(modified) if modified != self.modified => modified,
+            _ => return false,
+        };
+        match (self.build)(&self.files) {
+            Ok(config) => {
+                self.config = config;
+                self.modified = modified;
+                true
+            }
+            Err(e) => {
+                eprintln!(
+                    "Keeping the current TLS certificate, cannot load {}: {}",
+                    self.files.cert.display(),
+                    e
+                );
+                false
+            }
+        }
+    }
+}
+
+#[cfg(feature = "tls")]
+mod rustls_config {
+    use std::io;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use rustls::crypto::{ring, CryptoProvider};
+    use rustls::pki_types::pem::PemObject;
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+    use rustls::server::WebPkiClientVerifier;
+    use rustls::{ClientConfig, RootCertStore, ServerConfig};
+
+    use super::TlsFiles;
+
+    fn invalid(path: &Path, e: impl std::fmt::Display) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), e),
+        )
+    }
+
+    fn certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+        let certs = CertificateDer::pem_file_iter(path)
+            .map_err(|e| invalid(path, e))?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| invalid(path, e))?;
+        match certs.is_empty() {
+            true => Err(invalid(path, "no certificate")),
+            false => Ok(certs),
+        }
+    }
+
+    fn key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+        PrivateKeyDer::from_pem_file(path).map_err(|e| invalid(path, e))
+    }
+
+    fn roots(path: &Path) -> io::Result<Arc<RootCertStore>> {
+        let mut roots = RootCertStore::empty();
+        for cert in certs(path)? {
+            roots.add(cert).map_err(|e| invalid(path, e))?;
+        }
+        Ok(Arc::new(roots))
+    }
+
+    fn provider() -> Arc<CryptoProvider> {
+        Arc::new(ring::default_provider())
+    }
+
+    pub fn server(files: &TlsFiles) -> io::Result<Arc<ServerConfig>> {
+        let verifier = WebPkiClientVerifier::builder_with_provider(roots(&files.ca)?, provider())
+            .build()
+            .map_err(|e| invalid(&files.ca, e))?;
+        let config = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(io::Error::other)?
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(certs(&files.cert)?, key(&files.key)?)
+            .map_err(|e| invalid(&files.key, e))?;
+        Ok(Arc::new(config))
+    }
+
+    pub fn client(files: &TlsFiles) -> io::Result<Arc<ClientConfig>> {
+        let config = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(io::Error::other)?
+            .with_root_certificates(roots(&files.ca)?)
+            .with_client_auth_cert(certs(&files.cert)?, key(&files.key)?)
+            .map_err(|e| invalid(&files.key, e))?;
+        Ok(Arc::new(config))
+    }
+}
+
+/// TlsClient opens the connections of a server to the collector.
+#[cfg(feature = "tls")]
+pub struct TlsClient {
+    config: Reloading<std::sync::Arc<rustls::ClientConfig>>,
+    server_name: rustls::pki_types::ServerName<'static>,
+}
+
+#[cfg(feature = "tls")]
+impl TlsClient {
+    /// `server_name` is the name or the IP address the certificate of the collector must be
+    /// valid for.
+    pub fn new(files: TlsFiles, server_name: &str) -> io::Result<Self> {
+        Ok(TlsClient {
+            config: Reloading::new(files, rustls_config::client)?,
+            server_name: std::convert::TryFrom::try_from(server_name.to_string())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+        })
+    }
+
+    /// Returns true if the certificate changed since the last call, so connections opened with
+    /// the previous one should be reopened.
+    pub fn reload(&mut self) -> bool {
+        self.config.reload()
+    }
+
+    /// Starts a TLS session on `tcp`. The handshake happens on the first write.
+    pub fn connect(&self, tcp: TcpStream) -> io::Result<Box<dyn Connection>> {
+        let conn =
+            rustls::ClientConnection::new(self.config.config.clone(), self.server_name.clone())
+                .map_err(io::Error::other)?;
+        Ok(Box::new(rustls::StreamOwned::new(conn, tcp)))
+    }
+}
+
+/// TlsServer accepts the connections of the servers on the collector.
+#[cfg(feature = "tls")]
+pub struct TlsServer {
+    config: std::sync::Mutex<Reloading<std::sync::Arc<rustls::ServerConfig>>>,
+}
+
+#[cfg(feature = "tls")]
+impl TlsServer {
+    pub fn new(files: TlsFiles) -> io::Result<Self> {
+        Ok(TlsServer {
+            config: std::sync::Mutex::new(Reloading::new(files, rustls_config::server)?),
+        })
+    }
+
+    /// Starts a TLS session on an accepted connection, with the current certificate. The
+    /// handshake happens on the first read.
+    pub fn accept(&self, tcp: TcpStream) -> io::Result<Box<dyn Connection>> {
+        let config = {
+            let mut config = self.config.lock().unwrap();
+            config.reload();
+            config.config.clone()
+        };
+        let conn = rustls::ServerConnection::new(config).map_err(io::Error::other)?;
+        Ok(Box::new(rustls::StreamOwned::new(conn, tcp)))
+    }
+}
+
+#[cfg(not(feature = "tls"))]
+fn disabled() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "TLS needs procshot_server built with the `tls` feature.",
+    )
+}
+
+/// TlsClient opens the connections of a server to the collector.
+#[cfg(not(feature = "tls"))]
+pub struct TlsClient(());
+
+#[cfg(not(feature = "tls"))]
+impl TlsClient {
+    pub fn new(_files: TlsFiles, _server_name: &str) -> io::Result<Self> {
+        Err(disabled())
+    }
+
+    pub fn reload(&mut self) -> bool {
+        false
+    }
+
+    pub fn connect(&self, _tcp: TcpStream) -> io::Result<Box<dyn Connection>> {
+        Err(disabled())
+    }
+}
+
+/// TlsServer accepts the connections of the servers on the collector.
+#[cfg(not(feature = "tls"))]
+pub struct TlsServer(());
+
+#[cfg(not(feature = "tls"))]
+impl TlsServer {
+    pub fn new(_files: TlsFiles) -> io::Result<Self> {
+        Err(disabled())
+    }
+
+    pub fn accept(&self, _tcp: TcpStream) -> io::Result<Box<dyn Connection>> {
+        Err(disabled())
+    }
+}
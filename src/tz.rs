@@ -0,0 +1,274 @@
+//! Time zone used to display and parse timestamps.
+//!
+//! Snapshots always store UTC epoch seconds. Everything that shows a time to a user, or reads one
+//! from them (eg: the `-t` option), goes through a `TimeZone`, so input and output are always
+//! interpreted the same way. It is selected with `--tz` and defaults to UTC.
+//!
+//! Times read from users are parsed by `TimeZone::parse_at`, which also takes epochs, times in
+//! another zone than the selected one, and times relative to now like `2h ago`, and fails with a
+//! `TimeError`.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::units;
+
+/// TimeError is why a time or a range of times given by a user doesn't parse.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TimeError {
+    /// The time isn't in any accepted format.
+    Format(String),
+    /// The range isn't written `<from>..<to>`.
+    NotARange(String),
+    /// The range ends before it starts.
+    Reversed(String),
+}
+
+impl fmt::Display for TimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TimeError::Format(s) => write!(
+                f,
+                "Invalid time {}. Accepted formats: 2015-09-05 23:56:04, optionally followed by utc, local or an offset like +05:30, an epoch, now, or a duration ago like 30m ago",
+                s
+            ),
+            TimeError::NotARange(s) => write!(f, "Invalid range {}, expected <from>..<to>", s),
+            TimeError::Reversed(s) => {
+                write!(f, "Invalid range {}, it ends before it starts", s)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TimeError {}
+
+/// TimeZone selects how epochs are converted to and from wall clock times.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum TimeZone {
+    #[default]
+    Utc,
+    /// The local time zone of the host, as configured by /etc/localtime or `TZ`, including its
+    /// daylight saving time rules.
+    Local,
+    /// A fixed offset from UTC in seconds, eg: +05:30 is 19800.
+    Fixed(i32),
+}
+
+impl FromStr for TimeZone {
+    type Err = String;
+
+    /// Accepts `utc`, `local`, or an offset like `+05:30`, `-0800` or `+2`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "Invalid time zone {}. Accepted values are utc, local or an offset like +05:30.",
+                s
+            )
+        };
+        match s.to_lowercase().as_ref() {
+            "utc" | "z" | "gmt" => return Ok(TimeZone::Utc),
+            "local" => return Ok(TimeZone::Local),
+            _ => (),
+        }
+        let sign = match s.chars().next() {
+            Some('+') => 1,
+            Some('-') => -1,
+            _ => return Err(invalid()),
+        };
+        let digits: String = s[1..].chars().filter(|c| *c != ':').collect();
+        let (hours, minutes) = match digits.len() {
+            1 | 2 => (digits.as_str(), "0"),
+            4 => digits.split_at(2),
+            _ => return Err(invalid()),
+        };
+        let hours: i32 = hours.parse().map_err(|_| invalid())?;
+        let minutes: i32 = minutes.parse().map_err(|_| invalid())?;
+        if hours > 14 || minutes > 59 {
+            return Err(invalid());
+        }
+        Ok(TimeZone::Fixed(sign * (hours * 3600 + minutes * 60)))
+    }
+}
+
+impl TimeZone {
+    /// Returns the offset from UTC in seconds at the instant `epoch`.
+    pub fn offset_at(&self, epoch: u64) -> i32 {
+        match self {
+            TimeZone::Utc => 0,
+            TimeZone::Fixed(offset) => *offset,
+            TimeZone::Local => local_offset(epoch as i64),
+        }
+    }
+
+    /// Formats an epoch as `YYYY-MM-DD HH:MM:SS +hh:mm` in this time zone.
+    pub fn format(&self, epoch: u64) -> String {
+        let offset = self.offset_at(epoch);
+        let (date, time) = civil(epoch as i64 + i64::from(offset));
+        let sign = if offset < 0 { '-' } else { '+' };
+        let offset = offset.abs();
+        format!(
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02} {}{:02}:{:02}",
+            date.0,
+            date.1,
+            date.2,
+            time / 3600,
+            time % 3600 / 60,
+            time % 60,
+            sign,
+            offset / 3600,
+            offset % 3600 / 60
+        )
+    }
+
+    /// Parses a time given by a user, `now` being the current epoch, and returns its epoch. Accepts:
+    ///
+    /// * a wall clock time, see `parse`, followed by the time zone it is in, eg: `utc` or
+    ///   `+05:30`, or in this time zone if none is given;
+    /// * an epoch, eg: `1563617611`;
+    /// * `now`, or a duration in the format of `--retention` before now, eg: `30m ago`.
+    pub fn parse_at(&self, s: &str, now: u64) -> Result<u64, TimeError> {
+        let s = s.trim();
+        if let Ok(epoch) = s.parse::<u64>() {
+            return Ok(epoch);
+        }
+        if s.eq_ignore_ascii_case("now") {
+            return Ok(now);
+        }
+        if let Some(ago) = s.strip_suffix("ago") {
+            let ago = units::parse_duration(ago).map_err(|_| TimeError::Format(s.to_string()))?;
+            return Ok(now.saturating_sub(ago.as_secs()));
+        }
+        if let Some((time, zone)) = s.rsplit_once(' ') {
+            if let Ok(zone) = zone.parse::<TimeZone>() {
+                return zone
+                    .parse(time)
+                    .map_err(|_| TimeError::Format(s.to_string()));
+            }
+        }
+        self.parse(s)
+    }
+
+    /// Parses a wall clock time in the `YYYY-MM-DD HH:MM:SS` format of the `-t` option, or a
+    /// date alone, interpreted in this time zone, and returns its epoch.
+    pub fn parse(&self, s: &str) -> Result<u64, TimeError> {
+        let invalid = || TimeError::Format(s.to_string());
+        let numbers: Vec<i64> = s
+            .trim()
+            .split(['-', ' ', ':', 'T'])
+            .map(|n| n.parse().map_err(|_| invalid()))
+            .collect::<Result<_, _>>()?;
+        let (y, mo, d, h, mi, sec) = match numbers[..] {
+            [y, mo, d, h, mi, sec] => (y, mo, d, h, mi, sec),
+            [y, mo, d] => (y, mo, d, 0, 0, 0),
+            _ => return Err(invalid()),
+        };
+        if !(1..=12).contains(&mo) || !(1..=31).contains(&d) || h > 23 || mi > 59 || sec > 60 {
+            return Err(invalid());
+        }
+        let naive = days_from_civil(y, mo, d) * 86400 + h * 3600 + mi * 60 + sec;
+        // The offset depends on the instant, which depends on the offset: start from the offset at
+        // the naive time and correct once, which settles everywhere except inside DST gaps.
+        let guess = naive - i64::from(self.offset_at(naive.max(0) as u64));
+        let epoch = naive - i64::from(self.offset_at(guess.max(0) as u64));
+        u64::try_from(epoch).map_err(|_| invalid())
+    }
+}
+
+/// Returns the (year, month, day) and the seconds into the day of a unix time.
+pub fn civil(epoch: i64) -> ((i64, i64, i64), i64) {
+    let days = epoch.div_euclid(86400);
+    let secs = epoch.rem_euclid(86400);
+    // Civil from days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    ((year, month, day), secs)
+}
+
+/// Returns the number of days since 1970-01-01 of a date, the inverse of `civil`.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+#[cfg(unix)]
+fn local_offset(epoch: i64) -> i32 {
+    let time = epoch as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    // localtime_r is the thread safe variant, and reads TZ and /etc/localtime as needed.
+    match unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        true => 0,
+        false => tm.tm_gmtoff as i32,
+    }
+}
+
+/// Without a C library to ask, eg: on wasm32, local time is UTC.
+#[cfg(not(unix))]
+fn local_offset(_epoch: i64) -> i32 {
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_time_zone() {
+        assert_eq!("UTC".parse::<TimeZone>(), Ok(TimeZone::Utc));
+        assert_eq!("local".parse::<TimeZone>(), Ok(TimeZone::Local));
+        assert_eq!("+05:30".parse::<TimeZone>(), Ok(TimeZone::Fixed(19800)));
+        assert_eq!("-0800".parse::<TimeZone>(), Ok(TimeZone::Fixed(-28800)));
+        assert_eq!("+2".parse::<TimeZone>(), Ok(TimeZone::Fixed(7200)));
+        assert!("Mars/Olympus".parse::<TimeZone>().is_err());
+        assert!("+25".parse::<TimeZone>().is_err());
+    }
+
+    #[test]
+    fn test_format_and_parse() {
+        assert_eq!(
+            TimeZone::Utc.format(1563617611),
+            "2019-07-20 10:13:31 +00:00"
+        );
+        let ist = TimeZone::Fixed(19800);
+        assert_eq!(ist.format(1563617611), "2019-07-20 15:43:31 +05:30");
+        assert_eq!(ist.parse("2019-07-20 15:43:31"), Ok(1563617611));
+        assert_eq!(TimeZone::Utc.parse("2000-02-29"), Ok(951782400));
+        assert!(TimeZone::Utc.parse("2019-13-01 00:00:00").is_err());
+        assert_eq!(
+            TimeZone::Utc.parse("yesterday"),
+            Err(TimeError::Format("yesterday".to_string()))
+        );
+        let now = 1563617611;
+        assert_eq!(
+            TimeZone::Local.parse(&TimeZone::Local.format(now)[..19]),
+            Ok(now)
+        );
+    }
+
+    #[test]
+    fn test_parse_at() {
+        let now = 1563617611;
+        let ist = TimeZone::Fixed(19800);
+        assert_eq!(ist.parse_at("2019-07-20 15:43:31", now), Ok(now));
+        assert_eq!(ist.parse_at("2019-07-20 10:13:31 UTC", now), Ok(now));
+        assert_eq!(TimeZone::Utc.parse_at(&ist.format(now), now), Ok(now));
+        assert_eq!(ist.parse_at(" 1563617000 ", now), Ok(1563617000));
+        assert_eq!(ist.parse_at("now", now), Ok(now));
+        assert_eq!(ist.parse_at("30m ago", now), Ok(now - 1800));
+        assert_eq!(ist.parse_at("2h ago", now), Ok(now - 7200));
+        assert!(ist.parse_at("2 fortnights ago", now).is_err());
+        assert!(ist.parse_at("2019-07-20 10:13:31 Mars", now).is_err());
+    }
+}
@@ -0,0 +1,230 @@
+//! Memory unit formatting shared by the client side outputs.
+//!
+//! Everything that prints a memory size should go through `ByteFormat`, so the same value is shown
+//! the same way in every table and report, and scripts can ask for raw bytes. Durations given on
+//! the command line are parsed by `parse_duration`, and sizes by `parse_size`.
+
+use std::str::FromStr;
+use std::time::Duration;
+
+/// UnitSystem selects how memory sizes are scaled.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnitSystem {
+    /// Powers of 1024 with IEC suffixes: KiB, MiB, GiB, TiB.
+    Binary,
+    /// Powers of 1000 with SI suffixes: KB, MB, GB, TB.
+    Decimal,
+    /// Plain number of bytes without a suffix, for scripts.
+    Raw,
+}
+
+impl FromStr for UnitSystem {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "binary" | "iec" | "kib" => Ok(UnitSystem::Binary),
+            "decimal" | "si" | "kb" => Ok(UnitSystem::Decimal),
+            "raw" | "bytes" => Ok(UnitSystem::Raw),
+            _ => Err(format!(
+                "Unknown unit system {}. Accepted values are binary, decimal and raw.",
+                s
+            )),
+        }
+    }
+}
+
+/// ByteFormat formats byte counts according to the configured unit system and locale.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ByteFormat {
+    pub units: UnitSystem,
+    /// Number of digits after the decimal separator for scaled values.
+    pub precision: usize,
+    /// Character used as decimal separator, eg: ',' for most European locales.
+    pub decimal_separator: char,
+}
+
+impl Default for ByteFormat {
+    fn default() -> Self {
+        ByteFormat {
+            units: UnitSystem::Binary,
+            precision: 1,
+            decimal_separator: '.',
+        }
+    }
+}
+
+impl ByteFormat {
+    pub fn new(units: UnitSystem) -> Self {
+        ByteFormat {
+            units,
+            ..Default::default()
+        }
+    }
+
+    /// Formats a size given in bytes, eg: `rss_bytes`.
+    pub fn bytes(&self, bytes: u64) -> String {
+        let (base, suffixes): (f64, [&str; 5]) = match self.units {
+            UnitSystem::Raw => return bytes.to_string(),
+            UnitSystem::Binary => (1024.0, ["B", "KiB", "MiB", "GiB", "TiB"]),
+            UnitSystem::Decimal => (1000.0, ["B", "KB", "MB", "GB", "TB"]),
+        };
+        let mut value = bytes as f64;
+        let mut i = 0;
+        while value >= base && i < suffixes.len() - 1 {
+            value /= base;
+            i += 1;
+        }
+        if i == 0 {
+            return format!("{} {}", bytes, suffixes[0]);
+        }
+        let number = format!("{:.*}", self.precision, value);
+        let number = match self.decimal_separator {
+            '.' => number,
+            sep => number.replace('.', &sep.to_string()),
+        };
+        format!("{} {}", number, suffixes[i])
+    }
+
+    /// Formats a size given in kB as reported by /proc/<pid>/status, eg: `vmpeak`. The kernel's kB
+    /// is 1024 bytes.
+    pub fn kib(&self, kib: u64) -> String {
+        self.bytes(kib.saturating_mul(1024))
+    }
+
+    /// Formats a size given in pages, eg: `rss_pages`.
+    pub fn pages(&self, pages: u64, page_size: u64) -> String {
+        self.bytes(pages.saturating_mul(page_size))
+    }
+}
+
+/// Parses a memory size like `512M`, `2GiB` or `1500MB`. K, M, G and T with or without `iB` are
+/// powers of 1024, KB, MB, GB and TB are powers of 1000. A plain number is taken as bytes.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("Invalid size {}. Expected eg: 512M, 2GiB.", s))?;
+    let multiplier: u64 = match unit.trim().to_uppercase().as_ref() {
+        "" | "B" => 1,
+        "K" | "KIB" => 1 << 10,
+        "M" | "MIB" => 1 << 20,
+        "G" | "GIB" => 1 << 30,
+        "T" | "TIB" => 1 << 40,
+        "KB" => 1_000,
+        "MB" => 1_000_000,
+        "GB" => 1_000_000_000,
+        "TB" => 1_000_000_000_000,
+        _ => return Err(format!("Unknown size unit {} in {}.", unit, s)),
+    };
+    Ok(number.saturating_mul(multiplier))
+}
+
+/// Parses a duration like `500ms`, `90s`, `15m`, `24h`, `7d` or `2w`. A plain number is taken as
+/// seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("Invalid duration {}. Expected eg: 30s, 15m, 24h, 7d.", s))?;
+    let multiplier = match unit {
+        "ms" => return Ok(Duration::from_millis(number)),
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => {
+            return Err(format!(
+                "Unknown duration unit {} in {}. Accepted units are ms, s, m, h, d and w.",
+                unit, s
+            ))
+        }
+    };
+    Ok(Duration::from_secs(number.saturating_mul(multiplier)))
+}
+
+/// Formats a duration in the units of `parse_duration`, keeping the two largest, eg: `45s`,
+/// `1h 5m` or `2d 3h`. Durations below a second are formatted in ms.
+pub fn format_duration(d: Duration) -> String {
+    if d < Duration::from_secs(1) {
+        return format!("{}ms", d.as_millis());
+    }
+    let units = [("d", 24 * 60 * 60), ("h", 60 * 60), ("m", 60), ("s", 1)];
+    let mut secs = d.as_secs();
+    let largest = units
+        .iter()
+        .position(|(_, size)| secs >= *size)
+        .unwrap_or(3);
+    units[largest..]
+        .iter()
+        .take(2)
+        .filter_map(|(unit, size)| {
+            let n = secs / size;
+            secs %= size;
+            (n > 0).then(|| format!("{}{}", n, unit))
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_binary_and_decimal() {
+        let b = ByteFormat::new(UnitSystem::Binary);
+        assert_eq!(b.bytes(512), "512 B");
+        assert_eq!(b.bytes(1536), "1.5 KiB");
+        assert_eq!(b.kib(2 * 1024 * 1024), "2.0 GiB");
+        let d = ByteFormat::new(UnitSystem::Decimal);
+        assert_eq!(d.bytes(1_500_000), "1.5 MB");
+        assert_eq!(ByteFormat::new(UnitSystem::Raw).bytes(1536), "1536");
+    }
+
+    #[test]
+    fn test_locale_separator() {
+        let f = ByteFormat {
+            decimal_separator: ',',
+            precision: 2,
+            ..Default::default()
+        };
+        assert_eq!(f.bytes(1536), "1,50 KiB");
+    }
+
+    #[test]
+    fn test_parse_unit_system() {
+        assert_eq!("IEC".parse::<UnitSystem>(), Ok(UnitSystem::Binary));
+        assert_eq!("si".parse::<UnitSystem>(), Ok(UnitSystem::Decimal));
+        assert!("furlongs".parse::<UnitSystem>().is_err());
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512M"), Ok(512 << 20));
+        assert_eq!(parse_size("2GiB"), Ok(2 << 30));
+        assert_eq!(parse_size("1500MB"), Ok(1_500_000_000));
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert!(parse_size("lots").is_err());
+        assert!(parse_size("5 parsecs").is_err());
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("24h"), Ok(Duration::from_secs(86400)));
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("2w"), Ok(Duration::from_secs(1_209_600)));
+        assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("3 fortnights").is_err());
+        assert_eq!(format_duration(Duration::from_secs(45)), "45s");
+        assert_eq!(format_duration(Duration::from_secs(3900)), "1h 5m");
+        assert_eq!(format_duration(Duration::from_secs(3 * 86400 + 30)), "3d");
+        assert_eq!(format_duration(Duration::from_millis(250)), "250ms");
+    }
+}
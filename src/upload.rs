@@ -0,0 +1,319 @@
+//! Uploads closed snapshot files to S3 compatible object storage.
+//!
+//! `Uploader::upload_closed` ships every snapshot in the datadir except the newest one (which the
+//! server may still be writing) to `<bucket>/<prefix><hostname>/<file>`, using a bounded number of
+//! worker threads and retrying failed uploads with exponential backoff. A local file is deleted
+//! only after the upload has been verified: the request is signed with the SHA-256 of the payload,
+//! which the server checks, and a HEAD request must report the expected size.
+//!
+//! The configuration is always available so the CLI can parse it; the uploader itself needs the
+//! `s3` feature.
+
+use std::fmt;
+use std::time::Duration;
+
+/// S3Config holds the object storage settings.
+#[derive(Clone, PartialEq)]
+pub struct S3Config {
+    /// Endpoint url, eg: `https://s3.eu-west-1.amazonaws.com` or `http://minio:9000`.
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    /// Prepended to the object keys, eg: `procshot/`.
+    pub prefix: String,
+    pub access_key: String,
+    pub secret_key: String,
+    /// Number of files uploaded in parallel.
+    pub concurrency: usize,
+    /// Number of attempts per file before giving up on it for this run.
+    pub max_attempts: u32,
+    /// Wait before the first retry, doubled on every further retry.
+    pub initial_backoff: Duration,
+}
+
+impl fmt::Debug for S3Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("S3Config")
+            .field("endpoint", &self.endpoint)
+            .field("bucket", &self.bucket)
+            .field("region", &self.region)
+            .field("prefix", &self.prefix)
+            .field("access_key", &self.access_key)
+            .field("secret_key", &"<redacted>")
+            .field("concurrency", &self.concurrency)
+            .field("max_attempts", &self.max_attempts)
+            .field("initial_backoff", &self.initial_backoff)
+            .finish()
+    }
+}
+
+impl S3Config {
+    /// Returns a config with the credentials taken from the `AWS_ACCESS_KEY_ID` and
+    /// `AWS_SECRET_ACCESS_KEY` environment variables, so they don't show up in the process list.
+    pub fn new(endpoint: &str, bucket: &str, region: &str, prefix: &str) -> Self {
+        S3Config {
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            bucket: bucket.to_string(),
+            region: region.to_string(),
+            prefix: prefix.to_string(),
+            access_key: std::env::var("AWS_ACCESS_KEY_ID").unwrap_or_default(),
+            secret_key: std::env::var("AWS_SECRET_ACCESS_KEY").unwrap_or_default(),
+            concurrency: 4,
+            max_attempts: 5,
+            initial_backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Result of one upload run.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UploadReport {
+    /// Files uploaded, verified and deleted locally.
+    pub uploaded: Vec<String>,
+    /// Files that could not be uploaded, with the last error. They are kept locally and retried on
+    /// the next run.
+    pub failed: Vec<(String, String)>,
+}
+
+#[cfg(feature = "s3")]
+pub use self::s3::Uploader;
+
+#[cfg(feature = "s3")]
+mod s3 {
+    use std::fs;
+    use std::io;
+    use std::path::{Path, PathBuf};
+    use std::sync::Mutex;
+    use std::thread;
+
+    use hmac::{Hmac, Mac};
+    use sha2::{Digest, Sha256};
+
+    use super::{S3Config, UploadReport};
+
+    /// Uploader ships snapshot files to object storage.
+    pub struct Uploader {
+        config: S3Config,
+        hostname: String,
+        agent: ureq::Agent,
+    }
+
+    impl Uploader {
+        pub fn new(config: S3Config, hostname: &str) -> io::Result<Self> {
+            if config.access_key.is_empty() || config.secret_key.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY must be set",
+                ));
+            }
+            let agent = ureq::AgentBuilder::new()
+                .timeout(std::time::Duration::from_secs(300))
+                .build();
+            Ok(Uploader {
+                config,
+                hostname: hostname.to_string(),
+                agent,
+            })
+        }
+
+        /// Uploads every snapshot in `datadir` except the newest one.
+        pub fn upload_closed(&self, datadir: &Path) -> io::Result<UploadReport> {
+            let mut files: Vec<PathBuf> = crate::store::snapshot_files(datadir)?
+                .into_iter()
+                .map(|(_, p)| p)
+                .collect();
+            // The newest file may still be in the middle of being written.
+            files.pop();
+            Ok(self.upload_files(&files))
+        }
+
+        /// Uploads the given files and deletes each one after a verified upload.
+        pub fn upload_files(&self, files: &[PathBuf]) -> UploadReport {
+            let queue = Mutex::new(files.iter());
+            let report = Mutex::new(UploadReport::default());
+            thread::scope(|scope| {
+                for _ in 0..self.config.concurrency.max(1) {
+                    scope.spawn(|| loop {
+                        let next = queue.lock().unwrap().next();
+                        let path = match next {
+                            Some(p) => p,
+                            None => break,
+                        };
+                        let name = path
+                            .file_name()
+                            .map(|n| n.to_string_lossy().to_string())
+                            .unwrap_or_default();
+                        let result = self
+                            .upload_with_retry(path, &name)
+                            .and_then(|()| fs::remove_file(path));
+                        let mut report = report.lock().unwrap();
+                        match result {
+                            Ok(()) => report.uploaded.push(name),
+                            Err(e) => report.failed.push((name, e.to_string())),
+                        }
+                    });
+                }
+            });
+            let mut report = report.into_inner().unwrap();
+            report.uploaded.sort();
+            report.failed.sort();
+            report
+        }
+
+        fn upload_with_retry(&self, path: &Path, name: &str) -> io::Result<()> {
+            let data = fs::read(path)?;
+            // A hostname with slashes would otherwise land under another host's prefix.
+            let key = format!(
+                "{}{}/{}",
+                self.config.prefix,
+                crate::paths::component(&self.hostname),
+                name
+            );
+            let mut backoff = self.config.initial_backoff;
+            let mut attempt = 1;
+            loop {
+                match self.put(&key, &data).and_then(|()| self.verify(&key, data.len())) {
+                    Ok(()) => return Ok(()),
+                    Err(e) if attempt >= self.config.max_attempts => return Err(e),
+                    Err(_) => {
+                        std::thread::sleep(backoff);
+                        backoff *= 2;
+                        attempt += 1;
+                    }
+                }
+            }
+        }
+
+        fn put(&self, key: &str, data: &[u8]) -> io::Result<()> {
+            let payload_hash = hex(&Sha256::digest(data));
+            let request = self.signed("PUT", key, &payload_hash);
+            request
+                .send_bytes(data)
+                .map(|_| ())
+                .map_err(|e| io::Error::other(format!("PUT {}: {}", key, e)))
+        }
+
+        fn verify(&self, key: &str, len: usize) -> io::Result<()> {
+            let empty_hash = hex(&Sha256::digest(b""));
+            let response = self
+                .signed("HEAD", key, &empty_hash)
+                .call()
+                .map_err(|e| io::Error::other(format!("HEAD {}: {}", key, e)))?;
+            match response.header("Content-Length").and_then(|l| l.parse::<usize>().ok()) {
+                Some(l) if l == len => Ok(()),
+                other => Err(io::Error::other(format!(
+                    "uploaded {} has size {:?}, expected {}",
+                    key, other, len
+                ))),
+            }
+        }
+
+        /// Builds a request for a path style url, signed with AWS signature version 4.
+        fn signed(&self, method: &str, key: &str, payload_hash: &str) -> ureq::Request {
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            let (amz_date, date) = amz_dates(now);
+            let host = host_header(&self.config.endpoint);
+            let path = format!("/{}/{}", uri_encode(&self.config.bucket), uri_encode(key));
+            let canonical = format!(
+                "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\nhost;x-amz-content-sha256;x-amz-date\n{}",
+                method, path, host, payload_hash, amz_date, payload_hash
+            );
+            let scope = format!("{}/{}/s3/aws4_request", date, self.config.region);
+            let to_sign = format!(
+                "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+                amz_date,
+                scope,
+                hex(&Sha256::digest(canonical.as_bytes()))
+            );
+            let mut signing_key = format!("AWS4{}", self.config.secret_key).into_bytes();
+            for part in &[date.as_str(), self.config.region.as_str(), "s3", "aws4_request"] {
+                signing_key = hmac(&signing_key, part.as_bytes());
+            }
+            let signature = hex(&hmac(&signing_key, to_sign.as_bytes()));
+            let authorization = format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={}",
+                self.config.access_key, scope, signature
+            );
+            self.agent
+                .request(method, &format!("{}{}", self.config.endpoint, path))
+                .set("x-amz-content-sha256", payload_hash)
+                .set("x-amz-date", &amz_date)
+                .set("Authorization", &authorization)
+        }
+    }
+
+    fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac accepts any key size");
+        mac.update(data);
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// Encodes a path as required by signature version 4, keeping the `/` separators.
+    fn uri_encode(s: &str) -> String {
+        s.bytes()
+            .map(|b| match b {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                    (b as char).to_string()
+                }
+                _ => format!("%{:02X}", b),
+            })
+            .collect()
+    }
+
+    /// Returns the Host header the http client sends for `endpoint`: the port is only included if
+    /// it is not the default one of the scheme.
+    pub(super) fn host_header(endpoint: &str) -> String {
+        let (scheme, rest) = endpoint.split_once("://").unwrap_or(("https", endpoint));
+        let authority = rest.split('/').next().unwrap_or(rest);
+        let default_port = if scheme == "http" { ":80" } else { ":443" };
+        authority
+            .strip_suffix(default_port)
+            .unwrap_or(authority)
+            .to_string()
+    }
+
+    /// Returns the `x-amz-date` (YYYYMMDDTHHMMSSZ) and the date (YYYYMMDD) for a unix time.
+    pub(super) fn amz_dates(epoch: u64) -> (String, String) {
+        let ((year, month, day), secs) = crate::tz::civil(epoch as i64);
+        let date = format!("{:04}{:02}{:02}", year, month, day);
+        let amz_date = format!(
+            "{}T{:02}{:02}{:02}Z",
+            date,
+            secs / 3600,
+            secs % 3600 / 60,
+            secs % 60
+        );
+        (amz_date, date)
+    }
+}
+
+#[cfg(all(test, feature = "s3"))]
+mod tests {
+    use super::s3::{amz_dates, host_header};
+
+    #[test]
+    fn test_amz_dates() {
+        assert_eq!(
+            amz_dates(1563617611),
+            ("20190720T101331Z".to_string(), "20190720".to_string())
+        );
+        assert_eq!(
+            amz_dates(951782400),
+            ("20000229T000000Z".to_string(), "20000229".to_string())
+        );
+    }
+
+    #[test]
+    fn test_host_header() {
+        assert_eq!(host_header("https://s3.amazonaws.com"), "s3.amazonaws.com");
+        assert_eq!(host_header("http://minio:9000"), "minio:9000");
+        assert_eq!(host_header("http://minio:80"), "minio");
+    }
+}
@@ -0,0 +1,316 @@
+//! Offline validation of the snapshot files of a datadir.
+//!
+//! `validate` reads every snapshot file of both tiers of a datadir and reports, as JSON, the
+//! problems it finds in each, so a corrupt archive is found before a report or a conversion
+//! stumbles on it, and scripts can act on the result:
+//!
+//! * `read`: the file can't be read.
+//! * `checksum`: the compressed stream is corrupt. gzip files carry a CRC32 of their content,
+//!   which is checked, as are the checksums of the zstd frames written with one. Uncompressed
+//!   files have no checksum.
+//! * `header`, `version`: the `store::SNAPSHOT_MAGIC` header is truncated or invalid, or tells a
+//!   format version newer than this build reads.
+//! * `decode`: the snapshot doesn't decode.
+//! * `timestamp`: the snapshot wasn't taken at the time of its file name, or before the snapshot
+//!   of the previous file.
+//! * `counter`: a metric of a process, eg: its rss or CPU usage, is negative. See the `metrics`
+//!   module.
+//!
+//! `check_bytes` and `check_snapshot` work on the content of a file, without touching the disk,
+//! and `fuzz` runs both on arbitrary bytes, eg: from a `cargo fuzz` target:
+//!
+//! ```text
+//! fuzz_target!(|data: &[u8]| procshot_server::validate::fuzz(data));
+//! ```
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::metrics;
+use crate::store::{self, DecodeError};
+use crate::EncoDecode;
+
+/// ValidateJob describes one run, as given to the `validate` subcommand.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidateJob {
+    /// Datadir to check, the `--datadir` of the server if None.
+    pub datadir: Option<PathBuf>,
+}
+
+/// ProblemKind is what is wrong with a snapshot file, see the module documentation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProblemKind {
+    Read,
+    Checksum,
+    Header,
+    Version,
+    Decode,
+    Timestamp,
+    Counter,
+}
+
+/// Issue is a problem found in the content of a snapshot file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Issue {
+    pub kind: ProblemKind,
+    pub detail: String,
+}
+
+impl Issue {
+    fn new(kind: ProblemKind, detail: impl Into<String>) -> Self {
+        Issue {
+            kind,
+            detail: detail.into(),
+        }
+    }
+}
+
+/// Problem is an `Issue` of a file of the datadir.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Problem {
+    pub path: PathBuf,
+    #[serde(flatten)]
+    pub issue: Issue,
+}
+
+/// ValidateReport is the outcome of checking a datadir.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ValidateReport {
+    pub datadir: PathBuf,
+    /// Number of snapshot files checked.
+    pub files: usize,
+    /// Number of files without any problem.
+    pub valid: usize,
+    /// Problems found, by file in the order of the archive.
+    pub problems: Vec<Problem>,
+}
+
+impl ValidateReport {
+    /// Returns true if no problem was found.
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Decodes the content of a snapshot file, as read from the disk. Returns the first issue that
+/// prevents decoding it. Never panics, whatever `data` holds.
+pub fn check_bytes(data: &[u8]) -> Result<EncoDecode, Issue> {
+    let data = store::decompress(data.to_vec())
+        .map_err(|e| Issue::new(ProblemKind::Checksum, e.to_string()))?;
+    match store::split_header(&data) {
+        Err(DecodeError::Version(found)) => {
+            return Err(Issue::new(
+                ProblemKind::Version,
+                DecodeError::Version(found).to_string(),
+            ))
+        }
+        Err(e) => return Err(Issue::new(ProblemKind::Header, e.to_string())),
+        Ok(_) => (),
+    }
+    store::decode(&data).map_err(|e| Issue::new(ProblemKind::Decode, e.to_string()))
+}
+
+/// Returns the issues of a decoded snapshot that can be told without the other snapshots of the
+/// archive: negative metrics of the processes, by pid.
+pub fn check_snapshot(snapshot: &EncoDecode) -> Vec<Issue> {
+    let mut pids: Vec<_> = snapshot.pid_map_list.iter().collect();
+    pids.sort_by_key(|(pid, _)| **pid);
+    let mut issues = Vec::new();
+    for (pid, s) in pids {
+        for metric in metrics::PROCESS_METRICS.iter().filter(|m| m.field) {
+            match metric.of(s) {
+                Some(value) if value < 0.0 || value.is_nan() => issues.push(Issue::new(
+                    ProblemKind::Counter,
+                    format!("pid {}: {} is {}", pid, metric, value),
+                )),
+                _ => (),
+            }
+        }
+    }
+    issues
+}
+
+/// Runs `check_bytes` and `check_snapshot` on arbitrary bytes, the entry point of fuzzers.
+pub fn fuzz(data: &[u8]) {
+    if let Ok(snapshot) = check_bytes(data) {
+        check_snapshot(&snapshot);
+    }
+}
+
+/// Checks every snapshot file of both tiers of `datadir`.
+pub fn validate(datadir: &Path) -> std::io::Result<ValidateReport> {
+    let files = store::snapshot_files(datadir)?;
+    let mut report = ValidateReport {
+        datadir: datadir.to_path_buf(),
+        files: files.len(),
+        valid: 0,
+        problems: Vec::new(),
+    };
+    let mut previous: Option<u64> = None;
+    for (epoch, path) in files {
+        let issues = match fs::read(&path) {
+            Ok(data) => check_file(&data, epoch, &mut previous),
+            Err(e) => vec![Issue::new(ProblemKind::Read, e.to_string())],
+        };
+        if issues.is_empty() {
+            report.valid += 1;
+        }
+        report
+            .problems
+            .extend(issues.into_iter().map(|issue| Problem {
+                path: path.clone(),
+                issue,
+            }));
+    }
+    Ok(report)
+}
+
+/// Returns the issues of the file named after `epoch`, `previous` being the time of the last
+/// snapshot decoded before it.
+fn check_file(data: &[u8], epoch: u64, previous: &mut Option<u64>) -> Vec<Issue> {
+    let snapshot = match check_bytes(data) {
+        Ok(snapshot) => snapshot,
+        Err(issue) => return vec![issue],
+    };
+    let mut issues = Vec::new();
+    if snapshot.time_epoch != epoch {
+        issues.push(Issue::new(
+            ProblemKind::Timestamp,
+            format!(
+                "taken at {}, but named after {}",
+                snapshot.time_epoch, epoch
+            ),
+        ));
+    }
+    if let Some(previous) = previous.filter(|p| snapshot.time_epoch < *p) {
+        issues.push(Issue::new(
+            ProblemKind::Timestamp,
+            format!(
+                "taken at {}, before the previous snapshot taken at {}",
+                snapshot.time_epoch, previous
+            ),
+        ));
+    }
+    *previous = Some(snapshot.time_epoch);
+    issues.extend(check_snapshot(&snapshot));
+    issues
+}
+
+/// Formats the report as pretty printed JSON.
+pub fn render(report: &ValidateReport) -> String {
+    serde_json::to_string_pretty(report).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{DirBackend, StorageBackend};
+    use crate::store::{CompressionMode, SerializationFormat};
+    use crate::{Pid, PidStatus};
+    use std::time::Duration;
+
+    fn snapshot(epoch: u64, rss_bytes: i64) -> EncoDecode {
+        let status = PidStatus {
+            ppid: Pid::new(1),
+            cmd_long: vec!["sshd".to_string()],
+            name: "sshd".to_string(),
+            cmd_short: "sshd".to_string(),
+            fdsize: 64,
+            state: "S (sleeping)".to_string(),
+            vmpeak: Some(1024),
+            vmsize: Some(1000),
+            rss_pages: rss_bytes / 4096,
+            rss_bytes,
+            rsslim_bytes: u64::MAX,
+            processor_last_executed: Some(0),
+            utime: 1,
+            stime: 1,
+            user_cpu_usage: 0.5,
+            sys_cpu_usage: 0.5,
+            ..Default::default()
+        };
+        EncoDecode {
+            hostname: "localghost".to_string(),
+            pid_map_list: vec![(Pid::new(42), status)].into_iter().collect(),
+            time_epoch: epoch,
+            delay: Duration::from_secs(60),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let dir = std::env::temp_dir().join(format!("procshot_validate_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let mut backend = DirBackend::new(&dir, false);
+        for epoch in [60, 120] {
+            backend.write_snapshot(&snapshot(epoch, 4096)).unwrap();
+        }
+        let encoded = |s: &EncoDecode| SerializationFormat::Bincode.encode(s).unwrap();
+        fs::write(dir.join("180.procshot"), encoded(&snapshot(100, -4096))).unwrap();
+        let mut gzip = CompressionMode::Gzip
+            .compress(encoded(&snapshot(240, 4096)))
+            .unwrap();
+        let crc = gzip.len() - 8;
+        gzip[crc] ^= 0xff;
+        fs::write(dir.join("240.procshot.gz"), gzip).unwrap();
+        let mut newer = encoded(&snapshot(300, 4096));
+        newer[store::SNAPSHOT_MAGIC.len()] = store::FORMAT_VERSION + 1;
+        fs::write(dir.join("300.procshot"), newer).unwrap();
+        fs::write(dir.join("360.procshot"), b"PSHT").unwrap();
+        fs::write(
+            dir.join("420.procshot"),
+            &encoded(&snapshot(420, 4096))[..40],
+        )
+        .unwrap();
+
+        let report = validate(&dir).unwrap();
+        assert_eq!((report.files, report.valid), (7, 2));
+        let kinds: Vec<(String, ProblemKind)> = report
+            .problems
+            .iter()
+            .map(|p| {
+                let name = p.path.file_name().unwrap().to_string_lossy();
+                (name.to_string(), p.issue.kind)
+            })
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                ("180.procshot".to_string(), ProblemKind::Timestamp),
+                ("180.procshot".to_string(), ProblemKind::Timestamp),
+                ("180.procshot".to_string(), ProblemKind::Counter),
+                ("180.procshot".to_string(), ProblemKind::Counter),
+                ("240.procshot.gz".to_string(), ProblemKind::Checksum),
+                ("300.procshot".to_string(), ProblemKind::Version),
+                ("360.procshot".to_string(), ProblemKind::Header),
+                ("420.procshot".to_string(), ProblemKind::Decode),
+            ]
+        );
+        assert!(!report.is_ok());
+        let json: serde_json::Value = serde_json::from_str(&render(&report)).unwrap();
+        assert_eq!(json["problems"][2]["kind"], "counter");
+        assert_eq!(json["problems"][2]["detail"], "pid 42: rss_bytes is -4096");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_fuzz_corrupted_snapshots() {
+        let valid = SerializationFormat::Bincode
+            .encode(&snapshot(60, 4096))
+            .unwrap();
+        let headerless = bincode::serialize(&snapshot(60, 4096)).unwrap();
+        for data in [valid, headerless] {
+            for len in 0..data.len() {
+                fuzz(&data[..len]);
+            }
+            for i in 0..data.len() {
+                let mut corrupt = data.clone();
+                corrupt[i] ^= 0xff;
+                fuzz(&corrupt);
+            }
+        }
+    }
+}
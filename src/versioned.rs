@@ -0,0 +1,226 @@
+//! Snapshots in the layouts written by older releases.
+//!
+//! `EncoDecode` and `PidStatus` gained fields over the releases, and bincode, which isn't
+//! self-describing, can't decode a snapshot into a struct of another layout. Rather than asking
+//! users to delete their history on every release, the layouts of older releases are kept here,
+//! and their snapshots are decoded into them and upgraded in memory to the current `EncoDecode`,
+//! with the fields they lack left to their defaults.
+//!
+//! Layouts are numbered like `store::FORMAT_VERSION`, and the first two were written without
+//! header:
+//!
+//! - version 1 is the layout of the first releases, without CPU usage, and with the processes in
+//!   a list of maps of one process each, like `test_data.procshot`;
+//! - version 2 is the layout of the 0.1.5 release.
+//!
+//! Version 3 is the layout of the next release, written with a header. The layouts its development
+//! went through were never released and aren't kept.
+//!
+//! A change of layout in a release raises `FORMAT_VERSION` and adds the layout of the previous
+//! release here, as a new variant of `VersionedSnapshot` upgraded to the one after it.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::{EncoDecode, Pid, PidStatus};
+
+/// PidStatusV1 is the `PidStatus` of the snapshots of version 1.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct PidStatusV1 {
+    pub ppid: Pid,
+    pub euid: i32,
+    pub cmd_long: Vec<String>,
+    pub name: String,
+    pub cmd_short: String,
+    pub tracerpid: Pid,
+    pub fdsize: u32,
+    pub state: String,
+    pub vmpeak: Option<u64>,
+    pub vmsize: Option<u64>,
+    pub rss_pages: i64,
+    pub rss_bytes: i64,
+    pub rsslim_bytes: u64,
+    pub processor_last_executed: Option<i32>,
+    pub utime: u64,
+    pub stime: u64,
+}
+
+/// EncoDecodeV1 is the `EncoDecode` of the snapshots of version 1.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct EncoDecodeV1 {
+    pub hostname: String,
+    pub pid_map_list: Vec<HashMap<Pid, PidStatusV1>>,
+    pub time_epoch: u64,
+    /// Delay between two snapshots, in seconds.
+    pub delay: u64,
+    pub total_cpu_time: u64,
+}
+
+/// PidStatusV2 is the `PidStatus` of the snapshots of version 2.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct PidStatusV2 {
+    pub ppid: Pid,
+    pub euid: i32,
+    pub cmd_long: Vec<String>,
+    pub name: String,
+    pub cmd_short: String,
+    pub tracerpid: Pid,
+    pub fdsize: u32,
+    pub state: String,
+    pub vmpeak: Option<u64>,
+    pub vmsize: Option<u64>,
+    pub rss_pages: i64,
+    pub rss_bytes: i64,
+    pub rsslim_bytes: u64,
+    pub processor_last_executed: Option<i32>,
+    pub utime: u64,
+    pub stime: u64,
+    pub user_cpu_usage: f64,
+    pub sys_cpu_usage: f64,
+}
+
+/// EncoDecodeV2 is the `EncoDecode` of the snapshots of version 2.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct EncoDecodeV2 {
+    pub hostname: String,
+    pub pid_map_list: HashMap<Pid, PidStatusV2>,
+    pub time_epoch: u64,
+    /// Delay between two snapshots, in seconds.
+    pub delay: u64,
+    pub total_cpu_time: u64,
+}
+
+/// VersionedSnapshot is a snapshot in the layout of the version it was written in.
+#[derive(Debug, PartialEq, Clone)]
+pub enum VersionedSnapshot {
+    V1(EncoDecodeV1),
+    V2(EncoDecodeV2),
+    V3(Box<EncoDecode>),
+}
+
+impl VersionedSnapshot {
+    /// Returns the version of the layout of the snapshot.
+    pub fn version(&self) -> u8 {
+        match self {
+            VersionedSnapshot::V1(_) => 1,
+            VersionedSnapshot::V2(_) => 2,
+            VersionedSnapshot::V3(_) => 3,
+        }
+    }
+
+    /// Returns the snapshot in the current layout.
+    pub fn upgrade(self) -> EncoDecode {
+        match self {
+            VersionedSnapshot::V1(v1) => VersionedSnapshot::V2(v1.into()).upgrade(),
+            VersionedSnapshot::V2(v2) => v2.into(),
+            VersionedSnapshot::V3(snapshot) => *snapshot,
+        }
+    }
+}
+
+impl From<PidStatusV1> for PidStatusV2 {
+    fn from(v1: PidStatusV1) -> Self {
+        PidStatusV2 {
+            ppid: v1.ppid,
+            euid: v1.euid,
+            cmd_long: v1.cmd_long,
+            name: v1.name,
+            cmd_short: v1.cmd_short,
+            tracerpid: v1.tracerpid,
+            fdsize: v1.fdsize,
+            state: v1.state,
+            vmpeak: v1.vmpeak,
+            vmsize: v1.vmsize,
+            rss_pages: v1.rss_pages,
+            rss_bytes: v1.rss_bytes,
+            rsslim_bytes: v1.rsslim_bytes,
+            processor_last_executed: v1.processor_last_executed,
+            utime: v1.utime,
+            stime: v1.stime,
+            user_cpu_usage: 0.0,
+            sys_cpu_usage: 0.0,
+        }
+    }
+}
+
+impl From<EncoDecodeV1> for EncoDecodeV2 {
+    fn from(v1: EncoDecodeV1) -> Self {
+        EncoDecodeV2 {
+            hostname: v1.hostname,
+            pid_map_list: v1
+                .pid_map_list
+                .into_iter()
+                .flatten()
+                .map(|(pid, status)| (pid, status.into()))
+                .collect(),
+            time_epoch: v1.time_epoch,
+            delay: v1.delay,
+            total_cpu_time: v1.total_cpu_time,
+        }
+    }
+}
+
+impl From<PidStatusV2> for PidStatus {
+    fn from(v2: PidStatusV2) -> Self {
+        PidStatus {
+            ppid: v2.ppid,
+            euid: v2.euid,
+            cmd_long: v2.cmd_long,
+            name: v2.name,
+            cmd_short: v2.cmd_short,
+            tracerpid: v2.tracerpid,
+            fdsize: v2.fdsize,
+            fds: None,
+            connections: None,
+            state: v2.state,
+            vmpeak: v2.vmpeak,
+            vmsize: v2.vmsize,
+            rss_pages: v2.rss_pages,
+            rss_bytes: v2.rss_bytes,
+            rsslim_bytes: v2.rsslim_bytes,
+            shared_pages: 0,
+            text_pages: 0,
+            data_pages: 0,
+            rss_pct_of_limit: None,
+            processor_last_executed: v2.processor_last_executed,
+            sched_policy: None,
+            rt_priority: None,
+            utime: v2.utime,
+            stime: v2.stime,
+            io: None,
+            service_hint: None,
+            runtime: None,
+            cgroup: None,
+            cgroup_limits: None,
+            user_cpu_usage: v2.user_cpu_usage,
+            sys_cpu_usage: v2.sys_cpu_usage,
+            restricted: false,
+            vanished_during_scan: false,
+            extensions: Default::default(),
+        }
+    }
+}
+
+impl From<EncoDecodeV2> for EncoDecode {
+    fn from(v2: EncoDecodeV2) -> Self {
+        EncoDecode {
+            hostname: v2.hostname,
+            pid_map_list: v2
+                .pid_map_list
+                .into_iter()
+                .map(|(pid, status)| (pid, status.into()))
+                .collect(),
+            time_epoch: v2.time_epoch,
+            delay: Duration::from_secs(v2.delay),
+            total_cpu_time: v2.total_cpu_time,
+            cpu_times: Default::default(),
+            per_cpu: Default::default(),
+            system_memory: Default::default(),
+            load_average: Default::default(),
+            uptime: Duration::from_secs(0),
+            disks: Vec::new(),
+            interfaces: Vec::new(),
+            labels: Default::default(),
+        }
+    }
+}
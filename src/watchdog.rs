@@ -0,0 +1,265 @@
+//! Detection of wedged iterations.
+//!
+//! A read of /proc can block for a long time, eg: the cmdline of a process whose mm lock is held
+//! by a stuck page fault, or the files of a process in uninterruptible sleep on a dead NFS mount.
+//! The server then stops recording without any error. The scan loop reports its progress to a
+//! shared `Progress`, the iteration and the pid being read, and a `Watchdog` thread checks that
+//! no iteration runs longer than its timeout. When one does, the watchdog logs what the loop was
+//! doing, with the kernel function the offending process and the scanning thread are blocked in,
+//! and restarts the server by executing it again with the same arguments. The datadir lock is
+//! kept across the restart since the pid doesn't change, and `DirBackend` writes the snapshot
+//! files aside and renames them into place, so the restart leaves no truncated file behind.
+//!
+//! `execve` waits for the other threads of the process to exit, which a thread in uninterruptible
+//! sleep (state D), eg: on a dead NFS mount, never does: the restart would hang as well. When the
+//! scan thread is in that state, or the restart fails, the server exits instead, for a supervisor
+//! to start it again. The process is only reaped once the kernel releases the thread, so its
+//! `flock` on the datadir lock is released first, and the new server takes the lock over once its
+//! heartbeat is stale.
+
+use std::fs;
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicI32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use std::{env, fmt};
+
+use crate::Pid;
+
+/// Shortest timeout, so that sub-second delays don't restart the server on a slow iteration.
+pub const MIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Value of `Progress::started` between iterations.
+const NONE: u64 = u64::MAX;
+
+/// Progress is updated by the scan loop and read by the watchdog.
+#[derive(Debug)]
+pub struct Progress {
+    origin: Instant,
+    /// Milliseconds since `origin` at the start of the running iteration, `NONE` between
+    /// iterations.
+    started: AtomicU64,
+    /// Number of the running iteration.
+    iteration: AtomicU64,
+    /// Process being read, 0 when none is.
+    pid: AtomicI32,
+    /// Thread id of the scan loop.
+    tid: AtomicI32,
+}
+
+/// Stall describes an iteration that ran longer than the timeout.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stall {
+    pub iteration: u64,
+    pub elapsed: Duration,
+    /// Process being read, None if the iteration was past reading the processes.
+    pub pid: Option<Pid>,
+    /// Thread id of the scan loop.
+    pub tid: i32,
+}
+
+impl fmt::Display for Stall {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "iteration {} has been running for {:?}",
+            self.iteration, self.elapsed
+        )?;
+        match self.pid {
+            Some(pid) => write!(f, ", reading /proc/{}", pid),
+            None => write!(f, ", after reading the processes"),
+        }
+    }
+}
+
+impl Default for Progress {
+    fn default() -> Self {
+        Progress {
+            origin: Instant::now(),
+            started: AtomicU64::new(NONE),
+            iteration: AtomicU64::new(0),
+            pid: AtomicI32::new(0),
+            tid: AtomicI32::new(0),
+        }
+    }
+}
+
+impl Progress {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Marks the start of an iteration, on the thread running the scan loop.
+    pub fn start(&self) {
+        self.tid.store(
+            unsafe { libc::syscall(libc::SYS_gettid) } as i32,
+            Ordering::Relaxed,
+        );
+        self.iteration.fetch_add(1, Ordering::Relaxed);
+        self.pid.store(0, Ordering::Relaxed);
+        self.started.store(self.millis(), Ordering::Release);
+    }
+
+    /// Marks the start of the reading of `pid`, None once the processes are read.
+    pub fn reading(&self, pid: Option<Pid>) {
+        self.pid
+            .store(pid.map_or(0, Pid::as_raw), Ordering::Relaxed);
+    }
+
+    /// Marks the end of the iteration.
+    pub fn finish(&self) {
+        self.started.store(NONE, Ordering::Release);
+    }
+
+    /// Returns the running iteration if it has been running for longer than `timeout`.
+    pub fn stalled(&self, timeout: Duration) -> Option<Stall> {
+        let started = self.started.load(Ordering::Acquire);
+        if started == NONE {
+            return None;
+        }
+        let elapsed = Duration::from_millis(self.millis().saturating_sub(started));
+        if elapsed <= timeout {
+            return None;
+        }
+        let pid = self.pid.load(Ordering::Relaxed);
+        Some(Stall {
+            iteration: self.iteration.load(Ordering::Relaxed),
+            elapsed,
+            pid: Some(Pid::new(pid)).filter(|_| pid != 0),
+            tid: self.tid.load(Ordering::Relaxed),
+        })
+    }
+
+    fn millis(&self) -> u64 {
+        self.origin.elapsed().as_millis() as u64
+    }
+}
+
+/// Returns the timeout of iterations for a watchdog firing after `multiple` times the delay.
+pub fn timeout(delay: Duration, multiple: u32) -> Duration {
+    (delay * multiple).max(MIN_TIMEOUT)
+}
+
+/// Watchdog checks the progress of the scan loop from its own thread.
+#[derive(Debug)]
+pub struct Watchdog {
+    progress: Arc<Progress>,
+    timeout: Duration,
+}
+
+impl Watchdog {
+    pub fn new(progress: Arc<Progress>, timeout: Duration) -> Self {
+        Watchdog { progress, timeout }
+    }
+
+    /// Starts the watchdog thread, which restarts the server at the first stall.
+    pub fn spawn(self) -> io::Result<thread::JoinHandle<()>> {
+        let interval = (self.timeout / 10).min(Duration::from_secs(1));
+        thread::Builder::new()
+            .name("procshot-watchdog".to_string())
+            .spawn(move || loop {
+                thread::sleep(interval);
+                if let Some(stall) = self.progress.stalled(self.timeout) {
+                    eprintln!("Watchdog: {}", stall);
+                    for line in diagnostics(&stall) {
+                        eprintln!("Watchdog: {}", line);
+                    }
+                    if thread_state(stall.tid) == Some('D') {
+                        eprintln!("Watchdog: the scan thread is in uninterruptible sleep, exiting");
+                        crate::lock::release_flock();
+                        std::process::exit(1);
+                    }
+                    let e = restart();
+                    eprintln!("Watchdog: cannot restart the server, exiting, err: {}", e);
+                    std::process::exit(1);
+                }
+            })
+    }
+}
+
+/// Returns what is known about where the scan loop is blocked: the kernel function the offending
+/// process and the scanning thread wait in, and the kernel stack of the thread if readable (root
+/// only). Only files that don't block on the locks of the process are read.
+pub fn diagnostics(stall: &Stall) -> Vec<String> {
+    let mut lines = Vec::new();
+    if let Some(pid) = stall.pid {
+        let dir = Path::new("/proc").join(pid.to_string());
+        lines.push(format!(
+            "pid {} ({}) waits in {}",
+            pid,
+            read_trimmed(&dir.join("comm")),
+            read_trimmed(&dir.join("wchan"))
+        ));
+    }
+    let thread = Path::new("/proc/self/task").join(stall.tid.to_string());
+    lines.push(format!(
+        "scan thread {} waits in {}",
+        stall.tid,
+        read_trimmed(&thread.join("wchan"))
+    ));
+    if let Ok(stack) = fs::read_to_string(thread.join("stack")) {
+        lines.extend(stack.lines().map(|l| format!("  {}", l)));
+    }
+    lines
+}
+
+fn read_trimmed(path: &Path) -> String {
+    fs::read_to_string(path)
+        .map(|s| s.trim_end().to_string())
+        .unwrap_or_else(|_| "?".to_string())
+}
+
+/// Returns the state of the thread `tid` of this process, eg: 'R', 'S' or 'D', from its stat
+/// file.
+pub fn thread_state(tid: i32) -> Option<char> {
+    let stat = fs::read_to_string(format!("/proc/self/task/{}/stat", tid)).ok()?;
+    // The command name may contain spaces and parentheses, the state follows the last `)`.
+    stat[stat.rfind(')')? + 1..].trim_start().chars().next()
+}
+
+/// Executes the running binary again with the same arguments. Only returns on failure.
+pub fn restart() -> io::Error {
+    let mut args = env::args_os();
+    let arg0 = args.next().unwrap_or_default();
+    std::process::Command::new("/proc/self/exe")
+        .arg0(arg0)
+        .args(args)
+        .exec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stalled() {
+        let progress = Progress::new();
+        assert_eq!(progress.stalled(Duration::from_millis(0)), None);
+        progress.start();
+        progress.reading(Some(Pid::current()));
+        thread::sleep(Duration::from_millis(5));
+        assert_eq!(progress.stalled(Duration::from_secs(60)), None);
+        let stall = progress.stalled(Duration::from_millis(1)).unwrap();
+        assert_eq!(stall.iteration, 1);
+        assert_eq!(stall.pid, Some(Pid::current()));
+        let lines = diagnostics(&stall);
+        assert!(lines[0].starts_with(&format!("pid {} (", Pid::current())));
+        assert!(lines[1].starts_with(&format!("scan thread {} waits in ", stall.tid)));
+        assert_eq!(thread_state(stall.tid), Some('R'));
+
+        progress.reading(None);
+        let stall = progress.stalled(Duration::from_millis(1)).unwrap();
+        assert!(stall.to_string().ends_with("after reading the processes"));
+        progress.finish();
+        assert_eq!(progress.stalled(Duration::from_millis(0)), None);
+
+        assert_eq!(timeout(Duration::from_millis(500), 5), MIN_TIMEOUT);
+        assert_eq!(
+            timeout(Duration::from_secs(60), 5),
+            Duration::from_secs(300)
+        );
+    }
+}
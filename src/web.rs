@@ -0,0 +1,230 @@
+//! Minimal embedded web UI over the datadir, started with the `serve-static` subcommand.
+//!
+//! A single static page, compiled into the binary, renders tables and charts from a few JSON
+//! endpoints:
+//!
+//! ```text
+//! GET /                                    the UI
+//! GET /api/snapshots                       epochs of every stored snapshot
+//! GET /api/summaries?from=&to=&limit=      SnapshotSummary of the most recent snapshots in range
+//! GET /api/snapshot/<epoch>                the full snapshot taken at epoch
+//! GET /api/annotations?from=&to=           annotations in range, see the `annotations` module
+//! ```
+//!
+//! The server speaks just enough HTTP/1.1 for browsers and curl: one request per connection, GET
+//! only. It binds to localhost by default; put a reverse proxy in front of it for anything else.
+
+use std::collections::HashMap;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use crate::summary::SnapshotSummary;
+use crate::{annotations, query, store};
+
+/// Address the UI listens on when none is given.
+pub const DEFAULT_LISTEN: &str = "127.0.0.1:8080";
+
+/// Number of summaries returned by /api/summaries when no limit is given.
+const DEFAULT_SUMMARY_LIMIT: usize = 500;
+
+const INDEX_HTML: &str = include_str!("web/index.html");
+
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A response: status line, content type and body.
+#[derive(Debug, PartialEq)]
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn json<T: serde::Serialize>(value: &T) -> Self {
+        match serde_json::to_vec(value) {
+            Ok(body) => Response {
+                status: "200 OK",
+                content_type: "application/json",
+                body,
+            },
+            Err(e) => Response::error("500 Internal Server Error", &e.to_string()),
+        }
+    }
+
+    fn error(status: &'static str, message: &str) -> Self {
+        Response {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: format!("{}\n", message).into_bytes(),
+        }
+    }
+}
+
+/// Serves the UI for `datadir` on `listen` until the process is stopped. Every connection is
+/// handled on its own thread.
+pub fn serve(datadir: &Path, listen: &str, workers: usize) -> io::Result<()> {
+    let listener = TcpListener::bind(listen)?;
+    println!("Serving {} on http://{}", datadir.display(), listen);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("Cannot accept connection, err: {}", e);
+                continue;
+            }
+        };
+        let datadir: PathBuf = datadir.to_path_buf();
+        thread::spawn(move || {
+            if let Err(e) = handle(stream, &datadir, workers) {
+                eprintln!("Error serving request, err: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+fn handle(stream: TcpStream, datadir: &Path, workers: usize) -> io::Result<()> {
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // The headers are not needed, but have to be read before the response is sent.
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+    let mut parts = request_line.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(target)) => route(datadir, target, workers),
+        (Some(_), Some(_)) => Response::error("405 Method Not Allowed", "only GET is supported"),
+        _ => Response::error("400 Bad Request", "malformed request"),
+    };
+    let mut stream = reader.into_inner();
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.content_type,
+        response.body.len()
+    )?;
+    stream.write_all(&response.body)
+}
+
+fn route(datadir: &Path, target: &str, workers: usize) -> Response {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let params = parse_query(query);
+    let param = |name: &str| params.get(name).and_then(|v| v.parse::<u64>().ok());
+    match path {
+        "/" | "/index.html" => Response {
+            status: "200 OK",
+            content_type: "text/html; charset=utf-8",
+            body: INDEX_HTML.as_bytes().to_vec(),
+        },
+        "/api/snapshots" => match store::snapshot_files(datadir) {
+            Ok(files) => Response::json(&files.iter().map(|(e, _)| *e).collect::<Vec<u64>>()),
+            Err(e) => Response::error("500 Internal Server Error", &e.to_string()),
+        },
+        "/api/summaries" => {
+            let limit = param("limit").map_or(DEFAULT_SUMMARY_LIMIT, |l| l as usize);
+            match summaries(
+                datadir,
+                param("from").unwrap_or(0),
+                param("to").unwrap_or(u64::MAX),
+                limit,
+                workers,
+            ) {
+                Ok(s) => Response::json(&s),
+                Err(e) => Response::error("500 Internal Server Error", &e.to_string()),
+            }
+        }
+        "/api/annotations" => match annotations::read(
+            datadir,
+            param("from").unwrap_or(0),
+            param("to").unwrap_or(u64::MAX),
+        ) {
+            Ok(a) => Response::json(&a),
+            Err(e) => Response::error("500 Internal Server Error", &e.to_string()),
+        },
+        _ => match path
+            .strip_prefix("/api/snapshot/")
+            .map(|e| e.parse::<u64>())
+        {
+            Some(Ok(epoch)) => match store::find_snapshot(datadir, epoch) {
+                Some(p) => match store::read_snapshot(&p) {
+                    Ok(s) => Response::json(&s),
+                    Err(e) => Response::error("500 Internal Server Error", &e.to_string()),
+                },
+                None => Response::error("404 Not Found", "no snapshot at this epoch"),
+            },
+            _ => Response::error("404 Not Found", "not found"),
+        },
+    }
+}
+
+/// Returns the summaries of the `limit` most recent snapshots between `from` and `to`, oldest
+/// first. Unreadable snapshots are left out.
+fn summaries(
+    datadir: &Path,
+    from: u64,
+    to: u64,
+    limit: usize,
+    workers: usize,
+) -> io::Result<Vec<SnapshotSummary>> {
+    let mut files = query::files_in_range(datadir, from, to)?;
+    // One more than the limit, so the oldest returned summary has a previous one for steal%.
+    let skip = files.len().saturating_sub(limit.saturating_add(1));
+    files.drain(..skip);
+    let results = query::par_map(&files, workers, |s| {
+        (SnapshotSummary::new(s, None), s.cpu_times)
+    });
+    let mut summaries = Vec::new();
+    let mut previous = None;
+    for (_, result) in results {
+        if let Ok((mut summary, cpu_times)) = result {
+            summary.steal_percent = previous.and_then(|p| cpu_times.steal_percent(&p));
+            previous = Some(cpu_times);
+            summaries.push(summary);
+        }
+    }
+    let skip = summaries.len().saturating_sub(limit);
+    summaries.drain(..skip);
+    Ok(summaries)
+}
+
+/// Parses `a=1&b=2`. Values are not percent decoded, the API only takes numbers.
+fn parse_query(query: &str) -> HashMap<&str, &str> {
+    query
+        .split('&')
+        .filter_map(|kv| kv.split_once('='))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route() {
+        let dir = Path::new("/nonexistent");
+        let index = route(dir, "/", 1);
+        assert_eq!(index.status, "200 OK");
+        assert!(String::from_utf8(index.body).unwrap().contains("<html"));
+        assert_eq!(route(dir, "/etc/passwd", 1).status, "404 Not Found");
+        assert_eq!(route(dir, "/api/snapshot/12", 1).status, "404 Not Found");
+        assert_eq!(
+            route(dir, "/api/snapshots", 1).status,
+            "500 Internal Server Error"
+        );
+        let annotations = route(dir, "/api/annotations", 1);
+        assert_eq!(annotations.status, "200 OK");
+        assert_eq!(annotations.body, b"[]");
+        let params = parse_query("from=10&to=20&junk");
+        assert_eq!(params.get("from"), Some(&"10"));
+        assert_eq!(params.len(), 2);
+    }
+}
@@ -0,0 +1,217 @@
+//! A synthetic proc filesystem for the integration tests.
+//!
+//! `FakeProc` lays out a directory like /proc, with the files procshot reads for every process
+//! (stat, status, statm, io, cmdline) and the host wide /proc/stat, written from `FakeProcess`es
+//! whose counters the tests control. Pointing the collection at it, eg: with `snapshot_in`, runs
+//! the whole pipeline without root and without depending on what runs on the machine.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// FakeProcess is the state of one process of a `FakeProc`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FakeProcess {
+    pub pid: u32,
+    pub ppid: u32,
+    pub uid: u32,
+    pub name: String,
+    pub cmdline: Vec<String>,
+    /// State letter of the stat file, eg: `S`.
+    pub state: char,
+    /// User and system time, in clock ticks.
+    pub utime: u64,
+    pub stime: u64,
+    /// Resident set size, in pages.
+    pub rss_pages: u64,
+    /// Peak and current virtual memory size, in kB.
+    pub vmpeak_kb: u64,
+    pub vmsize_kb: u64,
+    pub fdsize: u32,
+    pub read_bytes: u64,
+    pub write_bytes: u64,
+}
+
+impl FakeProcess {
+    /// A sleeping process of the current user, running `/usr/bin/<name>`, with 1 MiB of virtual
+    /// memory of which `rss_pages` are resident.
+    pub fn new(pid: u32, name: &str, rss_pages: u64) -> Self {
+        FakeProcess {
+            pid,
+            ppid: 1,
+            uid: unsafe { libc::geteuid() },
+            name: name.to_string(),
+            cmdline: vec![format!("/usr/bin/{}", name)],
+            state: 'S',
+            utime: 0,
+            stime: 0,
+            rss_pages,
+            vmpeak_kb: 1024,
+            vmsize_kb: 1024,
+            fdsize: 64,
+            read_bytes: 0,
+            write_bytes: 0,
+        }
+    }
+
+    fn stat(&self) -> String {
+        // Fields from the state on, numbered from 3 as in proc(5), up to the exit code (52).
+        let mut fields = vec!["0".to_string(); 50];
+        let mut set = |n: usize, value: String| fields[n - 3] = value;
+        set(3, self.state.to_string());
+        set(4, self.ppid.to_string());
+        set(5, self.pid.to_string());
+        set(6, self.pid.to_string());
+        set(14, self.utime.to_string());
+        set(15, self.stime.to_string());
+        set(18, "20".to_string());
+        set(20, "1".to_string());
+        set(23, (self.vmsize_kb * 1024).to_string());
+        set(24, self.rss_pages.to_string());
+        set(25, u64::MAX.to_string());
+        format!("{} ({}) {}\n", self.pid, self.name, fields.join(" "))
+    }
+
+    fn status(&self) -> String {
+        format!(
+            "Name:\t{name}\nState:\t{state} (sleeping)\nTgid:\t{pid}\nPid:\t{pid}\nPPid:\t{ppid}\n\
+             TracerPid:\t0\nUid:\t{uid}\t{uid}\t{uid}\t{uid}\nGid:\t{uid}\t{uid}\t{uid}\t{uid}\n\
+             FDSize:\t{fdsize}\nVmPeak:\t{vmpeak} kB\nVmSize:\t{vmsize} kB\nVmRSS:\t{rss} kB\n\
+             Threads:\t1\n",
+            name = self.name,
+            state = self.state,
+            pid = self.pid,
+            ppid = self.ppid,
+            uid = self.uid,
+            fdsize = self.fdsize,
+            vmpeak = self.vmpeak_kb,
+            vmsize = self.vmsize_kb,
+            rss = self.rss_pages * page_size() / 1024,
+        )
+    }
+
+    fn statm(&self) -> String {
+        let size = self.vmsize_kb * 1024 / page_size();
+        format!(
+            "{} {} {} 1 0 {} 0\n",
+            size,
+            self.rss_pages,
+            self.rss_pages / 2,
+            self.rss_pages / 2
+        )
+    }
+
+    fn io(&self) -> String {
+        format!(
+            "rchar: {r}\nwchar: {w}\nsyscr: 1\nsyscw: 1\nread_bytes: {r}\nwrite_bytes: {w}\n\
+             cancelled_write_bytes: 0\n",
+            r = self.read_bytes,
+            w = self.write_bytes,
+        )
+    }
+}
+
+/// FakeProc is a directory laid out like /proc, removed when dropped.
+pub struct FakeProc {
+    root: PathBuf,
+    processes: BTreeMap<u32, FakeProcess>,
+    /// Clock ticks of the host, spent in user, system and idle time.
+    cpu: (u64, u64, u64),
+}
+
+impl FakeProc {
+    /// Creates an empty proc filesystem in a temporary directory unique to `name`.
+    pub fn new(name: &str) -> Self {
+        let root = std::env::temp_dir().join(format!(
+            "procshot_fake_proc_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let mut fake = FakeProc {
+            root,
+            processes: BTreeMap::new(),
+            cpu: (0, 0, 0),
+        };
+        fake.write_stat();
+        fake
+    }
+
+    /// Creates a proc filesystem of `n` processes with pids from 1000, named `worker-<i % 10>`,
+    /// with `i + 1` resident pages each.
+    pub fn with_processes(name: &str, n: u32) -> Self {
+        let mut fake = FakeProc::new(name);
+        for i in 0..n {
+            fake.insert(FakeProcess::new(
+                1000 + i,
+                &format!("worker-{}", i % 10),
+                u64::from(i) + 1,
+            ));
+        }
+        fake
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn processes(&self) -> impl Iterator<Item = &FakeProcess> {
+        self.processes.values()
+    }
+
+    /// Adds a process, or replaces the one with the same pid, and writes its files.
+    pub fn insert(&mut self, process: FakeProcess) {
+        let dir = self.root.join(process.pid.to_string());
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("stat"), process.stat()).unwrap();
+        fs::write(dir.join("status"), process.status()).unwrap();
+        fs::write(dir.join("statm"), process.statm()).unwrap();
+        fs::write(dir.join("io"), process.io()).unwrap();
+        let cmdline: String = process.cmdline.iter().map(|a| format!("{}\0", a)).collect();
+        fs::write(dir.join("cmdline"), cmdline).unwrap();
+        fs::write(dir.join("comm"), format!("{}\n", process.name)).unwrap();
+        self.processes.insert(process.pid, process);
+    }
+
+    /// Changes the process `pid` with `f` and rewrites its files.
+    pub fn update<F: FnOnce(&mut FakeProcess)>(&mut self, pid: u32, f: F) {
+        let mut process = self.processes[&pid].clone();
+        f(&mut process);
+        self.insert(process);
+    }
+
+    /// Removes the process `pid`, as if it exited.
+    pub fn remove(&mut self, pid: u32) {
+        self.processes.remove(&pid);
+        fs::remove_dir_all(self.root.join(pid.to_string())).unwrap();
+    }
+
+    /// Advances the clock of the host by `user`, `system` and `idle` ticks.
+    pub fn tick(&mut self, user: u64, system: u64, idle: u64) {
+        self.cpu = (self.cpu.0 + user, self.cpu.1 + system, self.cpu.2 + idle);
+        self.write_stat();
+    }
+
+    fn write_stat(&mut self) {
+        let (user, system, idle) = self.cpu;
+        fs::write(
+            self.root.join("stat"),
+            format!(
+                "cpu  {} 0 {} {} 0 0 0 0 0 0\ncpu0 {} 0 {} {} 0 0 0 0 0 0\n",
+                user, system, idle, user, system, idle
+            ),
+        )
+        .unwrap();
+    }
+}
+
+impl Drop for FakeProc {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
+
+pub fn page_size() -> u64 {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as u64 }
+}
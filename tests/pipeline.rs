@@ -0,0 +1,122 @@
+//! End-to-end tests of the scan, write, read and query pipeline over a synthetic proc filesystem.
+
+mod common;
+
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, UNIX_EPOCH};
+
+use common::{page_size, FakeProc, FakeProcess};
+use procshot_server::prelude::*;
+use procshot_server::store::CompressionMode;
+use procshot_server::{collect, report, snapshot_in, validate};
+
+fn datadir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("procshot_it_{}_{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn test_collect_fake_proc() {
+    let mut proc = FakeProc::with_processes("collect", 50);
+    proc.insert(FakeProcess {
+        cmdline: vec![
+            "/usr/bin/postgres".to_string(),
+            "-D".to_string(),
+            "/data".to_string(),
+        ],
+        read_bytes: 4096,
+        ppid: 1000,
+        ..FakeProcess::new(2000, "postgres", 256)
+    });
+    // Kernel threads have no VmPeak, and aren't recorded.
+    proc.insert(FakeProcess {
+        vmpeak_kb: 0,
+        rss_pages: 0,
+        ..FakeProcess::new(2, "kthreadd", 0)
+    });
+
+    let collected = collect::collect_all(proc.path()).unwrap();
+    assert_eq!(collected.len(), 51);
+    let postgres = &collected[&Pid::new(2000)];
+    assert_eq!(postgres.name, "postgres");
+    assert_eq!(postgres.cmd_long, vec!["/usr/bin/postgres", "-D", "/data"]);
+    assert_eq!(postgres.ppid, Pid::new(1000));
+    assert_eq!(postgres.rss_bytes, 256 * page_size() as i64);
+    assert_eq!(postgres.vmpeak, Some(1024));
+    assert_eq!(postgres.data_pages, 128);
+    assert_eq!(postgres.io.map(|io| io.read_bytes), Some(4096));
+    assert!(!postgres.restricted);
+    for p in proc.processes().filter(|p| p.rss_pages > 0) {
+        assert_eq!(
+            collected[&Pid::new(p.pid as i32)].rss_pages,
+            p.rss_pages as i64
+        );
+    }
+}
+
+#[test]
+fn test_scan_write_read_query() {
+    let mut proc = FakeProc::with_processes("pipeline", 20);
+    let dir = datadir("pipeline");
+    let mut backend = DirBackend::new(&dir, false).with_compression(CompressionMode::Gzip);
+    let mut previous: Option<EncoDecode> = None;
+    for i in 0..5u64 {
+        if i > 0 {
+            // worker-0 (pid 1000) burns a quarter of the host's CPU and leaks 100 pages per
+            // iteration, pid 1019 exits after the second snapshot and pid 3000 starts.
+            proc.tick(100, 100, 200);
+            proc.update(1000, |p| {
+                p.utime += 80;
+                p.stime += 20;
+                p.rss_pages += 100;
+            });
+        }
+        if i == 2 {
+            proc.remove(1019);
+            proc.insert(FakeProcess::new(3000, "cron", 10));
+        }
+        let mut snapshot = snapshot_in(proc.path(), "localghost", previous.as_ref()).unwrap();
+        snapshot.time_epoch = 1_000_000 + i * 60;
+        backend.write_snapshot(&snapshot).unwrap();
+        previous = Some(snapshot);
+    }
+
+    let store = SnapshotStore::open(&dir).unwrap();
+    let at = |epoch: u64| UNIX_EPOCH + Duration::from_secs(epoch);
+    let snapshots: Vec<EncoDecode> = store
+        .query(at(1_000_060), at(1_000_180))
+        .map(|s| s.unwrap())
+        .collect();
+    assert_eq!(snapshots.len(), 3);
+    for s in &snapshots {
+        let worker = &s.pid_map_list[&Pid::new(1000)];
+        assert_eq!((worker.user_cpu_usage, worker.sys_cpu_usage), (20.0, 5.0));
+        assert_eq!(s.cpu_times.total(), proc_ticks(s));
+    }
+    assert!(snapshots[0].pid_map_list.contains_key(&Pid::new(1019)));
+    assert!(!snapshots[1].pid_map_list.contains_key(&Pid::new(1019)));
+    assert!(snapshots[1].pid_map_list.contains_key(&Pid::new(3000)));
+
+    let summary = SnapshotSummary::new(&snapshots[2], Some(&snapshots[1].cpu_times));
+    assert_eq!(summary.process_count, 20);
+    assert_eq!(summary.total_cpu_usage, 25.0);
+
+    let growth = report::growth(&dir, 0, u64::MAX, 2).unwrap();
+    assert_eq!(growth.snapshots, 5);
+    let top = growth.top_rss(1);
+    assert_eq!(top[0].pid, Pid::new(1000));
+    assert_eq!(top[0].rss_bytes.absolute(), 400 * page_size() as i64);
+
+    let validated = validate::validate(&dir).unwrap();
+    assert!(validated.is_ok(), "{}", validate::render(&validated));
+    assert_eq!(validated.files, 5);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Returns the ticks of the host at the time of `snapshot`, as written by `FakeProc::tick`.
+fn proc_ticks(snapshot: &EncoDecode) -> u64 {
+    (snapshot.time_epoch - 1_000_000) / 60 * 400
+}